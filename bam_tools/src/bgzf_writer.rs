@@ -0,0 +1,157 @@
+use super::gz::{
+    CompressionMethod, OperatingSystem, BGZF_HEADER_SIZE, MAGIC_NUMBER, MTIME_NONE, TRAILER_SIZE,
+};
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Write};
+
+/// Maximum amount of uncompressed data in one block (same as in htslib).
+pub const BGZF_MAX_BLOCK_DATA_SIZE: usize = 0xff00;
+
+// FEXTRA
+const FLG_FEXTRA: u8 = 0x04;
+// XFL
+const XFL_NONE: u8 = 0x00;
+
+/// Empty block marking the end of BGZF stream.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02,
+    0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses written data into BGZF blocks. The stream is terminated with EOF
+/// block by [`BgzfWriter::finish`] or on drop.
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    buf: Vec<u8>,
+    compressed: Vec<u8>,
+    level: Compression,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_level(inner, Compression::default())
+    }
+
+    pub fn with_level(inner: W, level: Compression) -> Self {
+        Self {
+            inner: Some(inner),
+            buf: Vec::with_capacity(BGZF_MAX_BLOCK_DATA_SIZE),
+            compressed: Vec::new(),
+            level,
+            finished: false,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Writes out buffered data and EOF block.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.flush()?;
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&BGZF_EOF)?;
+        inner.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Finishes the stream and returns underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.finish()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.compressed.clear();
+        let mut encoder = DeflateEncoder::new(&mut self.compressed, self.level);
+        encoder.write_all(&self.buf)?;
+        encoder.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let block_size = BGZF_HEADER_SIZE + self.compressed.len() + TRAILER_SIZE;
+        // Incompressible data may not fit into one block. Split it in halves.
+        if block_size > usize::from(u16::MAX) + 1 {
+            let tail = self.buf.split_off(self.buf.len() / 2);
+            self.write_block()?;
+            self.buf = tail;
+            return self.write_block();
+        }
+
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&MAGIC_NUMBER)?;
+        inner.write_u8(CompressionMethod::Deflate as u8)?;
+        inner.write_u8(FLG_FEXTRA)?;
+        inner.write_u32::<LittleEndian>(MTIME_NONE)?;
+        inner.write_u8(XFL_NONE)?;
+        inner.write_u8(OperatingSystem::Unknown as u8)?;
+        // XLEN, then BC subfield holding total block size minus 1.
+        inner.write_u16::<LittleEndian>(6)?;
+        inner.write_all(b"BC")?;
+        inner.write_u16::<LittleEndian>(2)?;
+        inner.write_u16::<LittleEndian>((block_size - 1) as u16)?;
+        inner.write_all(&self.compressed)?;
+        inner.write_u32::<LittleEndian>(crc.sum())?;
+        inner.write_u32::<LittleEndian>(crc.amount())?;
+
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(data.len(), BGZF_MAX_BLOCK_DATA_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() == BGZF_MAX_BLOCK_DATA_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    /// Writes out current block even if it is not full.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_bgzf_round_trip() {
+        let data: Vec<u8> = (0..200_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+        let out = writer.into_inner().unwrap();
+
+        assert!(out.ends_with(&BGZF_EOF));
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&out[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
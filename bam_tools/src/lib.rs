@@ -1,4 +1,6 @@
 mod block;
+/// BGZF compressing writer
+pub mod bgzf_writer;
 pub mod gz;
 mod util;
 mod virtual_position;
@@ -23,6 +25,7 @@ pub mod record {
     mod tags;
 }
 
+pub use bgzf_writer::BgzfWriter;
 use block::Block;
 pub use reader::parse_reference_sequences;
 pub use reader::Reader;
@@ -295,8 +295,8 @@ pub fn put_sequence(mut dst: &mut [u8], read_length: usize, sequence: &String) -
     while let Some(l) = bases.next() {
         // § 4.2.3 "SEQ and QUAL encoding" (2021-06-03): "When `l_seq` is odd the bottom 4 bits of
         // the last byte are undefined, but we recommend writing these as zero."
-        let r = bases.next().unwrap_or('\0');
-        let b = encode_base(l) << 4 | encode_base(r);
+        let r = bases.next().map_or(0, encode_base);
+        let b = encode_base(l) << 4 | r;
         dst.write_u8(b)?;
     }
 
@@ -121,6 +121,18 @@ impl std::fmt::Display for Fields {
     }
 }
 
+/// Parses field from its enum name (case insensitive), e.g. `RawTags` or `mapq`.
+impl std::str::FromStr for Fields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Fields::iterator()
+            .find(|field| field.to_string().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| format!("Unknown field <{}>.", s))
+    }
+}
+
 /// Type of Field.
 #[derive(Debug)]
 #[allow(missing_docs)]
@@ -1,21 +1,62 @@
 // use gbam_tools::bam_to_gbam;
-use bam_tools::{record::fields::Fields, MEGA_BYTE_SIZE};
+use bam_tools::{record::fields::{is_data_field, Fields, FIELDS_NUM}, MEGA_BYTE_SIZE};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use gbam_tools::{
-    bam::bam_to_gbam::bam_sort_to_gbam,
+    bam::bam_to_gbam::{bam_append_to_gbam, bam_sort_to_gbam},
     bam::gbam_to_bam::gbam_to_bam,
-    query::depth::main_depth,
-    reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord},
-    {bam_to_gbam, Codecs},
+    bam::gbam_to_sam::{format_sam_record, write_sam_header},
+    bam::gbam_to_fastq::gbam_to_fastq,
+    bam::sam_to_gbam::{sam_append_to_gbam, sam_to_gbam},
+    bam::cram_to_gbam::cram_to_gbam,
+    dedup::{shared_blocks, DedupStore},
+    fingerprint::{store_fingerprint, verify_fingerprint, Fingerprint},
+    interval_index::store_interval_index,
+    reference::store_reference_checksums,
+    writer::{store_binary_meta, ProgressCallback, WriteProgress},
+    name_index::store_name_index,
+    merge::merge_gbam,
+    slice::slice_gbam,
+    rescue::rescue_gbam,
+    check::check_gbam,
+    meta::FileMeta,
+    query::downsample::{downsample, subsample, Subsampler},
+    query::markdup::markdup::markdup,
+    sort::{sort_to_gbam, SortBy, DEFAULT_MEM_LIMIT},
+    query::depth::{main_depth, sorts_in_memory, DepthOptions, DepthOutput, Quantize, DEFAULT_EXCLUDE_FLAGS},
+    query::depth_cache::DepthCache,
+    reader::{block_cache::BlockCache, filter::RecordFilter, io_stats::{IoStats, SharedIoStats}, parse_tmplt::ParsingTemplate, partition::Partition, reader::{ColumnSize, Reader}, record::GbamRecord, regions::Region},
+    {bam_to_gbam, Codecs, GbamError},
     query::flagstat::collect_stats,
+    query::idxstats::idxstats,
+    query::stats::{collect_seq_stats, Metric},
+    query::contig_groups::{count_per_ref, write_group_summary, ContigGroups},
+    query::mask::{coverage_mask, MaskFormat},
+    query::mates::MateResolver,
+    query::read_length::{length_track, LengthStat},
+    query::estimate::{write_estimates, SampleEstimates},
+    query::softclip::{collect_clip_stats, write_clip_report, AdapterScreen},
+    query::pileup::{write_consensus, write_mpileup},
+    export::arrow::{write_ipc_stream, RecordBatches, DEFAULT_BATCH_SIZE},
+    export::parquet::write_parquet,
+    export::table::{write_table, TableColumn},
+    query::pairs::{write_pair_summary, InsertSizeStats, PairFilter, PAIR_FIELDS},
+    query::read_groups::write_read_group_stats,
+    reader::remote::{block_on, RemoteReader},
+    reader::source::{is_remote, open_source},
+    utils::bed::parse_bed_from_file,
+    utils::sink::{open_sink, OutputSink},
+    utils::tabix::{index_bed_gz, TabixLayout},
 };
 use itertools::zip_eq;
+use memmap2::Mmap;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use std::fs::OpenOptions;
 
 use std::io::{BufRead, BufReader, Seek, SeekFrom};
 
 
-use std::{path::PathBuf, convert::TryInto, io::{Read}, io::{BufWriter, Write}};
+use std::{path::{Path, PathBuf}, convert::TryInto, io::{Read}, io::{BufWriter, Write}};
+use std::sync::PoisonError;
 use std::time::Instant;
 use std::fs::File;
 use structopt::StructOpt;
@@ -33,10 +74,16 @@ struct Cli {
     /// Specify which kind of temporary medium to use while sorting: ram, lz4_ram, file, lz4_file
     #[structopt(long)]
     sort_temp_mode: Option<String>,
+    /// Memory in MB for records kept before a sorted run is spilled to --temp-dir (sorting SAM or GBAM input, or by query name).
+    #[structopt(long)]
+    sort_mem: Option<usize>,
+    /// Sort order: coordinate (default) or queryname (natural order of read names, as samtools sort -n).
+    #[structopt(long)]
+    sort_by: Option<String>,
     /// Determines whether conversion is requested
     #[structopt(short, long)]
     convert_to_gbam: bool,
-    /// Convert to bam
+    /// Convert GBAM to BGZF compressed BAM file given by -o, with the original header.
     #[structopt(long)]
     convert_to_bam: bool,
     /// Perform the test
@@ -51,6 +98,27 @@ struct Cli {
     /// Collect statistic from flag field from all records in the file.
     #[structopt(short, long)]
     flagstat: bool,
+    /// Print the number of records passing --require-flags, --exclude-flags, --mapq and --expr filters and overlapping -b or -q regions (samtools view -c). Only filtered fields are decoded.
+    #[structopt(long)]
+    count: bool,
+    /// Print the number of records and codec, blocks, compressed and uncompressed bytes and compression ratio of every column, read from meta only.
+    #[structopt(long)]
+    inspect: bool,
+    /// Print --inspect report as JSON.
+    #[structopt(long)]
+    json: bool,
+    /// Write records of given range to a new GBAM file (-o), copying whole compressed blocks where possible. Range is 0-based record numbers START..END, or records starting in a region, e.g. chr1:1,000-2,000.
+    #[structopt(long)]
+    slice: Option<String>,
+    /// Write the first N records to a new GBAM file (-o), as --slice 0..N.
+    #[structopt(long)]
+    head: Option<usize>,
+    /// Recover records of file without meta, e.g. of killed conversion, into a new GBAM file (-o).
+    #[structopt(long)]
+    rescue: bool,
+    /// Check CRCs of meta and blocks, block contents, record counts of columns, index columns and recorded sort order. Prints problems with their block or record, exits with 1 if any is found.
+    #[structopt(long)]
+    check: bool,
     /// The path to the BAM file to read
     #[structopt(parse(from_os_str))]
     in_path: PathBuf,
@@ -58,17 +126,58 @@ struct Cli {
     #[structopt(short, parse(from_os_str))]
     out_path: Option<PathBuf>,
     /// Depth query. Example: chr1:54-54, or chrX:1258-9999
+    /// With --view or --count, only records overlapping the region (samtools syntax, e.g. chr1:1,000-2,000) are viewed.
     #[structopt(short, long)]
     query: Option<String>,
     /// Depth query. Example: chr1:54, or chrX:1258
+    /// With --view or --count, only records overlapping BED regions are viewed.
     #[structopt(short, parse(from_os_str))]
     bed_file: Option<PathBuf>,
+    /// Cache up to this many MB of decompressed blocks when viewing. Speeds up views of many BED regions.
+    #[structopt(long)]
+    block_cache: Option<usize>,
     /// Depth query. Filter reads with map quality lower than.
+    /// With --view or --count, records with map quality lower than this are skipped (samtools view -q).
     #[structopt(long)]
     mapq: Option<u32>,
-    /// Depth query. Number of threads to use. WARNING: each thread will attempt to allocate up to 1GB.
+    /// Depth query. Number of threads to use. WARNING: each thread will attempt to allocate up to 1GB, unless --tile-size is given.
+    /// With -c of CRAM file, number of decoding and of compression threads (8 by default).
     #[structopt(long)]
     thread_num: Option<usize>,
+    /// Depth query. Count only bases aligned by CIGAR M, = and X, not skipped regions (N) and deletions (D), as samtools depth.
+    #[structopt(long)]
+    cigar_aware: bool,
+    /// Depth query. With --cigar-aware, count deletions too (samtools depth -J).
+    #[structopt(long)]
+    count_deletions: bool,
+    /// Depth query. Count only aligned bases with at least this base quality (samtools depth -q).
+    /// With --mpileup or --consensus, bases of lower quality are left out.
+    #[structopt(long, default_value = "0")]
+    min_base_quality: u8,
+    /// Depth query. Count bases where mates of a pair overlap once, using mate position and template length of the leftmost mate.
+    #[structopt(long)]
+    fast_mate_correction: bool,
+    /// Depth query. Write per base depth even if output path is given, which otherwise selects bedGraph output.
+    #[structopt(long)]
+    per_base: bool,
+    /// Depth query. Write mean depth in windows of this many bases (BED4) instead of per base depth.
+    #[structopt(long)]
+    by: Option<u32>,
+    /// Depth query. Also write total bases and mean, min and max depth of each reference sequence and of the whole genome to <output>.summary.txt (as mosdepth.summary.txt).
+    #[structopt(long)]
+    summary: bool,
+    /// Depth query. Compute depth in tiles of this many bases instead of whole reference sequences to bound memory. Not supported with --by and region summaries.
+    #[structopt(long)]
+    tile_size: Option<u32>,
+    /// Depth query. Write regions labeled by coverage band (BED4, as mosdepth --quantize), e.g. 0:1:5:30: for bands 0:1, 1:5, 5:30 and 30:inf.
+    #[structopt(long)]
+    quantize: Option<String>,
+    /// Depth query. Write one line per BED region (-b or -q) with mean, min, max and median depth over it.
+    #[structopt(long)]
+    region_summary: bool,
+    /// Depth query. Comma separated depth thresholds, e.g. 1,10,30. Implies --region-summary, which then also writes breadth of coverage and number of bases with depth of at least each threshold.
+    #[structopt(long)]
+    thresholds: Option<String>,
     /// Sort temp directory.
     #[structopt(long, parse(from_os_str))]
     temp_dir: Option<PathBuf>,
@@ -95,97 +204,583 @@ struct Cli {
     /// Calculate uncompressed size of BAM file.
     #[structopt(long)]
     calc_uncompressed_size: bool,
+    /// Comma separated list of fields to store without compression when converting. Example: RawTags,RawQual
+    #[structopt(long)]
+    store_fields: Option<String>,
+    /// Comma separated list of tags to store in their own columns apart from RawTags when converting BAM or SAM without sorting. Example: NM,AS,MD,RG
+    #[structopt(long)]
+    tag_columns: Option<String>,
+    /// Append records of input BAM file to existing GBAM file given by -o. Used with -c.
+    #[structopt(long)]
+    append: bool,
+    /// Find read pairs which mates are stored in different coordinate sorted shards (input file and --shards). Prints TSV: read name, shard, record, mate shard, mate record.
+    #[structopt(long)]
+    resolve_mates: bool,
+    /// Compress output of view, depth and other exporters with BGZF. Enabled for -o paths ending with .gz or .bgz.
+    #[structopt(long)]
+    bgzip: bool,
+    /// Additional GBAM shards.
+    #[structopt(long, parse(from_os_str))]
+    shards: Vec<PathBuf>,
+    /// Write BED of regions which total depth across input file and --samples is below --min-depth or above --max-depth.
+    #[structopt(long)]
+    mask: bool,
+    /// Write mask as FASTA (P - passed, L - low, H - high coverage) instead of BED.
+    #[structopt(long)]
+    mask_fasta: bool,
+    /// Additional GBAM files of other samples.
+    #[structopt(long, parse(from_os_str))]
+    samples: Vec<PathBuf>,
+    /// Minimal depth for mask.
+    #[structopt(long)]
+    min_depth: Option<i32>,
+    /// Maximal depth for mask.
+    #[structopt(long)]
+    max_depth: Option<i32>,
+    /// Mapped and unmapped record counts per reference sequence, as samtools idxstats.
+    #[structopt(long)]
+    idxstats: bool,
+    /// Insert size, read length, GC and per-cycle quality distributions, as samtools stats.
+    #[structopt(long)]
+    stats: bool,
+    /// Export primary reads as FASTQ in sequencing orientation. With -o PREFIX writes PREFIX_R1.fastq, PREFIX_R2.fastq and PREFIX_singletons.fastq, otherwise interleaved FASTQ to stdout.
+    #[structopt(long)]
+    fastq: bool,
+    /// Write reference-free pileup of reads (as samtools mpileup without reference) over -q or -b regions, whole file otherwise.
+    #[structopt(long)]
+    mpileup: bool,
+    /// Write majority consensus FASTA of -q or -b regions, or of every reference sequence with reads.
+    #[structopt(long)]
+    consensus: bool,
+    /// Write records as Arrow IPC stream (e.g. for polars.read_ipc_stream), of -q or -b regions if given.
+    #[structopt(long)]
+    arrow: bool,
+    /// Write records as Parquet file (-o) with Zstandard compressed columns named as in SAM and tags as SAM text, of -q or -b regions if given.
+    #[structopt(long)]
+    parquet: bool,
+    /// Write selected --columns of records as TSV with header line, of -q or -b regions if given.
+    #[structopt(long)]
+    table: bool,
+    /// Comma separated columns of --table: refid, rname, pos, mapq, bin, flag, next_refid, rnext, pnext, tlen, qname, cigar, seq, qual, tags.
+    #[structopt(long, default_value = "refid,pos,mapq,flag,tlen")]
+    columns: String,
+    /// With --table, write CSV instead of TSV.
+    #[structopt(long)]
+    csv: bool,
+    /// Comma separated fields exported by --arrow or --parquet, e.g. ReadName,RefID,Pos,Mapq. All data fields by default.
+    #[structopt(long)]
+    fields: Option<String>,
+    /// Comma separated metrics of --stats: insert_size, read_length, quality, gc. All by default.
+    #[structopt(long)]
+    metrics: Option<String>,
+    /// Summarize alignments and coverage by contig groups (autosomes, sex, mito, alt).
+    #[structopt(long)]
+    contig_summary: bool,
+    /// TSV file assigning contigs to groups: contig, group. Unlisted contigs are grouped by name.
+    #[structopt(long, parse(from_os_str))]
+    contig_groups: Option<PathBuf>,
+    /// Split file into N block aligned record ranges for scatter jobs. Prints one JSON partition descriptor per line.
+    #[structopt(long)]
+    partitions: Option<usize>,
+    /// View only records of partition given as JSON descriptor printed by --partitions.
+    #[structopt(long)]
+    partition: Option<String>,
+    /// View as SAM text (with header) instead of BAM.
+    #[structopt(long)]
+    sam: bool,
+    /// Comma separated list of fields not to fetch for view. SAM view prints them as `*` or 0. Example: RawSequence,RawQual
+    #[structopt(long)]
+    exclude_fields: Option<String>,
+    /// Depth cache file. Used with --depth: depth of records appended since the cache was saved is added to it, the cache is saved and whole depth is written as bedGraph.
+    #[structopt(long, parse(from_os_str))]
+    depth_cache: Option<PathBuf>,
+    /// Merge input file and --shards, all sorted by coordinate or all by query name, into one GBAM file given by -o.
+    #[structopt(long)]
+    merge: bool,
+    /// Reference FASTA for decoding CRAM input. With conversion, M5 checksums of its sequences are stored in the file meta.
+    #[structopt(long, parse(from_os_str))]
+    reference: Option<PathBuf>,
+    /// Compare reference sequences of the file (M5 checksums, or lengths if none are stored) with --reference FASTA. Exits with 1 on mismatch.
+    #[structopt(long)]
+    validate_reference: bool,
+    /// View only pairs of given comma separated orientations: FR, RF, TANDEM.
+    #[structopt(long)]
+    pair_orientation: Option<String>,
+    /// View only pairs which insert size z-score (against FR pairs of the file) is at least this.
+    #[structopt(long, allow_hyphen_values = true)]
+    min_insert_z: Option<f64>,
+    /// View only pairs which insert size z-score is at most this.
+    #[structopt(long, allow_hyphen_values = true)]
+    max_insert_z: Option<f64>,
+    /// Add po:Z (pair orientation) and iz:f (insert size z-score) tags to viewed pairs.
+    #[structopt(long)]
+    annotate_pairs: bool,
+    /// Print insert size statistics and number of records per pair orientation.
+    #[structopt(long)]
+    pair_summary: bool,
+    /// Print records, mapped records, duplicates, mean map quality and mean insert size per read group.
+    #[structopt(long)]
+    read_group_stats: bool,
+    /// Print content digests of every column and of the whole file (independent of codecs and block layout).
+    #[structopt(long)]
+    fingerprint: bool,
+    /// Compute fingerprint and store it in the file meta.
+    #[structopt(long)]
+    store_fingerprint: bool,
+    /// Compare content with the fingerprint stored in the file meta. Exits with 1 on mismatch.
+    #[structopt(long)]
+    verify_fingerprint: bool,
+    /// Mark duplicate reads and write new GBAM file to -o. Only Flags column is rewritten.
+    #[structopt(long)]
+    markdup: bool,
+    /// With --markdup, drop duplicates instead of marking them.
+    #[structopt(long)]
+    remove_duplicates: bool,
+    /// Downsample to target coverage (e.g. 30x) and write new GBAM file to -o. Mates are kept or dropped together.
+    #[structopt(long)]
+    target_coverage: Option<String>,
+    /// Keep this fraction of templates (e.g. 0.1) by hash of read name and write new GBAM file to -o, as samtools view --subsample. Mates are kept or dropped together.
+    #[structopt(long)]
+    subsample: Option<f64>,
+    /// Seed for --target-coverage, --subsample and --sample.
+    #[structopt(long, default_value = "0")]
+    seed: u32,
+    /// Compare column blocks of input file and --shards: prints TSV of blocks and bytes shared with preceding files.
+    #[structopt(long)]
+    shared_blocks: bool,
+    /// Store input file and --shards in deduplicating store directory, blocks shared between files are written once.
+    #[structopt(long, parse(from_os_str))]
+    dedup_store: Option<PathBuf>,
+    /// Restore file named as input (see --dedup-list) from deduplicating store directory to -o.
+    #[structopt(long, parse(from_os_str))]
+    dedup_restore: Option<PathBuf>,
+    /// List files of deduplicating store directory given as input.
+    #[structopt(long)]
+    dedup_list: bool,
+    /// Print soft clip length distributions and adapter content of clipped 3' ends per read group.
+    #[structopt(long)]
+    clip_report: bool,
+    /// FASTA of adapters and contaminants screened by --clip-report instead of the default ones.
+    #[structopt(long, parse(from_os_str))]
+    adapters: Option<PathBuf>,
+    /// Write bedGraph of aligned read length statistic (--length-stat) in windows of given size, for long read files.
+    #[structopt(long)]
+    length_track: Option<u32>,
+    /// Statistic of --length-track: median or n50.
+    #[structopt(long, default_value = "n50")]
+    length_stat: LengthStat,
+    /// Store interval index of coordinate sorted file in its meta, so region fetch (-b, -q) does not look back over the longest alignment. Indexes the output when converting, otherwise the input file.
+    #[structopt(long)]
+    interval_index: bool,
+    /// Store meta in binary encoding, which is smaller and opens faster than JSON for files with many blocks (readers of format version 1.0 can't open it). Converts the output when converting, otherwise the input file.
+    #[structopt(long)]
+    binary_meta: bool,
+    /// Estimate insert size, error rate (NM per aligned base) and base qualities from given number of uniformly sampled records.
+    #[structopt(long)]
+    sample: Option<usize>,
+    /// Store Bloom filter index of read names in the file, so --read-name scans only records which may match. Indexes the output when converting, otherwise the input file.
+    #[structopt(long)]
+    name_index: bool,
+    /// Print records with given read name as SAM.
+    #[structopt(long)]
+    read_name: Option<String>,
+    /// With --view or --count, only records with all of these flag bits set are viewed (samtools view -f), decimal or 0x hex.
+    /// With --depth, --mpileup or --consensus, only such reads are counted.
+    #[structopt(long, alias = "include-flags", default_value = "0", parse(try_from_str = parse_flag_mask))]
+    require_flags: u16,
+    /// With --view or --count, records with any of these flag bits set are skipped (samtools view -F), decimal or 0x hex.
+    /// With --depth, --mpileup or --consensus, such reads are not counted. --depth counts all reads by default (0x704 skips as samtools depth),
+    /// --mpileup and --consensus skip 0x704 (unmapped, secondary, QC fail, duplicate) by default.
+    #[structopt(long, parse(try_from_str = parse_flag_mask))]
+    exclude_flags: Option<u16>,
+    /// With --view or --count, only records matching filter expression are viewed (samtools view -e), e.g. "mapq >= 30 && flag.paired && rname == 'chr1'".
+    #[structopt(long)]
+    expr: Option<String>,
+    /// Print blocks and bytes read per field to stderr after --view or --read-name.
+    #[structopt(long)]
+    io_stats: bool,
+}
+
+fn parse_flag_mask(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
 }
 
 /// Limited wrapper of `gbam_tools` converts BAM file to GBAM
-/// file. Also limited tests may be run.
+/// file. Also limited tests may be run. Errors are printed and exit with
+/// their exit code (see [`GbamError::exit_code`]).
 fn main() {
     let args = Cli::from_args();
     let arguments_strings: Vec<String> = env::args().collect();
     let full_command = arguments_strings.join(" ");
+    if let Err(e) = run(args, full_command) {
+        exit_with_error(e);
+    }
+}
+
+fn run(args: Cli, full_command: String) -> Result<(), GbamError> {
     if args.convert_to_gbam {
-        convert(args, full_command);
+        convert(args, full_command)
     } else if args.test {
-        test(args);
+        test(args)
     } else if args.parallel_cigar_fetch {
-        test_parallel_cigar_fetch(args);
+        test_parallel_cigar_fetch(args)
     } else if args.depth {
-        depth(args);
+        depth(args)
     } else if args.convert_to_bam {
-        convert_to_bam(args);
+        convert_to_bam(args)
     } else if args.flagstat {
-        flagstat(args);
+        flagstat(args)
+    } else if args.count {
+        count(args)
+    } else if args.inspect {
+        inspect(args)
+    } else if args.slice.is_some() || args.head.is_some() {
+        slice(args, full_command)
+    } else if args.rescue {
+        rescue(args, full_command)
+    } else if args.check {
+        check(args)
     } else if args.header {
-        view_header(args);
+        view_header(args)
     } else if args.view {
         let mut template = ParsingTemplate::new();
-        template.set_all();
-        view_file(args, template);
+        template.set_all_except(&parse_fields(args.exclude_fields.as_deref())?);
+        if is_remote(path_str(&args.in_path)?) {
+            view_remote(args, template)
+        } else if args.sam {
+            view_sam(args, template)
+        } else {
+            view_file(args, template)
+        }
     } else if args.markdup_view {
         let mut template = ParsingTemplate::new();
         template.set_all_except(&[Fields::RawQual,Fields::RawSequence]);
-        view_file(args, template);
+        view_file(args, template)
     } else if args.patch_gbam_with_dups {
-        patch_dups(args);
-    }else if args.calc_uncompressed_size {
-        test_file_uncompressed_size_fetch(args);
+        patch_dups(args)
+    } else if args.calc_uncompressed_size {
+        test_file_uncompressed_size_fetch(args)
+    } else if args.resolve_mates {
+        resolve_mates(args)
+    } else if args.mask {
+        mask(args)
+    } else if args.contig_summary {
+        contig_summary(args)
+    } else if args.idxstats {
+        write_idxstats(args)
+    } else if args.stats {
+        write_seq_stats(args)
+    } else if args.fastq {
+        export_fastq(args)
+    } else if args.mpileup || args.consensus {
+        pileup(args)
+    } else if args.table {
+        export_table(args)
+    } else if args.arrow || args.parquet {
+        export_columns(args)
+    } else if args.merge {
+        merge(args, full_command)
+    } else if let Some(n) = args.partitions {
+        print_partitions(args, n)
+    } else if args.pair_summary {
+        pair_summary(args)
+    } else if args.read_group_stats {
+        read_group_stats(args)
+    } else if args.fingerprint || args.store_fingerprint || args.verify_fingerprint {
+        fingerprint(args)
+    } else if args.validate_reference {
+        check_reference(args)
+    } else if args.markdup {
+        mark_duplicates(args, full_command)
+    } else if let Some(fraction) = args.subsample {
+        subsample_templates(args, fraction, full_command)
+    } else if args.target_coverage.is_some() {
+        downsample_to_coverage(args, full_command)
+    } else if args.shared_blocks || args.dedup_store.is_some() || args.dedup_restore.is_some() || args.dedup_list {
+        dedup(args)
+    } else if args.clip_report {
+        clip_report(args)
+    } else if let Some(window) = args.length_track {
+        read_length_track(args, window)
+    } else if let Some(n) = args.sample {
+        sample_estimates(args, n)
+    } else if let Some(name) = args.read_name.clone() {
+        view_read_name(args, &name)
+    } else {
+        if args.interval_index {
+            store_interval_index(&args.in_path)?;
+        }
+        if args.name_index {
+            store_name_index(&args.in_path)?;
+        }
+        if args.binary_meta {
+            store_binary_meta(&args.in_path, true)?;
+        }
+        Ok(())
     }
 }
 
-fn convert(args: Cli, full_command: String) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path");
-    let out_path = args
-        .out_path
-        .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
-    if args.sort {
-        bam_sort_to_gbam(in_path, out_path, Codecs::Lz4, args.sort_temp_mode, args.temp_dir, full_command, args.index_sort);
+/// Prints `error` and exits with its exit code.
+fn exit_with_error(error: GbamError) -> ! {
+    eprintln!("Error: {}", error);
+    std::process::exit(error.exit_code());
+}
+
+fn invalid_argument(message: impl ToString) -> GbamError {
+    GbamError::InvalidArgument(message.to_string())
+}
+
+/// Paths are passed to converters as strings.
+fn path_str(path: &Path) -> Result<&str, GbamError> {
+    path.to_str().ok_or_else(|| invalid_argument(format!("Path {} is not valid UTF-8.", path.display())))
+}
+
+/// -o path of operations writing GBAM or BAM files.
+fn out_path(args: &Cli) -> Result<&Path, GbamError> {
+    args.out_path.as_deref().ok_or_else(|| invalid_argument("Output path is mandatory for this operation."))
+}
+
+fn convert(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let in_path = path_str(&args.in_path)?;
+    let out_path = path_str(out_path(&args)?)?;
+    if args.append {
+        if in_path.ends_with(".sam") || in_path.ends_with(".sam.gz") {
+            sam_append_to_gbam(in_path, out_path, 8)?;
+        } else if in_path.ends_with(".cram") {
+            return Err(invalid_argument("Appending CRAM is not supported. Convert it to BAM or SAM first."));
+        } else {
+            bam_append_to_gbam(in_path, out_path, 8);
+        }
+        return Ok(());
+    }
+    let codecs = get_codecs(args.store_fields.as_deref())?;
+    let tag_columns = parse_tags(args.tag_columns.as_deref())?;
+    if !tag_columns.is_empty() && (args.sort || in_path.ends_with(".cram")) {
+        return Err(invalid_argument("Tag columns are supported for conversion of BAM or SAM without sorting only."));
+    }
+    if out_path == "-" && (args.sort || args.interval_index || args.name_index || args.reference.is_some() || args.binary_meta || !(in_path.ends_with(".sam") || in_path.ends_with(".sam.gz"))) {
+        return Err(invalid_argument("Only SAM input converted without sorting or indexes can be written to stdout."));
+    }
+    let sort_by: SortBy = args.sort_by.as_deref().map_or(Ok(SortBy::Coordinate), str::parse).map_err(invalid_argument)?;
+    // Coordinate sort of BAM input is done by bam_tools sorter.
+    let sorted_by_gbam = in_path.ends_with(".sam")
+        || in_path.ends_with(".sam.gz")
+        || in_path.ends_with(".gbam")
+        || sort_by == SortBy::QueryName;
+    if in_path.ends_with(".cram") {
+        if args.sort {
+            return Err(invalid_argument("Sorting is not supported for CRAM input."));
+        }
+        let reference = args.reference.as_deref().map(path_str).transpose()?;
+        cram_to_gbam(in_path, out_path, reference, codecs, args.thread_num.unwrap_or(8), full_command)?;
+    } else if args.sort && sorted_by_gbam {
+        let mem_limit = args.sort_mem.map_or(DEFAULT_MEM_LIMIT, |mb| mb * MEGA_BYTE_SIZE);
+        sort_to_gbam(in_path, out_path, codecs, sort_by, mem_limit, args.temp_dir.as_deref(), full_command)?;
+    } else if in_path.ends_with(".sam") || in_path.ends_with(".sam.gz") {
+        let (bar, progress) = conversion_progress();
+        sam_to_gbam(in_path, out_path, codecs, &tag_columns, full_command, Some(progress))?;
+        bar.finish();
+    } else if args.sort {
+        bam_sort_to_gbam(in_path, out_path, codecs, args.sort_temp_mode.clone(), args.temp_dir.clone(), full_command, args.index_sort);
     } else {
-        bam_to_gbam(in_path, out_path, Codecs::Lz4, full_command);
+        let (bar, progress) = conversion_progress();
+        bam_to_gbam(in_path, out_path, codecs, &tag_columns, full_command, Some(progress));
+        bar.finish();
+    }
+    if args.interval_index {
+        store_interval_index(out_path)?;
+    }
+    if args.name_index {
+        store_name_index(out_path)?;
+    }
+    if let Some(reference) = args.reference.as_ref() {
+        store_reference_checksums(out_path, reference)?;
+    }
+    if args.binary_meta {
+        store_binary_meta(out_path, true)?;
     }
+    Ok(())
 }
 
-fn convert_to_bam(args: Cli) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path.");
-    let out_path = args
-        .out_path
-        .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
-    gbam_to_bam(in_path, out_path);
+/// LZ4 for every field except the ones requested to be stored uncompressed.
+fn get_codecs(store_fields: Option<&str>) -> Result<Vec<Codecs>, GbamError> {
+    let mut codecs = vec![Codecs::Lz4; FIELDS_NUM];
+    for field in parse_fields(store_fields)? {
+        codecs[field as usize] = Codecs::NoCompression;
+    }
+    Ok(codecs)
+}
+
+/// Parses comma separated list of field names.
+fn parse_fields(fields: Option<&str>) -> Result<Vec<Fields>, GbamError> {
+    fields
+        .into_iter()
+        .flat_map(|fields| fields.split(','))
+        .map(|field| field.parse::<Fields>().map_err(invalid_argument))
+        .collect()
+}
+
+/// Two letter tag names, e.g. `NM,AS`.
+fn parse_tags(tags: Option<&str>) -> Result<Vec<[u8; 2]>, GbamError> {
+    tags.into_iter()
+        .flat_map(|tags| tags.split(','))
+        .map(|tag| tag.as_bytes().try_into().map_err(|_| invalid_argument(format!("Invalid tag name {}.", tag))))
+        .collect()
+}
+
+fn parse_thresholds(thresholds: Option<&str>) -> Result<Vec<u32>, GbamError> {
+    thresholds
+        .into_iter()
+        .flat_map(|thresholds| thresholds.split(','))
+        .map(|threshold| threshold.parse().map_err(|_| invalid_argument(format!("Invalid threshold {}.", threshold))))
+        .collect()
+}
+
+fn convert_to_bam(args: Cli) -> Result<(), GbamError> {
+    gbam_to_bam(path_str(&args.in_path)?, path_str(out_path(&args)?)?)?;
+    Ok(())
 }
 
-fn flagstat(args: Cli) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path.");
+fn flagstat(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let stats = collect_stats(file)?;
+    let mut out = output_sink(&args)?;
+    writeln!(out, "{}", stats)?;
+    out.finish()?;
+    Ok(())
+}
 
-    let file = File::open(in_path).unwrap();
-    collect_stats(file);
+fn count(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new_with_index(file, ParsingTemplate::new(), index_mapping(&args)?)?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(0));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let regions = view_regions(&args, &mut reader)?;
+    let count = reader.count_records(regions.as_deref())?;
+    let mut out = output_sink(&args)?;
+    writeln!(out, "{}", count)?;
+    out.finish()?;
+    Ok(())
 }
 
-fn test(args: Cli) {
+/// Spinner with records converted and bytes written, drawn only if stderr
+/// is a terminal.
+fn conversion_progress() -> (ProgressBar, ProgressCallback) {
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}"));
+    bar.enable_steady_tick(100);
+    let spinner = bar.clone();
+    let callback: ProgressCallback = Box::new(move |progress: WriteProgress| {
+        spinner.set_message(format!("{} records, {} written", progress.records, HumanBytes(progress.bytes_written)));
+    });
+    (bar, callback)
+}
+
+fn inspect(args: Cli) -> Result<(), GbamError> {
+    let reader = Reader::new(File::open(&args.in_path)?, ParsingTemplate::new())?;
+    let stats = reader.stats();
+    let name = |column: &ColumnSize| match column.tag {
+        Some(tag) => format!("{}:{}", column.field, String::from_utf8_lossy(&tag)),
+        None => column.field.to_string(),
+    };
+    let blocks: usize = stats.columns.iter().map(|column| column.blocks).sum();
+    let compressed: u64 = stats.columns.iter().map(|column| column.compressed_bytes).sum();
+    let uncompressed: u64 = stats.columns.iter().map(|column| column.uncompressed_bytes).sum();
+    let mut out = output_sink(&args)?;
+    if args.json {
+        let columns: Vec<_> = stats
+            .columns
+            .iter()
+            .map(|column| {
+                serde_json::json!({
+                    "column": name(column),
+                    "codec": format!("{:?}", column.codec),
+                    "blocks": column.blocks,
+                    "compressed_bytes": column.compressed_bytes,
+                    "uncompressed_bytes": column.uncompressed_bytes,
+                    "ratio": column.ratio(),
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "records": stats.records,
+            "blocks": blocks,
+            "compressed_bytes": compressed,
+            "uncompressed_bytes": uncompressed,
+            "columns": columns,
+        });
+        writeln!(out, "{}", serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?)?;
+        out.finish()?;
+        return Ok(());
+    }
+    let ratio = |ratio: Option<f64>| ratio.map_or_else(|| "-".to_owned(), |ratio| format!("{:.2}", ratio));
+    writeln!(out, "records\t{}", stats.records)?;
+    writeln!(out, "column\tcodec\tblocks\tcompressed_bytes\tuncompressed_bytes\tratio")?;
+    for column in &stats.columns {
+        writeln!(out, "{}\t{:?}\t{}\t{}\t{}\t{}", name(column), column.codec, column.blocks, column.compressed_bytes, column.uncompressed_bytes, ratio(column.ratio()))?;
+    }
+    let total_ratio = (compressed > 0).then(|| uncompressed as f64 / compressed as f64);
+    writeln!(out, "total\t\t{}\t{}\t{}\t{}", blocks, compressed, uncompressed, ratio(total_ratio))?;
+    out.finish()?;
+    Ok(())
+}
+
+fn slice(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new())?;
+    let range = match (args.head, args.slice.as_deref()) {
+        (Some(n), _) => 0..n.min(reader.amount),
+        (None, Some(spec)) => match spec.split_once("..").map(|(start, end)| (start.parse(), end.parse())) {
+            Some((Ok(start), Ok(end))) => start..end,
+            _ => {
+                let region = Region::parse(spec, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?;
+                reader.region_range(&region)?
+            }
+        },
+        (None, None) => unreachable!(),
+    };
+    let out = BufWriter::new(File::create(out_path(&args)?)?);
+    let stats = slice_gbam(reader.storage.as_ref().as_ref(), range, out, full_command)?;
+    eprintln!("Records: {}, blocks copied: {}, blocks re-encoded: {}", stats.records, stats.copied_blocks, stats.encoded_blocks);
+    Ok(())
+}
+
+fn rescue(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let bytes = unsafe { Mmap::map(&file)? };
+    let out = BufWriter::new(File::create(out_path(&args)?)?);
+    let stats = rescue_gbam(&bytes, out, full_command)?;
+    eprintln!("Blocks found: {}, records recovered: {}", stats.found_blocks, stats.records);
+    Ok(())
+}
+
+fn check(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let bytes = unsafe { Mmap::map(&file)? };
+    let problems = check_gbam(std::sync::Arc::new(bytes));
+    if problems.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for problem in problems {
+        println!("{}", problem);
+    }
+    std::process::exit(1);
+}
+
+fn test(args: Cli) -> Result<(), GbamError> {
     let mut tmplt = ParsingTemplate::new();
     tmplt.set(&Fields::RawCigar, true);
 
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let file = File::open(&args.in_path)?;
 
-    let mut reader = Reader::new(file, tmplt).unwrap();
+    let mut reader = Reader::new(file, tmplt)?;
     let mut records = reader.records();
     let now = Instant::now();
 
@@ -200,21 +795,21 @@ fn test(args: Cli) {
         now.elapsed().as_millis()
     );
     drop(records);
+    Ok(())
 }
 
-fn test_parallel_cigar_fetch(args: Cli) {
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-    let temp_reader = Reader::new(file.try_clone().unwrap(), ParsingTemplate::new()).unwrap();
+fn test_parallel_cigar_fetch(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let temp_reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
     let file_meta = temp_reader.file_meta;
     let total_records = temp_reader.amount;
     let now = Instant::now();
-    
-    (0..total_records).into_par_iter().chunks(500_000).for_each(|records_range| {
+    (0..total_records).into_par_iter().chunks(500_000).try_for_each(|records_range| -> Result<(), GbamError> {
         let mut rec =  GbamRecord::default();
         let mut tmplt = ParsingTemplate::new();
         tmplt.set(&Fields::RawCigar, true);
-    
-        let mut reader = Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
+
+        let mut reader = Reader::new_with_meta(file.try_clone()?, tmplt, &file_meta, None)?;
 
         let mut collector = Vec::with_capacity(records_range.len());
 
@@ -222,80 +817,174 @@ fn test_parallel_cigar_fetch(args: Cli) {
             reader.fill_record(rec_num, &mut rec);
             collector.push(base_coverage(&rec.cigar.as_ref().unwrap().0[..]));
         }
-    });
+        Ok(())
+    })?;
 
     println!(
         "Fetching CIGAR in parallel took: {}",
         now.elapsed().as_millis()
     );
+    Ok(())
 }
 
-fn test_file_uncompressed_size_fetch(args: Cli) {
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+fn test_file_uncompressed_size_fetch(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
 
-    let file_sz = file.metadata().unwrap().len();
+    let file_sz = file.metadata()?.len();
     if file_sz == 0 {
         println!("File is empty.");
-        return;
+        return Ok(());
     }
 
     let mut reader = file;
-    
+
 
     let mut buf: [u8; 1000] = [0; 1000];
     const OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE : usize = 128/8;
     let mut total_uncrompressed_size_of_file : usize = 0;
     const ERR : &str = "Couldn't parse the bgzf block.";
+    let bgzf_error = || std::io::Error::new(std::io::ErrorKind::InvalidData, ERR);
     loop {
-        let cur_reader_pos = reader.seek(std::io::SeekFrom::Current(0)).unwrap();
+        let cur_reader_pos = reader.stream_position()?;
         if file_sz == cur_reader_pos {
             break;
         }
         if file_sz-cur_reader_pos == 28 {
             break;
         }
-        reader.read_exact(&mut buf[..OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE]).expect(ERR); 
-        let block_size = reader.read_u16::<LittleEndian>().expect(ERR)+1;
+        reader.read_exact(&mut buf[..OFFEST_IN_BGZF_FILE_TILL_BLOCK_SIZE_VALUE]).map_err(|_| bgzf_error())?;
+        let block_size = reader.read_u16::<LittleEndian>().map_err(|_| bgzf_error())?+1;
         let uncompressed_info_start = cur_reader_pos+block_size as u64 - std::mem::size_of::<u32>() as u64;
-        assert!(uncompressed_info_start < file_sz);
-        reader.seek(std::io::SeekFrom::Start(uncompressed_info_start)).unwrap();
-        let uncompressed_block_size = reader.read_u32::<LittleEndian>().expect(ERR);
+        if uncompressed_info_start >= file_sz {
+            return Err(bgzf_error().into());
+        }
+        reader.seek(std::io::SeekFrom::Start(uncompressed_info_start))?;
+        let uncompressed_block_size = reader.read_u32::<LittleEndian>().map_err(|_| bgzf_error())?;
         total_uncrompressed_size_of_file += uncompressed_block_size as usize;
-        
+
     }
 
     println!("Total uncompressed size of file is: {}", total_uncrompressed_size_of_file);
+    Ok(())
 }
 
-fn read_index(index: PathBuf) -> Option<std::sync::Arc<Vec<u32>>> {
-    let file = File::open(index).unwrap();
-    let size = file.metadata().unwrap().len();
+fn read_index(index: &Path) -> std::io::Result<std::sync::Arc<Vec<u32>>> {
+    let file = File::open(index)?;
+    let size = file.metadata()?.len();
     let mut f = std::io::BufReader::new(file);
-    let mut res = vec![0 as u32; (size/(std::mem::size_of::<u32>() as u64)).try_into().unwrap()];
+    let mut res = vec![0 as u32; (size / std::mem::size_of::<u32>() as u64) as usize];
 
     for slot in &mut res {
-        *slot = f.read_u32::<LittleEndian>().unwrap();
+        *slot = f.read_u32::<LittleEndian>()?;
+    }
+
+    Ok(std::sync::Arc::new(res))
+}
+
+/// Record order of --index-file if it is given.
+fn index_mapping(args: &Cli) -> Result<Option<std::sync::Arc<Vec<u32>>>, GbamError> {
+    Ok(args.index_file.as_deref().map(read_index).transpose()?)
+}
+
+fn depth(args: Cli) -> Result<(), GbamError> {
+    let gbam_file = File::open(&args.in_path)?;
+    // Depth cache is built without index file.
+    let index_file = if args.depth_cache.is_some() { None } else { index_mapping(&args)? };
+    let reader = Reader::new(gbam_file.try_clone()?, ParsingTemplate::new())?;
+    if sorts_in_memory(&reader.file_meta, &index_file) {
+        eprintln!("File is not known to be coordinate sorted (sort order: {:?}). Sorting records in memory.", reader.file_meta.sort_order());
+    }
+    if let Some(cache_path) = args.depth_cache.as_ref() {
+        let mut cache = DepthCache::load_or_new(cache_path, reader.file_meta.get_ref_seqs())?;
+        drop(reader);
+        let cached_records = cache.records;
+        cache.update(gbam_file)?;
+        eprintln!("Depth cache: {} records cached, {} added.", cached_records, cache.records - cached_records);
+        cache.save(cache_path)?;
+
+        let mut out = output_sink(&args)?;
+        cache.write_bed_graph(&mut out)?;
+        out.finish()?;
+        return index_depth_output(&args, TabixLayout::Bed);
+    }
+    // Regions of equal depth are written if output path is given.
+    let mode = match args.by {
+        Some(window) => DepthOutput::Windows(window),
+        None if args.quantize.is_some() => {
+            let spec = args.quantize.as_deref().unwrap();
+            DepthOutput::Quantized(Quantize::parse(spec)?)
+        }
+        None if args.region_summary || args.thresholds.is_some() => DepthOutput::Regions { thresholds: parse_thresholds(args.thresholds.as_deref())? },
+        None if args.out_path.as_ref().is_some_and(|path| path.extension().is_some_and(|ext| ext == "bw" || ext == "bigwig")) => DepthOutput::BigWig,
+        None if args.out_path.as_ref().is_some_and(|path| path.extension().is_some_and(|ext| ext == "d4")) => DepthOutput::D4,
+        None if args.out_path.is_some() && !args.per_base => DepthOutput::BedGraph,
+        None => DepthOutput::PerBase,
+    };
+    let output = output_sink(&args)?;
+    // Written alongside the output, as mosdepth.summary.txt.
+    let summary = if args.summary {
+        let out_path = args.out_path.as_deref().ok_or_else(|| invalid_argument("--summary needs output path (-o)."))?;
+        let summary_path = format!("{}.summary.txt", path_str(out_path)?);
+        Some(open_sink(Some(&summary_path), false)?)
+    } else {
+        None
+    };
+    let index_layout = match mode {
+        DepthOutput::BigWig | DepthOutput::D4 => None,
+        DepthOutput::PerBase => Some(TabixLayout::Position),
+        _ => Some(TabixLayout::Bed),
+    };
+    let options = DepthOptions {
+        min_mapq: args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8),
+        include_flags: args.require_flags,
+        exclude_flags: args.exclude_flags.unwrap_or(0),
+        skip_deletions: args.cigar_aware && !args.count_deletions,
+        skip_ref_skips: args.cigar_aware,
+        min_base_quality: args.min_base_quality,
+        mate_overlap: args.fast_mate_correction,
+    };
+    drop(reader);
+    main_depth(gbam_file, args.bed_file.as_ref(), index_file, args.query.clone(), options, output, summary, mode, args.tile_size, args.thread_num)?;
+    match index_layout {
+        Some(layout) => index_depth_output(&args, layout),
+        None => Ok(()),
     }
+}
+
+/// Writes tabix index next to BGZF compressed local depth output.
+fn index_depth_output(args: &Cli, layout: TabixLayout) -> Result<(), GbamError> {
+    if let Some(path) = args.out_path.as_ref() {
+        let name = path.to_string_lossy();
+        let compressed = args.bgzip || name.ends_with(".gz") || name.ends_with(".bgz");
+        if compressed && !name.contains("://") && name != "-" {
+            let index_path = index_bed_gz(path, layout)?;
+            eprintln!("Tabix index written to {}.", index_path.display());
+        }
+    }
+    Ok(())
+}
 
-    Some(std::sync::Arc::new(res))
+fn read_length_track(args: Cli, window: u32) -> Result<(), GbamError> {
+    let gbam_file = File::open(&args.in_path)?;
+    let mut out = output_sink(&args)?;
+    length_track(gbam_file, index_mapping(&args)?, window, args.length_stat, &mut out)?;
+    out.finish()?;
+    Ok(())
 }
 
-fn depth(args: Cli) {
-    let in_path = args.in_path.as_path().to_str().unwrap();
-    let gbam_file = File::open(in_path).unwrap();
-    main_depth(gbam_file, args.bed_file.as_ref(), args.index_file.and_then(read_index), args.query, args.mapq, args.out_path, args.thread_num);
+fn sample_estimates(args: Cli, n: usize) -> Result<(), GbamError> {
+    let estimates = SampleEstimates::collect(File::open(&args.in_path)?, n, u64::from(args.seed))?;
+    let mut out = output_sink(&args)?;
+    write_estimates(&mut out, &estimates)?;
+    out.finish()?;
+    Ok(())
 }
 
-fn view_header(args: Cli){
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-    let reader = Reader::new(file, ParsingTemplate::new()).unwrap();
-    
-    let header_len = (&reader.file_meta.get_sam_header()[..std::mem::size_of::<u32>()]).read_u32::<LittleEndian>().unwrap() as usize;
-    let header_bytes = reader.file_meta.get_sam_header()[std::mem::size_of::<u32>()..std::mem::size_of::<u32>()+header_len].to_owned();
-    let header = 
-        String::from_utf8(header_bytes).unwrap();
-   
-    println!("{}", header);
+fn view_header(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let reader = Reader::new(file, ParsingTemplate::new())?;
+    println!("{}", reader.header().text());
+    Ok(())
 }
 
 fn parse_tag(tags: &[u8], target_tag: &str) -> Option<String> {
@@ -331,66 +1020,604 @@ fn parse_tag(tags: &[u8], target_tag: &str) -> Option<String> {
     None
 }
 
+/// Closed pipe (e.g. piping into head) is not an error.
+fn ignore_broken_pipe(written: std::io::Result<()>) -> Result<(), GbamError> {
+    match written {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
 
-fn view_file(args: Cli, template: ParsingTemplate){
-    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-
-    let mut reader = Reader::new_with_index(file, template, args.index_file.and_then(read_index)).unwrap();
+fn view_file(args: Cli, mut template: ParsingTemplate) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut out = output_sink(&args)?;
+    let pair_filter = pair_filter(&args, &mut template)?;
 
-    let st = std::io::stdout();
-    let lock = st.lock();
-    let mut stdout = BufWriter::with_capacity(64 * 1024, lock);
+    let mut reader = Reader::new_with_index(file, template, index_mapping(&args)?)?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(0));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let io_stats = io_stats(&args, &mut reader);
 
     const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
-    stdout.write_all(BAM_MAGIC).unwrap();
-    stdout.write_all(reader.file_meta.get_sam_header()).unwrap();
-    
-    let mut records = reader.records();
+    let mut written = out.write_all(BAM_MAGIC).and_then(|_| out.write_all(reader.file_meta.get_sam_header()));
+
+    let partition = args
+        .partition
+        .as_ref()
+        .map(|json| serde_json::from_str::<Partition>(json).map_err(|e| invalid_argument(format!("Invalid partition descriptor: {}.", e))))
+        .transpose()?;
+    let file_meta = reader.file_meta.clone();
+    let regions = view_regions(&args, &mut reader)?;
+    let mut records = match (partition.as_ref(), regions) {
+        (Some(_), Some(_)) => return Err(invalid_argument("Partition and regions can't be viewed together.")),
+        (Some(partition), None) => reader.partition_records(partition),
+        (None, Some(regions)) => reader.fetch_regions(&regions)?,
+        (None, None) => reader.records(),
+    };
     let mut buf = Vec::new();
-    while let Some(rec) = records.next_rec() {
+    while let (Ok(()), Some(rec)) = (&written, records.next_rec()) {
+        let annotation = pair_filter.as_ref().map(|filter| (filter, filter.annotate(rec)));
+        if let Some((filter, annotation)) = annotation.as_ref() {
+            if !filter.matches(annotation.as_ref()) {
+                continue;
+            }
+        }
         rec.convert_to_bytes(&mut buf);
-        if stdout.write_all(&buf).is_err() {
-            break;
+        if let (true, Some((_, Some(annotation)))) = (args.annotate_pairs, annotation) {
+            annotation.append_bam_tags(&mut buf);
         }
+        written = out.write_all(&buf);
     }
+    ignore_broken_pipe(written.and_then(|_| out.finish()))?;
+    print_io_stats(io_stats, &file_meta)
 }
 
+fn view_sam(args: Cli, mut template: ParsingTemplate) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut out = output_sink(&args)?;
+    let pair_filter = pair_filter(&args, &mut template)?;
+    let mut reader = Reader::new_with_index(file, template, index_mapping(&args)?)?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(0));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let io_stats = io_stats(&args, &mut reader);
 
+    let file_meta = reader.file_meta.clone();
+    let mut written = write_sam_header(&file_meta, &mut out);
+    let regions = view_regions(&args, &mut reader)?;
+    let mut records = match regions {
+        Some(regions) => reader.fetch_regions(&regions)?,
+        None => reader.records(),
+    };
+    let mut line = Vec::new();
+    while let (Ok(()), Some(rec)) = (&written, records.next_rec()) {
+        let annotation = pair_filter.as_ref().map(|filter| (filter, filter.annotate(rec)));
+        if let Some((filter, annotation)) = annotation.as_ref() {
+            if !filter.matches(annotation.as_ref()) {
+                continue;
+            }
+        }
+        line.clear();
+        format_sam_record(rec, file_meta.get_ref_seqs(), &mut line);
+        if let (true, Some((_, Some(annotation)))) = (args.annotate_pairs, annotation) {
+            annotation.append_sam_tags(&mut line);
+        }
+        written = out.write_all(&line);
+    }
+    ignore_broken_pipe(written.and_then(|_| out.finish()))?;
+    print_io_stats(io_stats, &file_meta)
+}
 
-fn patch_dups(args: Cli){
+/// Views regions of file given by URL (see [`open_source`]), downloading
+/// only blocks of the regions. Filters are not applied.
+fn view_remote(args: Cli, template: ParsingTemplate) -> Result<(), GbamError> {
+    let source = open_source(path_str(&args.in_path)?)?;
+    let reader = block_on(RemoteReader::open(source))?;
+    let file_meta = reader.file_meta().clone();
+    let regions = cli_regions(&args, file_meta.get_ref_seqs())?.ok_or_else(|| invalid_argument("Remote files are viewed by regions, give -q or -b."))?;
+    let fields: Vec<Fields> = template.get_active_data_fields_iter().copied().collect();
+    let mut slice = block_on(reader.fetch_regions(&regions, &fields))?;
+
+    let mut out = output_sink(&args)?;
+    let mut written = if args.sam {
+        write_sam_header(&file_meta, &mut out)
+    } else {
+        out.write_all(b"BAM\x01").and_then(|_| out.write_all(file_meta.get_sam_header()))
+    };
+    let mut records = slice.records();
+    let mut buf = Vec::new();
+    while let (Ok(()), Some(rec)) = (&written, records.next_rec()) {
+        buf.clear();
+        if args.sam {
+            format_sam_record(rec, file_meta.get_ref_seqs(), &mut buf);
+        } else {
+            rec.convert_to_bytes(&mut buf);
+        }
+        written = out.write_all(&buf);
+    }
+    ignore_broken_pipe(written.and_then(|_| out.finish()))
+}
+
+fn view_read_name(args: Cli, name: &str) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut template = ParsingTemplate::new();
+    template.set_all_except(&parse_fields(args.exclude_fields.as_deref())?);
+    let mut reader = Reader::new(file, template)?;
+    let io_stats = io_stats(&args, &mut reader);
+    let mut out = output_sink(&args)?;
+    let mut rec = GbamRecord::default();
+    let mut line = Vec::new();
+    for rec_num in reader.find_by_name(name.as_bytes())? {
+        reader.fill_record(rec_num, &mut rec);
+        line.clear();
+        format_sam_record(&rec, reader.file_meta.get_ref_seqs(), &mut line);
+        out.write_all(&line)?;
+    }
+    out.finish()?;
+    print_io_stats(io_stats, &reader.file_meta)
+}
+
+/// Counts blocks read by `reader` if --io-stats is given.
+fn io_stats(args: &Cli, reader: &mut Reader) -> Option<SharedIoStats> {
+    if !args.io_stats {
+        return None;
+    }
+    let stats = IoStats::shared();
+    reader.set_io_stats(Some(stats.clone()));
+    Some(stats)
+}
+
+fn print_io_stats(stats: Option<SharedIoStats>, file_meta: &FileMeta) -> Result<(), GbamError> {
+    if let Some(stats) = stats {
+        // Counters stay valid if a reading thread panicked.
+        let stats = stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.write_report(&mut std::io::stderr(), file_meta)?;
+    }
+    Ok(())
+}
+
+/// Regions of -b BED file and -q region if they are given. Enables block
+/// cache if requested.
+fn view_regions(args: &Cli, reader: &mut Reader) -> Result<Option<Vec<Region>>, GbamError> {
+    if let Some(mb) = args.block_cache {
+        reader.set_block_cache(Some(BlockCache::shared(mb * MEGA_BYTE_SIZE)));
+    }
+    cli_regions(args, reader.file_meta.get_ref_seqs())
+}
+
+/// Regions of -b and -q, `None` if neither is given.
+fn cli_regions(args: &Cli, ref_seqs: &[(String, u32)]) -> Result<Option<Vec<Region>>, GbamError> {
+    if args.bed_file.is_none() && args.query.is_none() {
+        return Ok(None);
+    }
+    let mut regions = Vec::new();
+    if let Some(path) = args.bed_file.as_ref() {
+        let bed = parse_bed_from_file(path).map_err(|source| GbamError::InvalidBed { path: path.clone(), source })?;
+        regions = Region::from_bed(&bed, ref_seqs).map_err(invalid_argument)?;
+    }
+    if let Some(query) = args.query.as_ref() {
+        regions.push(Region::parse(query, ref_seqs).map_err(invalid_argument)?);
+    }
+    Ok(Some(regions))
+}
+
+/// Pair filter requested for view, `None` if there are no pair options.
+/// Fields needed for classification are added to `template`.
+fn pair_filter(args: &Cli, template: &mut ParsingTemplate) -> Result<Option<PairFilter>, GbamError> {
+    let needs_stats = args.min_insert_z.is_some() || args.max_insert_z.is_some() || args.annotate_pairs;
+    if args.pair_orientation.is_none() && !needs_stats {
+        return Ok(None);
+    }
+    for field in PAIR_FIELDS.iter() {
+        template.set(field, true);
+    }
+    let orientations = args
+        .pair_orientation
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(|orientation| orientation.parse().map_err(invalid_argument))
+        .collect::<Result<_, _>>()?;
+    let stats = if needs_stats {
+        InsertSizeStats::collect(File::open(&args.in_path)?)?
+    } else {
+        InsertSizeStats::default()
+    };
+    Ok(Some(PairFilter {
+        orientations,
+        min_z: args.min_insert_z,
+        max_z: args.max_insert_z,
+        stats,
+    }))
+}
+
+fn pair_summary(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut out = output_sink(&args)?;
+    write_pair_summary(file, &mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn read_group_stats(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut out = output_sink(&args)?;
+    write_read_group_stats(file, &mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn fingerprint(args: Cli) -> Result<(), GbamError> {
+    if args.verify_fingerprint {
+        let reader = Reader::new(File::open(&args.in_path)?, ParsingTemplate::new())?;
+        match verify_fingerprint(&reader)? {
+            None => return Err(invalid_argument("File has no stored fingerprint.")),
+            Some(mismatched) if mismatched.is_empty() => println!("OK"),
+            Some(mismatched) => {
+                let fields: Vec<String> = mismatched.iter().map(|field| field.to_string()).collect();
+                println!("MISMATCH\t{}", fields.join(","));
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let fingerprint = if args.store_fingerprint {
+        store_fingerprint(&args.in_path)?
+    } else {
+        let reader = Reader::new(File::open(&args.in_path)?, ParsingTemplate::new())?;
+        Fingerprint::compute(&reader)?
+    };
+    let mut out = output_sink(&args)?;
+    writeln!(out, "records\t{}", fingerprint.records)?;
+    for column in fingerprint.columns.iter() {
+        writeln!(out, "{}\t{}", column.field, column.md5)?;
+    }
+    writeln!(out, "file\t{}", fingerprint.file)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn check_reference(args: Cli) -> Result<(), GbamError> {
+    let fasta = args.reference.as_ref().ok_or_else(|| invalid_argument("--validate-reference needs --reference FASTA."))?;
+    let reader = Reader::new(File::open(&args.in_path)?, ParsingTemplate::new())?;
+    let mismatches = reader.validate_reference(fasta)?;
+    if mismatches.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for mismatch in mismatches {
+        println!("MISMATCH\t{}", mismatch);
+    }
+    std::process::exit(1);
+}
+
+fn mark_duplicates(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let stats = markdup(path_str(&args.in_path)?, path_str(out_path(&args)?)?, args.remove_duplicates, full_command)?;
+    println!("pairs\t{}", stats.pairs);
+    println!("duplicate_pairs\t{}", stats.duplicate_pairs);
+    println!("fragments\t{}", stats.fragments);
+    println!("duplicate_fragments\t{}", stats.duplicate_fragments);
+    Ok(())
+}
+
+fn downsample_to_coverage(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let out_path = path_str(out_path(&args)?)?;
+    let target = args.target_coverage.as_ref().unwrap();
+    let target: f64 = target
+        .trim_end_matches(['x', 'X'])
+        .parse()
+        .map_err(|_| invalid_argument(format!("Invalid target coverage {}.", target)))?;
+    let stats = downsample(path_str(&args.in_path)?, out_path, target, args.seed, full_command)?;
+    println!("coverage\t{:.4}", stats.coverage);
+    println!("fraction\t{:.6}", stats.fraction);
+    println!("records\t{}", stats.records);
+    println!("kept\t{}", stats.kept);
+    Ok(())
+}
+
+fn subsample_templates(args: Cli, fraction: f64, full_command: String) -> Result<(), GbamError> {
+    let subsampler = Subsampler::new(fraction, args.seed);
+    let (records, kept) = subsample(path_str(&args.in_path)?, path_str(out_path(&args)?)?, subsampler, full_command)?;
+    println!("records\t{}", records);
+    println!("kept\t{}", kept);
+    Ok(())
+}
+
+fn dedup(args: Cli) -> Result<(), GbamError> {
+    if args.dedup_list {
+        for name in DedupStore::open(&args.in_path)?.names()? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    if let Some(dir) = args.dedup_restore.as_ref() {
+        let mut out = output_sink(&args)?;
+        DedupStore::open(dir)?.restore(path_str(&args.in_path)?, &mut out)?;
+        out.finish()?;
+        return Ok(());
+    }
+    let mut paths = vec![args.in_path.clone()];
+    paths.extend(args.shards.iter().cloned());
+    if let Some(dir) = args.dedup_store.as_ref() {
+        let mut store = DedupStore::open(dir)?;
+        println!("file\tchunks\tbytes\tnew_chunks\tnew_bytes");
+        for path in paths.iter() {
+            let stats = store.store(path)?;
+            println!("{}\t{}\t{}\t{}\t{}", path.display(), stats.chunks, stats.bytes, stats.new_chunks, stats.new_bytes);
+        }
+        return Ok(());
+    }
+    println!("file\tblocks\tbytes\tshared_blocks\tshared_bytes");
+    for (path, shared) in paths.iter().zip(shared_blocks(&paths)?) {
+        println!("{}\t{}\t{}\t{}\t{}", path.display(), shared.blocks, shared.bytes, shared.shared_blocks, shared.shared_bytes);
+    }
+    Ok(())
+}
+
+fn clip_report(args: Cli) -> Result<(), GbamError> {
+    let screen = match args.adapters.as_ref() {
+        Some(path) => AdapterScreen::from_fasta(BufReader::new(File::open(path)?), 12)?,
+        None => AdapterScreen::default_adapters(),
+    };
+    let stats = collect_clip_stats(File::open(&args.in_path)?, &screen)?;
+    let mut out = output_sink(&args)?;
+    write_clip_report(&mut out, &stats, &screen)?;
+    out.finish()?;
+    Ok(())
+}
+
+/// Sink for exporter output: -o path (stdout if not given).
+fn output_sink(args: &Cli) -> Result<Box<dyn OutputSink>, GbamError> {
+    let target = args.out_path.as_deref().map(path_str).transpose()?;
+    Ok(open_sink(target, args.bgzip)?)
+}
+
+
+
+fn resolve_mates(args: Cli) -> Result<(), GbamError> {
+    let mut paths = vec![args.in_path.clone()];
+    paths.extend(args.shards.iter().cloned());
+    let files = paths.iter().map(File::open).collect::<std::io::Result<_>>()?;
+    let mut resolver = MateResolver::new(files)?;
+
+    let mut out = output_sink(&args)?;
+    // First failed write, pairs after it are skipped.
+    let mut written = Ok(());
+    resolver.split_pairs(|rec, loc, mate| {
+        if written.is_err() {
+            return;
+        }
+        let name = rec.read_name.as_ref().unwrap();
+        written = writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            String::from_utf8_lossy(&name[..name.len() - 1]),
+            paths[loc.shard].display(),
+            loc.rec_num,
+            paths[mate.shard].display(),
+            mate.rec_num
+        );
+    })?;
+    written?;
+    out.finish()?;
+    Ok(())
+}
+
+fn mask(args: Cli) -> Result<(), GbamError> {
+    let mut files = vec![File::open(&args.in_path)?];
+    for path in args.samples.iter() {
+        files.push(File::open(path)?);
+    }
+    let format = if args.mask_fasta { MaskFormat::Fasta } else { MaskFormat::Bed };
+
+    let mut out = output_sink(&args)?;
+    coverage_mask(files, args.min_depth, args.max_depth, format, &mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn contig_summary(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    drop(reader);
+
+    let groups = match args.contig_groups.as_ref() {
+        Some(path) => ContigGroups::from_reader(BufReader::new(File::open(path)?), &ref_seqs)?,
+        None => ContigGroups::new(&ref_seqs),
+    };
+    let (per_ref, unmapped) = count_per_ref(file)?;
+
+    let mut out = output_sink(&args)?;
+    write_group_summary(&mut out, &ref_seqs, &groups, &per_ref, unmapped)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn write_idxstats(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    drop(reader);
+
+    let stats = idxstats(file)?;
+    let mut out = output_sink(&args)?;
+    stats.write(&mut out, &ref_seqs)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn write_seq_stats(args: Cli) -> Result<(), GbamError> {
+    let metrics = args.metrics.as_deref().map_or_else(|| Ok(Metric::ALL.to_vec()), Metric::parse_list).map_err(invalid_argument)?;
+    let stats = collect_seq_stats(File::open(&args.in_path)?, &metrics)?;
+    let mut out = output_sink(&args)?;
+    stats.write(&mut out, &metrics)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn export_fastq(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new())?;
+    let stats = match args.out_path.as_ref() {
+        Some(prefix) => {
+            let ext = if args.bgzip { "fastq.gz" } else { "fastq" };
+            let path = |suffix: &str| format!("{}_{}.{}", prefix.display(), suffix, ext);
+            let mut read1 = open_sink(Some(&path("R1")), args.bgzip)?;
+            let mut read2 = open_sink(Some(&path("R2")), args.bgzip)?;
+            let mut singletons = open_sink(Some(&path("singletons")), args.bgzip)?;
+            let stats = gbam_to_fastq(&mut reader, &mut read1, Some(&mut read2), Some(&mut singletons))?;
+            read1.finish()?;
+            read2.finish()?;
+            singletons.finish()?;
+            stats
+        }
+        None => {
+            let mut out = output_sink(&args)?;
+            let stats = gbam_to_fastq(&mut reader, &mut out, None, None)?;
+            out.finish()?;
+            stats
+        }
+    };
+    eprintln!("{} pairs, {} singletons", stats.pairs, stats.singletons);
+    Ok(())
+}
+
+fn pileup(args: Cli) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new())?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(DEFAULT_EXCLUDE_FLAGS));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let regions = view_regions(&args, &mut reader)?;
+    let mut out = output_sink(&args)?;
+    if args.consensus {
+        write_consensus(&mut reader, regions.as_deref(), args.min_base_quality, &mut out)?;
+    } else {
+        write_mpileup(&mut reader, regions.as_deref(), args.min_base_quality, &mut out)?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+fn export_table(args: Cli) -> Result<(), GbamError> {
+    let columns = TableColumn::parse_list(&args.columns).map_err(invalid_argument)?;
+    let fields = TableColumn::fields(&columns);
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new_with(&fields))?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(0));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let regions = view_regions(&args, &mut reader)?;
+    let records = match regions {
+        Some(regions) => reader.fetch_regions(&regions)?,
+        None => reader.records(),
+    };
+    let mut out = output_sink(&args)?;
+    write_table(records, &columns, &ref_seqs, if args.csv { b',' } else { b'\t' }, &mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn export_columns(args: Cli) -> Result<(), GbamError> {
+    let mut fields = parse_fields(args.fields.as_deref())?;
+    if fields.is_empty() {
+        fields = Fields::iterator().copied().filter(is_data_field).collect();
+    }
+    let file = File::open(&args.in_path)?;
+    let mut reader = Reader::new(file, ParsingTemplate::new_with(&fields))?;
+    reader.set_flag_filter(args.require_flags, args.exclude_flags.unwrap_or(0));
+    reader.set_min_mapq(args.mapq.map_or(0, |mapq| mapq.min(u32::from(u8::MAX)) as u8));
+    if let Some(expr) = args.expr.as_deref() {
+        reader.set_record_filter(Some(RecordFilter::compile(expr, reader.file_meta.get_ref_seqs()).map_err(invalid_argument)?));
+    }
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let regions = view_regions(&args, &mut reader)?;
+    let records = match regions {
+        Some(regions) => reader.fetch_regions(&regions)?,
+        None => reader.records(),
+    };
+    let batches = RecordBatches::new(records, &fields, &ref_seqs, DEFAULT_BATCH_SIZE);
+    if args.parquet {
+        let out = BufWriter::new(File::create(out_path(&args)?)?);
+        write_parquet(batches, out).map_err(std::io::Error::other)?;
+    } else {
+        let mut out = output_sink(&args)?;
+        write_ipc_stream(batches, &mut out).map_err(std::io::Error::other)?;
+        out.finish()?;
+    }
+    Ok(())
+}
+
+fn merge(args: Cli, full_command: String) -> Result<(), GbamError> {
+    let mut files = vec![File::open(&args.in_path)?];
+    for path in args.shards.iter() {
+        files.push(File::open(path)?);
+    }
+    let out_path = path_str(out_path(&args)?)?;
+    merge_gbam(files, out_path, get_codecs(args.store_fields.as_deref())?, full_command)?;
+    Ok(())
+}
+
+fn print_partitions(args: Cli, n: usize) -> Result<(), GbamError> {
+    let file = File::open(&args.in_path)?;
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let reader = Reader::new(file, template)?;
+
+    let mut out = output_sink(&args)?;
+    for partition in reader.partitions(n) {
+        writeln!(out, "{}", serde_json::to_string(&partition).map_err(std::io::Error::from)?)?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+fn patch_dups(args: Cli) -> Result<(), GbamError> {
 
     let file = OpenOptions::new()
         .write(true)
         .read(true)
-        .open(args.in_path.as_path().to_str().unwrap())
-        .unwrap();
+        .open(&args.in_path)?;
 
-    let reader = Reader::new_with_index(file.try_clone().unwrap(), ParsingTemplate::new(), args.index_file.and_then(read_index)).unwrap();
+    let reader = Reader::new_with_index(file.try_clone()?, ParsingTemplate::new(), index_mapping(&args)?)?;
     let file_meta = reader.file_meta.clone();
 
     let mut buf = Vec::new();
 
-    let codec = file_meta.get_field_codec(&Fields::Flags);
-    assert!(codec == &Codecs::NoCompression);
+    if file_meta.get_field_codec(&Fields::Flags) != &Codecs::NoCompression {
+        return Err(invalid_argument("Flags column has to be stored without compression to be patched."));
+    }
 
-    let mut read_manual = BufReader::with_capacity(MEGA_BYTE_SIZE, file.try_clone().unwrap());
-    let mut write_manual = BufWriter::with_capacity(MEGA_BYTE_SIZE, file.try_clone().unwrap());
+    let mut read_manual = BufReader::with_capacity(MEGA_BYTE_SIZE, file.try_clone()?);
+    let mut write_manual = BufWriter::with_capacity(MEGA_BYTE_SIZE, file.try_clone()?);
     for block in file_meta.view_blocks(&Fields::Flags){
         let available_in_block = block.numitems;
         buf.resize(block.block_size as usize, 0);
-        read_manual.seek(SeekFrom::Start(block.seekpos)).unwrap();
-        read_manual.read_exact(&mut buf).unwrap();
+        read_manual.seek(SeekFrom::Start(block.seekpos))?;
+        read_manual.read_exact(&mut buf)?;
         let slice = &mut buf[..];
         for (chunk, is_dup) in zip_eq(slice.chunks_mut(2), std::io::stdin().lock().lines().take(available_in_block as usize)){
-            let mut val = (&chunk[..]).read_u16::<byteorder::LittleEndian>().unwrap();
-            if is_dup.unwrap() == "1" {
+            let mut val = (&chunk[..]).read_u16::<byteorder::LittleEndian>()?;
+            if is_dup? == "1" {
                 val = val | 0x400;
             }
-            (&mut chunk[..]).write_u16::<byteorder::LittleEndian>(val).unwrap();
+            (&mut chunk[..]).write_u16::<byteorder::LittleEndian>(val)?;
         }
-        write_manual.seek(SeekFrom::Start(block.seekpos)).unwrap();
-        write_manual.write_all(&buf).unwrap();
+        write_manual.seek(SeekFrom::Start(block.seekpos))?;
+        write_manual.write_all(&buf)?;
     }
+    write_manual.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
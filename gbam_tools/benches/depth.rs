@@ -0,0 +1,27 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use gbam_tools::query::depth::{prefix_sum, prefix_sum_scalar};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn sweep_line(len: usize) -> Vec<i32> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..len).map(|_| rng.gen_range(-2..=2)).collect()
+}
+
+fn bench_prefix_sum(c: &mut Criterion) {
+    // Tile which fits in cache and sweep line of a 10 Mb reference sequence.
+    for len in [1 << 16, 10_000_000] {
+        let values = sweep_line(len);
+        let mut group = c.benchmark_group(format!("prefix_sum/{}", len));
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_function("scalar", |b| {
+            b.iter_batched_ref(|| values.clone(), |values| prefix_sum_scalar(black_box(values)), BatchSize::LargeInput)
+        });
+        group.bench_function("simd", |b| {
+            b.iter_batched_ref(|| values.clone(), |values| prefix_sum(black_box(values)), BatchSize::LargeInput)
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_prefix_sum);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+/* C API of GBAM reader, exported by the gbam_tools shared library. */
+#ifndef GBAM_H
+#define GBAM_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct GbamReader gbam_reader;
+
+/* Pointers stay valid until the next call with the same reader. */
+typedef struct {
+    int32_t ref_id;
+    int32_t pos; /* 0-based */
+    uint8_t mapq;
+    uint16_t bin;
+    uint16_t flag;
+    int32_t next_ref_id;
+    int32_t next_pos;
+    int32_t tlen;
+    const char *read_name; /* NUL terminated */
+    const uint32_t *cigar; /* length << 4 | op, as in BAM */
+    uint32_t n_cigar;
+    const char *seq; /* NUL terminated, l_seq bases */
+    uint32_t l_seq;
+    const uint8_t *qual; /* l_seq Phred qualities, 0xFF if missing */
+    const uint8_t *tags; /* as in BAM */
+    uint32_t l_tags;
+} gbam_record;
+
+/* Returns NULL on error. */
+gbam_reader *gbam_open(const char *path);
+/* Restricts iteration to records overlapping region, e.g. "chr1:1,000-2,000".
+ * Returns 0, or -1 on error. */
+int gbam_fetch_region(gbam_reader *reader, const char *region);
+/* Returns 1 if rec is filled, 0 at the end, or -1 on error. */
+int gbam_next_record(gbam_reader *reader, gbam_record *rec);
+void gbam_close(gbam_reader *reader);
+/* Message of the last error on this thread, or NULL. */
+const char *gbam_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
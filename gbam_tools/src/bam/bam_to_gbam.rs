@@ -1,8 +1,9 @@
+use crate::writer::ProgressCallback;
 use crate::MEGA_BYTE_SIZE;
-use crate::{Codecs, Writer};
+use crate::{Codecs, SortOrder, Writer};
 use bam_tools::parse_reference_sequences;
 use bam_tools::record::bamrawrecord::BAMRawRecord;
-use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use bam_tools::record::fields::Fields;
 use bam_tools::sorting::sort;
 use bam_tools::sorting::sort::TempFilesMode;
 use bam_tools::Reader;
@@ -17,8 +18,38 @@ use tempdir::TempDir;
 const MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
 
 /// Converts BAM file to GBAM file. This uses the `bam_parallel` reader.
-pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: String) {
-    let (mut bam_reader, mut writer) = get_bam_reader_gbam_writer(in_path, out_path, codec, full_command);
+/// `codecs` are indexed by field (see [`Writer::new`]), `tag_columns` are
+/// stored apart from RawTags (see [`Writer::set_tag_columns`]). `progress`
+/// is called by the writer (see [`Writer::set_progress`]), without it
+/// progress of reading BAM file is shown.
+pub fn bam_to_gbam(in_path: &str, out_path: &str, codecs: Vec<Codecs>, tag_columns: &[[u8; 2]], full_command: String, progress: Option<ProgressCallback>) {
+    let (mut bam_reader, mut writer) = get_bam_reader_gbam_writer(in_path, out_path, codecs, full_command, progress.is_none());
+    writer.set_progress(progress);
+    let tags_codec = *writer.file_meta().get_field_codec(&Fields::RawTags);
+    writer.set_tag_columns(tag_columns, tags_codec);
+
+    let mut records = bam_reader.records();
+    while let Some(Ok(rec)) = records.next_rec() {
+        let wrapper = BAMRawRecord(Cow::Borrowed(rec));
+        writer.push_record(&wrapper);
+    }
+
+    writer.finish().unwrap();
+}
+
+/// Appends records of BAM file to existing GBAM file. Reference sequences of
+/// both files must match.
+pub fn bam_append_to_gbam(in_path: &str, out_path: &str, thread_num: usize) {
+    let fin = File::open(in_path).expect("failed");
+    let file_size = fin.metadata().unwrap().len();
+    let mut bam_reader = Reader::new(BufReader::new(fin), 4, Some(file_size));
+    let (_, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bam_reader);
+
+    let mut writer = Writer::open_for_append(out_path, thread_num).unwrap();
+    assert!(
+        writer.file_meta().get_ref_seqs() == &ref_seqs,
+        "Reference sequences of BAM file differ from those of GBAM file."
+    );
 
     let mut records = bam_reader.records();
     while let Some(Ok(rec)) = records.next_rec() {
@@ -30,7 +61,7 @@ pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: S
 }
 
 /// Converts BAM file to GBAM file. Sorts BAM file in process. This uses the `bam_parallel` reader.
-pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, full_command: String, index_sort: bool) {
+pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codecs: Vec<Codecs>, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, full_command: String, index_sort: bool) {
     let fin_for_ref_seqs = File::open(in_path).expect("failed");
     
     let mut reader_for_header_only = Reader::new(fin_for_ref_seqs, 1, None);
@@ -48,7 +79,7 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
 
     let mut writer = Writer::new(
         buf_writer,
-        vec![codec; FIELDS_NUM],
+        codecs,
         8,
         vec![Fields::RefID],
         ref_seqs,
@@ -56,6 +87,7 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
         full_command,
         true
     );
+    writer.set_sort_order(SortOrder::Coordinate);
 
     let tmp_dir_path = temp_dir.map_or(std::env::temp_dir(), |path| path);
     if sort_temp_mode.is_none() {
@@ -104,7 +136,7 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
 /// **tuple.1** -> parsed reference sequences from BAM header.
 ///
 /// **tuple.2** -> offset to reference sequences in tuple.0. It's before n_ref uint32_t.
-fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> (Vec<u8>, Vec<(String, u32)>, usize) {
+pub(crate) fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> (Vec<u8>, Vec<(String, u32)>, usize) {
     let (bytes_of_header, ref_sequences_offset) = reader.read_header().unwrap();
     let sequences = parse_reference_sequences(&bytes_of_header[ref_sequences_offset..]).unwrap();
     (bytes_of_header, sequences, ref_sequences_offset)
@@ -113,8 +145,9 @@ fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> (Vec<u8>, Vec<(String, u
 fn get_bam_reader_gbam_writer(
     in_path: &str,
     out_path: &str,
-    codec: Codecs,
+    codecs: Vec<Codecs>,
     full_command: String,
+    track_progress: bool,
 ) -> (Reader, Writer<BufWriter<File>>) {
     let fin = File::open(in_path).expect("failed");
     let fout = File::create(out_path).expect("failed");
@@ -124,13 +157,14 @@ fn get_bam_reader_gbam_writer(
     let buf_reader = BufReader::new(fin);
     let buf_writer = BufWriter::new(fout);
 
-    let mut bgzf_reader = Reader::new(buf_reader, 4, Some(file_size));
+    let mut bgzf_reader = Reader::new(buf_reader, 4, track_progress.then_some(file_size));
 
     let (sam_header, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bgzf_reader);
 
-    let writer = Writer::new(
+    let sort_order = SortOrder::from_sam_header(&sam_header);
+    let mut writer = Writer::new(
         buf_writer,
-        vec![codec; FIELDS_NUM],
+        codecs,
         8,
         vec![Fields::RefID],
         ref_seqs,
@@ -138,6 +172,7 @@ fn get_bam_reader_gbam_writer(
         full_command,
         false,
     );
+    writer.set_sort_order(sort_order);
 
     (bgzf_reader, writer)
 }
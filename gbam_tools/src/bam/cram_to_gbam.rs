@@ -0,0 +1,44 @@
+use super::htslib_records::{gbam_header, htslib_error, write_htslib_records};
+use crate::{Codecs, SortOrder, Writer};
+use bam_tools::record::fields::Fields;
+use rust_htslib::bam::{self, Read};
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Converts CRAM file to GBAM file. Records are decoded by htslib against
+/// `reference` FASTA (or the reference found through M5/UR tags and
+/// REF_PATH if it is not given) and written straight into GBAM.
+/// `codecs` are indexed by field (see [`Writer::new`]). `thread_num` threads
+/// are used for htslib decoding and as many for GBAM compression.
+pub fn cram_to_gbam(
+    in_path: &str,
+    out_path: &str,
+    reference: Option<&str>,
+    codecs: Vec<Codecs>,
+    thread_num: usize,
+    full_command: String,
+) -> io::Result<()> {
+    let mut cram_reader = bam::Reader::from_path(in_path).map_err(htslib_error)?;
+    if let Some(reference) = reference {
+        cram_reader.set_reference(reference).map_err(htslib_error)?;
+    }
+    cram_reader.set_threads(thread_num).map_err(htslib_error)?;
+
+    let (sam_header, ref_seqs) = gbam_header(cram_reader.header());
+    let sort_order = SortOrder::from_sam_header(&sam_header);
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path)?),
+        codecs,
+        thread_num,
+        vec![Fields::RefID],
+        ref_seqs,
+        sam_header,
+        full_command,
+        false,
+    );
+    writer.set_sort_order(sort_order);
+
+    write_htslib_records(&mut cram_reader, &mut writer)?;
+    writer.finish()?;
+    Ok(())
+}
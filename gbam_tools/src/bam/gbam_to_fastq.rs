@@ -0,0 +1,155 @@
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const PAIRED: u16 = 0x1;
+const REVERSE: u16 = 0x10;
+const READ1: u16 = 0x40;
+const READ2: u16 = 0x80;
+// Secondary or supplementary.
+const NOT_PRIMARY: u16 = 0x100 | 0x800;
+const MISSING_QUAL: u8 = 0xFF;
+// Written for bases without quality, as samtools fastq -v 1.
+const DEFAULT_QUAL: u8 = b'"';
+
+/// Fields decoded for FASTQ export.
+pub const FASTQ_FIELDS: [Fields; 4] = [Fields::ReadName, Fields::Flags, Fields::RawSequence, Fields::RawQual];
+
+/// Reads written by [`gbam_to_fastq`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FastqStats {
+    pub pairs: u64,
+    pub singletons: u64,
+}
+
+/// Writes primary reads as FASTQ in sequencing orientation: reverse strand
+/// reads are reverse complemented. Reads of pairs with both mates present go
+/// to `read1` and `read2` (named with /1 and /2 suffix) in the same order,
+/// unpaired reads and mates without their pair go to `singletons`. Missing
+/// outputs fall back to `read1`, which then holds interleaved pairs. Mates
+/// are matched by name, so files in any order are supported; mates of
+/// coordinate sorted files wait in memory until their pair is read.
+pub fn gbam_to_fastq(
+    reader: &mut Reader,
+    read1: &mut dyn Write,
+    mut read2: Option<&mut dyn Write>,
+    mut singletons: Option<&mut dyn Write>,
+) -> io::Result<FastqStats> {
+    let mut stats = FastqStats::default();
+    // FASTQ entries of mates waiting for their pair, by name.
+    let mut pending: HashMap<Vec<u8>, (u16, Vec<u8>)> = HashMap::new();
+    let mut records = reader.records_with(&FASTQ_FIELDS);
+    while let Some(rec) = records.next_rec() {
+        let flag = rec.flag.unwrap();
+        if flag & NOT_PRIMARY != 0 {
+            continue;
+        }
+        let mate = match flag & (READ1 | READ2) {
+            READ1 if flag & PAIRED != 0 => READ1,
+            READ2 if flag & PAIRED != 0 => READ2,
+            _ => {
+                let mut entry = Vec::new();
+                format_fastq(rec, None, &mut entry);
+                write_or(&mut singletons, read1, &entry)?;
+                stats.singletons += 1;
+                continue;
+            }
+        };
+        let mut entry = Vec::new();
+        format_fastq(rec, Some(mate), &mut entry);
+        let name = rec.read_name.as_ref().unwrap();
+        match pending.remove(name.as_slice()) {
+            Some((other, other_entry)) if other != mate => {
+                let (first, second) = if mate == READ1 { (entry, other_entry) } else { (other_entry, entry) };
+                read1.write_all(&first)?;
+                write_or(&mut read2, read1, &second)?;
+                stats.pairs += 1;
+            }
+            Some(duplicate) => {
+                // Two records of the same mate, the first one is a singleton.
+                write_or(&mut singletons, read1, &duplicate.1)?;
+                stats.singletons += 1;
+                pending.insert(name.clone(), (mate, entry));
+            }
+            None => {
+                pending.insert(name.clone(), (mate, entry));
+            }
+        }
+    }
+    let mut orphans: Vec<_> = pending.into_iter().collect();
+    orphans.sort_unstable();
+    for (_, (_, entry)) in orphans {
+        write_or(&mut singletons, read1, &entry)?;
+        stats.singletons += 1;
+    }
+    Ok(stats)
+}
+
+/// Writes `entry` to `out` if given, otherwise to `fallback`.
+fn write_or(out: &mut Option<&mut dyn Write>, fallback: &mut dyn Write, entry: &[u8]) -> io::Result<()> {
+    match out {
+        Some(out) => out.write_all(entry),
+        None => fallback.write_all(entry),
+    }
+}
+
+/// Appends FASTQ entry of record, name suffixed by /1 or /2 for `mate`.
+fn format_fastq(rec: &GbamRecord, mate: Option<u16>, entry: &mut Vec<u8>) {
+    let name = rec.read_name.as_ref().unwrap();
+    entry.push(b'@');
+    entry.extend_from_slice(name.strip_suffix(&[0]).unwrap_or(name));
+    match mate {
+        Some(READ1) => entry.extend_from_slice(b"/1"),
+        Some(_) => entry.extend_from_slice(b"/2"),
+        None => {}
+    }
+    entry.push(b'\n');
+    let reverse = rec.flag.unwrap() & REVERSE != 0;
+    let seq = rec.sequence_revcomp();
+    entry.extend_from_slice(&seq);
+    entry.extend_from_slice(b"\n+\n");
+    let qual = rec.qual.as_deref().unwrap_or_default();
+    if qual.first().is_none_or(|&q| q == MISSING_QUAL) {
+        entry.extend(std::iter::repeat_n(DEFAULT_QUAL, seq.len()));
+    } else if reverse {
+        entry.extend(qual.iter().rev().map(|&q| q + 33));
+    } else {
+        entry.extend(qual.iter().map(|&q| q + 33));
+    }
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+
+    // p1 mates are apart as in coordinate sorted file, s1 is secondary, o1
+    // lost its mate, u1 is unpaired without qualities.
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+p1\t99\tchr1\t10\t60\t4M\t=\t50\t44\tACGT\tABCD\n\
+o1\t137\tchr1\t20\t60\t3M\t=\t20\t0\tGGA\t###\n\
+s1\t355\tchr1\t30\t0\t4M\t=\t50\t24\tACGT\tABCD\n\
+p1\t147\tchr1\t50\t60\t4M\t=\t10\t-44\tAACG\tEFGH\n\
+u1\t16\tchr1\t60\t60\t3M\t*\t0\t0\tAAN\t*\n";
+
+    #[test]
+    fn test_gbam_to_fastq() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new());
+
+        let (mut read1, mut read2, mut singletons) = (Vec::new(), Vec::new(), Vec::new());
+        let stats = gbam_to_fastq(&mut reader, &mut read1, Some(&mut read2), Some(&mut singletons)).unwrap();
+        assert_eq!(stats, FastqStats { pairs: 1, singletons: 2 });
+        assert_eq!(String::from_utf8(read1).unwrap(), "@p1/1\nACGT\n+\nABCD\n");
+        // Reverse strand mate is reverse complemented.
+        assert_eq!(String::from_utf8(read2).unwrap(), "@p1/2\nCGTT\n+\nHGFE\n");
+        assert_eq!(String::from_utf8(singletons).unwrap(), "@u1\nNTT\n+\n\"\"\"\n@o1/2\nGGA\n+\n###\n");
+
+        let mut interleaved = Vec::new();
+        gbam_to_fastq(&mut reader, &mut interleaved, None, None).unwrap();
+        assert_eq!(String::from_utf8(interleaved).unwrap().lines().count(), 16);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::meta::FileMeta;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::tags::Tags;
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+/// Writes SAM text of all records (according to reader parsing template),
+/// preceded by the header if `with_header` is set. Fields which are not
+/// fetched are written as `*` (or 0 for numeric fields), so omitting
+/// sequence and qualities speeds up the dump considerably.
+pub fn gbam_to_sam(reader: &mut Reader, out: &mut dyn Write, with_header: bool) -> io::Result<()> {
+    let file_meta = reader.file_meta.clone();
+    if with_header {
+        write_sam_header(&file_meta, out)?;
+    }
+    let ref_seqs = file_meta.get_ref_seqs();
+    let mut records = reader.records();
+    let mut line = Vec::new();
+    while let Some(rec) = records.next_rec() {
+        line.clear();
+        format_sam_record(rec, ref_seqs, &mut line);
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+/// Writes header text stored in GBAM file. @SQ lines are generated from
+/// reference sequences if the text has none.
+pub fn write_sam_header(file_meta: &FileMeta, out: &mut dyn Write) -> io::Result<()> {
+    let sam_header = file_meta.get_sam_header();
+    let l_text = u32::from_le_bytes(sam_header[..4].try_into().unwrap()) as usize;
+    // Text may be NUL padded.
+    let text = &sam_header[4..4 + l_text];
+    let text = &text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())];
+    out.write_all(text)?;
+    if !text.is_empty() && !text.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    if !text.starts_with(b"@SQ\t") && !text.windows(5).any(|w| w == b"\n@SQ\t") {
+        for (name, len) in file_meta.get_ref_seqs() {
+            writeln!(out, "@SQ\tSN:{}\tLN:{}", name, len)?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats record as SAM line (with trailing newline) appended to `line`.
+pub fn format_sam_record(rec: &GbamRecord, ref_seqs: &[(String, u32)], line: &mut Vec<u8>) {
+    let ref_name = |ref_id: Option<i32>| match ref_id {
+        Some(id) if id >= 0 => ref_seqs[id as usize].0.as_str(),
+        _ => "*",
+    };
+
+    match rec.read_name.as_ref() {
+        Some(name) => line.extend_from_slice(&name[..name.len() - 1]),
+        None => line.push(b'*'),
+    }
+    let rname = ref_name(rec.refid);
+    let rnext = match rec.next_ref_id {
+        Some(id) if id >= 0 && Some(id) == rec.refid => "=",
+        id => ref_name(id),
+    };
+    let cigar = match rec.cigar.as_ref() {
+        Some(cigar) if !cigar.0.is_empty() => cigar.to_string(),
+        _ => "*".to_owned(),
+    };
+    write!(
+        line,
+        "\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+        rec.flag.unwrap_or(0),
+        rname,
+        rec.pos.map_or(0, |pos| pos + 1),
+        rec.mapq.unwrap_or(0),
+        cigar,
+        rnext,
+        rec.next_pos.map_or(0, |pos| pos + 1),
+        rec.tlen.unwrap_or(0),
+    )
+    .unwrap();
+
+    let seq = rec.seq.as_deref().unwrap_or("");
+    if seq.is_empty() {
+        line.push(b'*');
+    } else {
+        line.extend_from_slice(seq.as_bytes());
+    }
+    line.push(b'\t');
+    match rec.qual.as_ref() {
+        Some(qual) if !qual.is_empty() && qual[0] != 0xff => line.extend(qual.iter().map(|q| q + 33)),
+        _ => line.push(b'*'),
+    }
+
+    if let Some(tags) = rec.tags.as_ref() {
+        write_sam_tags(tags, line);
+    }
+    line.push(b'\n');
+}
+
+/// Appends BAM encoded tags as tab separated TAG:TYPE:VALUE.
+pub(crate) fn write_sam_tags(tags: &[u8], line: &mut Vec<u8>) {
+    for (tag, value) in Tags::new(tags) {
+        line.push(b'\t');
+        line.extend_from_slice(&tag);
+        line.push(b':');
+        value.write_sam(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+
+    #[test]
+    fn test_format_sam_record() {
+        let ref_seqs = vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 2000)];
+        let rec = GbamRecord {
+            refid: Some(0),
+            pos: Some(10),
+            mapq: Some(60),
+            flag: Some(99),
+            next_ref_id: Some(0),
+            next_pos: Some(20),
+            tlen: Some(15),
+            read_name: Some(b"r1\0".to_vec()),
+            cigar: Some(Cigar::new(vec![Op::new(3 << 4), Op::new(1 << 4 | 1), Op::new(2 << 4)])),
+            seq: Some("ACGTAC".to_owned()),
+            qual: Some(vec![40; 6]),
+            tags: Some(b"NMC\x01RGZgrp\0XNs\xd4\xfeXBBc\x02\x00\x00\x00\xff\x01".to_vec()),
+            ..Default::default()
+        };
+        let mut line = Vec::new();
+        format_sam_record(&rec, &ref_seqs, &mut line);
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\tRG:Z:grp\tXN:i:-300\tXB:B:c,-1,1\n"
+        );
+
+        // Projection without sequence, qualities and tags.
+        let rec = GbamRecord {
+            refid: Some(1),
+            pos: Some(0),
+            next_ref_id: Some(-1),
+            next_pos: Some(-1),
+            ..Default::default()
+        };
+        let mut line = Vec::new();
+        format_sam_record(&rec, &ref_seqs, &mut line);
+        assert_eq!(String::from_utf8(line).unwrap(), "*\t0\tchr2\t1\t0\t*\t*\t0\t0\t*\t*\n");
+    }
+}
@@ -0,0 +1,224 @@
+use super::sam_to_gbam::{bam_header_bytes, reference_length, RefSeqs, MAX_CIGAR_OPS};
+use crate::reader::record::GbamRecord;
+use crate::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rust_htslib::bam;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, Seek, Write};
+
+const FIXED_LEN: usize = 32;
+
+/// Serializes htslib record as BAM record without block_size, the layout
+/// taken by [`Writer::push_record`]. CIGARs of more than 65535 operations
+/// are moved into CG tag as in BAM files. Fails if read name is longer
+/// than 254 bytes or positions or template length do not fit into 32 bits,
+/// which BAM can't hold.
+pub fn htslib_to_raw(rec: &bam::Record, buf: &mut Vec<u8>) -> io::Result<()> {
+    let inner = rec.inner();
+    let core = &inner.core;
+    // Data holds read name (padded with l_extranul NULs), CIGAR, sequence,
+    // qualities and tags, the same as BAM record variable part.
+    let data = unsafe { std::slice::from_raw_parts(inner.data, inner.l_data as usize) };
+    let l_qname = core.l_qname as usize;
+    let l_read_name = l_qname - core.l_extranul as usize;
+    let read_name = String::from_utf8_lossy(&data[..l_read_name.saturating_sub(1)]);
+    let out_of_range = |what: &str, value: i64| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} {} of record {} does not fit into BAM.", what, value, read_name))
+    };
+    let l_read_name_u8 = u8::try_from(l_read_name).map_err(|_| out_of_range("Read name length", l_read_name as i64))?;
+    let pos = i32::try_from(core.pos).map_err(|_| out_of_range("Position", core.pos))?;
+    let mpos = i32::try_from(core.mpos).map_err(|_| out_of_range("Mate position", core.mpos))?;
+    let tlen = i32::try_from(core.isize_).map_err(|_| out_of_range("Template length", core.isize_))?;
+    let cigar_end = l_qname + core.n_cigar as usize * 4;
+
+    let cigar: Vec<u32> = data[l_qname..cigar_end]
+        .chunks(4)
+        .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+        .collect();
+    // Long CIGAR goes into CG tag, CIGAR field holds <l_seq>S<ref_len>N.
+    let long_cigar = cigar.len() > MAX_CIGAR_OPS;
+    let short_cigar = if long_cigar {
+        vec![(core.l_qseq as u32) << 4 | 4, (reference_length(&cigar) as u32) << 4 | 3]
+    } else {
+        Vec::new()
+    };
+
+    buf.clear();
+    buf.write_i32::<LittleEndian>(core.tid).unwrap();
+    buf.write_i32::<LittleEndian>(pos).unwrap();
+    buf.write_u8(l_read_name_u8).unwrap();
+    buf.write_u8(core.qual).unwrap();
+    buf.write_u16::<LittleEndian>(core.bin).unwrap();
+    if long_cigar {
+        buf.write_u16::<LittleEndian>(short_cigar.len() as u16).unwrap();
+    } else {
+        buf.write_u16::<LittleEndian>(cigar.len() as u16).unwrap();
+    }
+    buf.write_u16::<LittleEndian>(core.flag).unwrap();
+    buf.write_u32::<LittleEndian>(core.l_qseq as u32).unwrap();
+    buf.write_i32::<LittleEndian>(core.mtid).unwrap();
+    buf.write_i32::<LittleEndian>(mpos).unwrap();
+    buf.write_i32::<LittleEndian>(tlen).unwrap();
+    buf.extend_from_slice(&data[..l_read_name]);
+    if long_cigar {
+        short_cigar.iter().for_each(|op| buf.write_u32::<LittleEndian>(*op).unwrap());
+    } else {
+        buf.extend_from_slice(&data[l_qname..cigar_end]);
+    }
+    buf.extend_from_slice(&data[cigar_end..]);
+    if long_cigar {
+        buf.extend_from_slice(b"CGBI");
+        buf.write_u32::<LittleEndian>(cigar.len() as u32).unwrap();
+        buf.extend_from_slice(&data[l_qname..cigar_end]);
+    }
+    Ok(())
+}
+
+/// Fills htslib record from BAM record without block_size. CG tag is kept
+/// as is, the same as htslib does for BAM records it does not read itself.
+pub fn raw_to_htslib(raw: &BAMRawRecord, rec: &mut bam::Record) {
+    let bytes = &raw.0[..];
+    let mut fixed = &bytes[..FIXED_LEN];
+    let tid = fixed.read_i32::<LittleEndian>().unwrap();
+    let pos = fixed.read_i32::<LittleEndian>().unwrap();
+    let l_read_name = fixed.read_u8().unwrap() as usize;
+    let qual = fixed.read_u8().unwrap();
+    let bin = fixed.read_u16::<LittleEndian>().unwrap();
+    let n_cigar = fixed.read_u16::<LittleEndian>().unwrap();
+    let flag = fixed.read_u16::<LittleEndian>().unwrap();
+    let l_seq = fixed.read_u32::<LittleEndian>().unwrap();
+    let mtid = fixed.read_i32::<LittleEndian>().unwrap();
+    let mpos = fixed.read_i32::<LittleEndian>().unwrap();
+    let tlen = fixed.read_i32::<LittleEndian>().unwrap();
+
+    // htslib keeps CIGAR 4-byte aligned by padding read name with NULs.
+    let l_extranul = (4 - l_read_name % 4) % 4;
+    let name_end = FIXED_LEN + l_read_name;
+    let mut data = Vec::with_capacity(bytes.len() - FIXED_LEN + l_extranul);
+    data.extend_from_slice(&bytes[FIXED_LEN..name_end]);
+    data.resize(l_read_name + l_extranul, 0);
+    data.extend_from_slice(&bytes[name_end..]);
+    rec.set_data(&data);
+
+    let core = &mut rec.inner_mut().core;
+    core.tid = tid;
+    core.pos = pos as _;
+    core.bin = bin;
+    core.qual = qual;
+    core.l_extranul = l_extranul as u8;
+    core.flag = flag;
+    core.l_qname = (l_read_name + l_extranul) as u16;
+    core.n_cigar = n_cigar as u32;
+    core.l_qseq = l_seq as i32;
+    core.mtid = mtid;
+    core.mpos = mpos as _;
+    core.isize_ = tlen as _;
+}
+
+/// Converts htslib record into fully filled GBAM record. Fails as
+/// [`htslib_to_raw`].
+pub fn htslib_to_gbam(rec: &bam::Record) -> io::Result<GbamRecord> {
+    let mut buf = Vec::new();
+    htslib_to_raw(rec, &mut buf)?;
+    Ok(GbamRecord::from(&BAMRawRecord(Cow::Borrowed(&buf))))
+}
+
+/// Fills htslib record from GBAM record. All fields of `gbam_rec` have to be
+/// present (see [`GbamRecord::convert_to_bytes`]).
+pub fn gbam_to_htslib(gbam_rec: &GbamRecord, rec: &mut bam::Record) {
+    let mut buf = Vec::new();
+    gbam_rec.convert_to_bytes(&mut buf);
+    raw_to_htslib(&BAMRawRecord(Cow::Borrowed(&buf[4..])), rec);
+}
+
+/// Binary SAM header and reference sequences of htslib header, as taken by
+/// [`Writer::new`].
+pub fn gbam_header(header: &bam::HeaderView) -> (Vec<u8>, RefSeqs) {
+    let ref_seqs: RefSeqs = (0..header.target_count())
+        .map(|tid| {
+            (
+                String::from_utf8_lossy(header.tid2name(tid)).into_owned(),
+                header.target_len(tid).unwrap() as u32,
+            )
+        })
+        .collect();
+    (bam_header_bytes(header.as_bytes(), &ref_seqs), ref_seqs)
+}
+
+/// Pushes all remaining records of htslib reader (BAM, SAM or CRAM) into
+/// `writer`, which should be created with [`gbam_header`] of the reader.
+/// Returns number of records.
+pub fn write_htslib_records<R: bam::Read, W: Write + Seek>(reader: &mut R, writer: &mut Writer<W>) -> io::Result<u64> {
+    let mut rec = bam::Record::new();
+    let mut buf = Vec::new();
+    let mut count = 0;
+    while let Some(res) = reader.read(&mut rec) {
+        res.map_err(htslib_error)?;
+        htslib_to_raw(&rec, &mut buf)?;
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub(crate) fn htslib_error(e: rust_htslib::errors::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    fn record() -> bam::Record {
+        let mut rec = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(3), Cigar::Del(2), Cigar::Match(2)]);
+        rec.set(b"read1", Some(&cigar), b"ACGTA", &[30, 31, 32, 33, 34]);
+        rec.set_tid(1);
+        rec.set_pos(100);
+        rec.set_mapq(60);
+        rec.set_flags(0x63);
+        rec.set_mtid(1);
+        rec.set_mpos(300);
+        rec.set_insert_size(205);
+        rec.push_aux(b"NM", Aux::I32(2)).unwrap();
+        rec
+    }
+
+    #[test]
+    fn test_htslib_round_trip() {
+        let rec = record();
+        let mut buf = Vec::new();
+        htslib_to_raw(&rec, &mut buf).unwrap();
+        let mut back = bam::Record::new();
+        raw_to_htslib(&BAMRawRecord(Cow::Borrowed(&buf)), &mut back);
+        assert_eq!(back, rec);
+        assert_eq!(back.qname(), b"read1");
+        assert_eq!(back.cigar().to_string(), "3M2D2M");
+
+        let mut back = bam::Record::new();
+        gbam_to_htslib(&htslib_to_gbam(&rec).unwrap(), &mut back);
+        assert_eq!(back, rec);
+    }
+
+    #[test]
+    fn test_htslib_out_of_range() {
+        let mut buf = Vec::new();
+        let mut rec = record();
+        rec.set_pos(i64::from(i32::MAX) + 1);
+        assert_eq!(htslib_to_raw(&rec, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut rec = record();
+        rec.set_mpos(-(1 << 40));
+        assert!(htslib_to_raw(&rec, &mut buf).is_err());
+
+        // Read name of 299 bytes and NUL, without CIGAR and sequence.
+        let mut rec = bam::Record::new();
+        let mut data = vec![b'r'; 299];
+        data.push(0);
+        rec.set_data(&data);
+        rec.inner_mut().core.l_qname = data.len() as u16;
+        assert!(htslib_to_raw(&rec, &mut buf).is_err());
+    }
+}
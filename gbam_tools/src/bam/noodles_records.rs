@@ -0,0 +1,220 @@
+use super::sam_to_gbam::{bam_header_bytes, reference_length, reg2bin, RefSeqs};
+use crate::reader::record::GbamRecord;
+use crate::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use byteorder::{LittleEndian, WriteBytesExt};
+use noodles_sam::alignment::io::Write as AlignmentWrite;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, Seek, Write};
+
+/// Serializes noodles record as BAM record without block_size, the layout
+/// taken by [`Writer::push_record`]. Bin is recomputed from position and
+/// CIGAR. Fails if reference sequence IDs or positions are invalid, as
+/// noodles decoding of the fields does, or if read name is longer than 254
+/// bytes.
+pub fn noodles_to_raw(rec: &noodles_bam::Record, buf: &mut Vec<u8>) -> io::Result<()> {
+    let refid = id(rec.reference_sequence_id())?;
+    let start = pos(rec.alignment_start())?;
+    let read_name = rec.name().map_or(&b"*"[..], |name| name);
+    // Length includes the NUL terminator.
+    let l_read_name = u8::try_from(read_name.len() + 1).map_err(|_| invalid_data("Read name is longer than 254 bytes."))?;
+    let cigar = rec.cigar();
+    let cigar = cigar.as_ref();
+    let ops: Vec<u32> = cigar
+        .chunks(4)
+        .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+        .collect();
+    let ref_len = reference_length(&ops);
+    let end = if ref_len == 0 { start + 1 } else { start + ref_len };
+
+    buf.clear();
+    buf.write_i32::<LittleEndian>(refid).unwrap();
+    buf.write_i32::<LittleEndian>(start).unwrap();
+    buf.write_u8(l_read_name).unwrap();
+    buf.write_u8(rec.mapping_quality().map_or(255, u8::from)).unwrap();
+    buf.write_u16::<LittleEndian>(reg2bin(start, end)).unwrap();
+    buf.write_u16::<LittleEndian>(ops.len() as u16).unwrap();
+    buf.write_u16::<LittleEndian>(rec.flags().bits()).unwrap();
+    buf.write_u32::<LittleEndian>(rec.sequence().len() as u32).unwrap();
+    buf.write_i32::<LittleEndian>(id(rec.mate_reference_sequence_id())?).unwrap();
+    buf.write_i32::<LittleEndian>(pos(rec.mate_alignment_start())?).unwrap();
+    buf.write_i32::<LittleEndian>(rec.template_length()).unwrap();
+    buf.extend_from_slice(read_name);
+    buf.push(0);
+    buf.extend_from_slice(cigar);
+    buf.extend_from_slice(rec.sequence().as_ref());
+    // noodles hides missing qualities (all 0xff), BAM stores them as is.
+    let quality_scores = rec.quality_scores();
+    if quality_scores.as_ref().is_empty() {
+        buf.resize(buf.len() + rec.sequence().len(), 0xff);
+    } else {
+        buf.extend_from_slice(quality_scores.as_ref());
+    }
+    buf.extend_from_slice(rec.data().as_ref());
+    Ok(())
+}
+
+/// Fills noodles record from BAM record without block_size.
+pub fn raw_to_noodles(raw: &BAMRawRecord, rec: &mut noodles_bam::Record) {
+    let mut buf = Vec::with_capacity(raw.0.len() + 4);
+    buf.write_u32::<LittleEndian>(raw.0.len() as u32).unwrap();
+    buf.extend_from_slice(&raw.0);
+    // noodles only fills records from a stream of block_size prefixed
+    // records, reading from memory does not fail.
+    noodles_bam::io::Reader::from(&buf[..]).read_record(rec).unwrap();
+}
+
+/// Panics on records with invalid reference sequence IDs or positions, see
+/// [`noodles_to_raw`].
+impl From<&noodles_bam::Record> for GbamRecord {
+    fn from(rec: &noodles_bam::Record) -> Self {
+        let mut buf = Vec::new();
+        noodles_to_raw(rec, &mut buf).expect("Invalid noodles record.");
+        GbamRecord::from(&BAMRawRecord(Cow::Borrowed(&buf)))
+    }
+}
+
+/// All fields of the record have to be present (see
+/// [`GbamRecord::convert_to_bytes`]).
+impl From<&GbamRecord> for noodles_bam::Record {
+    fn from(gbam_rec: &GbamRecord) -> Self {
+        let mut buf = Vec::new();
+        gbam_rec.convert_to_bytes(&mut buf);
+        let mut rec = noodles_bam::Record::default();
+        raw_to_noodles(&BAMRawRecord(Cow::Borrowed(&buf[4..])), &mut rec);
+        rec
+    }
+}
+
+/// Binary SAM header and reference sequences of noodles header, as taken by
+/// [`Writer::new`].
+pub fn gbam_header(header: &noodles_sam::Header) -> io::Result<(Vec<u8>, RefSeqs)> {
+    let ref_seqs = header
+        .reference_sequences()
+        .iter()
+        .map(|(name, rs)| {
+            let len = u32::try_from(rs.length().get()).map_err(invalid_data)?;
+            Ok((String::from_utf8_lossy(name).into_owned(), len))
+        })
+        .collect::<io::Result<RefSeqs>>()?;
+    let mut text = noodles_sam::io::Writer::new(Vec::new());
+    text.write_header(header)?;
+    Ok((bam_header_bytes(text.get_ref(), &ref_seqs), ref_seqs))
+}
+
+/// Writes alignment records of any noodles reader (BAM, SAM, CRAM or record
+/// buffers) into GBAM [`Writer`], which should be created with
+/// [`gbam_header`] of the same header. Records are encoded by noodles BAM
+/// codec, so reference sequence IDs are checked against the header.
+pub struct NoodlesWriter<W: Write + Seek> {
+    writer: Writer<W>,
+    encoder: noodles_bam::io::Writer<Vec<u8>>,
+}
+
+impl<W: Write + Seek> NoodlesWriter<W> {
+    pub fn new(writer: Writer<W>) -> Self {
+        Self { writer, encoder: noodles_bam::io::Writer::from(Vec::new()) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut Writer<W> {
+        &mut self.writer
+    }
+}
+
+impl<W: Write + Seek> AlignmentWrite for NoodlesWriter<W> {
+    /// Header is stored by [`Writer::new`], nothing is written here.
+    fn write_alignment_header(&mut self, _header: &noodles_sam::Header) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_alignment_record(
+        &mut self,
+        header: &noodles_sam::Header,
+        record: &dyn noodles_sam::alignment::Record,
+    ) -> io::Result<()> {
+        self.encoder.write_alignment_record(header, record)?;
+        let buf = self.encoder.get_mut();
+        self.writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf[4..])));
+        buf.clear();
+        Ok(())
+    }
+
+    fn finish(&mut self, _header: &noodles_sam::Header) -> io::Result<()> {
+        self.writer.finish().map(|_| ())
+    }
+}
+
+fn id(id: Option<io::Result<usize>>) -> io::Result<i32> {
+    id.transpose()?.map_or(Ok(-1), |id| i32::try_from(id).map_err(invalid_data))
+}
+
+// noodles positions are 1-based, BAM ones 0-based.
+fn pos<P: Into<usize>>(pos: Option<io::Result<P>>) -> io::Result<i32> {
+    pos.transpose()?.map_or(Ok(-1), |pos| i32::try_from(pos.into() - 1).map_err(invalid_data))
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_util::parse_sam;
+    use crate::Codecs;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:2000\n\
+r1\t99\tchr1\t11\t60\t3M1D3M\t=\t21\t16\tACGTAC\tIIIIII\tNM:i:1\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tACG\t*\n";
+
+    #[test]
+    fn test_noodles_round_trip() {
+        let (_, _, records) = parse_sam(SAM);
+        for raw in records {
+            let mut rec = noodles_bam::Record::default();
+            raw_to_noodles(&BAMRawRecord(Cow::Borrowed(&raw)), &mut rec);
+            let mut buf = Vec::new();
+            noodles_to_raw(&rec, &mut buf).unwrap();
+            assert_eq!(buf, raw);
+
+            let gbam_rec = GbamRecord::from(&rec);
+            assert_eq!(noodles_bam::Record::from(&gbam_rec), rec);
+        }
+    }
+
+    #[test]
+    fn test_noodles_writer() {
+        let header: noodles_sam::Header = SAM.lines().filter(|l| l.starts_with('@')).map(|l| format!("{}\n", l)).collect::<String>().parse().unwrap();
+        let (sam_header, ref_seqs) = gbam_header(&header).unwrap();
+        assert_eq!(ref_seqs, vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 2000)]);
+
+        let dir = tempdir::TempDir::new("gbam_noodles_test").unwrap();
+        let path = dir.path().join("out.gbam");
+        let writer = Writer::new(BufWriter::new(File::create(&path).unwrap()), vec![Codecs::Lz4], 2, vec![], ref_seqs, sam_header, String::new(), true);
+        let mut writer = NoodlesWriter::new(writer);
+        writer.write_alignment_header(&header).unwrap();
+        let (_, _, raw) = parse_sam(SAM);
+        let mut rec = noodles_bam::Record::default();
+        for raw in raw.iter() {
+            raw_to_noodles(&BAMRawRecord(Cow::Borrowed(raw)), &mut rec);
+            writer.write_alignment_record(&header, &rec).unwrap();
+        }
+        writer.finish(&header).unwrap();
+
+        let mut tmplt = ParsingTemplate::new();
+        tmplt.set_all();
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        assert_eq!(reader.amount, raw.len());
+        let mut gbam_rec = GbamRecord::default();
+        for (i, raw) in raw.iter().enumerate() {
+            reader.fill_record(i, &mut gbam_rec);
+            let mut buf = Vec::new();
+            gbam_rec.convert_to_bytes(&mut buf);
+            assert_eq!(&buf[4..], &raw[..]);
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use crate::writer::ProgressCallback;
+use crate::{Codecs, SortOrder, Writer};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::read::MultiGzDecoder;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, Write};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// Maximal number of CIGAR operations which fit into BAM record CIGAR field.
+pub(crate) const MAX_CIGAR_OPS: usize = 0xffff;
+
+/// Names and lengths of reference sequences.
+pub(crate) type RefSeqs = Vec<(String, u32)>;
+
+/// Converts SAM file (plain or gzip/BGZF compressed) to GBAM file.
+/// `codecs` are indexed by field (see [`Writer::new`]), `tag_columns` are
+/// stored apart from RawTags (see [`Writer::set_tag_columns`]). `out_path`
+/// `-` is stdout, written as a stream (see [`Writer::new_streaming`]).
+/// `progress` is called by the writer, see [`Writer::set_progress`].
+pub fn sam_to_gbam(in_path: &str, out_path: &str, codecs: Vec<Codecs>, tag_columns: &[[u8; 2]], full_command: String, progress: Option<ProgressCallback>) -> io::Result<()> {
+    let mut sam_reader = SamReader::new(open_sam(in_path)?);
+    let (sam_header, ref_seqs) = sam_reader.read_header()?;
+
+    let sort_order = SortOrder::from_sam_header(&sam_header);
+    if out_path == "-" {
+        let writer = Writer::new_streaming(BufWriter::new(io::stdout()), codecs, 8, vec![Fields::RefID], ref_seqs, sam_header, full_command, false);
+        return write_sam_records(sam_reader, writer, sort_order, tag_columns, progress);
+    }
+    let writer = Writer::new(
+        BufWriter::new(File::create(out_path)?),
+        codecs,
+        8,
+        vec![Fields::RefID],
+        ref_seqs,
+        sam_header,
+        full_command,
+        false,
+    );
+    write_sam_records(sam_reader, writer, sort_order, tag_columns, progress)
+}
+
+/// Appends records of SAM file (plain or gzip/BGZF compressed) to existing
+/// GBAM file, see [`Writer::open_for_append`]. Reference sequences of both
+/// files must match.
+pub fn sam_append_to_gbam(in_path: &str, out_path: &str, thread_num: usize) -> io::Result<()> {
+    let mut sam_reader = SamReader::new(open_sam(in_path)?);
+    let (_, ref_seqs) = sam_reader.read_header()?;
+
+    let mut writer = Writer::open_for_append(out_path, thread_num)?;
+    if writer.file_meta().get_ref_seqs() != &ref_seqs {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Reference sequences of SAM file differ from those of GBAM file."));
+    }
+    let mut buf = Vec::new();
+    while sam_reader.read_record(&mut buf)? != 0 {
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_sam_records<R: BufRead, W: Write + Seek>(
+    mut sam_reader: SamReader<R>,
+    mut writer: Writer<W>,
+    sort_order: SortOrder,
+    tag_columns: &[[u8; 2]],
+    progress: Option<ProgressCallback>,
+) -> io::Result<()> {
+    writer.set_sort_order(sort_order);
+    writer.set_progress(progress);
+    let tags_codec = *writer.file_meta().get_field_codec(&Fields::RawTags);
+    writer.set_tag_columns(tag_columns, tags_codec);
+
+    let mut buf = Vec::new();
+    while sam_reader.read_record(&mut buf)? != 0 {
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+/// Opens SAM file, decompressing it if it starts with gzip magic.
+pub(crate) fn open_sam(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Parses SAM text into BAM header and BAM records (without block_size).
+pub struct SamReader<R: BufRead> {
+    inner: R,
+    line: String,
+    line_num: usize,
+    ref_name_to_id: HashMap<String, i32>,
+}
+
+impl<R: BufRead> SamReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: String::new(),
+            line_num: 0,
+            ref_name_to_id: HashMap::new(),
+        }
+    }
+
+    /// Consumes header lines. Returns BAM header bytes as stored in GBAM
+    /// (l_text, text, n_ref, reference sequences) and reference sequences
+    /// from @SQ lines.
+    pub fn read_header(&mut self) -> io::Result<(Vec<u8>, RefSeqs)> {
+        let mut text = String::new();
+        let mut ref_seqs = Vec::new();
+        while self.inner.fill_buf()?.first() == Some(&b'@') {
+            self.next_line()?;
+            let line = self.line.trim_end_matches(&['\n', '\r'][..]);
+            if line.starts_with("@SQ\t") {
+                ref_seqs.push(self.parse_sq_line(line)?);
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+
+        let header = bam_header_bytes(text.as_bytes(), &ref_seqs);
+        self.ref_name_to_id = ref_seqs
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (name.clone(), id as i32))
+            .collect();
+        Ok((header, ref_seqs))
+    }
+
+    /// Reads next alignment line and encodes it as BAM record into `buf`.
+    /// Returns 0 at the end of file, record size otherwise.
+    pub fn read_record(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        loop {
+            if self.next_line()? == 0 {
+                return Ok(0);
+            }
+            let line = self.line.trim_end_matches(&['\n', '\r'][..]);
+            if !line.is_empty() {
+                break;
+            }
+        }
+        buf.clear();
+        let line = self.line.trim_end_matches(&['\n', '\r'][..]);
+        encode_record(line, &self.ref_name_to_id, buf).map_err(|msg| self.error(&msg))?;
+        Ok(buf.len())
+    }
+
+    fn next_line(&mut self) -> io::Result<usize> {
+        self.line.clear();
+        self.line_num += 1;
+        self.inner.read_line(&mut self.line)
+    }
+
+    fn parse_sq_line(&self, line: &str) -> io::Result<(String, u32)> {
+        let mut name = None;
+        let mut len = None;
+        for tag in line.split('\t').skip(1) {
+            if let Some(val) = tag.strip_prefix("SN:") {
+                name = Some(val.to_owned());
+            } else if let Some(val) = tag.strip_prefix("LN:") {
+                len = val.parse::<u32>().ok();
+            }
+        }
+        match (name, len) {
+            (Some(name), Some(len)) => Ok((name, len)),
+            _ => Err(self.error("@SQ line requires SN and LN tags")),
+        }
+    }
+
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("SAM line {}: {}", self.line_num, msg),
+        )
+    }
+}
+
+/// BAM header as stored in GBAM: l_text, text, n_ref, reference sequences.
+pub(crate) fn bam_header_bytes(text: &[u8], ref_seqs: &[(String, u32)]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    header.extend_from_slice(text);
+    header.write_u32::<LittleEndian>(ref_seqs.len() as u32).unwrap();
+    for (name, len) in ref_seqs.iter() {
+        header.write_u32::<LittleEndian>(name.len() as u32 + 1).unwrap();
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.write_u32::<LittleEndian>(*len).unwrap();
+    }
+    header
+}
+
+/// Encodes SAM alignment line as BAM record.
+fn encode_record(line: &str, ref_name_to_id: &HashMap<String, i32>, buf: &mut Vec<u8>) -> Result<(), String> {
+    let cols: Vec<&str> = line.split('\t').collect();
+    if cols.len() < 11 {
+        return Err(format!("expected at least 11 columns, found {}", cols.len()));
+    }
+    let ref_id = |name: &str| -> Result<i32, String> {
+        match name {
+            "*" => Ok(-1),
+            _ => ref_name_to_id
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("reference {} is not in header", name)),
+        }
+    };
+    let parse_num = |col: usize| -> Result<i64, String> {
+        cols[col]
+            .parse::<i64>()
+            .map_err(|_| format!("column {} is not a number: {}", col + 1, cols[col]))
+    };
+
+    let read_name = cols[0];
+    let flag = parse_num(1)? as u16;
+    let refid = ref_id(cols[2])?;
+    let pos = parse_num(3)? as i32 - 1;
+    let mapq = parse_num(4)? as u8;
+    let mut cigar = parse_cigar(cols[5])?;
+    let next_refid = match cols[6] {
+        "=" => refid,
+        name => ref_id(name)?,
+    };
+    let next_pos = parse_num(7)? as i32 - 1;
+    let tlen = parse_num(8)? as i32;
+    let seq = if cols[9] == "*" { "" } else { cols[9] };
+    let qual = cols[10];
+    if qual != "*" && qual.len() != seq.len() {
+        return Err("SEQ and QUAL lengths differ".to_owned());
+    }
+
+    let ref_len = reference_length(&cigar);
+    let end = if ref_len == 0 { pos + 1 } else { pos + ref_len };
+    // Long CIGAR goes into CG tag, CIGAR field holds <l_seq>S<ref_len>N.
+    let long_cigar = if cigar.len() > MAX_CIGAR_OPS {
+        let ops = std::mem::replace(
+            &mut cigar,
+            vec![(seq.len() as u32) << 4 | 4, (ref_len as u32) << 4 | 3],
+        );
+        Some(ops)
+    } else {
+        None
+    };
+
+    buf.write_i32::<LittleEndian>(refid).unwrap();
+    buf.write_i32::<LittleEndian>(pos).unwrap();
+    buf.write_u8(read_name.len() as u8 + 1).unwrap();
+    buf.write_u8(mapq).unwrap();
+    buf.write_u16::<LittleEndian>(reg2bin(pos, end)).unwrap();
+    buf.write_u16::<LittleEndian>(cigar.len() as u16).unwrap();
+    buf.write_u16::<LittleEndian>(flag).unwrap();
+    buf.write_u32::<LittleEndian>(seq.len() as u32).unwrap();
+    buf.write_i32::<LittleEndian>(next_refid).unwrap();
+    buf.write_i32::<LittleEndian>(next_pos).unwrap();
+    buf.write_i32::<LittleEndian>(tlen).unwrap();
+    buf.extend_from_slice(read_name.as_bytes());
+    buf.push(0);
+    cigar.iter().for_each(|op| buf.write_u32::<LittleEndian>(*op).unwrap());
+    for pair in seq.as_bytes().chunks(2) {
+        let hi = base_to_code(pair[0]);
+        let lo = pair.get(1).map_or(0, |&base| base_to_code(base));
+        buf.push(hi << 4 | lo);
+    }
+    if qual == "*" {
+        buf.resize(buf.len() + seq.len(), 0xff);
+    } else {
+        buf.extend(qual.bytes().map(|q| q.wrapping_sub(33)));
+    }
+    for tag in cols[11..].iter() {
+        encode_tag(tag, buf)?;
+    }
+    if let Some(ops) = long_cigar {
+        buf.extend_from_slice(b"CGBI");
+        buf.write_u32::<LittleEndian>(ops.len() as u32).unwrap();
+        ops.iter().for_each(|op| buf.write_u32::<LittleEndian>(*op).unwrap());
+    }
+    Ok(())
+}
+
+fn parse_cigar(cigar: &str) -> Result<Vec<u32>, String> {
+    if cigar == "*" {
+        return Ok(Vec::new());
+    }
+    let mut ops = Vec::new();
+    let mut len: u32 = 0;
+    for ch in cigar.bytes() {
+        match ch {
+            b'0'..=b'9' => len = len * 10 + u32::from(ch - b'0'),
+            _ => {
+                let op = b"MIDNSHP=X"
+                    .iter()
+                    .position(|&c| c == ch)
+                    .ok_or_else(|| format!("invalid CIGAR operation {}", ch as char))?;
+                ops.push(len << 4 | op as u32);
+                len = 0;
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Number of reference bases covered by CIGAR (M, D, N, =, X).
+pub(crate) fn reference_length(cigar: &[u32]) -> i32 {
+    cigar
+        .iter()
+        .filter(|op| matches!(*op & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|op| (op >> 4) as i32)
+        .sum()
+}
+
+fn base_to_code(base: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN"
+        .iter()
+        .position(|&c| c == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+/// SAM spec 5.3: bin of alignment spanning [beg, end).
+pub(crate) fn reg2bin(beg: i32, end: i32) -> u16 {
+    let end = end - 1;
+    let bin = if beg >> 14 == end >> 14 {
+        4681 + (beg >> 14)
+    } else if beg >> 17 == end >> 17 {
+        585 + (beg >> 17)
+    } else if beg >> 20 == end >> 20 {
+        73 + (beg >> 20)
+    } else if beg >> 23 == end >> 23 {
+        9 + (beg >> 23)
+    } else if beg >> 26 == end >> 26 {
+        1 + (beg >> 26)
+    } else {
+        0
+    };
+    bin as u16
+}
+
+/// Encodes TAG:TYPE:VALUE. Integers get the smallest fitting type, as htslib
+/// does.
+fn encode_tag(tag: &str, buf: &mut Vec<u8>) -> Result<(), String> {
+    let mut parts = tag.splitn(3, ':');
+    let (name, typ, val) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(typ), Some(val)) if name.len() == 2 && typ.len() == 1 => (name, typ, val),
+        _ => return Err(format!("invalid tag {}", tag)),
+    };
+    let invalid = || format!("invalid value of tag {}", tag);
+    buf.extend_from_slice(name.as_bytes());
+    match typ {
+        "A" => {
+            buf.push(b'A');
+            buf.push(*val.as_bytes().first().ok_or_else(invalid)?);
+        }
+        "i" => {
+            let val = val.parse::<i64>().map_err(|_| invalid())?;
+            encode_int(val, buf).ok_or_else(invalid)?;
+        }
+        "f" => {
+            buf.push(b'f');
+            buf.write_f32::<LittleEndian>(val.parse().map_err(|_| invalid())?).unwrap();
+        }
+        "Z" | "H" => {
+            buf.push(typ.as_bytes()[0]);
+            buf.extend_from_slice(val.as_bytes());
+            buf.push(0);
+        }
+        "B" => {
+            let mut items = val.split(',');
+            let subtype = items.next().filter(|t| t.len() == 1).ok_or_else(invalid)?;
+            let items: Vec<&str> = items.collect();
+            buf.push(b'B');
+            buf.push(subtype.as_bytes()[0]);
+            buf.write_u32::<LittleEndian>(items.len() as u32).unwrap();
+            for item in items {
+                let res = match subtype {
+                    "c" => item.parse::<i8>().map(|v| buf.write_i8(v).unwrap()).is_ok(),
+                    "C" => item.parse::<u8>().map(|v| buf.write_u8(v).unwrap()).is_ok(),
+                    "s" => item.parse::<i16>().map(|v| buf.write_i16::<LittleEndian>(v).unwrap()).is_ok(),
+                    "S" => item.parse::<u16>().map(|v| buf.write_u16::<LittleEndian>(v).unwrap()).is_ok(),
+                    "i" => item.parse::<i32>().map(|v| buf.write_i32::<LittleEndian>(v).unwrap()).is_ok(),
+                    "I" => item.parse::<u32>().map(|v| buf.write_u32::<LittleEndian>(v).unwrap()).is_ok(),
+                    "f" => item.parse::<f32>().map(|v| buf.write_f32::<LittleEndian>(v).unwrap()).is_ok(),
+                    _ => false,
+                };
+                if !res {
+                    return Err(invalid());
+                }
+            }
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+fn encode_int(val: i64, buf: &mut Vec<u8>) -> Option<()> {
+    if val < 0 {
+        if val >= i64::from(i8::MIN) {
+            buf.push(b'c');
+            buf.write_i8(val as i8).unwrap();
+        } else if val >= i64::from(i16::MIN) {
+            buf.push(b's');
+            buf.write_i16::<LittleEndian>(val as i16).unwrap();
+        } else if val >= i64::from(i32::MIN) {
+            buf.push(b'i');
+            buf.write_i32::<LittleEndian>(val as i32).unwrap();
+        } else {
+            return None;
+        }
+    } else if val <= i64::from(u8::MAX) {
+        buf.push(b'C');
+        buf.write_u8(val as u8).unwrap();
+    } else if val <= i64::from(u16::MAX) {
+        buf.push(b'S');
+        buf.write_u16::<LittleEndian>(val as u16).unwrap();
+    } else if val <= i64::from(u32::MAX) {
+        buf.push(b'I');
+        buf.write_u32::<LittleEndian>(val as u32).unwrap();
+    } else {
+        return None;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bam_tools::parse_reference_sequences;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    fn read_all<R: Read>(reader: R) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let mut sam_reader = SamReader::new(BufReader::new(reader));
+        let (header, _) = sam_reader.read_header()?;
+        let mut records = Vec::new();
+        let mut buf = Vec::new();
+        while sam_reader.read_record(&mut buf)? != 0 {
+            records.push(buf.clone());
+        }
+        Ok((header, records))
+    }
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+@SQ\tSN:chr1\tLN:1000\n\
+@SQ\tSN:chr2\tLN:2000\n\
+r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\tRG:Z:grp\tXN:i:-300\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tACG\t*\n";
+
+    #[test]
+    fn test_sam_to_bam_record() {
+        let (header, records) = read_all(SAM.as_bytes()).unwrap();
+        assert_eq!(SortOrder::from_sam_header(&header), SortOrder::Coordinate);
+        let l_text = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let ref_seqs = parse_reference_sequences(&header[4 + l_text..]).unwrap();
+        assert_eq!(ref_seqs, vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 2000)]);
+        assert_eq!(records.len(), 2);
+
+        let rec = BAMRawRecord(Cow::Borrowed(&records[0]));
+        assert_eq!(rec.get_bytes(&Fields::RefID), &0i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Pos), &10i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Bin), &4681u16.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::NextRefID), &0i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::NextPos), &20i32.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::ReadName), b"r1\0");
+        assert_eq!(rec.get_bytes(&Fields::RawCigar), &[0x30, 0, 0, 0, 0x11, 0, 0, 0, 0x20, 0, 0, 0]);
+        assert_eq!(rec.get_bytes(&Fields::RawSequence), &[0x12, 0x48, 0x12]);
+        assert_eq!(rec.get_bytes(&Fields::RawQual), &[40; 6]);
+        assert_eq!(
+            rec.get_bytes(&Fields::RawTags),
+            b"NMC\x01RGZgrp\0XNs\xd4\xfe"
+        );
+
+        let rec = BAMRawRecord(Cow::Borrowed(&records[1]));
+        assert_eq!(rec.get_bytes(&Fields::RefID), &(-1i32).to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Pos), &(-1i32).to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::Bin), &4680u16.to_le_bytes());
+        assert_eq!(rec.get_bytes(&Fields::RawQual), &[0xff; 3]);
+    }
+
+    #[test]
+    fn test_sam_append_to_gbam() {
+        use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+        let dir = tempdir::TempDir::new("gbam_sam_append_test").unwrap();
+        let sam_path = dir.path().join("in.sam");
+        let gbam_path = dir.path().join("out.gbam");
+        std::fs::write(&sam_path, SAM).unwrap();
+        let (sam_path, gbam_path) = (sam_path.to_str().unwrap(), gbam_path.to_str().unwrap());
+        sam_to_gbam(sam_path, gbam_path, vec![Codecs::Lz4], &[], String::new(), None).unwrap();
+        sam_append_to_gbam(sam_path, gbam_path, 2).unwrap();
+        assert_eq!(Reader::new(File::open(gbam_path).unwrap(), ParsingTemplate::new()).unwrap().amount, 4);
+
+        let other_refs = dir.path().join("other.sam");
+        std::fs::write(&other_refs, SAM.replace("chr2", "chrX")).unwrap();
+        let err = sam_append_to_gbam(other_refs.to_str().unwrap(), gbam_path, 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
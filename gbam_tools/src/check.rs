@@ -0,0 +1,294 @@
+//! Validation of GBAM files: CRCs of meta and blocks, block contents,
+//! record counts of columns, index columns of variable sized ones and sort
+//! order recorded in meta.
+
+use crate::meta::{BlockHeader, FileInfo, FileMeta, SortOrder, BLOCK_HEADER_SIZE, BLOCK_MAGIC};
+use crate::name_index::trim_nul;
+use crate::reader::column::decompress_block;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::{read_storage_footer, Reader, Storage};
+use crate::sort::{natural_cmp, SortBy};
+use bam_tools::record::fields::{field_item_size, field_type, var_size_field_to_index, FieldType, Fields};
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Arc;
+
+/// Where a [`Problem`] is found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// File info or meta.
+    File,
+    /// Block of column, named by field and tag of tag column.
+    Block { column: String, block: usize },
+    /// Record number.
+    Record(usize),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File => write!(f, "file"),
+            Self::Block { column, block } => write!(f, "{} block {}", column, block),
+            Self::Record(rec_num) => write!(f, "record {}", rec_num),
+        }
+    }
+}
+
+/// Problem found by [`check_gbam`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}", self.location, self.message)
+    }
+}
+
+/// Checks GBAM file `storage`, returns problems found. Every block is
+/// decompressed and checked against its header (files written before block
+/// headers have none) and meta. Sort order is checked only if blocks have
+/// no problems, as records are read for it.
+pub fn check_gbam(storage: Storage) -> Vec<Problem> {
+    let footer = read_storage_footer(&storage).and_then(|(file_info, file_meta)| {
+        file_meta.decode_blocks(Fields::iterator())?;
+        Ok((file_info, file_meta))
+    });
+    let (file_info, file_meta) = match footer {
+        Ok(footer) => footer,
+        Err(e) => return vec![Problem { location: Location::File, message: e.to_string() }],
+    };
+    let bytes = (*storage).as_ref();
+    let mut problems = Vec::new();
+    let tag_cols = file_meta.tag_columns().len();
+    let columns = Fields::iterator()
+        .map(|field| (*field, None))
+        .chain((0..tag_cols).flat_map(|col| [(Fields::RawTags, Some(col)), (Fields::RawTagsLen, Some(col))]));
+    let mut records = None;
+    for (field, tag_col) in columns {
+        let column = column_name(&file_meta, &field, tag_col);
+        let count = check_blocks(bytes, &file_meta, &field, tag_col, &column, &mut problems);
+        // Counts are compared with RefID, the first column.
+        match records {
+            None => records = Some(count),
+            Some(records) if records != count => problems.push(Problem {
+                location: Location::Block { column, block: 0 },
+                message: format!("Column has {} records, RefID has {}.", count, records),
+            }),
+            _ => {}
+        }
+        if matches!(field_type(&field), FieldType::VariableSized) {
+            check_index(bytes, &file_meta, &field, tag_col, &mut problems);
+        }
+    }
+    if problems.is_empty() {
+        check_sort_order(storage.clone(), &file_info, file_meta, &mut problems);
+    }
+    problems
+}
+
+fn column_name(file_meta: &FileMeta, field: &Fields, tag_col: Option<usize>) -> String {
+    match tag_col {
+        None => field.to_string(),
+        Some(col) => format!("{}:{}", field, String::from_utf8_lossy(&file_meta.tag_columns()[col].tag())),
+    }
+}
+
+/// Checks blocks of column, returns number of its records.
+fn check_blocks(bytes: &[u8], file_meta: &FileMeta, field: &Fields, tag_col: Option<usize>, column: &str, problems: &mut Vec<Problem>) -> u64 {
+    let codec = file_meta.get_column_codec(field, tag_col);
+    let mut buf = Vec::new();
+    let mut records = 0;
+    for (block_num, block) in file_meta.view_column_blocks(field, tag_col).iter().enumerate() {
+        let mut problem = |message: String| problems.push(Problem { location: Location::Block { column: column.to_owned(), block: block_num }, message });
+        records += u64::from(block.numitems);
+        let start = block.seekpos as usize;
+        let data = match bytes.get(start..start + block.block_size as usize) {
+            Some(data) => data,
+            None => {
+                problem(format!("Block at {} of {} bytes is out of file.", block.seekpos, block.block_size));
+                continue;
+            }
+        };
+        let header_start = start.checked_sub(BLOCK_HEADER_SIZE);
+        if let Some(header_bytes) = header_start.map(|header_start| &bytes[header_start..]).filter(|bytes| bytes.starts_with(BLOCK_MAGIC)) {
+            let expected = BlockHeader {
+                field: Some(*field),
+                tag_col,
+                block_num: block_num as u64,
+                numitems: block.numitems,
+                block_size: block.block_size,
+                uncompressed_size: block.uncompressed_size,
+            };
+            match BlockHeader::parse(header_bytes) {
+                None => problem("Block CRC mismatch.".to_owned()),
+                Some(header) if header != expected => problem(format!("Block header {:?} doesn't match meta.", header)),
+                _ => {}
+            }
+        }
+        if let Some(item_size) = field_item_size(field) {
+            if block.uncompressed_size != u64::from(block.numitems) * item_size as u64 {
+                problem(format!("Block of {} items has {} bytes.", block.numitems, block.uncompressed_size));
+            }
+        }
+        if block.uncompressed_size == 0 {
+            continue;
+        }
+        buf.resize(block.uncompressed_size as usize, 0);
+        match decompress_block(data, &mut buf, codec) {
+            Err(e) => problem(format!("Decompression failed: {}.", e)),
+            Ok(()) if buf.len() as u64 != block.uncompressed_size => {
+                problem(format!("Block decompressed into {} bytes, meta records {}.", buf.len(), block.uncompressed_size))
+            }
+            _ => {}
+        }
+    }
+    records
+}
+
+/// Checks that offsets of index column of variable sized column don't
+/// decrease within data blocks and end at their sizes.
+fn check_index(bytes: &[u8], file_meta: &FileMeta, field: &Fields, tag_col: Option<usize>, problems: &mut Vec<Problem>) {
+    let index_field = var_size_field_to_index(field);
+    let codec = file_meta.get_column_codec(&index_field, tag_col);
+    let mut offsets = Vec::new();
+    let mut buf = Vec::new();
+    for block in file_meta.view_column_blocks(&index_field, tag_col) {
+        let start = block.seekpos as usize;
+        buf.resize(block.uncompressed_size as usize, 0);
+        let decoded = match bytes.get(start..start + block.block_size as usize) {
+            Some(data) if block.uncompressed_size > 0 => decompress_block(data, &mut buf, codec).is_ok(),
+            Some(_) => true,
+            None => false,
+        };
+        if !decoded {
+            // Reported by check of index column blocks.
+            return;
+        }
+        offsets.extend(buf.chunks_exact(4).map(|item| u32::from_le_bytes(item.try_into().unwrap()) as u64));
+    }
+    let mut rec_num = 0;
+    for (block_num, block) in file_meta.view_column_blocks(field, tag_col).iter().enumerate() {
+        let location = || Location::Block { column: column_name(file_meta, field, tag_col), block: block_num };
+        let end = rec_num + block.numitems as usize;
+        let block_offsets = match offsets.get(rec_num..end) {
+            Some(block_offsets) => block_offsets,
+            // Record counts mismatch is reported separately.
+            None => return,
+        };
+        let decreasing = block_offsets.windows(2).position(|pair| pair[1] < pair[0]);
+        if let Some(i) = decreasing {
+            problems.push(Problem {
+                location: location(),
+                message: format!("{} offset of record {} is less than of the previous one.", index_field, rec_num + i + 1),
+            });
+        } else if block_offsets.last().is_some_and(|&last| last != block.uncompressed_size) {
+            problems.push(Problem {
+                location: location(),
+                message: format!("{} offsets end at {}, block has {} bytes.", index_field, block_offsets.last().unwrap(), block.uncompressed_size),
+            });
+        }
+        rec_num = end;
+    }
+}
+
+/// Checks order of records if meta records coordinate or queryname sort.
+/// Files written before sort order was recorded are checked for coordinate
+/// sort if they are marked sorted.
+fn check_sort_order(storage: Storage, file_info: &FileInfo, file_meta: FileMeta, problems: &mut Vec<Problem>) {
+    let sort_by = match file_meta.sort_order() {
+        SortOrder::Coordinate => SortBy::Coordinate,
+        SortOrder::Queryname => SortBy::QueryName,
+        SortOrder::Unknown if file_info.is_sorted => SortBy::Coordinate,
+        _ => return,
+    };
+    let fields = sort_by.key_fields();
+    let mut reader = match Reader::new_with_storage(storage, None, ParsingTemplate::new_with(fields), &Arc::new(file_meta), None) {
+        Ok(reader) => reader,
+        Err(e) => return problems.push(Problem { location: Location::File, message: e.to_string() }),
+    };
+    let mut records = reader.records_with(fields);
+    let mut previous = None;
+    let mut rec_num = 0;
+    let mut unordered = Vec::new();
+    while let Some(rec) = records.next_rec() {
+        let key = match sort_by {
+            SortBy::Coordinate => SortKey::Coordinate(rec.refid.unwrap() as u32, rec.pos.unwrap()),
+            SortBy::QueryName => {
+                SortKey::QueryName(trim_nul(rec.read_name.as_deref().unwrap()).to_vec(), rec.flag.unwrap() & SortBy::PAIR_FLAGS)
+            }
+        };
+        if previous.as_ref().is_some_and(|previous| key.compare(previous) == Ordering::Less) {
+            unordered.push(rec_num);
+        }
+        previous = Some(key);
+        rec_num += 1;
+    }
+    if let Some(&first) = unordered.first() {
+        problems.push(Problem {
+            location: Location::Record(first),
+            message: format!("Record is out of {} order, {} records are.", sort_by.sort_order().sam_name(), unordered.len()),
+        });
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum SortKey {
+    Coordinate(u32, i32),
+    QueryName(Vec<u8>, u16),
+}
+
+impl SortKey {
+    fn compare(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Coordinate(ref_id, pos), Self::Coordinate(other_ref_id, other_pos)) => (ref_id, pos).cmp(&(other_ref_id, other_pos)),
+            (Self::QueryName(name, flags), Self::QueryName(other_name, other_flags)) => {
+                natural_cmp(name, other_name).then_with(|| flags.cmp(other_flags))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_bytes_from_sam;
+    use crate::Codecs;
+
+    fn write_gbam(sam: &str, sort_order: SortOrder) -> Vec<u8> {
+        gbam_bytes_from_sam(sam, |writer| {
+            writer.set_tag_columns(&[*b"NM"], Codecs::Gzip);
+            writer.set_sort_order(sort_order);
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let sam = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t5M\t*\t0\t0\tACGTA\tIIIII\tNM:i:1\n\
+r2\t0\tchr1\t20\t60\t5M\t*\t0\t0\tACGTA\tIIIII\n";
+        let bytes = write_gbam(sam, SortOrder::Unknown);
+        assert_eq!(check_gbam(Arc::new(bytes.clone())), vec![]);
+
+        // Coordinate sort is detected by writer, other orders are trusted.
+        let unsorted = write_gbam(&sam.replace("r1", "r3"), SortOrder::Queryname);
+        let problems = check_gbam(Arc::new(unsorted));
+        assert_eq!(problems, vec![Problem { location: Location::Record(1), message: "Record is out of queryname order, 1 records are.".to_owned() }]);
+
+        // Damaged byte of the first ReadName block.
+        let (_, file_meta) = read_storage_footer(&(Arc::new(bytes.clone()) as Storage)).unwrap();
+        let block = &file_meta.view_blocks(&Fields::ReadName)[0];
+        let mut damaged = bytes.clone();
+        damaged[block.seekpos as usize] ^= 0xff;
+        let problems = check_gbam(Arc::new(damaged));
+        assert_eq!(problems[0], Problem { location: Location::Block { column: "ReadName".to_owned(), block: 0 }, message: "Block CRC mismatch.".to_owned() });
+
+        let mut truncated = bytes;
+        truncated.truncate(truncated.len() - 1);
+        assert_eq!(check_gbam(Arc::new(truncated))[0].location, Location::File);
+    }
+}
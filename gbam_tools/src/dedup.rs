@@ -0,0 +1,295 @@
+use crate::meta::FileMeta;
+use crate::reader::reader::read_footer;
+use bam_tools::record::fields::Fields;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const PACK_FILE: &str = "blocks.pack";
+const INDEX_FILE: &str = "index.json";
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// MD5 of compressed bytes of one column block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockDigest {
+    pub field: Fields,
+    pub block_num: usize,
+    pub seekpos: u64,
+    pub size: u64,
+    pub md5: String,
+}
+
+/// Digests of all blocks of GBAM file (index and tag columns included),
+/// ordered by position in file. Blocks are hashed as stored, so equal digests
+/// mean equal content compressed with the same codec.
+pub fn block_digests(bytes: &[u8]) -> io::Result<Vec<BlockDigest>> {
+    let file_meta = parse_file_meta(bytes)?;
+    let mut digests = Vec::new();
+    let tag_cols = (0..file_meta.tag_columns().len())
+        .flat_map(|tag_col| [(&Fields::RawTags, Some(tag_col)), (&Fields::RawTagsLen, Some(tag_col))]);
+    for (field, tag_col) in Fields::iterator().map(|field| (field, None)).chain(tag_cols) {
+        for (block_num, block) in file_meta.view_column_blocks(field, tag_col).iter().enumerate() {
+            let start = block.seekpos as usize;
+            let data = bytes
+                .get(start..start + block.block_size as usize)
+                .ok_or_else(|| invalid_data(format!("Block {} of {} is out of file.", block_num, field)))?;
+            digests.push(BlockDigest {
+                field: *field,
+                block_num,
+                seekpos: block.seekpos,
+                size: u64::from(block.block_size),
+                md5: format!("{:x}", md5::compute(data)),
+            });
+        }
+    }
+    digests.sort_by_key(|digest| digest.seekpos);
+    Ok(digests)
+}
+
+fn parse_file_meta(bytes: &[u8]) -> io::Result<FileMeta> {
+    read_footer(bytes).map(|(_, file_meta)| file_meta)
+}
+
+/// Blocks of one file which are also present in files compared before it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SharedBlocks {
+    pub blocks: u64,
+    pub bytes: u64,
+    pub shared_blocks: u64,
+    pub shared_bytes: u64,
+}
+
+/// Compares blocks of every file with blocks of all preceding files.
+pub fn shared_blocks<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<SharedBlocks>> {
+    let mut seen = HashSet::new();
+    let mut res = Vec::with_capacity(paths.len());
+    for path in paths {
+        let digests = block_digests(&fs::read(path)?)?;
+        let mut shared = SharedBlocks::default();
+        for digest in digests.iter() {
+            shared.blocks += 1;
+            shared.bytes += digest.size;
+            if seen.contains(&digest.md5) {
+                shared.shared_blocks += 1;
+                shared.shared_bytes += digest.size;
+            }
+        }
+        seen.extend(digests.into_iter().map(|digest| digest.md5));
+        res.push(shared);
+    }
+    Ok(res)
+}
+
+/// Piece of stored file, kept once in the pack of the store.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub md5: String,
+    pub size: u64,
+}
+
+/// Stored file: its bytes are concatenation of chunks.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// Chunks written by [`DedupStore::store`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Chunks not present in the store before.
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+}
+
+/// Directory storing GBAM files with column blocks shared between them
+/// written once. Every file is split into chunks: column blocks and the
+/// bytes between them (file info, meta). Chunks are content addressed by
+/// MD5 and appended to a pack file, a file is kept as manifest of chunk
+/// references and restored byte-identical.
+pub struct DedupStore {
+    dir: PathBuf,
+    // Chunk MD5 to (offset, size) in pack.
+    index: HashMap<String, (u64, u64)>,
+}
+
+impl DedupStore {
+    /// Opens store in `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let index = match File::open(dir.join(INDEX_FILE)) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file)).map_err(|e| invalid_data(e.to_string()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { dir, index })
+    }
+
+    /// Names of stored files.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let file_name = entry?.file_name();
+            if let Some(name) = file_name.to_str().and_then(|name| name.strip_suffix(MANIFEST_SUFFIX)) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Stores GBAM file under its file name, replacing previously stored
+    /// file with the same name. Chunks of the replaced file stay in the pack.
+    pub fn store<P: AsRef<Path>>(&mut self, path: P) -> io::Result<StoreStats> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name."))?
+            .to_owned();
+        let bytes = fs::read(path)?;
+
+        let mut chunk_ranges = Vec::new();
+        let mut pos = 0;
+        for digest in block_digests(&bytes)? {
+            if digest.seekpos < pos {
+                return Err(invalid_data(format!("Block {} of {} overlaps other data.", digest.block_num, digest.field)));
+            }
+            if digest.seekpos > pos {
+                chunk_ranges.push(pos..digest.seekpos);
+            }
+            if digest.size > 0 {
+                chunk_ranges.push(digest.seekpos..digest.seekpos + digest.size);
+            }
+            pos = digest.seekpos + digest.size;
+        }
+        if pos < bytes.len() as u64 {
+            chunk_ranges.push(pos..bytes.len() as u64);
+        }
+
+        let mut pack = BufWriter::new(OpenOptions::new().create(true).append(true).open(self.dir.join(PACK_FILE))?);
+        let mut pack_len = pack.get_mut().seek(SeekFrom::End(0))?;
+        let mut stats = StoreStats::default();
+        let mut chunks = Vec::with_capacity(chunk_ranges.len());
+        for range in chunk_ranges {
+            let data = &bytes[range.start as usize..range.end as usize];
+            let md5 = format!("{:x}", md5::compute(data));
+            let size = data.len() as u64;
+            stats.chunks += 1;
+            stats.bytes += size;
+            if !self.index.contains_key(&md5) {
+                pack.write_all(data)?;
+                self.index.insert(md5.clone(), (pack_len, size));
+                pack_len += size;
+                stats.new_chunks += 1;
+                stats.new_bytes += size;
+            }
+            chunks.push(ChunkRef { md5, size });
+        }
+        pack.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        // Index and manifest are written after chunks they point to.
+        self.write_json(INDEX_FILE, &self.index)?;
+        let manifest = Manifest {
+            name: name.clone(),
+            size: bytes.len() as u64,
+            chunks,
+        };
+        self.write_json(&format!("{}{}", name, MANIFEST_SUFFIX), &manifest)?;
+        Ok(stats)
+    }
+
+    /// Writes stored file `name` to `out`.
+    pub fn restore(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        let manifest: Manifest = serde_json::from_reader(io::BufReader::new(File::open(self.dir.join(format!("{}{}", name, MANIFEST_SUFFIX)))?))
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let mut pack = File::open(self.dir.join(PACK_FILE))?;
+        let mut buf = Vec::new();
+        for chunk in manifest.chunks.iter() {
+            let &(offset, size) = self
+                .index
+                .get(&chunk.md5)
+                .ok_or_else(|| invalid_data(format!("Chunk {} is missing in the store.", chunk.md5)))?;
+            buf.resize(size as usize, 0);
+            pack.seek(SeekFrom::Start(offset))?;
+            pack.read_exact(&mut buf)?;
+            if format!("{:x}", md5::compute(&buf)) != chunk.md5 {
+                return Err(invalid_data(format!("Chunk {} is damaged.", chunk.md5)));
+            }
+            out.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_json<T: Serialize>(&self, file_name: &str, value: &T) -> io::Result<()> {
+        // Replaced atomically, so interrupted store keeps previous version.
+        let tmp_path = self.dir.join(format!("{}.tmp", file_name));
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut file, value).map_err(|e| invalid_data(e.to_string()))?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(file_name))
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\n\
+r2\t147\tchr1\t21\t60\t5M\t=\t11\t-15\tACGTA\t*\n";
+
+    fn gbam_bytes(sam: &str, command: &str) -> Vec<u8> {
+        let writer = write_sam(sam, |sam_header, ref_seqs| {
+            Writer::new_no_stats(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, command.to_owned(), false)
+        });
+        writer.into_inner().into_inner()
+    }
+
+    #[test]
+    fn test_dedup_store() {
+        let dir = TempDir::new("gbam_dedup_test").unwrap();
+        let a = dir.path().join("a.gbam");
+        let b = dir.path().join("b.gbam");
+        let c = dir.path().join("c.gbam");
+        fs::write(&a, gbam_bytes(SAM, "first")).unwrap();
+        // Re-delivery: same records, other creation command.
+        fs::write(&b, gbam_bytes(SAM, "second")).unwrap();
+        fs::write(&c, gbam_bytes(&SAM.replace("NM:i:1", "NM:i:2"), "first")).unwrap();
+
+        let shared = shared_blocks(&[&a, &b, &c]).unwrap();
+        assert_eq!(shared[0].shared_blocks, 0);
+        assert_eq!(shared[1].shared_blocks, shared[1].blocks);
+        assert_eq!(shared[2].shared_blocks, shared[2].blocks - 1);
+
+        let mut store = DedupStore::open(dir.path().join("store")).unwrap();
+        let first = store.store(&a).unwrap();
+        assert!(first.new_chunks > 0 && first.new_chunks <= first.chunks);
+        let second = store.store(&b).unwrap();
+        // Only file info and meta are new.
+        assert_eq!(second.new_chunks, 2);
+        assert!(second.new_bytes < second.bytes);
+        store.store(&c).unwrap();
+
+        let store = DedupStore::open(dir.path().join("store")).unwrap();
+        assert_eq!(store.names().unwrap(), vec!["a.gbam", "b.gbam", "c.gbam"]);
+        for path in [&a, &b, &c] {
+            let mut restored = Vec::new();
+            store.restore(path.file_name().unwrap().to_str().unwrap(), &mut restored).unwrap();
+            assert_eq!(restored, fs::read(path).unwrap());
+        }
+    }
+}
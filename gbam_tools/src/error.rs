@@ -0,0 +1,45 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Error of GBAM tools. Damaged or unsupported files and failed reads or
+/// writes are [`GbamError::Io`], the rest are errors of user input.
+#[derive(Debug, Error)]
+pub enum GbamError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Reference sequence of a query is not in the file.
+    #[error("Reference sequence {0:?} is not in the file.")]
+    UnknownReference(String),
+    /// Region query which can't be parsed.
+    #[error("Invalid query {0:?}. The format is <ref name> <start> <end>, e.g. \"chr1 1000 2000\".")]
+    InvalidQuery(String),
+    /// BED file which can't be read or parsed.
+    #[error("Invalid BED file {}: {source}", path.display())]
+    InvalidBed { path: PathBuf, source: io::Error },
+    /// Invalid value or combination of options.
+    #[error("{0}")]
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, GbamError>;
+
+impl GbamError {
+    /// Process exit code for the error: 2 for errors of user input (as for
+    /// invalid command line), 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GbamError::Io(_) => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl From<GbamError> for io::Error {
+    fn from(error: GbamError) -> Self {
+        match error {
+            GbamError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::bam::gbam_to_sam::write_sam_tags;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::records::Records;
+use arrow::array::{
+    ArrayRef, BinaryBuilder, DictionaryArray, Int32Array, Int32Builder, StringArray, StringBuilder, UInt16Builder, UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use bam_tools::record::fields::{is_data_field, Fields};
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+/// Records per batch of [`Reader::record_batches`].
+pub const DEFAULT_BATCH_SIZE: usize = 65_536;
+
+const MISSING_QUAL: u8 = 0xFF;
+
+/// Column name of `field`, as in SAM.
+pub fn column_name(field: Fields) -> &'static str {
+    match field {
+        Fields::RefID => "rname",
+        Fields::Pos => "pos",
+        Fields::Mapq => "mapq",
+        Fields::Bin => "bin",
+        Fields::Flags => "flag",
+        Fields::NextRefID => "rnext",
+        Fields::NextPos => "pnext",
+        Fields::TemplateLength => "tlen",
+        Fields::ReadName => "qname",
+        Fields::RawCigar => "cigar",
+        Fields::RawSequence => "seq",
+        Fields::RawQual => "qual",
+        Fields::RawTags => "tags",
+        _ => panic!("{} is not a data field.", field),
+    }
+}
+
+/// Arrow field of GBAM `field`. Reference sequences are dictionary encoded
+/// names, positions 0-based, CIGAR and sequence strings, qualities binary
+/// Phred values and tags binary BAM encoded, or tab separated SAM text if
+/// `sam_tags`. Missing values (reference `*`, sequence or qualities) are
+/// null.
+pub fn arrow_field(field: Fields, sam_tags: bool) -> Field {
+    let data_type = match field {
+        Fields::RawTags if sam_tags => DataType::Utf8,
+        Fields::RefID | Fields::NextRefID => DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+        Fields::Pos | Fields::NextPos | Fields::TemplateLength => DataType::Int32,
+        Fields::Mapq => DataType::UInt8,
+        Fields::Bin | Fields::Flags => DataType::UInt16,
+        Fields::ReadName | Fields::RawCigar | Fields::RawSequence => DataType::Utf8,
+        _ => DataType::Binary,
+    };
+    let nullable = matches!(field, Fields::RefID | Fields::NextRefID | Fields::RawSequence | Fields::RawQual);
+    Field::new(column_name(field), data_type, nullable)
+}
+
+/// Schema of batches with `fields` columns, in the given order.
+pub fn arrow_schema(fields: &[Fields], sam_tags: bool) -> SchemaRef {
+    Arc::new(Schema::new(fields.iter().map(|&field| arrow_field(field, sam_tags)).collect::<Vec<_>>()))
+}
+
+enum ColumnBuilder {
+    // Reference ids, turned into dictionary of reference names.
+    RefId(Int32Builder),
+    Int32(Int32Builder),
+    UInt8(UInt8Builder),
+    UInt16(UInt16Builder),
+    Utf8(StringBuilder),
+    Binary(BinaryBuilder),
+    // Tags as SAM text.
+    SamTags(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(field: Fields, sam_tags: bool) -> Self {
+        match field {
+            Fields::RawTags if sam_tags => ColumnBuilder::SamTags(StringBuilder::new()),
+            Fields::RefID | Fields::NextRefID => ColumnBuilder::RefId(Int32Builder::new()),
+            Fields::Pos | Fields::NextPos | Fields::TemplateLength => ColumnBuilder::Int32(Int32Builder::new()),
+            Fields::Mapq => ColumnBuilder::UInt8(UInt8Builder::new()),
+            Fields::Bin | Fields::Flags => ColumnBuilder::UInt16(UInt16Builder::new()),
+            Fields::ReadName | Fields::RawCigar | Fields::RawSequence => ColumnBuilder::Utf8(StringBuilder::new()),
+            _ => ColumnBuilder::Binary(BinaryBuilder::new()),
+        }
+    }
+
+    fn append(&mut self, field: Fields, rec: &GbamRecord, buf: &mut String, line: &mut Vec<u8>) {
+        match (self, field) {
+            (ColumnBuilder::RefId(builder), Fields::RefID) => builder.append_option(rec.refid.filter(|&id| id >= 0)),
+            (ColumnBuilder::RefId(builder), _) => builder.append_option(rec.next_ref_id.filter(|&id| id >= 0)),
+            (ColumnBuilder::Int32(builder), Fields::Pos) => builder.append_value(rec.pos.unwrap()),
+            (ColumnBuilder::Int32(builder), Fields::NextPos) => builder.append_value(rec.next_pos.unwrap()),
+            (ColumnBuilder::Int32(builder), _) => builder.append_value(rec.tlen.unwrap()),
+            (ColumnBuilder::UInt8(builder), _) => builder.append_value(rec.mapq.unwrap()),
+            (ColumnBuilder::UInt16(builder), Fields::Bin) => builder.append_value(rec.bin.unwrap()),
+            (ColumnBuilder::UInt16(builder), _) => builder.append_value(rec.flag.unwrap()),
+            (ColumnBuilder::Utf8(builder), Fields::ReadName) => {
+                let name = rec.read_name.as_ref().unwrap();
+                builder.append_value(String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(name)));
+            }
+            (ColumnBuilder::Utf8(builder), Fields::RawCigar) => {
+                buf.clear();
+                write!(buf, "{}", rec.cigar.as_ref().unwrap()).unwrap();
+                builder.append_value(buf.as_str());
+            }
+            (ColumnBuilder::Utf8(builder), _) => {
+                let seq = rec.seq.as_ref().unwrap();
+                builder.append_option(Some(seq.as_str()).filter(|seq| !seq.is_empty()));
+            }
+            (ColumnBuilder::Binary(builder), Fields::RawQual) => {
+                let qual = rec.qual.as_ref().unwrap();
+                builder.append_option(Some(qual).filter(|qual| qual.first().is_some_and(|&q| q != MISSING_QUAL)));
+            }
+            (ColumnBuilder::Binary(builder), _) => builder.append_value(rec.tags.as_ref().unwrap()),
+            (ColumnBuilder::SamTags(builder), _) => {
+                line.clear();
+                write_sam_tags(rec.tags.as_ref().unwrap(), line);
+                // Tags are written with leading tab.
+                builder.append_value(String::from_utf8_lossy(line.get(1..).unwrap_or_default()));
+            }
+        }
+    }
+
+    fn finish(&mut self, ref_names: &Arc<StringArray>) -> ArrayRef {
+        match self {
+            ColumnBuilder::RefId(builder) => {
+                let keys: Int32Array = builder.finish();
+                let values: ArrayRef = ref_names.clone();
+                Arc::new(DictionaryArray::<Int32Type>::try_new(keys, values).unwrap())
+            }
+            ColumnBuilder::Int32(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::UInt8(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::UInt16(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Utf8(builder) | ColumnBuilder::SamTags(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Binary(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Iterator over Arrow record batches of records, see
+/// [`Reader::record_batches`]. Implements [`RecordBatchReader`], so it can
+/// be passed to Arrow IPC writers, Polars or DataFusion.
+pub struct RecordBatches<'a> {
+    records: Records<'a>,
+    fields: Vec<Fields>,
+    schema: SchemaRef,
+    ref_names: Arc<StringArray>,
+    batch_size: usize,
+    sam_tags: bool,
+}
+
+impl<'a> RecordBatches<'a> {
+    /// Batches of `fields` columns of `records`, which have to fetch these
+    /// fields. Reference ids are resolved to names of `ref_seqs`.
+    pub fn new(records: Records<'a>, fields: &[Fields], ref_seqs: &[(String, u32)], batch_size: usize) -> Self {
+        assert!(fields.iter().all(is_data_field), "Only data fields can be exported.");
+        assert!(batch_size > 0, "Batch size has to be positive.");
+        Self {
+            records,
+            fields: fields.to_vec(),
+            schema: arrow_schema(fields, false),
+            ref_names: Arc::new(ref_seqs.iter().map(|(name, _)| Some(name.as_str())).collect()),
+            batch_size,
+            sam_tags: false,
+        }
+    }
+
+    /// Tags are exported as tab separated SAM text (`NM:i:1\tMD:Z:26`)
+    /// instead of BAM encoded binary, e.g. for SQL engines.
+    pub fn with_sam_tags(mut self) -> Self {
+        self.sam_tags = true;
+        self.schema = arrow_schema(&self.fields, true);
+        self
+    }
+}
+
+impl Iterator for RecordBatches<'_> {
+    type Item = Result<RecordBatch, ArrowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut builders: Vec<_> = self.fields.iter().map(|&field| ColumnBuilder::new(field, self.sam_tags)).collect();
+        let (mut buf, mut line) = (String::new(), Vec::new());
+        let mut len = 0;
+        while len < self.batch_size {
+            let rec = match self.records.next_rec() {
+                Some(rec) => rec,
+                None => break,
+            };
+            for (builder, &field) in builders.iter_mut().zip(self.fields.iter()) {
+                builder.append(field, rec, &mut buf, &mut line);
+            }
+            len += 1;
+        }
+        if len == 0 {
+            return None;
+        }
+        let columns = builders.iter_mut().map(|builder| builder.finish(&self.ref_names)).collect();
+        Some(RecordBatch::try_new(self.schema.clone(), columns))
+    }
+}
+
+impl RecordBatchReader for RecordBatches<'_> {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+/// Writes batches as Arrow IPC stream, readable e.g. by
+/// `polars.read_ipc_stream`. Returns number of records.
+pub fn write_ipc_stream(batches: RecordBatches<'_>, out: &mut dyn io::Write) -> Result<usize, ArrowError> {
+    let mut writer = StreamWriter::try_new(out, &batches.schema())?;
+    let mut records = 0;
+    for batch in batches {
+        let batch = batch?;
+        records += batch.num_rows();
+        writer.write(&batch)?;
+    }
+    writer.finish()?;
+    Ok(records)
+}
+
+impl Reader {
+    /// Get iterator over Arrow record batches of up to `batch_size` records
+    /// with only `fields` columns decoded (see [`Reader::records_with`]).
+    /// Reader filters apply. Template is restored when the iterator is
+    /// dropped.
+    pub fn record_batches(&mut self, fields: &[Fields], batch_size: usize) -> RecordBatches<'_> {
+        let ref_seqs = self.file_meta.get_ref_seqs().clone();
+        RecordBatches::new(self.records_with(fields), fields, &ref_seqs, batch_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{UInt16Type, UInt8Type};
+    use std::io::Cursor;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t99\tchr1\t10\t60\t2M1I1M\t=\t50\t44\tACGT\tABCD\tNM:i:1\n\
+r2\t0\tchr2\t20\t30\t3M\t*\t0\t0\tGGA\t*\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_record_batches() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new());
+
+        let fields = [Fields::ReadName, Fields::RefID, Fields::Pos, Fields::Mapq, Fields::Flags, Fields::RawCigar, Fields::RawSequence, Fields::RawQual];
+        let batches: Vec<_> = reader.record_batches(&fields, 2).collect::<Result<_, _>>().unwrap();
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(), vec![2, 1]);
+        let batch = &batches[0];
+        assert_eq!(batch.schema().field(1).name(), "rname");
+        let names = batch.column(0).as_string::<i32>();
+        assert_eq!((names.value(0), names.value(1)), ("r1", "r2"));
+        let refs = batch.column(1).as_dictionary::<Int32Type>();
+        let ref_names = refs.values().as_string::<i32>();
+        assert_eq!(ref_names.value(refs.keys().value(1) as usize), "chr2");
+        assert_eq!(batch.column(2).as_primitive::<Int32Type>().values().to_vec(), vec![9, 19]);
+        assert_eq!(batch.column(3).as_primitive::<UInt8Type>().value(1), 30);
+        assert_eq!(batch.column(4).as_primitive::<UInt16Type>().value(0), 99);
+        assert_eq!(batch.column(5).as_string::<i32>().value(0), "2M1I1M");
+        assert_eq!(batch.column(6).as_string::<i32>().value(1), "GGA");
+        let quals = batch.column(7).as_binary::<i32>();
+        assert_eq!(quals.value(0), &[32, 33, 34, 35]);
+        assert!(quals.is_null(1));
+
+        // Unmapped record has no reference and no sequence.
+        let batch = &batches[1];
+        assert!(batch.column(1).is_null(0));
+        assert!(batch.column(6).is_null(0));
+
+        let mut out = Vec::new();
+        assert_eq!(write_ipc_stream(reader.record_batches(&fields, 2), &mut out).unwrap(), 3);
+        let stream = arrow::ipc::reader::StreamReader::try_new(Cursor::new(out), None).unwrap();
+        assert_eq!(stream.map(|batch| batch.unwrap().num_rows()).sum::<usize>(), 3);
+
+        let batch = reader.record_batches(&[Fields::RawTags], 3).with_sam_tags().next().unwrap().unwrap();
+        let tags = batch.column(0).as_string::<i32>();
+        assert_eq!((tags.value(0), tags.value(1)), ("NM:i:1", ""));
+    }
+}
@@ -0,0 +1,74 @@
+use super::arrow::RecordBatches;
+use arrow::record_batch::RecordBatchReader;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use std::io::Write;
+
+/// Records per row group of Parquet files.
+pub const ROW_GROUP_SIZE: usize = 1_048_576;
+
+/// Writes batches as Parquet file with Zstandard compressed columns,
+/// named as in SAM (see [`super::arrow::column_name`]). Tags are written as
+/// SAM text so SQL engines can match them with string functions. Returns
+/// number of records.
+pub fn write_parquet<W: Write + Send>(batches: RecordBatches<'_>, out: W) -> Result<usize, ParquetError> {
+    let batches = batches.with_sam_tags();
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build();
+    let mut writer = ArrowWriter::try_new(out, batches.schema(), Some(props))?;
+    let mut records = 0;
+    for batch in batches {
+        let batch = batch?;
+        records += batch.num_rows();
+        writer.write(&batch)?;
+    }
+    writer.close()?;
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::Int32Type;
+    use bam_tools::record::fields::Fields;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tempdir::TempDir;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t99\tchr1\t10\t60\t2M1I1M\t=\t50\t44\tACGT\tABCD\tNM:i:1\tMD:Z:3\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_write_parquet() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new());
+
+        let fields = [Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::RawTags];
+        let dir = TempDir::new("gbam_parquet_test").unwrap();
+        let path = dir.path().join("test.parquet");
+        let out = std::fs::File::create(&path).unwrap();
+        assert_eq!(write_parquet(reader.record_batches(&fields, 1), out).unwrap(), 2);
+
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let batch = arrow::compute::concat_batches(&batches[0].schema(), &batches).unwrap();
+        let names: Vec<_> = batch.schema().fields().iter().map(|field| field.name().clone()).collect();
+        assert_eq!(names, vec!["rname", "pos", "cigar", "tags"]);
+        let refs = batch.column(0).as_dictionary::<Int32Type>();
+        assert_eq!(refs.values().as_string::<i32>().value(refs.keys().value(0) as usize), "chr1");
+        assert!(refs.is_null(1));
+        assert_eq!(batch.column(1).as_primitive::<Int32Type>().value(0), 9);
+        assert_eq!(batch.column(2).as_string::<i32>().value(0), "2M1I1M");
+        assert_eq!(batch.column(3).as_string::<i32>().value(0), "NM:i:1\tMD:Z:3");
+    }
+}
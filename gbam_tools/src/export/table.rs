@@ -0,0 +1,249 @@
+use crate::bam::gbam_to_sam::write_sam_tags;
+use crate::reader::record::GbamRecord;
+use crate::reader::records::Records;
+use bam_tools::record::fields::Fields;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const MISSING_QUAL: u8 = 0xFF;
+
+/// Column of tabular export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableColumn {
+    /// Reference sequence id, -1 for none.
+    RefId,
+    /// Reference sequence name, `*` for none.
+    RefName,
+    /// 0-based position.
+    Pos,
+    Mapq,
+    Bin,
+    Flag,
+    NextRefId,
+    NextRefName,
+    /// 0-based mate position.
+    NextPos,
+    Tlen,
+    ReadName,
+    Cigar,
+    Seq,
+    /// Phred+33 qualities.
+    Qual,
+    /// SAM tags separated by spaces.
+    Tags,
+}
+
+impl TableColumn {
+    pub fn name(self) -> &'static str {
+        match self {
+            TableColumn::RefId => "refid",
+            TableColumn::RefName => "rname",
+            TableColumn::Pos => "pos",
+            TableColumn::Mapq => "mapq",
+            TableColumn::Bin => "bin",
+            TableColumn::Flag => "flag",
+            TableColumn::NextRefId => "next_refid",
+            TableColumn::NextRefName => "rnext",
+            TableColumn::NextPos => "pnext",
+            TableColumn::Tlen => "tlen",
+            TableColumn::ReadName => "qname",
+            TableColumn::Cigar => "cigar",
+            TableColumn::Seq => "seq",
+            TableColumn::Qual => "qual",
+            TableColumn::Tags => "tags",
+        }
+    }
+
+    /// Field the column is decoded from.
+    pub fn field(self) -> Fields {
+        match self {
+            TableColumn::RefId | TableColumn::RefName => Fields::RefID,
+            TableColumn::Pos => Fields::Pos,
+            TableColumn::Mapq => Fields::Mapq,
+            TableColumn::Bin => Fields::Bin,
+            TableColumn::Flag => Fields::Flags,
+            TableColumn::NextRefId | TableColumn::NextRefName => Fields::NextRefID,
+            TableColumn::NextPos => Fields::NextPos,
+            TableColumn::Tlen => Fields::TemplateLength,
+            TableColumn::ReadName => Fields::ReadName,
+            TableColumn::Cigar => Fields::RawCigar,
+            TableColumn::Seq => Fields::RawSequence,
+            TableColumn::Qual => Fields::RawQual,
+            TableColumn::Tags => Fields::RawTags,
+        }
+    }
+
+    /// Parses comma separated list, e.g. `refid,pos,mapq,flag,tlen`.
+    pub fn parse_list(s: &str) -> io::Result<Vec<TableColumn>> {
+        s.split(',').map(str::parse).collect()
+    }
+
+    /// Fields decoded for `columns`.
+    pub fn fields(columns: &[TableColumn]) -> Vec<Fields> {
+        let mut fields: Vec<Fields> = Vec::new();
+        for column in columns {
+            if !fields.contains(&column.field()) {
+                fields.push(column.field());
+            }
+        }
+        fields
+    }
+
+    /// Appends text value of column as written by [`write_table`].
+    pub fn write_value(self, rec: &GbamRecord, ref_seqs: &[(String, u32)], out: &mut Vec<u8>) {
+        let ref_name = |ref_id: i32| ref_seqs.get(ref_id as usize).map_or("*", |(name, _)| name.as_str());
+        match self {
+            TableColumn::RefId => write!(out, "{}", rec.refid.unwrap()).unwrap(),
+            TableColumn::RefName => out.extend_from_slice(ref_name(rec.refid.unwrap()).as_bytes()),
+            TableColumn::Pos => write!(out, "{}", rec.pos.unwrap()).unwrap(),
+            TableColumn::Mapq => write!(out, "{}", rec.mapq.unwrap()).unwrap(),
+            TableColumn::Bin => write!(out, "{}", rec.bin.unwrap()).unwrap(),
+            TableColumn::Flag => write!(out, "{}", rec.flag.unwrap()).unwrap(),
+            TableColumn::NextRefId => write!(out, "{}", rec.next_ref_id.unwrap()).unwrap(),
+            TableColumn::NextRefName => out.extend_from_slice(ref_name(rec.next_ref_id.unwrap()).as_bytes()),
+            TableColumn::NextPos => write!(out, "{}", rec.next_pos.unwrap()).unwrap(),
+            TableColumn::Tlen => write!(out, "{}", rec.tlen.unwrap()).unwrap(),
+            TableColumn::ReadName => {
+                let name = rec.read_name.as_ref().unwrap();
+                out.extend_from_slice(name.strip_suffix(&[0]).unwrap_or(name));
+            }
+            TableColumn::Cigar => {
+                let cigar = rec.cigar.as_ref().unwrap();
+                if cigar.is_empty() {
+                    out.push(b'*');
+                }
+                write!(out, "{}", cigar).unwrap();
+            }
+            TableColumn::Seq => match rec.seq.as_ref().unwrap().as_bytes() {
+                [] => out.push(b'*'),
+                seq => out.extend_from_slice(seq),
+            },
+            TableColumn::Qual => match rec.qual.as_ref().unwrap().as_slice() {
+                [] | [MISSING_QUAL, ..] => out.push(b'*'),
+                qual => out.extend(qual.iter().map(|&q| q + 33)),
+            },
+            TableColumn::Tags => {
+                let start = out.len();
+                write_sam_tags(rec.tags.as_ref().unwrap(), out);
+                // Tags are written with leading tabs.
+                for byte in out[start..].iter_mut().filter(|byte| **byte == b'\t') {
+                    *byte = b' ';
+                }
+                if out.len() > start {
+                    out.remove(start);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for TableColumn {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let column = match s.trim().to_ascii_lowercase().as_str() {
+            "refid" => TableColumn::RefId,
+            "rname" => TableColumn::RefName,
+            "pos" => TableColumn::Pos,
+            "mapq" => TableColumn::Mapq,
+            "bin" => TableColumn::Bin,
+            "flag" => TableColumn::Flag,
+            "next_refid" => TableColumn::NextRefId,
+            "rnext" => TableColumn::NextRefName,
+            "pnext" => TableColumn::NextPos,
+            "tlen" => TableColumn::Tlen,
+            "qname" => TableColumn::ReadName,
+            "cigar" => TableColumn::Cigar,
+            "seq" => TableColumn::Seq,
+            "qual" => TableColumn::Qual,
+            "tags" => TableColumn::Tags,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown column {}, expected refid, rname, pos, mapq, bin, flag, next_refid, rnext, pnext, tlen, qname, cigar, seq, qual or tags.",
+                        s
+                    ),
+                ))
+            }
+        };
+        Ok(column)
+    }
+}
+
+/// Writes header line of column names and one line per record, values
+/// separated by `delimiter`. With `,` values containing commas or quotes
+/// are quoted as in CSV. `records` have to fetch fields of `columns` (see
+/// [`TableColumn::fields`]). Returns number of records.
+pub fn write_table(mut records: Records<'_>, columns: &[TableColumn], ref_seqs: &[(String, u32)], delimiter: u8, out: &mut dyn Write) -> io::Result<u64> {
+    let mut line = Vec::new();
+    for (idx, column) in columns.iter().enumerate() {
+        if idx > 0 {
+            line.push(delimiter);
+        }
+        line.extend_from_slice(column.name().as_bytes());
+    }
+    line.push(b'\n');
+    out.write_all(&line)?;
+
+    let (mut value, mut count) = (Vec::new(), 0);
+    while let Some(rec) = records.next_rec() {
+        line.clear();
+        for (idx, column) in columns.iter().enumerate() {
+            if idx > 0 {
+                line.push(delimiter);
+            }
+            value.clear();
+            column.write_value(rec, ref_seqs, &mut value);
+            if delimiter == b',' && value.iter().any(|&byte| byte == b',' || byte == b'"') {
+                line.push(b'"');
+                for &byte in value.iter() {
+                    if byte == b'"' {
+                        line.push(b'"');
+                    }
+                    line.push(byte);
+                }
+                line.push(b'"');
+            } else {
+                line.extend_from_slice(&value);
+            }
+        }
+        line.push(b'\n');
+        out.write_all(&line)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t99\tchr1\t10\t60\t2M1I1M\t=\t50\t44\tACGT\tABCD\tNM:i:1\tXA:Z:chr2,+5,4M,0\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_write_table() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new());
+        let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+
+        let columns = TableColumn::parse_list("refid,pos,mapq,flag,tlen").unwrap();
+        let mut out = Vec::new();
+        let count = write_table(reader.records_with(&TableColumn::fields(&columns)), &columns, &ref_seqs, b'\t', &mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(String::from_utf8(out).unwrap(), "refid\tpos\tmapq\tflag\ttlen\n0\t9\t60\t99\t44\n-1\t-1\t0\t4\t0\n");
+
+        // Tags with commas are quoted in CSV.
+        let columns = TableColumn::parse_list("qname,rname,cigar,qual,tags").unwrap();
+        let mut out = Vec::new();
+        write_table(reader.records_with(&TableColumn::fields(&columns)), &columns, &ref_seqs, b',', &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "qname,rname,cigar,qual,tags\nr1,chr1,2M1I1M,ABCD,\"NM:i:1 XA:Z:chr2,+5,4M,0\"\nu1,*,*,*,\n"
+        );
+        assert!("seqlen".parse::<TableColumn>().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::error::GbamError;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::regions::Region;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::ops::Range;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Opaque reader handle.
+pub struct GbamReader {
+    reader: Reader,
+    // Current range and ranges left, with region records have to overlap.
+    cur: Range<usize>,
+    region: Option<Region>,
+    ranges: VecDeque<(Range<usize>, Region)>,
+    rec: GbamRecord,
+    cigar: Vec<u32>,
+    // Sequence with terminating NUL.
+    seq: Vec<u8>,
+}
+
+/// Record filled by [`gbam_next_record`]. Pointers stay valid until the next
+/// call with the same reader.
+#[repr(C)]
+pub struct GbamCRecord {
+    pub ref_id: i32,
+    /// 0-based leftmost position.
+    pub pos: i32,
+    pub mapq: u8,
+    pub bin: u16,
+    pub flag: u16,
+    pub next_ref_id: i32,
+    pub next_pos: i32,
+    pub tlen: i32,
+    /// NUL terminated read name.
+    pub read_name: *const c_char,
+    /// CIGAR operations encoded as in BAM: length << 4 | op.
+    pub cigar: *const u32,
+    pub n_cigar: u32,
+    /// NUL terminated sequence of `l_seq` bases.
+    pub seq: *const c_char,
+    pub l_seq: u32,
+    /// `l_seq` Phred qualities, 0xFF if missing.
+    pub qual: *const u8,
+    /// Tags encoded as in BAM.
+    pub tags: *const u8,
+    pub l_tags: u32,
+}
+
+/// Opens GBAM file. Returns NULL on error, see [`gbam_last_error`].
+///
+/// # Safety
+/// `path` has to be NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_open(path: *const c_char) -> *mut GbamReader {
+    if path.is_null() {
+        set_error("Path is NULL.");
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(e) => {
+            set_error(e);
+            return ptr::null_mut();
+        }
+    };
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    match File::open(path).map_err(GbamError::from).and_then(|file| Reader::new(file, template)) {
+        Ok(reader) => Box::into_raw(Box::new(GbamReader {
+            cur: 0..reader.amount,
+            reader,
+            region: None,
+            ranges: VecDeque::new(),
+            rec: GbamRecord::default(),
+            cigar: Vec::new(),
+            seq: Vec::new(),
+        })),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Restricts following [`gbam_next_record`] calls to records overlapping
+/// region given as in samtools view, e.g. `chr1:1,000-2,000`. Records have to
+/// be coordinate sorted. Returns 0, or -1 on error.
+///
+/// # Safety
+/// `handle` has to come from [`gbam_open`], `region` has to be NUL terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_fetch_region(handle: *mut GbamReader, region: *const c_char) -> c_int {
+    let handle = match handle.as_mut() {
+        Some(handle) if !region.is_null() => handle,
+        _ => {
+            set_error("Reader or region is NULL.");
+            return -1;
+        }
+    };
+    let region = CStr::from_ptr(region).to_string_lossy();
+    let region = match Region::parse(&region, handle.reader.file_meta.get_ref_seqs()) {
+        Ok(region) => region,
+        Err(e) => {
+            set_error(e);
+            return -1;
+        }
+    };
+    handle.ranges = match handle.reader.region_record_ranges(&[region]) {
+        Ok(ranges) => ranges.into(),
+        Err(e) => {
+            set_error(e);
+            return -1;
+        }
+    };
+    handle.cur = 0..0;
+    handle.region = None;
+    0
+}
+
+/// Fills `rec` with the next record. Returns 1, 0 at the end, or -1 on error.
+///
+/// # Safety
+/// `handle` has to come from [`gbam_open`], `rec` has to be valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_next_record(handle: *mut GbamReader, rec: *mut GbamCRecord) -> c_int {
+    let (handle, out) = match (handle.as_mut(), rec.as_mut()) {
+        (Some(handle), Some(out)) => (handle, out),
+        _ => {
+            set_error("Reader or record is NULL.");
+            return -1;
+        }
+    };
+    loop {
+        if handle.cur.is_empty() {
+            match handle.ranges.pop_front() {
+                Some((range, region)) => {
+                    handle.cur = range;
+                    handle.region = Some(region);
+                    continue;
+                }
+                None => return 0,
+            }
+        }
+        let rec_num = handle.cur.start;
+        handle.cur.start += 1;
+        if !handle.reader.passes_filters(rec_num) {
+            continue;
+        }
+        handle.reader.fill_record(rec_num, &mut handle.rec);
+        if handle.region.is_none_or(|region| region.overlaps(&handle.rec)) {
+            break;
+        }
+    }
+
+    let rec = &handle.rec;
+    handle.cigar.clear();
+    handle.cigar.extend(rec.cigar.as_ref().unwrap().0.iter().map(|op| op.0));
+    handle.seq.clear();
+    handle.seq.extend_from_slice(rec.seq.as_ref().unwrap().as_bytes());
+    handle.seq.push(0);
+    let qual = rec.qual.as_ref().unwrap();
+    let tags = rec.tags.as_ref().unwrap();
+    *out = GbamCRecord {
+        ref_id: rec.refid.unwrap(),
+        pos: rec.pos.unwrap(),
+        mapq: rec.mapq.unwrap(),
+        bin: rec.bin.unwrap(),
+        flag: rec.flag.unwrap(),
+        next_ref_id: rec.next_ref_id.unwrap(),
+        next_pos: rec.next_pos.unwrap(),
+        tlen: rec.tlen.unwrap(),
+        // Read name is stored with its NUL.
+        read_name: rec.read_name.as_ref().unwrap().as_ptr() as *const c_char,
+        cigar: handle.cigar.as_ptr(),
+        n_cigar: handle.cigar.len() as u32,
+        seq: handle.seq.as_ptr() as *const c_char,
+        l_seq: (handle.seq.len() - 1) as u32,
+        qual: qual.as_ptr(),
+        tags: tags.as_ptr(),
+        l_tags: tags.len() as u32,
+    };
+    1
+}
+
+/// Closes reader. NULL is ignored.
+///
+/// # Safety
+/// `handle` has to come from [`gbam_open`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_close(handle: *mut GbamReader) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Message of the last error on this thread, or NULL. Valid until the next
+/// failing call.
+#[no_mangle]
+pub extern "C" fn gbam_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use tempdir::TempDir;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t5M\t*\t0\t0\tACGTA\tABCDE\tNM:i:1\n\
+r2\t16\tchr1\t100\t30\t2M1D3M\t*\t0\t0\tACGTA\t*\n";
+
+    #[test]
+    fn test_ffi_reader() {
+        let dir = TempDir::new("gbam_ffi_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        write_sam(SAM, |sam_header, ref_seqs| {
+            Writer::new_no_stats(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false)
+        });
+
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let handle = gbam_open(path.as_ptr());
+            assert!(!handle.is_null());
+            let mut rec = std::mem::MaybeUninit::<GbamCRecord>::uninit();
+            assert_eq!(gbam_next_record(handle, rec.as_mut_ptr()), 1);
+            let first = rec.assume_init_ref();
+            assert_eq!((first.pos, first.mapq, first.l_seq), (9, 60, 5));
+            assert_eq!(CStr::from_ptr(first.read_name).to_str().unwrap(), "r1");
+            assert_eq!(CStr::from_ptr(first.seq).to_str().unwrap(), "ACGTA");
+            assert_eq!(std::slice::from_raw_parts(first.qual, 5), &[32, 33, 34, 35, 36]);
+            assert_eq!(std::slice::from_raw_parts(first.tags, first.l_tags as usize), b"NMC\x01");
+            assert_eq!(gbam_next_record(handle, rec.as_mut_ptr()), 1);
+            assert_eq!(gbam_next_record(handle, rec.as_mut_ptr()), 0);
+
+            let region = CString::new("chr1:101-101").unwrap();
+            assert_eq!(gbam_fetch_region(handle, region.as_ptr()), 0);
+            assert_eq!(gbam_next_record(handle, rec.as_mut_ptr()), 1);
+            let second = rec.assume_init_ref();
+            assert_eq!((second.flag, second.n_cigar), (16, 3));
+            assert_eq!(*second.cigar.add(1), 1 << 4 | 2);
+            assert_eq!(gbam_next_record(handle, rec.as_mut_ptr()), 0);
+
+            let region = CString::new("chr2").unwrap();
+            assert_eq!(gbam_fetch_region(handle, region.as_ptr()), -1);
+            assert!(!gbam_last_error().is_null());
+            gbam_close(handle);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::meta::FileMeta;
+use crate::reader::column::decompress_block;
+use crate::reader::reader::{init_col, Reader};
+use crate::reader::record::GbamRecord;
+use crate::writer::{open_for_update, rewrite_meta};
+use bam_tools::record::fields::{field_type, is_data_field, var_size_field_to_index, FieldType, Fields};
+use byteorder::{LittleEndian, ReadBytesExt};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// MD5 digest of the content of one data field of all records.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ColumnDigest {
+    pub field: Fields,
+    pub md5: String,
+}
+
+/// Content digests of GBAM file. They are calculated over uncompressed
+/// values of records, so they do not depend on codecs or on how columns are
+/// split into blocks: files with equal records have equal fingerprints even
+/// if they are not byte-identical (e.g. after repacking with other codecs).
+/// Header is not part of the fingerprint.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub records: u64,
+    /// Digests of data fields in [`Fields::iterator`] order.
+    pub columns: Vec<ColumnDigest>,
+    /// Digest of record count and all column digests.
+    pub file: String,
+}
+
+impl Fingerprint {
+    /// Decompresses every block of data fields (in parallel by fields).
+    /// Fixed sized fields are hashed as concatenation of values, variable
+    /// sized ones as u32 length followed by bytes for every value.
+    pub fn compute(reader: &Reader) -> io::Result<Self> {
+        let bytes = (*reader.storage).as_ref();
+        let file_meta = &reader.file_meta;
+        let fields: Vec<Fields> = Fields::iterator().filter(|field| is_data_field(field)).copied().collect();
+        let tags = (!file_meta.tag_columns().is_empty()).then(|| tags_digest(reader));
+        let digests = fields
+            .par_iter()
+            .map(|field| match (field, tags) {
+                (Fields::RawTags, Some(tags)) => Ok(tags),
+                _ => column_digest(file_meta, bytes, field),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let records = reader.amount as u64;
+        let mut file_digest = md5::Context::new();
+        file_digest.consume(records.to_le_bytes());
+        for (field, digest) in fields.iter().zip(digests.iter()) {
+            file_digest.consume(field.to_string().as_bytes());
+            file_digest.consume(digest.0);
+        }
+        Ok(Self {
+            records,
+            columns: fields
+                .into_iter()
+                .zip(digests)
+                .map(|(field, digest)| ColumnDigest {
+                    field,
+                    md5: format!("{:x}", digest),
+                })
+                .collect(),
+            file: format!("{:x}", file_digest.compute()),
+        })
+    }
+
+    /// Fields which digests differ between fingerprints.
+    pub fn mismatched_columns(&self, other: &Fingerprint) -> Vec<Fields> {
+        self.columns
+            .iter()
+            .filter(|col| !other.columns.contains(col))
+            .map(|col| col.field)
+            .collect()
+    }
+}
+
+fn column_digest(file_meta: &FileMeta, bytes: &[u8], field: &Fields) -> io::Result<md5::Digest> {
+    let mut digest = md5::Context::new();
+    let mut data = Vec::new();
+    match field_type(field) {
+        FieldType::FixedSized => {
+            for block_num in 0..file_meta.view_blocks(field).len() {
+                read_block(file_meta, bytes, field, block_num, &mut data)?;
+                digest.consume(&data);
+            }
+        }
+        FieldType::VariableSized => {
+            // Index holds end offsets of values within their data block.
+            let mut index = BlockStream::new(var_size_field_to_index(field));
+            for (block_num, block) in file_meta.view_blocks(field).iter().enumerate() {
+                read_block(file_meta, bytes, field, block_num, &mut data)?;
+                let mut start = 0;
+                for _ in 0..block.numitems {
+                    let end = index.next_u32(file_meta, bytes)? as usize;
+                    let value = data.get(start..end).ok_or_else(|| damaged(field))?;
+                    digest.consume(((end - start) as u32).to_le_bytes());
+                    digest.consume(value);
+                    start = end;
+                }
+            }
+        }
+    }
+    Ok(digest.compute())
+}
+
+/// Digest of RawTags of file with tag columns, hashed as variable sized
+/// column of all tags of records.
+fn tags_digest(reader: &Reader) -> md5::Digest {
+    let mut digest = md5::Context::new();
+    let mut column = init_col(Fields::RawTags, &reader.storage, &reader.file_meta, &None, &None, &None, &None);
+    let mut rec = GbamRecord::default();
+    for rec_num in 0..reader.amount {
+        column.fill_record_field(rec_num, &mut rec);
+        let tags = rec.tags.as_ref().unwrap();
+        digest.consume((tags.len() as u32).to_le_bytes());
+        digest.consume(tags);
+    }
+    digest.compute()
+}
+
+/// Sequential reader of u32 values of fixed sized column.
+struct BlockStream {
+    field: Fields,
+    next_block: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BlockStream {
+    fn new(field: Fields) -> Self {
+        Self {
+            field,
+            next_block: 0,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn next_u32(&mut self, file_meta: &FileMeta, bytes: &[u8]) -> io::Result<u32> {
+        while self.pos == self.buf.len() {
+            if self.next_block == file_meta.view_blocks(&self.field).len() {
+                return Err(damaged(&self.field));
+            }
+            read_block(file_meta, bytes, &self.field, self.next_block, &mut self.buf)?;
+            self.next_block += 1;
+            self.pos = 0;
+        }
+        let mut value = &self.buf[self.pos..];
+        self.pos += 4;
+        value.read_u32::<LittleEndian>()
+    }
+}
+
+fn read_block(file_meta: &FileMeta, bytes: &[u8], field: &Fields, block_num: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    let block = &file_meta.view_blocks(field)[block_num];
+    let start = block.seekpos as usize;
+    let compressed = bytes
+        .get(start..start + block.block_size as usize)
+        .ok_or_else(|| damaged(field))?;
+    buf.resize(block.uncompressed_size as usize, 0);
+    if block.uncompressed_size > 0 {
+        decompress_block(compressed, buf, file_meta.get_field_codec(field))?;
+    }
+    Ok(())
+}
+
+fn damaged(field: &Fields) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Column {} is damaged.", field))
+}
+
+/// Computes fingerprint of GBAM file and stores it in the file meta, which is
+/// rewritten in place.
+pub fn store_fingerprint<P: AsRef<Path>>(path: P) -> io::Result<Fingerprint> {
+    let (mut file, mut file_info, mut file_meta) = open_for_update(path)?;
+    let fingerprint = Fingerprint::compute(&Reader::new_with_meta(file.try_clone()?, Default::default(), &Arc::new(file_meta.clone()), None)?)?;
+    file_meta.set_fingerprint(Some(fingerprint.clone()));
+    rewrite_meta(&mut file, &mut file_info, &file_meta)?;
+    Ok(fingerprint)
+}
+
+/// Recomputes fingerprint and compares it with the one stored in the file
+/// meta. Returns fields which content changed (empty if the file is intact)
+/// or `None` if no fingerprint is stored.
+pub fn verify_fingerprint(reader: &Reader) -> io::Result<Option<Vec<Fields>>> {
+    match reader.file_meta.fingerprint() {
+        Some(stored) => Ok(Some(Fingerprint::compute(reader)?.mismatched_columns(stored))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use std::io::Cursor;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\n\
+r2\t147\tchr1\t21\t60\t5M\t=\t11\t-15\tACGTA\t*\n";
+
+    fn in_memory_gbam(sam: &str, codec: Codecs) -> Reader {
+        let writer = write_sam(sam, |sam_header, ref_seqs| {
+            Writer::new_no_stats(Cursor::new(Vec::new()), vec![codec], 2, ref_seqs, sam_header, String::new(), false)
+        });
+        Reader::from_bytes(writer.into_inner().into_inner(), ParsingTemplate::new()).unwrap()
+    }
+
+    #[test]
+    fn test_fingerprint_independent_of_codecs() {
+        let lz4 = Fingerprint::compute(&in_memory_gbam(SAM, Codecs::Lz4)).unwrap();
+        let gzip = Fingerprint::compute(&in_memory_gbam(SAM, Codecs::Gzip)).unwrap();
+        assert_eq!(lz4, gzip);
+        assert_eq!(lz4.records, 2);
+        assert_eq!(lz4.columns.len(), 13);
+
+        let changed = Fingerprint::compute(&in_memory_gbam(&SAM.replace("NM:i:1", "NM:i:2"), Codecs::Lz4)).unwrap();
+        assert_ne!(lz4.file, changed.file);
+        assert_eq!(lz4.mismatched_columns(&changed), vec![Fields::RawTags]);
+    }
+}
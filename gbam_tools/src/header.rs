@@ -0,0 +1,106 @@
+use std::convert::TryInto;
+
+/// SAM header text stored at conversion, with its parsed records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SamHeader {
+    text: String,
+    records: Vec<HeaderRecord>,
+}
+
+/// Line of SAM header, e.g. `@SQ\tSN:chr1\tLN:248956422`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderRecord {
+    /// Two letter type without `@`, e.g. `SQ`.
+    pub record_type: String,
+    /// TAG:VALUE fields in header order, empty for `@CO`.
+    pub tags: Vec<(String, String)>,
+    /// Text of `@CO` line.
+    pub comment: Option<String>,
+}
+
+impl SamHeader {
+    /// Parses text of BAM header (with leading l_text), as stored in
+    /// [`crate::meta::FileMeta`].
+    pub fn from_bam_header(sam_header: &[u8]) -> Self {
+        let l_text = sam_header.get(..4).map_or(0, |l_text| u32::from_le_bytes(l_text.try_into().unwrap()) as usize);
+        let text = sam_header.get(4..).unwrap_or_default();
+        // Reference sequences follow the text, which may be NUL padded.
+        let text = &text[..l_text.min(text.len())];
+        let text = &text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())];
+        Self::parse(&String::from_utf8_lossy(text))
+    }
+
+    /// Parses SAM header text. Lines which are not header records are kept
+    /// in the text only.
+    pub fn parse(text: &str) -> Self {
+        Self {
+            text: text.to_owned(),
+            records: text.lines().filter_map(HeaderRecord::parse).collect(),
+        }
+    }
+
+    /// Header text as stored.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn records(&self) -> &[HeaderRecord] {
+        &self.records
+    }
+
+    /// Records of `record_type`, e.g. `RG`.
+    pub fn records_of<'a>(&'a self, record_type: &'a str) -> impl Iterator<Item = &'a HeaderRecord> {
+        self.records.iter().filter(move |rec| rec.record_type == record_type)
+    }
+
+    /// The @HD line.
+    pub fn hd(&self) -> Option<&HeaderRecord> {
+        self.records_of("HD").next()
+    }
+}
+
+impl HeaderRecord {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_prefix('@')?;
+        let (record_type, fields) = line.split_once('\t').unwrap_or((line, ""));
+        if record_type.len() != 2 {
+            return None;
+        }
+        if record_type == "CO" {
+            return Some(Self { record_type: record_type.to_owned(), tags: Vec::new(), comment: Some(fields.to_owned()) });
+        }
+        let tags = fields
+            .split('\t')
+            .filter_map(|field| field.split_once(':'))
+            .map(|(tag, value)| (tag.to_owned(), value.to_owned()))
+            .collect();
+        Some(Self { record_type: record_type.to_owned(), tags, comment: None })
+    }
+
+    /// Value of tag, e.g. `get("SN")`.
+    pub fn get(&self, tag: &str) -> Option<&str> {
+        self.tags.iter().find(|(name, _)| name == tag).map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let text = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@RG\tID:grp\tSM:s1\n@PG\tID:bwa\tPN:bwa\tCL:bwa mem ref.fa r.fq\n@CO\tfree text\n\0";
+        let mut bam_header = (text.len() as u32).to_le_bytes().to_vec();
+        bam_header.extend_from_slice(text.as_bytes());
+        bam_header.extend_from_slice(&0u32.to_le_bytes());
+
+        let header = SamHeader::from_bam_header(&bam_header);
+        assert_eq!(header.text(), text.trim_end_matches('\0'));
+        assert_eq!(header.records().len(), 5);
+        assert_eq!(header.hd().unwrap().get("SO"), Some("coordinate"));
+        assert_eq!(header.records_of("SQ").next().unwrap().get("LN"), Some("1000"));
+        assert_eq!(header.records_of("PG").next().unwrap().get("CL"), Some("bwa mem ref.fa r.fq"));
+        assert_eq!(header.records_of("CO").next().unwrap().comment.as_deref(), Some("free text"));
+        assert!(SamHeader::from_bam_header(&[]).records().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::writer::{open_for_update, rewrite_meta};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Reference bases covered by one window of the index, as in BAI linear
+/// index.
+pub const INDEX_WINDOW: u32 = 1 << 14;
+
+/// Linear interval index of coordinate sorted file. For every window of
+/// reference sequence holds number of the first record (in stored order)
+/// which may overlap it, so region fetch does not have to look back over
+/// the longest alignment of the file. Windows after the last record
+/// overlapping reference sequence are not stored.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IntervalIndex {
+    window: u32,
+    // Per reference sequence.
+    linear: Vec<Vec<u64>>,
+}
+
+impl IntervalIndex {
+    /// Builds index from RefID, Pos and RawCigar of all records. Records have
+    /// to be coordinate sorted.
+    pub fn build(reader: &mut Reader) -> Self {
+        let mut builder = IndexBuilder::new(reader.file_meta.get_ref_seqs().len(), 0);
+        let mut records = reader.records_with(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        while let Some(rec) = records.next_rec() {
+            builder.push(rec.refid.unwrap(), rec.pos.unwrap(), rec.alignment_span());
+        }
+        builder.finish()
+    }
+
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Number of the first record which may overlap `pos` (0-based) or any
+    /// further position of reference sequence. None if no record does.
+    pub fn first_record(&self, ref_id: i32, pos: u32) -> Option<u64> {
+        let linear = self.linear.get(usize::try_from(ref_id).ok()?)?;
+        linear.get((pos / self.window) as usize).copied()
+    }
+}
+
+/// Builds [`IntervalIndex`] from records pushed in stored order.
+pub(crate) struct IndexBuilder {
+    window: u32,
+    // u64::MAX marks windows no record overlaps yet.
+    linear: Vec<Vec<u64>>,
+    records: u64,
+}
+
+impl IndexBuilder {
+    /// Builder for file which already holds `records` records.
+    pub fn new(ref_count: usize, records: u64) -> Self {
+        Self {
+            window: INDEX_WINDOW,
+            linear: vec![Vec::new(); ref_count],
+            records,
+        }
+    }
+
+    /// Continues index of file with `records` records, e.g. when appending
+    /// to it. Numbers stored for empty windows stay valid lower bounds.
+    pub fn resume(index: IntervalIndex, records: u64) -> Self {
+        Self {
+            window: index.window,
+            linear: index.linear,
+            records,
+        }
+    }
+
+    /// Adds record, records without reference bases occupy one base.
+    pub fn push(&mut self, ref_id: i32, pos: i32, span: u32) {
+        let rec_num = self.records;
+        self.records += 1;
+        let window = u64::from(self.window);
+        let linear = match usize::try_from(ref_id).ok().and_then(|ref_id| self.linear.get_mut(ref_id)) {
+            Some(linear) if pos >= 0 => linear,
+            _ => return,
+        };
+        let first = (pos as u64 / window) as usize;
+        let last = ((pos as u64 + u64::from(span.max(1)) - 1) / window) as usize;
+        if linear.len() <= last {
+            linear.resize(last + 1, u64::MAX);
+        }
+        for slot in linear[first..=last].iter_mut() {
+            *slot = (*slot).min(rec_num);
+        }
+    }
+
+    pub fn push_record(&mut self, record: &BAMRawRecord) {
+        let ref_id = (&record.get_bytes(&Fields::RefID)[..]).read_i32::<LittleEndian>().unwrap();
+        let pos = (&record.get_bytes(&Fields::Pos)[..]).read_i32::<LittleEndian>().unwrap();
+        let span = record
+            .get_bytes(&Fields::RawCigar)
+            .chunks_exact(4)
+            .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+            .filter(|op| matches!(op & 0xF, 0 | 2 | 3 | 7 | 8))
+            .map(|op| op >> 4)
+            .sum();
+        self.push(ref_id, pos, span);
+    }
+
+    /// Empty windows get number of the next record overlapping any further
+    /// window, which in sorted file is not greater than number of any record
+    /// overlapping positions after them.
+    pub fn finish(mut self) -> IntervalIndex {
+        let mut next = self.records;
+        for slot in self.linear.iter_mut().rev().flat_map(|linear| linear.iter_mut().rev()) {
+            if *slot == u64::MAX {
+                *slot = next;
+            } else {
+                next = *slot;
+            }
+        }
+        IntervalIndex {
+            window: self.window,
+            linear: self.linear,
+        }
+    }
+}
+
+/// Builds interval index of coordinate sorted GBAM file and stores it in the
+/// file meta, replacing the existing one.
+pub fn store_interval_index<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let (mut file, mut file_info, mut file_meta) = open_for_update(path)?;
+    if !file_meta.may_be_coordinate_sorted() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Interval index needs coordinate sorted file."));
+    }
+    let mut reader = Reader::new_with_meta(file.try_clone()?, ParsingTemplate::new(), &Arc::new(file_meta.clone()), None)?;
+    let index = IntervalIndex::build(&mut reader);
+    drop(reader);
+    file_meta.set_interval_index(Some(index));
+    rewrite_meta(&mut file, &mut file_info, &file_meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::regions::Region;
+    use crate::test_util::gbam_bytes_from_sam;
+
+    #[test]
+    fn test_builder() {
+        let mut builder = IndexBuilder::new(3, 0);
+        builder.push(0, 100, 50);
+        builder.push(0, 16_000, 40_000);
+        builder.push(0, 20_000, 10);
+        builder.push(0, 70_000, 0);
+        builder.push(2, 5, 10);
+        builder.push(-1, -1, 0);
+        let index = builder.finish();
+        assert_eq!(index.linear, vec![vec![0, 1, 1, 1, 3], vec![], vec![4]]);
+        assert_eq!(index.first_record(0, 49_152), Some(1));
+        assert_eq!(index.first_record(0, 90_000), None);
+        assert_eq!(index.first_record(1, 0), None);
+        assert_eq!(index.first_record(-1, 0), None);
+
+        // Empty windows 1 and 2 get the next record on finish and keep it
+        // after append.
+        let mut builder = IndexBuilder::new(2, 0);
+        builder.push(0, 0, 10);
+        builder.push(0, 50_000, 10);
+        let mut builder = IndexBuilder::resume(builder.finish(), 2);
+        builder.push(0, 60_000, 10);
+        assert_eq!(builder.finish().linear, vec![vec![0, 1, 1, 1], vec![]]);
+    }
+
+    #[test]
+    fn test_fetch_with_index() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000000\n@SQ\tSN:chr2\tLN:1000\n");
+        // One long alignment, so lookback by the longest span would cover
+        // most of the records.
+        sam.push_str("long\t0\tchr1\t1\t60\t200000M\t*\t0\t0\t*\t*\n");
+        for i in 0..2000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t100M\t*\t0\t0\t*\t*\n", i, i * 250 + 1));
+        }
+        sam.push_str("c2\t0\tchr2\t10\t60\t5M\t*\t0\t0\t*\t*\nu1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n");
+
+        let bytes = gbam_bytes_from_sam(&sam, |writer| writer.set_interval_index(true));
+        let mut reader = Reader::from_bytes(bytes, ParsingTemplate::new_with(&[Fields::ReadName])).unwrap();
+        let index = reader.file_meta.interval_index().unwrap().clone();
+        assert_eq!(index, IntervalIndex::build(&mut reader));
+        assert_eq!(index.first_record(0, 300_000), Some(1181));
+
+        let mut names = Vec::new();
+        let mut records = reader.fetch_regions(&[Region::new(0, 300_050, 300_300), Region::new(1, 0, 1000)]).unwrap();
+        while let Some(rec) = records.next_rec() {
+            names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+        }
+        drop(records);
+        assert_eq!(names, vec!["r1200", "r1201", "c2"]);
+        let mut records = reader.fetch("chr1:150000-150001").unwrap();
+        assert_eq!(records.next_rec().unwrap().read_name.as_deref(), Some(&b"long\0"[..]));
+    }
+}
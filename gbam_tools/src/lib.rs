@@ -8,30 +8,105 @@ pub mod bam {
     pub mod bam_to_gbam;
     /// GBAM to BAM converter
     pub mod gbam_to_bam;
+    /// GBAM to SAM text view
+    pub mod gbam_to_sam;
+    /// GBAM to FASTQ exporter
+    pub mod gbam_to_fastq;
+    /// SAM to GBAM converter
+    pub mod sam_to_gbam;
+    /// CRAM to GBAM converter
+    #[cfg(feature = "htslib")]
+    pub mod cram_to_gbam;
+    /// Conversions between htslib and GBAM records
+    #[cfg(feature = "htslib")]
+    pub mod htslib_records;
+    /// Conversions between noodles and GBAM records
+    #[cfg(feature = "noodles")]
+    pub mod noodles_records;
 }
 ///
 pub mod utils {
     /// BED reader
     pub mod bed;
+    /// Output sinks for exporters
+    pub mod sink;
+    /// Tabix index of BGZF compressed BED output
+    pub mod tabix;
+    /// BigWig writer
+    pub mod bigwig;
+    /// D4 writer
+    pub mod d4;
 }
 
 pub mod reader {
+    /// Async reader for tokio services
+    #[cfg(feature = "tokio")]
+    pub mod async_reader;
+    /// LRU cache of decompressed blocks
+    pub mod block_cache;
+    /// Decompressed blocks of fixed sized columns
+    pub mod chunks;
     pub mod column;
+    /// Record filter expressions
+    pub mod filter;
+    /// Per field block read statistics
+    pub mod io_stats;
     pub mod parse_tmplt;
+    /// Block aligned record ranges for distributed processing
+    pub mod partition;
+    /// Background decompression of blocks ahead of reads
+    pub mod prefetch;
+    /// Records grouped by position and pileup columns
+    pub mod position_groups;
     /// GBAM reader
     #[allow(clippy::module_inception)]
     pub mod reader;
     pub mod record;
     pub mod records;
-
+    /// Reader over byte range requests, e.g. from browsers
+    pub mod remote;
+    /// Local, HTTP, S3 and GCS sources of file byte ranges
+    pub mod source;
+    /// Batched fetch of records overlapping regions
+    pub mod regions;
+    /// Typed access to auxiliary fields
+    pub mod tags;
+    /// Records grouped by read name into templates
+    pub mod templates;
 }
 
 #[cfg(not(feature = "python-ffi"))]
 pub mod query {
     pub mod cigar;
+    /// Alignment summary by contig groups
+    pub mod contig_groups;
     pub mod depth;
+    /// Depth cache updated incrementally for appended records
+    pub mod depth_cache;
+    /// Template consistent downsampling to target coverage
+    pub mod downsample;
+    /// Quick estimates from uniformly sampled records
+    pub mod estimate;
     pub mod flagstat;
+    /// Mapped and unmapped record counts per reference sequence
+    pub mod idxstats;
     pub mod int2str;
+    /// Coverage based genome masks
+    pub mod mask;
+    /// Mate resolution across sharded GBAM files
+    pub mod mates;
+    /// Read pair orientation and insert size classification
+    pub mod pairs;
+    /// Reference-free mpileup and consensus FASTA export
+    pub mod pileup;
+    /// Windowed median and N50 aligned read length track
+    pub mod read_length;
+    /// Per read group statistics
+    pub mod read_groups;
+    /// Soft clip and adapter content report
+    pub mod softclip;
+    /// Insert size, read length, GC and quality distributions
+    pub mod stats;
     pub mod markdup {
         pub mod markdup;
         mod sorted_storage;
@@ -40,12 +115,53 @@ pub mod query {
 
 
 
+/// Validation of GBAM files
+pub mod check;
 /// Manages parallel compression
 mod compressor;
+pub mod export {
+    /// Arrow record batches of GBAM columns
+    #[cfg(feature = "arrow")]
+    pub mod arrow;
+    /// Parquet files of GBAM columns
+    #[cfg(feature = "parquet")]
+    pub mod parquet;
+    /// TSV and CSV tables of selected columns
+    pub mod table;
+}
+/// Block level deduplication of related GBAM files
+pub mod dedup;
+/// C API of GBAM reader, declared in include/gbam.h
+pub mod ffi;
+/// Errors of GBAM tools
+pub mod error;
+/// Content digests of GBAM files
+pub mod fingerprint;
+/// SAM header text and records
+pub mod header;
+/// Linear interval index for region fetch
+pub mod interval_index;
+/// Bloom filter index of read names
+pub mod name_index;
+/// Merge of coordinate sorted GBAM files
+pub mod merge;
 /// Meta information for GBAM file
 pub mod meta;
-/// Manages stats collection
-mod stats;
+/// Read groups of SAM header
+pub mod read_group;
+/// Checksums of reference sequences and their validation against FASTA
+pub mod reference;
+/// Recovery of records of files without meta
+pub mod rescue;
+/// Record ranges extracted into standalone GBAM files
+pub mod slice;
+/// External coordinate sort of unsorted inputs
+pub mod sort;
+/// Per block statistics of columns
+pub mod stats;
+/// GBAM files written from SAM text for unit tests
+#[cfg(test)]
+pub(crate) mod test_util;
 /// GBAM writer
 pub mod writer;
 
@@ -53,7 +169,8 @@ pub mod writer;
 // pub use {ParsingTemplate, Reader};
 use self::writer::Writer;
 pub use bam::bam_to_gbam::{bam_sort_to_gbam, bam_to_gbam};
-pub use meta::Codecs;
+pub use error::GbamError;
+pub use meta::{Codecs, SortOrder};
 pub use bam_tools::record::fields::Fields;
 
 
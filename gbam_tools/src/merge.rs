@@ -0,0 +1,136 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::sort::{natural_cmp, SortBy};
+use crate::{Codecs, SortOrder, Writer};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Position of record in merge order.
+#[derive(PartialEq, Eq)]
+enum MergeKey {
+    /// (RefID, Pos), unmapped (RefID -1) go last.
+    Coordinate(u32, i32),
+    /// Read name (without NUL) and READ1/READ2 flags.
+    Name(Vec<u8>, u16),
+}
+
+impl Ord for MergeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MergeKey::Coordinate(a_ref, a_pos), MergeKey::Coordinate(b_ref, b_pos)) => (a_ref, a_pos).cmp(&(b_ref, b_pos)),
+            (MergeKey::Name(a, a_flag), MergeKey::Name(b, b_flag)) => natural_cmp(a, b).then(a_flag.cmp(b_flag)),
+            _ => unreachable!("Inputs are sorted the same way."),
+        }
+    }
+}
+
+impl PartialOrd for MergeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// One input of the merge. Merge order is decided by `keys` reader, which
+/// decodes only fields of the sort key, full records are fetched by `records`
+/// reader. Both go through the file sequentially, so blocks of every column
+/// are decompressed once.
+struct MergeInput {
+    keys: Reader,
+    records: Reader,
+    next: usize,
+}
+
+impl MergeInput {
+    fn next_key(&mut self, rec: &mut GbamRecord, sort_by: SortBy) -> Option<MergeKey> {
+        if self.next == self.keys.amount {
+            return None;
+        }
+        self.keys.fill_record(self.next, rec);
+        Some(match sort_by {
+            SortBy::Coordinate => MergeKey::Coordinate(rec.refid.unwrap() as u32, rec.pos.unwrap()),
+            SortBy::QueryName => {
+                let mut name = rec.read_name.take().unwrap();
+                name.pop();
+                MergeKey::Name(name, rec.flag.unwrap() & SortBy::PAIR_FLAGS)
+            }
+        })
+    }
+}
+
+/// Merges GBAM files with equal reference sequences, all sorted by
+/// coordinate or all by query name, into one file sorted the same way.
+/// Header is taken from the first input. Records with equal keys are written
+/// in order of inputs. `codecs` are indexed by field (see [`Writer::new`]).
+pub fn merge_gbam(inputs: Vec<File>, out_path: &str, codecs: Vec<Codecs>, full_command: String) -> io::Result<()> {
+    let mut all_fields = ParsingTemplate::new();
+    all_fields.set_all();
+    let mut merge_inputs = Vec::with_capacity(inputs.len());
+    let mut sort_by = None;
+    for file in inputs {
+        let records = Reader::new(file.try_clone()?, all_fields.clone())?;
+        let input_sort_by = match records.file_meta.sort_order() {
+            SortOrder::Queryname => SortBy::QueryName,
+            _ if records.is_coordinate_sorted() => SortBy::Coordinate,
+            _ => return Err(invalid_input("Merged files have to be sorted by coordinate or query name.")),
+        };
+        if *sort_by.get_or_insert(input_sort_by) != input_sort_by {
+            return Err(invalid_input("Merged files are sorted differently."));
+        }
+        let keys = Reader::new_with_meta(file, ParsingTemplate::new_with(input_sort_by.key_fields()), &records.file_meta, None)?;
+        merge_inputs.push(MergeInput { keys, records, next: 0 });
+    }
+    let (first_meta, sort_by) = match (merge_inputs.first(), sort_by) {
+        (Some(input), Some(sort_by)) => (input.keys.file_meta.clone(), sort_by),
+        _ => return Err(invalid_input("Nothing to merge.")),
+    };
+    for input in merge_inputs.iter() {
+        if input.keys.file_meta.get_ref_seqs() != first_meta.get_ref_seqs() {
+            return Err(invalid_input("Reference sequences of merged files differ."));
+        }
+    }
+
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path)?),
+        codecs,
+        8,
+        vec![Fields::RefID],
+        first_meta.get_ref_seqs().clone(),
+        first_meta.get_sam_header().to_vec(),
+        full_command,
+        false,
+    );
+    writer.set_sort_order(sort_by.sort_order());
+
+    let mut rec = GbamRecord::default();
+    let mut heap = BinaryHeap::new();
+    for (idx, input) in merge_inputs.iter_mut().enumerate() {
+        if let Some(key) = input.next_key(&mut rec, sort_by) {
+            heap.push(Reverse((key, idx)));
+        }
+    }
+    let mut buf = Vec::new();
+    while let Some(Reverse((_, idx))) = heap.pop() {
+        let input = &mut merge_inputs[idx];
+        input.records.fill_record(input.next, &mut rec);
+        rec.convert_to_bytes(&mut buf);
+        // Writer expects BAM record without block_size.
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf[4..])));
+        input.next += 1;
+        if let Some(key) = input.next_key(&mut rec, sort_by) {
+            heap.push(Reverse((key, idx)));
+        }
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
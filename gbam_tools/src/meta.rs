@@ -1,5 +1,13 @@
 use super::GBAM_MAGIC;
-use bam_tools::record::fields::{field_item_size, Fields, FIELDS_NUM};
+use bitflags::bitflags;
+use crate::fingerprint::Fingerprint;
+use crate::header::SamHeader;
+use crate::interval_index::IntervalIndex;
+use crate::name_index::NameIndex;
+use crate::read_group::ReadGroup;
+use crate::reference::checksums_from_header;
+use crate::stats::ColumnStat;
+use bam_tools::record::fields::{field_item_size, field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM};
 use serde::ser::{SerializeMap, Serializer};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::marker::PhantomData;
@@ -7,7 +15,37 @@ use std::marker::PhantomData;
 use serde::de::{MapAccess, Visitor};
 // use serde::de::{Deserialize, Deserializer};
 // use serde_json::Result;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::OnceLock;
+use bincode::Options;
+use crate::reader::reader::Storage;
+use serde_json::value::RawValue;
+
+/// Version of the format written by this build. Readers accept files of
+/// the same major version; additions which old readers would misread are
+/// announced by [`FileFeatures`].
+pub const GBAM_VERSION: [u32; 2] = [1, 1];
+
+bitflags! {
+    /// Format features GBAM file relies on, stored in file info. Readers
+    /// refuse files with features they do not know instead of misreading
+    /// them, so new encodings are introduced by adding a flag.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FileFeatures: u64 {
+        /// Tags are split into their own columns, see [`TagColumnMeta`].
+        const TAG_COLUMNS = 1;
+        /// File info is repeated at the end of file, see
+        /// [`crate::writer::StreamOutput`].
+        const TRAILER = 1 << 1;
+        /// Meta is encoded by [`encode_meta`] in binary form.
+        const BINARY_META = 1 << 2;
+        /// Block lists of binary meta hold [`BlockMeta::column_stats`]. Set
+        /// with [`FileFeatures::BINARY_META`].
+        const COLUMN_STATS = 1 << 3;
+    }
+}
 
 /// Holds data related to GBAM file: gbam version, seekpos to meta.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -18,24 +56,153 @@ pub(crate) struct FileInfo {
     pub crc32: u32,
     pub is_sorted: bool,
     pub creation_command: String,
+    /// Length of meta. Meta of files written before it was recorded spans
+    /// till the end of file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta_size: Option<u64>,
+    /// Set in placeholder file info of files written as a stream (see
+    /// [`crate::writer::StreamOutput`]). The final file info is the last
+    /// `FILE_INFO_SIZE` bytes of the file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trailer: bool,
+    /// Bits of [`FileFeatures`].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub features: u64,
+}
+
+fn is_zero(features: &u64) -> bool {
+    *features == 0
 }
 
 impl FileInfo {
-    pub fn new(gbam_version: [u32; 2], seekpos: u64, crc32: u32, full_command: String, is_sorted: bool) -> Self {
+    pub fn new(seekpos: u64, crc32: u32, full_command: String, is_sorted: bool) -> Self {
         FileInfo {
             magic: String::from_utf8(GBAM_MAGIC.to_owned()).unwrap(),
-            gbam_version,
+            gbam_version: GBAM_VERSION,
             seekpos,
             crc32,
             creation_command: full_command,
-            is_sorted
+            is_sorted,
+            meta_size: None,
+            trailer: false,
+            features: 0,
+        }
+    }
+
+    /// Records features used by file with `file_meta`. Meta encoding is
+    /// kept.
+    pub fn set_features(&mut self, file_meta: &FileMeta) {
+        let mut features = FileFeatures::from_bits_truncate(self.features) & FileFeatures::BINARY_META;
+        features.set(FileFeatures::COLUMN_STATS, self.binary_meta());
+        features.set(FileFeatures::TAG_COLUMNS, !file_meta.tag_columns().is_empty());
+        features.set(FileFeatures::TRAILER, self.trailer);
+        self.features = features.bits();
+    }
+
+    /// Whether meta is encoded by [`encode_meta`] in binary form rather
+    /// than as JSON.
+    pub fn binary_meta(&self) -> bool {
+        self.features & FileFeatures::BINARY_META.bits() != 0
+    }
+
+    pub fn set_binary_meta(&mut self, enabled: bool) {
+        let mut features = FileFeatures::from_bits_retain(self.features);
+        features.set(FileFeatures::BINARY_META | FileFeatures::COLUMN_STATS, enabled);
+        self.features = features.bits();
+    }
+
+    /// Fails if file is not GBAM, or needs a newer reader.
+    pub fn check_supported(&self) -> std::io::Result<()> {
+        let unsupported = |msg: String| std::io::Error::new(std::io::ErrorKind::Unsupported, msg);
+        if self.magic.as_bytes() != GBAM_MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a GBAM file."));
+        }
+        let [major, minor] = self.gbam_version;
+        if major != GBAM_VERSION[0] {
+            return Err(unsupported(format!(
+                "File format version {}.{} is not supported by this build, which reads version {}.x.",
+                major, minor, GBAM_VERSION[0]
+            )));
+        }
+        let unknown = self.features & !FileFeatures::all().bits();
+        if unknown != 0 {
+            return Err(unsupported(format!(
+                "File format version {}.{} uses features {:#x} unsupported by this build (version {}.{}).",
+                major, minor, unknown, GBAM_VERSION[0], GBAM_VERSION[1]
+            )));
         }
+        Ok(())
     }
 }
 
 /// Should be enough for JSON.
 pub const FILE_INFO_SIZE: usize = 1000;
 
+pub(crate) const BLOCK_MAGIC: &[u8; 4] = b"GBLK";
+pub(crate) const BLOCK_HEADER_SIZE: usize = 36;
+// Field byte of meta block header.
+const META_BLOCK: u8 = u8::MAX;
+
+/// Header written before every block, so blocks can be found if meta is
+/// lost (see [`crate::rescue`]). Holds CRC32 of its fields and block bytes.
+/// Meta block, written before data blocks, holds JSON of meta without block
+/// lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct BlockHeader {
+    /// None for meta block.
+    pub field: Option<Fields>,
+    pub tag_col: Option<usize>,
+    pub block_num: u64,
+    pub numitems: u32,
+    pub block_size: u32,
+    pub uncompressed_size: u64,
+}
+
+impl BlockHeader {
+    /// Header of block with `data` bytes as stored.
+    pub fn to_bytes(self, data: &[u8]) -> [u8; BLOCK_HEADER_SIZE] {
+        let mut bytes = [0; BLOCK_HEADER_SIZE];
+        bytes[..4].copy_from_slice(BLOCK_MAGIC);
+        bytes[4] = self.field.map_or(META_BLOCK, |field| field as u8);
+        bytes[6..8].copy_from_slice(&self.tag_col.map_or(u16::MAX, |col| col as u16).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.block_num.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.numitems.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.uncompressed_size.to_le_bytes());
+        let crc = Self::crc(&bytes, data);
+        bytes[32..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Header at the start of `bytes`, if it is followed by its block and
+    /// CRC matches.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.get(..4)? != BLOCK_MAGIC || bytes.len() < BLOCK_HEADER_SIZE {
+            return None;
+        }
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let field = match bytes[4] {
+            META_BLOCK => None,
+            byte => Some(*Fields::iterator().find(|field| **field as u8 == byte)?),
+        };
+        let tag_col = match u16::from_le_bytes([bytes[6], bytes[7]]) {
+            u16::MAX => None,
+            col => Some(col as usize),
+        };
+        let header = BlockHeader { field, tag_col, block_num: u64_at(8), numitems: u32_at(16), block_size: u32_at(20), uncompressed_size: u64_at(24) };
+        let data = bytes[BLOCK_HEADER_SIZE..].get(..header.block_size as usize)?;
+        (Self::crc(bytes, data) == u32_at(32)).then_some(header)
+    }
+
+    fn crc(header: &[u8], data: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..32]);
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
 /// Type of encoding used in GBAM writer
 /// TODO: use MessagePack or another compact form of serialization.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
@@ -44,10 +211,127 @@ pub enum Codecs {
     Gzip,
     /// LZ4 encoding
     Lz4,
-    /// No compression
+    /// No compression. Blocks bypass the compressor and are stored as is.
     NoCompression,
 }
 
+/// Order of records in GBAM file, as in SAM @HD SO tag.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// Files written before sort order was recorded.
+    #[default]
+    Unknown,
+    Unsorted,
+    Coordinate,
+    Queryname,
+}
+
+impl SortOrder {
+    /// Reads SO tag from @HD line of BAM header (with leading l_text).
+    pub fn from_sam_header(sam_header: &[u8]) -> Self {
+        let text = &sam_header[std::mem::size_of::<u32>().min(sam_header.len())..];
+        let hd_line = match text.split(|&c| c == b'\n').next() {
+            Some(line) if line.starts_with(b"@HD") => line,
+            _ => return SortOrder::Unknown,
+        };
+        let so = hd_line
+            .split(|&c| c == b'\t')
+            .find_map(|tag| tag.strip_prefix(b"SO:"));
+        match so {
+            Some(b"unsorted") => SortOrder::Unsorted,
+            Some(b"coordinate") => SortOrder::Coordinate,
+            Some(b"queryname") => SortOrder::Queryname,
+            _ => SortOrder::Unknown,
+        }
+    }
+
+    /// Value of SAM @HD SO tag.
+    pub fn sam_name(self) -> &'static str {
+        match self {
+            SortOrder::Unknown => "unknown",
+            SortOrder::Unsorted => "unsorted",
+            SortOrder::Coordinate => "coordinate",
+            SortOrder::Queryname => "queryname",
+        }
+    }
+
+    /// BAM header (l_text, text and reference sequences) with SO tag of @HD
+    /// line set to this order. @HD line is added if the text has none.
+    pub fn set_in_sam_header(self, sam_header: &[u8]) -> Vec<u8> {
+        let (text, ref_seqs) = split_bam_header(sam_header).expect("BAM header is truncated.");
+
+        let mut new_text = Vec::with_capacity(text.len() + 32);
+        let rest = if text.starts_with(b"@HD") {
+            let line_end = text.iter().position(|&c| c == b'\n').unwrap_or(text.len());
+            for tag in text[..line_end].split(|&c| c == b'\t').filter(|tag| !tag.starts_with(b"SO:")) {
+                new_text.extend_from_slice(tag);
+                new_text.push(b'\t');
+            }
+            &text[(line_end + 1).min(text.len())..]
+        } else {
+            new_text.extend_from_slice(b"@HD\tVN:1.6\t");
+            text
+        };
+        new_text.extend_from_slice(b"SO:");
+        new_text.extend_from_slice(self.sam_name().as_bytes());
+        new_text.push(b'\n');
+        new_text.extend_from_slice(rest);
+
+        join_bam_header(&new_text, ref_seqs)
+    }
+}
+
+/// Program name (PN) of @PG records added by GBAM tools.
+pub const PROGRAM_NAME: &str = "gbam";
+
+/// BAM header (l_text, text and reference sequences) with @PG line of
+/// `program` appended, as samtools does: ID is `program`, suffixed with a
+/// number if taken, and PP links it to the last program of the existing
+/// chain. Header without text is returned as is.
+pub fn add_program_record(sam_header: &[u8], program: &str, version: &str, command_line: &str) -> Vec<u8> {
+    let (text, ref_seqs) = match split_bam_header(sam_header) {
+        Some(parts) => parts,
+        None => return sam_header.to_vec(),
+    };
+    let header = SamHeader::parse(&String::from_utf8_lossy(text));
+    let programs: Vec<&str> = header.records_of("PG").filter_map(|pg| pg.get("ID")).collect();
+    let id = (0..)
+        .map(|n| if n == 0 { program.to_owned() } else { format!("{}.{}", program, n) })
+        .find(|id| !programs.contains(&id.as_str()))
+        .unwrap();
+    // The chain ends at the program no other one names as previous.
+    let previous = programs
+        .iter()
+        .rev()
+        .find(|id| !header.records_of("PG").any(|pg| pg.get("PP") == Some(**id)));
+
+    let mut new_text = text.to_vec();
+    if !new_text.is_empty() && !new_text.ends_with(b"\n") {
+        new_text.push(b'\n');
+    }
+    new_text.extend_from_slice(format!("@PG\tID:{}\tPN:{}", id, program).as_bytes());
+    if let Some(previous) = previous {
+        new_text.extend_from_slice(format!("\tPP:{}", previous).as_bytes());
+    }
+    new_text.extend_from_slice(format!("\tVN:{}\tCL:{}\n", version, command_line.replace(['\t', '\n'], " ")).as_bytes());
+    join_bam_header(&new_text, ref_seqs)
+}
+
+/// Text of BAM header (without NUL padding) and reference sequences
+/// following it, `None` if header is truncated.
+fn split_bam_header(sam_header: &[u8]) -> Option<(&[u8], &[u8])> {
+    let l_text = u32::from_le_bytes(sam_header.get(..4)?.try_into().unwrap()) as usize;
+    let text = sam_header.get(4..4 + l_text)?;
+    Some((&text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())], &sam_header[4 + l_text..]))
+}
+
+fn join_bam_header(text: &[u8], ref_seqs: &[u8]) -> Vec<u8> {
+    let mut header = (text.len() as u32).to_le_bytes().to_vec();
+    header.extend_from_slice(text);
+    header.extend_from_slice(ref_seqs);
+    header
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 /// Currently block stats only for RefID or POS are supported.
 pub struct Stat {
@@ -90,13 +374,172 @@ pub struct BlockMeta {
     pub block_size: u32,
     pub uncompressed_size: u64,
     pub stats: Option<Stat>,
+    /// Collected by [`crate::stats::collectors`] of the column if writer has
+    /// [`crate::writer::Writer::set_column_stats`], empty otherwise.
+    #[serde(default)]
+    pub column_stats: Vec<ColumnStat>,
+}
+
+/// Block of binary meta written before [`FileFeatures::COLUMN_STATS`].
+#[derive(Deserialize)]
+struct BlockMetaV1 {
+    seekpos: u64,
+    numitems: u32,
+    block_size: u32,
+    uncompressed_size: u64,
+    stats: Option<Stat>,
+}
+
+impl From<BlockMetaV1> for BlockMeta {
+    fn from(block: BlockMetaV1) -> Self {
+        let BlockMetaV1 { seekpos, numitems, block_size, uncompressed_size, stats } = block;
+        BlockMeta { seekpos, numitems, block_size, uncompressed_size, stats, column_stats: Vec::new() }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FieldMeta {
     item_size: Option<u32>, // NONE for variable sized fields
     codec: Codecs,
-    blocks: Vec<BlockMeta>,
+    blocks: Blocks,
+}
+
+/// Blocks of a column. Block lists are decoded on first access from meta
+/// bytes of the file, so opening a file decodes block lists of the columns
+/// it reads only, others take no memory.
+#[derive(Clone, Default)]
+struct Blocks {
+    blocks: OnceLock<Vec<BlockMeta>>,
+    encoded: Option<EncodedBlocks>,
+}
+
+/// Block list not decoded yet: range of meta bytes, e.g. of mapped file.
+#[derive(Clone)]
+struct EncodedBlocks {
+    bytes: Storage,
+    range: Range<usize>,
+    encoding: BlockEncoding,
+}
+
+#[derive(Clone, Copy)]
+enum BlockEncoding {
+    Json,
+    Bincode,
+    /// Without column stats, see [`BlockMetaV1`].
+    BincodeV1,
+}
+
+impl Blocks {
+    /// Block list, decoded if not yet.
+    fn decode(&self) -> std::io::Result<&Vec<BlockMeta>> {
+        if let Some(blocks) = self.blocks.get() {
+            return Ok(blocks);
+        }
+        let blocks = match &self.encoded {
+            Some(encoded) => {
+                let bytes = &(*encoded.bytes).as_ref()[encoded.range.clone()];
+                let damaged = |e: &dyn std::fmt::Display| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Block list of meta is damaged: {}", e))
+                };
+                match encoded.encoding {
+                    BlockEncoding::Json => serde_json::from_slice(bytes).map_err(|e| damaged(&e))?,
+                    BlockEncoding::Bincode => bincode_options().deserialize(bytes).map_err(|e| damaged(&e))?,
+                    BlockEncoding::BincodeV1 => bincode_options()
+                        .deserialize::<Vec<BlockMetaV1>>(bytes)
+                        .map_err(|e| damaged(&e))?
+                        .into_iter()
+                        .map(BlockMeta::from)
+                        .collect(),
+                }
+            }
+            None => Vec::new(),
+        };
+        Ok(self.blocks.get_or_init(|| blocks))
+    }
+
+    fn get(&self) -> &Vec<BlockMeta> {
+        // Lists of columns a reader uses are decoded by
+        // `FileMeta::decode_blocks` when it is opened, errors are returned
+        // there.
+        self.decode().expect("Block list of meta is damaged.")
+    }
+
+    fn get_mut(&mut self) -> &mut Vec<BlockMeta> {
+        self.get();
+        self.encoded = None;
+        self.blocks.get_mut().unwrap()
+    }
+}
+
+impl Serialize for Blocks {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Blocks {
+    /// Block list is decoded at once. [`decode_meta`] locates block lists
+    /// of file meta instead, to decode them on first use.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self { blocks: OnceLock::from(Vec::<BlockMeta>::deserialize(deserializer)?), encoded: None })
+    }
+}
+
+/// Block lists of JSON meta, borrowed undecoded from meta bytes.
+#[derive(Deserialize)]
+struct JsonBlockLists<'a> {
+    #[serde(borrow)]
+    field_to_meta: HashMap<Fields, JsonColumn<'a>>,
+    #[serde(borrow, default)]
+    tag_columns: Vec<JsonTagColumn<'a>>,
+}
+
+#[derive(Deserialize)]
+struct JsonColumn<'a> {
+    #[serde(borrow)]
+    blocks: &'a RawValue,
+}
+
+#[derive(Deserialize)]
+struct JsonTagColumn<'a> {
+    #[serde(borrow)]
+    data: JsonColumn<'a>,
+    #[serde(borrow)]
+    index: JsonColumn<'a>,
+}
+
+/// Codecs of meta columns, see [`parse_meta_json`].
+#[derive(Deserialize)]
+struct MetaCodecs {
+    field_to_meta: HashMap<Fields, ColumnCodec>,
+    #[serde(default)]
+    tag_columns: Vec<TagColumnCodecs>,
+}
+
+#[derive(Deserialize)]
+struct ColumnCodec {
+    codec: StoredCodec,
+}
+
+#[derive(Deserialize)]
+struct TagColumnCodecs {
+    data: ColumnCodec,
+    index: ColumnCodec,
+}
+
+/// Codec of stored column, known to this build or not.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCodec {
+    // Only matched, meta holds the codec.
+    #[allow(dead_code)]
+    Known(Codecs),
+    Unknown(String),
+}
+
+/// Varint encoding of block lists of binary meta.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
 }
 
 impl FieldMeta {
@@ -104,17 +547,33 @@ impl FieldMeta {
         FieldMeta {
             item_size: field_item_size(field).map(|v| v as u32), // TODO
             codec,
-            blocks: Vec::<BlockMeta>::new(),
+            blocks: Blocks::default(),
         }
     }
 }
 
+/// Tag stored in its own column apart from RawTags, see
+/// [`crate::writer::Writer::set_tag_columns`]. Values are BAM encoded tag
+/// entries (name, type and value), indexed as RawTags by RawTagsLen column.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TagColumnMeta {
+    tag: String,
+    data: FieldMeta,
+    index: FieldMeta,
+}
+
+impl TagColumnMeta {
+    pub fn tag(&self) -> [u8; 2] {
+        self.tag.as_bytes().try_into().unwrap()
+    }
+}
+
 impl Default for FieldMeta {
     fn default() -> Self {
         FieldMeta {
             item_size: None,
             codec: Codecs::Gzip,
-            blocks: Vec::<BlockMeta>::new(),
+            blocks: Blocks::default(),
         }
     }
 }
@@ -129,6 +588,34 @@ pub struct FileMeta {
     field_to_meta: [FieldMeta; FIELDS_NUM],
     sam_header: Vec<u8>,
     name_to_ref_id: Vec<(String, u32)>,
+    #[serde(default)]
+    sort_order: SortOrder,
+    /// Content digests stored by [`crate::fingerprint::store_fingerprint`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<Fingerprint>,
+    /// Stored by [`crate::interval_index::store_interval_index`] or by writer
+    /// with [`crate::writer::Writer::set_interval_index`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval_index: Option<IntervalIndex>,
+    /// Stored by [`crate::name_index::store_name_index`] or by writer with
+    /// [`crate::writer::Writer::set_name_index`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_index: Option<NameIndex>,
+    /// @RG lines of header. Parsed on read for files written before they
+    /// were stored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    read_groups: Vec<ReadGroup>,
+    /// Tags split out of RawTags by writer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tag_columns: Vec<TagColumnMeta>,
+    /// User defined entries, e.g. sample ID or pipeline version, set with
+    /// [`crate::writer::Writer::set_metadata`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, serde_json::Value>,
+    /// M5 checksums of reference sequences by name, from @SQ lines or
+    /// stored by [`crate::reference::store_reference_checksums`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    ref_checksums: BTreeMap<String, String>,
 }
 
 impl FileMeta {
@@ -140,6 +627,67 @@ impl FileMeta {
     pub fn get_sam_header(&self) -> &[u8] {
         &self.sam_header[..]
     }
+
+    /// Appends @PG record of GBAM tool run with `command_line` to SAM
+    /// header, see [`add_program_record`]. Writers call it on finish.
+    pub fn add_program_record(&mut self, command_line: &str) {
+        self.sam_header = add_program_record(&self.sam_header, PROGRAM_NAME, env!("CARGO_PKG_VERSION"), command_line);
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        self.sort_order
+    }
+
+    /// False only if records are known not to be coordinate sorted. Files
+    /// without recorded sort order are trusted.
+    pub fn may_be_coordinate_sorted(&self) -> bool {
+        matches!(self.sort_order, SortOrder::Coordinate | SortOrder::Unknown)
+    }
+
+    pub(crate) fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    pub(crate) fn set_fingerprint(&mut self, fingerprint: Option<Fingerprint>) {
+        self.fingerprint = fingerprint;
+    }
+
+    pub fn interval_index(&self) -> Option<&IntervalIndex> {
+        self.interval_index.as_ref()
+    }
+
+    pub(crate) fn set_interval_index(&mut self, interval_index: Option<IntervalIndex>) {
+        self.interval_index = interval_index;
+    }
+
+    pub fn name_index(&self) -> Option<&NameIndex> {
+        self.name_index.as_ref()
+    }
+
+    pub(crate) fn set_name_index(&mut self, name_index: Option<NameIndex>) {
+        self.name_index = name_index;
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, serde_json::Value> {
+        &self.metadata
+    }
+
+    /// Sets user defined entry `key`, replacing the previous value.
+    pub fn set_metadata(&mut self, key: &str, value: serde_json::Value) {
+        self.metadata.insert(key.to_owned(), value);
+    }
+
+    pub fn ref_checksums(&self) -> &BTreeMap<String, String> {
+        &self.ref_checksums
+    }
+
+    pub(crate) fn set_ref_checksums(&mut self, ref_checksums: BTreeMap<String, String>) {
+        self.ref_checksums = ref_checksums;
+    }
 }
 
 // To make metadata easier to read, convert to json where fields are represented
@@ -235,9 +783,18 @@ impl<'de> Deserialize<'de> for FieldMetaMap {
 }
 
 impl FileMeta {
-    pub fn new(codec: Codecs, ref_seqs: Vec<(String, u32)>, sam_header: Vec<u8>) -> Self {
+    /// `codecs` holds codec of each field, indexed by `Fields as usize`, or
+    /// one codec used for all fields. Panics if it has other length.
+    pub fn new(codecs: &[Codecs], ref_seqs: Vec<(String, u32)>, sam_header: Vec<u8>) -> Self {
+        assert!(
+            codecs.len() == 1 || codecs.len() == FIELDS_NUM,
+            "Expected one codec or one per field ({}), got {}.",
+            FIELDS_NUM,
+            codecs.len()
+        );
         let mut map: [FieldMeta; FIELDS_NUM] = Default::default();
         for field in Fields::iterator() {
+            let codec = codecs[if codecs.len() == 1 { 0 } else { *field as usize }];
             map[*field as usize] = FieldMeta::new(field, codec);
         }
 
@@ -245,20 +802,28 @@ impl FileMeta {
         map[Fields::Flags as usize].codec = Codecs::NoCompression;
 
         FileMeta {
+            read_groups: ReadGroup::from_sam_header(&sam_header),
+            ref_checksums: checksums_from_header(&sam_header),
             field_to_meta: map,
             sam_header,
             name_to_ref_id: ref_seqs,
+            sort_order: SortOrder::Unknown,
+            fingerprint: None,
+            interval_index: None,
+            name_index: None,
+            tag_columns: Vec::new(),
+            metadata: BTreeMap::new(),
         }
     }
 
     /// Used to retrieve BlockMeta vector mutable borrow, to push new blocks
     /// directly into it, avoiding field matching.
     pub fn get_blocks(&mut self, field: &Fields) -> &mut Vec<BlockMeta> {
-        &mut self.field_to_meta[*field as usize].blocks
+        self.field_to_meta[*field as usize].blocks.get_mut()
     }
 
     pub fn view_blocks(&self, field: &Fields) -> &Vec<BlockMeta> {
-        &self.field_to_meta[*field as usize].blocks
+        self.field_to_meta[*field as usize].blocks.get()
     }
 
     pub fn get_field_size(&self, field: &Fields) -> &Option<u32> {
@@ -268,4 +833,369 @@ impl FileMeta {
     pub fn get_field_codec(&self, field: &Fields) -> &Codecs {
         &self.field_to_meta[*field as usize].codec
     }
+
+    /// Codecs of fields indexed by `Fields as usize`, as taken by
+    /// [`FileMeta::new`].
+    pub fn field_codecs(&self) -> Vec<Codecs> {
+        self.field_to_meta.iter().map(|meta| meta.codec).collect()
+    }
+
+    pub fn read_groups(&self) -> &[ReadGroup] {
+        &self.read_groups
+    }
+
+    /// Read group with ID `id`, e.g. of [`crate::reader::record::GbamRecord::read_group`].
+    pub fn read_group(&self, id: &[u8]) -> Option<&ReadGroup> {
+        self.read_groups.iter().find(|group| group.id.as_bytes() == id)
+    }
+
+    /// Parses read groups and reference checksums of header if none are
+    /// stored.
+    pub(crate) fn fill_from_header(&mut self) {
+        if self.read_groups.is_empty() {
+            self.read_groups = ReadGroup::from_sam_header(&self.sam_header);
+        }
+        if self.ref_checksums.is_empty() {
+            self.ref_checksums = checksums_from_header(&self.sam_header);
+        }
+    }
+
+    pub fn tag_columns(&self) -> &[TagColumnMeta] {
+        &self.tag_columns
+    }
+
+    /// Adds column of `tag`, returns its number.
+    pub(crate) fn add_tag_column(&mut self, tag: [u8; 2], codec: Codecs) -> usize {
+        self.tag_columns.push(TagColumnMeta {
+            tag: String::from_utf8(tag.to_vec()).unwrap(),
+            data: FieldMeta::new(&Fields::RawTags, codec),
+            index: FieldMeta::new(&Fields::RawTagsLen, codec),
+        });
+        self.tag_columns.len() - 1
+    }
+
+    /// Meta of `field`, or of tag column `tag_col` if given. Tag columns
+    /// consist of RawTags and index RawTagsLen fields.
+    /// Decodes block lists of `fields`, with index fields of variable sized
+    /// ones and tag columns for RawTags. Fails if a list is damaged, which
+    /// would panic once the column is read.
+    pub fn decode_blocks<'a>(&self, fields: impl IntoIterator<Item = &'a Fields>) -> std::io::Result<()> {
+        for field in fields {
+            self.field_to_meta[*field as usize].blocks.decode()?;
+            if let FieldType::VariableSized = field_type(field) {
+                self.field_to_meta[var_size_field_to_index(field) as usize].blocks.decode()?;
+            }
+            if *field == Fields::RawTags {
+                for col in self.tag_columns.iter() {
+                    col.data.blocks.decode()?;
+                    col.index.blocks.decode()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn column_meta(&self, field: &Fields, tag_col: Option<usize>) -> &FieldMeta {
+        match tag_col {
+            None => &self.field_to_meta[*field as usize],
+            Some(col) if *field == Fields::RawTagsLen => &self.tag_columns[col].index,
+            Some(col) => &self.tag_columns[col].data,
+        }
+    }
+
+    /// Copy without block lists, as stored in meta block (see
+    /// [`BlockHeader`]).
+    pub(crate) fn without_blocks(&self) -> FileMeta {
+        let mut meta = self.clone();
+        meta.columns_mut().for_each(|blocks| *blocks = Blocks::default());
+        meta
+    }
+
+    /// Block lists of all columns: fields, then data and index of tag
+    /// columns.
+    fn columns_mut(&mut self) -> impl Iterator<Item = &mut Blocks> {
+        let fields = self.field_to_meta.iter_mut().map(|meta| &mut meta.blocks);
+        fields.chain(self.tag_columns.iter_mut().flat_map(|col| [&mut col.data.blocks, &mut col.index.blocks]))
+    }
+
+    /// Same as [`FileMeta::get_blocks`], for tag column `tag_col` if given.
+    pub fn get_column_blocks(&mut self, field: &Fields, tag_col: Option<usize>) -> &mut Vec<BlockMeta> {
+        match tag_col {
+            None => self.field_to_meta[*field as usize].blocks.get_mut(),
+            Some(col) if *field == Fields::RawTagsLen => self.tag_columns[col].index.blocks.get_mut(),
+            Some(col) => self.tag_columns[col].data.blocks.get_mut(),
+        }
+    }
+
+    pub fn view_column_blocks(&self, field: &Fields, tag_col: Option<usize>) -> &Vec<BlockMeta> {
+        self.column_meta(field, tag_col).blocks.get()
+    }
+
+    pub fn get_column_codec(&self, field: &Fields, tag_col: Option<usize>) -> &Codecs {
+        &self.column_meta(field, tag_col).codec
+    }
+}
+
+/// Meta bytes as stored in file: JSON, or if file info has
+/// [`FileFeatures::BINARY_META`], length prefixed JSON without blocks
+/// followed by number of columns and length prefixed bincode block lists of
+/// every column (fields, then data and index of tag columns).
+pub(crate) fn encode_meta(file_info: &FileInfo, file_meta: &FileMeta) -> Vec<u8> {
+    if !file_info.binary_meta() {
+        return serde_json::to_vec(file_meta).unwrap();
+    }
+    let mut meta = file_meta.clone();
+    let columns: Vec<Vec<BlockMeta>> = meta.columns_mut().map(|blocks| std::mem::take(blocks.get_mut())).collect();
+    let json = serde_json::to_vec(&meta).unwrap();
+    let mut bytes = Vec::with_capacity(json.len() + columns.iter().map(|blocks| blocks.len() * 16).sum::<usize>());
+    bytes.extend_from_slice(&(json.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&json);
+    bytes.extend_from_slice(&(columns.len() as u32).to_le_bytes());
+    for blocks in columns {
+        let encoded = bincode_options().serialize(&blocks).unwrap();
+        bytes.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&encoded);
+    }
+    bytes
+}
+
+/// Parses meta written by [`encode_meta`] from `range` of `bytes`. Block
+/// lists are only located, they are decoded from `bytes` when their column
+/// is first used.
+pub(crate) fn decode_meta(file_info: &FileInfo, bytes: &Storage, range: Range<usize>) -> std::io::Result<FileMeta> {
+    if !file_info.binary_meta() {
+        return decode_json_meta(file_info, bytes, range);
+    }
+    let damaged = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Binary meta is damaged.");
+    let mut pos = range.start;
+    let mut take = |len: usize| {
+        let range = pos..pos.checked_add(len).filter(|&end| end <= range.end).ok_or_else(damaged)?;
+        pos = range.end;
+        Ok::<_, std::io::Error>(range)
+    };
+    let all = (**bytes).as_ref();
+    let len = |range: Range<usize>| u64::from_le_bytes(all[range].try_into().unwrap()) as usize;
+    let json = take(8).map(len).and_then(&mut take)?;
+    let mut meta = parse_meta_json(file_info, &all[json])?;
+    let count = u32::from_le_bytes(all[take(4)?].try_into().unwrap()) as usize;
+    let mut ranges = Vec::with_capacity(count);
+    for _ in 0..count {
+        ranges.push(take(8).map(len).and_then(&mut take)?);
+    }
+    if ranges.len() != meta.columns_mut().count() {
+        return Err(damaged());
+    }
+    let encoding = match file_info.features & FileFeatures::COLUMN_STATS.bits() {
+        0 => BlockEncoding::BincodeV1,
+        _ => BlockEncoding::Bincode,
+    };
+    for (blocks, range) in meta.columns_mut().zip(ranges) {
+        *blocks = Blocks { blocks: OnceLock::new(), encoded: Some(EncodedBlocks { bytes: bytes.clone(), range, encoding }) };
+    }
+    Ok(meta)
+}
+
+/// Parses JSON meta with block lists cut out. Block lists are located in
+/// `bytes` and decoded when their column is first used.
+fn decode_json_meta(file_info: &FileInfo, bytes: &Storage, range: Range<usize>) -> std::io::Result<FileMeta> {
+    let buf = &(**bytes).as_ref()[range.clone()];
+    let lists: JsonBlockLists = serde_json::from_slice(buf).map_err(|e| meta_error(file_info, e))?;
+    let damaged = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Meta is damaged.");
+    // In `FileMeta::columns_mut` order, fields by index first.
+    let mut fields: Vec<&Fields> = Fields::iterator().collect();
+    fields.sort_by_key(|field| **field as usize);
+    let mut columns = Vec::with_capacity(FIELDS_NUM + lists.tag_columns.len() * 2);
+    for field in fields {
+        columns.push(lists.field_to_meta.get(field).ok_or_else(damaged)?.blocks.get());
+    }
+    columns.extend(lists.tag_columns.iter().flat_map(|col| [col.data.blocks.get(), col.index.blocks.get()]));
+    // Borrowed raw values are slices of `buf`.
+    let ranges: Vec<Range<usize>> = columns
+        .iter()
+        .map(|raw| {
+            let start = raw.as_ptr() as usize - buf.as_ptr() as usize;
+            start..start + raw.len()
+        })
+        .collect();
+
+    let mut cut = ranges.clone();
+    cut.sort_by_key(|range| range.start);
+    let mut skeleton = Vec::with_capacity(buf.len());
+    let mut pos = 0;
+    for list in cut {
+        skeleton.extend_from_slice(&buf[pos..list.start]);
+        skeleton.extend_from_slice(b"[]");
+        pos = list.end;
+    }
+    skeleton.extend_from_slice(&buf[pos..]);
+    let mut meta = parse_meta_json(file_info, &skeleton)?;
+    if meta.columns_mut().count() != ranges.len() {
+        return Err(damaged());
+    }
+    for (blocks, list) in meta.columns_mut().zip(ranges) {
+        let range = range.start + list.start..range.start + list.end;
+        *blocks = Blocks { blocks: OnceLock::new(), encoded: Some(EncodedBlocks { bytes: bytes.clone(), range, encoding: BlockEncoding::Json }) };
+    }
+    Ok(meta)
+}
+
+/// Parses JSON of meta. Codecs unknown to this build, e.g. of newer
+/// builds, are reported as unsupported rather than damaged meta.
+fn parse_meta_json(file_info: &FileInfo, json: &[u8]) -> std::io::Result<FileMeta> {
+    let codecs: MetaCodecs = serde_json::from_slice(json).map_err(|e| meta_error(file_info, e))?;
+    let columns = codecs.field_to_meta.values().chain(codecs.tag_columns.iter().flat_map(|col| [&col.data, &col.index]));
+    for column in columns {
+        if let StoredCodec::Unknown(codec) = &column.codec {
+            return Err(unsupported(file_info, &format!("codec {}", codec)));
+        }
+    }
+    serde_json::from_slice(json).map_err(|e| meta_error(file_info, e))
+}
+
+/// Error of meta parsing. Unknown enum variants are reported as
+/// unsupported encodings rather than damaged meta.
+fn meta_error(file_info: &FileInfo, e: serde_json::Error) -> std::io::Error {
+    let msg = e.to_string();
+    match msg.strip_prefix("unknown variant `").and_then(|rest| rest.split('`').next()) {
+        Some(variant) => unsupported(file_info, &format!("encoding {}", variant)),
+        None => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+    }
+}
+
+fn unsupported(file_info: &FileInfo, what: &str) -> std::io::Error {
+    let [major, minor] = file_info.gbam_version;
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("File (format version {}.{}) uses {} unsupported by this build.", major, minor, what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::reader::parse_meta;
+    use crate::writer::calc_crc_for_meta_bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_add_program_record() {
+        let text = "@HD\tVN:1.6\n@PG\tID:bwa\tPN:bwa\n@PG\tID:gbam\tPN:gbam\tPP:bwa";
+        let mut sam_header = (text.len() as u32).to_le_bytes().to_vec();
+        sam_header.extend_from_slice(text.as_bytes());
+        sam_header.extend_from_slice(&0u32.to_le_bytes());
+
+        let sam_header = add_program_record(&sam_header, "gbam", "0.1.0", "gbam_binary\t-c in.sam");
+        let header = SamHeader::from_bam_header(&sam_header);
+        let added = header.records_of("PG").last().unwrap();
+        assert_eq!(added.get("ID"), Some("gbam.1"));
+        assert_eq!(added.get("PP"), Some("gbam"));
+        assert_eq!(added.get("CL"), Some("gbam_binary -c in.sam"));
+        assert!(sam_header.ends_with(&0u32.to_le_bytes()));
+        assert_eq!(SortOrder::from_sam_header(&SortOrder::Coordinate.set_in_sam_header(&sam_header)), SortOrder::Coordinate);
+    }
+
+    #[test]
+    fn test_field_codecs() {
+        let meta = FileMeta::new(&[Codecs::Gzip], Vec::new(), Vec::new());
+        assert_eq!(meta.get_field_codec(&Fields::RawSequence), &Codecs::Gzip);
+        // Flags are patched in place by markdup.
+        assert_eq!(meta.get_field_codec(&Fields::Flags), &Codecs::NoCompression);
+        let mut codecs = vec![Codecs::Lz4; FIELDS_NUM];
+        codecs[Fields::RawQual as usize] = Codecs::NoCompression;
+        let meta = FileMeta::new(&codecs, Vec::new(), Vec::new());
+        assert_eq!(meta.get_field_codec(&Fields::RawQual), &Codecs::NoCompression);
+        assert_eq!(meta.get_field_codec(&Fields::Pos), &Codecs::Lz4);
+        assert_eq!(FileMeta::new(&meta.field_codecs(), Vec::new(), Vec::new()).field_codecs(), meta.field_codecs());
+        assert!(std::panic::catch_unwind(|| FileMeta::new(&[], Vec::new(), Vec::new())).is_err());
+        assert!(std::panic::catch_unwind(|| FileMeta::new(&codecs[1..], Vec::new(), Vec::new())).is_err());
+    }
+
+    #[test]
+    fn test_check_supported() {
+        let mut file_info = FileInfo::new(0, 0, String::new(), false);
+        file_info.trailer = true;
+        file_info.set_features(&FileMeta::new(&[Codecs::Lz4], Vec::new(), Vec::new()));
+        assert_eq!(file_info.features, FileFeatures::TRAILER.bits());
+        assert!(file_info.check_supported().is_ok());
+        file_info.features |= 1 << 40;
+        assert_eq!(file_info.check_supported().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+        file_info.features = 0;
+        file_info.gbam_version = [GBAM_VERSION[0] + 1, 0];
+        assert_eq!(file_info.check_supported().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+        file_info.magic = "BAM".to_owned();
+        assert_eq!(file_info.check_supported().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        // Codecs of newer builds are reported by name.
+        let meta = serde_json::to_string(&FileMeta::new(&[Codecs::Lz4], Vec::new(), Vec::new())).unwrap().replace("\"Lz4\"", "\"Zstd\"");
+        let mut file_info = FileInfo::new(0, 0, String::new(), false);
+        file_info.crc32 = calc_crc_for_meta_bytes(meta.as_bytes());
+        let len = meta.len();
+        let err = parse_meta(&file_info, &(Arc::new(meta.into_bytes()) as Storage), 0..len).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("codec Zstd"), "{}", err);
+    }
+
+    #[test]
+    fn test_binary_meta() {
+        let mut meta = FileMeta::new(&[Codecs::Lz4], vec![("chr1".to_owned(), 1000)], Vec::new());
+        meta.add_tag_column(*b"NM", Codecs::Gzip);
+        for (i, field) in Fields::iterator().enumerate() {
+            for seekpos in 0..100 {
+                meta.get_blocks(field).push(BlockMeta { seekpos: seekpos * 1_000_000 + i as u64, numitems: 4096, block_size: 16384, uncompressed_size: 16384, stats: None, column_stats: Vec::new() });
+            }
+        }
+        meta.get_blocks(&Fields::Pos)[0].stats = Some(Stat { min_value: 1, max_value: 9 });
+        meta.get_blocks(&Fields::Pos)[1].column_stats = vec![ColumnStat::Int { min: 1, max: 9, null_count: 2, sum: 30 }];
+        meta.get_column_blocks(&Fields::RawTagsLen, Some(0)).push(BlockMeta { seekpos: 1012, numitems: 3, ..Default::default() });
+        let mut file_info = FileInfo::new(0, 0, String::new(), false);
+        file_info.set_binary_meta(true);
+        let bytes = encode_meta(&file_info, &meta);
+        assert!(bytes.len() * 4 < serde_json::to_vec(&meta).unwrap().len());
+
+        let len = bytes.len();
+        let bytes: Storage = Arc::new(bytes);
+        let decoded = decode_meta(&file_info, &bytes, 0..len).unwrap();
+        // Block lists are decoded on first access.
+        assert!(decoded.field_to_meta[Fields::Pos as usize].blocks.blocks.get().is_none());
+        assert_eq!(decoded.view_blocks(&Fields::Pos)[0].stats.as_ref().unwrap().max_value, 9);
+        assert_eq!(decoded.view_column_blocks(&Fields::RawTagsLen, Some(0))[0].seekpos, 1012);
+        assert_eq!(decoded.view_blocks(&Fields::RefID).len(), 100);
+        assert_eq!(decoded.get_ref_seqs(), meta.get_ref_seqs());
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&meta).unwrap());
+        assert!(decode_meta(&file_info, &bytes, 0..len - 1).is_err());
+    }
+
+    #[test]
+    fn test_json_meta() {
+        let mut meta = FileMeta::new(&[Codecs::Lz4], vec![("chr1".to_owned(), 1000)], Vec::new());
+        meta.add_tag_column(*b"NM", Codecs::Gzip);
+        for (i, field) in Fields::iterator().enumerate() {
+            meta.get_blocks(field).push(BlockMeta { seekpos: i as u64, numitems: 4096, ..Default::default() });
+        }
+        meta.get_column_blocks(&Fields::RawTagsLen, Some(0)).push(BlockMeta { seekpos: 1012, numitems: 3, ..Default::default() });
+        let file_info = FileInfo::new(0, 0, String::new(), false);
+        let bytes = encode_meta(&file_info, &meta);
+        // Meta parsed without `decode_meta` has block lists decoded.
+        let parsed: FileMeta = serde_json::from_slice(&bytes).unwrap();
+        assert!(parsed.field_to_meta[Fields::Pos as usize].blocks.blocks.get().is_some());
+
+        let len = bytes.len();
+        let bytes: Storage = Arc::new(bytes);
+        let decoded = decode_meta(&file_info, &bytes, 0..len).unwrap();
+        assert!(decoded.field_to_meta[Fields::Pos as usize].blocks.blocks.get().is_none());
+        for field in Fields::iterator() {
+            assert_eq!(decoded.view_blocks(field)[0].seekpos, meta.view_blocks(field)[0].seekpos);
+        }
+        assert_eq!(decoded.view_column_blocks(&Fields::RawTagsLen, Some(0))[0].seekpos, 1012);
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&meta).unwrap());
+
+        let mut value = serde_json::to_value(&meta).unwrap();
+        value["field_to_meta"]["Pos"]["blocks"] = serde_json::json!([{ "seekpos": "damaged" }]);
+        let bytes = serde_json::to_vec(&value).unwrap();
+        let len = bytes.len();
+        let damaged = decode_meta(&file_info, &(Arc::new(bytes) as Storage), 0..len).unwrap();
+        assert!(damaged.decode_blocks([&Fields::Flags]).is_ok());
+        let err = damaged.decode_blocks([&Fields::RefID, &Fields::Pos]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
 }
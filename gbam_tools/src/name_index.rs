@@ -0,0 +1,247 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::writer::{open_for_update, rewrite_meta};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Records covered by one Bloom filter of the index.
+pub const NAME_INDEX_RANGE: u64 = 1 << 16;
+// About 1% false positives with 7 hashes.
+const BITS_PER_NAME: u64 = 10;
+const HASHES: u32 = 7;
+
+/// Read name index: Bloom filter of read names of every range of
+/// [`NAME_INDEX_RANGE`] consecutive records (in stored order), so lookup by
+/// name scans only ranges which may hold it. Filters are stored as raw bytes
+/// after data blocks of GBAM file, meta holds their location.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NameIndex {
+    seekpos: u64,
+    // Records covered by filters.
+    records: u64,
+    range: u64,
+    // Bytes of one filter.
+    filter_size: u64,
+    hashes: u32,
+}
+
+impl NameIndex {
+    /// Number of records covered, the following ones are not indexed.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Bytes of all filters.
+    pub fn size(&self) -> u64 {
+        self.records.div_ceil(self.range) * self.filter_size
+    }
+
+    /// Ranges of records which may hold records named `name` (without
+    /// terminating NUL). `bytes` are bytes of the GBAM file.
+    pub fn candidate_ranges(&self, bytes: &[u8], name: &[u8]) -> io::Result<Vec<Range<u64>>> {
+        let filters = usize::try_from(self.seekpos)
+            .ok()
+            .and_then(|start| bytes.get(start..start + self.size() as usize))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Name index is out of file."))?;
+        let hashes = name_hashes(name);
+        Ok(filters
+            .chunks_exact(self.filter_size as usize)
+            .enumerate()
+            .filter(|(_, filter)| bits(hashes, self.hashes, filter.len()).all(|bit| filter[bit / 8] & (1 << (bit % 8)) != 0))
+            .map(|(idx, _)| {
+                let start = idx as u64 * self.range;
+                start..(start + self.range).min(self.records)
+            })
+            .collect())
+    }
+}
+
+/// Builds [`NameIndex`] from records pushed in stored order.
+pub(crate) struct NameIndexBuilder {
+    range: u64,
+    filter_size: u64,
+    hashes: u32,
+    filters: Vec<u8>,
+    records: u64,
+}
+
+impl NameIndexBuilder {
+    pub fn new() -> Self {
+        Self {
+            range: NAME_INDEX_RANGE,
+            filter_size: NAME_INDEX_RANGE * BITS_PER_NAME / 8,
+            hashes: HASHES,
+            filters: Vec::new(),
+            records: 0,
+        }
+    }
+
+    /// Continues index of file, e.g. when appending to it. Filters are read
+    /// from `file`.
+    pub fn resume<R: Read + Seek>(index: &NameIndex, file: &mut R) -> io::Result<Self> {
+        let mut filters = vec![0; index.size() as usize];
+        file.seek(SeekFrom::Start(index.seekpos))?;
+        file.read_exact(&mut filters)?;
+        Ok(Self {
+            range: index.range,
+            filter_size: index.filter_size,
+            hashes: index.hashes,
+            filters,
+            records: index.records,
+        })
+    }
+
+    /// Adds name of the next record, terminating NUL is ignored.
+    pub fn push(&mut self, name: &[u8]) {
+        if self.records.is_multiple_of(self.range) {
+            self.filters.resize(self.filters.len() + self.filter_size as usize, 0);
+        }
+        self.records += 1;
+        let filter_start = self.filters.len() - self.filter_size as usize;
+        let filter = &mut self.filters[filter_start..];
+        for bit in bits(name_hashes(trim_nul(name)), self.hashes, filter.len()) {
+            filter[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn push_record(&mut self, record: &BAMRawRecord) {
+        self.push(record.get_bytes(&Fields::ReadName));
+    }
+
+    /// Writes filters at the current position of `out`.
+    pub fn write<W: Write + Seek>(self, out: &mut W) -> io::Result<NameIndex> {
+        let seekpos = out.stream_position()?;
+        out.write_all(&self.filters)?;
+        Ok(NameIndex {
+            seekpos,
+            records: self.records,
+            range: self.range,
+            filter_size: self.filter_size,
+            hashes: self.hashes,
+        })
+    }
+}
+
+pub(crate) fn trim_nul(name: &[u8]) -> &[u8] {
+    name.strip_suffix(&[0]).unwrap_or(name)
+}
+
+/// Two hashes of name for double hashing. FNV-1a is stable across platforms
+/// and versions, unlike std hashers, and is mixed by SplitMix64 finalizer.
+pub(crate) fn name_hashes(name: &[u8]) -> (u64, u64) {
+    let hash = name.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3));
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    (mix(hash), mix(hash ^ 0x9e37_79b9_7f4a_7c15) | 1)
+}
+
+/// Bits of filter of `filter_size` bytes set for name.
+fn bits((h1, h2): (u64, u64), hashes: u32, filter_size: usize) -> impl Iterator<Item = usize> {
+    let bits = filter_size as u64 * 8;
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+/// Builds name index of GBAM file and stores it in the file, replacing the
+/// existing one.
+pub fn store_name_index<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let (mut file, mut file_info, mut file_meta) = open_for_update(path)?;
+    let mut reader = Reader::new_with_meta(file.try_clone()?, ParsingTemplate::new(), &Arc::new(file_meta.clone()), None)?;
+    let mut builder = NameIndexBuilder::new();
+    let mut records = reader.records_with(&[Fields::ReadName]);
+    while let Some(rec) = records.next_rec() {
+        builder.push(rec.read_name.as_ref().unwrap());
+    }
+    drop(records);
+    drop(reader);
+    file.seek(SeekFrom::End(0))?;
+    file_meta.set_name_index(Some(builder.write(&mut file)?));
+    rewrite_meta(&mut file, &mut file_info, &file_meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{parse_sam, write_sam};
+    use crate::{Codecs, Writer};
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_builder() {
+        let mut builder = NameIndexBuilder::new();
+        for i in 0..NAME_INDEX_RANGE + 10 {
+            builder.push(format!("read{}\0", i).as_bytes());
+        }
+        let mut out = io::Cursor::new(vec![0; 4]);
+        out.seek(SeekFrom::End(0)).unwrap();
+        let index = builder.write(&mut out).unwrap();
+        assert_eq!(index.size(), 2 * NAME_INDEX_RANGE * BITS_PER_NAME / 8);
+        let bytes = out.into_inner();
+        assert_eq!(index.candidate_ranges(&bytes, b"read5").unwrap(), vec![0..NAME_INDEX_RANGE]);
+        assert_eq!(
+            index.candidate_ranges(&bytes, format!("read{}", NAME_INDEX_RANGE + 3).as_bytes()).unwrap(),
+            vec![NAME_INDEX_RANGE..NAME_INDEX_RANGE + 10]
+        );
+        let false_positives = (0..1000).filter(|i| !index.candidate_ranges(&bytes, format!("other{}", i).as_bytes()).unwrap().is_empty()).count();
+        assert!(false_positives < 50);
+        assert!(index.candidate_ranges(&bytes[..100], b"read5").is_err());
+
+        // Appended names go to the partially filled last filter.
+        let mut builder = NameIndexBuilder::resume(&index, &mut io::Cursor::new(bytes)).unwrap();
+        builder.push(b"appended\0");
+        let mut out = io::Cursor::new(Vec::new());
+        let index = builder.write(&mut out).unwrap();
+        assert_eq!(index.records(), NAME_INDEX_RANGE + 11);
+        let bytes = out.into_inner();
+        assert_eq!(index.candidate_ranges(&bytes, b"appended").unwrap(), vec![NAME_INDEX_RANGE..NAME_INDEX_RANGE + 11]);
+        assert_eq!(index.candidate_ranges(&bytes, b"read5").unwrap(), vec![0..NAME_INDEX_RANGE]);
+    }
+
+    #[test]
+    fn test_find_by_name() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..100 {
+            sam.push_str(&format!("p{}\t99\tchr1\t{}\t60\t10M\t=\t{}\t110\t*\t*\n", i, i * 10 + 1, i * 10 + 101));
+        }
+        sam.push_str("p7\t147\tchr1\t171\t60\t10M\t=\t71\t-110\t*\t*\n");
+
+        let dir = TempDir::new("gbam_name_index_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        write_sam(&sam, |sam_header, ref_seqs| {
+            Writer::new_no_stats(BufWriter::new(File::create(&path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false)
+        });
+        let (_, _, records) = parse_sam(&sam);
+
+        let mut reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new_with(&[Fields::Pos])).unwrap();
+        assert!(reader.file_meta.name_index().is_none());
+        assert_eq!(reader.find_by_name(b"p7").unwrap(), vec![7, 100]);
+        store_name_index(&path).unwrap();
+
+        let mut reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new_with(&[Fields::Pos])).unwrap();
+        assert_eq!(reader.file_meta.name_index().unwrap().records(), 101);
+        assert_eq!(reader.find_by_name(b"p7").unwrap(), vec![7, 100]);
+        assert_eq!(reader.find_by_name(b"p7\0").unwrap(), vec![7, 100]);
+        assert!(reader.find_by_name(b"p700").unwrap().is_empty());
+        // Template is kept.
+        assert_eq!(reader.records().next_rec().unwrap().read_name, None);
+
+        let mut appender = Writer::open_for_append(&path, 2).unwrap();
+        appender.push_record(&BAMRawRecord(Cow::Borrowed(&records[3])));
+        appender.finish().unwrap();
+        drop(appender);
+        let mut reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.file_meta.name_index().unwrap().records(), 102);
+        assert_eq!(reader.find_by_name(b"p3").unwrap(), vec![3, 101]);
+    }
+}
@@ -45,7 +45,7 @@ impl Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cigar(pub Vec<Op>);
 
 pub fn base_coverage(arr: &[Op]) -> u32 {
@@ -78,6 +78,38 @@ impl Cigar {
         count
     }
 
+    /// Calculates the length of reference covered, i.e. sum of lengths of
+    /// `M`, `D`, `N`, `=` and `X` operations.
+    pub fn reference_length(&self) -> u32 {
+        base_coverage(&self.0)
+    }
+
+    /// Operations as pairs of type (e.g. `M`) and length.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (char, u32)> + ExactSizeIterator + '_ {
+        self.ops().map(|op| (op.op_type(), op.length()))
+    }
+
+    /// Lengths of soft clips at the start and at the end, which may be
+    /// outside of hard clips.
+    pub fn soft_clips(&self) -> (u32, u32) {
+        fn soft_clip(mut ops: impl Iterator<Item = (char, u32)>) -> u32 {
+            ops.find(|(op, _)| *op != 'H').filter(|(op, _)| *op == 'S').map_or(0, |(_, len)| len)
+        }
+        (soft_clip(self.iter()), soft_clip(self.iter().rev()))
+    }
+
+    /// Lengths of hard clips at the start and at the end.
+    pub fn hard_clips(&self) -> (u32, u32) {
+        fn hard_clip(mut ops: impl Iterator<Item = (char, u32)>) -> u32 {
+            ops.next().filter(|(op, _)| *op == 'H').map_or(0, |(_, len)| len)
+        }
+        (hard_clip(self.iter()), hard_clip(self.iter().rev()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn ops(&self) -> Iter<Op> {
         self.0.iter()
     }
@@ -101,3 +133,29 @@ impl std::fmt::Display for Cigar {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cigar(ops: &[(u32, u32)]) -> Cigar {
+        Cigar::new(ops.iter().map(|&(len, op)| Op::new(len << 4 | op)).collect())
+    }
+
+    #[test]
+    fn test_cigar() {
+        // 2H3S4M1I2D5N3M2S
+        let c = cigar(&[(2, 5), (3, 4), (4, 0), (1, 1), (2, 2), (5, 3), (3, 0), (2, 4)]);
+        assert_eq!(c.to_string(), "2H3S4M1I2D5N3M2S");
+        assert_eq!(c.iter().collect::<Vec<_>>()[..3], [('H', 2), ('S', 3), ('M', 4)]);
+        assert_eq!(c.reference_length(), 14);
+        assert_eq!(c.read_length(), 13);
+        assert_eq!(c.soft_clips(), (3, 2));
+        assert_eq!(c.hard_clips(), (2, 0));
+
+        let c = cigar(&[(5, 0)]);
+        assert_eq!((c.soft_clips(), c.hard_clips()), ((0, 0), (0, 0)));
+        assert!(cigar(&[]).is_empty());
+        assert_eq!(cigar(&[]).soft_clips(), (0, 0));
+    }
+}
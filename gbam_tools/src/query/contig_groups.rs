@@ -0,0 +1,270 @@
+use crate::query::cigar::base_coverage;
+use crate::query::flagstat::BamFlags;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+
+pub const AUTOSOMES: &str = "autosomes";
+pub const SEX: &str = "sex";
+pub const MITO: &str = "mito";
+pub const ALT: &str = "alt";
+
+// Aligned bases of these records are not counted towards coverage (same as in depth).
+const NOT_COVERING: u32 = BamFlags::BAM_FUNMAP.bits() | BamFlags::BAM_FSECONDARY.bits() | BamFlags::BAM_FQCFAIL.bits() | BamFlags::BAM_FDUP.bits();
+
+/// Assignment of reference sequences to groups.
+pub struct ContigGroups {
+    groups: Vec<String>,
+    // Group index of every reference sequence (by RefID).
+    ref_to_group: Vec<usize>,
+}
+
+impl ContigGroups {
+    /// Autosomes, sex chromosomes, mitochondrion and the rest (alt, decoy,
+    /// unplaced contigs) recognized by name, with or without `chr` prefix.
+    pub fn new(ref_seqs: &[(String, u32)]) -> Self {
+        Self::with_overrides(ref_seqs, &HashMap::new())
+    }
+
+    /// Reads `contig<TAB>group` lines. Contigs which are not listed are
+    /// grouped as in [`ContigGroups::new`].
+    pub fn from_reader<R: BufRead>(reader: R, ref_seqs: &[(String, u32)]) -> io::Result<Self> {
+        let mut overrides = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut cols = line.split('\t');
+            match (cols.next(), cols.next()) {
+                (Some(contig), Some(group)) => {
+                    overrides.insert(contig.to_owned(), group.trim().to_owned());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Expected contig and group separated by tab: {}", line),
+                    ))
+                }
+            }
+        }
+        Ok(Self::with_overrides(ref_seqs, &overrides))
+    }
+
+    fn with_overrides(ref_seqs: &[(String, u32)], overrides: &HashMap<String, String>) -> Self {
+        let mut groups: Vec<String> = [AUTOSOMES, SEX, MITO, ALT].iter().map(|g| g.to_string()).collect();
+        let mut ref_to_group = Vec::with_capacity(ref_seqs.len());
+        for (name, _) in ref_seqs {
+            let group = overrides.get(name).map_or_else(|| default_group(name), |g| g.as_str());
+            let idx = match groups.iter().position(|g| g == group) {
+                Some(idx) => idx,
+                None => {
+                    groups.push(group.to_owned());
+                    groups.len() - 1
+                }
+            };
+            ref_to_group.push(idx);
+        }
+        Self { groups, ref_to_group }
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    pub fn group_of(&self, ref_id: usize) -> &str {
+        &self.groups[self.ref_to_group[ref_id]]
+    }
+}
+
+fn default_group(name: &str) -> &'static str {
+    let short = name.strip_prefix("chr").unwrap_or(name);
+    match short {
+        "X" | "Y" => SEX,
+        "M" | "MT" => MITO,
+        _ if !short.is_empty() && short.bytes().all(|c| c.is_ascii_digit()) => AUTOSOMES,
+        _ => ALT,
+    }
+}
+
+/// Counts of one reference sequence.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RefCounts {
+    pub alignments: u64,
+    pub primary_mapped: u64,
+    /// Reference bases covered by alignments (excluding secondary, duplicate
+    /// and QC failed).
+    pub aligned_bases: u64,
+}
+
+impl RefCounts {
+    fn add(&mut self, other: &RefCounts) {
+        self.alignments += other.alignments;
+        self.primary_mapped += other.primary_mapped;
+        self.aligned_bases += other.aligned_bases;
+    }
+}
+
+/// Counts of every reference sequence (by RefID) and of unmapped records.
+/// Blocks holding records of a single reference (by RefID block stats) are
+/// scanned without decoding RefID, blocks of unmapped records are not scanned
+/// at all.
+pub fn count_per_ref(file: File) -> io::Result<(Vec<RefCounts>, u64)> {
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let file_meta = reader.file_meta.clone();
+    let ref_num = file_meta.get_ref_seqs().len();
+
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for block in file_meta.view_blocks(&Fields::RefID) {
+        let single_ref = block
+            .stats
+            .as_ref()
+            .filter(|stat| stat.min_value == stat.max_value)
+            .map(|stat| stat.min_value);
+        blocks.push((start..start + block.numitems as usize, single_ref));
+        start += block.numitems as usize;
+    }
+
+    let (per_ref, unmapped) = blocks
+        .into_par_iter()
+        .map(|(range, single_ref)| {
+            let mut per_ref = vec![RefCounts::default(); ref_num];
+            if single_ref == Some(-1) {
+                return (per_ref, range.len() as u64);
+            }
+            let mut unmapped = 0;
+            let mut fields = vec![Fields::RawCigar, Fields::Flags];
+            if single_ref.is_none() {
+                fields.push(Fields::RefID);
+            }
+            let mut reader =
+                Reader::new_with_meta(file.try_clone().unwrap(), ParsingTemplate::new_with(&fields), &file_meta, None).unwrap();
+            let mut rec = GbamRecord::default();
+            for rec_num in range {
+                reader.fill_record(rec_num, &mut rec);
+                let ref_id = single_ref.unwrap_or_else(|| rec.refid.unwrap());
+                if ref_id < 0 {
+                    unmapped += 1;
+                    continue;
+                }
+                let counts = &mut per_ref[ref_id as usize];
+                let flag = u32::from(rec.flag.unwrap());
+                counts.alignments += 1;
+                if flag & (BamFlags::BAM_FUNMAP.bits() | BamFlags::BAM_FSECONDARY.bits() | BamFlags::BAM_FSUPPLEMENTARY.bits()) == 0 {
+                    counts.primary_mapped += 1;
+                }
+                if flag & NOT_COVERING == 0 {
+                    counts.aligned_bases += u64::from(base_coverage(&rec.cigar.as_ref().unwrap().0[..]));
+                }
+            }
+            (per_ref, unmapped)
+        })
+        .reduce(
+            || (vec![RefCounts::default(); ref_num], 0),
+            |(mut a, a_unmapped), (b, b_unmapped)| {
+                a.iter_mut().zip(b.iter()).for_each(|(a, b)| a.add(b));
+                (a, a_unmapped + b_unmapped)
+            },
+        );
+    Ok((per_ref, unmapped))
+}
+
+/// Writes metrics per contig group as TSV, followed by sex inference ratios
+/// and mitochondrial fraction.
+pub fn write_group_summary(
+    out: &mut dyn Write,
+    ref_seqs: &[(String, u32)],
+    groups: &ContigGroups,
+    per_ref: &[RefCounts],
+    unmapped: u64,
+) -> io::Result<()> {
+    let mut group_counts = vec![(0usize, 0u64, RefCounts::default()); groups.groups().len()];
+    for (ref_id, ((_, len), counts)) in ref_seqs.iter().zip(per_ref.iter()).enumerate() {
+        let entry = &mut group_counts[groups.ref_to_group[ref_id]];
+        entry.0 += 1;
+        entry.1 += u64::from(*len);
+        entry.2.add(counts);
+    }
+    let total_primary: u64 = per_ref.iter().map(|c| c.primary_mapped).sum();
+
+    writeln!(out, "group\tcontigs\tlength\talignments\tprimary_mapped\taligned_bases\tmean_coverage\tprimary_mapped_fraction")?;
+    for (name, (contigs, len, counts)) in groups.groups().iter().zip(group_counts.iter()) {
+        if *contigs == 0 {
+            continue;
+        }
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.6}",
+            name,
+            contigs,
+            len,
+            counts.alignments,
+            counts.primary_mapped,
+            counts.aligned_bases,
+            ratio(counts.aligned_bases, *len),
+            ratio(counts.primary_mapped, total_primary),
+        )?;
+    }
+    writeln!(out, "unmapped\t0\t0\t{}\t0\t0\t0\t0", unmapped)?;
+
+    let coverage_of = |names: &[&str]| {
+        ref_seqs
+            .iter()
+            .zip(per_ref.iter())
+            .find(|((name, _), _)| names.contains(&name.as_str()))
+            .map(|((_, len), counts)| ratio(counts.aligned_bases, u64::from(*len)))
+    };
+    let autosomes = groups.groups().iter().position(|g| g == AUTOSOMES).map(|idx| group_counts[idx]);
+    if let Some((_, len, counts)) = autosomes.filter(|(contigs, _, _)| *contigs > 0) {
+        let autosome_coverage = ratio(counts.aligned_bases, len);
+        for (label, names) in [("chrX", ["chrX", "X"]), ("chrY", ["chrY", "Y"])] {
+            if let Some(coverage) = coverage_of(&names) {
+                writeln!(out, "# {}/autosomes coverage ratio\t{:.4}", label, coverage / autosome_coverage)?;
+            }
+        }
+    }
+    if let Some(idx) = groups.groups().iter().position(|g| g == MITO) {
+        writeln!(
+            out,
+            "# mito fraction of primary mapped reads\t{:.6}",
+            ratio(group_counts[idx].2.primary_mapped, total_primary)
+        )?;
+    }
+    Ok(())
+}
+
+fn ratio(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_groups() {
+        let ref_seqs: Vec<(String, u32)> = ["chr1", "22", "chrX", "Y", "chrM", "MT", "chr1_KI270706v1_random", "chrUn_GL000195v1", "HLA-A*01:01:01:01"]
+            .iter()
+            .map(|name| (name.to_string(), 1))
+            .collect();
+        let groups = ContigGroups::new(&ref_seqs);
+        let assigned: Vec<&str> = (0..ref_seqs.len()).map(|id| groups.group_of(id)).collect();
+        assert_eq!(assigned, vec![AUTOSOMES, AUTOSOMES, SEX, SEX, MITO, MITO, ALT, ALT, ALT]);
+
+        let config = "chrX\tchrX\nchrY\tchrY\n";
+        let groups = ContigGroups::from_reader(config.as_bytes(), &ref_seqs).unwrap();
+        assert_eq!(groups.group_of(2), "chrX");
+        assert_eq!(groups.group_of(3), SEX);
+        assert_eq!(groups.groups().len(), 5);
+    }
+}
@@ -1,304 +1,1010 @@
 use bam_tools::record::fields::Fields;
-use std::cmp::min;
-use std::convert::TryInto;
-use std::io::{Write, BufWriter, StdoutLock};
-use std::ops::{RangeInclusive, Range};
+use std::cmp::{max, min};
+use std::io::Write;
+use std::ops::Range;
 use std::sync::Arc;
-use std::{cmp::Ordering, collections::HashMap, time::Instant};
+use std::collections::HashMap;
 use std::fs::File;
 use crate::reader::parse_tmplt::ParsingTemplate;
 use crate::utils::bed;
+use crate::utils::bigwig::BigWigWriter;
+use crate::utils::d4::D4Writer;
+use crate::utils::sink::OutputSink;
+use crate::error::{GbamError, Result};
 /// This module provides function for fast querying of read depth.
-use crate::meta::{BlockMeta, FileMeta};
+use crate::meta::{FileMeta, SortOrder};
 use crate::reader::{reader::Reader, record::GbamRecord};
-use crate::query::cigar::base_coverage;
+use crate::query::cigar::Op;
 use std::path::{PathBuf};
-use crossbeam::channel::{Receiver, Sender, bounded};
-use std::thread;
-use std::thread::JoinHandle;
+use crossbeam::channel::unbounded;
+use std::collections::BTreeMap;
 use super::int2str::{i32toa_countlut, u32toa_countlut};
-use flate2::Compression;
-use flate2::write::GzEncoder;
 use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
-#[allow(dead_code)]
-fn panic_err() {
-    panic!("The query you entered is incorrect. The format is as following: <ref name>:<position>\ne.g. chr1:1257\n");
+/// Position of record in coordinate order. Without index records are already
+/// in this order.
+#[inline]
+fn record_idx(index_file: &Option<Arc<Vec<u32>>>, idx: usize) -> usize {
+    index_file.as_ref().map_or(idx, |index| index[idx] as usize)
 }
 
-fn process_range(preparsed_records: Arc<Vec<DepthUnit>>, index_file: Option<Arc<Vec<u32>>>, rec_range: Range<usize>, mut scan_line: Vec<i32>, target_id: i32) -> Vec<i32> {
-    // let mut rec = GbamRecord::default();
+/// Unmapped, secondary, QC failed and duplicate, as skipped by `samtools
+/// depth`. Depth counts all reads by default, pass these as
+/// [`DepthOptions::exclude_flags`] for samtools compatible counts.
+pub const DEFAULT_EXCLUDE_FLAGS: u16 = 0x4 | 0x100 | 0x200 | 0x400;
+
+/// Which reads are counted in depth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepthOptions {
+    /// Reads with map quality lower than this are skipped.
+    pub min_mapq: u8,
+    /// Only reads with all of these flag bits set are counted.
+    pub include_flags: u16,
+    /// Reads with any of these flag bits set are skipped.
+    pub exclude_flags: u16,
+    /// Deletions (CIGAR `D`) are not counted.
+    pub skip_deletions: bool,
+    /// Skipped regions, e.g. introns of spliced reads (CIGAR `N`), are not
+    /// counted.
+    pub skip_ref_skips: bool,
+    /// Aligned bases with lower quality are not counted, 0 disables the
+    /// check. Deletions have no quality and are not checked.
+    pub min_base_quality: u8,
+    /// Bases where mates of a pair overlap are counted once. The overlap is
+    /// found from position, mate position and template length of the
+    /// leftmost mate and is not counted for it, so it is lost if the other
+    /// mate is filtered out.
+    pub mate_overlap: bool,
+}
+
+impl DepthOptions {
+    fn counts(&self, rec: &DepthUnit) -> bool {
+        rec.mapq >= self.min_mapq && rec.flag & self.include_flags == self.include_flags && rec.flag & self.exclude_flags == 0
+    }
+
+    /// Whether only parts of reads may be counted, so CIGAR has to be walked.
+    fn needs_segments(&self) -> bool {
+        self.skip_deletions || self.skip_ref_skips || self.min_base_quality > 0
+    }
+
+    /// Offsets from read start of reference range shared with the mate, if
+    /// the read is the leftmost mate of an overlapping pair. For mates
+    /// starting at the same position the first in pair is the leftmost.
+    fn mate_overlap(&self, rec: &GbamRecord, span: u32) -> Option<Range<u32>> {
+        const PAIRED: u16 = 0x1;
+        const MATE_UNMAPPED: u16 = 0x8;
+        const FIRST_IN_PAIR: u16 = 0x40;
+        if !self.mate_overlap {
+            return None;
+        }
+        let flag = rec.flag.unwrap();
+        let (pos, mate_pos, tlen) = (rec.pos.unwrap(), rec.next_pos.unwrap(), rec.tlen.unwrap());
+        if flag & (PAIRED | MATE_UNMAPPED) != PAIRED || rec.refid != rec.next_ref_id || tlen <= 0 {
+            return None;
+        }
+        if mate_pos < pos || (mate_pos == pos && flag & FIRST_IN_PAIR == 0) {
+            return None;
+        }
+        let start = (mate_pos - pos) as u32;
+        // Template ends where the mate does.
+        let end = span.min(tlen as u32);
+        (start < end).then_some(start..end)
+    }
+
+    /// Parts of reference span of read with CIGAR `ops` and base qualities
+    /// `quals` counted in depth. `quals` are used only with
+    /// `min_base_quality`.
+    fn cigar_segments(&self, ops: &[Op], quals: &[u8], segments: &mut Vec<Segment>) {
+        segments.clear();
+        let mut push = |offset: u32, len: u32| match segments.last_mut() {
+            Some(last) if last.0 + last.1 == offset => last.1 += len,
+            _ => segments.push((offset, len)),
+        };
+        let (mut offset, mut read_pos) = (0, 0);
+        for op in ops {
+            let len = op.length();
+            match op.op_type() {
+                'M' | '=' | 'X' if self.min_base_quality > 0 => {
+                    for i in 0..len {
+                        // Missing qualities (0xff) pass.
+                        if quals.get((read_pos + i) as usize).is_none_or(|&qual| qual >= self.min_base_quality) {
+                            push(offset + i, 1);
+                        }
+                    }
+                }
+                'M' | '=' | 'X' => push(offset, len),
+                'D' if !self.skip_deletions => push(offset, len),
+                'N' if !self.skip_ref_skips => push(offset, len),
+                _ => {}
+            }
+            if op.consumes_read() {
+                read_pos += len;
+            }
+            if op.is_consuming_reference() {
+                offset += len;
+            }
+        }
+        segments.retain(|segment| segment.1 > 0);
+    }
+}
+
+/// Adds records of `target_id` passing `options` to sweep line of `tile`,
+/// whose first slot is `tile.start`. Reads are clipped to the tile.
+fn process_range(preparsed_records: Arc<PreparsedRecords>, index_file: Option<Arc<Vec<u32>>>, rec_range: Range<usize>, mut scan_line: Vec<i32>, target_id: i32, tile: Range<u32>, options: DepthOptions) -> Vec<i32> {
+    let mut add = |start: u32, end: u32| {
+        let (start, end) = (start.max(tile.start), end.min(tile.end));
+        if start < end {
+            scan_line[(start - tile.start) as usize] += 1;
+            scan_line[(end - tile.start) as usize] -= 1;
+        }
+    };
     for idx in rec_range {
-        let rec = preparsed_records[index_file.as_ref().unwrap()[idx] as usize];
-        if rec.refid != target_id {
+        let rec = preparsed_records[record_idx(&index_file, idx)];
+        if rec.refid != target_id || rec.pos as u32 >= tile.end {
             break;
         }
-        if rec.cigar == 0 {
+        if rec.cigar == 0 || !options.counts(&rec) {
             continue;
         }
-        let read_start: usize = rec.pos as usize;
-        let mut base_cov = rec.cigar as usize;
-        if(rec.flag & 0b11100000100) != 0 {
-            base_cov = 0;
+        let read_start = rec.pos as u32;
+        if let Some(segments) = preparsed_records.segments(&rec) {
+            for &(offset, len) in segments {
+                add(read_start + offset, read_start + offset + len);
+            }
+            continue;
         }
-        if base_cov == 0 {
-            base_cov = 1;
+        add(read_start, read_start + rec.cigar);
+    }
+    scan_line
+}
+
+/// Depth of every base of `ref_id`, or its band index if `quantize` is given.
+pub(crate) fn calc_depth(preparsed_records: Arc<PreparsedRecords>, index_file: Option<Arc<Vec<u32>>>, ref_id: i32, coverage_arr: Vec<i32>, ref_len: usize, options: DepthOptions, quantize: Option<&Quantize>) -> Vec<i32> {
+    calc_depth_tile(preparsed_records, index_file, ref_id, 0..ref_len as u32, coverage_arr, options, quantize, 1)
+}
+
+// Fewer records are not worth a shard of their own.
+const MIN_SHARD_RECORDS: usize = 100_000;
+
+/// As [`calc_depth`], but only for bases of `tile`: the result is
+/// `tile.len() + 1` long and starts at `tile.start`. Only records which can
+/// reach the tile are visited, so the whole reference sequence is never held.
+/// Records are split into up to `shards` ranges processed in parallel.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn calc_depth_tile(preparsed_records: Arc<PreparsedRecords>, index_file: Option<Arc<Vec<u32>>>, ref_id: i32, tile: Range<u32>, mut coverage_arr: Vec<i32>, options: DepthOptions, quantize: Option<&Quantize>, shards: usize) -> Vec<i32> {
+    coverage_arr.clear();
+    coverage_arr.resize(tile.len() + 1, 0);
+    let min_pos = tile.start.saturating_sub(preparsed_records.max_span).min(i32::MAX as u32) as i32;
+    let first_rec = first_record_at(&preparsed_records, &index_file, ref_id, min_pos);
+    let amount = preparsed_records.len();
+    if first_rec == amount || preparsed_records[record_idx(&index_file, first_rec)].refid != ref_id {
+        if let Some(quantize) = quantize {
+            coverage_arr.fill(quantize.band(0));
         }
-      
-        let read_end = read_start + base_cov;
+        return coverage_arr;
+    }
 
-        scan_line[read_start] += 1;
-        scan_line[read_end] -= 1;
-        // buf.increments.push(read_start);
-        // buf.decrements.push(read_end);
+    let last_rec = first_record_at(&preparsed_records, &index_file, ref_id, tile.end.min(i32::MAX as u32) as i32);
+    let shard_len = max((last_rec - first_rec).div_ceil(shards.max(1)), MIN_SHARD_RECORDS);
+    let mut coverage = if last_rec - first_rec > shard_len {
+        sharded_scan_line(preparsed_records, index_file, first_rec..last_rec, coverage_arr, ref_id, tile, options, shard_len)
+    } else {
+        process_range(preparsed_records, index_file, first_rec..last_rec, coverage_arr, ref_id, tile, options)
+    };
+    prefix_sum(&mut coverage);
+    if let Some(quantize) = quantize {
+        coverage.iter_mut().for_each(|slot| *slot = quantize.band(*slot));
+    }
+    coverage
+}
+
+/// Replaces sweep line `values` with their running sums, i.e. depth. Uses
+/// SSE2 on x86_64, see [`prefix_sum_scalar`] for other targets.
+pub fn prefix_sum(values: &mut [i32]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        prefix_sum_sse2(values);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        prefix_sum_scalar(values);
     }
-    scan_line
 }
 
-fn calc_depth(preparsed_records: Arc<Vec<DepthUnit>>, file_meta: Arc<FileMeta>, index_file: Option<Arc<Vec<u32>>>, number_of_records: usize, ref_id: i32, mut coverage_arr: Vec<i32>, ref_len: usize) -> Vec<i32> {
-    coverage_arr.resize(ref_len+1, 0);
+/// Running sums of `values`, one value at a time.
+pub fn prefix_sum_scalar(values: &mut [i32]) {
+    running_sum(values, 0);
+}
 
-    // let lower_bound = if let Some(block_num) = find_leftmost_block(ref_id, file_meta.view_blocks(&Fields::RefID)) {
-    //     block_num as usize
-    // }
-    // else {
-    //     // This refid was not found in any block
-    //     return coverage_arr;
-    // };
-    // let upper_bound = find_rightmost_block(ref_id, file_meta.view_blocks(&Fields::RefID)) as usize;
-    let mut first_rec:i64 = -1;
-    
-    let mut last_rec:i64=  preparsed_records.len() as i64;
-    
-  
-    while(last_rec - first_rec > 1){
-        let mid: usize = ((first_rec + last_rec)/2) as usize;
-        let buf = preparsed_records[index_file.as_ref().unwrap()[mid] as usize];
-        if buf.refid >= ref_id || buf.refid == -1 {
-            last_rec = mid as i64;
+fn running_sum(values: &mut [i32], mut acc: i32) {
+    for value in values {
+        acc = acc.wrapping_add(*value);
+        *value = acc;
+    }
+}
+
+/// Running sums of 4 values at a time: each vector is added to itself shifted
+/// by one and by two lanes, then the last sum of the previous one is added.
+#[cfg(target_arch = "x86_64")]
+fn prefix_sum_sse2(values: &mut [i32]) {
+    use std::arch::x86_64::*;
+
+    let vectors = values.len() / 4;
+    let ptr = values.as_mut_ptr() as *mut __m128i;
+    // SSE2 is part of x86_64 baseline. Loads and stores are unaligned and
+    // stay within `values`.
+    unsafe {
+        let mut carry = _mm_setzero_si128();
+        for i in 0..vectors {
+            let mut sums = _mm_loadu_si128(ptr.add(i));
+            sums = _mm_add_epi32(sums, _mm_slli_si128::<4>(sums));
+            sums = _mm_add_epi32(sums, _mm_slli_si128::<8>(sums));
+            sums = _mm_add_epi32(sums, carry);
+            _mm_storeu_si128(ptr.add(i), sums);
+            carry = _mm_shuffle_epi32::<0xFF>(sums);
         }
-        else{
-            first_rec = mid as i64;
+    }
+    let acc = match vectors {
+        0 => 0,
+        _ => values[vectors * 4 - 1],
+    };
+    running_sum(&mut values[vectors * 4..], acc);
+}
+
+/// Coverage bands as in `mosdepth --quantize`, e.g. `0:1:5:30:` gives bands
+/// `[0, 1)`, `[1, 5)`, `[5, 30)` and `[30, inf)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quantize {
+    bounds: Vec<i32>,
+    // Last band has no upper bound.
+    open_end: bool,
+}
+
+impl Quantize {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || GbamError::InvalidArgument(format!("Invalid quantize bands {}.", spec));
+        let mut parts: Vec<&str> = spec.split(':').collect();
+        let open_end = parts.last() == Some(&"");
+        if open_end {
+            parts.pop();
         }
+        let bounds = parts.iter().map(|part| part.parse::<i32>().map_err(|_| invalid())).collect::<Result<Vec<_>>>()?;
+        if bounds.is_empty() || bounds.len() + usize::from(open_end) < 2 || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid());
+        }
+        Ok(Self { bounds, open_end })
     }
-    first_rec += 1;
-    let amount = preparsed_records.len();
 
-    if first_rec as usize == amount || preparsed_records[index_file.as_ref().unwrap()[first_rec as usize] as usize].refid != ref_id {
-        return coverage_arr;
+    /// Band index of `depth`, -1 if it is in no band.
+    pub fn band(&self, depth: i32) -> i32 {
+        let band = self.bounds.partition_point(|&bound| bound <= depth) as i32 - 1;
+        if band + 1 == self.bounds.len() as i32 && !self.open_end {
+            return -1;
+        }
+        band
     }
 
+    /// Label of band, e.g. `1:5` or `30:inf`.
+    pub fn label(&self, band: i32) -> String {
+        let start = self.bounds[band as usize];
+        match self.bounds.get(band as usize + 1) {
+            Some(end) => format!("{}:{}", start, end),
+            None => format!("{}:inf", start),
+        }
+    }
+}
 
-    // let last_rec = std::cmp::min(upper_bound*file_meta.view_blocks(&Fields::RefID)[0].numitems as usize, number_of_records-1);
+/// Runs of equal values of `coverage` within `region` (clipped to
+/// `ref_len`).
+fn runs(coverage: &[i32], region: (u32, u32), ref_len: u32) -> impl Iterator<Item = (u32, u32, i32)> + '_ {
+    let end = min(region.1, ref_len);
+    let mut start = region.0;
+    std::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+        let value = coverage[start as usize];
+        let len = coverage[start as usize..end as usize].iter().take_while(|&&v| v == value).count() as u32;
+        let run = (start, start + len, value);
+        start += len;
+        Some(run)
+    })
+}
 
-    // let mut temp_reader = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::RawCigar]), &file_meta).unwrap();
-    // let mut rec = GbamRecord::default();
-    // temp_reader.fill_record(last_rec, &mut rec);
+/// Sweep line of `tile` as [`process_range`], with `rec_range` split into
+/// shards of `shard_len` records processed in parallel. Records are sorted by
+/// position, so the sweep line of a shard only spans the positions its records
+/// cover, and is added to `scan_line` afterwards.
+#[allow(clippy::too_many_arguments)]
+fn sharded_scan_line(preparsed_records: Arc<PreparsedRecords>, index_file: Option<Arc<Vec<u32>>>, rec_range: Range<usize>, mut scan_line: Vec<i32>, target_id: i32, tile: Range<u32>, options: DepthOptions, shard_len: usize) -> Vec<i32> {
+    let shards: Vec<usize> = rec_range.clone().step_by(shard_len).collect();
+    let parts: Vec<(u32, Vec<i32>)> = shards.into_par_iter().map(|start| {
+        let end = min(start + shard_len, rec_range.end);
+        let first_pos = preparsed_records[record_idx(&index_file, start)].pos as u32;
+        let last_pos = preparsed_records[record_idx(&index_file, end - 1)].pos as u32;
+        let span = tile.start.max(first_pos)..min(tile.end, last_pos.saturating_add(preparsed_records.max_span));
+        let part = process_range(preparsed_records.clone(), index_file.clone(), start..end, vec![0; span.len() + 1], target_id, span.clone(), options);
+        (span.start, part)
+    }).collect();
+    for (start, part) in parts {
+        for (slot, diff) in scan_line[(start - tile.start) as usize..].iter_mut().zip(part) {
+            *slot += diff;
+        }
+    }
+    scan_line
+}
 
-    // let read_start: usize = rec.pos.unwrap().try_into().unwrap();
-    // let base_cov = rec.cigar.as_ref().unwrap().base_coverage() as usize;
-    // let read_end = read_start + base_cov;
+/// Index (in coordinate order) of the first record of `ref_id` or of the
+/// first record after them.
+fn first_ref_record(preparsed_records: &[DepthUnit], index_file: &Option<Arc<Vec<u32>>>, ref_id: i32) -> usize {
+    first_record_at(preparsed_records, index_file, ref_id, i32::MIN)
+}
 
-    // Loads of page faults here.
-    
+/// Index (in coordinate order) of the first record of `ref_id` starting at
+/// `pos` or later, or of the first record after them.
+fn first_record_at(preparsed_records: &[DepthUnit], index_file: &Option<Arc<Vec<u32>>>, ref_id: i32, pos: i32) -> usize {
+    let mut first_rec:i64 = -1;
+    let mut last_rec:i64=  preparsed_records.len() as i64;
+    while last_rec - first_rec > 1 {
+        let mid: usize = ((first_rec + last_rec)/2) as usize;
+        let buf = preparsed_records[record_idx(index_file, mid)];
+        if buf.refid > ref_id || buf.refid == -1 || (buf.refid == ref_id && buf.pos >= pos) {
+            last_rec = mid as i64;
+        }
+        else{
+            first_rec = mid as i64;
+        }
+    }
+    (first_rec + 1) as usize
+}
 
-    // dbg!("Allocated {}", ref_len);
+/// Counted part of read: offset from read start and length.
+type Segment = (u32, u32);
 
-    let mut coverage = process_range(preparsed_records, index_file, first_rec as usize..amount, coverage_arr, ref_id);
-    let mut acc = 0;
-    for slot in coverage.iter_mut() {
-        acc += *slot;
-        *slot = acc; 
+/// Removes `range` of offsets from `segments`.
+fn remove_range(segments: &mut Vec<Segment>, range: Range<u32>) {
+    let mut kept = Vec::with_capacity(segments.len() + 1);
+    for &(offset, len) in segments.iter() {
+        let end = offset + len;
+        if offset < range.start {
+            kept.push((offset, end.min(range.start) - offset));
+        }
+        if end > range.end {
+            let start = offset.max(range.end);
+            kept.push((start, end - start));
+        }
     }
-    coverage
+    *segments = kept;
 }
 
-#[derive(Default, Clone, Copy)]
-struct DepthUnit {
+const NO_SEGMENTS: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+pub(crate) struct DepthUnit {
     refid: i32,
     pos: i32,
     cigar: u32,
     flag: u16,
+    mapq: u8,
+    // Index of segment count in `PreparsedRecords::segments`, followed by the
+    // segments. NO_SEGMENTS if the whole reference span is counted.
+    segments: u32,
 }
 
-pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Option<Arc<Vec<u32>>>, bed_cli_request: Option<String>, _mapq: Option<u32>, bed_gz_path: Option<PathBuf>, thread_num: Option<usize>){
-    let mut queries = HashMap::<String, Vec<(u32, u32)>>::new();
-    if let Some(bed_path) = bed_file {
-        queries = bed::parse_bed_from_file(bed_path).expect("BED file is corrupted.");
-    } 
-    if let Some(query) = bed_cli_request {
-        queries.extend(bed::parse_bed(&mut query.as_bytes()).unwrap().into_iter());
+impl Default for DepthUnit {
+    fn default() -> Self {
+        Self { refid: 0, pos: 0, cigar: 0, flag: 0, mapq: 0, segments: NO_SEGMENTS }
     }
+}
 
-    let mut reader = Reader::new(gbam_file.try_clone().unwrap(), ParsingTemplate::new()).unwrap();
-    let file_meta = reader.file_meta.clone();
-    let ref_seqs = file_meta.get_ref_seqs().clone();
-    let chr_to_ref_id = get_chr_name_mapping(ref_seqs.iter().map(|(chr, _)| chr), &mut reader);
-    let number_of_records = reader.amount;
-    drop(reader);
-
-    // Calculate for whole file.
-    if queries.is_empty() {
-        ref_seqs.iter().for_each(|(chr, len)| {queries.insert(chr.clone(), vec![(0, *len)]);});
-    }
+/// Records loaded by [`preparse_records`], dereferences to the records.
+#[derive(Default)]
+pub(crate) struct PreparsedRecords {
+    units: Vec<DepthUnit>,
+    segments: Vec<Segment>,
+    // Longest reference span of a record.
+    max_span: u32,
+}
 
-    let mut buffers = vec![Vec::<i32>::new()];
-    if thread_num.is_some(){
-        buffers = vec![Vec::<i32>::new();std::cmp::min(thread_num.unwrap(), 8)];
+impl PreparsedRecords {
+    /// Counted parts of read, None if its whole span is counted.
+    fn segments(&self, unit: &DepthUnit) -> Option<&[Segment]> {
+        if unit.segments == NO_SEGMENTS {
+            return None;
+        }
+        let idx = unit.segments as usize;
+        Some(&self.segments[idx + 1..idx + 1 + self.segments[idx].0 as usize])
     }
+}
 
-    type VectorOfSendersAndReceivers= Vec::<Option<(Sender<(Arc<Vec<DepthUnit>>, Arc<FileMeta>, Option<Arc<Vec<u32>>>, usize, i32, Vec<i32>, usize, String)>,Receiver<(String, Vec<i32>)>)>>;
-    let mut circular_buf_channels = VectorOfSendersAndReceivers::new();
-    (0..buffers.len()).for_each(|_|circular_buf_channels.push(None));
-    let mut handles: Vec::<JoinHandle<()>> = Vec::new();
-
-    let mut idx = 0;
-    // let mut coverage_arr: Vec<i64> = Vec::new(); 
-    // coverage_arr.reserve(longest_chr as usize);
+impl std::ops::Deref for PreparsedRecords {
+    type Target = [DepthUnit];
 
-    
-    let mut iter = ref_seqs.iter();
-    let mut accum = 0;  
-    let mut bed_gz_printer = bed_gz_path.map(BedGzPrinter::new);
+    fn deref(&self) -> &[DepthUnit] {
+        &self.units
+    }
+}
 
-    let st = std::io::stdout();
-    let lock = st.lock();
-    let mut printer = ConsolePrinter::new(lock);
+const PREPARSE_CHUNK: usize = 2_000_000;
 
-    let mut preparsed = vec![DepthUnit::default(); number_of_records];
+/// Loads fields needed for depth calculation of records in `records` range
+/// into memory. CIGAR is walked only if `options` count parts of reads.
+pub(crate) fn preparse_records(gbam_file: &File, file_meta: &Arc<FileMeta>, records: Range<usize>, index_file: &Option<Arc<Vec<u32>>>, options: &DepthOptions) -> PreparsedRecords {
+    let mut preparsed = vec![DepthUnit::default(); records.len()];
 
-    preparsed.par_iter_mut().zip(0..number_of_records).chunks(2_000_000).for_each(|records_range| {
+    // Segments of each chunk, indexed from the chunk start.
+    let chunk_segments: Vec<Vec<Segment>> = preparsed.par_iter_mut().zip(records).chunks(PREPARSE_CHUNK).map(|records_range| {
         let mut rec =  GbamRecord::default();
-        let mut tmplt = ParsingTemplate::new();
-        tmplt.set(&Fields::RawCigar, true);
-    
-        let mut reader = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags]), &file_meta, None).unwrap();
+        let mut segments = Vec::new();
+        let mut read_segments = Vec::new();
+
+        let mut fields = vec![Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags, Fields::Mapq];
+        if options.min_base_quality > 0 {
+            fields.push(Fields::RawQual);
+        }
+        if options.mate_overlap {
+            fields.extend_from_slice(&[Fields::NextRefID, Fields::NextPos, Fields::TemplateLength]);
+        }
+        let mut reader = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&fields), file_meta, None).unwrap();
 
         for (dest, rec_num) in records_range {
             reader.fill_record(rec_num, &mut rec);
+            let cigar = rec.cigar.as_ref().unwrap();
+            let ops = &cigar.0[..];
             dest.refid = rec.refid.unwrap();
             dest.pos = rec.pos.unwrap();
-            dest.cigar = base_coverage(&rec.cigar.as_ref().unwrap().0[..]);
+            dest.cigar = cigar.reference_length();
             dest.flag = rec.flag.unwrap();
+            dest.mapq = rec.mapq.unwrap();
+            let overlap = options.mate_overlap(&rec, dest.cigar);
+            if options.needs_segments() || overlap.is_some() {
+                options.cigar_segments(ops, rec.qual.as_deref().unwrap_or_default(), &mut read_segments);
+                if let Some(overlap) = overlap {
+                    remove_range(&mut read_segments, overlap);
+                }
+                if read_segments[..] != [(0, dest.cigar)] {
+                    dest.segments = segments.len() as u32;
+                    segments.push((read_segments.len() as u32, 0));
+                    segments.extend_from_slice(&read_segments);
+                }
+            }
         }
-    });
+        segments
+    }).collect();
 
-    let arc_of_records = Arc::new(preparsed);
+    let mut segments = Vec::new();
+    for (units, chunk) in preparsed.chunks_mut(PREPARSE_CHUNK).zip(chunk_segments) {
+        let offset = segments.len() as u32;
+        units.iter_mut().filter(|unit| unit.segments != NO_SEGMENTS).for_each(|unit| unit.segments += offset);
+        segments.extend(chunk);
+    }
 
-    dbg!("Finished parsing all records to RAM buffer.");
+    if sorts_in_memory(file_meta, index_file) {
+        preparsed.par_sort_unstable_by_key(|rec| (rec.refid as u32, rec.pos));
+    }
+    let max_span = preparsed.par_iter().map(|rec| rec.cigar).max().unwrap_or(0);
+    PreparsedRecords { units: preparsed, segments, max_span }
+}
 
-    loop {
-        // dbg!(buffers.len()); 
-        if idx == circular_buf_channels.len() {
-            idx = 0;
-        }
-        if circular_buf_channels[idx].is_some() {
-            let (thread_chr, mut coverage_arr) = circular_buf_channels[idx].as_mut().unwrap().1.recv().unwrap();
-
-            if let Some(bed_regions) = queries.get(&thread_chr) {
-                // coverage_arr.resize(*ref_len as usize, 0);
-                // let ref_id = chr_to_ref_id.get(chr).unwrap().unwrap();
-                // buffers = calc_depth(gbam_file.try_clone().unwrap(), file_meta.clone(), number_of_records, ref_id, &mut coverage_arr, buffers);
-    
-                // 41641770854
-                
-                // printer.set_chr(thread_chr.clone());
-                let now = Instant::now();
-                if bed_gz_printer.is_none() {
-                    
-                    for bed_region in bed_regions {
-                        let st = bed_region.0 as usize;
-                        let en = min((bed_region.1) as usize, coverage_arr.len());
-                        for coord in st..en {
-                            unsafe {
-                                if coverage_arr.get_unchecked(coord) > &0 {
-                                    printer.write_efficient(&thread_chr, (coord) as u32, *coverage_arr.get_unchecked(coord));
-                                }
-                            }
-                        }
-                    }
-                    
-                }
-                else {
-                    
-                    for bed_region in bed_regions {
-                        let st = bed_region.0;
-                        let en = min(bed_region.1, (coverage_arr.len()) as u32);
-                        let mut prev_coord = None;
-                        let mut prev_depth = None;
-                        
-                        for coord in st..en {
-                            unsafe {
-                                let cur_depth = *coverage_arr.get_unchecked(coord as usize);
-                                // if cur_depth > 0 {
-                                    if prev_coord.is_none(){
-                                        prev_coord = Some(coord);
-                                        prev_depth = Some(cur_depth);
-                                    } 
-                                    else if prev_depth.unwrap() != cur_depth {
-                                        
-                                            bed_gz_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord.unwrap(), coord, prev_depth.unwrap());
-                                            prev_depth = Some(cur_depth);
-                                            prev_coord = Some(coord);
-                                        
-                                    }
-                                // }
-                            }
-                        }
+/// Whether records are sorted in memory before depth is calculated. Without
+/// index depth can be calculated only for coordinate sorted records, so
+/// records of files not known to be sorted are sorted.
+pub fn sorts_in_memory(file_meta: &FileMeta, index_file: &Option<Arc<Vec<u32>>>) -> bool {
+    index_file.is_none() && file_meta.sort_order() != SortOrder::Coordinate
+}
 
-                        bed_gz_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord.unwrap() , en , prev_depth.unwrap());                        
-                    }
+/// Calculates per base depth of reference sequences of one file. Records of
+/// the file are held in memory.
+pub struct DepthCalculator {
+    records: Arc<PreparsedRecords>,
+    index_file: Option<Arc<Vec<u32>>>,
+    file_meta: Arc<FileMeta>,
+    ref_name_to_id: HashMap<String, i32>,
+}
+
+impl DepthCalculator {
+    pub fn new(gbam_file: File, index_file: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
+        let reader = Reader::new(gbam_file.try_clone()?, ParsingTemplate::new())?;
+        let file_meta = reader.file_meta.clone();
+        let records = preparse_records(&gbam_file, &file_meta, 0..reader.amount, &index_file, &DepthOptions::default());
+        let ref_name_to_id = file_meta
+            .get_ref_seqs()
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (name.clone(), id as i32))
+            .collect();
+        Ok(Self {
+            records: Arc::new(records),
+            index_file,
+            file_meta,
+            ref_name_to_id,
+        })
+    }
+
+    pub fn file_meta(&self) -> &FileMeta {
+        &self.file_meta
+    }
+
+    /// Depth of every base of reference sequence with default
+    /// [`DepthOptions`]. `buf` is reused for the result, which is `ref_len +
+    /// 1` long. None if the file has no such reference sequence.
+    pub fn depth(&self, ref_name: &str, buf: Vec<i32>) -> Option<Vec<i32>> {
+        let ref_id = *self.ref_name_to_id.get(ref_name)?;
+        let ref_len = self.file_meta.get_ref_seqs()[ref_id as usize].1 as usize;
+        Some(calc_depth(
+            self.records.clone(),
+            self.index_file.clone(),
+            ref_id,
+            buf,
+            ref_len,
+            DepthOptions::default(),
+            None,
+        ))
+    }
+
+    /// Aligned lengths of reads overlapping each `window` bases long window
+    /// of reference sequence, in the same sweep over records as depth.
+    /// Unmapped, secondary, QC failed and duplicate reads are skipped. `buf`
+    /// is reused for the result. None if the file has no such reference
+    /// sequence.
+    pub fn window_read_lengths(&self, ref_name: &str, window: u32, mut buf: Vec<Vec<u32>>) -> Option<Vec<Vec<u32>>> {
+        assert!(window > 0, "Window has to be positive.");
+        let ref_id = *self.ref_name_to_id.get(ref_name)?;
+        let ref_len = self.file_meta.get_ref_seqs()[ref_id as usize].1 as usize;
+        let window = window as usize;
+        buf.iter_mut().for_each(|lengths| lengths.clear());
+        buf.resize_with(ref_len.div_ceil(window), Vec::new);
+
+        let first_rec = first_ref_record(&self.records, &self.index_file, ref_id);
+        for idx in first_rec..self.records.len() {
+            let rec = self.records[record_idx(&self.index_file, idx)];
+            if rec.refid != ref_id {
+                break;
+            }
+            if rec.cigar == 0 || (rec.flag & 0b11100000100) != 0 {
+                continue;
+            }
+            let start = rec.pos as usize;
+            let end = min(start + rec.cigar as usize, ref_len);
+            for lengths in buf.iter_mut().take(end.div_ceil(window)).skip(start / window) {
+                lengths.push(rec.cigar);
+            }
+        }
+        Some(buf)
+    }
+}
+
+/// How [`main_depth`] writes depth.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DepthOutput {
+    /// `chr pos depth` of every covered base.
+    PerBase,
+    /// BED regions of equal depth.
+    BedGraph,
+    /// BED4 of mean depth in windows of given size, as `mosdepth --by N`.
+    /// Windows tile each queried region from its start.
+    Windows(u32),
+    /// Regions of equal depth as BigWig.
+    BigWig,
+    /// Depth of every base as D4.
+    D4,
+    /// BED4 of regions labeled by coverage band they fall into.
+    Quantized(Quantize),
+    /// One line per queried region with mean, min, max and median depth
+    /// over it, see [`RegionSummary`]. If `thresholds` are given, breadth of
+    /// coverage and number of bases with depth of at least each threshold
+    /// follow.
+    Regions { thresholds: Vec<u32> },
+}
+
+/// Depth statistics of one region.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegionSummary {
+    pub mean: f64,
+    pub min: i32,
+    pub max: i32,
+    /// Mean of the two middle values for even length.
+    pub median: f64,
+    /// Fraction of bases with depth of at least 1.
+    pub breadth: f64,
+    /// Number of bases with depth of at least each of the thresholds.
+    pub threshold_bases: Vec<u32>,
+}
+
+impl RegionSummary {
+    /// Summary of `region` (clipped to `ref_len`) of per base depth
+    /// `coverage`. All zero for empty region. `buf` is reused for median.
+    fn new(coverage: &[i32], region: (u32, u32), ref_len: u32, thresholds: &[u32], buf: &mut Vec<i32>) -> Self {
+        let end = min(region.1, ref_len) as usize;
+        let start = min(region.0 as usize, end);
+        let depths = &coverage[start..end];
+        let mut summary = Self { threshold_bases: vec![0; thresholds.len()], ..Self::default() };
+        if depths.is_empty() {
+            return summary;
+        }
+        let (mut sum, mut covered) = (0i64, 0u32);
+        summary.min = i32::MAX;
+        for &depth in depths {
+            sum += i64::from(depth);
+            summary.min = summary.min.min(depth);
+            summary.max = summary.max.max(depth);
+            if depth > 0 {
+                covered += 1;
+            }
+            for (bases, &threshold) in summary.threshold_bases.iter_mut().zip(thresholds) {
+                if i64::from(depth) >= i64::from(threshold) {
+                    *bases += 1;
                 }
-                accum += now.elapsed().as_millis();
-                coverage_arr.clear();
             }
-            
-            buffers.push(coverage_arr);
         }
+        summary.mean = sum as f64 / depths.len() as f64;
+        summary.breadth = f64::from(covered) / depths.len() as f64;
 
-        let next_chr = iter.next(); 
-        
-        if let Some((chr, ref_len)) = next_chr {
-            if circular_buf_channels[idx].is_none() {
-                let (s, r) = bounded(1);
-                let (ready_s, ready_r) = bounded(1);
-                let handle = thread::spawn(move || {
-                    for task  in r {
-                        let (preparsed, meta, index_file, number_of_records, ref_id, buf, t_ref_len, t_chr) = task;
-                        ready_s.send((t_chr, calc_depth(preparsed, meta, index_file, number_of_records, ref_id, buf, t_ref_len))).unwrap();
-                    } 
-                });
-                circular_buf_channels[idx] = Some((s, ready_r));
-                handles.push(handle);
+        buf.clear();
+        buf.extend_from_slice(depths);
+        let mid = buf.len() / 2;
+        let (lower, &mut upper, _) = buf.select_nth_unstable(mid);
+        summary.median = if depths.len().is_multiple_of(2) {
+            (f64::from(*lower.iter().max().unwrap()) + f64::from(upper)) / 2.0
+        } else {
+            f64::from(upper)
+        };
+        summary
+    }
+}
+
+/// Windows of `window` bases tiling `region` (clipped to `ref_len`) with mean
+/// depth over each. `coverage` is per base depth.
+fn window_means(coverage: &[i32], region: (u32, u32), ref_len: u32, window: u32) -> impl Iterator<Item = (u32, u32, f64)> + '_ {
+    let end = min(region.1, ref_len);
+    (region.0..end).step_by(window as usize).map(move |start| {
+        let win_end = min(start.saturating_add(window), end);
+        let sum: i64 = coverage[start as usize..win_end as usize].iter().map(|&depth| i64::from(depth)).sum();
+        (start, win_end, sum as f64 / f64::from(win_end - start))
+    })
+}
+
+/// Depth over a set of bases, a row of [`write_summary_table`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DepthSummary {
+    pub length: u64,
+    /// Sum of depth.
+    pub bases: u64,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl DepthSummary {
+    pub fn add(&mut self, depths: &[i32]) {
+        let mut other = Self { length: depths.len() as u64, bases: 0, min: i32::MAX, max: i32::MIN };
+        for &depth in depths {
+            other.bases += depth as u64;
+            other.min = other.min.min(depth);
+            other.max = other.max.max(depth);
+        }
+        self.merge(&other);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if other.length == 0 {
+            return;
+        }
+        if self.length == 0 {
+            *self = other.clone();
+            return;
+        }
+        self.length += other.length;
+        self.bases += other.bases;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Mean depth, 0 over no bases.
+    pub fn mean(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.bases as f64 / self.length as f64
+    }
+}
+
+/// Writes table as `mosdepth.summary.txt`: `chrom length bases mean min max`
+/// of every reference sequence (with `<chrom>_region` rows over queried
+/// regions if `region_summaries` is given) and `total` (`total_region`) rows.
+pub fn write_summary_table(out: &mut dyn Write, ref_seqs: &[(String, u32)], summaries: &HashMap<String, DepthSummary>, region_summaries: Option<&HashMap<String, DepthSummary>>) -> std::io::Result<()> {
+    fn row(out: &mut dyn Write, name: &str, summary: &DepthSummary) -> std::io::Result<()> {
+        writeln!(out, "{}\t{}\t{}\t{:.2}\t{}\t{}", name, summary.length, summary.bases, summary.mean(), summary.min, summary.max)
+    }
+    writeln!(out, "chrom\tlength\tbases\tmean\tmin\tmax")?;
+    let (mut total, mut total_region) = (DepthSummary::default(), DepthSummary::default());
+    let empty = DepthSummary::default();
+    for (chr, _) in ref_seqs {
+        let summary = summaries.get(chr).unwrap_or(&empty);
+        row(out, chr, summary)?;
+        total.merge(summary);
+        if let Some(region_summaries) = region_summaries {
+            if let Some(summary) = region_summaries.get(chr) {
+                row(out, &format!("{}_region", chr), summary)?;
+                total_region.merge(summary);
             }
+        }
+    }
+    row(out, "total", &total)?;
+    if region_summaries.is_some() {
+        row(out, "total_region", &total_region)?;
+    }
+    Ok(())
+}
 
-            let ref_id = chr_to_ref_id.get(chr).unwrap().unwrap();
-            let buf = buffers.pop().unwrap();
-            let meta = file_meta.clone();
-            let file = gbam_file.try_clone().unwrap();
-            let index = index_file.as_ref().map(|f| f.clone());
-            let t_chr = chr.clone();
-            let t_ref_len = *ref_len as usize;
-            circular_buf_channels[idx].as_mut().unwrap().0.send((arc_of_records.clone(), meta, index, number_of_records, ref_id, buf, t_ref_len, t_chr)).unwrap();
+/// Runs `work` on `items` in `pool` and passes results to `consume` in item
+/// order. Each item gets one of `buffers`, given back by `consume` for reuse,
+/// so at most `buffers.len()` items are in flight. Stops at the first error
+/// of `consume`, items in flight are left to finish.
+fn run_ordered<T, B, R, W, C>(pool: &ThreadPool, items: impl IntoIterator<Item = T>, mut buffers: Vec<B>, work: W, mut consume: C) -> std::io::Result<()>
+where
+    T: Send + 'static,
+    B: Send + 'static,
+    R: Send + 'static,
+    W: Fn(T, B) -> R + Send + Sync + 'static,
+    C: FnMut(R) -> std::io::Result<B>,
+{
+    assert!(!buffers.is_empty(), "At least one buffer is needed.");
+    let work = Arc::new(work);
+    let (done_s, done_r) = unbounded();
+    let mut items = items.into_iter().enumerate();
+    let mut done = BTreeMap::new();
+    let (mut submitted, mut consumed) = (0, 0);
+    loop {
+        while !buffers.is_empty() {
+            let (idx, item) = match items.next() {
+                Some(next) => next,
+                None => break,
+            };
+            let (buf, work, done_s) = (buffers.pop().unwrap(), work.clone(), done_s.clone());
+            // Send fails once consuming stopped.
+            pool.spawn(move || {
+                let _ = done_s.send((idx, work(item, buf)));
+            });
+            submitted += 1;
+        }
+        if consumed == submitted {
+            return Ok(());
+        }
+        let (idx, result) = done_r.recv().unwrap();
+        done.insert(idx, result);
+        while let Some(result) = done.remove(&consumed) {
+            buffers.push(consume(result)?);
+            consumed += 1;
         }
+    }
+}
 
-        if buffers.len() == circular_buf_channels.len(){
-            break;
+/// Runs of tiles of a region, see [`TileRuns::join`].
+#[derive(Default)]
+struct TileRuns {
+    // Last run of the previous tile, continued in the next one.
+    held: Option<(u32, u32, i32)>,
+}
+
+impl TileRuns {
+    /// Passes `runs` of a region in one tile to `emit`. The held run is
+    /// extended by the first run if `joined` (region continues from the
+    /// previous tile) and both have the same value. The last run is held if
+    /// `hold` (region continues in the next tile).
+    fn join(&mut self, runs: impl Iterator<Item = (u32, u32, i32)>, joined: bool, hold: bool, mut emit: impl FnMut((u32, u32, i32)) -> std::io::Result<()>) -> std::io::Result<()> {
+        let mut prev = self.held.take();
+        if !joined {
+            if let Some(held) = prev.take() {
+                emit(held)?;
+            }
+        }
+        for run in runs {
+            match prev {
+                Some(held) if held.1 == run.0 && held.2 == run.2 => prev = Some((held.0, run.1, held.2)),
+                Some(held) => {
+                    emit(held)?;
+                    prev = Some(run);
+                }
+                None => prev = Some(run),
+            }
+        }
+        if hold {
+            self.held = prev;
+        } else if let Some(run) = prev {
+            emit(run)?;
         }
+        Ok(())
+    }
+}
 
-        idx += 1;
+/// Writes depth to `output` as given by `mode`. Only reads passing `options`
+/// are counted. With `tile_size` reference sequences are processed in tiles
+/// of that many bases (only those overlapping queried regions), so memory of
+/// coverage buffers does not grow with sequence length. Window and region
+/// summary outputs need whole sequences and can't be tiled. If `summary` is
+/// given, a table of depth per reference sequence is written to it, see
+/// [`write_summary_table`].
+#[allow(clippy::too_many_arguments)]
+pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Option<Arc<Vec<u32>>>, bed_cli_request: Option<String>, options: DepthOptions, output: Box<dyn OutputSink>, summary: Option<Box<dyn OutputSink>>, mode: DepthOutput, tile_size: Option<u32>, thread_num: Option<usize>) -> Result<()> {
+    let invalid = |msg: &str| Err(GbamError::InvalidArgument(msg.to_owned()));
+    if matches!(mode, DepthOutput::Windows(0)) {
+        return invalid("Window has to be positive.");
+    }
+    if summary.is_some() && matches!(mode, DepthOutput::Quantized(_)) {
+        return invalid("Depth summary can't be written with quantized output.");
+    }
+    if tile_size == Some(0) {
+        return invalid("Tile size has to be positive.");
+    }
+    if tile_size.is_some() && matches!(mode, DepthOutput::Windows(_) | DepthOutput::Regions { .. }) {
+        return invalid("Window and region summary output can't be tiled.");
     }
 
-    // Drop all senders so that threads get closed.
-    circular_buf_channels.clear();
+    let mut reader = Reader::new(gbam_file.try_clone()?, ParsingTemplate::new())?;
+    let file_meta = reader.file_meta.clone();
+    let ref_seqs = file_meta.get_ref_seqs().clone();
+    let chr_to_ref_id = get_chr_name_mapping(ref_seqs.iter().map(|(chr, _)| chr), &mut reader);
+    let number_of_records = reader.amount;
+    drop(reader);
+    let mut queries = parse_queries(bed_file, bed_cli_request.as_deref(), &ref_seqs)?;
 
-    for h in handles {
-        h.join().unwrap();
+    let regions_queried = !queries.is_empty();
+    // Calculate for whole file.
+    if queries.is_empty() {
+        ref_seqs.iter().for_each(|(chr, len)| {queries.insert(chr.clone(), vec![(0, *len)]);});
     }
 
-    dbg!(accum);
+    // Records of a tile are split among all threads, so a long sequence does
+    // not leave the others idle.
+    let threads = thread_num.unwrap_or(1).max(1);
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build().expect("Failed to create thread pool.");
+
+    let tiles: Vec<(String, i32, u32, Range<u32>)> = ref_seqs.iter().flat_map(|(chr, len)| {
+        let ref_id = chr_to_ref_id.get(chr).unwrap().unwrap();
+        match tile_size {
+            None => vec![(chr.clone(), ref_id, *len, 0..*len)],
+            Some(size) => (0..*len)
+                .step_by(size as usize)
+                .map(|start| start..min(start.saturating_add(size), *len))
+                .filter(|tile| summary.is_some() || queries.get(chr).is_some_and(|regions| regions.iter().any(|region| region.0 < tile.end && region.1 > tile.start)))
+                .map(|tile| (chr.clone(), ref_id, *len, tile))
+                .collect(),
+        }
+    }).collect();
+    let mut tile_runs = TileRuns::default();
+    let (mut printer, mut bed_graph_printer) = match mode {
+        DepthOutput::PerBase => (Some(ConsolePrinter::new(output)), None),
+        DepthOutput::BigWig => (None, Some(BedGraphPrinter::new_bigwig(output, &ref_seqs)?)),
+        DepthOutput::D4 => (None, Some(BedGraphPrinter::new_d4(output, &ref_seqs)?)),
+        _ => (None, Some(BedGraphPrinter::new(output))),
+    };
+
+    let preparsed = pool.install(|| preparse_records(&gbam_file, &file_meta, 0..number_of_records, &index_file, &options));
+    let arc_of_records = Arc::new(preparsed);
+
+    let quantize = match &mode {
+        DepthOutput::Quantized(quantize) => Some(quantize.clone()),
+        _ => None,
+    };
+    let work = move |(chr, ref_id, ref_len, tile): (String, i32, u32, Range<u32>), buf| {
+        let coverage = calc_depth_tile(arc_of_records.clone(), index_file.clone(), ref_id, tile.clone(), buf, options, quantize.as_ref(), threads);
+        (chr, ref_len, tile, coverage)
+    };
+    let mut summaries = HashMap::<String, DepthSummary>::new();
+    let mut region_summaries = HashMap::<String, DepthSummary>::new();
+    run_ordered(&pool, tiles, vec![Vec::new(); threads], work, |(thread_chr, ref_len, tile, mut coverage_arr)| {
+        if summary.is_some() {
+            let depths = &coverage_arr[..tile.len()];
+            summaries.entry(thread_chr.clone()).or_default().add(depths);
+            if let Some(regions) = queries.get(&thread_chr).filter(|_| regions_queried) {
+                let chr_summary = region_summaries.entry(thread_chr.clone()).or_default();
+                for region in regions {
+                    let (st, en) = (region.0.max(tile.start), min(region.1, tile.end));
+                    if st < en {
+                        chr_summary.add(&depths[(st - tile.start) as usize..(en - tile.start) as usize]);
+                    }
+                }
+            }
+        }
+        if let Some(bed_regions) = queries.get(&thread_chr) {
+            // coverage_arr.resize(*ref_len as usize, 0);
+            // let ref_id = chr_to_ref_id.get(chr).unwrap().unwrap();
+            // buffers = calc_depth(gbam_file.try_clone().unwrap(), file_meta.clone(), number_of_records, ref_id, &mut coverage_arr, buffers);
+
+            // 41641770854
+            
+            // printer.set_chr(thread_chr.clone());
+            if let Some(printer) = printer.as_mut() {
+                
+                for bed_region in bed_regions {
+                    let st = bed_region.0.max(tile.start);
+                    let en = min(bed_region.1, tile.end);
+                    for coord in st..en {
+                        unsafe {
+                            let depth = *coverage_arr.get_unchecked((coord - tile.start) as usize);
+                            if depth > 0 {
+                                printer.write_efficient(&thread_chr, coord, depth)?;
+                            }
+                        }
+                    }
+                }
+                
+            }
+            else if let &DepthOutput::Windows(window) = &mode {
+                let ref_len = (coverage_arr.len() - 1) as u32;
+                for bed_region in bed_regions {
+                    for (start, end, mean) in window_means(&coverage_arr, *bed_region, ref_len, window) {
+                        bed_graph_printer.as_mut().unwrap().write_mean(&thread_chr, start, end, mean)?;
+                    }
+                }
+            }
+            else if let DepthOutput::Regions { thresholds } = &mode {
+                let ref_len = (coverage_arr.len() - 1) as u32;
+                let mut buf = Vec::new();
+                for &(start, end) in bed_regions {
+                    let summary = RegionSummary::new(&coverage_arr, (start, end), ref_len, thresholds, &mut buf);
+                    bed_graph_printer.as_mut().unwrap().write_summary(&thread_chr, start, end, &summary, !thresholds.is_empty())?;
+                }
+            }
+            else {
+                // Regions of equal depth, or of equal band if quantized.
+                let printer = bed_graph_printer.as_mut().unwrap();
+                for &bed_region in bed_regions {
+                    let st = bed_region.0.max(tile.start);
+                    let en = min(bed_region.1, tile.end);
+                    if st >= en {
+                        continue;
+                    }
+                    let joined = st == tile.start && st > bed_region.0;
+                    let hold = en == tile.end && en < min(bed_region.1, ref_len);
+                    let runs = runs(&coverage_arr, (st - tile.start, en - tile.start), tile.len() as u32)
+                        .map(|(start, end, value)| (start + tile.start, end + tile.start, value));
+                    tile_runs.join(runs, joined, hold, |(start, end, value)| match &mode {
+                        DepthOutput::Quantized(_) if value < 0 => Ok(()),
+                        DepthOutput::Quantized(quantize) => printer.write_label(&thread_chr, start, end, &quantize.label(value)),
+                        _ => printer.write_region(&thread_chr, start, end, value),
+                    })?;
+                }
+            }
+            coverage_arr.clear();
+        }
+        
+        Ok(coverage_arr)
+    })?;
+
+    if let Some(printer) = printer {
+        printer.finish()?;
+    }
+    if let Some(printer) = bed_graph_printer {
+        printer.finish()?;
+    }
+    if let Some(mut out) = summary {
+        write_summary_table(&mut out, &ref_seqs, &summaries, regions_queried.then_some(&region_summaries))?;
+        out.finish()?;
+    }
     // Shouldn't allocate more.
     // assert!(coverage_arr.capacity() == longest_chr as usize);
+    Ok(())
+}
+
+/// Regions of BED file and of `query` (BED lines) by reference sequence.
+/// Regions of sequences not in `ref_seqs` are rejected.
+fn parse_queries(bed_file: Option<&PathBuf>, query: Option<&str>, ref_seqs: &[(String, u32)]) -> Result<HashMap<String, Vec<(u32, u32)>>> {
+    let mut queries = HashMap::<String, Vec<(u32, u32)>>::new();
+    if let Some(path) = bed_file {
+        queries = bed::parse_bed_from_file(path).map_err(|source| GbamError::InvalidBed { path: path.clone(), source })?;
+    }
+    if let Some(query) = query {
+        let regions = bed::parse_bed(&mut query.as_bytes()).map_err(|_| GbamError::InvalidQuery(query.to_owned()))?;
+        for (chr, mut chr_regions) in regions {
+            queries.entry(chr).or_default().append(&mut chr_regions);
+        }
+    }
+    match queries.keys().find(|chr| !ref_seqs.iter().any(|(name, _)| name == *chr)) {
+        Some(chr) => Err(GbamError::UnknownReference(chr.clone())),
+        None => Ok(queries),
+    }
 }
 
 fn get_chr_name_mapping<'a, I>(ref_ids: I, reader: &mut Reader) -> HashMap<String, Option<i32>>
@@ -332,54 +1038,24 @@ where
 // chrM    15276   281
 // Approach as in https://github.com/brentp/mosdepth
 
-fn find_leftmost_block(id: i32, block_metas: &Vec<BlockMeta>) -> Option<i64> {
-    let mut left: i64 = -1;
-    let mut right: i64 = block_metas.len() as i64;
-    while (right-left) > 1 {
-        let mid = (left + right) / 2;
-        let max_val = &block_metas[mid as usize].stats.as_ref().unwrap().max_value;
-        match max_val.cmp(&id) {
-            Ordering::Equal | Ordering::Greater => right = mid,
-            Ordering::Less => left = mid,
-        }
-    }
-    if right as usize == block_metas.len() || block_metas[right as usize].stats.as_ref().unwrap().min_value > id {
-        return None;
-    }
-    Some(right)
-}
-
-fn find_rightmost_block(id: i32, block_metas: &Vec<BlockMeta>) -> i64 {
-    let mut left: i64 = -1;
-    let mut right: i64 = block_metas.len() as i64;
-    while (right-left) > 1 {
-        let mid = (left + right) / 2;
-        let min_val = &block_metas[mid as usize].stats.as_ref().unwrap().min_value;
-        match min_val.cmp(&id) {
-            Ordering::Equal | Ordering::Less => left = mid,
-            Ordering::Greater => right = mid,
-        }
-    }
-    right
-}
-
-
-struct ConsolePrinter<'a> {
+struct ConsolePrinter {
     buffer: [u8; 400],
-    stdout: BufWriter<StdoutLock<'a>>
- 
+    out: Box<dyn OutputSink>,
 }
-impl<'a> ConsolePrinter<'a> {
-    pub fn new(stdout_lock: StdoutLock<'a>) -> Self {
-        let stdout = BufWriter::with_capacity(64 * 1024, stdout_lock);
+impl ConsolePrinter {
+    pub fn new(out: Box<dyn OutputSink>) -> Self {
         Self {  
             buffer: [0;400],
-            stdout,
+            out,
         }
     }
 
+    pub fn finish(self) -> std::io::Result<()> {
+        self.out.finish()
+    }
+
     /// Done in reversed direction because we don't know what is the size of integers beforehand.
-    pub fn write_efficient(&mut self, reversed_chr: &str,  coord: u32,  depth: i32){
+    pub fn write_efficient(&mut self, reversed_chr: &str,  coord: u32,  depth: i32) -> std::io::Result<()> {
         let mut buff_ptr = self.buffer.as_mut_ptr();
         let orig: *mut u8 = self.buffer.as_mut_ptr();
         unsafe {
@@ -395,7 +1071,7 @@ impl<'a> ConsolePrinter<'a> {
             buff_ptr = i32toa_countlut(depth, buff_ptr);
             *buff_ptr = b'\n';
             buff_ptr = buff_ptr.add(1);
-            self.stdout.write_all(&self.buffer[..(buff_ptr as usize - orig as usize)]).unwrap();
+            self.out.write_all(&self.buffer[..(buff_ptr as usize - orig as usize)])
         }
     }
 }
@@ -412,22 +1088,57 @@ impl<'a> ConsolePrinter<'a> {
 // chr1    18816   18843   1
 // chr1    18843   19754   0
 // chr1    19754   19781   1
-struct BedGzPrinter{
+struct BedGraphPrinter{
     buffer: [u8; 400],
-    compressor: GzEncoder<BufWriter<File>>,
-
+    out: Box<dyn OutputSink>,
+    // Regions go here instead of `out` until finish.
+    bigwig: Option<BigWigWriter>,
+    d4: Option<D4Writer>,
 }
-impl BedGzPrinter {
-    pub fn new(path: PathBuf) -> Self {
-        let  file = File::create(path).expect("Failed to create depth file.");
+impl BedGraphPrinter {
+    pub fn new(out: Box<dyn OutputSink>) -> Self {
         Self {  
             buffer: [0;400],
-            compressor: GzEncoder::new(BufWriter::with_capacity(64 * 1024, file), Compression::default()),
+            out,
+            bigwig: None,
+            d4: None,
         }
     }
 
+    /// Regions are written as BigWig.
+    pub fn new_bigwig(out: Box<dyn OutputSink>, ref_seqs: &[(String, u32)]) -> std::io::Result<Self> {
+        Ok(Self {
+            bigwig: Some(BigWigWriter::new(ref_seqs)?),
+            ..Self::new(out)
+        })
+    }
+
+    /// Regions are written as D4.
+    pub fn new_d4(out: Box<dyn OutputSink>, ref_seqs: &[(String, u32)]) -> std::io::Result<Self> {
+        Ok(Self {
+            d4: Some(D4Writer::new(ref_seqs)?),
+            ..Self::new(out)
+        })
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(bigwig) = self.bigwig.take() {
+            bigwig.finish(&mut self.out)?;
+        }
+        if let Some(d4) = self.d4.take() {
+            d4.finish(&mut self.out)?;
+        }
+        self.out.finish()
+    }
+
     /// Done in reversed direction because we don't know what is the size of integers beforehand.
-    pub fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32){
+    pub fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32) -> std::io::Result<()> {
+        if let Some(bigwig) = self.bigwig.as_mut() {
+            return bigwig.write_region(chr, prev_coord, coord, prev_depth as f32);
+        }
+        if let Some(d4) = self.d4.as_mut() {
+            return d4.write_region(chr, prev_coord, coord, prev_depth);
+        }
         let mut buff_ptr = self.buffer.as_mut_ptr();
         let orig: *mut u8 = self.buffer.as_mut_ptr();
         unsafe {
@@ -446,7 +1157,286 @@ impl BedGzPrinter {
             buff_ptr = i32toa_countlut(prev_depth, buff_ptr);
             *buff_ptr = b'\n';
             buff_ptr = buff_ptr.add(1);
-            self.compressor.write_all(&self.buffer[..(buff_ptr as usize - orig as usize)]).unwrap();
+            self.out.write_all(&self.buffer[..(buff_ptr as usize - orig as usize)])
         }
     }
-}
\ No newline at end of file
+
+    /// BED4 line of region with `label`.
+    pub fn write_label(&mut self, chr: &str, start: u32, end: u32, label: &str) -> std::io::Result<()> {
+        writeln!(self.out, "{}\t{}\t{}\t{}", chr, start, end, label)
+    }
+
+    /// BED4 line of mean depth of region.
+    pub fn write_mean(&mut self, chr: &str, start: u32, end: u32, mean: f64) -> std::io::Result<()> {
+        writeln!(self.out, "{}\t{}\t{}\t{:.2}", chr, start, end, mean)
+    }
+
+    /// Region followed by its mean, min, max and median depth and, if
+    /// `thresholds` is set, breadth and bases at thresholds.
+    pub fn write_summary(&mut self, chr: &str, start: u32, end: u32, summary: &RegionSummary, thresholds: bool) -> std::io::Result<()> {
+        write!(
+            self.out,
+            "{}\t{}\t{}\t{:.2}\t{}\t{}\t{:.1}",
+            chr, start, end, summary.mean, summary.min, summary.max, summary.median
+        )?;
+        if thresholds {
+            write!(self.out, "\t{:.4}", summary.breadth)?;
+            for bases in &summary.threshold_bases {
+                write!(self.out, "\t{}", bases)?;
+            }
+        }
+        writeln!(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_sum() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(7);
+        for len in (0..20).chain([1000, 1001, 1002, 1003]) {
+            let values: Vec<i32> = (0..len).map(|_| rng.gen_range(-5..=5)).collect();
+            let (mut simd, mut scalar) = (values.clone(), values);
+            prefix_sum(&mut simd);
+            prefix_sum_scalar(&mut scalar);
+            assert_eq!(simd, scalar, "{}", len);
+        }
+        let mut values = vec![1, 1, -1, 0, 2, -3, 1, 0, 0];
+        prefix_sum(&mut values);
+        assert_eq!(values, [1, 2, 1, 1, 3, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_min_mapq() {
+        let unit = |pos, mapq| DepthUnit { refid: 0, pos, cigar: 3, flag: 0, mapq, segments: NO_SEGMENTS };
+        let records = Arc::new(PreparsedRecords { units: vec![unit(0, 60), unit(1, 10), unit(2, 30)], segments: Vec::new(), max_span: 3 });
+        let depth = |min_mapq| {
+            let options = DepthOptions { min_mapq, ..DepthOptions::default() };
+            let mut scan_line = process_range(records.clone(), None, 0..3, vec![0; 6], 0, 0..5, options);
+            let mut acc = 0;
+            scan_line.iter_mut().for_each(|slot| {
+                acc += *slot;
+                *slot = acc;
+            });
+            scan_line
+        };
+        assert_eq!(depth(0), vec![1, 2, 3, 2, 1, 0]);
+        assert_eq!(depth(30), vec![1, 1, 2, 1, 1, 0]);
+    }
+
+    #[test]
+    fn test_flag_filter() {
+        let unit = |pos, flag| DepthUnit { refid: 0, pos, cigar: 2, flag, mapq: 60, segments: NO_SEGMENTS };
+        // Paired, duplicate, secondary.
+        let records = Arc::new(PreparsedRecords { units: vec![unit(0, 0x1), unit(1, 0x1 | 0x400), unit(2, 0x100)], segments: Vec::new(), max_span: 2 });
+        let depth = |options| {
+            let scan_line = process_range(records.clone(), None, 0..3, vec![0; 5], 0, 0..4, options);
+            scan_line.iter().scan(0, |acc, slot| { *acc += slot; Some(*acc) }).collect::<Vec<_>>()
+        };
+        assert_eq!(depth(DepthOptions::default()), vec![1, 2, 2, 1, 0]);
+        let options = DepthOptions { exclude_flags: DEFAULT_EXCLUDE_FLAGS, ..DepthOptions::default() };
+        assert_eq!(depth(options), vec![1, 1, 0, 0, 0]);
+        let options = DepthOptions { include_flags: 0x1, exclude_flags: 0x100, ..DepthOptions::default() };
+        assert_eq!(depth(options), vec![1, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_cigar_segments() {
+        // 2M 1I 2D 3N 1M 1S
+        let ops: Vec<Op> = [(2, 0), (1, 1), (2, 2), (3, 3), (1, 0), (1, 4)].iter().map(|&(len, op)| Op(len << 4 | op)).collect();
+        let mut segments = Vec::new();
+        DepthOptions::default().cigar_segments(&ops, &[], &mut segments);
+        assert_eq!(segments, vec![(0, 8)]);
+        let options = DepthOptions { skip_ref_skips: true, ..DepthOptions::default() };
+        options.cigar_segments(&ops, &[], &mut segments);
+        assert_eq!(segments, vec![(0, 4), (7, 1)]);
+        let quals = [30, 10, 20, 30, 30];
+        let quality = DepthOptions { min_base_quality: 20, ..options };
+        quality.cigar_segments(&ops, &quals, &mut segments);
+        // Inserted base does not shift reference offsets.
+        assert_eq!(segments, vec![(0, 1), (2, 2), (7, 1)]);
+        let options = DepthOptions { skip_deletions: true, skip_ref_skips: true, ..DepthOptions::default() };
+        options.cigar_segments(&ops, &[], &mut segments);
+        assert_eq!(segments, vec![(0, 2), (7, 1)]);
+
+        let unit = DepthUnit { refid: 0, pos: 1, cigar: 8, flag: 0, mapq: 60, segments: 0 };
+        let records = Arc::new(PreparsedRecords { units: vec![unit], segments: vec![(2, 0), (0, 2), (7, 1)], max_span: 8 });
+        let scan_line = process_range(records, None, 0..1, vec![0; 10], 0, 0..9, options);
+        let depth: Vec<i32> = scan_line.iter().scan(0, |acc, slot| { *acc += slot; Some(*acc) }).collect();
+        assert_eq!(depth, vec![0, 1, 1, 0, 0, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_mate_overlap() {
+        let options = DepthOptions { mate_overlap: true, ..DepthOptions::default() };
+        let mate = |flag, pos, next_pos, tlen| GbamRecord {
+            refid: Some(0),
+            next_ref_id: Some(0),
+            flag: Some(flag),
+            pos: Some(pos),
+            next_pos: Some(next_pos),
+            tlen: Some(tlen),
+            ..GbamRecord::default()
+        };
+        // 100 bp mates at 0 and 60.
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x40, 0, 60, 160), 100), Some(60..100));
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x80, 60, 0, -160), 100), None);
+        // Mate contained in the read.
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x80, 0, 10, 50), 100), Some(10..50));
+        // Same start, only the first in pair gives the overlap up.
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x40, 0, 0, 100), 100), Some(0..100));
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x80, 0, 0, 100), 100), None);
+        // Not overlapping, mate unmapped.
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x40, 0, 150, 250), 100), None);
+        assert_eq!(options.mate_overlap(&mate(0x1 | 0x8, 0, 0, 0), 100), None);
+        assert_eq!(DepthOptions::default().mate_overlap(&mate(0x1 | 0x40, 0, 60, 160), 100), None);
+
+        let mut segments = vec![(0, 30), (40, 60)];
+        remove_range(&mut segments, 20..50);
+        assert_eq!(segments, vec![(0, 20), (50, 50)]);
+        remove_range(&mut segments, 0..100);
+        assert!(segments.is_empty());
+    }
+
+    #[test]
+    fn test_tiles() {
+        let unit = |pos, cigar| DepthUnit { refid: 0, pos, cigar, flag: 0, mapq: 60, segments: NO_SEGMENTS };
+        let records = Arc::new(PreparsedRecords { units: vec![unit(0, 9), unit(2, 2), unit(3, 5), unit(8, 2)], segments: Vec::new(), max_span: 9 });
+        let whole = calc_depth_tile(records.clone(), None, 0, 0..10, Vec::new(), DepthOptions::default(), None, 1);
+        assert_eq!(whole, vec![1, 1, 2, 3, 2, 2, 2, 2, 2, 1, 0]);
+        for tile in [0..4, 4..8, 8..10, 3..4] {
+            let depth = calc_depth_tile(records.clone(), None, 0, tile.clone(), vec![7; 20], DepthOptions::default(), None, 1);
+            assert_eq!(depth[..tile.len()], whole[tile.start as usize..tile.end as usize]);
+        }
+        for shard_len in 1..4 {
+            let scan_line = sharded_scan_line(records.clone(), None, 0..4, vec![0; 11], 0, 0..10, DepthOptions::default(), shard_len);
+            let depth: Vec<i32> = scan_line.iter().scan(0, |acc, slot| { *acc += slot; Some(*acc) }).collect();
+            assert_eq!(depth, whole);
+        }
+
+        let mut tile_runs = TileRuns::default();
+        let mut emitted = Vec::new();
+        let mut emit = |run| {
+            emitted.push(run);
+            Ok(())
+        };
+        tile_runs.join([(0, 2, 1), (2, 4, 2)].iter().copied(), false, true, &mut emit).unwrap();
+        tile_runs.join([(4, 6, 2), (6, 8, 0)].iter().copied(), true, true, &mut emit).unwrap();
+        // Region starting at tile start is not joined.
+        tile_runs.join([(8, 9, 0)].iter().copied(), false, false, &mut emit).unwrap();
+        assert_eq!(emitted, vec![(0, 2, 1), (2, 6, 2), (6, 8, 0), (8, 9, 0)]);
+    }
+
+    #[test]
+    fn test_run_ordered() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let threads = 3;
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (running_w, max_running_w) = (running.clone(), max_running.clone());
+        let work = move |item: u64, buf: Vec<u64>| {
+            let now = running_w.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running_w.fetch_max(now, Ordering::SeqCst);
+            assert_eq!(rayon::current_num_threads(), threads);
+            // Later items finish first.
+            std::thread::sleep(std::time::Duration::from_millis(20 - item));
+            running_w.fetch_sub(1, Ordering::SeqCst);
+            (item, buf)
+        };
+        let mut consumed = Vec::new();
+        run_ordered(&pool, 0..20, vec![Vec::new(); 8], work, |(item, buf)| {
+            consumed.push(item);
+            Ok(buf)
+        })
+        .unwrap();
+        assert_eq!(consumed, (0..20).collect::<Vec<_>>());
+        assert!(max_running.load(Ordering::SeqCst) <= threads);
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_summary_table() {
+        let mut chr1 = DepthSummary::default();
+        chr1.add(&[0, 2, 4]);
+        chr1.add(&[]);
+        chr1.add(&[6]);
+        assert_eq!(chr1, DepthSummary { length: 4, bases: 12, min: 0, max: 6 });
+        let mut region = DepthSummary::default();
+        region.add(&[2, 4]);
+        let summaries: HashMap<String, DepthSummary> = vec![("chr1".to_owned(), chr1)].into_iter().collect();
+        let regions: HashMap<String, DepthSummary> = vec![("chr1".to_owned(), region)].into_iter().collect();
+        let ref_seqs = vec![("chr1".to_owned(), 4), ("chr2".to_owned(), 0)];
+        let mut out = Vec::new();
+        write_summary_table(&mut out, &ref_seqs, &summaries, Some(&regions)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "chrom\tlength\tbases\tmean\tmin\tmax\n\
+chr1\t4\t12\t3.00\t0\t6\n\
+chr1_region\t2\t6\t3.00\t2\t4\n\
+chr2\t0\t0\t0.00\t0\t0\n\
+total\t4\t12\t3.00\t0\t6\n\
+total_region\t2\t6\t3.00\t2\t4\n"
+        );
+    }
+
+    #[test]
+    fn test_quantize() {
+        let quantize = Quantize::parse("0:1:5:30:").unwrap();
+        let bands: Vec<_> = [0, 1, 4, 5, 29, 30, 1000].iter().map(|&depth| quantize.band(depth)).collect();
+        assert_eq!(bands, vec![0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(quantize.label(1), "1:5");
+        assert_eq!(quantize.label(3), "30:inf");
+        let closed = Quantize::parse("1:5").unwrap();
+        assert_eq!((closed.band(0), closed.band(1), closed.band(5)), (-1, 0, -1));
+        assert!(Quantize::parse("5:1:").is_err());
+        assert!(Quantize::parse("1").is_err());
+        assert!(Quantize::parse("a:").is_err());
+
+        let bands = [0, 0, 1, 1, 1, -1, 0];
+        let found: Vec<_> = runs(&bands, (1, 100), 6).collect();
+        assert_eq!(found, vec![(1, 2, 0), (2, 5, 1), (5, 6, -1)]);
+    }
+
+    #[test]
+    fn test_window_means() {
+        let coverage = [1, 2, 3, 4, 0, 0, 5, 0];
+        let windows: Vec<_> = window_means(&coverage, (0, 100), 7, 3).collect();
+        assert_eq!(windows, vec![(0, 3, 2.0), (3, 6, 4.0 / 3.0), (6, 7, 5.0)]);
+        let windows: Vec<_> = window_means(&coverage, (2, 5), 7, 2).collect();
+        assert_eq!(windows, vec![(2, 4, 3.5), (4, 5, 0.0)]);
+    }
+
+    #[test]
+    fn test_parse_queries() {
+        let ref_seqs = vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 2000)];
+        let queries = parse_queries(None, Some("chr1 10 20\nchr1 30 40\nchr2 0 5"), &ref_seqs).unwrap();
+        assert_eq!(queries["chr1"], vec![(10, 20), (30, 40)]);
+        assert!(matches!(parse_queries(None, Some("chr3 10 20"), &ref_seqs), Err(GbamError::UnknownReference(chr)) if chr == "chr3"));
+        let error = parse_queries(None, Some("chr1:10-20"), &ref_seqs).unwrap_err();
+        assert!(matches!(error, GbamError::InvalidQuery(_)));
+        assert_eq!(error.exit_code(), 2);
+        let missing = PathBuf::from("/nonexistent/regions.bed");
+        assert!(matches!(parse_queries(Some(&missing), None, &ref_seqs), Err(GbamError::InvalidBed { .. })));
+    }
+
+    #[test]
+    fn test_region_summary() {
+        let coverage = [4, 2, 0, 7, 3, 3, 0];
+        let mut buf = Vec::new();
+        let summary = RegionSummary::new(&coverage, (0, 5), 6, &[1, 3, 5], &mut buf);
+        assert_eq!((summary.mean, summary.min, summary.max, summary.median), (16.0 / 5.0, 0, 7, 3.0));
+        assert_eq!(summary.breadth, 0.8);
+        assert_eq!(summary.threshold_bases, vec![4, 3, 1]);
+        // Clipped to reference end.
+        let summary = RegionSummary::new(&coverage, (2, 10), 6, &[], &mut buf);
+        assert_eq!((summary.mean, summary.min, summary.max, summary.median), (13.0 / 4.0, 0, 7, 3.0));
+        let summary = RegionSummary::new(&coverage, (0, 2), 6, &[], &mut buf);
+        assert_eq!(summary.median, 3.0);
+        let summary = RegionSummary::new(&coverage, (8, 10), 6, &[10], &mut buf);
+        assert_eq!(summary, RegionSummary { threshold_bases: vec![0], ..RegionSummary::default() });
+    }
+}
@@ -0,0 +1,202 @@
+use super::depth::{calc_depth, preparse_records, DepthOptions};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"GDC\x01";
+
+/// Per base depth of the first `records` records of GBAM file, kept as runs
+/// of equal depth. When records are appended to the file, the cache is
+/// brought up to date by [`DepthCache::update`] which only reads the new
+/// records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepthCache {
+    /// Number of records the depth was calculated for.
+    pub records: u64,
+    pub ref_seqs: Vec<(String, u32)>,
+    // Per reference sequence: (end, depth) of consecutive runs starting at 0.
+    runs: Vec<Vec<(u32, i32)>>,
+}
+
+impl DepthCache {
+    /// Depth of no records.
+    pub fn new(ref_seqs: Vec<(String, u32)>) -> Self {
+        let runs = ref_seqs
+            .iter()
+            .map(|(_, len)| if *len == 0 { Vec::new() } else { vec![(*len, 0)] })
+            .collect();
+        Self {
+            records: 0,
+            ref_seqs,
+            runs,
+        }
+    }
+
+    /// Reads the cache from `path` or starts from empty one if there is no such
+    /// file.
+    pub fn load_or_new<P: AsRef<Path>>(path: P, ref_seqs: &[(String, u32)]) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::read_from(BufReader::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new(ref_seqs.to_vec())),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    /// Adds depth of records of `gbam_file` which were not counted yet. The
+    /// counted records must be unchanged and reference sequences must match.
+    pub fn update(&mut self, gbam_file: File) -> io::Result<()> {
+        let reader = Reader::new(gbam_file.try_clone()?, ParsingTemplate::new())?;
+        let file_meta = reader.file_meta.clone();
+        let amount = reader.amount as u64;
+        drop(reader);
+
+        if file_meta.get_ref_seqs() != &self.ref_seqs {
+            return Err(invalid_input("Reference sequences of GBAM file differ from those of depth cache."));
+        }
+        if amount < self.records {
+            return Err(invalid_input("GBAM file has fewer records than depth cache was calculated for."));
+        }
+        if amount == self.records {
+            return Ok(());
+        }
+
+        let segment = self.records as usize..amount as usize;
+        let records = Arc::new(preparse_records(&gbam_file, &file_meta, segment, &None, &DepthOptions::default()));
+        let mut buf = Vec::new();
+        for (ref_id, (_, ref_len)) in self.ref_seqs.iter().enumerate() {
+            let mut depth = calc_depth(
+                records.clone(),
+                None,
+                ref_id as i32,
+                buf,
+                *ref_len as usize,
+                DepthOptions::default(),
+                None,
+            );
+            depth.truncate(*ref_len as usize);
+            let mut start = 0;
+            for &(end, prev_depth) in self.runs[ref_id].iter() {
+                depth[start..end as usize].iter_mut().for_each(|d| *d += prev_depth);
+                start = end as usize;
+            }
+            self.runs[ref_id] = to_runs(&depth);
+            depth.clear();
+            buf = depth;
+        }
+        self.records = amount;
+        Ok(())
+    }
+
+    /// Regions of equal depth as bedGraph.
+    pub fn write_bed_graph(&self, out: &mut dyn Write) -> io::Result<()> {
+        for ((name, _), runs) in self.ref_seqs.iter().zip(self.runs.iter()) {
+            let mut start = 0;
+            for &(end, depth) in runs.iter() {
+                writeln!(out, "{}\t{}\t{}\t{}", name, start, end, depth)?;
+                start = end;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Self> {
+        let mut reader = GzDecoder::new(reader);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a depth cache file."));
+        }
+        let records = reader.read_u64::<LittleEndian>()?;
+        let n_ref = reader.read_u32::<LittleEndian>()?;
+        let mut ref_seqs = Vec::new();
+        let mut runs = Vec::new();
+        for _ in 0..n_ref {
+            let mut name = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            ref_seqs.push((name, reader.read_u32::<LittleEndian>()?));
+            let n_runs = reader.read_u32::<LittleEndian>()?;
+            let mut ref_runs = Vec::with_capacity(n_runs as usize);
+            for _ in 0..n_runs {
+                ref_runs.push((reader.read_u32::<LittleEndian>()?, reader.read_i32::<LittleEndian>()?));
+            }
+            runs.push(ref_runs);
+        }
+        Ok(Self {
+            records,
+            ref_seqs,
+            runs,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = GzEncoder::new(writer, Compression::fast());
+        writer.write_all(MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.records)?;
+        writer.write_u32::<LittleEndian>(self.ref_seqs.len() as u32)?;
+        for ((name, len), runs) in self.ref_seqs.iter().zip(self.runs.iter()) {
+            writer.write_u32::<LittleEndian>(name.len() as u32)?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_u32::<LittleEndian>(*len)?;
+            writer.write_u32::<LittleEndian>(runs.len() as u32)?;
+            for &(end, depth) in runs.iter() {
+                writer.write_u32::<LittleEndian>(end)?;
+                writer.write_i32::<LittleEndian>(depth)?;
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+fn to_runs(depth: &[i32]) -> Vec<(u32, i32)> {
+    let mut runs: Vec<(u32, i32)> = Vec::new();
+    for (pos, &d) in depth.iter().enumerate() {
+        match runs.last_mut() {
+            Some((end, prev)) if *prev == d => *end = pos as u32 + 1,
+            _ => runs.push((pos as u32 + 1, d)),
+        }
+    }
+    runs
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_round_trip() {
+        assert_eq!(to_runs(&[0, 0, 2, 2, 2, 1]), vec![(2, 0), (5, 2), (6, 1)]);
+
+        let mut cache = DepthCache::new(vec![("chr1".to_owned(), 6), ("chr2".to_owned(), 3)]);
+        cache.records = 10;
+        cache.runs[0] = to_runs(&[0, 0, 2, 2, 2, 1]);
+        let mut bytes = Vec::new();
+        cache.write_to(&mut bytes).unwrap();
+        assert_eq!(DepthCache::read_from(&bytes[..]).unwrap(), cache);
+
+        let mut bed_graph = Vec::new();
+        cache.write_bed_graph(&mut bed_graph).unwrap();
+        assert_eq!(
+            String::from_utf8(bed_graph).unwrap(),
+            "chr1\t0\t2\t0\nchr1\t2\t5\t2\nchr1\t5\t6\t1\nchr2\t0\t3\t0\n"
+        );
+    }
+}
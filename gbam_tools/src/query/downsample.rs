@@ -0,0 +1,185 @@
+use crate::query::contig_groups::count_per_ref;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::writer::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Mean depth over all reference sequences: aligned bases (as counted by
+/// [`count_per_ref`]) divided by total reference length.
+pub fn estimate_coverage(file: File) -> io::Result<f64> {
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let genome_len: u64 = reader.file_meta.get_ref_seqs().iter().map(|(_, len)| u64::from(*len)).sum();
+    let (per_ref, _) = count_per_ref(file)?;
+    let aligned_bases: u64 = per_ref.iter().map(|counts| counts.aligned_bases).sum();
+    if genome_len == 0 {
+        return Ok(0.0);
+    }
+    Ok(aligned_bases as f64 / genome_len as f64)
+}
+
+/// Keeps templates by hash of read name, so all records of a template
+/// (mates, secondary and supplementary alignments) are kept or dropped
+/// together, in this file and in other files of the same reads.
+#[derive(Clone, Copy, Debug)]
+pub struct Subsampler {
+    fraction: f64,
+    seed: u32,
+}
+
+impl Subsampler {
+    pub fn new(fraction: f64, seed: u32) -> Self {
+        assert!((0.0..=1.0).contains(&fraction), "Fraction has to be in [0, 1].");
+        Self { fraction, seed }
+    }
+
+    /// `read_name` may include terminating NUL.
+    pub fn keeps(&self, read_name: &[u8]) -> bool {
+        let name = read_name.strip_suffix(&[0]).unwrap_or(read_name);
+        // X31 string hash mixed by Wang integer hash, as in samtools view --subsample.
+        let hash = name.iter().fold(0u32, |h, &c| (h << 5).wrapping_sub(h).wrapping_add(u32::from(c)));
+        let hash = wang_hash(hash ^ self.seed);
+        f64::from(hash & 0xffffff) / f64::from(0x1000000) < self.fraction
+    }
+}
+
+fn wang_hash(mut key: u32) -> u32 {
+    key = key.wrapping_add(!(key << 15));
+    key ^= key >> 10;
+    key = key.wrapping_add(key << 3);
+    key ^= key >> 6;
+    key = key.wrapping_add(!(key << 11));
+    key ^ (key >> 16)
+}
+
+/// Result of [`downsample`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownsampleStats {
+    pub coverage: f64,
+    pub fraction: f64,
+    pub records: u64,
+    pub kept: u64,
+}
+
+/// Downsamples GBAM file to `target_coverage`: estimates coverage (see
+/// [`estimate_coverage`]), then writes templates kept by [`Subsampler`] with
+/// fraction target / estimated coverage to `out_path` (see [`subsample`]).
+/// If the coverage is already below target, all records are written.
+pub fn downsample(in_path: &str, out_path: &str, target_coverage: f64, seed: u32, full_command: String) -> io::Result<DownsampleStats> {
+    let coverage = estimate_coverage(File::open(in_path)?)?;
+    let fraction = if coverage > target_coverage { target_coverage / coverage } else { 1.0 };
+    let (records, kept) = subsample(in_path, out_path, Subsampler::new(fraction, seed), full_command)?;
+    Ok(DownsampleStats { coverage, fraction, records, kept })
+}
+
+/// Writes records of templates kept by `subsampler` to `out_path`, as
+/// samtools view --subsample. Only ReadName is decoded for dropped records.
+/// Sort order, codecs, tag columns and user metadata of input are preserved. Returns
+/// numbers of input and kept records.
+pub fn subsample(in_path: &str, out_path: &str, subsampler: Subsampler, full_command: String) -> io::Result<(u64, u64)> {
+    let file = File::open(in_path)?;
+    let mut all_fields = ParsingTemplate::new();
+    all_fields.set_all();
+    let mut records = Reader::new(file.try_clone()?, all_fields)?;
+    let file_meta = records.file_meta.clone();
+    let mut names = Reader::new_with_meta(file, ParsingTemplate::new_with(&[Fields::ReadName]), &file_meta, None)?;
+    let codecs = file_meta.field_codecs();
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path)?),
+        codecs,
+        8,
+        vec![Fields::RefID],
+        file_meta.get_ref_seqs().clone(),
+        file_meta.get_sam_header().to_vec(),
+        full_command,
+        false,
+    );
+    writer.set_sort_order(file_meta.sort_order());
+    for (key, value) in file_meta.metadata() {
+        writer.set_metadata(key, value.clone());
+    }
+    if let Some(codec) = file_meta.tag_columns().first().map(|_| *file_meta.get_column_codec(&Fields::RawTags, Some(0))) {
+        let tags: Vec<[u8; 2]> = file_meta.tag_columns().iter().map(|col| col.tag()).collect();
+        writer.set_tag_columns(&tags, codec);
+    }
+
+    let mut kept = 0;
+    let mut name = GbamRecord::default();
+    let mut rec = GbamRecord::default();
+    let mut buf = Vec::new();
+    for rec_num in 0..records.amount {
+        names.fill_record(rec_num, &mut name);
+        if !subsampler.keeps(name.read_name.as_ref().unwrap()) {
+            continue;
+        }
+        records.fill_record(rec_num, &mut rec);
+        rec.convert_to_bytes(&mut buf);
+        // Writer expects BAM record without block_size.
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf[4..])));
+        kept += 1;
+    }
+    writer.finish()?;
+    Ok((records.amount as u64, kept))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::Codecs;
+    use crate::test_util::write_sam;
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_subsampler() {
+        let subsampler = Subsampler::new(0.3, 7);
+        assert_eq!(subsampler.keeps(b"read1\0"), subsampler.keeps(b"read1"));
+        let kept = (0..10000).filter(|i| subsampler.keeps(format!("read{}", i).as_bytes())).count();
+        assert!((2700..3300).contains(&kept), "{}", kept);
+        assert!((0..100).all(|i| Subsampler::new(1.0, 7).keeps(format!("read{}", i).as_bytes())));
+        assert!(!(0..100).any(|i| Subsampler::new(0.0, 7).keeps(format!("read{}", i).as_bytes())));
+    }
+
+    #[test]
+    fn test_subsample() {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n");
+        for i in 0..100 {
+            sam += &format!("p{}\t99\tchr1\t{}\t60\t10M\t=\t{}\t60\tACGTACGTAC\t*\tNM:i:0\n", i, i * 10 + 1, i * 10 + 51);
+        }
+        for i in 0..100 {
+            sam += &format!("p{}\t147\tchr1\t{}\t60\t10M\t=\t{}\t-60\tACGTACGTAC\t*\tNM:i:0\n", i, i * 10 + 51, i * 10 + 1);
+        }
+        let dir = TempDir::new("gbam_subsample_test").unwrap();
+        let in_path = dir.path().join("in.gbam");
+        write_sam(&sam, |sam_header, ref_seqs| {
+            let mut writer = Writer::new_no_stats(BufWriter::new(File::create(&in_path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false);
+            writer.set_tag_columns(&[*b"NM"], Codecs::Lz4);
+            writer
+        });
+
+        let out_path = dir.path().join("out.gbam");
+        let subsample_to = |path: &std::path::Path| subsample(in_path.to_str().unwrap(), path.to_str().unwrap(), Subsampler::new(0.5, 3), String::new()).unwrap();
+        let (records, kept) = subsample_to(&out_path);
+        assert_eq!(records, 200);
+        assert!((60..140).contains(&kept), "{}", kept);
+        // Same seed keeps the same records.
+        assert_eq!(subsample_to(&dir.path().join("again.gbam")).1, kept);
+
+        let mut tmpl = ParsingTemplate::new();
+        tmpl.set_all();
+        let mut reader = Reader::new(File::open(&out_path).unwrap(), tmpl).unwrap();
+        assert_eq!(reader.amount as u64, kept);
+        assert_eq!(reader.file_meta.tag_columns().len(), 1);
+        let mut mates: HashMap<Vec<u8>, u32> = HashMap::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            assert_eq!(rec.tags.as_deref(), Some(&b"NMC\0"[..]));
+            *mates.entry(rec.read_name.clone().unwrap()).or_insert(0) += 1;
+        }
+        assert!(mates.values().all(|&n| n == 2));
+    }
+}
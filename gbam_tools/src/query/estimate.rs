@@ -0,0 +1,158 @@
+use super::pairs::{InsertSizeStats, PAIR_FIELDS};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use std::fs::File;
+use std::io::{self, Write};
+
+const UNMAPPED: u16 = 0x4;
+// Secondary, QC fail, supplementary.
+const NOT_PRIMARY: u16 = 0x100 | 0x200 | 0x800;
+// Quality of base when qualities are missing.
+const NO_QUALITY: u8 = 0xFF;
+
+/// Estimates of insert size, error rate and base qualities from a uniform
+/// sample of records (see [`Reader::sample`]).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SampleEstimates {
+    /// Sampled records.
+    pub records: u64,
+    pub insert_size: InsertSizeStats,
+    /// Primary mapped records with NM tag.
+    pub aligned_records: u64,
+    /// Read bases of M, I, = and X operations of those records.
+    pub aligned_bases: u64,
+    /// Sum of NM tags (mismatches and indel bases) of those records.
+    pub edits: u64,
+    /// Bases with quality.
+    pub bases: u64,
+    pub quality_sum: u64,
+    pub q30_bases: u64,
+}
+
+impl SampleEstimates {
+    /// Fields needed by [`SampleEstimates::add`].
+    pub fn fields() -> Vec<Fields> {
+        let mut fields = PAIR_FIELDS.to_vec();
+        fields.extend_from_slice(&[Fields::RawCigar, Fields::RawQual, Fields::RawTags]);
+        fields
+    }
+
+    /// Samples `n` records of GBAM file.
+    pub fn collect(file: File, n: usize, seed: u64) -> io::Result<Self> {
+        let mut reader = Reader::new(file, ParsingTemplate::new_with(&Self::fields()))?;
+        let mut estimates = Self::default();
+        for rec in reader.sample(n, seed).iter() {
+            estimates.add(rec);
+        }
+        estimates.insert_size.update();
+        Ok(estimates)
+    }
+
+    /// Adds record. [`InsertSizeStats::update`] of `insert_size` has to be
+    /// called before insert size statistics are used.
+    pub fn add(&mut self, rec: &GbamRecord) {
+        self.records += 1;
+        self.insert_size.add(rec);
+        let qual = rec.qual.as_deref().unwrap_or(&[]);
+        if qual.first() != Some(&NO_QUALITY) {
+            self.bases += qual.len() as u64;
+            self.quality_sum += qual.iter().map(|&q| u64::from(q)).sum::<u64>();
+            self.q30_bases += qual.iter().filter(|&&q| q >= 30).count() as u64;
+        }
+        if rec.flag.unwrap() & (UNMAPPED | NOT_PRIMARY) != 0 {
+            return;
+        }
+        if let Some(edits) = rec.int_tag(b"NM") {
+            self.aligned_records += 1;
+            self.edits += edits.max(0) as u64;
+            self.aligned_bases += rec
+                .cigar
+                .as_ref()
+                .unwrap()
+                .ops()
+                .filter(|op| matches!(op.op_type(), 'M' | 'I' | '=' | 'X'))
+                .map(|op| u64::from(op.length()))
+                .sum::<u64>();
+        }
+    }
+
+    /// Edits per aligned base.
+    pub fn error_rate(&self) -> f64 {
+        ratio(self.edits, self.aligned_bases)
+    }
+
+    pub fn mean_quality(&self) -> f64 {
+        ratio(self.quality_sum, self.bases)
+    }
+
+    pub fn q30_fraction(&self) -> f64 {
+        ratio(self.q30_bases, self.bases)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+/// Writes estimates as TSV.
+pub fn write_estimates(out: &mut dyn Write, estimates: &SampleEstimates) -> io::Result<()> {
+    writeln!(out, "sampled_records\t{}", estimates.records)?;
+    writeln!(out, "pairs\t{}", estimates.insert_size.pairs)?;
+    writeln!(out, "insert_size_median\t{}", estimates.insert_size.median)?;
+    writeln!(out, "insert_size_mad\t{}", estimates.insert_size.mad)?;
+    writeln!(out, "insert_size_mean\t{:.3}", estimates.insert_size.mean)?;
+    writeln!(out, "insert_size_sd\t{:.3}", estimates.insert_size.sd)?;
+    writeln!(out, "aligned_records\t{}", estimates.aligned_records)?;
+    writeln!(out, "error_rate\t{:.6}", estimates.error_rate())?;
+    writeln!(out, "mean_base_quality\t{:.3}", estimates.mean_quality())?;
+    writeln!(out, "q30_fraction\t{:.6}", estimates.q30_fraction())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_from_sam;
+
+    #[test]
+    fn test_sample() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..500 {
+            sam.push_str(&format!(
+                "r{}\t99\tchr1\t{}\t60\t4M1I5M\t=\t{}\t310\tACGTACGTAC\t5555555555\tNM:i:{}\n",
+                i,
+                i * 10 + 1,
+                i * 10 + 301,
+                i % 2 + 1
+            ));
+        }
+        sam.push_str("u\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*\n");
+
+        let mut reader = gbam_from_sam(&sam, ParsingTemplate::new_with(&SampleEstimates::fields()));
+
+        let sample = reader.sample(50, 7);
+        assert_eq!(sample.len(), 50);
+        let positions: Vec<i32> = sample.iter().map(|rec| rec.pos.unwrap()).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        let again: Vec<i32> = reader.sample(50, 7).iter().map(|rec| rec.pos.unwrap()).collect();
+        assert_eq!(positions, again);
+        assert_eq!(reader.sample(1000, 0).len(), 501);
+
+        let mut estimates = SampleEstimates::default();
+        for rec in sample.iter() {
+            estimates.add(rec);
+        }
+        estimates.insert_size.update();
+        assert_eq!(estimates.insert_size.pairs, 50);
+        assert_eq!(estimates.insert_size.median, 310.0);
+        assert_eq!(estimates.aligned_bases, 500);
+        assert!(estimates.edits >= 50 && estimates.edits <= 100);
+        assert_eq!(estimates.mean_quality(), 20.0);
+        assert_eq!(estimates.q30_fraction(), 0.0);
+    }
+}
@@ -12,7 +12,7 @@ use crate::reader::parse_tmplt::ParsingTemplate;
 // https://github.com/samtools/htslib/blob/32de287eafdafc45dde0a22244b72697294f161d/htslib/sam.h
 bitflags! {
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-        struct BamFlags: u32 {
+        pub(crate) struct BamFlags: u32 {
             /// @abstract the read is paired in sequencing, no matter whether it is mapped in a pair.
             const BAM_FPAIRED =        1;
             /// @abstract the read is mapped in a proper pair.
@@ -40,8 +40,9 @@ bitflags! {
     }
 }
 
-#[derive(Default)]
-struct Stats {
+/// Counts of `samtools flagstat`, each as `[QC-passed, QC-failed]`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlagStats {
     pub n_reads: [i64; 2],
     pub n_mapped: [i64; 2],
     pub n_pair_all: [i64; 2],
@@ -60,12 +61,16 @@ struct Stats {
     pub n_pdup: [i64; 2],
 }
 
-impl Stats {
+/// Columns read by [`collect_stats`].
+pub const FLAGSTAT_FIELDS: [Fields; 4] = [Fields::Flags, Fields::RefID, Fields::NextRefID, Fields::Mapq];
+
+impl FlagStats {
     fn add_two_arrs(dest: &mut[i64;2], src:&[i64;2]){
         dest[0] += src[0];
         dest[1] += src[1];
     }
-    pub fn add(&mut self, other: &Stats){
+    /// Adds counts of `other`.
+    pub fn add(&mut self, other: &FlagStats){
         Self::add_two_arrs(&mut self.n_reads,&  other.n_reads);
         Self::add_two_arrs(&mut self.n_mapped,&  other.n_mapped);
         Self::add_two_arrs(&mut self.n_pair_all,&  other.n_pair_all);
@@ -97,7 +102,8 @@ fn percent(n: i64, total: i64) -> String
 
 }
 
-impl fmt::Display for Stats {
+/// As output of `samtools flagstat`.
+impl fmt::Display for FlagStats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {        
         writeln!(f, "{} + {} in total (QC-passed reads + QC-failed reads)", self.n_reads[0], self.n_reads[1]).unwrap();
         writeln!(f, "{} + {} primary", self.n_primary[0], self.n_primary[1]).unwrap();
@@ -119,7 +125,14 @@ impl fmt::Display for Stats {
     }
 }
 
-fn collect(rec: &GbamRecord, stats: &mut Stats) {
+impl FlagStats {
+    /// Counts record with [`FLAGSTAT_FIELDS`] filled.
+    pub fn add_record(&mut self, rec: &GbamRecord) {
+        collect(rec, self);
+    }
+}
+
+fn collect(rec: &GbamRecord, stats: &mut FlagStats) {
     let record_flag = BamFlags::from_bits(rec.flag.unwrap() as u32).unwrap();
     let w = record_flag.contains(BamFlags::BAM_FQCFAIL) as usize;
     
@@ -173,32 +186,68 @@ fn collect(rec: &GbamRecord, stats: &mut Stats) {
     }
 }
 
-pub fn collect_stats(file: File) {
+/// Counts flags of all records in parallel. Only [`FLAGSTAT_FIELDS`] columns
+/// are decoded.
+pub fn collect_stats(file: File) -> std::io::Result<FlagStats> {
     let tmplt = ParsingTemplate::new();
-    let reader = Reader::new(file.try_clone().unwrap(), tmplt).unwrap();
+    let reader = Reader::new(file.try_clone()?, tmplt)?;
     let total_records = reader.amount;
     let file_meta = reader.file_meta;
     
-    let file_stats = (0..total_records).into_par_iter().chunks(500_000).map(|records_range| {
-        let mut stats = Stats::default();
+    (0..total_records).into_par_iter().chunks(500_000).map(|records_range| {
+        let mut stats = FlagStats::default();
 
         let mut rec =  GbamRecord::default();
-        let mut tmplt = ParsingTemplate::new();
-        tmplt.set(&Fields::Flags, true);
-        tmplt.set(&Fields::RefID, true);
-        tmplt.set(&Fields::NextRefID, true);
-        tmplt.set(&Fields::Mapq, true);
-    
-        let mut reader = Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
+        let tmplt = ParsingTemplate::new_with(&FLAGSTAT_FIELDS);
+        let mut reader = Reader::new_with_meta(file.try_clone()?, tmplt, &file_meta, None)?;
 
         for rec_num in records_range {
             reader.fill_record(rec_num, &mut rec);
             collect(&rec, &mut stats);
         }
 
-        stats
+        Ok(stats)
 
-    }).reduce(Stats::default, |mut a, b| {a.add(&b); a});
+    }).try_reduce(FlagStats::default, |mut a, b| {a.add(&b); Ok(a)})
+}
 
-    println!("{file_stats}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flagstat() {
+        let rec = |flag: u16, next_ref_id, mapq| GbamRecord {
+            flag: Some(flag),
+            refid: Some(0),
+            next_ref_id: Some(next_ref_id),
+            mapq: Some(mapq),
+            ..Default::default()
+        };
+        let mut stats = FlagStats::default();
+        // Proper pair, read1.
+        stats.add_record(&rec(0x1 | 0x2 | 0x40, 0, 60));
+        // Mate on other chromosome, duplicate.
+        stats.add_record(&rec(0x1 | 0x80 | 0x400, 1, 60));
+        // Mate unmapped.
+        stats.add_record(&rec(0x1 | 0x8 | 0x40, 0, 3));
+        stats.add_record(&rec(0x800, -1, 60));
+        stats.add_record(&rec(0x4 | 0x200, -1, 0));
+        let mut total = FlagStats::default();
+        total.add(&stats);
+        total.add(&stats);
+        assert_eq!(total.n_reads, [8, 2]);
+        assert_eq!(stats.n_primary, [3, 1]);
+        assert_eq!(stats.n_supp, [1, 0]);
+        assert_eq!(stats.n_mapped, [4, 0]);
+        assert_eq!(stats.n_pair_all, [3, 0]);
+        assert_eq!(stats.n_pair_good, [1, 0]);
+        assert_eq!(stats.n_pair_map, [2, 0]);
+        assert_eq!(stats.n_sgltn, [1, 0]);
+        assert_eq!((stats.n_diffchr, stats.n_diffhigh), ([1, 0], [1, 0]));
+        assert_eq!((stats.n_dup, stats.n_pdup), ([1, 0], [1, 0]));
+        let text = stats.to_string();
+        assert!(text.starts_with("4 + 1 in total (QC-passed reads + QC-failed reads)\n3 + 1 primary\n"));
+        assert!(text.contains("\n4 + 0 mapped (100.00% : 0.00%)\n"));
+    }
 }
@@ -0,0 +1,134 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, Write};
+
+const UNMAPPED: u16 = 0x4;
+
+/// Record counts as `samtools idxstats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdxStats {
+    /// Mapped and unmapped records placed on every reference sequence (by
+    /// RefID).
+    pub per_ref: Vec<(u64, u64)>,
+    /// Records without reference sequence.
+    pub unplaced: u64,
+}
+
+impl IdxStats {
+    fn new(ref_num: usize) -> Self {
+        Self { per_ref: vec![(0, 0); ref_num], unplaced: 0 }
+    }
+
+    fn add(&mut self, other: &IdxStats) {
+        for (counts, other) in self.per_ref.iter_mut().zip(other.per_ref.iter()) {
+            counts.0 += other.0;
+            counts.1 += other.1;
+        }
+        self.unplaced += other.unplaced;
+    }
+
+    /// Writes `name length mapped unmapped` of every reference sequence and
+    /// `* 0 0 unplaced`.
+    pub fn write(&self, out: &mut dyn Write, ref_seqs: &[(String, u32)]) -> io::Result<()> {
+        for ((name, len), (mapped, unmapped)) in ref_seqs.iter().zip(self.per_ref.iter()) {
+            writeln!(out, "{}\t{}\t{}\t{}", name, len, mapped, unmapped)?;
+        }
+        writeln!(out, "*\t0\t0\t{}", self.unplaced)
+    }
+}
+
+/// Counts records per reference sequence. Only RefID and Flags are decoded:
+/// blocks of unplaced records (by RefID block stats) are counted without
+/// scanning, blocks of a single reference sequence are scanned without
+/// decoding RefID, only boundary blocks need both.
+pub fn idxstats(file: File) -> io::Result<IdxStats> {
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let file_meta = reader.file_meta.clone();
+    let ref_num = file_meta.get_ref_seqs().len();
+
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for block in file_meta.view_blocks(&Fields::RefID) {
+        let single_ref = block
+            .stats
+            .as_ref()
+            .filter(|stat| stat.min_value == stat.max_value)
+            .map(|stat| stat.min_value);
+        blocks.push((start..start + block.numitems as usize, single_ref));
+        start += block.numitems as usize;
+    }
+
+    blocks
+        .into_par_iter()
+        .map(|(range, single_ref)| {
+            let mut stats = IdxStats::new(ref_num);
+            if single_ref == Some(-1) {
+                stats.unplaced = range.len() as u64;
+                return Ok(stats);
+            }
+            let mut fields = vec![Fields::Flags];
+            if single_ref.is_none() {
+                fields.push(Fields::RefID);
+            }
+            let mut reader = Reader::new_with_meta(file.try_clone()?, ParsingTemplate::new_with(&fields), &file_meta, None)?;
+            let mut rec = GbamRecord::default();
+            for rec_num in range {
+                reader.fill_record(rec_num, &mut rec);
+                let ref_id = single_ref.unwrap_or_else(|| rec.refid.unwrap());
+                if ref_id < 0 {
+                    stats.unplaced += 1;
+                } else if rec.flag.unwrap() & UNMAPPED != 0 {
+                    stats.per_ref[ref_id as usize].1 += 1;
+                } else {
+                    stats.per_ref[ref_id as usize].0 += 1;
+                }
+            }
+            Ok(stats)
+        })
+        .try_reduce(
+            || IdxStats::new(ref_num),
+            |mut a, b| {
+                a.add(&b);
+                Ok(a)
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{parse_sam, write_sam};
+    use crate::{Codecs, Writer};
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    // Unmapped u1 is placed at its mate.
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:250\n@SQ\tSN:chr2\tLN:100\n@SQ\tSN:chr3\tLN:50\n\
+r1\t65\tchr1\t1\t60\t10M\t=\t1\t0\t*\t*\n\
+u1\t133\tchr1\t1\t0\t*\t=\t1\t0\t*\t*\n\
+r2\t0\tchr1\t51\t60\t10M\t*\t0\t0\t*\t*\n\
+r3\t0\tchr2\t11\t60\t10M\t*\t0\t0\t*\t*\n\
+u2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_idxstats() {
+        let (_, ref_seqs, _) = parse_sam(SAM);
+        let writer = write_sam(SAM, |sam_header, ref_seqs| {
+            Writer::new(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, vec![Fields::RefID], ref_seqs, sam_header, String::new(), true)
+        });
+        let dir = TempDir::new("gbam_idxstats_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        std::fs::write(&path, writer.into_inner().into_inner()).unwrap();
+
+        let stats = idxstats(File::open(&path).unwrap()).unwrap();
+        assert_eq!(stats.per_ref, vec![(2, 1), (1, 0), (0, 0)]);
+        assert_eq!(stats.unplaced, 1);
+        let mut out = Vec::new();
+        stats.write(&mut out, &ref_seqs).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "chr1\t250\t2\t1\nchr2\t100\t1\t0\nchr3\t50\t0\t0\n*\t0\t0\t1\n");
+    }
+}
@@ -1,5 +1,330 @@
-// use crate::query::markdup::sorted_storage;
+use crate::compressor::compress;
+use crate::meta::FILE_INFO_SIZE;
+use crate::reader::column::decompress_block;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::{parse_file_info, Reader};
+use crate::reader::record::GbamRecord;
+use crate::writer::{rewrite_meta, Writer};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
 
-// fn markdup(gbam_file: File,  index_file: Option<Arc<Vec<u32>>>, thread_num: Option<usize>){
-    
-// }
+const PAIRED: u16 = 0x1;
+const UNMAPPED: u16 = 0x4;
+const MATE_UNMAPPED: u16 = 0x8;
+const REVERSE: u16 = 0x10;
+const DUPLICATE: u16 = 0x400;
+// Secondary, QC fail, supplementary.
+const NOT_EXAMINED: u16 = 0x100 | 0x200 | 0x800;
+// Offset of flag in BAM record with block_size.
+const FLAG_OFFSET: usize = 4 + 14;
+
+/// Fields read to find duplicates. Besides position columns, read names are
+/// needed to match mates and CIGAR to find unclipped 5' ends. Sequences,
+/// qualities and tags are never read.
+pub const MARKDUP_FIELDS: [Fields; 6] = [
+    Fields::RefID,
+    Fields::Pos,
+    Fields::Flags,
+    Fields::Mapq,
+    Fields::ReadName,
+    Fields::RawCigar,
+];
+
+/// Unclipped 5' end of a read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct End {
+    ref_id: i32,
+    pos: i32,
+    reverse: bool,
+}
+
+impl End {
+    fn of(rec: &GbamRecord) -> Self {
+        let cigar = rec.cigar.as_ref().unwrap();
+        let ((soft_start, soft_end), (hard_start, hard_end)) = (cigar.soft_clips(), cigar.hard_clips());
+        let pos = rec.pos.unwrap();
+        let reverse = rec.flag.unwrap() & REVERSE != 0;
+        let pos = if reverse {
+            pos + cigar.reference_length() as i32 - 1 + (soft_end + hard_end) as i32
+        } else {
+            pos - (soft_start + hard_start) as i32
+        };
+        Self {
+            ref_id: rec.refid.unwrap(),
+            pos,
+            reverse,
+        }
+    }
+}
+
+/// Best pair or fragment among those with equal ends.
+#[derive(Clone, Copy)]
+struct Candidate {
+    score: u32,
+    records: (u32, Option<u32>),
+}
+
+impl Candidate {
+    /// Higher score wins, the earlier one in file on tie.
+    fn beats(&self, other: &Candidate) -> bool {
+        (self.score, std::cmp::Reverse(self.records.0)) > (other.score, std::cmp::Reverse(other.records.0))
+    }
+}
+
+/// Counts of examined (primary, mapped, not QC failed) records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MarkdupStats {
+    pub pairs: u64,
+    pub duplicate_pairs: u64,
+    /// Unpaired reads and reads which mates are unmapped or missing.
+    pub fragments: u64,
+    pub duplicate_fragments: u64,
+}
+
+/// Result of [`find_duplicates`].
+pub struct Duplicates {
+    /// Indexed by record number.
+    pub is_duplicate: Vec<bool>,
+    pub stats: MarkdupStats,
+}
+
+/// Finds duplicates by the 5' position criterion (as Picard MarkDuplicates
+/// and samtools markdup): pairs are duplicates if unclipped 5' ends and
+/// strands of both reads are equal, fragments if their 5' end and strand
+/// are equal. Of duplicate pairs (fragments) the one with the highest sum
+/// of mapping qualities is kept, fragments at an end of a pair are always
+/// duplicates. Libraries are not told apart. `reader` has to fetch
+/// [`MARKDUP_FIELDS`], input does not need to be sorted.
+pub fn find_duplicates(reader: &mut Reader) -> Duplicates {
+    let mut is_duplicate = vec![false; reader.amount];
+    let mut stats = MarkdupStats::default();
+    let mut pairs: HashMap<(End, End), Candidate> = HashMap::new();
+    // Mates waiting for the other one, by read name.
+    let mut pending: HashMap<Vec<u8>, (u32, End, u8)> = HashMap::new();
+    let mut fragments = Vec::new();
+
+    let mut mark = |candidate: &Candidate| {
+        is_duplicate[candidate.records.0 as usize] = true;
+        if let Some(mate) = candidate.records.1 {
+            is_duplicate[mate as usize] = true;
+        }
+    };
+
+    let mut rec = GbamRecord::default();
+    for idx in 0..reader.amount {
+        reader.fill_record(idx, &mut rec);
+        let flag = rec.flag.unwrap();
+        if flag & (UNMAPPED | NOT_EXAMINED) != 0 {
+            continue;
+        }
+        let end = End::of(&rec);
+        let mapq = rec.mapq.unwrap();
+        if flag & PAIRED == 0 || flag & MATE_UNMAPPED != 0 {
+            fragments.push((idx as u32, end, mapq));
+            continue;
+        }
+        let name = rec.read_name.take().unwrap();
+        let (mate_idx, mate_end, mate_mapq) = match pending.remove(&name) {
+            Some(mate) => mate,
+            None => {
+                pending.insert(name, (idx as u32, end, mapq));
+                continue;
+            }
+        };
+        stats.pairs += 1;
+        let candidate = Candidate {
+            score: u32::from(mapq) + u32::from(mate_mapq),
+            records: (mate_idx, Some(idx as u32)),
+        };
+        let key = if mate_end <= end { (mate_end, end) } else { (end, mate_end) };
+        match pairs.get_mut(&key) {
+            Some(best) => {
+                stats.duplicate_pairs += 1;
+                if candidate.beats(best) {
+                    mark(best);
+                    *best = candidate;
+                } else {
+                    mark(&candidate);
+                }
+            }
+            None => {
+                pairs.insert(key, candidate);
+            }
+        }
+    }
+    // Mates not found in the file.
+    fragments.extend(pending.into_values());
+
+    let pair_ends: HashSet<End> = pairs.keys().flat_map(|&(a, b)| vec![a, b]).collect();
+    let mut best_fragments: HashMap<End, Candidate> = HashMap::new();
+    fragments.sort_unstable_by_key(|fragment| fragment.0);
+    for (idx, end, mapq) in fragments {
+        stats.fragments += 1;
+        let candidate = Candidate {
+            score: u32::from(mapq),
+            records: (idx, None),
+        };
+        if pair_ends.contains(&end) {
+            stats.duplicate_fragments += 1;
+            mark(&candidate);
+            continue;
+        }
+        match best_fragments.get_mut(&end) {
+            Some(best) => {
+                stats.duplicate_fragments += 1;
+                if candidate.beats(best) {
+                    mark(best);
+                    *best = candidate;
+                } else {
+                    mark(&candidate);
+                }
+            }
+            None => {
+                best_fragments.insert(end, candidate);
+            }
+        }
+    }
+
+    Duplicates { is_duplicate, stats }
+}
+
+/// Marks duplicates of GBAM file (see [`find_duplicates`]) writing new file.
+/// Previous duplicate flags are cleared. Only Flags column is rewritten,
+/// compressed blocks of other columns are copied as they are. With
+/// `remove_duplicates` duplicates are dropped instead, which rewrites all
+/// columns.
+pub fn markdup(in_path: &str, out_path: &str, remove_duplicates: bool, full_command: String) -> io::Result<MarkdupStats> {
+    let file = File::open(in_path)?;
+    let mut reader = Reader::new(file.try_clone()?, ParsingTemplate::new_with(&MARKDUP_FIELDS))?;
+    let duplicates = find_duplicates(&mut reader);
+    let file_meta = reader.file_meta.clone();
+    if remove_duplicates {
+        let mut all_fields = ParsingTemplate::new();
+        all_fields.set_all();
+        let mut reader = Reader::new_with_meta(file, all_fields, &file_meta, None)?;
+        let codecs = file_meta.field_codecs();
+        let mut writer = Writer::new(
+            BufWriter::new(File::create(out_path)?),
+            codecs,
+            8,
+            vec![Fields::RefID],
+            file_meta.get_ref_seqs().clone(),
+            file_meta.get_sam_header().to_vec(),
+            full_command,
+            false,
+        );
+        writer.set_sort_order(file_meta.sort_order());
+        for (key, value) in file_meta.metadata() {
+            writer.set_metadata(key, value.clone());
+        }
+        let tags: Vec<[u8; 2]> = file_meta.tag_columns().iter().map(|col| col.tag()).collect();
+        if !tags.is_empty() {
+            writer.set_tag_columns(&tags, *file_meta.get_column_codec(&Fields::RawTags, Some(0)));
+        }
+        let mut rec = GbamRecord::default();
+        let mut buf = Vec::new();
+        for idx in (0..reader.amount).filter(|&idx| !duplicates.is_duplicate[idx]) {
+            reader.fill_record(idx, &mut rec);
+            rec.convert_to_bytes(&mut buf);
+            let flag = u16::from_le_bytes([buf[FLAG_OFFSET], buf[FLAG_OFFSET + 1]]) & !DUPLICATE;
+            buf[FLAG_OFFSET..FLAG_OFFSET + 2].copy_from_slice(&flag.to_le_bytes());
+            // Writer expects BAM record without block_size.
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf[4..])));
+        }
+        writer.finish()?;
+    } else {
+        let bytes = (*reader.storage).as_ref();
+        let mut file_info = parse_file_info(bytes);
+        file_info.creation_command = full_command;
+        let mut new_meta = (*file_meta).clone();
+        new_meta.set_fingerprint(None);
+        // Name index is not copied.
+        new_meta.set_name_index(None);
+        if !file_info.creation_command.is_empty() {
+            new_meta.add_program_record(&file_info.creation_command);
+        }
+
+        let mut out = BufWriter::new(File::create(out_path)?);
+        out.write_all(&[0; FILE_INFO_SIZE])?;
+        let mut flags = Vec::new();
+        let mut compressed;
+        let mut first_rec = 0;
+        // Fields and tag columns.
+        let tag_cols = (0..file_meta.tag_columns().len())
+            .flat_map(|tag_col| [(Fields::RawTags, Some(tag_col)), (Fields::RawTagsLen, Some(tag_col))]);
+        for (field, tag_col) in Fields::iterator().map(|field| (*field, None)).chain(tag_cols) {
+            let codec = *file_meta.get_column_codec(&field, tag_col);
+            let new_blocks = new_meta.get_column_blocks(&field, tag_col);
+            for (block, new_block) in file_meta.view_column_blocks(&field, tag_col).iter().zip(new_blocks.iter_mut()) {
+                let start = block.seekpos as usize;
+                let mut data = &bytes[start..start + block.block_size as usize];
+                if field == Fields::Flags {
+                    flags.resize(block.uncompressed_size as usize, 0);
+                    decompress_block(data, &mut flags, &codec)?;
+                    for (i, item) in flags.chunks_exact_mut(2).enumerate() {
+                        let mut flag = u16::from_le_bytes([item[0], item[1]]) & !DUPLICATE;
+                        if duplicates.is_duplicate[first_rec + i] {
+                            flag |= DUPLICATE;
+                        }
+                        item.copy_from_slice(&flag.to_le_bytes());
+                    }
+                    first_rec += block.numitems as usize;
+                    compressed = compress(&flags, Vec::new(), codec);
+                    data = &compressed;
+                }
+                new_block.seekpos = out.stream_position()?;
+                new_block.block_size = data.len() as u32;
+                out.write_all(data)?;
+            }
+        }
+        let mut out = out.into_inner().map_err(|e| e.into_error())?;
+        rewrite_meta(&mut out, &mut file_info, &new_meta)?;
+    }
+    Ok(duplicates.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_from_sam;
+
+    // p1, p2 and p4 are the same fragment (p2 is soft clipped, p4 has lower
+    // mapping quality), p3 differs by reverse read end. f1 is at 5' end of
+    // pair p1, f2 and f3 are duplicate fragments.
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+p1\t99\tchr1\t100\t30\t10M\t=\t200\t110\t*\t*\n\
+p2\t99\tchr1\t102\t60\t2S8M\t=\t200\t108\t*\t*\n\
+p4\t99\tchr1\t100\t10\t10M\t=\t200\t110\t*\t*\n\
+p3\t99\tchr1\t100\t60\t10M\t=\t201\t111\t*\t*\n\
+f1\t0\tchr1\t100\t60\t10M\t*\t0\t0\t*\t*\n\
+f2\t16\tchr1\t300\t20\t10M\t*\t0\t0\t*\t*\n\
+f3\t16\tchr1\t295\t40\t10M5S\t*\t0\t0\t*\t*\n\
+p1\t147\tchr1\t200\t30\t10M\t=\t100\t-110\t*\t*\n\
+p2\t147\tchr1\t200\t60\t10M\t=\t102\t-108\t*\t*\n\
+p4\t147\tchr1\t200\t10\t10M\t=\t100\t-110\t*\t*\n\
+p3\t147\tchr1\t201\t60\t10M\t=\t100\t-111\t*\t*\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_find_duplicates() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new_with(&MARKDUP_FIELDS));
+
+        let duplicates = find_duplicates(&mut reader);
+        let dups: Vec<usize> = (0..reader.amount).filter(|&i| duplicates.is_duplicate[i]).collect();
+        // p1 and p4 (both reads), f1, f2.
+        assert_eq!(dups, vec![0, 2, 4, 5, 7, 9]);
+        assert_eq!(
+            duplicates.stats,
+            MarkdupStats {
+                pairs: 4,
+                duplicate_pairs: 2,
+                fragments: 3,
+                duplicate_fragments: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use super::depth::DepthCalculator;
+use std::fs::File;
+use std::io::{Result, Write};
+
+const FASTA_LINE_WIDTH: usize = 60;
+
+/// Output format of the mask.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskFormat {
+    /// Masked regions: chrom, start, end, `low` or `high`.
+    Bed,
+    /// One character per base, as in 1000 Genomes accessibility masks: `P`
+    /// passed, `L` low coverage, `H` high coverage.
+    Fasta,
+}
+
+/// Builds mask of bases which total depth across all `samples` is below
+/// `min_depth` or above `max_depth`. Reference sequences are taken from the
+/// first sample and matched by name in others.
+pub fn coverage_mask(
+    samples: Vec<File>,
+    min_depth: Option<i32>,
+    max_depth: Option<i32>,
+    format: MaskFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let calculators = samples
+        .into_iter()
+        .map(|file| DepthCalculator::new(file, None))
+        .collect::<Result<Vec<_>>>()?;
+    let ref_seqs = match calculators.first() {
+        Some(calc) => calc.file_meta().get_ref_seqs().clone(),
+        None => return Ok(()),
+    };
+
+    let classify = |depth: i32| {
+        if matches!(min_depth, Some(min) if depth < min) {
+            b'L'
+        } else if matches!(max_depth, Some(max) if depth > max) {
+            b'H'
+        } else {
+            b'P'
+        }
+    };
+
+    let mut total = Vec::<i32>::new();
+    let mut buf = Vec::<i32>::new();
+    for (ref_name, ref_len) in ref_seqs.iter() {
+        let ref_len = *ref_len as usize;
+        total.clear();
+        total.resize(ref_len, 0);
+        for calc in calculators.iter() {
+            if let Some(depth) = calc.depth(ref_name, std::mem::take(&mut buf)) {
+                total.iter_mut().zip(depth.iter()).for_each(|(t, d)| *t += d);
+                buf = depth;
+                buf.clear();
+            }
+        }
+
+        match format {
+            MaskFormat::Bed => write_bed(out, ref_name, &total, classify)?,
+            MaskFormat::Fasta => write_fasta(out, ref_name, &total, classify)?,
+        }
+    }
+    out.flush()
+}
+
+fn write_bed(out: &mut dyn Write, ref_name: &str, depth: &[i32], classify: impl Fn(i32) -> u8) -> Result<()> {
+    let mut start = 0;
+    while start < depth.len() {
+        let class = classify(depth[start]);
+        let end = depth[start..]
+            .iter()
+            .position(|&d| classify(d) != class)
+            .map_or(depth.len(), |len| start + len);
+        match class {
+            b'L' => writeln!(out, "{}\t{}\t{}\tlow", ref_name, start, end)?,
+            b'H' => writeln!(out, "{}\t{}\t{}\thigh", ref_name, start, end)?,
+            _ => {}
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+fn write_fasta(out: &mut dyn Write, ref_name: &str, depth: &[i32], classify: impl Fn(i32) -> u8) -> Result<()> {
+    writeln!(out, ">{}", ref_name)?;
+    let mut line = Vec::with_capacity(FASTA_LINE_WIDTH + 1);
+    for chunk in depth.chunks(FASTA_LINE_WIDTH) {
+        line.clear();
+        line.extend(chunk.iter().map(|&d| classify(d)));
+        line.push(b'\n');
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use crate::query::flagstat::BamFlags;
+use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord};
+use bam_tools::record::fields::Fields;
+use std::collections::HashMap;
+use std::fs::File;
+
+/// Fields needed to locate and verify mates.
+pub(crate) const MATE_FIELDS: [Fields; 6] = [
+    Fields::RefID,
+    Fields::Pos,
+    Fields::ReadName,
+    Fields::Flags,
+    Fields::NextRefID,
+    Fields::NextPos,
+];
+
+/// Position of a record in a set of shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLocation {
+    pub shard: usize,
+    pub rec_num: usize,
+}
+
+/// Resolves mates of paired reads which ended up in different GBAM files (e.g.
+/// per-chromosome splits of one BAM). Every shard has to be coordinate sorted.
+/// References are matched by name, so shards may have different reference
+/// lists.
+pub struct MateResolver {
+    files: Vec<File>,
+    shards: Vec<Reader>,
+    // Reference name to reference id for each shard.
+    name_to_ref_id: Vec<HashMap<String, i32>>,
+    // Shards which contain records mapped to the reference.
+    ref_to_shards: HashMap<String, Vec<usize>>,
+    buf: GbamRecord,
+}
+
+impl MateResolver {
+    pub fn new(files: Vec<File>) -> std::io::Result<Self> {
+        let mut shards = Vec::new();
+        let mut name_to_ref_id = Vec::new();
+        let mut ref_to_shards = HashMap::<String, Vec<usize>>::new();
+
+        for (shard, file) in files.iter().enumerate() {
+            let reader = Reader::new(file.try_clone()?, ParsingTemplate::new_with(&MATE_FIELDS))?;
+            if !reader.is_coordinate_sorted() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Shard {} is not coordinate sorted.", shard),
+                ));
+            }
+            let ref_seqs = reader.file_meta.get_ref_seqs();
+            name_to_ref_id.push(
+                ref_seqs
+                    .iter()
+                    .enumerate()
+                    .map(|(ref_id, (name, _))| (name.clone(), ref_id as i32))
+                    .collect::<HashMap<String, i32>>(),
+            );
+
+            // RefID block stats tell which references are present. Without
+            // stats every reference has to be considered.
+            let mut present = vec![false; ref_seqs.len()];
+            for block in reader.file_meta.view_blocks(&Fields::RefID) {
+                match &block.stats {
+                    Some(stat) if stat.min_value <= stat.max_value => {
+                        let first = std::cmp::max(stat.min_value, 0) as usize;
+                        let last = std::cmp::min(stat.max_value + 1, ref_seqs.len() as i32);
+                        (first..std::cmp::max(first, last as usize)).for_each(|id| present[id] = true);
+                    }
+                    Some(_) => {}
+                    None => present.iter_mut().for_each(|p| *p = true),
+                }
+            }
+            for (ref_id, (name, _)) in ref_seqs.iter().enumerate() {
+                if present[ref_id] {
+                    ref_to_shards.entry(name.clone()).or_default().push(shard);
+                }
+            }
+            shards.push(reader);
+        }
+
+        Ok(Self {
+            files,
+            shards,
+            name_to_ref_id,
+            ref_to_shards,
+            buf: GbamRecord::default(),
+        })
+    }
+
+    /// Number of shards.
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// Reader of the shard. Its template contains only fields needed for mate lookup.
+    pub fn shard(&mut self, shard: usize) -> &mut Reader {
+        &mut self.shards[shard]
+    }
+
+    /// Finds mate of the record stored in `shard`. The record should contain
+    /// at least RefID, Pos, ReadName, Flags, NextRefID and NextPos. Mates
+    /// without position or not found at it are looked up by read name in
+    /// shards which have name index (see [`crate::name_index::NameIndex`]).
+    /// Returns None if record is not paired or mate wasn't found.
+    pub fn find_mate(&mut self, rec: &GbamRecord, shard: usize) -> Option<RecordLocation> {
+        if rec.flag.unwrap() as u32 & BamFlags::BAM_FPAIRED.bits() == 0 {
+            return None;
+        }
+        self.find_mate_at_position(rec, shard).or_else(|| self.find_mate_by_name(rec))
+    }
+
+    fn find_mate_at_position(&mut self, rec: &GbamRecord, shard: usize) -> Option<RecordLocation> {
+        let next_ref_name = self.next_ref_name(rec, shard)?;
+        let candidates = self.ref_to_shards.get(&next_ref_name)?;
+        let next_pos = rec.next_pos.unwrap();
+
+        for &target in candidates {
+            let ref_id = self.name_to_ref_id[target][&next_ref_name];
+            let reader = &mut self.shards[target];
+            // Shards which are not coordinate sorted are searched by name.
+            let mut rec_num = match reader.lower_bound(ref_id, next_pos) {
+                Ok(rec_num) => rec_num,
+                Err(_) => continue,
+            };
+            while rec_num < reader.amount {
+                reader.fill_record(rec_num, &mut self.buf);
+                if self.buf.refid != Some(ref_id) || self.buf.pos != Some(next_pos) {
+                    break;
+                }
+                if is_mate(rec, &self.buf) {
+                    return Some(RecordLocation {
+                        shard: target,
+                        rec_num,
+                    });
+                }
+                rec_num += 1;
+            }
+        }
+        None
+    }
+
+    fn find_mate_by_name(&mut self, rec: &GbamRecord) -> Option<RecordLocation> {
+        let name = rec.read_name.as_ref()?;
+        for (target, reader) in self.shards.iter_mut().enumerate() {
+            if reader.file_meta.name_index().is_none() {
+                continue;
+            }
+            // Shards are read in stored order, so record numbers apply.
+            for rec_num in reader.find_by_name(name).ok()? {
+                reader.fill_record(rec_num, &mut self.buf);
+                if is_mate(rec, &self.buf) {
+                    return Some(RecordLocation {
+                        shard: target,
+                        rec_num,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Calls `f` for every pair which mates are stored in different shards.
+    /// Each pair is reported once, from the side of the first mate.
+    pub fn split_pairs<F>(&mut self, mut f: F) -> std::io::Result<()>
+    where
+        F: FnMut(&GbamRecord, RecordLocation, RecordLocation),
+    {
+        let mut rec = GbamRecord::default();
+        for shard in 0..self.shards.len() {
+            let mut scan_reader = Reader::new_with_meta(
+                self.files[shard].try_clone()?,
+                ParsingTemplate::new_with(&MATE_FIELDS),
+                &self.shards[shard].file_meta,
+                None,
+            )?;
+            for rec_num in 0..scan_reader.amount {
+                scan_reader.fill_record(rec_num, &mut rec);
+                let flag = rec.flag.unwrap() as u32;
+                if flag & BamFlags::BAM_FREAD1.bits() == 0 || flag & (BamFlags::BAM_FSECONDARY.bits() | BamFlags::BAM_FSUPPLEMENTARY.bits()) != 0 {
+                    continue;
+                }
+                // Skip lookup when the mate can only be in the same shard.
+                match self
+                    .next_ref_name(&rec, shard)
+                    .and_then(|name| self.ref_to_shards.get(&name))
+                {
+                    Some(shards) if shards.iter().any(|&s| s != shard) => {}
+                    _ => continue,
+                }
+                if let Some(mate) = self.find_mate(&rec, shard) {
+                    if mate.shard != shard {
+                        f(&rec, RecordLocation { shard, rec_num }, mate);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_ref_name(&self, rec: &GbamRecord, shard: usize) -> Option<String> {
+        if rec.flag.unwrap() as u32 & BamFlags::BAM_FPAIRED.bits() == 0 {
+            return None;
+        }
+        let next_ref_id = rec.next_ref_id.unwrap();
+        if next_ref_id < 0 {
+            return None;
+        }
+        self.shards[shard]
+            .file_meta
+            .get_ref_seqs()
+            .get(next_ref_id as usize)
+            .map(|(name, _)| name.clone())
+    }
+}
+
+/// Same template, primary alignment and the other segment of the pair.
+pub(crate) fn is_mate(rec: &GbamRecord, candidate: &GbamRecord) -> bool {
+    let segment = |flag: u16| flag as u32 & (BamFlags::BAM_FREAD1.bits() | BamFlags::BAM_FREAD2.bits());
+    let flag = candidate.flag.unwrap();
+    flag as u32 & (BamFlags::BAM_FSECONDARY.bits() | BamFlags::BAM_FSUPPLEMENTARY.bits()) == 0
+        && segment(flag) != segment(rec.flag.unwrap())
+        && candidate.name() == rec.name()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use std::io::BufWriter;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn write_shard(path: &Path, sam: &str, name_index: bool) -> File {
+        write_sam(sam, |sam_header, ref_seqs| {
+            let mut writer = Writer::new_no_stats(BufWriter::new(File::create(path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false);
+            writer.set_name_index(name_index);
+            writer
+        });
+        File::open(path).unwrap()
+    }
+
+    #[test]
+    fn test_find_unplaced_mate_by_name() {
+        let dir = TempDir::new("gbam_mates_test").unwrap();
+        let header = "@SQ\tSN:chr1\tLN:1000\n";
+        let mapped = format!("{}a\t99\tchr1\t11\t60\t5M\t=\t21\t15\t*\t*\na\t147\tchr1\t21\t60\t5M\t=\t11\t-15\t*\t*\nm\t73\tchr1\t31\t60\t5M\t*\t0\t0\t*\t*\n", header);
+        let unmapped = format!("{}m\t133\t*\t0\t0\t*\t*\t0\t0\t*\t*\n", header);
+        for name_index in [false, true] {
+            let files = vec![
+                write_shard(&dir.path().join("mapped.gbam"), &mapped, name_index),
+                write_shard(&dir.path().join("unmapped.gbam"), &unmapped, name_index),
+            ];
+            let mut resolver = MateResolver::new(files).unwrap();
+            let mut rec = GbamRecord::default();
+            resolver.shard(0).fill_record(2, &mut rec);
+            let expected = Some(RecordLocation { shard: 1, rec_num: 0 }).filter(|_| name_index);
+            assert_eq!(resolver.find_mate(&rec, 0), expected);
+            resolver.shard(0).fill_record(0, &mut rec);
+            assert_eq!(resolver.find_mate(&rec, 0), Some(RecordLocation { shard: 0, rec_num: 1 }));
+        }
+    }
+
+    #[test]
+    fn test_reader_find_mate() {
+        let dir = TempDir::new("gbam_reader_mates_test").unwrap();
+        let header = "@SQ\tSN:chr1\tLN:1000\n";
+        let records = [
+            "a\t99\tchr1\t11\t60\t5M\t=\t21\t15\t*\t*\n",
+            "b\t97\tchr1\t15\t60\t5M\t=\t500\t0\t*\t*\n",
+            "a\t403\tchr1\t21\t60\t5M\t=\t11\t-15\t*\t*\n",
+            "a\t147\tchr1\t21\t60\t5M\t=\t11\t-15\t*\t*\n",
+            "m\t73\tchr1\t31\t60\t5M\t=\t31\t0\t*\t*\n",
+            "m\t133\tchr1\t31\t0\t*\t=\t31\t0\t*\t*\n",
+            "u\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n",
+        ];
+        // Coordinate sorted, looked up by position, and unsorted, by name.
+        for order in [[0, 1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1, 0]] {
+            let sam: String = std::iter::once(header).chain(order.iter().map(|&i| records[i])).collect();
+            let file = write_shard(&dir.path().join("pairs.gbam"), &sam, false);
+            let mut reader = Reader::new(file, ParsingTemplate::new_with(&MATE_FIELDS)).unwrap();
+            assert_eq!(reader.file_meta.may_be_coordinate_sorted(), order[0] == 0);
+            let mut mates = Vec::new();
+            let mut rec = GbamRecord::default();
+            for &i in &[0, 1, 3, 4, 5, 6] {
+                let rec_num = order.iter().position(|&j| j == i).unwrap();
+                reader.fill_record(rec_num, &mut rec);
+                let mate = reader.find_mate(&rec).unwrap();
+                mates.push(mate.map(|mate| (mate.pos.unwrap(), mate.flag.unwrap())));
+            }
+            assert_eq!(mates, [Some((20, 147)), None, Some((10, 99)), Some((30, 133)), Some((30, 73)), None]);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const PAIRED: u16 = 0x1;
+const UNMAPPED: u16 = 0x4;
+const MATE_UNMAPPED: u16 = 0x8;
+const REVERSE: u16 = 0x10;
+const MATE_REVERSE: u16 = 0x20;
+// Secondary, QC fail, duplicate, supplementary.
+const NOT_COUNTED: u16 = 0x100 | 0x200 | 0x400 | 0x800;
+
+// Insert sizes further than this many MADs from the median are left out of
+// mean and standard deviation, as Picard CollectInsertSizeMetrics does.
+const MAX_DEVIATIONS: f64 = 10.0;
+
+/// Fields needed to classify pairs.
+pub const PAIR_FIELDS: [Fields; 6] = [
+    Fields::Flags,
+    Fields::RefID,
+    Fields::Pos,
+    Fields::NextRefID,
+    Fields::NextPos,
+    Fields::TemplateLength,
+];
+
+/// Relative orientation of read and its mate (as in Picard).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PairOrientation {
+    /// Forward read upstream of reverse one, reads point at each other.
+    FR,
+    /// Reverse read upstream of forward one, reads point away.
+    RF,
+    /// Both reads on the same strand.
+    Tandem,
+}
+
+impl PairOrientation {
+    pub const ALL: [PairOrientation; 3] = [PairOrientation::FR, PairOrientation::RF, PairOrientation::Tandem];
+
+    /// Orientation of pair both segments of which are mapped to the same
+    /// reference sequence. `None` for other records and when template length
+    /// is unknown. Needs [`PAIR_FIELDS`].
+    pub fn of(rec: &GbamRecord) -> Option<Self> {
+        let flag = rec.flag.unwrap();
+        if flag & PAIRED == 0 || flag & (UNMAPPED | MATE_UNMAPPED) != 0 {
+            return None;
+        }
+        let tlen = rec.tlen.unwrap();
+        if rec.refid.unwrap() != rec.next_ref_id.unwrap() || tlen == 0 {
+            return None;
+        }
+        let reverse = flag & REVERSE != 0;
+        if reverse == (flag & MATE_REVERSE != 0) {
+            return Some(PairOrientation::Tandem);
+        }
+        // 5' end of the reverse read is downstream of 5' end of the forward
+        // one exactly when template length of the forward read is positive.
+        let forward_tlen = if reverse { -tlen } else { tlen };
+        if forward_tlen > 0 {
+            Some(PairOrientation::FR)
+        } else {
+            Some(PairOrientation::RF)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PairOrientation::FR => "FR",
+            PairOrientation::RF => "RF",
+            PairOrientation::Tandem => "TANDEM",
+        }
+    }
+}
+
+impl fmt::Display for PairOrientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PairOrientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "FR" => Ok(PairOrientation::FR),
+            "RF" => Ok(PairOrientation::RF),
+            "TANDEM" | "FF" | "RR" => Ok(PairOrientation::Tandem),
+            _ => Err(format!("Unknown pair orientation: {}", s)),
+        }
+    }
+}
+
+/// Insert size distribution of FR pairs. Each pair is counted once (at the
+/// segment with positive template length), secondary, supplementary,
+/// duplicate and QC failed records are skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InsertSizeStats {
+    /// Number of pairs.
+    pub pairs: u64,
+    pub median: f64,
+    /// Median absolute deviation.
+    pub mad: f64,
+    /// Mean of insert sizes within 10 MADs of the median.
+    pub mean: f64,
+    /// Standard deviation of insert sizes within 10 MADs of the median.
+    pub sd: f64,
+    // Insert size -> number of pairs.
+    histogram: BTreeMap<u32, u64>,
+}
+
+impl InsertSizeStats {
+    /// Reads all records of GBAM file.
+    pub fn collect(file: File) -> io::Result<Self> {
+        let mut reader = Reader::new(file, ParsingTemplate::new_with(&PAIR_FIELDS))?;
+        let mut stats = Self::default();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            stats.add(rec);
+        }
+        stats.update();
+        Ok(stats)
+    }
+
+    /// Adds record to histogram. [`InsertSizeStats::update`] has to be
+    /// called before the statistics are used.
+    pub fn add(&mut self, rec: &GbamRecord) {
+        if rec.flag.unwrap() & NOT_COUNTED == 0 && rec.tlen.unwrap() > 0 && PairOrientation::of(rec) == Some(PairOrientation::FR) {
+            *self.histogram.entry(rec.tlen.unwrap() as u32).or_insert(0) += 1;
+        }
+    }
+
+    /// Recalculates statistics from histogram.
+    pub fn update(&mut self) {
+        self.pairs = self.histogram.values().sum();
+        if self.pairs == 0 {
+            return;
+        }
+        self.median = median(self.histogram.iter().map(|(&size, &n)| (f64::from(size), n)), self.pairs);
+        let mut deviations: Vec<(f64, u64)> = self
+            .histogram
+            .iter()
+            .map(|(&size, &n)| ((f64::from(size) - self.median).abs(), n))
+            .collect();
+        deviations.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        self.mad = median(deviations.into_iter(), self.pairs);
+
+        let max_deviation = MAX_DEVIATIONS * self.mad;
+        let (mut n, mut sum, mut sum_sq) = (0.0, 0.0, 0.0);
+        for (&size, &count) in self.histogram.iter() {
+            let size = f64::from(size);
+            if (size - self.median).abs() <= max_deviation {
+                n += count as f64;
+                sum += size * count as f64;
+                sum_sq += size * size * count as f64;
+            }
+        }
+        self.mean = sum / n;
+        self.sd = if n > 1.0 { ((sum_sq - sum * sum / n) / (n - 1.0)).max(0.0).sqrt() } else { 0.0 };
+    }
+
+    /// Z-score of absolute template length. 0 if there is no variance.
+    pub fn z_score(&self, tlen: i32) -> f64 {
+        if self.sd > 0.0 {
+            (f64::from(tlen.unsigned_abs()) - self.mean) / self.sd
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Median of sorted (value, count) pairs with `total` count.
+fn median(values: impl Iterator<Item = (f64, u64)>, total: u64) -> f64 {
+    let mut seen = 0;
+    for (value, count) in values {
+        seen += count;
+        if seen * 2 >= total {
+            return value;
+        }
+    }
+    0.0
+}
+
+/// Orientation and insert size z-score of a pair.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PairAnnotation {
+    pub orientation: PairOrientation,
+    pub insert_z: f64,
+}
+
+impl PairAnnotation {
+    /// Appends `po:Z` (orientation) and `iz:f` (insert size z-score) tags to
+    /// BAM record starting with block_size, which is updated.
+    pub fn append_bam_tags(&self, rec: &mut Vec<u8>) {
+        rec.extend_from_slice(b"poZ");
+        rec.extend_from_slice(self.orientation.as_str().as_bytes());
+        rec.push(0);
+        rec.extend_from_slice(b"izf");
+        rec.write_f32::<LittleEndian>(self.insert_z as f32).unwrap();
+        let block_size = (rec.len() - 4) as u32;
+        rec[..4].copy_from_slice(&block_size.to_le_bytes());
+    }
+
+    /// Inserts the same tags as [`PairAnnotation::append_bam_tags`] at the
+    /// end of SAM line (before the trailing newline).
+    pub fn append_sam_tags(&self, line: &mut Vec<u8>) {
+        let newline = line.last() == Some(&b'\n');
+        if newline {
+            line.pop();
+        }
+        write!(line, "\tpo:Z:{}\tiz:f:{:.3}", self.orientation, self.insert_z).unwrap();
+        if newline {
+            line.push(b'\n');
+        }
+    }
+}
+
+/// Selects pairs by orientation and insert size z-score and annotates them.
+#[derive(Debug, Clone, Default)]
+pub struct PairFilter {
+    /// Accepted orientations, any if empty.
+    pub orientations: Vec<PairOrientation>,
+    pub min_z: Option<f64>,
+    pub max_z: Option<f64>,
+    pub stats: InsertSizeStats,
+}
+
+impl PairFilter {
+    /// Whether any condition is set. Without conditions every record passes,
+    /// including those which are not classified.
+    pub fn is_active(&self) -> bool {
+        !self.orientations.is_empty() || self.min_z.is_some() || self.max_z.is_some()
+    }
+
+    /// Annotation of the record, `None` if it is not a classified pair.
+    pub fn annotate(&self, rec: &GbamRecord) -> Option<PairAnnotation> {
+        PairOrientation::of(rec).map(|orientation| PairAnnotation {
+            orientation,
+            insert_z: self.stats.z_score(rec.tlen.unwrap()),
+        })
+    }
+
+    pub fn matches(&self, annotation: Option<&PairAnnotation>) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        match annotation {
+            Some(ann) => {
+                (self.orientations.is_empty() || self.orientations.contains(&ann.orientation))
+                    && self.min_z.is_none_or(|min| ann.insert_z >= min)
+                    && self.max_z.is_none_or(|max| ann.insert_z <= max)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Writes insert size statistics and number of records per pair orientation
+/// as TSV.
+pub fn write_pair_summary(file: File, out: &mut dyn Write) -> io::Result<()> {
+    let mut reader = Reader::new(file, ParsingTemplate::new_with(&PAIR_FIELDS))?;
+    let mut stats = InsertSizeStats::default();
+    let mut counts = BTreeMap::new();
+    let mut unclassified = 0u64;
+    let mut records = reader.records();
+    while let Some(rec) = records.next_rec() {
+        stats.add(rec);
+        match PairOrientation::of(rec) {
+            Some(orientation) => *counts.entry(orientation).or_insert(0u64) += 1,
+            None => unclassified += 1,
+        }
+    }
+    stats.update();
+
+    writeln!(out, "pairs\t{}", stats.pairs)?;
+    writeln!(out, "insert_size_median\t{}", stats.median)?;
+    writeln!(out, "insert_size_mad\t{}", stats.mad)?;
+    writeln!(out, "insert_size_mean\t{:.3}", stats.mean)?;
+    writeln!(out, "insert_size_sd\t{:.3}", stats.sd)?;
+    for orientation in PairOrientation::ALL.iter() {
+        writeln!(out, "{}\t{}", orientation, counts.get(orientation).unwrap_or(&0))?;
+    }
+    writeln!(out, "UNCLASSIFIED\t{}", unclassified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair_rec(flag: u16, pos: i32, next_pos: i32, tlen: i32) -> GbamRecord {
+        GbamRecord {
+            flag: Some(flag),
+            refid: Some(0),
+            pos: Some(pos),
+            next_ref_id: Some(0),
+            next_pos: Some(next_pos),
+            tlen: Some(tlen),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_orientation_and_insert_size() {
+        // Forward read upstream, mate reverse.
+        assert_eq!(PairOrientation::of(&pair_rec(0x1 | 0x20, 100, 300, 300)), Some(PairOrientation::FR));
+        assert_eq!(PairOrientation::of(&pair_rec(0x1 | 0x10, 300, 100, -300)), Some(PairOrientation::FR));
+        // Reverse read upstream of forward mate.
+        assert_eq!(PairOrientation::of(&pair_rec(0x1 | 0x10, 100, 300, 300)), Some(PairOrientation::RF));
+        assert_eq!(PairOrientation::of(&pair_rec(0x1 | 0x10 | 0x20, 100, 300, 300)), Some(PairOrientation::Tandem));
+        assert_eq!(PairOrientation::of(&pair_rec(0x1 | 0x8, 100, 300, 0)), None);
+        assert_eq!("ff".parse::<PairOrientation>(), Ok(PairOrientation::Tandem));
+
+        let mut stats = InsertSizeStats::default();
+        for tlen in [100, 200, 300, 10_000].iter() {
+            stats.add(&pair_rec(0x1 | 0x20, 0, 0, *tlen));
+            // Mate is not counted again.
+            stats.add(&pair_rec(0x1 | 0x10, 0, 0, -tlen));
+        }
+        stats.update();
+        assert_eq!(stats.pairs, 4);
+        assert_eq!(stats.median, 200.0);
+        assert_eq!(stats.mad, 100.0);
+        // 10000 is an outlier.
+        assert_eq!(stats.mean, 200.0);
+        assert_eq!(stats.sd, 100.0);
+        assert_eq!(stats.z_score(-400), 2.0);
+
+        let filter = PairFilter {
+            orientations: vec![PairOrientation::FR],
+            min_z: Some(1.0),
+            max_z: None,
+            stats,
+        };
+        let ann = filter.annotate(&pair_rec(0x1 | 0x10, 300, 0, -400));
+        assert!(filter.matches(ann.as_ref()));
+        assert!(!filter.matches(filter.annotate(&pair_rec(0x1 | 0x20, 0, 300, 250)).as_ref()));
+        assert!(!filter.matches(None));
+
+        let mut line = b"r1\t0\n".to_vec();
+        ann.unwrap().append_sam_tags(&mut line);
+        assert_eq!(line, b"r1\t0\tpo:Z:FR\tiz:f:2.000\n");
+    }
+}
@@ -0,0 +1,316 @@
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::regions::Region;
+use bam_tools::record::fields::Fields;
+use std::io::{self, Write};
+use std::ops::Range;
+
+const REVERSE: u16 = 0x10;
+const MISSING_QUAL: u8 = 0xFF;
+// Highest quality printable as Phred+33.
+const MAX_QUAL: u8 = 93;
+const FASTA_LINE_LEN: usize = 60;
+
+/// Fields decoded for pileup besides RefID, Pos, Flags and RawCigar.
+pub const PILEUP_FIELDS: [Fields; 3] = [Fields::RawSequence, Fields::RawQual, Fields::Mapq];
+
+/// What a read has at a reference position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PileupEvent {
+    /// Aligned base (M, = or X) at this read position.
+    Base(usize),
+    /// Deleted base (D).
+    Deletion,
+    /// Skipped reference base (N).
+    RefSkip,
+}
+
+/// Read at a pileup column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PileupEntry {
+    pub event: PileupEvent,
+    /// Read position whose quality stands for the entry: the aligned base,
+    /// or the next base for deletions and skips.
+    pub qpos: usize,
+    /// First reference position of the alignment.
+    pub read_start: bool,
+    /// Last reference position of the alignment.
+    pub read_end: bool,
+    /// Read positions inserted (I) after this base.
+    pub insertion: Range<usize>,
+    /// Number of reference bases deleted (D) after this base.
+    pub deletion: u32,
+}
+
+/// Locates 0-based reference position `pos` in the alignment of `rec`.
+/// `None` if the alignment does not cover it. Pos and RawCigar have to be
+/// fetched.
+pub fn pileup_entry(rec: &GbamRecord, pos: i32) -> Option<PileupEntry> {
+    let ops = &rec.cigar.as_ref()?.0;
+    let start = rec.pos.unwrap();
+    let end = start + rec.alignment_span() as i32;
+    let (mut ref_pos, mut qpos) = (start, 0usize);
+    for (idx, op) in ops.iter().enumerate() {
+        let len = op.length() as usize;
+        let consumes_ref = op.is_consuming_reference();
+        if consumes_ref && pos < ref_pos + len as i32 {
+            let offset = (pos - ref_pos) as usize;
+            let mut entry = PileupEntry {
+                event: PileupEvent::Deletion,
+                qpos,
+                read_start: pos == start,
+                read_end: pos == end - 1,
+                insertion: 0..0,
+                deletion: 0,
+            };
+            match op.op_type() {
+                'D' => return Some(entry),
+                'N' => {
+                    entry.event = PileupEvent::RefSkip;
+                    return Some(entry);
+                }
+                _ => {}
+            }
+            entry.event = PileupEvent::Base(qpos + offset);
+            entry.qpos = qpos + offset;
+            if offset + 1 == len {
+                // Insertion and deletion following the last base of the op,
+                // padding between them is ignored.
+                let mut next_qpos = qpos + len;
+                for next in ops[idx + 1..].iter().filter(|next| next.op_type() != 'P') {
+                    match next.op_type() {
+                        'I' if entry.insertion.is_empty() && entry.deletion == 0 => {
+                            entry.insertion = next_qpos..next_qpos + next.length() as usize;
+                            next_qpos += next.length() as usize;
+                        }
+                        'D' if entry.deletion == 0 => entry.deletion = next.length(),
+                        _ => break,
+                    }
+                }
+            }
+            return Some(entry);
+        }
+        if consumes_ref {
+            ref_pos += len as i32;
+        }
+        if op.consumes_read() {
+            qpos += len;
+        }
+    }
+    None
+}
+
+/// Writes pileup of reads over `regions` (whole file if `None`) in
+/// reference-free `samtools mpileup` format: name, 1-based position, `N`
+/// as reference base, depth, read bases and base qualities. Bases are upper
+/// case for forward and lower case for reverse strand reads, `^` with map
+/// quality marks read starts and `$` read ends, `+3ACG` follows inserted
+/// bases and `-2NN` deleted bases, `*` (`#` on reverse strand) stands for a
+/// deleted and `>` (`<`) for a skipped reference base. Bases below
+/// `min_base_quality` are left out, as are positions without reads.
+/// Records filtered out by reader filters are not piled up.
+pub fn write_mpileup(reader: &mut Reader, regions: Option<&[Region]>, min_base_quality: u8, out: &mut dyn Write) -> io::Result<()> {
+    reader.add_fields(&PILEUP_FIELDS);
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let mut columns = match regions {
+        Some(regions) => reader.pileup_regions(regions)?,
+        None => reader.pileup_columns(),
+    };
+    let (mut bases, mut quals) = (Vec::new(), Vec::new());
+    while let Some(column) = columns.next_column() {
+        bases.clear();
+        quals.clear();
+        let mut depth = 0;
+        for rec in column.records {
+            let entry = match pileup_entry(rec, column.pos) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let qual = base_quality(rec, entry.qpos);
+            if matches!(entry.event, PileupEvent::Base(_)) && qual.is_some_and(|qual| qual < min_base_quality) {
+                continue;
+            }
+            let reverse = rec.flag.unwrap() & REVERSE != 0;
+            let strand = |base: u8| if reverse { base.to_ascii_lowercase() } else { base.to_ascii_uppercase() };
+            if entry.read_start {
+                bases.push(b'^');
+                bases.push(rec.mapq.unwrap().min(MAX_QUAL) + 33);
+            }
+            match entry.event {
+                PileupEvent::Base(qpos) => bases.push(strand(read_base(rec, qpos))),
+                PileupEvent::Deletion => bases.push(if reverse { b'#' } else { b'*' }),
+                PileupEvent::RefSkip => bases.push(if reverse { b'<' } else { b'>' }),
+            }
+            if !entry.insertion.is_empty() {
+                write!(bases, "+{}", entry.insertion.len())?;
+                bases.extend(entry.insertion.clone().map(|qpos| strand(read_base(rec, qpos))));
+            }
+            if entry.deletion > 0 {
+                write!(bases, "-{}", entry.deletion)?;
+                bases.extend(std::iter::repeat_n(strand(b'N'), entry.deletion as usize));
+            }
+            if entry.read_end {
+                bases.push(b'$');
+            }
+            quals.push(qual.unwrap_or(MAX_QUAL).min(MAX_QUAL) + 33);
+            depth += 1;
+        }
+        if depth == 0 {
+            continue;
+        }
+        write!(out, "{}\t{}\tN\t{}\t", ref_seqs[column.ref_id as usize].0, column.pos + 1, depth)?;
+        out.write_all(&bases)?;
+        out.write_all(b"\t")?;
+        out.write_all(&quals)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Writes majority consensus as FASTA: one sequence per region (named as
+/// `chr1:1001-2000`), or without regions per reference sequence with reads
+/// over its whole length. The most frequent of A, C, G and T with quality of
+/// at least `min_base_quality` is called, `N` on ties and positions without
+/// such bases. Positions where deletions outnumber every base are left out,
+/// insertions are ignored.
+pub fn write_consensus(reader: &mut Reader, regions: Option<&[Region]>, min_base_quality: u8, out: &mut dyn Write) -> io::Result<()> {
+    reader.add_fields(&PILEUP_FIELDS);
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+    let mut columns = match regions {
+        Some(regions) => reader.pileup_regions(regions)?,
+        None => reader.pileup_columns(),
+    };
+    let mut merged = regions.map(crate::reader::regions::merge_regions).unwrap_or_default().into_iter();
+    // Sequence in progress: its region and consensus of positions so far.
+    let mut current: Option<(Region, Vec<u8>)> = None;
+    while let Some(column) = columns.next_column() {
+        let in_current = current
+            .as_ref()
+            .is_some_and(|(region, _)| region.ref_id == column.ref_id && (column.pos as u32) < region.end);
+        if !in_current {
+            if let Some((region, seq)) = current.take() {
+                write_fasta(out, &ref_seqs, region, seq)?;
+            }
+            let region = match regions {
+                // Regions without reads are written empty.
+                Some(_) => loop {
+                    let region = merged.next().unwrap();
+                    if region.ref_id == column.ref_id && (column.pos as u32) < region.end {
+                        break region;
+                    }
+                    write_fasta(out, &ref_seqs, region, Vec::new())?;
+                },
+                None => Region::new(column.ref_id, 0, ref_seqs[column.ref_id as usize].1),
+            };
+            current = Some((region, Vec::new()));
+        }
+        let (region, seq) = current.as_mut().unwrap();
+        seq.resize((column.pos as u32 - region.start) as usize, b'N');
+
+        // A, C, G, T and deletions.
+        let mut counts = [0u32; 5];
+        for rec in column.records {
+            let idx = match pileup_entry(rec, column.pos).map(|entry| entry.event) {
+                Some(PileupEvent::Base(qpos)) if base_quality(rec, qpos).is_none_or(|qual| qual >= min_base_quality) => {
+                    match read_base(rec, qpos).to_ascii_uppercase() {
+                        b'A' => 0,
+                        b'C' => 1,
+                        b'G' => 2,
+                        b'T' => 3,
+                        _ => continue,
+                    }
+                }
+                Some(PileupEvent::Deletion) => 4,
+                _ => continue,
+            };
+            counts[idx] += 1;
+        }
+        let max = counts.iter().copied().max().unwrap();
+        if max > 0 && counts[4] == max && counts[..4].iter().all(|&n| n < max) {
+            // Deleted, position is left out.
+            seq.push(0);
+        } else if max > 0 && counts[..4].iter().filter(|&&n| n == max).count() == 1 {
+            seq.push(b"ACGT"[counts[..4].iter().position(|&n| n == max).unwrap()]);
+        } else {
+            seq.push(b'N');
+        }
+    }
+    if let Some((region, seq)) = current.take() {
+        write_fasta(out, &ref_seqs, region, seq)?;
+    }
+    for region in merged {
+        write_fasta(out, &ref_seqs, region, Vec::new())?;
+    }
+    Ok(())
+}
+
+/// Writes FASTA record of `region`, padding `seq` with `N` to the region
+/// end and dropping deleted positions (0).
+fn write_fasta(out: &mut dyn Write, ref_seqs: &[(String, u32)], region: Region, mut seq: Vec<u8>) -> io::Result<()> {
+    seq.resize((region.end - region.start) as usize, b'N');
+    seq.retain(|&base| base != 0);
+    writeln!(out, ">{}:{}-{}", ref_seqs[region.ref_id as usize].0, region.start + 1, region.end)?;
+    for line in seq.chunks(FASTA_LINE_LEN) {
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn read_base(rec: &GbamRecord, qpos: usize) -> u8 {
+    rec.seq.as_ref().unwrap().as_bytes().get(qpos).copied().unwrap_or(b'N')
+}
+
+/// Quality of read base, `None` if qualities are not stored.
+fn base_quality(rec: &GbamRecord, qpos: usize) -> Option<u8> {
+    rec.qual.as_ref().unwrap().get(qpos).copied().filter(|&qual| qual != MISSING_QUAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+
+    // 0-based: r1 [9, 13) with insertion after 10, r2 [10, 14) reverse with
+    // deletion of 12, r3 [11, 13) with low quality base at 12.
+    const SAM: &str = "@SQ\tSN:chr1\tLN:20\n\
+r1\t0\tchr1\t10\t60\t2M2I2M\t*\t0\t0\tACTTGT\tIIIIII\n\
+r2\t16\tchr1\t11\t30\t2M1D1M\t*\t0\t0\tCGA\tIII\n\
+r3\t0\tchr1\t12\t60\t2M\t*\t0\t0\tGA\tI#\n";
+
+    fn reader() -> Reader {
+        gbam_from_sam(SAM, ParsingTemplate::new())
+    }
+
+    #[test]
+    fn test_mpileup() {
+        let mut out = Vec::new();
+        write_mpileup(&mut reader(), None, 10, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "chr1\t10\tN\t1\t^]A\tI\n\
+chr1\t11\tN\t2\tC+2TT^?c\tII\n\
+chr1\t12\tN\t3\tGg-1n^]G\tIII\n\
+chr1\t13\tN\t2\tT$#\tII\n\
+chr1\t14\tN\t1\ta$\tI\n"
+        );
+
+        let mut out = Vec::new();
+        write_mpileup(&mut reader(), Some(&[Region::new(0, 12, 13)]), 0, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "chr1\t13\tN\t3\tT$#A$\tII#\n");
+    }
+
+    #[test]
+    fn test_consensus() {
+        let mut out = Vec::new();
+        write_consensus(&mut reader(), Some(&[Region::new(0, 8, 14), Region::new(0, 16, 18)]), 10, &mut out).unwrap();
+        // Position 12 has T of r1 as many times as deletion of r2, low
+        // quality A of r3 is not counted.
+        assert_eq!(String::from_utf8(out).unwrap(), ">chr1:9-14\nNACGTA\n>chr1:17-18\nNN\n");
+
+        let mut out = Vec::new();
+        write_consensus(&mut reader(), None, 0, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), ">chr1:1-20\nNNNNNNNNNACGNANNNNNN\n");
+    }
+}
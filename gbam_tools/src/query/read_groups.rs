@@ -0,0 +1,146 @@
+use super::pairs::{InsertSizeStats, PAIR_FIELDS};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+const UNMAPPED: u16 = 0x4;
+const DUPLICATE: u16 = 0x400;
+// Secondary or supplementary.
+const NOT_PRIMARY: u16 = 0x100 | 0x800;
+
+/// Counts of records of one read group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadGroupStats {
+    pub records: u64,
+    pub mapped: u64,
+    pub duplicates: u64,
+    // Over mapped primary records.
+    mapq_sum: u64,
+    mapq_count: u64,
+    /// Insert sizes of FR pairs, see [`InsertSizeStats`].
+    pub insert_sizes: InsertSizeStats,
+}
+
+impl ReadGroupStats {
+    /// [`ReadGroupStats::update`] has to be called before insert size
+    /// statistics are used.
+    pub fn add(&mut self, rec: &GbamRecord) {
+        let flag = rec.flag.unwrap();
+        self.records += 1;
+        if flag & DUPLICATE != 0 {
+            self.duplicates += 1;
+        }
+        if flag & UNMAPPED == 0 {
+            self.mapped += 1;
+            if flag & NOT_PRIMARY == 0 {
+                self.mapq_sum += u64::from(rec.mapq.unwrap());
+                self.mapq_count += 1;
+            }
+        }
+        self.insert_sizes.add(rec);
+    }
+
+    pub fn update(&mut self) {
+        self.insert_sizes.update();
+    }
+
+    /// Mean map quality of mapped primary records, 0 if there are none.
+    pub fn mean_mapq(&self) -> f64 {
+        if self.mapq_count == 0 {
+            return 0.0;
+        }
+        self.mapq_sum as f64 / self.mapq_count as f64
+    }
+}
+
+/// Statistics per read group ID (RG tag), records without RG are under
+/// `None`. Only RG tag of records is read, tag selection of `reader` (see
+/// [`Reader::select_tags`]) is reset afterwards.
+pub fn collect_read_group_stats(reader: &mut Reader) -> BTreeMap<Option<Vec<u8>>, ReadGroupStats> {
+    let mut fields = PAIR_FIELDS.to_vec();
+    fields.extend_from_slice(&[Fields::Mapq, Fields::RawTags]);
+    reader.select_tags(Some(&[*b"RG"]));
+    let mut groups: BTreeMap<Option<Vec<u8>>, ReadGroupStats> = BTreeMap::new();
+    let mut records = reader.records_with(&fields);
+    while let Some(rec) = records.next_rec() {
+        groups.entry(rec.read_group().map(<[u8]>::to_vec)).or_default().add(rec);
+    }
+    drop(records);
+    reader.select_tags(None);
+    groups.values_mut().for_each(ReadGroupStats::update);
+    groups
+}
+
+/// Writes TSV of read group statistics: groups of header (in header order,
+/// also those without records), then groups missing in header and `*` for
+/// records without RG tag.
+pub fn write_read_group_stats(file: File, out: &mut dyn Write) -> io::Result<()> {
+    let mut reader = Reader::new(file, ParsingTemplate::new())?;
+    let mut groups = collect_read_group_stats(&mut reader);
+    writeln!(out, "read_group\tsample\tlibrary\trecords\tmapped\tduplicates\tmean_mapq\tpairs\tmean_insert_size")?;
+    let empty = ReadGroupStats::default();
+    for group in reader.file_meta.read_groups() {
+        let stats = groups.remove(&Some(group.id.clone().into_bytes()));
+        write_row(out, &group.id, group.sample(), group.library(), stats.as_ref().unwrap_or(&empty))?;
+    }
+    for (id, stats) in groups.iter() {
+        let id = id.as_ref().map_or("*".into(), |id| String::from_utf8_lossy(id));
+        write_row(out, &id, None, None, stats)?;
+    }
+    Ok(())
+}
+
+fn write_row(out: &mut dyn Write, id: &str, sample: Option<&str>, library: Option<&str>, stats: &ReadGroupStats) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\t{:.2}",
+        id,
+        sample.unwrap_or("*"),
+        library.unwrap_or("*"),
+        stats.records,
+        stats.mapped,
+        stats.duplicates,
+        stats.mean_mapq(),
+        stats.insert_sizes.pairs,
+        stats.insert_sizes.mean
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_from_sam;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+@RG\tID:g1\tSM:s1\tLB:l1\n\
+@RG\tID:g2\tSM:s2\n\
+@RG\tID:g3\tSM:s3\n\
+p1\t99\tchr1\t100\t30\t10M\t=\t200\t110\t*\t*\tRG:Z:g1\n\
+p1\t147\tchr1\t200\t50\t10M\t=\t100\t-110\t*\t*\tRG:Z:g1\n\
+p2\t1123\tchr1\t100\t20\t10M\t=\t300\t210\t*\t*\tNM:i:0\tRG:Z:g1\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\tRG:Z:g2\n\
+x1\t0\tchr1\t500\t60\t10M\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_read_group_stats() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new());
+
+        assert_eq!(reader.file_meta.read_groups().len(), 3);
+        assert_eq!(reader.file_meta.read_group(b"g2").unwrap().sample(), Some("s2"));
+        let groups = collect_read_group_stats(&mut reader);
+        assert_eq!(groups.len(), 3);
+        let g1 = &groups[&Some(b"g1".to_vec())];
+        assert_eq!((g1.records, g1.mapped, g1.duplicates), (3, 3, 1));
+        assert_eq!(g1.mean_mapq(), 100.0 / 3.0);
+        // Duplicate pair is not counted.
+        assert_eq!(g1.insert_sizes.pairs, 1);
+        assert_eq!(g1.insert_sizes.mean, 110.0);
+        let g2 = &groups[&Some(b"g2".to_vec())];
+        assert_eq!((g2.records, g2.mapped, g2.mean_mapq()), (1, 0, 0.0));
+        assert_eq!(groups[&None].records, 1);
+    }
+}
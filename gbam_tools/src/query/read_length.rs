@@ -0,0 +1,128 @@
+use super::depth::DepthCalculator;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Statistic of aligned lengths of reads overlapping a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthStat {
+    /// Lower median.
+    Median,
+    /// Length L such that reads of length at least L hold at least half of
+    /// aligned bases.
+    N50,
+}
+
+impl FromStr for LengthStat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "median" => Ok(LengthStat::Median),
+            "n50" => Ok(LengthStat::N50),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown length statistic {}, expected median or n50.", s))),
+        }
+    }
+}
+
+impl LengthStat {
+    /// Sorts `lengths` and calculates the statistic, None if empty.
+    pub fn calc(self, lengths: &mut [u32]) -> Option<u32> {
+        if lengths.is_empty() {
+            return None;
+        }
+        lengths.sort_unstable();
+        match self {
+            LengthStat::Median => Some(lengths[(lengths.len() - 1) / 2]),
+            LengthStat::N50 => {
+                let total: u64 = lengths.iter().map(|&len| u64::from(len)).sum();
+                let mut acc = 0;
+                lengths.iter().rev().copied().find(|&len| {
+                    acc += u64::from(len);
+                    acc * 2 >= total
+                })
+            }
+        }
+    }
+}
+
+/// Writes bedGraph of `stat` of aligned read lengths in windows of `window`
+/// bases. Windows without reads are omitted, adjacent windows of equal value
+/// are merged.
+pub fn length_track(
+    gbam_file: File,
+    index_file: Option<Arc<Vec<u32>>>,
+    window: u32,
+    stat: LengthStat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let calc = DepthCalculator::new(gbam_file, index_file)?;
+    let ref_seqs = calc.file_meta().get_ref_seqs().clone();
+    let mut buf = Vec::new();
+    for (ref_name, ref_len) in ref_seqs.iter() {
+        let mut windows = calc.window_read_lengths(ref_name, window, buf).unwrap();
+        // Start, end and value of region not written yet.
+        let mut region: Option<(u32, u32, u32)> = None;
+        for (idx, lengths) in windows.iter_mut().enumerate() {
+            let start = idx as u32 * window;
+            let end = (start + window).min(*ref_len);
+            region = match (region, stat.calc(lengths)) {
+                (Some((prev_start, prev_end, prev)), Some(value)) if prev_end == start && prev == value => {
+                    Some((prev_start, end, value))
+                }
+                (prev, value) => {
+                    if let Some((prev_start, prev_end, prev)) = prev {
+                        writeln!(out, "{}\t{}\t{}\t{}", ref_name, prev_start, prev_end, prev)?;
+                    }
+                    value.map(|value| (start, end, value))
+                }
+            };
+        }
+        if let Some((start, end, value)) = region {
+            writeln!(out, "{}\t{}\t{}\t{}", ref_name, start, end, value)?;
+        }
+        buf = windows;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_bytes_from_sam;
+    use tempdir::TempDir;
+
+    // Spans: r1 [0, 100), r2 [50, 60), r3 [150, 180), r4 duplicate [150, 250),
+    // r5 [210, 250) on 250 bases long chr1.
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:250\n@SQ\tSN:chr2\tLN:100\n\
+r1\t0\tchr1\t1\t60\t100M\t*\t0\t0\t*\t*\n\
+r2\t0\tchr1\t51\t60\t4M2I6M\t*\t0\t0\t*\t*\n\
+r3\t16\tchr1\t151\t60\t10S30M\t*\t0\t0\t*\t*\n\
+r4\t1024\tchr1\t151\t60\t100M\t*\t0\t0\t*\t*\n\
+r5\t0\tchr1\t211\t60\t40M\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_length_track() {
+        let bytes = gbam_bytes_from_sam(SAM, |_| ());
+        let dir = TempDir::new("gbam_read_length_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut out = Vec::new();
+        length_track(File::open(&path).unwrap(), None, 100, LengthStat::N50, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "chr1\t0\t100\t100\nchr1\t100\t200\t30\nchr1\t200\t250\t40\n");
+        let mut out = Vec::new();
+        length_track(File::open(&path).unwrap(), None, 50, LengthStat::Median, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "chr1\t0\t50\t100\nchr1\t50\t100\t10\nchr1\t150\t200\t30\nchr1\t200\t250\t40\n");
+    }
+
+    #[test]
+    fn test_length_stats() {
+        assert_eq!(LengthStat::Median.calc(&mut [5, 1, 3, 2]), Some(2));
+        assert_eq!(LengthStat::N50.calc(&mut [2, 2, 2, 2, 10]), Some(10));
+        assert_eq!(LengthStat::N50.calc(&mut [1, 1, 2, 2, 3]), Some(2));
+        assert_eq!(LengthStat::N50.calc(&mut []), None);
+        assert_eq!("N50".parse::<LengthStat>().unwrap(), LengthStat::N50);
+    }
+}
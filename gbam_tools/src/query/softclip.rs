@@ -0,0 +1,297 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::{complement, GbamRecord};
+use bam_tools::record::fields::Fields;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+
+const UNMAPPED: u16 = 0x4;
+const REVERSE: u16 = 0x10;
+// Secondary, QC fail, supplementary.
+const NOT_EXAMINED: u16 = 0x100 | 0x200 | 0x800;
+
+/// Read group of records without RG tag.
+pub const NO_READ_GROUP: &str = "*";
+
+/// Adapters screened by default: Illumina TruSeq universal, Nextera and
+/// small RNA 3' adapters, and poly-A.
+pub const DEFAULT_ADAPTERS: [(&str, &str); 4] = [
+    ("illumina_universal", "AGATCGGAAGAGC"),
+    ("nextera", "CTGTCTCTTATACACATCT"),
+    ("small_rna", "TGGAATTCTCGG"),
+    ("poly_a", "AAAAAAAAAAAAAAAAAAAA"),
+];
+
+/// Screens clipped sequence for adapter or contaminant k-mers. Clips
+/// shorter than k match if they are a prefix of adapter, as adapter read
+/// through clips start with the beginning of adapter.
+pub struct AdapterScreen {
+    names: Vec<String>,
+    sequences: Vec<Vec<u8>>,
+    k: usize,
+    // K-mer to index of the first adapter containing it.
+    kmers: HashMap<Vec<u8>, usize>,
+    min_prefix: usize,
+}
+
+impl AdapterScreen {
+    pub fn new(adapters: Vec<(String, String)>, k: usize) -> Self {
+        let mut kmers = HashMap::new();
+        let mut names = Vec::new();
+        let mut sequences = Vec::new();
+        for (idx, (name, seq)) in adapters.into_iter().enumerate() {
+            let seq = seq.to_ascii_uppercase().into_bytes();
+            for kmer in seq.windows(k) {
+                kmers.entry(kmer.to_vec()).or_insert(idx);
+            }
+            names.push(name);
+            sequences.push(seq);
+        }
+        Self {
+            names,
+            sequences,
+            k,
+            kmers,
+            min_prefix: 6.min(k),
+        }
+    }
+
+    /// Screen of [`DEFAULT_ADAPTERS`] with k = 12.
+    pub fn default_adapters() -> Self {
+        Self::new(
+            DEFAULT_ADAPTERS.iter().map(|(name, seq)| (name.to_string(), seq.to_string())).collect(),
+            12,
+        )
+    }
+
+    /// Reads adapters from FASTA (name is the first word of header line).
+    pub fn from_fasta<R: BufRead>(reader: R, k: usize) -> io::Result<Self> {
+        let mut adapters: Vec<(String, String)> = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if let Some(header) = line.strip_prefix('>') {
+                adapters.push((header.split_whitespace().next().unwrap_or("").to_owned(), String::new()));
+            } else if let Some((_, seq)) = adapters.last_mut() {
+                seq.push_str(line);
+            } else if !line.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "FASTA has to start with header line."));
+            }
+        }
+        Ok(Self::new(adapters, k))
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Index of adapter found in `clip` (in read orientation).
+    pub fn find(&self, clip: &[u8]) -> Option<usize> {
+        if clip.len() >= self.k {
+            return clip.windows(self.k).find_map(|kmer| self.kmers.get(kmer).copied());
+        }
+        if clip.len() < self.min_prefix {
+            return None;
+        }
+        self.sequences.iter().position(|seq| seq.starts_with(clip))
+    }
+}
+
+/// Soft clip statistics of one read group. Ends are in read orientation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClipStats {
+    /// Primary mapped QC passed reads.
+    pub reads: u64,
+    pub clipped_reads: u64,
+    /// Clip length histograms of 5' and 3' ends (index is clip length,
+    /// unclipped ends are not counted).
+    pub five_prime: Vec<u64>,
+    pub three_prime: Vec<u64>,
+    /// 3' clips with adapter found, by adapter.
+    pub adapter_ends: Vec<u64>,
+}
+
+impl ClipStats {
+    fn add(&mut self, other: &ClipStats) {
+        self.reads += other.reads;
+        self.clipped_reads += other.clipped_reads;
+        add_hist(&mut self.five_prime, &other.five_prime);
+        add_hist(&mut self.three_prime, &other.three_prime);
+        add_hist(&mut self.adapter_ends, &other.adapter_ends);
+    }
+
+    fn add_record(&mut self, rec: &GbamRecord, screen: &AdapterScreen, clip: &mut Vec<u8>) {
+        self.reads += 1;
+        let (left, right) = rec.cigar.as_ref().unwrap().soft_clips();
+        let (left, right) = (left as usize, right as usize);
+        if left == 0 && right == 0 {
+            return;
+        }
+        self.clipped_reads += 1;
+        let seq = rec.seq.as_deref().unwrap_or("").as_bytes();
+        let reverse = rec.flag.unwrap() & REVERSE != 0;
+        let (five_prime, three_prime) = if reverse { (right, left) } else { (left, right) };
+        for (hist, len) in [(&mut self.five_prime, five_prime), (&mut self.three_prime, three_prime)] {
+            if len > 0 {
+                if hist.len() <= len {
+                    hist.resize(len + 1, 0);
+                }
+                hist[len] += 1;
+            }
+        }
+        if three_prime == 0 || seq.len() < left + right {
+            return;
+        }
+        clip.clear();
+        if reverse {
+            clip.extend(seq[..left].iter().rev().map(|&base| complement(base)));
+        } else {
+            clip.extend_from_slice(&seq[seq.len() - right..]);
+        }
+        if let Some(idx) = screen.find(clip) {
+            if self.adapter_ends.len() <= idx {
+                self.adapter_ends.resize(idx + 1, 0);
+            }
+            self.adapter_ends[idx] += 1;
+        }
+    }
+}
+
+fn add_hist(a: &mut Vec<u64>, b: &[u64]) {
+    if a.len() < b.len() {
+        a.resize(b.len(), 0);
+    }
+    a.iter_mut().zip(b.iter()).for_each(|(a, b)| *a += b);
+}
+
+/// Collects soft clip statistics by read group (RG tag, tags are read only
+/// if header has @RG lines) of primary mapped QC passed reads, in parallel.
+pub fn collect_clip_stats(file: File, screen: &AdapterScreen) -> io::Result<BTreeMap<String, ClipStats>> {
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let has_read_groups = reader.file_meta.get_sam_header().windows(4).any(|w| w == b"@RG\t");
+    let mut fields = vec![Fields::Flags, Fields::RawCigar, Fields::RawSequence];
+    if has_read_groups {
+        fields.push(Fields::RawTags);
+    }
+    let reader = Reader::new_with_meta(file, ParsingTemplate::new_with(&fields), &reader.file_meta, None)?;
+    let stats = reader
+        .par_record_batches()
+        .map(|batch| {
+            let mut stats: BTreeMap<String, ClipStats> = BTreeMap::new();
+            let mut clip = Vec::new();
+            for rec in batch.iter().filter(|rec| rec.flag.unwrap() & (UNMAPPED | NOT_EXAMINED) == 0) {
+                let read_group = match has_read_groups {
+                    true => rec.string_tag(b"RG").map_or(NO_READ_GROUP.into(), |rg| String::from_utf8_lossy(rg)),
+                    false => NO_READ_GROUP.into(),
+                };
+                match stats.get_mut(read_group.as_ref()) {
+                    Some(group) => group.add_record(rec, screen, &mut clip),
+                    None => stats.entry(read_group.into_owned()).or_default().add_record(rec, screen, &mut clip),
+                }
+            }
+            stats
+        })
+        .reduce(BTreeMap::new, |mut a, b| {
+            for (read_group, stats) in b {
+                a.entry(read_group).or_default().add(&stats);
+            }
+            a
+        });
+    Ok(stats)
+}
+
+/// Writes summary per read group as TSV, followed by clip length
+/// histograms.
+pub fn write_clip_report(out: &mut dyn Write, stats: &BTreeMap<String, ClipStats>, screen: &AdapterScreen) -> io::Result<()> {
+    write!(out, "read_group\treads\tclipped_reads\tclipped_fraction\tclipped_5p\tclipped_3p\tmean_clip_5p\tmean_clip_3p")?;
+    for name in screen.names() {
+        write!(out, "\tadapter_3p_{}", name)?;
+    }
+    writeln!(out)?;
+    for (read_group, group) in stats {
+        let clipped = |hist: &[u64]| hist.iter().sum::<u64>();
+        let mean = |hist: &[u64]| {
+            let n = clipped(hist);
+            let total: u64 = hist.iter().enumerate().map(|(len, count)| len as u64 * count).sum();
+            if n == 0 { 0.0 } else { total as f64 / n as f64 }
+        };
+        write!(
+            out,
+            "{}\t{}\t{}\t{:.6}\t{}\t{}\t{:.2}\t{:.2}",
+            read_group,
+            group.reads,
+            group.clipped_reads,
+            if group.reads == 0 { 0.0 } else { group.clipped_reads as f64 / group.reads as f64 },
+            clipped(&group.five_prime),
+            clipped(&group.three_prime),
+            mean(&group.five_prime),
+            mean(&group.three_prime),
+        )?;
+        for idx in 0..screen.names().len() {
+            write!(out, "\t{}", group.adapter_ends.get(idx).copied().unwrap_or(0))?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "# read_group\tend\tclip_length\tends")?;
+    for (read_group, group) in stats {
+        for (end, hist) in [("5p", &group.five_prime), ("3p", &group.three_prime)] {
+            for (len, count) in hist.iter().enumerate().filter(|(_, &count)| count > 0) {
+                writeln!(out, "{}\t{}\t{}\t{}", read_group, end, len, count)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_bytes_from_sam;
+    use tempdir::TempDir;
+
+    // r1: forward, 3' clip is adapter. r2: reverse, 3' clip (left, reverse
+    // complemented) is adapter prefix, 5' clip of 2 after hard clip. r3 is
+    // secondary, r4 is not clipped.
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n@RG\tID:a\n@RG\tID:b\n\
+r1\t0\tchr1\t11\t60\t5M13S\t*\t0\t0\tACGTAAGATCGGAAGAGC\t*\tRG:Z:a\n\
+r2\t16\tchr1\t21\t60\t7S4M2S5H\t*\t0\t0\tCCGATCTACGTTT\t*\tNM:i:0\tRG:Z:b\n\
+r3\t256\tchr1\t21\t60\t7S4M\t*\t0\t0\t*\t*\tRG:Z:b\n\
+r4\t0\tchr1\t31\t60\t5M\t*\t0\t0\tACGTA\t*\tRG:Z:a\n";
+
+    #[test]
+    fn test_clip_stats() {
+        let bytes = gbam_bytes_from_sam(SAM, |_| ());
+        let dir = TempDir::new("gbam_softclip_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        std::fs::write(&path, bytes).unwrap();
+
+        let screen = AdapterScreen::default_adapters();
+        let stats = collect_clip_stats(File::open(&path).unwrap(), &screen).unwrap();
+        let a = &stats["a"];
+        assert_eq!((a.reads, a.clipped_reads), (2, 1));
+        assert_eq!(a.three_prime[13], 1);
+        assert!(a.five_prime.is_empty());
+        assert_eq!(a.adapter_ends, vec![1]);
+        let b = &stats["b"];
+        assert_eq!((b.reads, b.clipped_reads), (1, 1));
+        assert_eq!((b.five_prime[2], b.three_prime[7]), (1, 1));
+        assert_eq!(b.adapter_ends, vec![1]);
+
+        let mut out = Vec::new();
+        write_clip_report(&mut out, &stats, &screen).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("a\t2\t1\t0.500000\t0\t1\t0.00\t13.00\t1\t0\t0\t0\n"));
+        assert!(report.contains("b\t3p\t7\t1\n"));
+    }
+
+    #[test]
+    fn test_adapter_screen_from_fasta() {
+        let screen = AdapterScreen::from_fasta(">phix some description\nGAGTTTTATCGCTTCC\nATGACGCAGAAG\n>x\nACGT\n".as_bytes(), 8).unwrap();
+        assert_eq!(screen.names(), &["phix".to_owned(), "x".to_owned()]);
+        assert_eq!(screen.find(b"TTCGCTTCCATGAC"), Some(0));
+        assert_eq!(screen.find(b"GAGTTTT"), Some(0));
+        assert_eq!(screen.find(b"ACGT"), None);
+    }
+}
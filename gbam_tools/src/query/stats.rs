@@ -0,0 +1,279 @@
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const PAIRED: u16 = 0x1;
+const UNMAPPED: u16 = 0x4;
+const MATE_UNMAPPED: u16 = 0x8;
+const REVERSE: u16 = 0x10;
+// Secondary or supplementary.
+const NOT_PRIMARY: u16 = 0x100 | 0x800;
+// Quality of bases when qualities are not stored.
+const MISSING_QUAL: u8 = 0xFF;
+
+/// Metric of [`SeqStats`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// Histogram of positive template lengths of pairs mapped to the same
+    /// reference sequence.
+    InsertSize,
+    /// Histogram of read lengths.
+    ReadLength,
+    /// Mean base quality per cycle (in sequencing direction).
+    Quality,
+    /// Histogram of GC percentage of reads (N bases are not counted).
+    Gc,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [Metric::InsertSize, Metric::ReadLength, Metric::Quality, Metric::Gc];
+
+    /// Fields decoded for the metric, besides Flags.
+    fn fields(self) -> &'static [Fields] {
+        match self {
+            Metric::InsertSize => &[Fields::RefID, Fields::NextRefID, Fields::TemplateLength],
+            // Length is read from index column of RawQual.
+            Metric::ReadLength => &[],
+            Metric::Quality => &[Fields::RawQual],
+            Metric::Gc => &[Fields::RawSequence],
+        }
+    }
+
+    /// Parses comma separated list, e.g. `insert_size,gc`.
+    pub fn parse_list(s: &str) -> io::Result<Vec<Metric>> {
+        s.split(',').map(str::parse).collect()
+    }
+}
+
+impl FromStr for Metric {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "insert_size" => Ok(Metric::InsertSize),
+            "read_length" => Ok(Metric::ReadLength),
+            "quality" => Ok(Metric::Quality),
+            "gc" => Ok(Metric::Gc),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown metric {}, expected insert_size, read_length, quality or gc.", s),
+            )),
+        }
+    }
+}
+
+/// Distributions over primary records, similar to `samtools stats`.
+/// Distributions of metrics which were not collected are empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeqStats {
+    /// Number of primary records.
+    pub records: u64,
+    /// Template length -> number of records.
+    pub insert_sizes: BTreeMap<u32, u64>,
+    /// Read length -> number of records.
+    pub read_lengths: BTreeMap<u32, u64>,
+    /// Sum of qualities and number of bases per cycle.
+    pub cycle_quality: Vec<(u64, u64)>,
+    /// Number of records per GC percentage.
+    pub gc_content: Vec<u64>,
+}
+
+impl SeqStats {
+    fn add(&mut self, other: &SeqStats) {
+        self.records += other.records;
+        for (size, n) in other.insert_sizes.iter() {
+            *self.insert_sizes.entry(*size).or_insert(0) += n;
+        }
+        for (len, n) in other.read_lengths.iter() {
+            *self.read_lengths.entry(*len).or_insert(0) += n;
+        }
+        if self.cycle_quality.len() < other.cycle_quality.len() {
+            self.cycle_quality.resize(other.cycle_quality.len(), (0, 0));
+        }
+        for (acc, (sum, n)) in self.cycle_quality.iter_mut().zip(other.cycle_quality.iter()) {
+            acc.0 += sum;
+            acc.1 += n;
+        }
+        if self.gc_content.is_empty() {
+            self.gc_content = other.gc_content.clone();
+        } else {
+            self.gc_content.iter_mut().zip(other.gc_content.iter()).for_each(|(acc, n)| *acc += n);
+        }
+    }
+
+    fn add_insert_size(&mut self, rec: &GbamRecord) {
+        let flag = rec.flag.unwrap();
+        if flag & PAIRED == 0 || flag & (UNMAPPED | MATE_UNMAPPED) != 0 || rec.refid != rec.next_ref_id {
+            return;
+        }
+        let tlen = rec.tlen.unwrap();
+        if tlen > 0 {
+            *self.insert_sizes.entry(tlen as u32).or_insert(0) += 1;
+        }
+    }
+
+    fn add_quality(&mut self, rec: &GbamRecord) {
+        let qual = rec.qual.as_ref().unwrap();
+        if qual.first().is_none_or(|&q| q == MISSING_QUAL) {
+            return;
+        }
+        if self.cycle_quality.len() < qual.len() {
+            self.cycle_quality.resize(qual.len(), (0, 0));
+        }
+        let reverse = rec.flag.unwrap() & REVERSE != 0;
+        for (idx, &q) in qual.iter().enumerate() {
+            let cycle = if reverse { qual.len() - 1 - idx } else { idx };
+            self.cycle_quality[cycle].0 += u64::from(q);
+            self.cycle_quality[cycle].1 += 1;
+        }
+    }
+
+    fn add_gc(&mut self, rec: &GbamRecord) {
+        let (mut gc, mut acgt) = (0usize, 0usize);
+        for base in rec.seq.as_ref().unwrap().bytes() {
+            match base {
+                b'G' | b'C' => {
+                    gc += 1;
+                    acgt += 1;
+                }
+                b'A' | b'T' => acgt += 1,
+                _ => {}
+            }
+        }
+        if let Some(percent) = (gc * 100 + acgt / 2).checked_div(acgt) {
+            self.gc_content.resize(101, 0);
+            self.gc_content[percent] += 1;
+        }
+    }
+
+    /// Writes sections of collected metrics as `samtools stats` does: `SN`
+    /// summary, then `IS insert_size pairs`, `RL length reads`, `GC percent
+    /// reads` and `CQ cycle mean_quality` lines. Cycles are 1-based.
+    pub fn write(&self, out: &mut dyn Write, metrics: &[Metric]) -> io::Result<()> {
+        writeln!(out, "SN\tprimary records:\t{}", self.records)?;
+        for metric in metrics {
+            match metric {
+                Metric::InsertSize => {
+                    for (size, n) in self.insert_sizes.iter() {
+                        writeln!(out, "IS\t{}\t{}", size, n)?;
+                    }
+                }
+                Metric::ReadLength => {
+                    for (len, n) in self.read_lengths.iter() {
+                        writeln!(out, "RL\t{}\t{}", len, n)?;
+                    }
+                }
+                Metric::Gc => {
+                    for (percent, n) in self.gc_content.iter().enumerate().filter(|(_, &n)| n > 0) {
+                        writeln!(out, "GC\t{}\t{}", percent, n)?;
+                    }
+                }
+                Metric::Quality => {
+                    for (cycle, (sum, n)) in self.cycle_quality.iter().enumerate().filter(|(_, (_, n))| *n > 0) {
+                        writeln!(out, "CQ\t{}\t{:.2}", cycle + 1, *sum as f64 / *n as f64)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects `metrics` over primary records. Only Flags and the columns
+/// needed by requested metrics are decoded; read lengths come from index
+/// column of RawQual without decompressing qualities.
+pub fn collect_seq_stats(file: File, metrics: &[Metric]) -> io::Result<SeqStats> {
+    let reader = Reader::new(file.try_clone()?, ParsingTemplate::new())?;
+    let total_records = reader.amount;
+    let file_meta = reader.file_meta;
+
+    let mut fields = vec![Fields::Flags];
+    for metric in metrics {
+        fields.extend_from_slice(metric.fields());
+    }
+    fields.dedup();
+    let read_length = metrics.contains(&Metric::ReadLength);
+    let mut tmplt_fields = fields.clone();
+    if read_length {
+        tmplt_fields.push(Fields::RawQual);
+    }
+
+    (0..total_records)
+        .into_par_iter()
+        .chunks(500_000)
+        .map(|records_range| {
+            let mut stats = SeqStats::default();
+            let mut rec = GbamRecord::default();
+            let mut reader = Reader::new_with_meta(file.try_clone()?, ParsingTemplate::new_with(&tmplt_fields), &file_meta, None)?;
+            reader.fetch_only(&fields);
+            for rec_num in records_range {
+                reader.fill_record(rec_num, &mut rec);
+                if rec.flag.unwrap() & NOT_PRIMARY != 0 {
+                    continue;
+                }
+                stats.records += 1;
+                for metric in metrics {
+                    match metric {
+                        Metric::InsertSize => stats.add_insert_size(&rec),
+                        Metric::ReadLength => {
+                            let len = reader.field_len(&Fields::RawQual, rec_num).unwrap() as u32;
+                            *stats.read_lengths.entry(len).or_insert(0) += 1;
+                        }
+                        Metric::Quality => stats.add_quality(&rec),
+                        Metric::Gc => stats.add_gc(&rec),
+                    }
+                }
+            }
+            Ok(stats)
+        })
+        .try_reduce(SeqStats::default, |mut a, b| {
+            a.add(&b);
+            Ok(a)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gbam_bytes_from_sam;
+    use tempdir::TempDir;
+
+    // s1 is secondary, u1 has no qualities.
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+p1\t99\tchr1\t100\t60\t4M\t=\t200\t104\tACGT\t#+5?\n\
+p1\t147\tchr1\t200\t60\t4M\t=\t100\t-104\tGGGA\t!!!+\n\
+s1\t355\tchr1\t300\t0\t4M\t=\t200\t50\tGGGG\t++++\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\tNNAAT\t*\n";
+
+    #[test]
+    fn test_seq_stats() {
+        let bytes = gbam_bytes_from_sam(SAM, |_| ());
+        let dir = TempDir::new("gbam_stats_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        std::fs::write(&path, bytes).unwrap();
+
+        let stats = collect_seq_stats(File::open(&path).unwrap(), &Metric::ALL).unwrap();
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.insert_sizes.into_iter().collect::<Vec<_>>(), vec![(104, 1)]);
+        assert_eq!(stats.read_lengths.into_iter().collect::<Vec<_>>(), vec![(4, 2), (5, 1)]);
+        // Second read is reversed: its qualities are 10, 0, 0, 0 by cycle.
+        assert_eq!(stats.cycle_quality, vec![(12, 2), (10, 2), (20, 2), (30, 2)]);
+        assert_eq!((stats.gc_content[50], stats.gc_content[75], stats.gc_content[0]), (1, 1, 1));
+
+        // Only read lengths, qualities are not decoded.
+        let stats = collect_seq_stats(File::open(&path).unwrap(), &[Metric::ReadLength]).unwrap();
+        assert!(stats.insert_sizes.is_empty() && stats.cycle_quality.is_empty());
+        let mut out = Vec::new();
+        stats.write(&mut out, &[Metric::ReadLength]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "SN\tprimary records:\t3\nRL\t4\t2\nRL\t5\t1\n");
+
+        assert_eq!(Metric::parse_list("gc,read_length").unwrap(), vec![Metric::Gc, Metric::ReadLength]);
+        assert!("depth".parse::<Metric>().is_err());
+    }
+}
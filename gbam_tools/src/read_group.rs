@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// @RG line of SAM header.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReadGroup {
+    /// ID tag, referenced by RG tag of records.
+    pub id: String,
+    /// Other tags in header order, e.g. ("SM", "NA12878").
+    pub tags: Vec<(String, String)>,
+}
+
+impl ReadGroup {
+    /// Parses @RG lines of BAM header (with leading l_text). Lines without
+    /// ID are skipped.
+    pub fn from_sam_header(sam_header: &[u8]) -> Vec<Self> {
+        let l_text = sam_header.get(..4).map_or(0, |l_text| u32::from_le_bytes(l_text.try_into().unwrap()) as usize);
+        let text = sam_header.get(4..).unwrap_or_default();
+        // Reference sequences follow the text, which may be NUL padded.
+        let text = &text[..l_text.min(text.len())];
+        let text = &text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())];
+        String::from_utf8_lossy(text)
+            .lines()
+            .filter_map(|line| line.strip_prefix("@RG\t"))
+            .filter_map(Self::parse_line)
+            .collect()
+    }
+
+    fn parse_line(fields: &str) -> Option<Self> {
+        let mut id = None;
+        let mut tags = Vec::new();
+        for field in fields.split('\t') {
+            let (tag, value) = field.split_once(':')?;
+            if tag == "ID" {
+                id = Some(value.to_owned());
+            } else {
+                tags.push((tag.to_owned(), value.to_owned()));
+            }
+        }
+        Some(Self { id: id?, tags })
+    }
+
+    /// Value of header tag, e.g. `get("SM")`.
+    pub fn get(&self, tag: &str) -> Option<&str> {
+        self.tags.iter().find(|(name, _)| name == tag).map(|(_, value)| value.as_str())
+    }
+
+    /// SM tag.
+    pub fn sample(&self) -> Option<&str> {
+        self.get("SM")
+    }
+
+    /// LB tag.
+    pub fn library(&self) -> Option<&str> {
+        self.get("LB")
+    }
+
+    /// PL tag.
+    pub fn platform(&self) -> Option<&str> {
+        self.get("PL")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_sam_header() {
+        let text = "@HD\tVN:1.6\n@RG\tID:grp1\tSM:s1\tLB:lib1\tPL:ILLUMINA\n@RG\tSM:no_id\n@RG\tID:grp2\tSM:s2\n\0\0";
+        let mut header = (text.len() as u32).to_le_bytes().to_vec();
+        header.extend_from_slice(text.as_bytes());
+        // No reference sequences.
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let groups = ReadGroup::from_sam_header(&header);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].id, "grp1");
+        assert_eq!(groups[0].sample(), Some("s1"));
+        assert_eq!(groups[0].library(), Some("lib1"));
+        assert_eq!(groups[0].platform(), Some("ILLUMINA"));
+        assert_eq!(groups[1].id, "grp2");
+        assert_eq!(groups[1].library(), None);
+        assert!(ReadGroup::from_sam_header(&[]).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::{Reader, Storage};
+use super::record::GbamRecord;
+use super::records::Records;
+use super::regions::Region;
+use super::remote::{block_on, RemoteReader};
+use super::source::{is_remote, open_source, BlockSource};
+use crate::error::GbamError;
+use crate::meta::FileMeta;
+use bam_tools::record::fields::Fields;
+use futures_core::Stream;
+use std::fs::File;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Records decoded per message to the stream.
+const BATCH_SIZE: usize = 1024;
+/// Batches decoded ahead of the consumer.
+const BATCHES_AHEAD: usize = 4;
+
+enum Backend {
+    Local { storage: Storage, file_meta: Arc<FileMeta> },
+    Remote(RemoteReader<Box<dyn BlockSource>>),
+}
+
+/// Reader for async (tokio) services. Files are opened, and blocks read and
+/// decoded, on tokio blocking thread pool and records are streamed in
+/// batches, so executor threads never wait for disk or network. Local files
+/// are memory mapped, URLs (see [`open_source`]) are read by regions as
+/// [`RemoteReader`] does. Cloning is cheap, clones share the file.
+#[derive(Clone)]
+pub struct AsyncReader {
+    backend: Arc<Backend>,
+}
+
+impl AsyncReader {
+    /// Opens local file or URL.
+    pub async fn open(path: &str) -> io::Result<Self> {
+        let path = path.to_owned();
+        let backend = task::spawn_blocking(move || -> io::Result<Backend> {
+            if is_remote(&path) {
+                return Ok(Backend::Remote(block_on(RemoteReader::open(open_source(&path)?))?));
+            }
+            let reader = Reader::new(File::open(&path)?, ParsingTemplate::new())?;
+            Ok(Backend::Local { storage: reader.storage.clone(), file_meta: reader.file_meta.clone() })
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(Self { backend: Arc::new(backend) })
+    }
+
+    pub fn file_meta(&self) -> &FileMeta {
+        match &*self.backend {
+            Backend::Local { file_meta, .. } => file_meta,
+            Backend::Remote(remote) => remote.file_meta(),
+        }
+    }
+
+    /// Streams all records with `fields` fetched. Remote files are only read
+    /// by regions.
+    pub fn records(&self, fields: &[Fields]) -> io::Result<RecordStream> {
+        if let Backend::Remote(_) = &*self.backend {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Remote files are read by regions."));
+        }
+        Ok(self.stream(None, fields))
+    }
+
+    /// Streams records overlapping region given as in samtools view, e.g.
+    /// `chr1:1,000-2,000`, with `fields` fetched.
+    pub fn fetch(&self, region: &str, fields: &[Fields]) -> io::Result<RecordStream> {
+        let region = Region::parse(region, self.file_meta().get_ref_seqs())?;
+        Ok(self.fetch_regions(&[region], fields))
+    }
+
+    /// Streams records overlapping any of `regions`, see
+    /// [`Reader::fetch_regions`].
+    pub fn fetch_regions(&self, regions: &[Region], fields: &[Fields]) -> RecordStream {
+        self.stream(Some(regions.to_vec()), fields)
+    }
+
+    fn stream(&self, regions: Option<Vec<Region>>, fields: &[Fields]) -> RecordStream {
+        let (tx, rx) = mpsc::channel(BATCHES_AHEAD);
+        let backend = self.backend.clone();
+        let fields = fields.to_vec();
+        task::spawn_blocking(move || match (&*backend, regions) {
+            (Backend::Local { storage, file_meta }, regions) => {
+                // Block lists of fields read are checked before records are.
+                let opened = file_meta
+                    .decode_blocks(fields.iter().chain([&Fields::Pos, &Fields::RawCigar]))
+                    .map_err(GbamError::from)
+                    .and_then(|()| Reader::new_with_storage(storage.clone(), None, ParsingTemplate::new(), file_meta, None));
+                let mut reader = match opened {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(e.into()));
+                        return;
+                    }
+                };
+                match regions {
+                    Some(regions) => match reader.region_record_ranges(&regions) {
+                        Ok(ranges) => send_records(reader.region_records_with(&fields, ranges), &tx),
+                        Err(e) => {
+                            let _ = tx.blocking_send(Err(e.into()));
+                        }
+                    },
+                    None => send_records(reader.records_with(&fields), &tx),
+                }
+            }
+            (Backend::Remote(remote), regions) => match block_on(remote.fetch_regions(&regions.unwrap_or_default(), &fields)) {
+                Ok(mut slice) => send_records(slice.records(), &tx),
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            },
+        });
+        RecordStream { rx, batch: Vec::new().into_iter() }
+    }
+}
+
+fn send_records(mut records: Records<'_>, tx: &mpsc::Sender<io::Result<Vec<GbamRecord>>>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(rec) = records.next_rec() {
+        batch.push(rec.clone());
+        // Send fails once the stream is dropped.
+        if batch.len() == BATCH_SIZE && tx.blocking_send(Ok(std::mem::take(&mut batch))).is_err() {
+            return;
+        }
+    }
+    if !batch.is_empty() {
+        let _ = tx.blocking_send(Ok(batch));
+    }
+}
+
+/// Stream of records of [`AsyncReader`]. Dropping it stops decoding.
+pub struct RecordStream {
+    rx: mpsc::Receiver<io::Result<Vec<GbamRecord>>>,
+    batch: std::vec::IntoIter<GbamRecord>,
+}
+
+impl RecordStream {
+    /// Next record, `None` after the last one.
+    pub async fn next(&mut self) -> Option<io::Result<GbamRecord>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = io::Result<GbamRecord>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(rec) = self.batch.next() {
+                return Poll::Ready(Some(Ok(rec)));
+            }
+            match ready!(self.rx.poll_recv(cx)) {
+                Some(Ok(batch)) => self.batch = batch.into_iter(),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use tempdir::TempDir;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t5M\t*\t0\t0\t*\t*\n\
+r2\t0\tchr1\t100\t60\t5M\t*\t0\t0\t*\t*\n\
+r3\t0\tchr1\t300\t60\t5M\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_async_reader() {
+        let dir = TempDir::new("gbam_async_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        write_sam(SAM, |sam_header, ref_seqs| {
+            Writer::new_no_stats(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), true)
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let reader = AsyncReader::open(path.to_str().unwrap()).await.unwrap();
+            let mut stream = reader.records(&[Fields::Pos]).unwrap();
+            let mut positions = Vec::new();
+            while let Some(rec) = stream.next().await {
+                positions.push(rec.unwrap().pos.unwrap());
+            }
+            assert_eq!(positions, vec![9, 99, 299]);
+
+            let mut stream = reader.fetch("chr1:101-400", &[Fields::ReadName]).unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap().read_name.unwrap(), b"r2\0");
+            assert_eq!(stream.next().await.unwrap().unwrap().read_name.unwrap(), b"r3\0");
+            assert!(stream.next().await.is_none());
+        });
+    }
+}
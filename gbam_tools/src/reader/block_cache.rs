@@ -0,0 +1,123 @@
+use bam_tools::record::fields::Fields;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Block cache which may be shared by readers of the same file on different
+/// threads.
+pub type SharedBlockCache = Arc<Mutex<BlockCache>>;
+
+/// Field, tag column and block number.
+type BlockKey = (Fields, Option<usize>, usize);
+
+/// LRU cache of decompressed blocks keyed by (field, tag column, block
+/// number), tag column is `None` for all fields but split tags (see
+/// [`crate::meta::TagColumnMeta`]). Holds
+/// blocks while their total size is within capacity. Buffers are shared with
+/// columns, so a hit costs no copy.
+pub struct BlockCache {
+    capacity: usize,
+    size: usize,
+    // Block and its last use tick.
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    // Tick of last use to block, the oldest first.
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    /// `capacity` in bytes of decompressed data.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn shared(capacity: usize) -> SharedBlockCache {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    pub fn get(&mut self, field: Fields, tag_col: Option<usize>, block_num: usize) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        match self.blocks.get_mut(&(field, tag_col, block_num)) {
+            Some((buffer, last_use)) => {
+                self.lru.remove(last_use);
+                *last_use = self.tick;
+                self.lru.insert(self.tick, (field, tag_col, block_num));
+                self.hits += 1;
+                Some(buffer.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Adds block, evicting least recently used ones. Blocks larger than
+    /// capacity are not cached.
+    pub fn insert(&mut self, field: Fields, tag_col: Option<usize>, block_num: usize, buffer: Arc<Vec<u8>>) {
+        if buffer.len() > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.size += buffer.len();
+        if let Some((old, last_use)) = self.blocks.insert((field, tag_col, block_num), (buffer, self.tick)) {
+            self.size -= old.len();
+            self.lru.remove(&last_use);
+        }
+        self.lru.insert(self.tick, (field, tag_col, block_num));
+        while self.size > self.capacity {
+            let (_, key) = self.lru.pop_first().unwrap();
+            let (evicted, _) = self.blocks.remove(&key).unwrap();
+            self.size -= evicted.len();
+        }
+    }
+
+    /// Bytes of cached blocks.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Numbers of lookups which found and did not find the block.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = BlockCache::new(10);
+        cache.insert(Fields::Pos, None, 0, Arc::new(vec![0; 4]));
+        cache.insert(Fields::Pos, None, 1, Arc::new(vec![1; 4]));
+        assert!(cache.get(Fields::Pos, None, 0).is_some());
+        // Block 1 is the least recently used one.
+        cache.insert(Fields::RefID, None, 0, Arc::new(vec![2; 4]));
+        assert!(cache.get(Fields::Pos, None, 1).is_none());
+        assert_eq!(cache.get(Fields::Pos, None, 0).unwrap()[0], 0);
+        assert_eq!(cache.get(Fields::RefID, None, 0).unwrap()[0], 2);
+        assert_eq!(cache.size(), 8);
+        cache.insert(Fields::RefID, None, 1, Arc::new(vec![3; 11]));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.hits_and_misses(), (3, 1));
+    }
+}
@@ -0,0 +1,128 @@
+use super::column::Inner;
+use bam_tools::record::fields::Fields;
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+/// Decompressed block of fixed sized column, see [`super::reader::Reader::column_chunks`].
+pub struct ColumnChunk<'a> {
+    pub field: Fields,
+    /// Number (in stored order) of the first record of the block.
+    pub first_record: usize,
+    /// Values as stored: little endian, `item_size` bytes each.
+    pub data: &'a [u8],
+    pub item_size: usize,
+}
+
+impl<'a> ColumnChunk<'a> {
+    /// Number of records of the block.
+    pub fn len(&self) -> usize {
+        self.data.len() / self.item_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Values of one byte fields, e.g. Mapq.
+    pub fn as_u8(&self) -> Option<&'a [u8]> {
+        (self.item_size == 1).then_some(self.data)
+    }
+
+    /// Values of two byte fields, e.g. Flags. Borrowed from the block unless
+    /// it is misaligned or the platform is big endian.
+    pub fn as_u16(&self) -> Option<Cow<'a, [u16]>> {
+        if self.item_size != 2 {
+            return None;
+        }
+        // Any bit pattern is a valid u16.
+        let (prefix, values, _) = unsafe { self.data.align_to::<u16>() };
+        if prefix.is_empty() && cfg!(target_endian = "little") {
+            return Some(Cow::Borrowed(values));
+        }
+        Some(Cow::Owned(self.data.chunks_exact(2).map(|value| u16::from_le_bytes(value.try_into().unwrap())).collect()))
+    }
+
+    /// Values of four byte fields, e.g. Pos. As [`ColumnChunk::as_u16`].
+    pub fn as_i32(&self) -> Option<Cow<'a, [i32]>> {
+        if self.item_size != 4 {
+            return None;
+        }
+        // Any bit pattern is a valid i32.
+        let (prefix, values, _) = unsafe { self.data.align_to::<i32>() };
+        if prefix.is_empty() && cfg!(target_endian = "little") {
+            return Some(Cow::Borrowed(values));
+        }
+        Some(Cow::Owned(self.data.chunks_exact(4).map(|value| i32::from_le_bytes(value.try_into().unwrap())).collect()))
+    }
+}
+
+/// Iterates over blocks of a column, see [`super::reader::Reader::column_chunks`].
+pub struct ColumnChunks {
+    inner: Inner,
+    item_size: usize,
+    next_block: usize,
+    first_record: usize,
+}
+
+impl ColumnChunks {
+    pub(crate) fn new(inner: Inner, item_size: usize) -> Self {
+        Self { inner, item_size, next_block: 0, first_record: 0 }
+    }
+
+    /// Number of blocks of the column.
+    pub fn blocks(&self) -> usize {
+        self.inner.blocks().len()
+    }
+
+    pub fn next_chunk(&mut self) -> Option<ColumnChunk<'_>> {
+        let block_num = self.next_block;
+        let numitems = self.inner.blocks().get(block_num)?.numitems as usize;
+        self.next_block += 1;
+        let first_record = self.first_record;
+        self.first_record += numitems;
+        let item_size = self.item_size;
+        let field = self.inner.field();
+        let data = self.inner.block_data(block_num).expect("Block can't be read.");
+        Some(ColumnChunk { field, first_record, data: &data[..numitems * item_size], item_size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+    use bam_tools::record::fields::Fields;
+
+    #[test]
+    fn test_column_chunks() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..5000 {
+            sam += &format!("r{}\t{}\tchr1\t{}\t{}\t5M\t*\t0\t0\t*\t*\n", i, if i % 2 == 0 { 0 } else { 16 }, i + 1, i % 61);
+        }
+        let reader = gbam_from_sam(&sam, ParsingTemplate::new());
+
+        let mut mapq = reader.column_chunks(Fields::Mapq).unwrap();
+        assert_eq!(mapq.blocks(), 1);
+        let mut hist = [0u64; 61];
+        let mut records = 0;
+        while let Some(chunk) = mapq.next_chunk() {
+            assert_eq!(chunk.first_record, records);
+            assert!(chunk.as_u16().is_none());
+            chunk.as_u8().unwrap().iter().for_each(|&mapq| hist[mapq as usize] += 1);
+            records += chunk.len();
+        }
+        assert_eq!(records, 5000);
+        assert_eq!(hist[0], 82);
+        assert_eq!(hist[60], 81);
+
+        let mut pos = reader.column_chunks(Fields::Pos).unwrap();
+        let mut all = Vec::new();
+        while let Some(chunk) = pos.next_chunk() {
+            all.extend_from_slice(&chunk.as_i32().unwrap());
+        }
+        assert_eq!(all, (0..5000).collect::<Vec<i32>>());
+        let mut flags = reader.column_chunks(Fields::Flags).unwrap();
+        let chunk = flags.next_chunk().unwrap();
+        assert_eq!(chunk.as_u16().unwrap()[..3], [0, 16, 0]);
+    }
+}
@@ -1,5 +1,8 @@
 use std::{collections::BTreeMap, io::Result, sync::Arc};
 
+use super::block_cache::SharedBlockCache;
+use super::io_stats::SharedIoStats;
+use super::prefetch::SharedPrefetcher;
 use super::reader::generate_block_treemap;
 use super::record::GbamRecord;
 use crate::SIZE_LIMIT;
@@ -7,10 +10,11 @@ use lzzzz::{lz4};
 use bam_tools::record::fields::Fields;
 use byteorder::{LittleEndian, ReadBytesExt};
 use flate2::write::GzDecoder;
-use memmap2::Mmap;
+use super::reader::Storage;
 use std::convert::TryFrom;
 
-use crate::{meta::FileMeta, Codecs};
+use crate::{meta::{BlockMeta, FileMeta}, Codecs};
+use super::tags::{entry_tag, RawEntries};
 
 // Contains fields needed both for fixed sized fields and variable sized fields.
 pub struct Inner {
@@ -19,21 +23,58 @@ pub struct Inner {
     range_begin: usize,
     range_end: usize,
     field: Fields,
-    buffer: Vec<u8>,
-    reader: Arc<Mmap>,
+    /// Tag column of RawTags or RawTagsLen field, see
+    /// [`crate::meta::TagColumnMeta`].
+    tag_col: Option<usize>,
+    /// Shared with block cache if it is used.
+    buffer: Arc<Vec<u8>>,
+    reader: Storage,
+    cache: Option<SharedBlockCache>,
+    stats: Option<SharedIoStats>,
+    prefetcher: Option<SharedPrefetcher>,
 }
 
 impl Inner {
-    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<Mmap>) -> Self {
+    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Storage, cache: Option<SharedBlockCache>, stats: Option<SharedIoStats>) -> Self {
         Inner {
             meta,
             range_begin: 0,
             range_end: 0,
             field,
-            buffer: Vec::<u8>::with_capacity(SIZE_LIMIT * 2),
+            tag_col: None,
+            buffer: Arc::new(Vec::<u8>::with_capacity(SIZE_LIMIT * 2)),
             reader,
+            cache,
+            stats,
+            prefetcher: None,
         }
     }
+
+    /// Takes blocks decompressed ahead by `prefetcher`.
+    pub(crate) fn with_prefetcher(mut self, prefetcher: Option<SharedPrefetcher>) -> Self {
+        self.prefetcher = prefetcher;
+        self
+    }
+
+    /// Reads tag column `tag_col` instead of the field.
+    pub(crate) fn with_tag_column(mut self, tag_col: usize) -> Self {
+        self.tag_col = Some(tag_col);
+        self
+    }
+
+    pub(crate) fn field(&self) -> Fields {
+        self.field
+    }
+
+    pub(crate) fn blocks(&self) -> &Vec<BlockMeta> {
+        self.meta.view_column_blocks(&self.field, self.tag_col)
+    }
+
+    /// Decompressed data of block `block_num`.
+    pub(crate) fn block_data(&mut self, block_num: usize) -> Result<&[u8]> {
+        fetch_block(self, block_num)?;
+        Ok(&self.buffer[..])
+    }
 }
 
 /// Defines how columns will operate. It is needed since variable sized fields
@@ -41,6 +82,11 @@ impl Inner {
 pub trait Column {
     // Fills GbamRecord field with data from corresponding BAM record.
     fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) ;
+    // Size of variable sized item in bytes, read from index column only. None
+    // for fixed sized columns.
+    fn item_len(&mut self, _item_num: usize) -> Option<usize> {
+        None
+    }
 }
 
 /// GBAM file column. Responsible for fetching data.
@@ -74,14 +120,14 @@ impl FixedColumn {
             return None;
         }
         // All blocks sizes are equal except maybe the last one since it's a fixed sized column and block size limit is constant.
-        let block_len = self.0.meta.view_blocks(&self.0.field)[0].numitems;
+        let block_len = self.0.blocks()[0].numitems;
         Some(item_num / block_len as usize)
     }
 
     fn update_buffer(inner: &mut Inner, block_num: usize) {
         fetch_block(inner, block_num).unwrap();
-        let block_len = inner.meta.view_blocks(&inner.field)[0].numitems as usize;
-        let cur_block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
+        let block_len = inner.blocks()[0].numitems as usize;
+        let cur_block_len = inner.blocks()[block_num].numitems as usize;
         inner.range_begin = block_num * block_len;
         inner.range_end = inner.range_begin + cur_block_len;
     }
@@ -99,12 +145,27 @@ impl Column for VariableColumn {
     fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
         rec.parse_from_bytes(&self.inner.field.clone(), self.get_item(item_num));
     }
+
+    fn item_len(&mut self, item_num: usize) -> Option<usize> {
+        // Data block is not fetched, its range is known from block treemap.
+        let range_begin = match self.find_block(item_num) {
+            Some((range_begin, _)) => range_begin,
+            None => self.inner.range_begin,
+        };
+        let mut read_offset =
+            |n| self.index.get_item(n).read_u32::<LittleEndian>().unwrap() as usize;
+        let start = match item_num - range_begin {
+            0 => 0,
+            _ => read_offset(item_num - 1),
+        };
+        Some(read_offset(item_num) - start)
+    }
 }
 
 impl VariableColumn {
     pub fn new(inner: Inner, index: FixedColumn) -> Self {
         Self {
-            blocks: generate_block_treemap(&inner.meta, &inner.field),
+            blocks: generate_block_treemap(inner.blocks()),
             inner,
             index,
         }
@@ -144,30 +205,112 @@ impl VariableColumn {
 
     fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) {
         fetch_block(inner, block_num).unwrap();
-        let block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
+        let block_len = inner.blocks()[block_num].numitems as usize;
         inner.range_begin = range_begin;
         inner.range_end = inner.range_begin + block_len;
     }
 }
 
+/// RawTags of files with tags stored in tag columns (see
+/// [`crate::meta::TagColumnMeta`]), or with only some tags selected (see
+/// [`super::reader::Reader::select_tags`]). Entries of tag columns follow
+/// the rest of the tags. RawTags column is read only if a selected tag is not
+/// in a tag column.
+pub struct TagsColumn {
+    rest: Option<VariableColumn>,
+    tag_cols: Vec<VariableColumn>,
+    selected: Option<Vec<[u8; 2]>>,
+    buf: Vec<u8>,
+}
+
+impl Column for TagsColumn {
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
+        let Self { rest, tag_cols, selected, buf } = self;
+        buf.clear();
+        if let Some(rest) = rest.as_mut() {
+            let data = rest.get_item(item_num);
+            match selected.as_ref() {
+                None => buf.extend_from_slice(data),
+                Some(selected) => RawEntries::new(data)
+                    .filter(|entry| matches!(entry_tag(entry), Some(tag) if selected.contains(&tag)))
+                    .for_each(|entry| buf.extend_from_slice(entry)),
+            }
+        }
+        for col in tag_cols.iter_mut() {
+            buf.extend_from_slice(col.get_item(item_num));
+        }
+        rec.parse_from_bytes(&Fields::RawTags, buf);
+    }
+}
+
+impl TagsColumn {
+    /// `new_col` creates column of RawTags or of tag column of given number.
+    pub fn new<F>(meta: &FileMeta, selected: Option<Vec<[u8; 2]>>, mut new_col: F) -> Self
+    where
+        F: FnMut(Option<usize>) -> VariableColumn,
+    {
+        let is_selected = |tag: &[u8; 2]| selected.as_ref().is_none_or(|selected| selected.contains(tag));
+        let tags: Vec<[u8; 2]> = meta.tag_columns().iter().map(|col| col.tag()).collect();
+        let rest_needed = match selected.as_ref() {
+            None => true,
+            Some(selected) => selected.iter().any(|tag| !tags.contains(tag)),
+        };
+        Self {
+            rest: rest_needed.then(|| new_col(None)),
+            tag_cols: (0..tags.len())
+                .filter(|&tag_col| is_selected(&tags[tag_col]))
+                .map(|tag_col| new_col(Some(tag_col)))
+                .collect(),
+            selected,
+            buf: Vec::new(),
+        }
+    }
+}
+
 /// Fetch and decompress a data block.
 fn fetch_block(inner_column: &mut Inner, block_num: usize) -> Result<()> {
     // println!("Fetching for {}", inner_column.field);
+    if let Some(cache) = inner_column.cache.as_ref() {
+        if let Some(buffer) = cache.lock().unwrap().get(inner_column.field, inner_column.tag_col, block_num) {
+            inner_column.buffer = buffer;
+            if let Some(stats) = inner_column.stats.as_ref() {
+                stats.lock().unwrap().add_cache_hit(inner_column.field);
+            }
+            return Ok(());
+        }
+    }
     let field = &inner_column.field;
-    let block_meta = inner_column.meta.view_blocks(field).get(block_num).unwrap();
-    let reader = &inner_column.reader;
+    let block_meta = inner_column.blocks().get(block_num).unwrap();
     let block_size = block_meta.block_size;
     let uncompressed_size = block_meta.uncompressed_size;
+    let codec = *inner_column.meta.get_column_codec(field, inner_column.tag_col);
 
-    let data =
-        &reader[usize::try_from(block_meta.seekpos).unwrap()..usize::try_from(block_meta.seekpos + block_size as u64).unwrap()];
-    // inner_column.buffer.clear();
-    // dbg!(uncompressed_size);
-    inner_column.buffer.resize(uncompressed_size as usize, 0);
-    let codec = inner_column.meta.get_field_codec(field);
+    let prefetched = inner_column
+        .prefetcher
+        .as_ref()
+        .and_then(|prefetcher| prefetcher.fetch((*field, inner_column.tag_col, block_num), inner_column.blocks(), codec));
+    if let Some(buffer) = prefetched {
+        inner_column.buffer = buffer;
+    } else {
+        let reader = (*inner_column.reader).as_ref();
+        let data =
+            &reader[usize::try_from(block_meta.seekpos).unwrap()..usize::try_from(block_meta.seekpos + block_size as u64).unwrap()];
+        // Buffer still held by the cache is not overwritten.
+        if Arc::get_mut(&mut inner_column.buffer).is_none() {
+            inner_column.buffer = Arc::new(Vec::with_capacity(uncompressed_size as usize));
+        }
+        let buffer = Arc::get_mut(&mut inner_column.buffer).unwrap();
+        buffer.resize(uncompressed_size as usize, 0);
 
-    if uncompressed_size > 0 {
-        decompress_block(data, &mut inner_column.buffer, codec).expect("Decompression failed.");
+        if uncompressed_size > 0 {
+            decompress_block(data, buffer, &codec).expect("Decompression failed.");
+        }
+    }
+    if let Some(stats) = inner_column.stats.as_ref() {
+        stats.lock().unwrap().add_read(*field, inner_column.tag_col, block_num, u64::from(block_size), uncompressed_size);
+    }
+    if let Some(cache) = inner_column.cache.as_ref() {
+        cache.lock().unwrap().insert(inner_column.field, inner_column.tag_col, block_num, inner_column.buffer.clone());
     }
     
     Ok(())
@@ -178,12 +321,14 @@ pub fn decompress_block(source: &[u8], dest: &mut Vec<u8>, codec: &Codecs) -> st
     use std::io::Write;
     match codec {
         Codecs::Gzip => {
+            dest.clear();
             let mut decoder = GzDecoder::new(dest);
-            decoder.write_all(source).unwrap();
-            decoder.try_finish().unwrap();
+            decoder.write_all(source)?;
+            decoder.try_finish()?;
         }
         Codecs::Lz4 => {
-            lz4::decompress(source, dest).unwrap();
+            let len = lz4::decompress(source, dest).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+            dest.truncate(len);
         }
         Codecs::NoCompression => {
             dest.clear();
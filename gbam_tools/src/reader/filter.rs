@@ -0,0 +1,475 @@
+use super::record::GbamRecord;
+use super::tags::TagValue;
+use crate::name_index::trim_nul;
+use bam_tools::record::fields::Fields;
+use std::cmp::Ordering;
+use std::io;
+use std::sync::Arc;
+
+/// Record filter compiled from expression as in samtools view -e, e.g.
+/// `mapq >= 30 && flag.paired && rname == "chr1"`. Only fields referenced
+/// by the expression are decoded to evaluate it (see [`RecordFilter::fields`]
+/// and [`super::reader::Reader::set_record_filter`]).
+///
+/// Supported are integer and string (single or double quoted) literals,
+/// `&&`, `||`, `!`, comparisons `== != < <= > >=`, arithmetic `+ - * /`,
+/// parentheses, tags as `[NM]` and these variables:
+///
+/// | variable | value |
+/// |---|---|
+/// | `qname` | read name |
+/// | `flag` | flags |
+/// | `flag.paired`, `flag.proper_pair`, `flag.unmap`, `flag.munmap`, `flag.reverse`, `flag.mreverse`, `flag.read1`, `flag.read2`, `flag.secondary`, `flag.qcfail`, `flag.dup`, `flag.supplementary` | flag bit, 0 or 1 |
+/// | `rname`, `tid` | reference sequence name (`*` if none) and ID |
+/// | `pos`, `endpos` | 1-based leftmost and rightmost aligned base |
+/// | `mapq` | map quality |
+/// | `mrname`, `mtid`, `mpos` | reference name, ID and 1-based position of mate |
+/// | `tlen` | template length |
+/// | `qlen`, `seq` | sequence length and sequence |
+/// | `rlen`, `ncigar` | reference bases covered by alignment and number of CIGAR operations |
+///
+/// Integers and non empty strings are true. Comparison of integer with
+/// string, missing tag or division by zero is false.
+#[derive(Clone, Debug)]
+pub struct RecordFilter {
+    expr: Expr,
+    fields: Vec<Fields>,
+    ref_names: Arc<Vec<String>>,
+}
+
+impl RecordFilter {
+    /// Parses `expr`. Reference sequence names are taken from `ref_seqs` of
+    /// the file to filter.
+    pub fn compile(expr: &str, ref_seqs: &[(String, u32)]) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid filter expression {}: {}", expr, msg));
+        let tokens = tokenize(expr).map_err(invalid)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let parsed = parser.or().map_err(invalid)?;
+        if let Some(token) = parser.tokens.get(parser.next) {
+            return Err(invalid(format!("unexpected {:?}.", token)));
+        }
+        let mut fields = Vec::new();
+        parsed.collect_fields(&mut fields);
+        Ok(Self {
+            expr: parsed,
+            fields,
+            ref_names: Arc::new(ref_seqs.iter().map(|(name, _)| name.clone()).collect()),
+        })
+    }
+
+    /// Fields which have to be filled in records passed to
+    /// [`RecordFilter::matches`].
+    pub fn fields(&self) -> &[Fields] {
+        &self.fields
+    }
+
+    pub fn matches(&self, rec: &GbamRecord) -> bool {
+        self.expr.eval(rec, &self.ref_names).is_true()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Var {
+    Qname,
+    Flag,
+    FlagBit(u16),
+    Rname,
+    Tid,
+    Pos,
+    Endpos,
+    Mapq,
+    Mrname,
+    Mtid,
+    Mpos,
+    Tlen,
+    Qlen,
+    Seq,
+    Rlen,
+    Ncigar,
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        let flag_bit = |bit| Some(Var::FlagBit(bit));
+        match name {
+            "qname" => Some(Var::Qname),
+            "flag" => Some(Var::Flag),
+            "flag.paired" => flag_bit(0x1),
+            "flag.proper_pair" => flag_bit(0x2),
+            "flag.unmap" => flag_bit(0x4),
+            "flag.munmap" => flag_bit(0x8),
+            "flag.reverse" => flag_bit(0x10),
+            "flag.mreverse" => flag_bit(0x20),
+            "flag.read1" => flag_bit(0x40),
+            "flag.read2" => flag_bit(0x80),
+            "flag.secondary" => flag_bit(0x100),
+            "flag.qcfail" => flag_bit(0x200),
+            "flag.dup" => flag_bit(0x400),
+            "flag.supplementary" => flag_bit(0x800),
+            "rname" => Some(Var::Rname),
+            "tid" => Some(Var::Tid),
+            "pos" => Some(Var::Pos),
+            "endpos" => Some(Var::Endpos),
+            "mapq" => Some(Var::Mapq),
+            "mrname" => Some(Var::Mrname),
+            "mtid" => Some(Var::Mtid),
+            "mpos" => Some(Var::Mpos),
+            "tlen" => Some(Var::Tlen),
+            "qlen" => Some(Var::Qlen),
+            "seq" => Some(Var::Seq),
+            "rlen" => Some(Var::Rlen),
+            "ncigar" => Some(Var::Ncigar),
+            _ => None,
+        }
+    }
+
+    fn fields(self) -> &'static [Fields] {
+        match self {
+            Var::Qname => &[Fields::ReadName],
+            Var::Flag | Var::FlagBit(_) => &[Fields::Flags],
+            Var::Rname | Var::Tid => &[Fields::RefID],
+            Var::Pos => &[Fields::Pos],
+            Var::Endpos => &[Fields::Pos, Fields::RawCigar],
+            Var::Mapq => &[Fields::Mapq],
+            Var::Mrname | Var::Mtid => &[Fields::NextRefID],
+            Var::Mpos => &[Fields::NextPos],
+            Var::Tlen => &[Fields::TemplateLength],
+            Var::Qlen | Var::Seq => &[Fields::RawSequence],
+            Var::Rlen | Var::Ncigar => &[Fields::RawCigar],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Int(i64),
+    Str(Vec<u8>),
+    Var(Var),
+    Tag([u8; 2]),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Null,
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl Value<'_> {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Int(value) => *value != 0,
+            Value::Str(value) => !value.is_empty(),
+        }
+    }
+}
+
+impl Expr {
+    fn collect_fields(&self, fields: &mut Vec<Fields>) {
+        match self {
+            Expr::Int(_) | Expr::Str(_) => {}
+            Expr::Var(var) => add_fields(fields, var.fields()),
+            Expr::Tag(_) => add_fields(fields, &[Fields::RawTags]),
+            Expr::Not(expr) | Expr::Neg(expr) => expr.collect_fields(fields),
+            Expr::And(left, right) | Expr::Or(left, right) | Expr::Bin(_, left, right) => {
+                left.collect_fields(fields);
+                right.collect_fields(fields);
+            }
+        }
+    }
+
+    fn eval<'a>(&'a self, rec: &'a GbamRecord, ref_names: &'a [String]) -> Value<'a> {
+        let bool_value = |value: bool| Value::Int(i64::from(value));
+        match self {
+            Expr::Int(value) => Value::Int(*value),
+            Expr::Str(value) => Value::Str(value),
+            Expr::Var(var) => eval_var(*var, rec, ref_names),
+            Expr::Tag(tag) => match rec.get_tag(tag) {
+                Some(TagValue::Int(value)) => Value::Int(value),
+                Some(TagValue::String(value)) => Value::Str(value),
+                _ => Value::Null,
+            },
+            Expr::Not(expr) => bool_value(!expr.eval(rec, ref_names).is_true()),
+            Expr::Neg(expr) => match expr.eval(rec, ref_names) {
+                Value::Int(value) => Value::Int(-value),
+                _ => Value::Null,
+            },
+            Expr::And(left, right) => bool_value(left.eval(rec, ref_names).is_true() && right.eval(rec, ref_names).is_true()),
+            Expr::Or(left, right) => bool_value(left.eval(rec, ref_names).is_true() || right.eval(rec, ref_names).is_true()),
+            Expr::Bin(op, left, right) => match (left.eval(rec, ref_names), right.eval(rec, ref_names)) {
+                (Value::Int(left), Value::Int(right)) => match op {
+                    BinOp::Add => Value::Int(left.wrapping_add(right)),
+                    BinOp::Sub => Value::Int(left.wrapping_sub(right)),
+                    BinOp::Mul => Value::Int(left.wrapping_mul(right)),
+                    BinOp::Div => left.checked_div(right).map_or(Value::Null, Value::Int),
+                    _ => compare(*op, left.cmp(&right)),
+                },
+                (Value::Str(left), Value::Str(right)) => compare(*op, left.cmp(right)),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+fn add_fields(fields: &mut Vec<Fields>, new: &[Fields]) {
+    for field in new {
+        if !fields.contains(field) {
+            fields.push(*field);
+        }
+    }
+}
+
+/// Result of comparison `op` of values ordered as `ordering`. Null for
+/// arithmetic operators, which apply to integers only.
+fn compare<'a>(op: BinOp, ordering: Ordering) -> Value<'a> {
+    let result = match op {
+        BinOp::Eq => ordering.is_eq(),
+        BinOp::Ne => ordering.is_ne(),
+        BinOp::Lt => ordering.is_lt(),
+        BinOp::Le => ordering.is_le(),
+        BinOp::Gt => ordering.is_gt(),
+        BinOp::Ge => ordering.is_ge(),
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => return Value::Null,
+    };
+    Value::Int(i64::from(result))
+}
+
+fn eval_var<'a>(var: Var, rec: &'a GbamRecord, ref_names: &'a [String]) -> Value<'a> {
+    let ref_name = |ref_id: i32| Value::Str(ref_names.get(ref_id as usize).map_or(&b"*"[..], |name| name.as_bytes()));
+    match var {
+        Var::Qname => Value::Str(trim_nul(rec.read_name.as_ref().unwrap())),
+        Var::Flag => Value::Int(i64::from(rec.flag.unwrap())),
+        Var::FlagBit(bit) => Value::Int(i64::from(rec.flag.unwrap() & bit != 0)),
+        Var::Rname => ref_name(rec.refid.unwrap()),
+        Var::Tid => Value::Int(i64::from(rec.refid.unwrap())),
+        Var::Pos => Value::Int(i64::from(rec.pos.unwrap()) + 1),
+        Var::Endpos => Value::Int(i64::from(rec.pos.unwrap()) + i64::from(rec.alignment_span())),
+        Var::Mapq => Value::Int(i64::from(rec.mapq.unwrap())),
+        Var::Mrname => ref_name(rec.next_ref_id.unwrap()),
+        Var::Mtid => Value::Int(i64::from(rec.next_ref_id.unwrap())),
+        Var::Mpos => Value::Int(i64::from(rec.next_pos.unwrap()) + 1),
+        Var::Tlen => Value::Int(i64::from(rec.tlen.unwrap())),
+        Var::Qlen => Value::Int(rec.seq.as_ref().unwrap().len() as i64),
+        Var::Seq => Value::Str(rec.seq.as_ref().unwrap().as_bytes()),
+        Var::Rlen => Value::Int(i64::from(rec.alignment_span())),
+        Var::Ncigar => Value::Int(rec.cigar.as_ref().unwrap().0.len() as i64),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(Vec<u8>),
+    Ident(String),
+    Tag([u8; 2]),
+    Op(&'static str),
+}
+
+const OPS: [&str; 17] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "+", "-", "*", "/", "=", "%"];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let bytes = expr.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            tokens.push(Token::Int(expr[start..i].parse().map_err(|_| format!("integer {} is too large.", &expr[start..i]))?));
+        } else if c == b'"' || c == b'\'' {
+            let end = bytes[i + 1..].iter().position(|&b| b == c).ok_or("unterminated string.")?;
+            tokens.push(Token::Str(bytes[i + 1..i + 1 + end].to_vec()));
+            i += end + 2;
+        } else if c == b'[' {
+            match bytes.get(i + 1..i + 4) {
+                Some(&[a, b, b']']) => tokens.push(Token::Tag([a, b])),
+                _ => return Err("tag has to be given as [XX].".to_owned()),
+            }
+            i += 4;
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(expr[start..i].to_owned()));
+        } else {
+            let op = OPS.iter().find(|op| bytes[i..].starts_with(op.as_bytes())).ok_or_else(|| format!("unexpected character {}.", c as char))?;
+            // `=` is accepted as `==`, `%` is not supported.
+            match *op {
+                "=" => tokens.push(Token::Op("==")),
+                "%" => return Err("operator % is not supported.".to_owned()),
+                op => tokens.push(Token::Op(op)),
+            }
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, from the lowest precedence: `||`, `&&`,
+/// comparisons, `+ -`, `* /`, unary `! -`.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, op: &str) -> bool {
+        self.eat_any(&[op]).is_some()
+    }
+
+    fn eat_any(&mut self, ops: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.next) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.next += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let expr = self.sum()?;
+        let op = match self.eat_any(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some("==") => BinOp::Eq,
+            Some("!=") => BinOp::Ne,
+            Some("<") => BinOp::Lt,
+            Some("<=") => BinOp::Le,
+            Some(">") => BinOp::Gt,
+            Some(">=") => BinOp::Ge,
+            _ => return Ok(expr),
+        };
+        Ok(Expr::Bin(op, Box::new(expr), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.eat_any(&["+", "-"]) {
+            let op = if op == "+" { BinOp::Add } else { BinOp::Sub };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.eat_any(&["*", "/"]) {
+            let op = if op == "*" { BinOp::Mul } else { BinOp::Div };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.eat_any(&["!", "-"]) {
+            Some("!") => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(_) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            None => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("missing ).".to_owned());
+            }
+            return Ok(expr);
+        }
+        let token = self.tokens.get(self.next).cloned().ok_or("unexpected end.")?;
+        self.next += 1;
+        match token {
+            Token::Int(value) => Ok(Expr::Int(value)),
+            Token::Str(value) => Ok(Expr::Str(value)),
+            Token::Tag(tag) => Ok(Expr::Tag(tag)),
+            Token::Ident(name) => Var::parse(&name).map(Expr::Var).ok_or_else(|| format!("unknown variable {}.", name)),
+            Token::Op(op) => Err(format!("unexpected {}.", op)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+
+    fn record() -> GbamRecord {
+        GbamRecord {
+            refid: Some(1),
+            pos: Some(99),
+            mapq: Some(40),
+            flag: Some(0x1 | 0x2 | 0x40),
+            next_ref_id: Some(-1),
+            next_pos: Some(-1),
+            read_name: Some(b"read1\0".to_vec()),
+            // 10M
+            cigar: Some(Cigar::new(vec![Op::new(10 << 4)])),
+            tags: Some(b"NMC\x02RGZgrp1\0".to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn matches(expr: &str) -> bool {
+        let ref_seqs = vec![("chr1".to_owned(), 1000), ("chr2".to_owned(), 1000)];
+        RecordFilter::compile(expr, &ref_seqs).unwrap().matches(&record())
+    }
+
+    #[test]
+    fn test_record_filter() {
+        assert!(matches("mapq>=30 && flag.paired && rname=='chr2'"));
+        assert!(!matches("mapq >= 30 && rname == \"chr1\""));
+        assert!(matches("!flag.dup && (flag.read2 || flag.read1)"));
+        assert!(matches("pos == 100 && endpos == 109 && rlen == pos - 90"));
+        assert!(matches("[NM] < 3 && [RG] == 'grp1' && qname == 'read1'"));
+        assert!(matches("![XS] && mrname == '*' && mpos == 0"));
+        assert!(!matches("[XS] > 0 || mapq / 0"));
+        assert!(matches("-tid + 2 == 1 && ncigar * 2 == 2"));
+
+        let filter = RecordFilter::compile("mapq > 1 && endpos > 0 || [NM]", &[]).unwrap();
+        assert_eq!(filter.fields(), &[Fields::Mapq, Fields::Pos, Fields::RawCigar, Fields::RawTags]);
+        for expr in ["mapq >", "mapq >= 'a", "(mapq", "bogus", "[N]", "mapq % 2", "1 2"] {
+            assert!(RecordFilter::compile(expr, &[]).is_err(), "{}", expr);
+        }
+    }
+}
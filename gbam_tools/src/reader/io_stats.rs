@@ -0,0 +1,165 @@
+use crate::meta::FileMeta;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Statistics which may be shared by readers of the same file on different
+/// threads.
+pub type SharedIoStats = Arc<Mutex<IoStats>>;
+
+/// Block reads of one field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FieldIoStats {
+    /// Blocks read from storage and decompressed.
+    pub blocks_read: u64,
+    /// Distinct blocks among them.
+    pub distinct_blocks: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    /// Blocks taken from block cache instead.
+    pub cache_hits: u64,
+}
+
+/// Per field counts of blocks and bytes read by columns of a reader, to see
+/// how much of the file a query touches. Reads of tag columns (see
+/// [`crate::meta::TagColumnMeta`]) count to RawTags and RawTagsLen. Blocks read more than once mean
+/// read amplification, e.g. random access without block cache.
+#[derive(Clone, Debug)]
+pub struct IoStats {
+    fields: Vec<FieldIoStats>,
+    // Field, tag column and block number.
+    seen: HashSet<(Fields, Option<usize>, usize)>,
+}
+
+impl Default for IoStats {
+    fn default() -> Self {
+        Self {
+            fields: vec![FieldIoStats::default(); FIELDS_NUM],
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl IoStats {
+    pub fn shared() -> SharedIoStats {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn field(&self, field: Fields) -> &FieldIoStats {
+        &self.fields[field as usize]
+    }
+
+    pub(crate) fn add_read(&mut self, field: Fields, tag_col: Option<usize>, block_num: usize, compressed: u64, uncompressed: u64) {
+        let stats = &mut self.fields[field as usize];
+        stats.blocks_read += 1;
+        stats.compressed_bytes += compressed;
+        stats.uncompressed_bytes += uncompressed;
+        if self.seen.insert((field, tag_col, block_num)) {
+            stats.distinct_blocks += 1;
+        }
+    }
+
+    pub(crate) fn add_cache_hit(&mut self, field: Fields) {
+        self.fields[field as usize].cache_hits += 1;
+    }
+
+    /// Sum over all fields.
+    pub fn total(&self) -> FieldIoStats {
+        self.fields.iter().fold(FieldIoStats::default(), |mut total, stats| {
+            total.blocks_read += stats.blocks_read;
+            total.distinct_blocks += stats.distinct_blocks;
+            total.compressed_bytes += stats.compressed_bytes;
+            total.uncompressed_bytes += stats.uncompressed_bytes;
+            total.cache_hits += stats.cache_hits;
+            total
+        })
+    }
+
+    /// Writes TSV of fields which were read, with their blocks and compressed
+    /// bytes in the file for comparison. Amplification is blocks read per
+    /// distinct block.
+    pub fn write_report(&self, out: &mut dyn Write, file_meta: &FileMeta) -> io::Result<()> {
+        writeln!(
+            out,
+            "field\tblocks_read\tdistinct_blocks\tfile_blocks\tcompressed_bytes\tfile_bytes\tuncompressed_bytes\tcache_hits\tamplification"
+        )?;
+        let mut file_total = (0, 0);
+        for &field in Fields::iterator() {
+            let stats = self.field(field);
+            let tag_cols = match field {
+                Fields::RawTags | Fields::RawTagsLen => file_meta.tag_columns().len(),
+                _ => 0,
+            };
+            let blocks: Vec<_> = std::iter::once(None)
+                .chain((0..tag_cols).map(Some))
+                .flat_map(|tag_col| file_meta.view_column_blocks(&field, tag_col))
+                .collect();
+            let file_bytes: u64 = blocks.iter().map(|block| u64::from(block.block_size)).sum();
+            file_total = (file_total.0 + blocks.len(), file_total.1 + file_bytes);
+            if stats.blocks_read + stats.cache_hits > 0 {
+                write_row(out, &field.to_string(), stats, blocks.len(), file_bytes)?;
+            }
+        }
+        write_row(out, "total", &self.total(), file_total.0, file_total.1)
+    }
+}
+
+fn write_row(out: &mut dyn Write, name: &str, stats: &FieldIoStats, file_blocks: usize, file_bytes: u64) -> io::Result<()> {
+    let amplification = if stats.distinct_blocks == 0 { 0.0 } else { stats.blocks_read as f64 / stats.distinct_blocks as f64 };
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+        name,
+        stats.blocks_read,
+        stats.distinct_blocks,
+        file_blocks,
+        stats.compressed_bytes,
+        file_bytes,
+        stats.uncompressed_bytes,
+        stats.cache_hits,
+        amplification
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::gbam_from_sam;
+    use rayon::prelude::*;
+
+    #[test]
+    fn test_io_stats() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:1000000\n");
+        for i in 0..3000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t5M\t*\t0\t0\tACGTA\t*\n", i, i + 1));
+        }
+        let mut reader = gbam_from_sam(&sam, ParsingTemplate::new_with(&[Fields::ReadName]));
+        let stats = IoStats::shared();
+        reader.set_io_stats(Some(stats.clone()));
+
+        let mut records = reader.records_with(&[Fields::Pos]);
+        while records.next_rec().is_some() {}
+        drop(records);
+        {
+            let stats = stats.lock().unwrap();
+            let pos = stats.field(Fields::Pos);
+            assert_eq!((pos.blocks_read, pos.distinct_blocks), (1, 1));
+            assert_eq!(pos.uncompressed_bytes, 3000 * 4);
+            assert_eq!(stats.field(Fields::ReadName).blocks_read, 0);
+            assert_eq!(stats.total(), pos.clone());
+        }
+
+        // Read name and its index column, counted by parallel readers too.
+        assert_eq!(reader.par_records().count(), 3000);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.field(Fields::ReadName).blocks_read, 1);
+        assert_eq!(stats.total().blocks_read, 3);
+        let mut out = Vec::new();
+        stats.write_report(&mut out, &reader.file_meta).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.lines().last().unwrap().starts_with("total\t3\t3\t"));
+    }
+}
@@ -1,5 +1,6 @@
 
 
+use crate::error::{GbamError, Result};
 use bam_tools::record::fields::{
     field_type, is_data_field, var_size_field_to_index, FieldType, Fields,
     FIELDS_NUM,
@@ -22,6 +23,12 @@ impl ParsingTemplate {
         }
     }
 
+    /// Builder of template which adds index fields of requested variable
+    /// sized fields, see [`TemplateBuilder`].
+    pub fn builder() -> TemplateBuilder {
+        TemplateBuilder::default()
+    }
+
     /// Create new parsing templates with passed fields set to active
     pub fn new_with(fields_to_set: &[Fields]) -> Self {
         let mut empty = Self::new();
@@ -135,6 +142,67 @@ impl Default for ParsingTemplate {
     }
 }
 
+/// Builds [`ParsingTemplate`] from requested and excluded fields, e.g.
+/// `ParsingTemplate::builder().with(Fields::RawCigar).build()`. Index fields
+/// of requested variable sized fields (e.g. NCigar of RawCigar) are added,
+/// so excluding one of them fails the build.
+#[derive(Clone, Debug, Default)]
+pub struct TemplateBuilder {
+    all: bool,
+    with: Vec<Fields>,
+    without: Vec<Fields>,
+}
+
+impl TemplateBuilder {
+    pub fn with(mut self, field: Fields) -> Self {
+        self.with.push(field);
+        self
+    }
+
+    pub fn with_fields(mut self, fields: &[Fields]) -> Self {
+        self.with.extend_from_slice(fields);
+        self
+    }
+
+    /// Requests all fields but excluded ones.
+    pub fn with_all(mut self) -> Self {
+        self.all = true;
+        self
+    }
+
+    pub fn without(mut self, field: Fields) -> Self {
+        self.without.push(field);
+        self
+    }
+
+    /// Fails if a field is both requested and excluded, or if index field
+    /// of a requested field is excluded.
+    pub fn build(self) -> Result<ParsingTemplate> {
+        let invalid = |msg: String| Err(GbamError::InvalidArgument(msg));
+        if let Some(field) = self.with.iter().find(|field| self.without.contains(field)) {
+            return invalid(format!("Field {} is both requested and excluded.", field));
+        }
+        let mut fields: Vec<Fields> = match self.all {
+            true => Fields::iterator().filter(|field| !self.without.contains(field)).copied().collect(),
+            false => self.with,
+        };
+        for i in 0..fields.len() {
+            let field = fields[i];
+            if !matches!(field_type(&field), FieldType::VariableSized) {
+                continue;
+            }
+            let index = var_size_field_to_index(&field);
+            if self.without.contains(&index) {
+                return invalid(format!("Field {} is read by its index field {}, which is excluded.", field, index));
+            }
+            if !fields.contains(&index) {
+                fields.push(index);
+            }
+        }
+        Ok(ParsingTemplate::new_with(&fields))
+    }
+}
+
 #[cfg(feature = "python-ffi")]
 #[pymethods]
 impl ParsingTemplate {
@@ -149,3 +217,22 @@ impl ParsingTemplate {
         tmplt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let template = ParsingTemplate::builder().with(Fields::RawCigar).with(Fields::Pos).build().unwrap();
+        assert_eq!(template.get_active_fields(), vec![Fields::Pos, Fields::RawCigar, Fields::NCigar]);
+        assert_eq!(template.get_active_data_fields_iter().copied().collect::<Vec<_>>(), vec![Fields::Pos, Fields::RawCigar]);
+
+        let template = ParsingTemplate::builder().with_all().without(Fields::RawQual).without(Fields::SequenceLength).build().unwrap();
+        assert!(!template.check_if_active(&[Fields::RawQual]) && template.check_if_active(&[Fields::RawSequence, Fields::RawSeqLen]));
+
+        let error = ParsingTemplate::builder().with_all().without(Fields::LName).build().unwrap_err();
+        assert_eq!(error.to_string(), "Field ReadName is read by its index field LName, which is excluded.");
+        assert!(ParsingTemplate::builder().with(Fields::Mapq).without(Fields::Mapq).build().is_err());
+    }
+}
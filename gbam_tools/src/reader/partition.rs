@@ -0,0 +1,144 @@
+use super::parse_tmplt::ParsingTemplate;
+use crate::meta::FileMeta;
+use bam_tools::record::fields::Fields;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Record range assigned to one scatter job. Boundaries are block boundaries
+/// of `fields` (see [`partition`]), so reading a partition decompresses only
+/// blocks overlapping its range.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub index: usize,
+    /// First record of the partition.
+    pub start: usize,
+    /// Record past the last one of the partition.
+    pub end: usize,
+    pub fields: Vec<Fields>,
+}
+
+impl Partition {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Splits records into at most `n` non-overlapping partitions of roughly
+/// equal size. Boundaries are record numbers where blocks of all fields
+/// active in `template` start. Fields are filled into blocks independently,
+/// so if there are too few such common boundaries, block boundaries of the
+/// field with the largest blocks (which still has at least `n` blocks) are
+/// used instead and blocks of other fields crossing a boundary are
+/// decompressed by both neighbouring partitions.
+pub fn partition(file_meta: &FileMeta, template: &ParsingTemplate, n: usize) -> Vec<Partition> {
+    assert!(n > 0, "Number of partitions must be positive.");
+    let fields = template.get_active_fields();
+    let mut boundaries = common_block_boundaries(file_meta, &fields);
+    if boundaries.len() <= n {
+        let block_num = |field: &&Fields| file_meta.view_blocks(field).len();
+        let coarsest = fields
+            .iter()
+            .filter(|field| block_num(field) >= n)
+            .min_by_key(block_num)
+            .or_else(|| fields.iter().max_by_key(block_num));
+        if let Some(field) = coarsest {
+            boundaries = block_starts(file_meta, field);
+        }
+    }
+    let total = *boundaries.last().unwrap();
+
+    let mut cuts = vec![0];
+    for i in 1..n {
+        let target = total * i / n;
+        let idx = boundaries.partition_point(|&b| b < target);
+        // Take the closest boundary to the target.
+        let cut = match (idx.checked_sub(1).map(|i| boundaries[i]), boundaries.get(idx)) {
+            (Some(before), Some(&after)) if target - before <= after - target => before,
+            (_, Some(&after)) => after,
+            (Some(before), None) => before,
+            (None, None) => unreachable!(),
+        };
+        if cut > *cuts.last().unwrap() && cut < total {
+            cuts.push(cut);
+        }
+    }
+    cuts.push(total);
+
+    cuts.windows(2)
+        .enumerate()
+        .map(|(index, w)| Partition {
+            index,
+            start: w[0],
+            end: w[1],
+            fields: fields.clone(),
+        })
+        .collect()
+}
+
+/// Sorted record numbers at which a block starts in every one of `fields`,
+/// including 0 and total number of records.
+fn common_block_boundaries(file_meta: &FileMeta, fields: &[Fields]) -> Vec<usize> {
+    // RefID is always written, so it gives record count for empty template.
+    let mut common = block_starts(file_meta, fields.first().unwrap_or(&Fields::RefID));
+    for field in fields.iter().skip(1) {
+        let starts = block_starts(file_meta, field);
+        common.retain(|b| starts.binary_search(b).is_ok());
+    }
+    common
+}
+
+fn block_starts(file_meta: &FileMeta, field: &Fields) -> Vec<usize> {
+    let mut starts = vec![0];
+    for block in file_meta.view_blocks(field) {
+        starts.push(starts.last().unwrap() + block.numitems as usize);
+    }
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{BlockMeta, Codecs};
+
+    fn push_blocks(meta: &mut FileMeta, field: Fields, sizes: &[u32]) {
+        for &numitems in sizes {
+            meta.get_blocks(&field).push(BlockMeta {
+                numitems,
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    fn test_partition_aligned_to_all_fields() {
+        let mut meta = FileMeta::new(&[Codecs::Lz4], Vec::new(), Vec::new());
+        push_blocks(&mut meta, Fields::RefID, &[100; 10]);
+        push_blocks(&mut meta, Fields::Pos, &[200; 5]);
+
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos]);
+        let parts = partition(&meta, &template, 3);
+        let ranges: Vec<_> = parts.iter().map(|p| p.range()).collect();
+        assert_eq!(ranges, vec![0..400, 400..600, 600..1000]);
+
+        // Only 0, 500 and 1000 are common boundaries with Flags blocks of 250.
+        push_blocks(&mut meta, Fields::Flags, &[250; 4]);
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Flags]);
+        let parts = partition(&meta, &template, 2);
+        let ranges: Vec<_> = parts.iter().map(|p| p.range()).collect();
+        assert_eq!(ranges, vec![0..500, 500..1000]);
+        assert_eq!(parts[1].index, 1);
+
+        // Not enough common boundaries, Flags blocks are the largest ones.
+        let parts = partition(&meta, &template, 4);
+        let ranges: Vec<_> = parts.iter().map(|p| p.range()).collect();
+        assert_eq!(ranges, vec![0..250, 250..500, 500..750, 750..1000]);
+    }
+}
@@ -0,0 +1,299 @@
+use super::reader::Reader;
+use super::record::GbamRecord;
+use super::regions::Region;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+const UNMAPPED: u16 = 0x4;
+
+/// Records of coordinate sorted file read ahead into reused buffers. First
+/// `len` buffers hold current records, the next one is the peeked record.
+struct Lookahead<'a> {
+    reader: &'a mut Reader,
+    next: usize,
+    end: usize,
+    buffers: Vec<GbamRecord>,
+    len: usize,
+    // (RefID, Pos) of peeked record.
+    peeked: Option<(i32, i32)>,
+    skip_unmapped: bool,
+}
+
+impl<'a> Lookahead<'a> {
+    fn new(reader: &'a mut Reader, skip_unmapped: bool) -> Self {
+        assert!(reader.is_coordinate_sorted(), "Records are not coordinate sorted.");
+        let end = reader.amount;
+        Self {
+            reader,
+            next: 0,
+            end,
+            buffers: Vec::new(),
+            len: 0,
+            peeked: None,
+            skip_unmapped,
+        }
+    }
+
+    /// Position of the next record. Iteration stops at unmapped records
+    /// without reference.
+    fn peek(&mut self) -> Option<(i32, i32)> {
+        while self.peeked.is_none() && self.next < self.end {
+            if !self.reader.passes_filters(self.next) {
+                self.next += 1;
+                continue;
+            }
+            if self.buffers.len() == self.len {
+                self.buffers.push(GbamRecord::default());
+            }
+            let rec = &mut self.buffers[self.len];
+            self.reader.fill_record(self.next, rec);
+            let ref_id = rec.refid.unwrap();
+            if ref_id < 0 {
+                self.next = self.end;
+            } else if self.skip_unmapped && rec.flag.unwrap() & UNMAPPED != 0 {
+                self.next += 1;
+            } else {
+                self.peeked = Some((ref_id, rec.pos.unwrap()));
+            }
+        }
+        self.peeked
+    }
+
+    /// Appends peeked record to current ones.
+    fn take(&mut self) {
+        debug_assert!(self.peeked.is_some());
+        self.peeked = None;
+        self.next += 1;
+        self.len += 1;
+    }
+
+    /// Keeps current records for which `keep` is true, in the same order.
+    fn retain(&mut self, mut keep: impl FnMut(&GbamRecord) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if keep(&self.buffers[i]) {
+                self.buffers.swap(kept, i);
+                kept += 1;
+            }
+        }
+        if self.peeked.is_some() {
+            self.buffers.swap(kept, self.len);
+        }
+        self.len = kept;
+    }
+
+    /// Drops current records and continues with `records`.
+    fn reset(&mut self, records: Range<usize>) {
+        self.len = 0;
+        self.peeked = None;
+        self.next = records.start;
+        self.end = records.end;
+    }
+
+    fn current(&self) -> &[GbamRecord] {
+        &self.buffers[..self.len]
+    }
+}
+
+/// Records starting in the same window of reference positions.
+pub struct PositionGroup<'a> {
+    pub ref_id: i32,
+    /// 0-based start of the window.
+    pub start: i32,
+    pub records: &'a [GbamRecord],
+}
+
+/// Iterates over groups of records which start in the same window of
+/// `window` bases. See [`Reader::position_groups`].
+pub struct PositionGroups<'a> {
+    records: Lookahead<'a>,
+    window: i32,
+}
+
+impl<'a> PositionGroups<'a> {
+    pub(crate) fn new(reader: &'a mut Reader, window: u32) -> Self {
+        assert!(window > 0, "Window has to be positive.");
+        Self {
+            records: Lookahead::new(reader, false),
+            window: window as i32,
+        }
+    }
+
+    pub fn next_group(&mut self) -> Option<PositionGroup<'_>> {
+        self.records.retain(|_| false);
+        let window = self.window;
+        let window_of = |pos: i32| pos - pos.rem_euclid(window);
+        let (ref_id, pos) = self.records.peek()?;
+        let start = window_of(pos);
+        while let Some((next_ref_id, next_pos)) = self.records.peek() {
+            if next_ref_id != ref_id || window_of(next_pos) != start {
+                break;
+            }
+            self.records.take();
+        }
+        Some(PositionGroup {
+            ref_id,
+            start,
+            records: self.records.current(),
+        })
+    }
+}
+
+/// Records overlapping one reference position.
+pub struct PileupColumn<'a> {
+    pub ref_id: i32,
+    /// 0-based position.
+    pub pos: i32,
+    /// Records in file order.
+    pub records: &'a [GbamRecord],
+}
+
+/// Moving pileup column: visits every reference position covered by at
+/// least one mapped record. See [`Reader::pileup_columns`] and
+/// [`Reader::pileup_regions`].
+pub struct PileupColumns<'a> {
+    records: Lookahead<'a>,
+    ref_id: i32,
+    pos: i32,
+    // Record ranges still to scan, with region columns are restricted to.
+    ranges: VecDeque<(Range<usize>, Option<Region>)>,
+    region: Option<Region>,
+}
+
+impl<'a> PileupColumns<'a> {
+    pub(crate) fn new(reader: &'a mut Reader) -> Self {
+        let all = 0..reader.amount;
+        Self::new_in_regions(reader, vec![(all, None)])
+    }
+
+    /// Columns inside regions, each scanned over its range of records.
+    pub(crate) fn new_in_regions(reader: &'a mut Reader, ranges: Vec<(Range<usize>, Option<Region>)>) -> Self {
+        let mut records = Lookahead::new(reader, true);
+        records.reset(0..0);
+        Self {
+            records,
+            ref_id: 0,
+            pos: 0,
+            ranges: ranges.into(),
+            region: None,
+        }
+    }
+
+    pub fn next_column(&mut self) -> Option<PileupColumn<'_>> {
+        while !self.advance() {
+            let (range, region) = self.ranges.pop_front()?;
+            self.records.reset(range);
+            self.region = region;
+        }
+        Some(PileupColumn {
+            ref_id: self.ref_id,
+            pos: self.pos,
+            records: self.records.current(),
+        })
+    }
+
+    /// Moves to the next column of current range, false if there is none.
+    fn advance(&mut self) -> bool {
+        loop {
+            if !self.records.current().is_empty() {
+                let mut pos = self.pos + 1;
+                if let Some(region) = self.region {
+                    // Jump over positions before the region, up to the next
+                    // record start.
+                    let ref_id = self.ref_id;
+                    let next_start = self.records.peek().filter(|next| next.0 == ref_id).map_or(i32::MAX, |next| next.1);
+                    pos = pos.max((region.start as i32).min(next_start));
+                }
+                self.pos = pos;
+                let pos = i64::from(pos);
+                self.records.retain(|rec| alignment_end(rec) > pos);
+            }
+            if self.records.current().is_empty() {
+                match self.records.peek() {
+                    Some((ref_id, pos)) => (self.ref_id, self.pos) = (ref_id, pos),
+                    None => return false,
+                }
+            }
+            while self.records.peek() == Some((self.ref_id, self.pos)) {
+                self.records.take();
+            }
+            match self.region {
+                None => return true,
+                Some(region) if self.ref_id != region.ref_id || self.pos >= region.end as i32 => return false,
+                Some(region) if self.pos >= region.start as i32 => return true,
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// Exclusive end, records without reference bases occupy one base.
+fn alignment_end(rec: &GbamRecord) -> i64 {
+    i64::from(rec.pos.unwrap()) + i64::from(rec.alignment_span().max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader, regions::Region};
+    use crate::test_util::gbam_from_sam;
+    use bam_tools::record::fields::Fields;
+
+    // 0-based spans: r1 [9, 12), r2 [10, 11), r3 [11, 13), r5 [20, 22), r6 chr2 [4, 5).
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t3M\t*\t0\t0\t*\t*\n\
+r2\t0\tchr1\t11\t60\t1M\t*\t0\t0\t*\t*\n\
+r3\t0\tchr1\t12\t60\t1M1D\t*\t0\t0\t*\t*\n\
+r4\t4\tchr1\t12\t0\t*\t*\t0\t0\t*\t*\n\
+r5\t0\tchr1\t21\t60\t2M\t*\t0\t0\t*\t*\n\
+r6\t0\tchr2\t5\t60\t1M\t*\t0\t0\t*\t*\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    fn reader() -> Reader {
+        gbam_from_sam(SAM, ParsingTemplate::new_with(&[Fields::ReadName]))
+    }
+
+    fn names(records: &[crate::reader::record::GbamRecord]) -> String {
+        records
+            .iter()
+            .map(|rec| String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    #[test]
+    fn test_position_groups() {
+        let mut reader = reader();
+        let mut groups = reader.position_groups(10);
+        let mut res = Vec::new();
+        while let Some(group) = groups.next_group() {
+            res.push((group.ref_id, group.start, names(group.records)));
+        }
+        assert_eq!(
+            res,
+            vec![(0, 0, "r1".to_owned()), (0, 10, "r2,r3,r4".to_owned()), (0, 20, "r5".to_owned()), (1, 0, "r6".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_pileup_columns() {
+        let mut reader = reader();
+        let mut columns = reader.pileup_columns();
+        let mut res = Vec::new();
+        while let Some(column) = columns.next_column() {
+            res.push(format!("{}:{} {}", column.ref_id, column.pos, names(column.records)));
+        }
+        assert_eq!(res, vec!["0:9 r1", "0:10 r1,r2", "0:11 r1,r3", "0:12 r3", "0:20 r5", "0:21 r5", "1:4 r6"]);
+    }
+
+    #[test]
+    fn test_pileup_regions() {
+        let mut reader = reader();
+        let mut columns = reader.pileup_regions(&[Region::new(0, 21, 30), Region::new(0, 9, 10), Region::new(0, 11, 12), Region::new(1, 0, 4)]).unwrap();
+        let mut res = Vec::new();
+        while let Some(column) = columns.next_column() {
+            res.push(format!("{}:{} {}", column.ref_id, column.pos, names(column.records)));
+        }
+        // r1 overlaps two regions and is piled up in both.
+        assert_eq!(res, vec!["0:9 r1", "0:11 r1,r3", "0:21 r5"]);
+    }
+}
@@ -0,0 +1,147 @@
+use super::column::decompress_block;
+use super::reader::Storage;
+use crate::meta::{BlockMeta, Codecs};
+use bam_tools::record::fields::Fields;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Field, tag column and block number.
+pub(crate) type BlockKey = (Fields, Option<usize>, usize);
+
+/// Background decompression shared by columns of a reader, see
+/// [`super::reader::Reader::set_decompression_threads`].
+pub type SharedPrefetcher = Arc<Prefetcher>;
+
+enum Slot {
+    Pending,
+    Ready(Arc<Vec<u8>>),
+    // Decompressed again by the column, which reports the error. Also
+    // set if decompression panicked.
+    Failed,
+}
+
+/// State shared with decompression tasks.
+struct Blocks {
+    storage: Storage,
+    slots: Mutex<HashMap<BlockKey, Slot>>,
+    ready: Condvar,
+}
+
+/// Decompresses blocks which columns are going to read on a pool of
+/// threads. A column fetching block N schedules blocks N+1 to N+`ahead` of
+/// its field and takes block N if it was scheduled before. Blocks behind the
+/// fetched one are dropped, so at most `ahead` blocks per column are held.
+pub struct Prefetcher {
+    pool: ThreadPool,
+    ahead: usize,
+    blocks: Arc<Blocks>,
+}
+
+impl Prefetcher {
+    pub fn new(storage: Storage, threads: usize, ahead: usize) -> io::Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("gbam-decompress-{}", i))
+            .build()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Self {
+            pool,
+            ahead,
+            blocks: Arc::new(Blocks { storage, slots: Mutex::new(HashMap::new()), ready: Condvar::new() }),
+        })
+    }
+
+    /// Schedules blocks following `key` of its column, `blocks` are blocks
+    /// of the column, and takes block `key`, waiting while it is being
+    /// decompressed. None if the block wasn't scheduled or failed.
+    pub(crate) fn fetch(&self, key: BlockKey, blocks: &[BlockMeta], codec: Codecs) -> Option<Arc<Vec<u8>>> {
+        let (field, tag_col, block_num) = key;
+        let mut slots = self.blocks.slots.lock().unwrap();
+        slots.retain(|&(other_field, other_tag_col, other_num), _| other_field != field || other_tag_col != tag_col || other_num >= block_num);
+        for (next, block) in blocks.iter().enumerate().skip(block_num + 1).take(self.ahead) {
+            let next_key = (field, tag_col, next);
+            if slots.contains_key(&next_key) {
+                continue;
+            }
+            slots.insert(next_key, Slot::Pending);
+            let range = block.seekpos as usize..(block.seekpos + u64::from(block.block_size)) as usize;
+            let shared = self.blocks.clone();
+            let uncompressed_size = block.uncompressed_size as usize;
+            self.pool.spawn(move || shared.decompress(next_key, range, uncompressed_size, codec));
+        }
+        loop {
+            match slots.get(&key) {
+                None => return None,
+                Some(Slot::Pending) => slots = self.blocks.ready.wait(slots).unwrap(),
+                Some(_) => match slots.remove(&key) {
+                    Some(Slot::Ready(buffer)) => return Some(buffer),
+                    _ => return None,
+                },
+            }
+        }
+    }
+}
+
+impl Blocks {
+    fn decompress(&self, key: BlockKey, range: Range<usize>, uncompressed_size: usize, codec: Codecs) {
+        // A panic would leave the slot pending and its column waiting
+        // forever (and abort the process from the pool thread).
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut buffer = vec![0; uncompressed_size];
+            match (*self.storage).as_ref().get(range) {
+                None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Some(_) if uncompressed_size == 0 => Ok(buffer),
+                Some(data) => decompress_block(data, &mut buffer, &codec).map(|()| buffer),
+            }
+        }));
+        let mut slots = self.slots.lock().unwrap();
+        // Not stored if the column has moved past the block meanwhile.
+        if let Some(slot) = slots.get_mut(&key) {
+            *slot = match result {
+                Ok(Ok(buffer)) => Slot::Ready(Arc::new(buffer)),
+                Ok(Err(_)) | Err(_) => Slot::Failed,
+            };
+            self.ready.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefetch() {
+        let data: Vec<u8> = (0..40).collect();
+        let blocks: Vec<BlockMeta> = (0..4)
+            .map(|i| BlockMeta { seekpos: i * 10, numitems: 10, block_size: 10, uncompressed_size: 10, ..BlockMeta::default() })
+            .chain(std::iter::once(BlockMeta { seekpos: 100, numitems: 10, block_size: 10, uncompressed_size: 10, ..BlockMeta::default() }))
+            .collect();
+        let prefetcher = Prefetcher::new(Arc::new(data), 2, 2).unwrap();
+        let fetch = |block_num| prefetcher.fetch((Fields::Mapq, None, block_num), &blocks, Codecs::NoCompression);
+
+        // The first block is decompressed by the column, next ones ahead.
+        assert!(fetch(0).is_none());
+        assert_eq!(fetch(1).unwrap()[..], (10..20).collect::<Vec<u8>>()[..]);
+        assert_eq!(fetch(3).unwrap()[..], (30..40).collect::<Vec<u8>>()[..]);
+        // Block 2 was dropped when the column moved past it.
+        assert!(fetch(2).is_none());
+        // Block 4 is out of file.
+        assert!(fetch(4).is_none());
+        assert!(prefetcher.blocks.slots.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_panic() {
+        // Buffer of this size can't be allocated, so decompression panics.
+        let blocks = vec![BlockMeta { block_size: 10, uncompressed_size: u64::MAX, ..BlockMeta::default() }; 2];
+        let prefetcher = Prefetcher::new(Arc::new(vec![0; 10]), 1, 1).unwrap();
+        let fetch = |block_num| prefetcher.fetch((Fields::Mapq, None, block_num), &blocks, Codecs::NoCompression);
+        assert!(fetch(0).is_none());
+        assert!(fetch(1).is_none());
+    }
+}
@@ -1,26 +1,78 @@
 use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::{borrow::Borrow, fs::File};
+use std::fs::TryLockError;
+use std::path::Path;
+use std::fs::File;
 
 use bam_tools::record::fields::{
     field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
 use byteorder::LittleEndian;
+use rayon::prelude::*;
 use memmap2::MmapOptions;
 use memmap2::Mmap;
+use rand::{rngs::StdRng, SeedableRng};
 
-use crate::meta::{FileInfo, FileMeta, FILE_INFO_SIZE, BlockMeta};
+use crate::error::{GbamError, Result};
+use crate::header::SamHeader;
+use crate::meta::{decode_meta, BlockMeta, Codecs, FileInfo, FileMeta, FILE_INFO_SIZE};
+use crate::name_index::trim_nul;
+use crate::query::mates::{is_mate, MATE_FIELDS};
+use crate::reference::{read_fasta_checksums, validate_reference, ReferenceMismatch};
 use crate::writer::calc_crc_for_meta_bytes;
 
 use super::{
-    column::{Column, FixedColumn, Inner, VariableColumn},
+    block_cache::SharedBlockCache,
+    chunks::ColumnChunks,
+    io_stats::SharedIoStats,
+    column::{Column, FixedColumn, Inner, TagsColumn, VariableColumn},
+    filter::RecordFilter,
     parse_tmplt::ParsingTemplate,
+    partition::{partition, Partition},
+    prefetch::{Prefetcher, SharedPrefetcher},
+    position_groups::{PileupColumns, PositionGroups},
     record::GbamRecord,
     records::Records,
+    regions::{overlapping_ranges, region_ranges, Region, RegionStart},
+    templates::Templates,
 };
 
 use std::convert::TryFrom;
 
+/// Attempts to read footer while it is being swapped by a writer, see
+/// [`Reader::open_shared`].
+const FOOTER_READ_ATTEMPTS: usize = 100;
+
+/// Bytes of GBAM file: memory mapped file or in-memory buffer.
+pub type Storage = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Blocks and bytes of a column, see [`Reader::stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnSize {
+    pub field: Fields,
+    /// Tag of tag column (see [`crate::meta::TagColumnMeta`]).
+    pub tag: Option<[u8; 2]>,
+    pub codec: Codecs,
+    pub blocks: usize,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+}
+
+impl ColumnSize {
+    /// Uncompressed bytes per compressed byte, None for empty column.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.compressed_bytes > 0).then(|| self.uncompressed_bytes as f64 / self.compressed_bytes as f64)
+    }
+}
+
+/// Records and sizes of columns of GBAM file, see [`Reader::stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileStats {
+    pub records: usize,
+    /// Columns of all fields, then tag columns.
+    pub columns: Vec<ColumnSize>,
+}
+
 pub struct Reader {
     // Instead of hashmap. Empty columns will contain None.
     pub columns: Vec<Option<Box<dyn Column + Send>>>,
@@ -29,27 +81,75 @@ pub struct Reader {
     pub amount: usize,
     pub file_meta: Arc<FileMeta>,
     // Kept so File won't drop while used by mmap.
-    _inner: Box<File>,
-    index_mapping: Option<Arc<Vec<u32>>>,
-    pub mmap: Arc<Mmap>
+    _inner: Option<Box<File>>,
+    pub(crate) index_mapping: Option<Arc<Vec<u32>>>,
+    pub storage: Storage,
+    // Longest alignment span, computed on first region fetch.
+    max_span: Option<u32>,
+    block_cache: Option<SharedBlockCache>,
+    io_stats: Option<SharedIoStats>,
+    // Required and excluded flag bits, see `set_flag_filter`.
+    flag_filter: Option<(u16, u16)>,
+    // See `set_min_mapq`.
+    min_mapq: Option<u8>,
+    // See `set_record_filter`.
+    record_filter: Option<RecordFilter>,
+    filter_buf: GbamRecord,
+    // See `select_tags`.
+    selected_tags: Option<Vec<[u8; 2]>>,
+    // See `set_decompression_threads`.
+    prefetcher: Option<SharedPrefetcher>,
 }
 
 impl Reader {
-    pub fn new(inner: File, parsing_template: ParsingTemplate) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
-        let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), None)
+    pub fn new(inner: File, parsing_template: ParsingTemplate) -> Result<Self> {
+        Self::new_with_index(inner, parsing_template, None)
     }
 
-    pub fn new_with_index(inner: File, parsing_template: ParsingTemplate, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
-        let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), index_mapping)
+    /// Opens file which other processes may append to or update meta of
+    /// meanwhile. Writers hold exclusive lock during update and do not modify
+    /// bytes of the current version of the file: new blocks and meta are
+    /// written after its end and then file info at the beginning is pointed
+    /// to the new meta with one write (footer swap). The reader takes shared
+    /// lock while it reads file info and meta, so it never sees a torn
+    /// footer. If an update is in progress, footer is read without lock and
+    /// reading is retried when it catches the swap. The reader sees the
+    /// version of the file at open, which stays intact while it is used.
+    pub fn open_shared<P: AsRef<Path>>(path: P, parsing_template: ParsingTemplate) -> Result<Self> {
+        let file = File::open(path)?;
+        let locked = match file.try_lock_shared() {
+            Ok(()) => true,
+            Err(TryLockError::WouldBlock) => false,
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        };
+        let mut attempts = 0;
+        let (storage, file_meta) = loop {
+            let storage: Storage = Arc::new(unsafe { Mmap::map(&file)? });
+            match read_storage_footer(&storage) {
+                Ok((_, file_meta)) => break (storage, file_meta),
+                Err(_) if !locked && attempts < FOOTER_READ_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let reader = Self::new_with_storage(storage, Some(Box::new(file.try_clone()?)), parsing_template, &Arc::new(file_meta), None)?;
+        if locked {
+            file.unlock()?;
+        }
+        Ok(reader)
     }
 
-    pub fn new_with_meta(_inner: File, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
+    /// Block lists of meta are decoded only for columns which are read,
+    /// from the mapped file.
+    pub fn new_with_index(inner: File, parsing_template: ParsingTemplate, index_mapping: Option<Arc<Vec<u32>>>) -> Result<Self> {
+        let storage: Storage = Arc::new(unsafe { Mmap::map(&inner)? });
+        let (_, file_meta) = read_storage_footer(&storage)?;
+        Self::new_with_storage(storage, Some(Box::new(inner)), parsing_template, &Arc::new(file_meta), index_mapping)
+    }
+
+    pub fn new_with_meta(_inner: File, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> Result<Self> {
         let _copy = _inner.try_clone()?;
         let _inner: Box<File> = Box::new(_inner);
         
@@ -57,23 +157,54 @@ impl Reader {
         // mmap.advise(memmap2::Advice::WillNeed)?;
         // Consumes up to 16 percent of runtime on big files (20GB).
         // verify(&mmap)?;
+        Self::new_with_storage(mmap, Some(_inner), parsing_template, file_meta, index_mapping)
+    }
+
+    /// Reads GBAM file held in memory, e.g. produced by [`crate::Writer`]
+    /// over `Cursor<Vec<u8>>`.
+    pub fn from_bytes<B>(bytes: B, parsing_template: ParsingTemplate) -> Result<Self>
+    where
+        B: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let storage: Storage = Arc::new(bytes);
+        let (_, file_meta) = read_storage_footer(&storage)?;
+        Self::new_with_storage(storage, None, parsing_template, &Arc::new(file_meta), None)
+    }
+
+    /// Fails if block lists of fields of `parsing_template` are damaged.
+    pub(crate) fn new_with_storage(storage: Storage, _inner: Option<Box<File>>, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> Result<Self> {
+        file_meta.decode_blocks(parsing_template.get_active_fields_iter().chain([&Fields::RefID]))?;
+        Ok(Self::with_decoded_meta(storage, _inner, parsing_template, file_meta, index_mapping))
+    }
+
+    /// Same as [`Reader::new_with_storage`], block lists of fields of
+    /// `parsing_template` are decoded already.
+    fn with_decoded_meta(storage: Storage, _inner: Option<Box<File>>, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> Self {
         let amount = usize::try_from(file_meta
             .view_blocks(&Fields::RefID)
             .iter()
             .fold(0, |acc: u64, x| acc + u64::from(x.numitems))).unwrap();
         let meta = file_meta.clone();
 
-        
-        Ok(Self {
-            columns: init_columns(&mmap, &parsing_template, &meta),
+        Self {
+            columns: init_columns(&storage, &parsing_template, &meta, &None, &None, &None, &None),
             original_template: parsing_template.clone(),
             parsing_template,
             file_meta: meta,
             amount,
             _inner,
-            mmap,
-            index_mapping: index_mapping.clone(),
-        })
+            storage,
+            index_mapping,
+            max_span: None,
+            block_cache: None,
+            io_stats: None,
+            flag_filter: None,
+            min_mapq: None,
+            record_filter: None,
+            filter_buf: GbamRecord::default(),
+            selected_tags: None,
+            prefetcher: None,
+        }
     }
 
     #[inline(always)]
@@ -90,10 +221,23 @@ impl Reader {
         }
     }
 
-    pub fn get_column(&mut self, field: &Fields) -> &mut Box<dyn Column + Send> {
+    /// Size in bytes of variable sized `field` of record, read from its index
+    /// column without decompressing data, e.g. read length by RawQual. The
+    /// column has to be in template (possibly paused by
+    /// [`Reader::fetch_only`]). None for fixed sized fields.
+    pub fn field_len(&mut self, field: &Fields, mut rec_num: usize) -> Option<usize> {
+        if let Some(index_map) = &self.index_mapping {
+            rec_num = index_map[rec_num] as usize;
+        }
+        assert!(rec_num < self.amount);
+        self.columns[*field as usize].as_mut()?.item_len(rec_num)
+    }
+
+    /// Column of `field`, which has to be in parsing template.
+    pub fn get_column(&mut self, field: &Fields) -> Result<&mut Box<dyn Column + Send>> {
         self.columns[*field as usize]
             .as_mut()
-            .unwrap()
+            .ok_or_else(|| GbamError::InvalidArgument(format!("Field {} is not in parsing template.", field)))
     }
 
     // Temporarily disable fetching for fields which are not needed
@@ -109,47 +253,695 @@ impl Reader {
         self.parsing_template = self.original_template.clone();
     }
 
+    /// Skips records unless all bits of `include_mask` and none of
+    /// `exclude_mask` are set in their flags, as samtools view -f and -F.
+    /// Flags are checked before other fields are decoded, so blocks of heavy
+    /// columns holding only skipped records are not decompressed. Applies to
+    /// record iterators of the reader ([`Reader::records`],
+    /// [`Reader::fetch_regions`], [`Reader::position_groups`],
+    /// [`Reader::par_records`] etc.), not to [`Reader::fill_record`]. Masks
+    /// (0, 0) disable filtering.
+    pub fn set_flag_filter(&mut self, include_mask: u16, exclude_mask: u16) {
+        self.flag_filter = Some((include_mask, exclude_mask)).filter(|&masks| masks != (0, 0));
+        if self.flag_filter.is_some() {
+            self.init_missing_columns(&[Fields::Flags]);
+        }
+    }
+
+    /// Skips records with map quality lower than `min_mapq`. Map quality is
+    /// checked before other fields are decoded and applies to the same
+    /// iterators as [`Reader::set_flag_filter`]. 0 disables filtering.
+    pub fn set_min_mapq(&mut self, min_mapq: u8) {
+        self.min_mapq = Some(min_mapq).filter(|&mapq| mapq > 0);
+        if self.min_mapq.is_some() {
+            self.init_missing_columns(&[Fields::Mapq]);
+        }
+    }
+
+    /// Skips records which do not match `filter` (see [`RecordFilter`]).
+    /// Fields referenced by the filter are decoded before other fields, after
+    /// flags and map quality of [`Reader::set_flag_filter`] and
+    /// [`Reader::set_min_mapq`], and it applies to the same iterators. `None`
+    /// disables filtering.
+    pub fn set_record_filter(&mut self, filter: Option<RecordFilter>) {
+        if let Some(filter) = filter.as_ref() {
+            self.init_missing_columns(filter.fields());
+        }
+        self.record_filter = filter;
+    }
+
+    fn has_filters(&self) -> bool {
+        self.flag_filter.is_some() || self.min_mapq.is_some() || self.record_filter.is_some()
+    }
+
+    /// Record passes filters set by [`Reader::set_flag_filter`],
+    /// [`Reader::set_min_mapq`] and [`Reader::set_record_filter`].
+    pub(crate) fn passes_filters(&mut self, mut rec_num: usize) -> bool {
+        if !self.has_filters() {
+            return true;
+        }
+        if let Some(index_map) = &self.index_mapping {
+            rec_num = index_map[rec_num] as usize;
+        }
+        if let Some((include, exclude)) = self.flag_filter {
+            self.columns[Fields::Flags as usize]
+                .as_mut()
+                .unwrap()
+                .fill_record_field(rec_num, &mut self.filter_buf);
+            let flag = self.filter_buf.flag.unwrap();
+            if flag & include != include || flag & exclude != 0 {
+                return false;
+            }
+        }
+        if let Some(min_mapq) = self.min_mapq {
+            self.columns[Fields::Mapq as usize]
+                .as_mut()
+                .unwrap()
+                .fill_record_field(rec_num, &mut self.filter_buf);
+            if self.filter_buf.mapq.unwrap() < min_mapq {
+                return false;
+            }
+        }
+        if let Some(filter) = self.record_filter.as_ref() {
+            for &field in filter.fields() {
+                self.columns[field as usize]
+                    .as_mut()
+                    .unwrap()
+                    .fill_record_field(rec_num, &mut self.filter_buf);
+            }
+            return filter.matches(&self.filter_buf);
+        }
+        true
+    }
+
+    /// SAM header stored at conversion, as text and parsed records.
+    pub fn header(&self) -> SamHeader {
+        SamHeader::from_bam_header(self.file_meta.get_sam_header())
+    }
+
+    /// Compares reference sequences of the file with `fasta` (see
+    /// [`crate::reference::validate_reference`]), e.g. to detect use with
+    /// another reference build. Returns differences, empty if they match.
+    pub fn validate_reference<P: AsRef<Path>>(&self, fasta: P) -> Result<Vec<ReferenceMismatch>> {
+        Ok(validate_reference(&self.file_meta, &read_fasta_checksums(fasta)?))
+    }
+
+    /// User defined entries stored by
+    /// [`crate::writer::Writer::set_metadata`].
+    pub fn metadata(&self) -> &BTreeMap<String, serde_json::Value> {
+        self.file_meta.metadata()
+    }
+
+    /// Number of records and blocks, bytes and codec of every column, e.g.
+    /// to see which columns take most of the file. Only meta is read.
+    pub fn stats(&self) -> FileStats {
+        let meta = &self.file_meta;
+        let tag_cols = meta.tag_columns().iter().enumerate().flat_map(|(col, tag_col)| {
+            [(Fields::RawTags, Some(col), Some(tag_col.tag())), (Fields::RawTagsLen, Some(col), Some(tag_col.tag()))]
+        });
+        let columns = Fields::iterator()
+            .map(|field| (*field, None, None))
+            .chain(tag_cols)
+            .map(|(field, tag_col, tag)| {
+                let blocks = meta.view_column_blocks(&field, tag_col);
+                ColumnSize {
+                    field,
+                    tag,
+                    codec: *meta.get_column_codec(&field, tag_col),
+                    blocks: blocks.len(),
+                    compressed_bytes: blocks.iter().map(|block| u64::from(block.block_size)).sum(),
+                    uncompressed_bytes: blocks.iter().map(|block| block.uncompressed_size).sum(),
+                }
+            })
+            .collect();
+        FileStats { records: self.amount, columns }
+    }
+
+    /// Caches decompressed blocks of all columns in `cache` (see
+    /// [`super::block_cache::BlockCache`]), or stops caching if `None`.
+    /// Random access over hot regions, e.g. repeated [`Reader::lower_bound`]
+    /// or region fetches, then decompresses every block once while it fits.
+    /// The cache may be shared by readers of the same file only.
+    pub fn set_block_cache(&mut self, cache: Option<SharedBlockCache>) {
+        self.block_cache = cache;
+        self.reinit_columns();
+    }
+
+    /// Counts blocks and bytes read by columns per field in `stats` (see
+    /// [`super::io_stats::IoStats`]), or stops counting if `None`. Readers
+    /// created by [`Reader::par_records`] count into the same stats.
+    pub fn set_io_stats(&mut self, stats: Option<SharedIoStats>) {
+        self.io_stats = stats;
+        self.reinit_columns();
+    }
+
+    /// Fills only `tags` into RawTags of records, `None` fills all tags.
+    /// Columns of tags stored apart (see [`crate::Writer::set_tag_columns`])
+    /// which are not selected are not read, RawTags column is read only if
+    /// some selected tag is not stored apart.
+    pub fn select_tags(&mut self, tags: Option<&[[u8; 2]]>) {
+        self.selected_tags = tags.map(<[[u8; 2]]>::to_vec);
+        self.reinit_columns();
+    }
+
+    /// Decompresses up to `blocks` blocks ahead of reads of every column on
+    /// `threads` background threads, so sequential scans are not bound by
+    /// decompression on the calling thread. 0 threads disables it. Readers
+    /// of [`Reader::par_record_batches`] decompress on their own.
+    pub fn set_decompression_threads(&mut self, threads: usize, blocks: usize) -> Result<()> {
+        self.prefetcher = match threads {
+            0 => None,
+            _ => Some(Arc::new(Prefetcher::new(self.storage.clone(), threads, blocks)?)),
+        };
+        self.reinit_columns();
+        Ok(())
+    }
+
+    fn reinit_columns(&mut self) {
+        for &field in Fields::iterator() {
+            if self.columns[field as usize].is_some() {
+                self.columns[field as usize] = Some(init_col(field, &self.storage, &self.file_meta, &self.block_cache, &self.io_stats, &self.selected_tags, &self.prefetcher));
+            }
+        }
+    }
+
     /// Get iterator over all GBAM records (according to parsing template).
     pub fn records(&mut self) -> Records {
         Records::new(self)
     }
+
+    /// Get iterator over all GBAM records with only `fields` fetched, e.g.
+    /// `reader.records_with(&[Fields::Pos, Fields::RawCigar])`. Fields do not
+    /// have to be in parsing template. Template is restored when the iterator
+    /// is dropped. The record is reused between calls, so scanning does not
+    /// allocate per record.
+    pub fn records_with(&mut self, fields: &[Fields]) -> Records<'_> {
+        self.init_missing_columns(fields);
+        let saved_template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(fields));
+        Records::new(self).restoring(saved_template)
+    }
+
+    /// As [`Reader::records_with`], over records in `range` only.
+    pub fn range_records_with(&mut self, fields: &[Fields], range: std::ops::Range<usize>) -> Records<'_> {
+        self.init_missing_columns(fields);
+        let saved_template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(fields));
+        Records::new_in_range(self, range).restoring(saved_template)
+    }
+
+    /// As [`Reader::records_with`], over records of every range overlapping
+    /// its region. See [`Reader::region_record_ranges`].
+    pub fn region_records_with(&mut self, fields: &[Fields], ranges: Vec<(std::ops::Range<usize>, Region)>) -> Records<'_> {
+        let mut fields = fields.to_vec();
+        fields.extend_from_slice(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        self.init_missing_columns(&fields);
+        let saved_template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(&fields));
+        Records::new_in_regions(self, ranges).restoring(saved_template)
+    }
+
+    /// Get iterator over decompressed blocks of fixed sized `field`, e.g.
+    /// `reader.column_chunks(Fields::Mapq)`, for scans which don't need
+    /// records. Blocks are in stored order, index mapping and filters are not
+    /// applied. The field doesn't have to be in parsing template. Fails for
+    /// variable sized fields.
+    pub fn column_chunks(&self, field: Fields) -> Result<ColumnChunks> {
+        let size = match (field_type(&field), self.file_meta.get_field_size(&field)) {
+            (FieldType::FixedSized, Some(size)) => *size as usize,
+            _ => return Err(GbamError::InvalidArgument(format!("Field {} is not fixed sized.", field))),
+        };
+        self.file_meta.decode_blocks([&field])?;
+        let inner = Inner::new(self.file_meta.clone(), field, self.storage.clone(), self.block_cache.clone(), self.io_stats.clone()).with_prefetcher(self.prefetcher.clone());
+        Ok(ColumnChunks::new(inner, size))
+    }
+
+    /// Parallel iterator over all records (according to parsing template),
+    /// decoded on rayon worker threads. Records are in file order when
+    /// collected. See [`Reader::par_record_batches`].
+    pub fn par_records(&self) -> impl ParallelIterator<Item = GbamRecord> {
+        self.par_record_batches().flat_map_iter(|batch| batch)
+    }
+
+    /// Parallel iterator over batches of consecutive records, in file order.
+    /// Batches are aligned to blocks of the field with the largest blocks in
+    /// parsing template, every batch is decoded by its own reader on a worker
+    /// thread, so blocks are decompressed in parallel.
+    pub fn par_record_batches(&self) -> impl IndexedParallelIterator<Item = Vec<GbamRecord>> {
+        let batch_num = self
+            .parsing_template
+            .get_active_fields()
+            .iter()
+            .map(|field| self.file_meta.view_blocks(field).len())
+            .min()
+            .unwrap_or_else(|| self.file_meta.view_blocks(&Fields::RefID).len())
+            .max(1);
+        let partitions = partition(&self.file_meta, &self.parsing_template, batch_num);
+        let storage = self.storage.clone();
+        let template = self.parsing_template.clone();
+        let file_meta = self.file_meta.clone();
+        let index_mapping = self.index_mapping.clone();
+        let (include, exclude) = self.flag_filter.unwrap_or((0, 0));
+        let min_mapq = self.min_mapq.unwrap_or(0);
+        let record_filter = self.record_filter.clone();
+        let io_stats = self.io_stats.clone();
+        let selected_tags = self.selected_tags.clone();
+        partitions.into_par_iter().map(move |partition| {
+            // Meta is shared with this reader, which decoded its block lists.
+            let mut reader = Self::with_decoded_meta(storage.clone(), None, template.clone(), &file_meta, index_mapping.clone());
+            reader.set_flag_filter(include, exclude);
+            reader.set_min_mapq(min_mapq);
+            reader.set_record_filter(record_filter.clone());
+            if io_stats.is_some() {
+                reader.set_io_stats(io_stats.clone());
+            }
+            if selected_tags.is_some() {
+                reader.select_tags(selected_tags.as_deref());
+            }
+            let mut batch = Vec::new();
+            for rec_num in partition.range() {
+                if reader.passes_filters(rec_num) {
+                    let mut rec = GbamRecord::default();
+                    reader.fill_record(rec_num, &mut rec);
+                    batch.push(rec);
+                }
+            }
+            batch
+        })
+    }
+
+    /// Get iterator over records of one partition (see [`partition`]).
+    pub fn partition_records(&mut self, partition: &Partition) -> Records<'_> {
+        Records::new_in_range(self, partition.range())
+    }
+
+    /// Picks `n` records uniformly at random (all records if there are not
+    /// more) and returns them in file order. Records are filled in order of
+    /// record numbers, so every block is decompressed at most once and at
+    /// most `n` blocks per field are touched: a cheap base for estimates of
+    /// insert size, error rate or base qualities on large files. Equal `seed`
+    /// gives equal sample.
+    pub fn sample(&mut self, n: usize, seed: u64) -> Vec<GbamRecord> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rec_nums = rand::seq::index::sample(&mut rng, self.amount, n.min(self.amount)).into_vec();
+        rec_nums.sort_unstable();
+        rec_nums
+            .into_iter()
+            .map(|rec_num| {
+                let mut rec = GbamRecord::default();
+                self.fill_record(rec_num, &mut rec);
+                rec
+            })
+            .collect()
+    }
+
+    /// Numbers (in stored order, index mapping is not applied) of records
+    /// named `name`, terminating NUL is optional. With name index (see
+    /// [`crate::name_index::NameIndex`]) only ranges of records which filter
+    /// may hold the name are scanned, otherwise all records are.
+    pub fn find_by_name(&mut self, name: &[u8]) -> Result<Vec<usize>> {
+        let name = trim_nul(name);
+        let amount = self.amount as u64;
+        let (mut ranges, indexed) = match self.file_meta.name_index() {
+            Some(index) => (index.candidate_ranges((*self.storage).as_ref(), name)?, index.records().min(amount)),
+            None => (Vec::new(), 0),
+        };
+        ranges.push(indexed..amount);
+
+        self.init_missing_columns(&[Fields::ReadName]);
+        let template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(&[Fields::ReadName]));
+        let index_mapping = self.index_mapping.take();
+        let mut rec = GbamRecord::default();
+        let mut found = Vec::new();
+        for rec_num in ranges.into_iter().flat_map(|range| range.start..range.end.min(amount)) {
+            self.fill_record(rec_num as usize, &mut rec);
+            if trim_nul(rec.read_name.as_ref().unwrap()) == name {
+                found.push(rec_num as usize);
+            }
+        }
+        self.parsing_template = template;
+        self.index_mapping = index_mapping;
+        Ok(found)
+    }
+
+    /// Mate of paired `rec`: primary record with the same read name and the
+    /// other segment (0x40/0x80) flag, filled as parsing template says. `rec`
+    /// has to contain at least ReadName, Flags, NextRefID and NextPos. If
+    /// records are stored coordinate sorted, the mate is looked up at
+    /// NextRefID/NextPos first. Otherwise, or if it is not there, it is
+    /// looked up by name (see [`Reader::find_by_name`]), which scans all
+    /// records of files without name index. None if `rec` is not paired or
+    /// its mate is not in the file.
+    pub fn find_mate(&mut self, rec: &GbamRecord) -> Result<Option<GbamRecord>> {
+        if !rec.is_paired() {
+            return Ok(None);
+        }
+        self.init_missing_columns(&MATE_FIELDS);
+        let template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(&MATE_FIELDS));
+        // Lookups are done in stored order.
+        let index_mapping = self.index_mapping.take();
+        let found = self.find_mate_num(rec);
+        self.parsing_template = template;
+        let found = found.map(|rec_num| {
+            rec_num.map(|rec_num| {
+                let mut mate = GbamRecord::default();
+                self.fill_record(rec_num, &mut mate);
+                mate
+            })
+        });
+        self.index_mapping = index_mapping;
+        found
+    }
+
+    /// Stored order number of mate of `rec`, see [`Reader::find_mate`].
+    fn find_mate_num(&mut self, rec: &GbamRecord) -> Result<Option<usize>> {
+        let (next_ref_id, next_pos) = (rec.next_ref_id.unwrap(), rec.next_pos.unwrap());
+        let mut candidate = GbamRecord::default();
+        if next_ref_id >= 0 && self.file_meta.may_be_coordinate_sorted() {
+            let mut rec_num = self.lower_bound(next_ref_id, next_pos)?;
+            while rec_num < self.amount {
+                self.fill_record(rec_num, &mut candidate);
+                if candidate.refid != Some(next_ref_id) || candidate.pos != Some(next_pos) {
+                    break;
+                }
+                if is_mate(rec, &candidate) {
+                    return Ok(Some(rec_num));
+                }
+                rec_num += 1;
+            }
+        }
+        for rec_num in self.find_by_name(rec.read_name.as_ref().unwrap())? {
+            self.fill_record(rec_num, &mut candidate);
+            if is_mate(rec, &candidate) {
+                return Ok(Some(rec_num));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get iterator over records overlapping any of `regions` (e.g. read
+    /// from BED). Regions are merged and visited in file order, so blocks
+    /// shared by nearby regions are decompressed once and every record is
+    /// returned once. RefID, Pos and RawCigar are fetched in addition to the
+    /// parsing template. Records have to be coordinate sorted. Scan starts
+    /// are taken from interval index if the file has one, otherwise records
+    /// are looked back over the longest alignment span of the file. Fails if
+    /// records are not coordinate sorted.
+    pub fn fetch_regions(&mut self, regions: &[Region]) -> Result<Records<'_>> {
+        self.add_fields(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        let ranges = self.region_record_ranges(regions)?;
+        Ok(Records::new_in_regions(self, ranges))
+    }
+
+    /// Record ranges which may hold records overlapping each of `regions`,
+    /// as iterated by [`Reader::fetch_regions`].
+    pub fn region_record_ranges(&mut self, regions: &[Region]) -> Result<Vec<(std::ops::Range<usize>, Region)>> {
+        let file_meta = self.file_meta.clone();
+        match file_meta.interval_index().filter(|_| self.index_mapping.is_none()) {
+            Some(index) => region_ranges(self, regions, RegionStart::Index(index)),
+            None => {
+                let max_span = self.max_span();
+                region_ranges(self, regions, RegionStart::Lookback(max_span))
+            }
+        }
+    }
+
+    /// Get iterator over records overlapping region given as in samtools
+    /// view: `chr1`, `chr1:1000` (to the end of reference sequence) or
+    /// `chr1:1,000-2,000` (1-based, inclusive). See [`Reader::fetch_regions`].
+    pub fn fetch(&mut self, region: &str) -> Result<Records<'_>> {
+        let region = Region::parse(region, self.file_meta.get_ref_seqs())?;
+        self.fetch_regions(&[region])
+    }
+
+    /// Range of records starting inside `region`. Records have to be
+    /// coordinate sorted.
+    pub fn region_range(&mut self, region: &Region) -> Result<std::ops::Range<usize>> {
+        Ok(self.lower_bound(region.ref_id, region.start as i32)?..self.lower_bound(region.ref_id, region.end as i32)?)
+    }
+
+    /// Number of records passing filters (see [`Reader::set_flag_filter`],
+    /// [`Reader::set_min_mapq`] and [`Reader::set_record_filter`]) and
+    /// overlapping any of `regions` if given, as samtools view -c. Only
+    /// fields of filters are decoded: without filters and regions the count
+    /// comes from file meta, and records starting inside a region are counted
+    /// by position bounds. Pos and RawCigar are decoded only for records
+    /// starting before a region. Regions need coordinate sorted records.
+    pub fn count_records(&mut self, regions: Option<&[Region]>) -> Result<usize> {
+        let regions = match regions {
+            Some(regions) => regions,
+            None if !self.has_filters() => return Ok(self.amount),
+            None => return Ok((0..self.amount).filter(|&rec_num| self.passes_filters(rec_num)).count()),
+        };
+        let file_meta = self.file_meta.clone();
+        let ranges = match file_meta.interval_index().filter(|_| self.index_mapping.is_none()) {
+            Some(index) => region_ranges(self, regions, RegionStart::Index(index))?,
+            None => {
+                let max_span = self.max_span();
+                region_ranges(self, regions, RegionStart::Lookback(max_span))?
+            }
+        };
+        let fields = [Fields::RefID, Fields::Pos, Fields::RawCigar];
+        self.init_missing_columns(&fields);
+        let mut count = 0;
+        let mut rec = GbamRecord::default();
+        for (range, region) in ranges {
+            // Records of the range start before the region end on its
+            // reference sequence, so those starting inside it overlap it.
+            let inside = self.lower_bound(region.ref_id, region.start as i32)?.clamp(range.start, range.end);
+            let saved_template = std::mem::replace(&mut self.parsing_template, ParsingTemplate::new_with(&fields));
+            for rec_num in range.start..inside {
+                if self.passes_filters(rec_num) {
+                    self.fill_record(rec_num, &mut rec);
+                    count += usize::from(region.overlaps(&rec));
+                }
+            }
+            self.parsing_template = saved_template;
+            count += if self.has_filters() {
+                (inside..range.end).filter(|&rec_num| self.passes_filters(rec_num)).count()
+            } else {
+                range.end - inside
+            };
+        }
+        Ok(count)
+    }
+
+    /// Get iterator over groups of records starting in the same window of
+    /// `window` reference bases (1 for records with equal start). RefID and
+    /// Pos are fetched in addition to the parsing template. Iteration stops
+    /// at unmapped records without reference. Records have to be coordinate
+    /// sorted.
+    pub fn position_groups(&mut self, window: u32) -> PositionGroups<'_> {
+        self.add_fields(&[Fields::RefID, Fields::Pos]);
+        PositionGroups::new(self, window)
+    }
+
+    /// Get iterator over pileup columns: records overlapping every covered
+    /// reference position, as a base for pileup and consensus tools. Records
+    /// with unmapped flag are skipped. RefID, Pos, Flags and RawCigar are
+    /// fetched in addition to the parsing template. Records have to be
+    /// coordinate sorted.
+    pub fn pileup_columns(&mut self) -> PileupColumns<'_> {
+        self.add_fields(&[Fields::RefID, Fields::Pos, Fields::Flags, Fields::RawCigar]);
+        PileupColumns::new(self)
+    }
+
+    /// Get iterator over pileup columns inside `regions`, see
+    /// [`Reader::pileup_columns`]. Regions are merged and visited in file
+    /// order. Scan starts are found as in [`Reader::fetch_regions`], which
+    /// fails the same way.
+    pub fn pileup_regions(&mut self, regions: &[Region]) -> Result<PileupColumns<'_>> {
+        self.add_fields(&[Fields::RefID, Fields::Pos, Fields::Flags, Fields::RawCigar]);
+        let file_meta = self.file_meta.clone();
+        let ranges = match file_meta.interval_index().filter(|_| self.index_mapping.is_none()) {
+            Some(index) => overlapping_ranges(self, regions, RegionStart::Index(index))?,
+            None => {
+                let max_span = self.max_span();
+                overlapping_ranges(self, regions, RegionStart::Lookback(max_span))?
+            }
+        };
+        let ranges = ranges.into_iter().map(|(range, region)| (range, Some(region))).collect();
+        Ok(PileupColumns::new_in_regions(self, ranges))
+    }
+
+    /// Get iterator over templates: records grouped by read name, primary
+    /// alignments first (see [`super::templates::Template`]). Records of a
+    /// template have to be adjacent (e.g. name sorted or collated) in files
+    /// which are not coordinate sorted. Coordinate sorted ones need name
+    /// index (see [`crate::name_index::store_name_index`]) and templates come
+    /// in order of their first records. Records are read in stored order,
+    /// ReadName and Flags are fetched in addition to the parsing template.
+    pub fn templates(&mut self) -> Result<Templates<'_>> {
+        Ok(Templates::new(self)?)
+    }
+
+    /// Splits records into at most `n` partitions aligned to block boundaries
+    /// of fields in parsing template. Alignment holds for stored order only,
+    /// i.e. when no index mapping is used.
+    pub fn partitions(&self, n: usize) -> Vec<Partition> {
+        partition(&self.file_meta, &self.original_template, n)
+    }
+
+    /// Records are read in coordinate order: either the file is sorted or
+    /// index from coordinate index sort is used. See
+    /// [`FileMeta::may_be_coordinate_sorted`].
+    pub fn is_coordinate_sorted(&self) -> bool {
+        self.index_mapping.is_some() || self.file_meta.may_be_coordinate_sorted()
+    }
+
+    /// Returns number of the first record which (RefID, Pos) is not less than
+    /// passed one. Unmapped records (RefID -1) are expected to be at the end.
+    /// Fails if records are not coordinate sorted (see
+    /// [`Reader::is_coordinate_sorted`]).
+    pub fn lower_bound(&mut self, ref_id: i32, pos: i32) -> Result<usize> {
+        if !self.is_coordinate_sorted() {
+            return Err(GbamError::InvalidArgument("Records are not coordinate sorted.".to_owned()));
+        }
+        let key = |ref_id: i32, pos: i32| (if ref_id < 0 { i32::MAX } else { ref_id }, pos);
+        let target = key(ref_id, pos);
+
+        self.init_missing_columns(&[Fields::RefID, Fields::Pos]);
+        let saved_template = std::mem::replace(
+            &mut self.parsing_template,
+            ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos]),
+        );
+
+        let mut rec = GbamRecord::default();
+        let (mut left, mut right) = self.stats_bounds(ref_id, pos);
+        while left < right {
+            let mid = (left + right) / 2;
+            self.fill_record(mid, &mut rec);
+            if key(rec.refid.unwrap(), rec.pos.unwrap()) < target {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+
+        self.parsing_template = saved_template;
+        Ok(left)
+    }
+
+    /// Range of records holding the first record which (RefID, Pos) is not
+    /// less than passed one, narrowed with min/max stats of RefID and Pos
+    /// blocks. All records if stats are missing or index mapping is used.
+    pub(crate) fn stats_bounds(&self, ref_id: i32, pos: i32) -> (usize, usize) {
+        let all = (0, self.amount);
+        let ref_blocks = self.file_meta.view_blocks(&Fields::RefID);
+        let pos_blocks = self.file_meta.view_blocks(&Fields::Pos);
+        if self.index_mapping.is_some() || ref_id < 0 || ref_blocks.len() != pos_blocks.len() {
+            return all;
+        }
+        let target = (ref_id, pos);
+        let (mut left, mut start) = (0, 0);
+        for (ref_block, pos_block) in ref_blocks.iter().zip(pos_blocks) {
+            let (refs, positions) = match (&ref_block.stats, &pos_block.stats) {
+                (Some(refs), Some(positions)) if ref_block.numitems == pos_block.numitems => (refs, positions),
+                _ => return all,
+            };
+            let end = start + ref_block.numitems as usize;
+            // Keys of mapped records lie within these bounds. Blocks with
+            // unmapped records are not sorted by them.
+            if refs.min_value >= 0 {
+                if (refs.max_value, positions.max_value) < target {
+                    left = end;
+                } else if (refs.min_value, positions.min_value) >= target {
+                    return (left, start);
+                }
+            }
+            start = end;
+        }
+        (left, self.amount)
+    }
+
+    /// Longest reference span of alignments in the file. Needs one pass over
+    /// RawCigar column, the result is cached.
+    fn max_span(&mut self) -> u32 {
+        if let Some(max_span) = self.max_span {
+            return max_span;
+        }
+        self.init_missing_columns(&[Fields::RawCigar]);
+        let mut rec = GbamRecord::default();
+        let mut max_span = 0;
+        // Stored order, index mapping does not matter here.
+        for rec_num in 0..self.amount {
+            self.columns[Fields::RawCigar as usize].as_mut().unwrap().fill_record_field(rec_num, &mut rec);
+            max_span = max_span.max(rec.alignment_span());
+        }
+        *self.max_span.insert(max_span)
+    }
+
+    /// Fetches `fields` from now on, also after [`Reader::restore_template`].
+    pub(crate) fn add_fields(&mut self, fields: &[Fields]) {
+        self.init_missing_columns(fields);
+        for field in fields {
+            self.parsing_template.set(field, true);
+            self.original_template.set(field, true);
+        }
+    }
+
+    pub(crate) fn init_missing_columns(&mut self, fields: &[Fields]) {
+        for &field in fields {
+            if self.columns[field as usize].is_none() {
+                self.columns[field as usize] = Some(init_col(field, &self.storage, &self.file_meta, &self.block_cache, &self.io_stats, &self.selected_tags, &self.prefetcher));
+            }
+        }
+    }
 }
 
 fn init_columns(
-    mmap: &Arc<Mmap>,
+    storage: &Storage,
     parse_template: &ParsingTemplate,
     meta: &Arc<FileMeta>,
+    cache: &Option<SharedBlockCache>,
+    stats: &Option<SharedIoStats>,
+    selected_tags: &Option<Vec<[u8; 2]>>,
+    prefetcher: &Option<SharedPrefetcher>,
 ) -> Vec<Option<Box<dyn Column + Send>>> {
     let mut res = Vec::new();
     (0..FIELDS_NUM).for_each(|_| res.push(None));
     for &field in parse_template.get_active_fields_iter() {
-        res[field as usize] = Some(init_col(field, mmap, meta));
+        res[field as usize] = Some(init_col(field, storage, meta, cache, stats, selected_tags, prefetcher));
     }
     res
 }
 
-fn init_col(field: Fields, mmap: &Arc<Mmap>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
-    let inner = Inner::new(meta.clone(), field, mmap.clone());
+pub(crate) fn init_col(
+    field: Fields,
+    storage: &Storage,
+    meta: &Arc<FileMeta>,
+    cache: &Option<SharedBlockCache>,
+    stats: &Option<SharedIoStats>,
+    selected_tags: &Option<Vec<[u8; 2]>>,
+    prefetcher: &Option<SharedPrefetcher>,
+) -> Box<dyn Column + Send> {
+    let new_inner = |field: Fields, tag_col: Option<usize>| {
+        let inner = Inner::new(meta.clone(), field, storage.clone(), cache.clone(), stats.clone()).with_prefetcher(prefetcher.clone());
+        match tag_col {
+            Some(tag_col) => inner.with_tag_column(tag_col),
+            None => inner,
+        }
+    };
+    let new_var_col = |tag_col: Option<usize>| {
+        let idx_field = var_size_field_to_index(&field);
+        let idx_col = FixedColumn::new(new_inner(idx_field, tag_col), meta.get_field_size(&idx_field).unwrap() as usize);
+        VariableColumn::new(new_inner(field, tag_col), idx_col)
+    };
     match field_type(&field) {
-        FieldType::FixedSized => Box::new(FixedColumn::new(inner, meta.get_field_size(&field).unwrap() as usize)),
-        FieldType::VariableSized => {
-            let idx_field = var_size_field_to_index(&field);
-            let idx_inner = Inner::new(meta.clone(), idx_field, mmap.clone());
-            let idx_col = FixedColumn::new(idx_inner, meta.get_field_size(&idx_field).unwrap() as usize);
-            Box::new(VariableColumn::new(inner, idx_col))
+        FieldType::FixedSized => Box::new(FixedColumn::new(new_inner(field, None), meta.get_field_size(&field).unwrap() as usize)),
+        FieldType::VariableSized if field == Fields::RawTags && (selected_tags.is_some() || !meta.tag_columns().is_empty()) => {
+            Box::new(TagsColumn::new(meta, selected_tags.clone(), new_var_col))
         }
+        FieldType::VariableSized => Box::new(new_var_col(None)),
     }
 }
 
-fn parse_file_info(mmap: &Mmap) -> FileInfo {
-    let file_info_bytes = &mmap[0..FILE_INFO_SIZE];
+pub(crate) fn parse_file_info(bytes: &[u8]) -> FileInfo {
+    let file_info_bytes = &bytes[0..FILE_INFO_SIZE];
     let end_of_json = file_info_bytes.iter().position(|&r| r == 0).unwrap();
     let file_info_str = String::from_utf8(file_info_bytes[..end_of_json].to_owned()).unwrap();
     serde_json::from_str(&file_info_str).expect("File meta json string was damaged.")
 }
 
 #[allow(dead_code)]
-fn verify(mmap: &Mmap) -> std::io::Result<()>{
+fn verify(mmap: &[u8]) -> std::io::Result<()>{
     let file_info = parse_file_info(mmap);
     // Read file meta
     let buf = &mmap[file_info.seekpos as usize..];
@@ -161,23 +953,68 @@ fn verify(mmap: &Mmap) -> std::io::Result<()>{
     }
     Ok(())
 }
-fn verify_and_parse_meta(mmap: &Mmap) -> std::io::Result<FileMeta> {
-    let file_info = parse_file_info(mmap);
-    // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
-    if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
+/// Parses file info and meta it points to. Damaged or torn (read while
+/// written) file info is reported as error. Meta bytes are copied, see
+/// [`read_storage_footer`], and all block lists are decoded.
+pub(crate) fn read_footer(bytes: &[u8]) -> std::io::Result<(FileInfo, FileMeta)> {
+    let (file_info, range) = footer_meta_range(bytes)?;
+    let meta_bytes: Storage = Arc::new(bytes[range.clone()].to_vec());
+    let file_meta = parse_meta(&file_info, &meta_bytes, 0..range.len())?;
+    file_meta.decode_blocks(Fields::iterator())?;
+    Ok((file_info, file_meta))
+}
+
+/// Same as [`read_footer`], block lists of meta stay in `storage` until
+/// their columns are used.
+pub(crate) fn read_storage_footer(storage: &Storage) -> std::io::Result<(FileInfo, FileMeta)> {
+    let (file_info, range) = footer_meta_range((**storage).as_ref())?;
+    let file_meta = parse_meta(&file_info, storage, range)?;
+    Ok((file_info, file_meta))
+}
+
+/// File info and range of meta it points to.
+fn footer_meta_range(bytes: &[u8]) -> std::io::Result<(FileInfo, std::ops::Range<usize>)> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+    let mut file_info = read_file_info(bytes)?;
+    if file_info.trailer {
+        let tail = bytes.len().checked_sub(FILE_INFO_SIZE).ok_or_else(|| invalid("Streamed file is truncated."))?;
+        file_info = read_file_info(&bytes[tail..])?;
+    }
+    let start = file_info.seekpos as usize;
+    let end = match file_info.meta_size {
+        Some(size) => start.checked_add(size as usize),
+        None => Some(bytes.len()),
+    };
+    let range = end.filter(|&end| start <= end && end <= bytes.len()).map(|end| start..end);
+    Ok((file_info, range.ok_or_else(|| invalid("Meta position is out of file."))?))
+}
+
+/// Parses file info at the beginning of `bytes`.
+pub(crate) fn read_file_info(bytes: &[u8]) -> std::io::Result<FileInfo> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+    let file_info_bytes = bytes.get(..FILE_INFO_SIZE).ok_or_else(|| invalid("File is too short for GBAM."))?;
+    let end_of_json = file_info_bytes.iter().position(|&b| b == 0).unwrap_or(FILE_INFO_SIZE);
+    let file_info: FileInfo = serde_json::from_slice(&file_info_bytes[..end_of_json]).map_err(|_| invalid("File info JSON was damaged."))?;
+    file_info.check_supported()?;
+    Ok(file_info)
+}
+
+/// Checks CRC of meta in `range` of `bytes` and parses it.
+pub(crate) fn parse_meta(file_info: &FileInfo, bytes: &Storage, range: std::ops::Range<usize>) -> std::io::Result<FileMeta> {
+    if calc_crc_for_meta_bytes(&(**bytes).as_ref()[range.clone()]) != file_info.crc32 {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             "Metadata JSON was damaged.",
         ));
     }
-    let file_meta_json_str = String::from_utf8(buf.to_owned()).unwrap();
-    Ok(serde_json::from_str(&file_meta_json_str).expect("File meta json string was damaged."))
+    let mut file_meta = decode_meta(file_info, bytes, range)?;
+    file_meta.fill_from_header();
+    Ok(file_meta)
 }
 
 // The tree map will be used to quickly determine which block record belong to.
-pub(crate) fn generate_block_treemap(meta: &FileMeta, field: &Fields) -> BTreeMap<usize, usize> {
-    meta.view_blocks(field)
+pub(crate) fn generate_block_treemap(blocks: &[BlockMeta]) -> BTreeMap<usize, usize> {
+    blocks
         .iter()
         .enumerate()
         // Prefix sum.
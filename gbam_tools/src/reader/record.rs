@@ -1,22 +1,26 @@
+use std::borrow::Cow;
 use std::io::Write;
 
 use itertools::Itertools;
 use serde::{Serialize, Deserialize};
 
 use bam_tools::record::{
-    bamrawrecord::{decode_seq, put_sequence},
-    fields::Fields,
+    bamrawrecord::{decode_seq, put_sequence, BAMRawRecord},
+    fields::{is_data_field, Fields},
 };
 
-use crate::query::cigar::base_coverage;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::mem;
 
 
+use crate::error::{GbamError, Result};
+use crate::name_index::trim_nul;
 use crate::{query::cigar::Cigar, query::cigar::Op, U32_SIZE};
+use std::convert::TryFrom;
+use super::tags::{TagValue, Tags};
 
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 /// Represents a GBAM record in which some fields may be omitted.
 pub struct GbamRecord {
     /// Reference sequence ID
@@ -191,7 +195,7 @@ impl GbamRecord {
 
     /// Returns the alignment span.
     pub fn alignment_span(&self) -> u32 {
-        base_coverage(&self.cigar.as_ref().unwrap().0[..])
+        self.cigar.as_ref().unwrap().reference_length()
     }
 
     /// Returns the alignment start.
@@ -213,12 +217,205 @@ impl GbamRecord {
 
     pub fn is_reverse_complemented(&self) -> bool {
         let flag = self.flag.unwrap();
-        (flag & rust_htslib::htslib::BAM_FREVERSE as u16) == rust_htslib::htslib::BAM_FREVERSE as u16
+        (flag & 0x10) == 0x10
     }
 
     pub fn is_unmapped(&self) -> bool {
         let flag = self.flag.unwrap();
-        (flag & rust_htslib::htslib::BAM_FUNMAP as u16) == rust_htslib::htslib::BAM_FUNMAP as u16
+        (flag & 0x4) == 0x4
+    }
+
+    fn has_flag(&self, mask: u16) -> bool {
+        self.flag.unwrap() & mask == mask
+    }
+
+    /// Template has multiple segments (0x1).
+    pub fn is_paired(&self) -> bool {
+        self.has_flag(0x1)
+    }
+
+    /// Each segment is properly aligned according to the aligner (0x2).
+    pub fn is_proper_pair(&self) -> bool {
+        self.has_flag(0x2)
+    }
+
+    /// Next segment in the template is unmapped (0x8).
+    pub fn is_mate_unmapped(&self) -> bool {
+        self.has_flag(0x8)
+    }
+
+    /// Next segment in the template is reverse complemented (0x20).
+    pub fn is_mate_reverse(&self) -> bool {
+        self.has_flag(0x20)
+    }
+
+    /// The first segment in the template (0x40).
+    pub fn is_first_segment(&self) -> bool {
+        self.has_flag(0x40)
+    }
+
+    /// The last segment in the template (0x80).
+    pub fn is_last_segment(&self) -> bool {
+        self.has_flag(0x80)
+    }
+
+    /// Secondary alignment (0x100).
+    pub fn is_secondary(&self) -> bool {
+        self.has_flag(0x100)
+    }
+
+    /// Not passing quality controls (0x200).
+    pub fn is_qc_fail(&self) -> bool {
+        self.has_flag(0x200)
+    }
+
+    /// PCR or optical duplicate (0x400).
+    pub fn is_duplicate(&self) -> bool {
+        self.has_flag(0x400)
+    }
+
+    /// Supplementary alignment (0x800).
+    pub fn is_supplementary(&self) -> bool {
+        self.has_flag(0x800)
+    }
+
+    /// Reference sequence ID, None for unplaced reads (-1). RefID has to be
+    /// fetched.
+    pub fn reference_id(&self) -> Option<usize> {
+        usize::try_from(self.refid.unwrap()).ok()
+    }
+
+    /// Reference sequence ID of the next segment, None if unavailable (-1).
+    pub fn mate_reference_id(&self) -> Option<usize> {
+        usize::try_from(self.next_ref_id.unwrap()).ok()
+    }
+
+    /// 0-based leftmost position of the next segment, None if unavailable (-1).
+    pub fn mate_alignment_start(&self) -> Option<u32> {
+        u32::try_from(self.next_pos.unwrap()).ok()
+    }
+
+    /// Mapping quality, None if unavailable (255).
+    pub fn mapping_quality(&self) -> Option<u8> {
+        Some(self.mapq.unwrap()).filter(|&mapq| mapq != 255)
+    }
+
+    /// Observed template length, 0 if unavailable.
+    pub fn template_length(&self) -> i32 {
+        self.tlen.unwrap()
+    }
+
+    /// Read name without terminating NUL.
+    pub fn name(&self) -> &[u8] {
+        trim_nul(self.read_name.as_ref().unwrap())
+    }
+
+    /// Phred-scaled base qualities, None if absent (stored as 0xFF).
+    pub fn quality_scores(&self) -> Option<&[u8]> {
+        Some(&self.qual.as_ref().unwrap()[..]).filter(|qual| qual.first() != Some(&0xFF))
+    }
+
+    /// Bases as IUPAC letters, e.g. ACGTN, decoded from 4-bit codes when
+    /// RawSequence is parsed. Empty if sequence is not stored (`*`).
+    pub fn sequence(&self) -> &[u8] {
+        self.seq.as_ref().unwrap().as_bytes()
+    }
+
+    /// Bases as sequenced: [`GbamRecord::sequence`] reverse complemented for
+    /// reverse strand reads (0x10), as is otherwise. Flags has to be fetched.
+    pub fn sequence_revcomp(&self) -> Cow<'_, [u8]> {
+        let seq = self.sequence();
+        if self.is_reverse() {
+            Cow::Owned(seq.iter().rev().map(|&base| complement(base)).collect())
+        } else {
+            Cow::Borrowed(seq)
+        }
+    }
+
+    /// Data fields which are not filled, in order of [`Fields::iterator`].
+    pub fn missing_fields(&self) -> Vec<Fields> {
+        Fields::iterator()
+            .filter(|field| is_data_field(field))
+            .filter(|field| match field {
+                Fields::RefID => self.refid.is_none(),
+                Fields::Pos => self.pos.is_none(),
+                Fields::Mapq => self.mapq.is_none(),
+                Fields::Bin => self.bin.is_none(),
+                Fields::Flags => self.flag.is_none(),
+                Fields::NextRefID => self.next_ref_id.is_none(),
+                Fields::NextPos => self.next_pos.is_none(),
+                Fields::TemplateLength => self.tlen.is_none(),
+                Fields::ReadName => self.read_name.is_none(),
+                Fields::RawCigar => self.cigar.is_none(),
+                Fields::RawSequence => self.seq.is_none(),
+                Fields::RawQual => self.qual.is_none(),
+                Fields::RawTags => self.tags.is_none(),
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// BAM record without block_size, as taken by [`crate::Writer::push_record`]
+    /// and [`GbamRecord::from`]. Unlike [`GbamRecord::convert_to_bytes`], fails
+    /// if some field is not filled, e.g. not fetched by the parsing template.
+    pub fn into_bam_bytes(self) -> Result<Vec<u8>> {
+        let missing = self.missing_fields();
+        if !missing.is_empty() {
+            return Err(GbamError::InvalidArgument(format!(
+                "Record can't be converted to BAM, fields {} are not fetched.",
+                missing.iter().join(", ")
+            )));
+        }
+        let mut bytes = Vec::new();
+        self.convert_to_bytes(&mut bytes);
+        bytes.drain(..mem::size_of::<u32>());
+        Ok(bytes)
+    }
+
+    /// Auxiliary fields, parsed as the iterator advances. RawTags has to be
+    /// fetched.
+    pub fn tags(&self) -> Tags<'_> {
+        Tags::new(self.tags.as_ref().unwrap())
+    }
+
+    /// Value of the first tag named `tag`, e.g. `get_tag(b"NM")`.
+    pub fn get_tag(&self, tag: &[u8; 2]) -> Option<TagValue<'_>> {
+        self.tags().get(tag)
+    }
+
+    /// Value of string (Z) tag without terminating NUL, e.g. `string_tag(b"RG")`.
+    pub fn string_tag(&self, tag: &[u8; 2]) -> Option<&[u8]> {
+        self.get_tag(tag)?.as_str()
+    }
+
+    /// ID of read group (RG tag), see [`crate::meta::FileMeta::read_group`].
+    pub fn read_group(&self) -> Option<&[u8]> {
+        self.string_tag(b"RG")
+    }
+
+    /// Value of integer tag of any width, e.g. `int_tag(b"NM")`.
+    pub fn int_tag(&self, tag: &[u8; 2]) -> Option<i64> {
+        self.get_tag(tag)?.as_int()
+    }
+}
+
+/// Complement of IUPAC base, other bytes (e.g. N or =) are kept.
+pub fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
     }
 }
 
@@ -226,4 +423,62 @@ impl std::fmt::Display for GbamRecord {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         std::fmt::Debug::fmt(self, f)
     }
-}
\ No newline at end of file
+}
+impl From<&BAMRawRecord<'_>> for GbamRecord {
+    /// Fully filled record of BAM record without block_size.
+    fn from(raw: &BAMRawRecord<'_>) -> Self {
+        let mut rec = GbamRecord::default();
+        for field in Fields::iterator().filter(|field| is_data_field(field)) {
+            rec.parse_from_bytes(field, raw.get_bytes(field));
+        }
+        rec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::parse_sam;
+
+    #[test]
+    fn test_from_raw_record() {
+        let sam = "@SQ\tSN:chr1\tLN:1000\nr1\t99\tchr1\t10\t60\t2M1I1M\t=\t50\t44\tACGT\tABCD\tNM:i:1\n";
+        let buf = parse_sam(sam).2.remove(0);
+
+        let rec = GbamRecord::from(&BAMRawRecord(std::borrow::Cow::Borrowed(&buf)));
+        assert_eq!(rec.pos, Some(9));
+        assert_eq!(rec.read_name.as_deref(), Some(&b"r1\0"[..]));
+        assert_eq!(rec.seq.as_deref(), Some("ACGT"));
+        assert_eq!(rec.int_tag(b"NM"), Some(1));
+        let mut bytes = Vec::new();
+        rec.convert_to_bytes(&mut bytes);
+        assert_eq!(&bytes[4..], &buf[..]);
+
+        assert!(rec.is_paired() && rec.is_proper_pair() && rec.is_first_segment() && rec.is_mate_reverse());
+        assert!(!rec.is_secondary() && !rec.is_duplicate() && !rec.is_supplementary());
+        assert_eq!(rec.reference_id(), Some(0));
+        assert_eq!(rec.mate_reference_id(), Some(0));
+        assert_eq!(rec.mate_alignment_start(), Some(49));
+        assert_eq!(rec.mapping_quality(), Some(60));
+        assert_eq!(rec.template_length(), 44);
+        assert_eq!(rec.name(), b"r1");
+        assert_eq!(rec.quality_scores(), Some(&[32, 33, 34, 35][..]));
+        assert!(rec.missing_fields().is_empty());
+        let owned = rec.to_owned();
+        assert_eq!(rec.into_bam_bytes().unwrap(), buf);
+
+        let mut partial = GbamRecord { tlen: None, qual: None, ..owned };
+        assert_eq!(partial.missing_fields(), vec![Fields::TemplateLength, Fields::RawQual]);
+        assert!(partial.clone().into_bam_bytes().is_err());
+        partial.qual = Some(vec![0xFF; 4]);
+        assert_eq!(partial.quality_scores(), None);
+
+        partial.seq = Some("ACGNR".to_string());
+        assert_eq!(partial.sequence(), b"ACGNR");
+        assert_eq!(partial.sequence_revcomp(), &b"ACGNR"[..]);
+        partial.flag = Some(0x10);
+        assert_eq!(partial.sequence_revcomp(), &b"YNCGT"[..]);
+        partial.seq = Some(String::new());
+        assert!(partial.sequence_revcomp().is_empty());
+    }
+}
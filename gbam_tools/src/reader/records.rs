@@ -1,4 +1,5 @@
-use super::{reader::Reader, record::GbamRecord};
+use super::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord, regions::Region};
+use std::ops::Range;
 
 /// Iterates over GBAM file.
 pub struct Records<'a> {
@@ -6,6 +7,12 @@ pub struct Records<'a> {
     cur_rec: usize,
     rec_amount: usize,
     buf: GbamRecord,
+    // Region records of current range have to overlap and further ranges
+    // with their regions, in reverse order.
+    region: Option<Region>,
+    next_ranges: Vec<(Range<usize>, Region)>,
+    // Parsing template of reader to restore on drop.
+    saved_template: Option<ParsingTemplate>,
 }
 
 impl<'a> Records<'a> {
@@ -15,15 +22,197 @@ impl<'a> Records<'a> {
             reader,
             cur_rec: 0,
             buf: GbamRecord::default(),
+            region: None,
+            next_ranges: Vec::new(),
+            saved_template: None,
         }
     }
 
+    /// Iterates over records in `range` only.
+    pub fn new_in_range(reader: &'a mut Reader, range: Range<usize>) -> Self {
+        assert!(range.end <= reader.amount, "Record range is out of bounds.");
+        Self {
+            reader,
+            cur_rec: range.start,
+            rec_amount: range.end,
+            buf: GbamRecord::default(),
+            region: None,
+            next_ranges: Vec::new(),
+            saved_template: None,
+        }
+    }
+
+    /// Iterates over records of every range which overlap its region. See
+    /// [`Reader::fetch_regions`].
+    pub(crate) fn new_in_regions(reader: &'a mut Reader, mut ranges: Vec<(Range<usize>, Region)>) -> Self {
+        ranges.reverse();
+        Self {
+            reader,
+            cur_rec: 0,
+            rec_amount: 0,
+            buf: GbamRecord::default(),
+            region: None,
+            next_ranges: ranges,
+            saved_template: None,
+        }
+    }
+
+    /// Sets reader's parsing template back to `template` on drop.
+    pub(crate) fn restoring(mut self, template: ParsingTemplate) -> Self {
+        self.saved_template = Some(template);
+        self
+    }
+
     pub fn next_rec(&mut self) -> Option<&GbamRecord> {
-        if self.cur_rec == self.rec_amount {
-            return None;
+        loop {
+            if self.cur_rec == self.rec_amount {
+                let (range, region) = self.next_ranges.pop()?;
+                self.cur_rec = range.start;
+                self.rec_amount = range.end;
+                self.region = Some(region);
+                continue;
+            }
+            if !self.reader.passes_filters(self.cur_rec) {
+                self.cur_rec += 1;
+                continue;
+            }
+            self.reader.fill_record(self.cur_rec, &mut self.buf);
+            self.cur_rec += 1;
+            if self.region.is_none_or(|region| region.overlaps(&self.buf)) {
+                return Some(&self.buf);
+            }
+        }
+    }
+}
+
+impl Drop for Records<'_> {
+    fn drop(&mut self) {
+        if let Some(template) = self.saved_template.take() {
+            self.reader.parsing_template = template;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::{filter::RecordFilter, parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_util::gbam_from_sam;
+    use bam_tools::record::fields::Fields;
+    use rayon::prelude::*;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t5M\t*\t0\t0\tACGTA\t*\n\
+r2\t0\tchr1\t20\t60\t2M1D3M\t*\t0\t0\tACGTA\t*\n";
+
+    fn in_memory_gbam(sam: &str, template: ParsingTemplate) -> Reader {
+        gbam_from_sam(sam, template)
+    }
+
+    #[test]
+    fn test_records_with() {
+        let mut reader = in_memory_gbam(SAM, ParsingTemplate::new_with(&[Fields::ReadName]));
+
+        let mut ends = Vec::new();
+        let mut records = reader.records_with(&[Fields::Pos, Fields::RawCigar]);
+        while let Some(rec) = records.next_rec() {
+            assert!(rec.read_name.is_none());
+            ends.push(rec.alignment_end().unwrap());
+        }
+        drop(records);
+        assert_eq!(ends, vec![13, 24]);
+
+        // Template of reader is restored.
+        let mut records = reader.records();
+        let rec = records.next_rec().unwrap();
+        assert_eq!(rec.read_name.as_deref(), Some(&b"r1\0"[..]));
+        assert!(rec.pos.is_none());
+    }
+
+    #[test]
+    fn test_flag_filter() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..3000 {
+            let flag = [99, 147, 1123, 4, 355][i % 5];
+            sam.push_str(&format!("r{}\t{}\tchr1\t{}\t60\t5M\t=\t1\t0\tACGTA\t*\n", i, flag, i + 1));
         }
-        self.reader.fill_record(self.cur_rec, &mut self.buf);
-        self.cur_rec += 1;
-        Some(&self.buf)
+        let mut reader = in_memory_gbam(&sam, ParsingTemplate::new_with(&[Fields::ReadName, Fields::RawSequence]));
+        // Paired, not duplicate nor secondary.
+        reader.set_flag_filter(0x1, 0x400 | 0x100);
+        let mut names = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            assert!(rec.flag.is_none());
+            names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+        }
+        drop(records);
+        assert_eq!(names.len(), 1200);
+        assert_eq!(&names[..3], &["r0", "r1", "r5"]);
+        let parallel: Vec<String> = reader.par_records().map(|rec| rec.to_string()).collect();
+        assert_eq!(parallel.len(), 1200);
+        let mut groups = reader.position_groups(100);
+        assert_eq!(groups.next_group().unwrap().records.len(), 40);
+        drop(groups);
+
+        reader.set_flag_filter(0, 0);
+        assert_eq!(reader.par_records().count(), 3000);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_min_mapq() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..3000 {
+            let mapq = [0, 20, 60][i % 3];
+            sam.push_str(&format!("r{}\t{}\tchr1\t{}\t{}\t5M\t*\t0\t0\tACGTA\t*\n", i, [0, 1024][i % 2], i + 1, mapq));
+        }
+        let mut reader = in_memory_gbam(&sam, ParsingTemplate::new_with(&[Fields::ReadName]));
+        reader.set_min_mapq(20);
+        let mut records = reader.records();
+        let mut names = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            assert!(rec.mapq.is_none());
+            names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+        }
+        drop(records);
+        assert_eq!(names.len(), 2000);
+        assert_eq!(&names[..3], &["r1", "r2", "r4"]);
+        // Combined with flag filter, also in parallel.
+        reader.set_flag_filter(0, 0x400);
+        assert_eq!(reader.par_records().count(), 1000);
+
+        reader.set_min_mapq(0);
+        reader.set_flag_filter(0, 0);
+        assert_eq!(reader.par_records().count(), 3000);
+    }
+
+    #[test]
+    fn test_record_filter() {
+        let mut reader = in_memory_gbam(SAM, ParsingTemplate::new_with(&[Fields::ReadName]));
+        let filter = RecordFilter::compile("rname == 'chr1' && endpos > 20", reader.file_meta.get_ref_seqs()).unwrap();
+        reader.set_record_filter(Some(filter));
+        let names: Vec<String> = reader.par_records().map(|rec| String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).into_owned()).collect();
+        assert_eq!(names, vec!["r2\0"]);
+        let mut records = reader.records();
+        assert!(records.next_rec().unwrap().pos.is_none());
+        assert!(records.next_rec().is_none());
+    }
+
+    #[test]
+    fn test_par_records() {
+        let mut sam = String::from("@SQ\tSN:chr1\tLN:100000\n");
+        for i in 0..5000 {
+            sam.push_str(&format!("r{}\t0\tchr1\t{}\t60\t5M\t*\t0\t0\tACGTA\t*\n", i, i + 1));
+        }
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = in_memory_gbam(&sam, template);
+        let parallel: Vec<String> = reader.par_records().map(|rec| rec.to_string()).collect();
+        let mut sequential = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            sequential.push(rec.to_string());
+        }
+        drop(records);
+        assert_eq!(parallel, sequential);
+        assert_eq!(reader.par_record_batches().map(|batch| batch.len()).sum::<usize>(), 5000);
+    }
+}
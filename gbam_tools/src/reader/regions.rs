@@ -0,0 +1,255 @@
+use super::reader::Reader;
+use super::record::GbamRecord;
+use crate::error::Result;
+use crate::interval_index::IntervalIndex;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+
+/// Half-open 0-based interval of reference sequence, as in BED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Region {
+    pub ref_id: i32,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Region {
+    pub fn new(ref_id: i32, start: u32, end: u32) -> Self {
+        Self { ref_id, start, end }
+    }
+
+    /// Regions of parsed BED file (see [`crate::utils::bed::parse_bed`]).
+    /// Reference sequences are looked up by name in `ref_seqs`.
+    pub fn from_bed(bed: &HashMap<String, Vec<(u32, u32)>>, ref_seqs: &[(String, u32)]) -> io::Result<Vec<Self>> {
+        let mut regions = Vec::new();
+        for (ref_name, intervals) in bed {
+            let ref_id = ref_seqs.iter().position(|(name, _)| name == ref_name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Reference sequence {} is not in the file.", ref_name))
+            })?;
+            regions.extend(intervals.iter().map(|&(start, end)| Self::new(ref_id as i32, start, end)));
+        }
+        Ok(regions)
+    }
+
+    /// Parses region as in samtools view: `chr1`, `chr1:1000` (to the end of
+    /// reference sequence) or `chr1:1,000-2,000` (1-based, inclusive).
+    /// Reference sequence names containing `:` are matched as a whole first.
+    pub fn parse(region: &str, ref_seqs: &[(String, u32)]) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let find_ref = |name: &str| ref_seqs.iter().position(|(ref_name, _)| ref_name == name);
+        if let Some(ref_id) = find_ref(region) {
+            return Ok(Self::new(ref_id as i32, 0, ref_seqs[ref_id].1));
+        }
+        let (name, range) = region
+            .rsplit_once(':')
+            .ok_or_else(|| invalid(format!("Reference sequence {} is not in the file.", region)))?;
+        let ref_id = find_ref(name).ok_or_else(|| invalid(format!("Reference sequence {} is not in the file.", name)))?;
+        let ref_len = ref_seqs[ref_id].1;
+        let parse_pos = |pos: &str| {
+            pos.replace(',', "")
+                .parse::<u32>()
+                .map_err(|_| invalid(format!("Invalid position {} in region {}.", pos, region)))
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, "")) => (parse_pos(start)?, ref_len),
+            Some((start, end)) => (parse_pos(start)?, parse_pos(end)?),
+            None => (parse_pos(range)?, ref_len),
+        };
+        if start == 0 || start > end {
+            return Err(invalid(format!("Invalid region {}.", region)));
+        }
+        Ok(Self::new(ref_id as i32, start - 1, end.min(ref_len)))
+    }
+
+    /// Record overlaps the region. Records with no reference bases in CIGAR
+    /// (e.g. unmapped reads placed at their mates) occupy one base.
+    /// RefID, Pos and RawCigar have to be fetched.
+    pub fn overlaps(&self, rec: &GbamRecord) -> bool {
+        let start = rec.pos.unwrap() as i64;
+        let end = start + i64::from(rec.alignment_span().max(1));
+        rec.refid.unwrap() == self.ref_id && start < i64::from(self.end) && end > i64::from(self.start)
+    }
+}
+
+/// Sorts regions and merges overlapping or adjacent ones.
+pub fn merge_regions(regions: &[Region]) -> Vec<Region> {
+    let mut sorted = regions.to_vec();
+    sorted.sort_unstable();
+    let mut merged: Vec<Region> = Vec::with_capacity(sorted.len());
+    for region in sorted.into_iter().filter(|region| region.start < region.end) {
+        match merged.last_mut() {
+            Some(last) if last.ref_id == region.ref_id && region.start <= last.end => last.end = last.end.max(region.end),
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// Where scan for records overlapping region starts.
+pub(crate) enum RegionStart<'a> {
+    /// Records starting at most this many bases before a region may overlap
+    /// it.
+    Lookback(u32),
+    Index(&'a IntervalIndex),
+}
+
+/// Ranges of records to scan for every merged region. Ranges are in file
+/// order and do not overlap, so no record is visited twice.
+pub(crate) fn region_ranges(reader: &mut Reader, regions: &[Region], region_start: RegionStart<'_>) -> Result<Vec<(Range<usize>, Region)>> {
+    let mut ranges = Vec::new();
+    let mut prev_end = 0;
+    for (range, region) in overlapping_ranges(reader, regions, region_start)? {
+        let start = range.start.max(prev_end);
+        if start < range.end {
+            ranges.push((start..range.end, region));
+            prev_end = range.end;
+        }
+    }
+    Ok(ranges)
+}
+
+/// Ranges of all records which may overlap every merged region. Ranges of
+/// nearby regions may overlap.
+pub(crate) fn overlapping_ranges(reader: &mut Reader, regions: &[Region], region_start: RegionStart<'_>) -> Result<Vec<(Range<usize>, Region)>> {
+    let mut ranges = Vec::new();
+    for region in merge_regions(regions) {
+        let end = reader.lower_bound(region.ref_id, region.end as i32)?;
+        let start = match region_start {
+            RegionStart::Lookback(max_span) => {
+                let lookback = region.start.saturating_sub(max_span);
+                reader.lower_bound(region.ref_id, lookback as i32)?
+            }
+            RegionStart::Index(index) => index.first_record(region.ref_id, region.start).map_or(end, |rec_num| rec_num as usize),
+        };
+        ranges.push((start.min(end)..end, region));
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GbamError;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_util::{gbam_from_sam, write_sam};
+    use crate::{Codecs, Writer};
+    use bam_tools::record::fields::Fields;
+    use std::io::Cursor;
+
+    // Positions are 1-based in SAM: r1 covers [9, 109), r2 [94, 99), r4 [149, 150).
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t100M\t*\t0\t0\t*\t*\n\
+r2\t0\tchr1\t95\t60\t5M\t*\t0\t0\t*\t*\n\
+r3\t0\tchr1\t120\t60\t5M\t*\t0\t0\t*\t*\n\
+r4\t4\tchr1\t150\t0\t*\t*\t0\t0\t*\t*\n\
+r5\t0\tchr2\t10\t60\t5M\t*\t0\t0\t*\t*\n\
+u1\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+
+    fn with_stats(template: ParsingTemplate) -> Reader {
+        let writer = write_sam(SAM, |sam_header, ref_seqs| {
+            Writer::new(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, vec![Fields::RefID, Fields::Pos], ref_seqs, sam_header, String::new(), true)
+        });
+        Reader::from_bytes(writer.into_inner().into_inner(), template).unwrap()
+    }
+
+    #[test]
+    fn test_fetch_regions() {
+        let mut reader = gbam_from_sam(SAM, ParsingTemplate::new_with(&[Fields::ReadName]));
+
+        let mut fetch = |regions: &[Region]| {
+            let mut names = Vec::new();
+            let mut records = reader.fetch_regions(regions).unwrap();
+            while let Some(rec) = records.next_rec() {
+                names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+            }
+            names
+        };
+        assert_eq!(fetch(&[Region::new(0, 98, 110)]), vec!["r1", "r2"]);
+        assert_eq!(fetch(&[Region::new(0, 100, 110)]), vec!["r1"]);
+        // Overlapping regions return every record once.
+        assert_eq!(fetch(&[Region::new(1, 0, 1000), Region::new(0, 105, 130), Region::new(0, 0, 120)]), vec!["r1", "r2", "r3", "r5"]);
+        assert_eq!(fetch(&[Region::new(0, 149, 150), Region::new(0, 200, 300)]), vec!["r4"]);
+        assert!(fetch(&[Region::new(0, 110, 119)]).is_empty());
+    }
+
+    #[test]
+    fn test_fetch() {
+        // Block stats of RefID and Pos narrow the search.
+        let mut reader = with_stats(ParsingTemplate::new_with(&[Fields::ReadName]));
+
+        let mut fetch = |region: &str| {
+            let mut names = Vec::new();
+            let mut records = reader.fetch(region).unwrap();
+            while let Some(rec) = records.next_rec() {
+                names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+            }
+            names
+        };
+        assert_eq!(fetch("chr1:99-110"), vec!["r1", "r2"]);
+        assert_eq!(fetch("chr1:1,000"), Vec::<String>::new());
+        assert_eq!(fetch("chr1:110-"), vec!["r3", "r4"]);
+        assert_eq!(fetch("chr2"), vec!["r5"]);
+        assert!(reader.fetch("chr3:1-10").is_err());
+        assert!(reader.fetch("chr1:20-10").is_err());
+        let bounds: Vec<_> = [(0, 0), (0, 94), (0, 95), (0, 200), (1, 0), (1, 10), (2, 0), (-1, -1)]
+            .iter()
+            .map(|&(ref_id, pos)| reader.lower_bound(ref_id, pos).unwrap())
+            .collect();
+        assert_eq!(bounds, vec![0, 1, 2, 4, 4, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_count_records() {
+        let mut reader = with_stats(ParsingTemplate::new());
+
+        assert_eq!(reader.count_records(None).unwrap(), 6);
+        // r1 starts before the region.
+        assert_eq!(reader.count_records(Some(&[Region::new(0, 98, 110)])).unwrap(), 2);
+        assert_eq!(reader.count_records(Some(&[Region::new(0, 110, 119)])).unwrap(), 0);
+        assert_eq!(reader.count_records(Some(&[Region::new(0, 0, 1000), Region::new(1, 0, 1000)])).unwrap(), 5);
+        reader.set_flag_filter(0, 0x4);
+        assert_eq!(reader.count_records(None).unwrap(), 4);
+        assert_eq!(reader.count_records(Some(&[Region::new(0, 100, 200)])).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_fetch_unsorted() {
+        let unsorted: String = SAM.lines().filter(|l| l.starts_with('@')).chain(SAM.lines().filter(|l| !l.starts_with('@')).rev()).map(|l| format!("{}\n", l)).collect();
+        let mut reader = gbam_from_sam(&unsorted, ParsingTemplate::new());
+
+        assert!(!reader.is_coordinate_sorted());
+        assert!(matches!(reader.lower_bound(0, 10), Err(GbamError::InvalidArgument(_))));
+        assert!(reader.fetch("chr1").is_err());
+        assert!(reader.count_records(Some(&[Region::new(0, 0, 1000)])).is_err());
+        assert_eq!(reader.count_records(None).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_parse_region() {
+        let ref_seqs = vec![("chr1".to_owned(), 1000), ("HLA-A*01:01".to_owned(), 500)];
+        assert_eq!(Region::parse("chr1", &ref_seqs).unwrap(), Region::new(0, 0, 1000));
+        assert_eq!(Region::parse("chr1:1,001-2,000", &ref_seqs).unwrap(), Region::new(0, 1000, 1000));
+        assert_eq!(Region::parse("chr1:10", &ref_seqs).unwrap(), Region::new(0, 9, 1000));
+        assert_eq!(Region::parse("HLA-A*01:01", &ref_seqs).unwrap(), Region::new(1, 0, 500));
+        assert_eq!(Region::parse("HLA-A*01:01:5-5", &ref_seqs).unwrap(), Region::new(1, 4, 5));
+        assert!(Region::parse("chr1:0-5", &ref_seqs).is_err());
+        assert!(Region::parse("chr1:x", &ref_seqs).is_err());
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let regions = [
+            Region::new(1, 50, 60),
+            Region::new(0, 10, 20),
+            Region::new(0, 15, 30),
+            Region::new(0, 30, 40),
+            Region::new(0, 45, 45),
+            Region::new(1, 0, 10),
+        ];
+        assert_eq!(
+            merge_regions(&regions),
+            vec![Region::new(0, 10, 40), Region::new(1, 0, 10), Region::new(1, 50, 60)]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::{parse_meta, read_file_info, Reader, Storage};
+use super::records::Records;
+use super::regions::{merge_regions, region_ranges, Region, RegionStart};
+use crate::meta::{FileMeta, FILE_INFO_SIZE};
+use bam_tools::record::fields::{field_type, var_size_field_to_index, FieldType, Fields};
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Block ranges closer than this are downloaded with one request.
+const MAX_GAP: u64 = 64 * 1024;
+
+/// Bytes of a range request, not `Send` so JavaScript promises fit.
+pub type FetchFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + 'a>>;
+
+/// Source of byte ranges of GBAM file, e.g. HTTP range requests from
+/// browser.
+pub trait RangeFetcher {
+    /// Bytes of `range` of the file. The end may be past the end of file,
+    /// then bytes till the end are returned, as HTTP servers do.
+    fn fetch(&self, range: Range<u64>) -> FetchFuture<'_>;
+}
+
+/// Reader of GBAM file which is not stored locally, e.g. in browser genome
+/// viewers. Only file info and meta are downloaded on open, region queries
+/// download blocks holding records of the region. Files need interval index
+/// (see [`crate::interval_index`]); RefID and Pos block stats narrow lookup
+/// of region end, otherwise whole RefID and Pos columns are downloaded.
+pub struct RemoteReader<F> {
+    fetcher: F,
+    file_meta: Arc<FileMeta>,
+}
+
+impl<F: RangeFetcher> RemoteReader<F> {
+    pub async fn open(fetcher: F) -> io::Result<Self> {
+        let file_info = read_file_info(&fetcher.fetch(0..FILE_INFO_SIZE as u64).await?)?;
+        if file_info.trailer {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Files written as a stream are not read over range requests, add interval index to make them regular.",
+            ));
+        }
+        let meta_end = file_info.meta_size.map_or(u64::MAX, |size| file_info.seekpos + size);
+        let meta_bytes = fetcher.fetch(file_info.seekpos..meta_end).await?;
+        let len = meta_bytes.len();
+        let file_meta = parse_meta(&file_info, &(Arc::new(meta_bytes) as Storage), 0..len)?;
+        Ok(Self { fetcher, file_meta: Arc::new(file_meta) })
+    }
+
+    pub fn file_meta(&self) -> &FileMeta {
+        &self.file_meta
+    }
+
+    /// Downloads records overlapping region given as in samtools view, e.g.
+    /// `chr1:1,000-2,000`, with `fields` fetched.
+    pub async fn fetch(&self, region: &str, fields: &[Fields]) -> io::Result<RegionSlice> {
+        let region = Region::parse(region, self.file_meta.get_ref_seqs())?;
+        self.fetch_regions(&[region], fields).await
+    }
+
+    /// Downloads records overlapping any of `regions`, with `fields`
+    /// fetched. See [`Reader::fetch_regions`].
+    pub async fn fetch_regions(&self, regions: &[Region], fields: &[Fields]) -> io::Result<RegionSlice> {
+        let index = self.file_meta.interval_index().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Region fetch over range requests needs interval index.")
+        })?;
+        // Region ends are looked up in RefID and Pos.
+        let bounds: Vec<Range<usize>> = {
+            let reader = self.image(&[], &[]).await?;
+            merge_regions(regions)
+                .iter()
+                .map(|region| {
+                    let (left, right) = reader.stats_bounds(region.ref_id, region.end as i32);
+                    left..right
+                })
+                .collect()
+        };
+        let mut reader = self.image(&bounds, &[Fields::RefID, Fields::Pos]).await?;
+        let ranges = region_ranges(&mut reader, regions, RegionStart::Index(index))?;
+
+        let mut fields = fields.to_vec();
+        fields.extend_from_slice(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        let record_ranges: Vec<Range<usize>> = ranges.iter().map(|(range, _)| range.clone()).collect();
+        let reader = self.image(&record_ranges, &fields).await?;
+        Ok(RegionSlice { reader, ranges, fields })
+    }
+
+    /// Reader over blocks of `fields` holding records in `records` ranges.
+    /// Blocks are downloaded into one buffer and meta is pointed to it,
+    /// other blocks are never read.
+    async fn image(&self, records: &[Range<usize>], fields: &[Fields]) -> io::Result<Reader> {
+        // Region bounds are looked up in stats of RefID and Pos.
+        self.file_meta.decode_blocks(fields.iter().chain([&Fields::RefID, &Fields::Pos]))?;
+        let mut file_meta = (*self.file_meta).clone();
+        let mut blocks = Vec::new();
+        for (field, tag_col) in self.columns(fields) {
+            let mut start = 0;
+            for (block_num, block) in file_meta.view_column_blocks(&field, tag_col).iter().enumerate() {
+                let end = start + block.numitems as usize;
+                // Variable sized items end where the previous one ends, so
+                // index is read one record back.
+                if records.iter().any(|range| range.start.saturating_sub(1) < end && start < range.end) {
+                    blocks.push((block.seekpos..block.seekpos + block.block_size as u64, field, tag_col, block_num));
+                }
+                start = end;
+            }
+        }
+        blocks.sort_by_key(|(range, ..)| range.start);
+
+        let mut image = Vec::new();
+        let mut requests: Vec<Range<u64>> = Vec::new();
+        for (range, ..) in blocks.iter() {
+            match requests.last_mut() {
+                Some(last) if range.start <= last.end + MAX_GAP => last.end = last.end.max(range.end),
+                _ => requests.push(range.clone()),
+            }
+        }
+        // Offset of every request in the image.
+        let mut offsets = Vec::with_capacity(requests.len());
+        for request in requests.iter() {
+            offsets.push(image.len() as u64);
+            image.extend_from_slice(&self.fetcher.fetch(request.clone()).await?);
+        }
+        for (range, field, tag_col, block_num) in blocks {
+            let request = requests.partition_point(|request| request.end <= range.start);
+            let seekpos = offsets[request] + range.start - requests[request].start;
+            file_meta.get_column_blocks(&field, tag_col)[block_num].seekpos = seekpos;
+        }
+        Reader::new_with_storage(Arc::new(image), None, ParsingTemplate::new_with(fields), &Arc::new(file_meta), None).map_err(io::Error::from)
+    }
+
+    /// Columns read for `fields`: index columns of variable sized fields
+    /// and tag columns of RawTags.
+    fn columns(&self, fields: &[Fields]) -> Vec<(Fields, Option<usize>)> {
+        let mut columns = Vec::new();
+        for &field in fields {
+            match field_type(&field) {
+                FieldType::FixedSized => columns.push((field, None)),
+                FieldType::VariableSized => {
+                    let tag_cols = if field == Fields::RawTags { self.file_meta.tag_columns().len() } else { 0 };
+                    for tag_col in std::iter::once(None).chain((0..tag_cols).map(Some)) {
+                        columns.push((field, tag_col));
+                        columns.push((var_size_field_to_index(&field), tag_col));
+                    }
+                }
+            }
+        }
+        columns.sort_by_key(|&(field, tag_col)| (field as usize, tag_col));
+        columns.dedup();
+        columns
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` on the current thread, e.g. [`RemoteReader`] methods over
+/// blocking [`super::source::BlockSource`].
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(res) => return res,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Downloaded records of regions, see [`RemoteReader::fetch_regions`].
+pub struct RegionSlice {
+    reader: Reader,
+    ranges: Vec<(Range<usize>, Region)>,
+    fields: Vec<Fields>,
+}
+
+impl RegionSlice {
+    /// Iterates over records overlapping the regions.
+    pub fn records(&mut self) -> Records<'_> {
+        self.reader.region_records_with(&self.fields, self.ranges.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, SortOrder, Writer};
+    use std::io::Cursor;
+
+    struct MemoryFetcher(Vec<u8>);
+
+    impl RangeFetcher for MemoryFetcher {
+        fn fetch(&self, range: Range<u64>) -> FetchFuture<'_> {
+            let end = range.end.min(self.0.len() as u64);
+            let bytes = self.0[range.start as usize..end as usize].to_vec();
+            Box::pin(async move { Ok(bytes) })
+        }
+    }
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t100M\t*\t0\t0\t*\t*\n\
+r2\t0\tchr1\t95\t60\t5M\t*\t0\t0\tACGTA\tABCDE\tNM:i:0\n\
+r3\t0\tchr1\t300\t60\t5M\t*\t0\t0\t*\t*\n\
+r4\t0\tchr2\t50\t60\t5M\t*\t0\t0\t*\t*\n";
+
+    #[test]
+    fn test_remote_fetch() {
+        let writer = write_sam(SAM, |sam_header, ref_seqs| {
+            let sort_order = SortOrder::from_sam_header(&sam_header);
+            let mut writer = Writer::new(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, vec![Fields::RefID, Fields::Pos], ref_seqs, sam_header, String::new(), true);
+            writer.set_sort_order(sort_order);
+            writer.set_interval_index(true);
+            writer
+        });
+        let reader = block_on(RemoteReader::open(MemoryFetcher(writer.into_inner().into_inner()))).unwrap();
+        let mut slice = block_on(reader.fetch("chr1:99-110", &[Fields::ReadName, Fields::RawSequence])).unwrap();
+        let mut names = Vec::new();
+        let mut records = slice.records();
+        while let Some(rec) = records.next_rec() {
+            names.push(String::from_utf8(rec.read_name.clone().unwrap()).unwrap());
+            assert!(rec.qual.is_none());
+        }
+        assert_eq!(names, vec!["r1\0", "r2\0"]);
+
+        let mut slice = block_on(reader.fetch("chr2", &[Fields::Flags])).unwrap();
+        assert_eq!(slice.records().next_rec().unwrap().pos, Some(49));
+    }
+}
@@ -0,0 +1,238 @@
+use super::remote::{FetchFuture, RangeFetcher};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::Mutex;
+#[cfg(feature = "remote-sources")]
+use object_store::{
+    aws::AmazonS3Builder, gcp::GoogleCloudStorageBuilder, http::HttpBuilder, path::Path as ObjectPath, ClientOptions, GetOptions,
+    GetRange, ObjectStore,
+};
+#[cfg(feature = "remote-sources")]
+use tokio::runtime::Runtime;
+
+/// Blocking source of byte ranges of GBAM file. Every source is a
+/// [`RangeFetcher`], so [`super::remote::RemoteReader`] reads regions from it
+/// downloading only the blocks they need.
+pub trait BlockSource: Send + Sync {
+    /// Bytes of `range` of the file, fewer if the file ends before
+    /// `range.end`. `u64::MAX` end reads till the end of file.
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>>;
+}
+
+impl<S: BlockSource> RangeFetcher for S {
+    fn fetch(&self, range: Range<u64>) -> FetchFuture<'_> {
+        let bytes = self.read_range(range);
+        Box::pin(async move { bytes })
+    }
+}
+
+impl BlockSource for Box<dyn BlockSource> {
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        (**self).read_range(range)
+    }
+}
+
+/// Opens block source for `path`:
+/// - `http://`, `https://`, `s3://` and `gs://` URLs - range requests
+///   through [`ObjectSource`], needs the `remote-sources` feature,
+/// - anything else - local file.
+pub fn open_source(path: &str) -> io::Result<Box<dyn BlockSource>> {
+    if is_remote(path) {
+        return open_remote(path);
+    }
+    Ok(Box::new(FileSource::new(File::open(path)?)))
+}
+
+#[cfg(feature = "remote-sources")]
+fn open_remote(url: &str) -> io::Result<Box<dyn BlockSource>> {
+    Ok(Box::new(ObjectSource::new(url)?))
+}
+
+#[cfg(not(feature = "remote-sources"))]
+fn open_remote(url: &str) -> io::Result<Box<dyn BlockSource>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Reading {} needs gbam_tools built with remote-sources feature.", url),
+    ))
+}
+
+/// Whether `path` is opened by [`open_source`] as remote object.
+pub fn is_remote(path: &str) -> bool {
+    ["http://", "https://", "s3://", "gs://"].iter().any(|scheme| path.starts_with(scheme))
+}
+
+/// Local file, read with seek and read.
+pub struct FileSource(Mutex<File>);
+
+impl FileSource {
+    pub fn new(file: File) -> Self {
+        Self(Mutex::new(file))
+    }
+}
+
+impl BlockSource for FileSource {
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut bytes = Vec::new();
+        file.by_ref().take(range.end - range.start).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// HTTP(S) URL, S3 or Google Cloud Storage object, read with range requests
+/// by object_store. The store is created once and keeps its connections
+/// open, so all blocks of a source are fetched over them. S3 and GCS
+/// credentials and region are taken from the environment (`AWS_*` and
+/// `GOOGLE_*` variables, see object_store `from_env` builders).
+#[cfg(feature = "remote-sources")]
+pub struct ObjectSource {
+    url: String,
+    store: Box<dyn ObjectStore>,
+    path: ObjectPath,
+    // Drives requests of the store for blocking reads.
+    runtime: Runtime,
+}
+
+#[cfg(feature = "remote-sources")]
+impl ObjectSource {
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid_url = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid URL {}: {}.", url, reason));
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| invalid_url("no scheme"))?;
+        let (host, key) = rest.split_once('/').ok_or_else(|| invalid_url("no object path"))?;
+        // The store sends the path only, query would be lost.
+        if key.contains('?') {
+            return Err(invalid_url("query is not supported"));
+        }
+        let store: Box<dyn ObjectStore> = match scheme {
+            "s3" => Box::new(AmazonS3Builder::from_env().with_bucket_name(host).build().map_err(io::Error::from)?),
+            "gs" => Box::new(GoogleCloudStorageBuilder::from_env().with_bucket_name(host).build().map_err(io::Error::from)?),
+            "http" | "https" => Box::new(
+                HttpBuilder::new()
+                    .with_url(format!("{}://{}", scheme, host))
+                    .with_client_options(ClientOptions::new().with_allow_http(scheme == "http"))
+                    .build()
+                    .map_err(io::Error::from)?,
+            ),
+            _ => return Err(invalid_url("unknown scheme")),
+        };
+        let path = ObjectPath::from_url_path(key).map_err(|e| invalid_url(&e.to_string()))?;
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        Ok(Self { url: url.to_owned(), store, path, runtime })
+    }
+}
+
+#[cfg(feature = "remote-sources")]
+impl BlockSource for ObjectSource {
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let range = if range.end == u64::MAX {
+            GetRange::Offset(range.start as usize)
+        } else {
+            GetRange::Bounded(range.start as usize..range.end as usize)
+        };
+        let options = GetOptions { range: Some(range), ..GetOptions::default() };
+        // Servers ignoring Range are reported by the store as not partial.
+        let bytes = self
+            .runtime
+            .block_on(async { self.store.get_opts(&self.path, options).await?.bytes().await })
+            .map_err(|e| {
+                let e = io::Error::from(e);
+                io::Error::new(e.kind(), format!("Failed to read {}: {}", self.url, e))
+            })?;
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_file_source() {
+        let dir = TempDir::new("gbam_source_test").unwrap();
+        let path = dir.path().join("blocks");
+        File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+        let source = open_source(path.to_str().unwrap()).unwrap();
+        assert_eq!(source.read_range(2..5).unwrap(), b"234");
+        assert_eq!(source.read_range(8..u64::MAX).unwrap(), b"89");
+        assert!(is_remote("s3://bucket/file.gbam") && !is_remote("file.gbam"));
+    }
+
+    /// Serves `data` with Range requests, returns the address and the
+    /// number of connections accepted so far.
+    #[cfg(feature = "remote-sources")]
+    fn serve_ranges(data: &'static [u8]) -> (std::net::SocketAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{BufRead, BufReader};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 {
+                        let mut range = String::new();
+                        loop {
+                            line.clear();
+                            reader.read_line(&mut line).unwrap();
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                                range = value.trim().to_owned();
+                            }
+                        }
+                        let (start, end) = range.split_once('-').unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end = end.parse::<usize>().map_or(data.len(), |end| (end + 1).min(data.len()));
+                        write!(
+                            stream,
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                            start,
+                            end - 1,
+                            data.len(),
+                            end - start
+                        )
+                        .unwrap();
+                        stream.write_all(&data[start..end]).unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    #[cfg(feature = "remote-sources")]
+    #[test]
+    fn test_object_source() {
+        let (addr, connections) = serve_ranges(b"0123456789");
+        let source = open_source(&format!("http://{}/dir/blocks", addr)).unwrap();
+        assert_eq!(source.read_range(2..5).unwrap(), b"234");
+        assert_eq!(source.read_range(8..u64::MAX).unwrap(), b"89");
+        assert_eq!(source.read_range(20..20).unwrap(), b"");
+        // Blocks are read over one connection.
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(open_source(&format!("http://{}/dir/blocks?sig=1", addr)).is_err());
+    }
+
+    #[cfg(not(feature = "remote-sources"))]
+    #[test]
+    fn test_remote_source_unsupported() {
+        let error = open_source("s3://bucket/file.gbam").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
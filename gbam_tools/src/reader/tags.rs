@@ -0,0 +1,276 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::Write;
+
+/// Value of auxiliary field, borrowed from BAM encoded tags of a record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TagValue<'a> {
+    /// `A`
+    Char(u8),
+    /// `c`, `C`, `s`, `S`, `i` or `I`
+    Int(i64),
+    /// `f`
+    Float(f32),
+    /// `Z`, without terminating NUL
+    String(&'a [u8]),
+    /// `H`, hex digits without terminating NUL
+    Hex(&'a [u8]),
+    /// `B`
+    Array(TagArray<'a>),
+}
+
+impl<'a> TagValue<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            TagValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            TagValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a [u8]> {
+        match self {
+            TagValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Appends value as in SAM: `TYPE:VALUE`, e.g. `i:3` or `B:c,1,-2`.
+    pub fn write_sam(&self, line: &mut Vec<u8>) {
+        match self {
+            TagValue::Char(value) => line.extend_from_slice(&[b'A', b':', *value]),
+            TagValue::Int(value) => write!(line, "i:{}", value).unwrap(),
+            TagValue::Float(value) => write!(line, "f:{}", value).unwrap(),
+            TagValue::String(value) => {
+                line.extend_from_slice(b"Z:");
+                line.extend_from_slice(value);
+            }
+            TagValue::Hex(value) => {
+                line.extend_from_slice(b"H:");
+                line.extend_from_slice(value);
+            }
+            TagValue::Array(array) => {
+                line.extend_from_slice(b"B:");
+                line.push(array.sub_type);
+                if array.sub_type == b'f' {
+                    array.floats().for_each(|value| write!(line, ",{}", value).unwrap());
+                } else {
+                    array.ints().for_each(|value| write!(line, ",{}", value).unwrap());
+                }
+            }
+        }
+    }
+}
+
+/// Numeric array (`B`) tag. Items are decoded on access.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TagArray<'a> {
+    /// Item type: `c`, `C`, `s`, `S`, `i`, `I` or `f`.
+    pub sub_type: u8,
+    bytes: &'a [u8],
+}
+
+impl<'a> TagArray<'a> {
+    pub fn len(&self) -> usize {
+        self.bytes.len() / item_size(self.sub_type)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Items of integer array, empty for float array.
+    pub fn ints(&self) -> impl Iterator<Item = i64> + 'a {
+        let sub_type = self.sub_type;
+        let bytes = if sub_type == b'f' { &[][..] } else { self.bytes };
+        bytes.chunks_exact(item_size(sub_type)).map(move |mut item| read_int(&mut item, sub_type).unwrap())
+    }
+
+    /// Items of float array, empty for integer array.
+    pub fn floats(&self) -> impl Iterator<Item = f32> + 'a {
+        let bytes = if self.sub_type == b'f' { self.bytes } else { &[][..] };
+        bytes.chunks_exact(4).map(|mut item| item.read_f32::<LittleEndian>().unwrap())
+    }
+}
+
+/// Iterator over tags of a record in stored order, see
+/// [`super::record::GbamRecord::tags`]. Values are parsed as the iterator
+/// advances, iteration stops at malformed data.
+#[derive(Clone, Debug)]
+pub struct Tags<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Tags<'a> {
+    /// Tags of BAM encoded auxiliary data.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Value of the first tag named `tag`, e.g. `get(b"NM")`.
+    pub fn get(mut self, tag: &[u8; 2]) -> Option<TagValue<'a>> {
+        self.find(|(name, _)| name == tag).map(|(_, value)| value)
+    }
+
+    fn parse_next(&mut self) -> Option<([u8; 2], TagValue<'a>)> {
+        if self.data.len() < 3 {
+            return None;
+        }
+        let (name, val_type, mut rest) = ([self.data[0], self.data[1]], self.data[2], &self.data[3..]);
+        let value = match val_type {
+            b'A' => TagValue::Char(rest.read_u8().ok()?),
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => TagValue::Int(read_int(&mut rest, val_type)?),
+            b'f' => TagValue::Float(rest.read_f32::<LittleEndian>().ok()?),
+            b'Z' | b'H' => {
+                let end = rest.iter().position(|&c| c == 0)?;
+                let value = &rest[..end];
+                rest = &rest[end + 1..];
+                if val_type == b'Z' {
+                    TagValue::String(value)
+                } else {
+                    TagValue::Hex(value)
+                }
+            }
+            b'B' => {
+                let sub_type = rest.read_u8().ok()?;
+                if !b"cCsSiIf".contains(&sub_type) {
+                    return None;
+                }
+                let count = rest.read_u32::<LittleEndian>().ok()? as usize;
+                let (bytes, tail) = split(rest, count.checked_mul(item_size(sub_type))?)?;
+                rest = tail;
+                TagValue::Array(TagArray { sub_type, bytes })
+            }
+            _ => return None,
+        };
+        self.data = rest;
+        Some((name, value))
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = ([u8; 2], TagValue<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.parse_next();
+        if next.is_none() {
+            self.data = &[];
+        }
+        next
+    }
+}
+
+/// Iterator over BAM encoded tag entries (name, type and value) as they are
+/// stored. Data from a malformed entry on is yielded as the last entry, so no
+/// bytes are lost.
+pub(crate) struct RawEntries<'a>(&'a [u8]);
+
+impl<'a> RawEntries<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+}
+
+impl<'a> Iterator for RawEntries<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut tags = Tags::new(self.0);
+        let len = match tags.parse_next() {
+            Some(_) => self.0.len() - tags.data.len(),
+            None => self.0.len(),
+        };
+        let (entry, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(entry)
+    }
+}
+
+/// Name of tag entry yielded by [`RawEntries`].
+pub(crate) fn entry_tag(entry: &[u8]) -> Option<[u8; 2]> {
+    entry.get(..2).map(|name| [name[0], name[1]])
+}
+
+fn split(bytes: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (bytes.len() >= at).then(|| bytes.split_at(at))
+}
+
+fn item_size(sub_type: u8) -> usize {
+    match sub_type {
+        b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        _ => 4,
+    }
+}
+
+fn read_int(bytes: &mut &[u8], val_type: u8) -> Option<i64> {
+    match val_type {
+        b'c' => bytes.read_i8().ok().map(i64::from),
+        b'C' => bytes.read_u8().ok().map(i64::from),
+        b's' => bytes.read_i16::<LittleEndian>().ok().map(i64::from),
+        b'S' => bytes.read_u16::<LittleEndian>().ok().map(i64::from),
+        b'i' => bytes.read_i32::<LittleEndian>().ok().map(i64::from),
+        b'I' => bytes.read_u32::<LittleEndian>().ok().map(i64::from),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        let mut data = b"NMC\x02XAAyRGZgrp1\0".to_vec();
+        data.extend_from_slice(b"ASs\xfe\xff");
+        data.extend_from_slice(b"XFf");
+        data.extend_from_slice(&1.5f32.to_le_bytes());
+        data.extend_from_slice(b"ZBBc\x03\0\0\0\x01\xff\x02");
+        data.extend_from_slice(b"ZHH1AE3\0");
+        let tags: Vec<_> = Tags::new(&data).collect();
+        assert_eq!(tags.len(), 7);
+        assert_eq!(tags[0], (*b"NM", TagValue::Int(2)));
+        assert_eq!(tags[1], (*b"XA", TagValue::Char(b'y')));
+        assert_eq!(Tags::new(&data).get(b"RG").unwrap().as_str(), Some(&b"grp1"[..]));
+        assert_eq!(Tags::new(&data).get(b"AS").unwrap().as_int(), Some(-2));
+        assert_eq!(Tags::new(&data).get(b"XF").unwrap().as_float(), Some(1.5));
+        assert_eq!(Tags::new(&data).get(b"XX"), None);
+        match Tags::new(&data).get(b"ZB").unwrap() {
+            TagValue::Array(array) => {
+                assert_eq!(array.len(), 3);
+                assert_eq!(array.ints().collect::<Vec<_>>(), vec![1, -1, 2]);
+                assert_eq!(array.floats().count(), 0);
+            }
+            value => panic!("Unexpected value {:?}", value),
+        }
+
+        let mut line = Vec::new();
+        for (tag, value) in Tags::new(&data) {
+            line.extend_from_slice(&tag);
+            line.push(b':');
+            value.write_sam(&mut line);
+            line.push(b' ');
+        }
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "NM:i:2 XA:A:y RG:Z:grp1 AS:i:-2 XF:f:1.5 ZB:B:c,1,-1,2 ZH:H:1AE3 "
+        );
+
+        // Truncated array ends iteration.
+        assert_eq!(Tags::new(&data[..data.len() - 9]).count(), 5);
+
+        let entries: Vec<_> = RawEntries::new(&data[..data.len() - 9]).collect();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[0], b"NMC\x02");
+        assert_eq!(entry_tag(entries[5]), Some(*b"ZB"));
+        assert_eq!(entries.concat(), &data[..data.len() - 9]);
+    }
+}
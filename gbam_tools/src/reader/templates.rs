@@ -0,0 +1,236 @@
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::Reader;
+use super::record::GbamRecord;
+use bam_tools::record::fields::Fields;
+use std::io;
+use std::sync::Arc;
+
+/// Records of one template (read name). Primary alignments go first, the
+/// first segment (0x40) before the last one, then secondary and
+/// supplementary alignments. Records of the same kind are in file order.
+pub struct Template<'a> {
+    pub records: &'a [GbamRecord],
+}
+
+impl Template<'_> {
+    /// Read name without terminating NUL.
+    pub fn name(&self) -> &[u8] {
+        self.records[0].name()
+    }
+
+    /// Primary alignment of the first segment, or of the only one stored.
+    pub fn primary(&self) -> Option<&GbamRecord> {
+        self.records.first().filter(|rec| is_primary(rec))
+    }
+
+    /// Primary alignment of the other segment of a pair.
+    pub fn mate(&self) -> Option<&GbamRecord> {
+        self.records.get(1).filter(|rec| is_primary(rec))
+    }
+
+    /// Secondary alignments (0x100).
+    pub fn secondary(&self) -> impl Iterator<Item = &GbamRecord> {
+        self.records.iter().filter(|rec| rec.is_secondary() && !rec.is_supplementary())
+    }
+
+    /// Supplementary alignments (0x800).
+    pub fn supplementary(&self) -> impl Iterator<Item = &GbamRecord> {
+        self.records.iter().filter(|rec| rec.is_supplementary())
+    }
+}
+
+fn is_primary(rec: &GbamRecord) -> bool {
+    !rec.is_secondary() && !rec.is_supplementary()
+}
+
+/// Order of records in [`Template`].
+fn rank(rec: &GbamRecord) -> (u8, bool) {
+    let kind = match (rec.is_supplementary(), rec.is_secondary()) {
+        (true, _) => 2,
+        (false, true) => 1,
+        (false, false) => 0,
+    };
+    (kind, rec.is_last_segment() && !rec.is_first_segment())
+}
+
+/// Iterates over templates of file, see [`Reader::templates`].
+pub struct Templates<'a> {
+    reader: &'a mut Reader,
+    next: usize,
+    buffers: Vec<GbamRecord>,
+    len: usize,
+    // Records are found by name index, otherwise records of a template are
+    // adjacent and `buffers[len]` may hold the first record of the next one.
+    by_name_index: bool,
+    peeked: bool,
+    // Restored on drop.
+    saved_template: ParsingTemplate,
+    saved_index_mapping: Option<Arc<Vec<u32>>>,
+}
+
+impl<'a> Templates<'a> {
+    pub(crate) fn new(reader: &'a mut Reader) -> io::Result<Self> {
+        let by_name_index = reader.file_meta.may_be_coordinate_sorted();
+        if by_name_index && reader.file_meta.name_index().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Records of coordinate sorted file are grouped by name index, which the file doesn't have.",
+            ));
+        }
+        let fields = [Fields::ReadName, Fields::Flags];
+        reader.init_missing_columns(&fields);
+        let mut template = reader.parsing_template.clone();
+        fields.iter().for_each(|field| template.set(field, true));
+        let saved_template = std::mem::replace(&mut reader.parsing_template, template);
+        let saved_index_mapping = reader.index_mapping.take();
+        Ok(Self {
+            reader,
+            next: 0,
+            buffers: Vec::new(),
+            len: 0,
+            by_name_index,
+            peeked: false,
+            saved_template,
+            saved_index_mapping,
+        })
+    }
+
+    pub fn next_template(&mut self) -> io::Result<Option<Template<'_>>> {
+        if self.by_name_index {
+            self.next_indexed()?;
+        } else {
+            self.next_adjacent();
+        }
+        if self.len == 0 {
+            return Ok(None);
+        }
+        let records = &mut self.buffers[..self.len];
+        records.sort_by_key(rank);
+        Ok(Some(Template { records }))
+    }
+
+    fn next_adjacent(&mut self) {
+        let len = std::mem::take(&mut self.len);
+        if self.peeked {
+            self.buffers.swap(0, len);
+            self.peeked = false;
+            self.len = 1;
+        }
+        while self.next < self.reader.amount {
+            let rec_num = self.next;
+            self.next += 1;
+            if !self.reader.passes_filters(rec_num) {
+                continue;
+            }
+            if self.buffers.len() == self.len {
+                self.buffers.push(GbamRecord::default());
+            }
+            self.reader.fill_record(rec_num, &mut self.buffers[self.len]);
+            if self.len > 0 && self.buffers[self.len].name() != self.buffers[0].name() {
+                self.peeked = true;
+                return;
+            }
+            self.len += 1;
+        }
+    }
+
+    fn next_indexed(&mut self) -> io::Result<()> {
+        self.len = 0;
+        if self.buffers.is_empty() {
+            self.buffers.push(GbamRecord::default());
+        }
+        let name_only = ParsingTemplate::new_with(&[Fields::ReadName]);
+        while self.len == 0 && self.next < self.reader.amount {
+            let rec_num = self.next;
+            self.next += 1;
+            if !self.reader.passes_filters(rec_num) {
+                continue;
+            }
+            let template = std::mem::replace(&mut self.reader.parsing_template, name_only.clone());
+            self.reader.fill_record(rec_num, &mut self.buffers[0]);
+            self.reader.parsing_template = template;
+            let mut found = self.reader.find_by_name(self.buffers[0].read_name.as_ref().unwrap())?;
+            found.retain(|&rec_num| self.reader.passes_filters(rec_num));
+            // Template is visited at its first record.
+            if found.first() != Some(&rec_num) {
+                continue;
+            }
+            if self.buffers.len() < found.len() {
+                self.buffers.resize_with(found.len(), GbamRecord::default);
+            }
+            for (buf, &rec_num) in self.buffers.iter_mut().zip(&found) {
+                self.reader.fill_record(rec_num, buf);
+            }
+            self.len = found.len();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Templates<'_> {
+    fn drop(&mut self) {
+        self.reader.parsing_template = std::mem::take(&mut self.saved_template);
+        self.reader.index_mapping = self.saved_index_mapping.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_util::gbam_bytes_from_sam;
+    use bam_tools::record::fields::Fields;
+
+    fn reader(sam: &str, name_index: bool) -> Reader {
+        let bytes = gbam_bytes_from_sam(sam, |writer| writer.set_name_index(name_index));
+        Reader::from_bytes(bytes, ParsingTemplate::new_with(&[Fields::Pos])).unwrap()
+    }
+
+    fn templates(reader: &mut Reader) -> Vec<String> {
+        let mut templates = reader.templates().unwrap();
+        let mut res = Vec::new();
+        while let Some(template) = templates.next_template().unwrap() {
+            let records: Vec<String> = template.records.iter().map(|rec| format!("{}:{}", rec.flag.unwrap(), rec.pos.unwrap() + 1)).collect();
+            res.push(format!("{} {}", String::from_utf8_lossy(template.name()), records.join(",")));
+        }
+        res
+    }
+
+    #[test]
+    fn test_templates() {
+        let header = "@SQ\tSN:chr1\tLN:1000\n";
+        let coordinate = format!(
+            "{}a\t2145\tchr1\t5\t60\t5S5M\t=\t20\t0\t*\t*\n\
+a\t163\tchr1\t10\t60\t10M\t=\t20\t20\t*\t*\n\
+b\t0\tchr1\t15\t60\t10M\t*\t0\t0\t*\t*\n\
+a\t83\tchr1\t20\t60\t10M\t=\t10\t-20\t*\t*\n\
+b\t256\tchr1\t30\t0\t10M\t*\t0\t0\t*\t*\n",
+            header
+        );
+        let expected = ["a 83:20,163:10,2145:5", "b 0:15,256:30"];
+        assert_eq!(templates(&mut reader(&coordinate, true)), expected);
+        assert!(reader(&coordinate, false).templates().is_err());
+
+        let name_sorted = format!(
+            "@HD\tVN:1.6\tSO:queryname\n{}a\t163\tchr1\t10\t60\t10M\t=\t20\t20\t*\t*\n\
+a\t83\tchr1\t20\t60\t10M\t=\t10\t-20\t*\t*\n\
+a\t2145\tchr1\t5\t60\t5S5M\t=\t20\t0\t*\t*\n\
+b\t256\tchr1\t30\t0\t10M\t*\t0\t0\t*\t*\n\
+b\t0\tchr1\t15\t60\t10M\t*\t0\t0\t*\t*\n",
+            header
+        );
+        let mut reader = reader(&name_sorted, false);
+        assert_eq!(templates(&mut reader), expected);
+        let mut templates = reader.templates().unwrap();
+        let template = templates.next_template().unwrap().unwrap();
+        assert_eq!(template.primary().unwrap().flag, Some(83));
+        assert_eq!(template.mate().unwrap().flag, Some(163));
+        assert_eq!(template.supplementary().count(), 1);
+        assert_eq!(template.secondary().count(), 0);
+        drop(templates);
+
+        // Parsing template of reader is restored.
+        let mut rec = crate::reader::record::GbamRecord::default();
+        reader.fill_record(0, &mut rec);
+        assert!(rec.read_name.is_none());
+    }
+}
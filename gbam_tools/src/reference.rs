@@ -0,0 +1,181 @@
+use crate::header::SamHeader;
+use crate::meta::FileMeta;
+use crate::writer::{open_for_update, rewrite_meta};
+use flate2::read::MultiGzDecoder;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Sequence of FASTA file with its length and M5 checksum.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FastaContig {
+    pub name: String,
+    pub length: u64,
+    /// Lowercase hex MD5 of the sequence as defined for @SQ M5: uppercase,
+    /// without whitespace.
+    pub md5: String,
+}
+
+/// Difference between reference sequences of GBAM file and FASTA.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReferenceMismatch {
+    /// Sequence of the file is not in FASTA.
+    Missing { name: String },
+    Length { name: String, expected: u64, found: u64 },
+    Checksum { name: String, expected: String, found: String },
+}
+
+impl fmt::Display for ReferenceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { name } => write!(f, "{}\tmissing", name),
+            Self::Length { name, expected, found } => write!(f, "{}\tlength\t{}\t{}", name, expected, found),
+            Self::Checksum { name, expected, found } => write!(f, "{}\tM5\t{}\t{}", name, expected, found),
+        }
+    }
+}
+
+/// Lengths and checksums of sequences of FASTA read from `reader`. Names
+/// are the first word of the description line.
+pub fn fasta_checksums<R: BufRead>(reader: R) -> io::Result<Vec<FastaContig>> {
+    let mut contigs = Vec::new();
+    let mut current: Option<(String, u64, md5::Context)> = None;
+    let mut finish = |current: Option<(String, u64, md5::Context)>| {
+        if let Some((name, length, digest)) = current {
+            contigs.push(FastaContig { name, length, md5: format!("{:x}", digest.compute()) });
+        }
+    };
+    let mut seq = Vec::new();
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if let Some(description) = line.strip_prefix(b">") {
+            let description = String::from_utf8_lossy(description);
+            let name = description.split_whitespace().next().unwrap_or_default().to_owned();
+            finish(current.replace((name, 0, md5::Context::new())));
+            continue;
+        }
+        let (_, length, digest) = current
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "FASTA sequence before the first description line."))?;
+        seq.clear();
+        seq.extend(line.iter().filter(|c| (33..=126).contains(*c)).map(u8::to_ascii_uppercase));
+        *length += seq.len() as u64;
+        digest.consume(&seq);
+    }
+    finish(current);
+    Ok(contigs)
+}
+
+/// [`fasta_checksums`] of FASTA file, which may be gzip compressed.
+pub fn read_fasta_checksums<P: AsRef<Path>>(path: P) -> io::Result<Vec<FastaContig>> {
+    let file = File::open(&path)?;
+    if path.as_ref().extension().is_some_and(|ext| ext == "gz") {
+        fasta_checksums(BufReader::new(MultiGzDecoder::new(file)))
+    } else {
+        fasta_checksums(BufReader::new(file))
+    }
+}
+
+/// M5 checksums of @SQ lines of BAM header, by sequence name.
+pub(crate) fn checksums_from_header(sam_header: &[u8]) -> BTreeMap<String, String> {
+    SamHeader::from_bam_header(sam_header)
+        .records_of("SQ")
+        .filter_map(|sq| Some((sq.get("SN")?.to_owned(), sq.get("M5")?.to_ascii_lowercase())))
+        .collect()
+}
+
+/// Compares reference sequences of file with `contigs` of FASTA. Stored
+/// checksums are compared where present, lengths otherwise. Returns
+/// differences, empty if FASTA is the reference of the file.
+pub fn validate_reference(file_meta: &FileMeta, contigs: &[FastaContig]) -> Vec<ReferenceMismatch> {
+    let contigs: BTreeMap<&str, &FastaContig> = contigs.iter().map(|contig| (contig.name.as_str(), contig)).collect();
+    let mut mismatches = Vec::new();
+    for (name, length) in file_meta.get_ref_seqs() {
+        let Some(contig) = contigs.get(name.as_str()) else {
+            mismatches.push(ReferenceMismatch::Missing { name: name.clone() });
+            continue;
+        };
+        match file_meta.ref_checksums().get(name) {
+            Some(md5) if *md5 != contig.md5 => {
+                mismatches.push(ReferenceMismatch::Checksum { name: name.clone(), expected: md5.clone(), found: contig.md5.clone() })
+            }
+            None if *length as u64 != contig.length => {
+                mismatches.push(ReferenceMismatch::Length { name: name.clone(), expected: *length as u64, found: contig.length })
+            }
+            _ => {}
+        }
+    }
+    mismatches
+}
+
+/// Computes checksums of reference sequences of GBAM file from `fasta` and
+/// stores them in the file meta, which is rewritten in place. Fails if
+/// FASTA is not the reference of the file by lengths. Returns number of
+/// checksums stored.
+pub fn store_reference_checksums<P: AsRef<Path>, F: AsRef<Path>>(path: P, fasta: F) -> io::Result<usize> {
+    let contigs = read_fasta_checksums(fasta)?;
+    let (mut file, mut file_info, mut file_meta) = open_for_update(path)?;
+    let mut checksums = file_meta.ref_checksums().clone();
+    let mismatches: Vec<ReferenceMismatch> = validate_reference(&file_meta, &contigs)
+        .into_iter()
+        .filter(|mismatch| matches!(mismatch, ReferenceMismatch::Length { .. }))
+        .collect();
+    if let Some(mismatch) = mismatches.first() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("FASTA does not match reference sequences of the file: {}.", mismatch)));
+    }
+    for contig in contigs {
+        if file_meta.get_ref_seqs().iter().any(|(name, _)| *name == contig.name) {
+            checksums.insert(contig.name, contig.md5);
+        }
+    }
+    let stored = checksums.len();
+    file_meta.set_ref_checksums(checksums);
+    rewrite_meta(&mut file, &mut file_info, &file_meta)?;
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::test_util::write_sam;
+    use crate::{Codecs, Writer};
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const FASTA: &str = ">chr1 first\nacgt\nACGTN\n>chr2\nAC GT\n";
+
+    #[test]
+    fn test_validate_reference() {
+        let contigs = fasta_checksums(FASTA.as_bytes()).unwrap();
+        assert_eq!(contigs[0].length, 9);
+        assert_eq!(contigs[0].md5, format!("{:x}", md5::compute(b"ACGTACGTN")));
+        assert_eq!(contigs[1].md5, format!("{:x}", md5::compute(b"ACGT")));
+
+        let dir = TempDir::new("gbam_reference_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        let sam = format!("@SQ\tSN:chr1\tLN:9\n@SQ\tSN:chr2\tLN:4\tM5:{}\n", contigs[1].md5.to_uppercase());
+        write_sam(&sam, |sam_header, ref_seqs| {
+            Writer::new_no_stats(File::create(&path).unwrap(), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false)
+        });
+
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.file_meta.ref_checksums().len(), 1);
+        assert!(validate_reference(&reader.file_meta, &contigs).is_empty());
+        let other = fasta_checksums(">chr1\nACGTACGTA\n>chr2\nACGA\n".as_bytes()).unwrap();
+        let mismatches = validate_reference(&reader.file_meta, &other);
+        assert_eq!(mismatches.len(), 1);
+        assert!(matches!(&mismatches[0], ReferenceMismatch::Checksum { name, .. } if name == "chr2"));
+
+        let fasta = dir.path().join("ref.fa");
+        File::create(&fasta).unwrap().write_all(FASTA.as_bytes()).unwrap();
+        assert_eq!(store_reference_checksums(&path, &fasta).unwrap(), 2);
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.validate_reference(&fasta).unwrap(), Vec::new());
+        assert_eq!(validate_reference(&reader.file_meta, &other).len(), 2);
+        assert!(matches!(&validate_reference(&reader.file_meta, &other[1..])[0], ReferenceMismatch::Missing { name } if name == "chr1"));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::meta::{BlockHeader, BlockMeta, FileInfo, FileMeta, BLOCK_HEADER_SIZE, BLOCK_MAGIC, FILE_INFO_SIZE};
+use crate::slice::slice_with_meta;
+use bam_tools::record::fields::Fields;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Seek, Write};
+
+/// Outcome of [`rescue_gbam`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RescueStats {
+    /// Blocks found by their headers.
+    pub found_blocks: u64,
+    /// Records present in every column, which are recovered.
+    pub records: usize,
+}
+
+/// Recovers records of GBAM file `bytes` whose meta was never written, e.g.
+/// of killed conversion, into standalone GBAM file `out`. Blocks are found
+/// by headers written before them (see [`crate::meta::BlockHeader`]) and
+/// the leading records present in all columns are kept; records still
+/// buffered by the writer are lost. Files written before block headers
+/// can't be recovered.
+pub fn rescue_gbam<W: Write + Seek>(bytes: &[u8], out: W, full_command: String) -> io::Result<RescueStats> {
+    let (meta, stats) = recover_meta(bytes)?;
+    let file_info = FileInfo::new(0, 0, String::new(), false);
+    slice_with_meta(bytes, &file_info, &meta, 0..stats.records, out, full_command)?;
+    Ok(stats)
+}
+
+/// Meta of the last meta block found, with block lists of found blocks.
+/// Rewritten blocks (e.g. the last block of a column continued by append)
+/// replace ones found earlier. Columns keep leading consecutive blocks.
+fn recover_meta(bytes: &[u8]) -> io::Result<(FileMeta, RescueStats)> {
+    let mut meta: Option<FileMeta> = None;
+    let mut columns: HashMap<(Fields, Option<usize>), BTreeMap<u64, BlockMeta>> = HashMap::new();
+    let mut stats = RescueStats::default();
+    let mut pos = FILE_INFO_SIZE;
+    while pos < bytes.len() {
+        let header = match BlockHeader::parse(&bytes[pos..]) {
+            Some(header) => header,
+            None => {
+                // Bytes which are not a block, e.g. meta of an earlier
+                // version of appended file, or a torn block.
+                match bytes[pos + 1..].windows(4).position(|window| window == BLOCK_MAGIC) {
+                    Some(skip) => pos += skip + 1,
+                    None => break,
+                }
+                continue;
+            }
+        };
+        let seekpos = pos + BLOCK_HEADER_SIZE;
+        pos = seekpos + header.block_size as usize;
+        let field = match header.field {
+            Some(field) => field,
+            None => {
+                let data = &bytes[seekpos..pos];
+                meta = Some(serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?);
+                continue;
+            }
+        };
+        stats.found_blocks += 1;
+        let block = BlockMeta {
+            seekpos: seekpos as u64,
+            numitems: header.numitems,
+            block_size: header.block_size,
+            uncompressed_size: header.uncompressed_size,
+            stats: None,
+            column_stats: Vec::new(),
+        };
+        columns.entry((field, header.tag_col)).or_default().insert(header.block_num, block);
+    }
+    let mut meta = meta.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No meta block is found, file was written without block headers."))?;
+
+    let tag_cols = meta.tag_columns().len();
+    let all_columns = Fields::iterator()
+        .map(|field| (*field, None))
+        .chain((0..tag_cols).flat_map(|col| [(Fields::RawTags, Some(col)), (Fields::RawTagsLen, Some(col))]));
+    let mut records = usize::MAX;
+    for (field, tag_col) in all_columns {
+        let found = columns.remove(&(field, tag_col)).unwrap_or_default();
+        let blocks: Vec<BlockMeta> = found.into_iter().enumerate().take_while(|(i, (num, _))| *i as u64 == *num).map(|(_, (_, block))| block).collect();
+        records = records.min(blocks.iter().map(|block| block.numitems as usize).sum());
+        *meta.get_column_blocks(&field, tag_col) = blocks;
+    }
+    stats.records = records;
+    Ok((meta, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::{read_footer, Reader};
+    use crate::reader::record::GbamRecord;
+    use crate::test_util::gbam_bytes_from_sam;
+    use crate::Codecs;
+    use std::io::Cursor;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r1\t0\tchr1\t10\t60\t5M\t*\t0\t0\tACGTA\tIIIII\tNM:i:1\n\
+r2\t0\tchr1\t20\t60\t5M\t*\t0\t0\tACGTA\tIIIII\n\
+r3\t4\t*\t0\t0\t*\t*\t0\t0\tACG\tIII\n";
+
+    #[test]
+    fn test_rescue() {
+        let mut bytes = gbam_bytes_from_sam(SAM, |writer| writer.set_tag_columns(&[*b"NM"], Codecs::Gzip));
+        // Meta and file info are lost.
+        let (file_info, _) = read_footer(&bytes).unwrap();
+        bytes.truncate(file_info.seekpos as usize);
+        bytes[..FILE_INFO_SIZE].fill(0);
+
+        let (meta, stats) = recover_meta(&bytes).unwrap();
+        assert_eq!(stats.records, 3);
+        assert_eq!(meta.tag_columns().len(), 1);
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(rescue_gbam(&bytes, &mut out, String::new()).unwrap(), stats);
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::from_bytes(out.into_inner(), template).unwrap();
+        assert_eq!(reader.amount, 3);
+        let mut rec = GbamRecord::default();
+        reader.fill_record(0, &mut rec);
+        assert_eq!(rec.pos, Some(9));
+        assert_eq!(rec.tags.as_deref(), Some(&b"NMC\x01"[..]));
+
+        // Records of a torn block are lost in all columns.
+        let last = meta.view_blocks(&Fields::RawQual).last().unwrap();
+        bytes.truncate((last.seekpos + u64::from(last.block_size)) as usize - 1);
+        assert_eq!(recover_meta(&bytes).unwrap().1.records, 0);
+
+        assert!(rescue_gbam(&[0; FILE_INFO_SIZE + 10], Cursor::new(Vec::new()), String::new()).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::compressor::compress;
+use crate::meta::{encode_meta, BlockMeta, Codecs, FileInfo, FileMeta, Stat, FILE_INFO_SIZE};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::read_footer;
+use crate::writer::{calc_crc_for_meta_bytes, write_file_info};
+use bam_tools::record::fields::{field_item_size, field_type, is_data_field, var_size_field_to_index, FieldType, Fields};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Blocks written by [`slice_gbam`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SliceStats {
+    pub records: usize,
+    /// Blocks copied without decompression.
+    pub copied_blocks: u64,
+    /// Blocks decompressed, cut and compressed again.
+    pub encoded_blocks: u64,
+}
+
+/// Writes records `range` of GBAM file `bytes` to `out` as a standalone GBAM
+/// file. Blocks lying wholly inside the range are copied as stored, only
+/// boundary blocks are cut and compressed again. Fixed sized columns keep
+/// blocks of equal size, so when the range does not start at their block
+/// boundary all their blocks are re-encoded. Header, reference sequences,
+/// tag columns and sort order are kept. Interval and name indexes and
+/// fingerprint are not.
+pub fn slice_gbam<W: Write + Seek>(bytes: &[u8], range: Range<usize>, out: W, full_command: String) -> io::Result<SliceStats> {
+    let (file_info, src_meta) = read_footer(bytes)?;
+    slice_with_meta(bytes, &file_info, &src_meta, range, out, full_command)
+}
+
+/// Same as [`slice_gbam`] with meta of `bytes` given, e.g. recovered by
+/// [`crate::rescue`].
+pub(crate) fn slice_with_meta<W: Write + Seek>(bytes: &[u8], file_info: &FileInfo, src_meta: &FileMeta, range: Range<usize>, mut out: W, full_command: String) -> io::Result<SliceStats> {
+    let amount: usize = src_meta.view_blocks(&Fields::RefID).iter().map(|block| block.numitems as usize).sum();
+    if range.start > range.end || range.end > amount {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Record range {}..{} is out of file with {} records.", range.start, range.end, amount),
+        ));
+    }
+    let mut meta = src_meta.clone();
+    meta.set_fingerprint(None);
+    meta.set_interval_index(None);
+    meta.set_name_index(None);
+    if !full_command.is_empty() {
+        meta.add_program_record(&full_command);
+    }
+
+    out.seek(SeekFrom::Start(FILE_INFO_SIZE as u64))?;
+    let mut slicer = Slicer { bytes, src_meta, range: range.clone(), out: &mut out, stats: SliceStats { records: range.len(), ..Default::default() } };
+    let tag_cols = (0..src_meta.tag_columns().len()).map(|tag_col| (&Fields::RawTags, Some(tag_col)));
+    for (field, tag_col) in Fields::iterator().filter(|field| is_data_field(field)).map(|field| (field, None)).chain(tag_cols) {
+        match field_type(field) {
+            FieldType::FixedSized => {
+                *meta.get_column_blocks(field, tag_col) = slicer.fixed_column(field, tag_col, None)?;
+            }
+            FieldType::VariableSized => {
+                let (data_blocks, shift) = slicer.variable_column(field, tag_col)?;
+                *meta.get_column_blocks(field, tag_col) = data_blocks;
+                let index_field = var_size_field_to_index(field);
+                *meta.get_column_blocks(&index_field, tag_col) = slicer.fixed_column(&index_field, tag_col, shift)?;
+            }
+        }
+    }
+    let stats = slicer.stats;
+
+    let meta_start_pos = out.stream_position()?;
+    let mut new_info = FileInfo::new(meta_start_pos, 0, full_command, file_info.is_sorted);
+    new_info.set_binary_meta(file_info.binary_meta());
+    let meta_bytes = encode_meta(&new_info, &meta);
+    out.write_all(&meta_bytes)?;
+    new_info.crc32 = calc_crc_for_meta_bytes(&meta_bytes);
+    new_info.meta_size = Some(meta_bytes.len() as u64);
+    new_info.set_features(&meta);
+    write_file_info(&mut out, &new_info)?;
+    out.flush()?;
+    Ok(stats)
+}
+
+// Records whose index offsets shift by the number of bytes cut from the
+// start of their data block.
+type Shift = Option<(Range<usize>, u32)>;
+
+struct Slicer<'a, W> {
+    bytes: &'a [u8],
+    src_meta: &'a FileMeta,
+    range: Range<usize>,
+    out: &'a mut W,
+    stats: SliceStats,
+}
+
+impl<W: Write + Seek> Slicer<'_, W> {
+    /// Blocks of column with record ranges overlapping the sliced range.
+    fn overlapping(&self, field: &Fields, tag_col: Option<usize>) -> Vec<(Range<usize>, BlockMeta)> {
+        let mut start = 0;
+        let mut blocks = Vec::new();
+        for block in self.src_meta.view_column_blocks(field, tag_col) {
+            let block_range = start..start + block.numitems as usize;
+            start = block_range.end;
+            if block_range.start < self.range.end && block_range.end > self.range.start {
+                blocks.push((block_range, block.clone()));
+            }
+        }
+        blocks
+    }
+
+    fn decompress(&self, field: &Fields, tag_col: Option<usize>, block: &BlockMeta) -> io::Result<Vec<u8>> {
+        let start = block.seekpos as usize;
+        let compressed = self
+            .bytes
+            .get(start..start + block.block_size as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Block of {} is out of file.", field)))?;
+        let mut data = vec![0; block.uncompressed_size as usize];
+        if block.uncompressed_size > 0 {
+            decompress_block(compressed, &mut data, self.src_meta.get_column_codec(field, tag_col))?;
+        }
+        Ok(data)
+    }
+
+    fn copy_block(&mut self, block: &BlockMeta) -> io::Result<BlockMeta> {
+        let start = block.seekpos as usize;
+        let seekpos = self.out.stream_position()?;
+        self.out.write_all(&self.bytes[start..start + block.block_size as usize])?;
+        self.stats.copied_blocks += 1;
+        Ok(BlockMeta { seekpos, ..block.clone() })
+    }
+
+    fn write_block(&mut self, data: &[u8], numitems: usize, codec: Codecs, stats: Option<Stat>) -> io::Result<BlockMeta> {
+        let compressed = compress(data, Vec::new(), codec);
+        let seekpos = self.out.stream_position()?;
+        self.out.write_all(&compressed)?;
+        self.stats.encoded_blocks += 1;
+        Ok(BlockMeta {
+            seekpos,
+            numitems: numitems as u32,
+            block_size: compressed.len() as u32,
+            uncompressed_size: data.len() as u64,
+            stats,
+            // Not collected for records cut out of blocks.
+            column_stats: Vec::new(),
+        })
+    }
+
+    /// Slices variable sized column. Returns its blocks and, if the first
+    /// block was cut at its start, records of that block with the number of
+    /// bytes cut, by which their index offsets shift.
+    fn variable_column(&mut self, field: &Fields, tag_col: Option<usize>) -> io::Result<(Vec<BlockMeta>, Shift)> {
+        let index_field = var_size_field_to_index(field);
+        let codec = *self.src_meta.get_column_codec(field, tag_col);
+        let mut blocks = Vec::new();
+        let mut shift = None;
+        for (block_range, block) in self.overlapping(field, tag_col) {
+            let start = self.range.start.max(block_range.start);
+            let end = self.range.end.min(block_range.end);
+            if start == block_range.start && end == block_range.end {
+                blocks.push(self.copy_block(&block)?);
+                continue;
+            }
+            let data = self.decompress(field, tag_col, &block)?;
+            let offset = |rec_num: usize| self.index_value(&index_field, tag_col, rec_num);
+            let data_start = if start > block_range.start { offset(start - 1)? as usize } else { 0 };
+            let data_end = offset(end - 1)? as usize;
+            if data_start > 0 {
+                shift = Some((start..end, data_start as u32));
+            }
+            blocks.push(self.write_block(&data[data_start..data_end], end - start, codec, None)?);
+        }
+        Ok((blocks, shift))
+    }
+
+    /// Value of fixed sized u32 column, e.g. offset of index column.
+    fn index_value(&self, field: &Fields, tag_col: Option<usize>, rec_num: usize) -> io::Result<u32> {
+        let mut start = 0;
+        for block in self.src_meta.view_column_blocks(field, tag_col) {
+            if rec_num < start + block.numitems as usize {
+                let data = self.decompress(field, tag_col, block)?;
+                let offset = (rec_num - start) * 4;
+                return Ok(LittleEndian::read_u32(&data[offset..offset + 4]));
+            }
+            start += block.numitems as usize;
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("Record {} is out of {} column.", rec_num, field)))
+    }
+
+    /// Slices fixed sized column into blocks of the same number of items as
+    /// in the source. `shift` is subtracted from u32 values of given records.
+    fn fixed_column(&mut self, field: &Fields, tag_col: Option<usize>, shift: Shift) -> io::Result<Vec<BlockMeta>> {
+        let item_size = field_item_size(field).unwrap();
+        let codec = *self.src_meta.get_column_codec(field, tag_col);
+        let src_blocks = self.src_meta.view_column_blocks(field, tag_col);
+        let block_len = src_blocks.first().map_or(1, |block| block.numitems.max(1) as usize);
+        let with_stats = src_blocks.first().is_some_and(|block| block.stats.is_some());
+        let mut blocks = Vec::new();
+        // Items not written yet.
+        let mut pending: Vec<u8> = Vec::new();
+        for (block_range, block) in self.overlapping(field, tag_col) {
+            let start = self.range.start.max(block_range.start);
+            let end = self.range.end.min(block_range.end);
+            let shifted = shift.as_ref().is_some_and(|(recs, _)| recs.start < block_range.end && recs.end > block_range.start);
+            if pending.is_empty() && start == block_range.start && end == block_range.end && !shifted {
+                blocks.push(self.copy_block(&block)?);
+                continue;
+            }
+            let data = self.decompress(field, tag_col, &block)?;
+            let mut items = data[(start - block_range.start) * item_size..(end - block_range.start) * item_size].to_vec();
+            if let Some((recs, by)) = shift.as_ref() {
+                for rec_num in start.max(recs.start)..end.min(recs.end) {
+                    let offset = (rec_num - start) * item_size;
+                    let value = LittleEndian::read_u32(&items[offset..]) - by;
+                    LittleEndian::write_u32(&mut items[offset..], value);
+                }
+            }
+            pending.extend_from_slice(&items);
+            while pending.len() >= block_len * item_size {
+                let rest = pending.split_off(block_len * item_size);
+                let stats = with_stats.then(|| block_stats(&pending));
+                blocks.push(self.write_block(&pending, block_len, codec, stats)?);
+                pending = rest;
+            }
+        }
+        if !pending.is_empty() {
+            let stats = with_stats.then(|| block_stats(&pending));
+            blocks.push(self.write_block(&pending, pending.len() / item_size, codec, stats)?);
+        }
+        Ok(blocks)
+    }
+}
+
+/// Min and max of i32 items (RefID or Pos).
+fn block_stats(items: &[u8]) -> Stat {
+    let mut stat = Stat::default();
+    items.chunks_exact(4).for_each(|item| stat.update(LittleEndian::read_i32(item)));
+    stat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::gbam_to_sam::format_sam_record;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::test_util::write_sam;
+    use crate::{SortOrder, Writer};
+    use std::io::Cursor;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\tRG:Z:grp\n\
+r2\t147\tchr1\t21\t60\t5M\t=\t11\t-15\tACGTA\t*\tNM:i:0\n\
+r3\t0\tchr2\t5\t30\t4M\t*\t0\t0\tGGCC\t++++\n\
+r4\t4\t*\t0\t0\t*\t*\t0\t0\tACG\tIII\n";
+
+    fn sam_lines(bytes: Vec<u8>) -> Vec<String> {
+        let mut tmpl = ParsingTemplate::new();
+        tmpl.set_all();
+        let mut reader = Reader::from_bytes(bytes, tmpl).unwrap();
+        let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+        let mut lines = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            let mut line = Vec::new();
+            format_sam_record(rec, &ref_seqs, &mut line);
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        lines
+    }
+
+    #[test]
+    fn test_slice_gbam() {
+        let writer = write_sam(SAM, |sam_header, ref_seqs| {
+            let mut writer = Writer::new(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, vec![Fields::RefID, Fields::Pos], ref_seqs, sam_header, String::new(), true);
+            writer.set_tag_columns(&[*b"NM"], Codecs::Gzip);
+            writer
+        });
+        let bytes = writer.into_inner().into_inner();
+        let all = sam_lines(bytes.clone());
+
+        for range in [0..4, 0..2, 1..3, 3..4, 2..2] {
+            let mut out = Cursor::new(Vec::new());
+            let stats = slice_gbam(&bytes, range.clone(), &mut out, String::new()).unwrap();
+            assert_eq!(stats.records, range.len());
+            let sliced = out.into_inner();
+            let reader = Reader::from_bytes(sliced.clone(), ParsingTemplate::new()).unwrap();
+            assert_eq!(reader.amount, range.len());
+            assert_eq!(reader.file_meta.sort_order(), SortOrder::Coordinate);
+            assert_eq!(reader.file_meta.tag_columns().len(), 1);
+            assert_eq!(sam_lines(sliced), all[range.clone()]);
+            if range == (0..4) {
+                // Whole file is copied.
+                assert_eq!(stats.encoded_blocks, 0);
+            } else if !range.is_empty() {
+                assert_eq!(stats.copied_blocks, 0);
+                let pos_stats = reader.file_meta.view_blocks(&Fields::Pos)[0].stats.clone().unwrap();
+                assert!(pos_stats.min_value <= pos_stats.max_value);
+            }
+        }
+        assert!(slice_gbam(&bytes, 2..5, Cursor::new(Vec::new()), String::new()).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::bam::bam_to_gbam::read_sam_header_and_ref_seqs;
+use crate::bam::sam_to_gbam::{open_sam, SamReader};
+use crate::merge::merge_gbam;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::{Codecs, SortOrder, Writer, MEGA_BYTE_SIZE};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use byteorder::{LittleEndian, ReadBytesExt};
+use rayon::slice::ParallelSliceMut;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tempdir::TempDir;
+
+/// Memory used for records kept in memory before they are spilled to disk.
+pub const DEFAULT_MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
+
+/// (RefID, Pos) sort key, unmapped records (RefID -1) go last.
+type Key = (u32, i32);
+
+/// Order of records produced by [`ExternalSorter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    /// By RefID and Pos, unmapped records go last.
+    Coordinate,
+    /// By read name in natural order (see [`natural_cmp`]), as `samtools
+    /// sort -n`. Of records with equal names first segments go first.
+    QueryName,
+}
+
+impl SortBy {
+    /// READ1 and READ2 flags, which order records with equal names.
+    pub(crate) const PAIR_FLAGS: u16 = 0x40 | 0x80;
+
+    pub fn sort_order(self) -> SortOrder {
+        match self {
+            SortBy::Coordinate => SortOrder::Coordinate,
+            SortBy::QueryName => SortOrder::Queryname,
+        }
+    }
+
+    /// Fields the sort key is made of.
+    pub(crate) fn key_fields(self) -> &'static [Fields] {
+        match self {
+            SortBy::Coordinate => &[Fields::RefID, Fields::Pos],
+            SortBy::QueryName => &[Fields::ReadName, Fields::Flags],
+        }
+    }
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "coordinate" => Ok(SortBy::Coordinate),
+            "queryname" | "name" => Ok(SortBy::QueryName),
+            _ => Err(format!("Unknown sort order: {}", s)),
+        }
+    }
+}
+
+/// Compares strings so that runs of digits are compared by their numeric
+/// value (`r2` < `r10`), like `samtools sort -n` does.
+pub fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            while i < a.len() && a[i] == b'0' {
+                i += 1;
+            }
+            while j < b.len() && b[j] == b'0' {
+                j += 1;
+            }
+            let a_end = i + a[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_end = j + b[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            // Longer number without leading zeros is larger.
+            let ord = (a_end - i).cmp(&(b_end - j)).then_with(|| a[i..a_end].cmp(&b[j..b_end]));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            i = a_end;
+            j = b_end;
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+/// Compares BAM records (without block_size) by name and pair flags.
+fn query_name_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let pair_flags = |rec: &[u8]| u16::from_le_bytes([rec[14], rec[15]]) & SortBy::PAIR_FLAGS;
+    natural_cmp(read_name(a), read_name(b)).then_with(|| pair_flags(a).cmp(&pair_flags(b)))
+}
+
+/// Read name of BAM record (without block_size), without NUL.
+fn read_name(rec: &[u8]) -> &[u8] {
+    &rec[32..32 + rec[8] as usize - 1]
+}
+
+/// Sorts records in bounded memory. Records are collected until they take
+/// `mem_limit` bytes, then sorted in parallel and spilled to a temporary
+/// sorted GBAM file (a run). At the end runs are k-way merged into the
+/// output. Sorting is stable: records with equal keys keep order in which
+/// they were pushed.
+pub struct ExternalSorter {
+    sort_by: SortBy,
+    mem_limit: usize,
+    tmp_dir: TempDir,
+    ref_seqs: Vec<(String, u32)>,
+    sam_header: Vec<u8>,
+    // BAM records (without block_size) pushed since the last spill.
+    data: Vec<u8>,
+    // Key, offset and length of every record in `data`.
+    entries: Vec<(Key, usize, usize)>,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    /// Runs are created in a new directory inside `tmp_dir` (system temporary
+    /// directory by default), which is removed when the sorter is dropped.
+    /// SO tag of `sam_header` is set to the sort order.
+    pub fn new(
+        ref_seqs: Vec<(String, u32)>,
+        sam_header: Vec<u8>,
+        sort_by: SortBy,
+        mem_limit: usize,
+        tmp_dir: Option<&Path>,
+    ) -> io::Result<Self> {
+        let tmp_dir = match tmp_dir {
+            Some(path) => TempDir::new_in(path, "gbam_sort")?,
+            None => TempDir::new("gbam_sort")?,
+        };
+        Ok(Self {
+            sort_by,
+            mem_limit,
+            tmp_dir,
+            ref_seqs,
+            sam_header: sort_by.sort_order().set_in_sam_header(&sam_header),
+            data: Vec::new(),
+            entries: Vec::new(),
+            runs: Vec::new(),
+        })
+    }
+
+    /// Adds BAM record (without block_size).
+    pub fn push(&mut self, rec: &[u8]) -> io::Result<()> {
+        let mut bytes = rec;
+        let refid = bytes.read_i32::<LittleEndian>()?;
+        let pos = bytes.read_i32::<LittleEndian>()?;
+        self.entries.push(((refid as u32, pos), self.data.len(), rec.len()));
+        self.data.extend_from_slice(rec);
+        if self.mem_used() >= self.mem_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Number of runs spilled to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Writes all pushed records in sorted order to GBAM file.
+    /// `codecs` are indexed by field (see [`Writer::new`]).
+    pub fn finish(mut self, out_path: &str, codecs: Vec<Codecs>, full_command: String) -> io::Result<()> {
+        if self.runs.is_empty() {
+            // Everything fits in memory, no merge needed.
+            let mut writer = self.new_writer(out_path, codecs, full_command)?;
+            self.write_sorted(&mut writer);
+            writer.finish()?;
+            return Ok(());
+        }
+        if !self.entries.is_empty() {
+            self.spill()?;
+        }
+        let runs = self.runs.iter().map(File::open).collect::<io::Result<Vec<_>>>()?;
+        merge_gbam(runs, out_path, codecs, full_command)
+    }
+
+    fn mem_used(&self) -> usize {
+        self.data.len() + self.entries.len() * mem::size_of::<(Key, usize, usize)>()
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let path = self.tmp_dir.path().join(format!("run_{}.gbam", self.runs.len()));
+        let path_str = path.to_str().expect("Temporary path is not valid UTF-8.");
+        // Runs are read once, so fast compression is enough.
+        let mut writer = self.new_writer(path_str, vec![Codecs::Lz4; FIELDS_NUM], String::new())?;
+        self.write_sorted(&mut writer);
+        writer.finish()?;
+        self.runs.push(path);
+        self.data.clear();
+        self.entries.clear();
+        Ok(())
+    }
+
+    fn write_sorted(&mut self, writer: &mut Writer<BufWriter<File>>) {
+        // Stable, so pushing order is kept for equal keys.
+        match self.sort_by {
+            SortBy::Coordinate => self.entries.par_sort_by_key(|entry| entry.0),
+            SortBy::QueryName => {
+                let data = &self.data;
+                self.entries
+                    .par_sort_by(|a, b| query_name_cmp(&data[a.1..a.1 + a.2], &data[b.1..b.1 + b.2]));
+            }
+        }
+        for &(_, offset, len) in self.entries.iter() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(&self.data[offset..offset + len])));
+        }
+    }
+
+    fn new_writer(&self, path: &str, codecs: Vec<Codecs>, full_command: String) -> io::Result<Writer<BufWriter<File>>> {
+        let mut writer = Writer::new(
+            BufWriter::new(File::create(path)?),
+            codecs,
+            8,
+            vec![Fields::RefID],
+            self.ref_seqs.clone(),
+            self.sam_header.clone(),
+            full_command,
+            false,
+        );
+        writer.set_sort_order(self.sort_by.sort_order());
+        Ok(writer)
+    }
+}
+
+/// Sorts BAM, SAM (plain or gzip/BGZF compressed) or GBAM file into GBAM
+/// file using [`ExternalSorter`]. Input kind is decided by file extension,
+/// BAM is assumed for unknown ones.
+pub fn sort_to_gbam(
+    in_path: &str,
+    out_path: &str,
+    codecs: Vec<Codecs>,
+    sort_by: SortBy,
+    mem_limit: usize,
+    tmp_dir: Option<&Path>,
+    full_command: String,
+) -> io::Result<()> {
+    let sorter = if in_path.ends_with(".sam") || in_path.ends_with(".sam.gz") {
+        let mut sam_reader = SamReader::new(open_sam(in_path)?);
+        let (sam_header, ref_seqs) = sam_reader.read_header()?;
+        let mut sorter = ExternalSorter::new(ref_seqs, sam_header, sort_by, mem_limit, tmp_dir)?;
+        let mut buf = Vec::new();
+        while sam_reader.read_record(&mut buf)? != 0 {
+            sorter.push(&buf)?;
+        }
+        sorter
+    } else if in_path.ends_with(".gbam") {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::new(File::open(in_path)?, template)?;
+        let file_meta = reader.file_meta.clone();
+        let mut sorter = ExternalSorter::new(
+            file_meta.get_ref_seqs().clone(),
+            file_meta.get_sam_header().to_vec(),
+            sort_by,
+            mem_limit,
+            tmp_dir,
+        )?;
+        let mut records = reader.records();
+        let mut buf = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            rec.convert_to_bytes(&mut buf);
+            // Skip block_size.
+            sorter.push(&buf[4..])?;
+        }
+        sorter
+    } else {
+        let file = File::open(in_path)?;
+        let file_size = file.metadata()?.len();
+        let mut bam_reader = bam_tools::Reader::new(BufReader::new(file), 4, Some(file_size));
+        let (sam_header, ref_seqs, _) = read_sam_header_and_ref_seqs(&mut bam_reader);
+        let mut sorter = ExternalSorter::new(ref_seqs, sam_header, sort_by, mem_limit, tmp_dir)?;
+        let mut records = bam_reader.records();
+        while let Some(rec) = records.next_rec() {
+            sorter.push(rec?)?;
+        }
+        sorter
+    };
+    sorter.finish(out_path, codecs, full_command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::parse_sam;
+
+    const SAM: &str = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+r1\t0\tchr2\t5\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tACG\tIII\n\
+r3\t0\tchr1\t50\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r4\t0\tchr1\t7\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r5\t0\tchr2\t5\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r6\t0\tchr1\t50\t60\t3M\t*\t0\t0\tACG\tIII\n";
+
+    const NAME_SAM: &str = "@SQ\tSN:chr1\tLN:1000\n\
+r10\t0\tchr1\t1\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r2\t129\tchr1\t2\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r02a\t0\tchr1\t3\t60\t3M\t*\t0\t0\tACG\tIII\n\
+r2\t65\tchr1\t4\t60\t3M\t*\t0\t0\tACG\tIII\n\
+q9\t0\tchr1\t5\t60\t3M\t*\t0\t0\tACG\tIII\n";
+
+    fn sorted_names(sam: &str, sort_by: SortBy, mem_limit: usize) -> (Vec<String>, usize) {
+        let (sam_header, ref_seqs, records) = parse_sam(sam);
+        let mut sorter = ExternalSorter::new(ref_seqs, sam_header, sort_by, mem_limit, None).unwrap();
+        for rec in records.iter() {
+            sorter.push(rec).unwrap();
+        }
+        let runs = sorter.runs();
+
+        let out_dir = TempDir::new("gbam_sort_test").unwrap();
+        let out_path = out_dir.path().join("sorted.gbam");
+        sorter.finish(out_path.to_str().unwrap(), vec![Codecs::Lz4], String::new()).unwrap();
+
+        let mut reader = Reader::new(
+            File::open(&out_path).unwrap(),
+            ParsingTemplate::new_with(&[Fields::ReadName]),
+        )
+        .unwrap();
+        assert_eq!(reader.file_meta.sort_order(), sort_by.sort_order());
+        assert_eq!(SortOrder::from_sam_header(reader.file_meta.get_sam_header()), sort_by.sort_order());
+        let mut rec = Default::default();
+        let names = (0..reader.amount)
+            .map(|i| {
+                reader.fill_record(i, &mut rec);
+                let name = rec.read_name.as_ref().unwrap();
+                String::from_utf8(name[..name.len() - 1].to_vec()).unwrap()
+            })
+            .collect();
+        (names, runs)
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let expected = vec!["r4", "r3", "r6", "r1", "r5", "r2"];
+        let (names, runs) = sorted_names(SAM, SortBy::Coordinate, DEFAULT_MEM_LIMIT);
+        assert_eq!(runs, 0);
+        assert_eq!(names, expected);
+
+        // Every record is spilled into its own run.
+        let (names, runs) = sorted_names(SAM, SortBy::Coordinate, 1);
+        assert_eq!(runs, 6);
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_query_name_sort() {
+        assert_eq!(natural_cmp(b"r2", b"r10"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r02a", b"r2b"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r2", b"r2a"), Ordering::Less);
+        assert_eq!(natural_cmp(b"a9", b"b1"), Ordering::Less);
+
+        // Records of pair r2 are swapped, the second one is the first segment.
+        let expected = vec!["q9", "r2", "r2", "r02a", "r10"];
+        for &mem_limit in [DEFAULT_MEM_LIMIT, 1].iter() {
+            let (names, _) = sorted_names(NAME_SAM, SortBy::QueryName, mem_limit);
+            assert_eq!(names, expected);
+        }
+        let (_, _, recs) = parse_sam(NAME_SAM);
+        assert_eq!(query_name_cmp(&recs[3], &recs[1]), Ordering::Less);
+    }
+}
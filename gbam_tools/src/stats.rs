@@ -1,43 +1,238 @@
-// pub type StatsComparator = fn(&[u8], &[u8]) -> Ordering;
-
-// pub struct StatsCollector {
-//     pub max_value: Vec<Stat>,
-//     pub min_value: Vec<Stat>,
-//     comparator: StatsComparator,
-// }
-
-// impl StatsCollector {
-//     pub fn new(field: Fields, comparator: StatsComparator) -> Self {
-//         Self {
-//             field,
-//             max_value: None,
-//             min_value: None,
-//             comparator,
-//         }
-//     }
-
-//     pub fn update(&mut self, value: &[u8]) {
-//         if self.max_value.is_none()
-//             || (self.comparator)(value, &self.max_value.as_ref().unwrap()) == Ordering::Greater
-//         {
-//             self.max_value = Some(value.to_vec());
-//         }
-//         if self.min_value.is_none()
-//             || (self.comparator)(value, &self.min_value.as_ref().unwrap()) == Ordering::Less
-//         {
-//             self.min_value = Some(value.to_vec());
-//         }
-//     }
-
-//     pub fn reset(&mut self) {
-//         self.max_value = None;
-//         self.min_value = None;
-//     }
-// }
-
-// pub fn refid_comparator(mut left: &[u8], mut right: &[u8]) -> Ordering {
-//     let left_val = left.read_i32::<LittleEndian>().unwrap();
-//     let right_val = right.read_i32::<LittleEndian>().unwrap();
-
-//     left_val.cmp(&right_val)
-// }
+//! Per block statistics of columns. Collectors of a column are registered by
+//! its field in [`collectors`], fed with every value written into a block,
+//! and their results are stored in [`BlockMeta::column_stats`] so queries can
+//! prune blocks or estimate without reading them.
+
+use crate::meta::BlockMeta;
+use crate::name_index::{name_hashes, trim_nul};
+use bam_tools::record::fields::{field_item_size, is_data_field, Fields};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// Registers of [`ColumnStat::Distinct`] sketch, 6.5% standard error.
+const SKETCH_REGISTERS: usize = 256;
+
+/// Statistic of values of a column block.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ColumnStat {
+    /// Integer values. Nulls (see [`null_value`]) are only counted, `min` is
+    /// greater than `max` if there are no other values.
+    Int { min: i64, max: i64, null_count: u32, sum: i64 },
+    /// Number of values by length in bytes as stored: entry `i` counts
+    /// values of lengths in `[2^(i-1), 2^i)`, entry 0 empty values.
+    Lengths(Vec<u64>),
+    /// HyperLogLog sketch of distinct values, see [`ColumnStat::distinct`].
+    Distinct(Vec<u8>),
+}
+
+impl ColumnStat {
+    /// Estimated number of distinct values of [`ColumnStat::Distinct`].
+    pub fn distinct(&self) -> Option<u64> {
+        let registers = match self {
+            ColumnStat::Distinct(registers) => registers,
+            _ => return None,
+        };
+        let m = registers.len() as f64;
+        let sum: f64 = registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = registers.iter().filter(|&&r| r == 0).count();
+        // Linear counting is more accurate for small cardinalities.
+        if estimate <= 2.5 * m && zeros > 0 {
+            return Some((m * (m / zeros as f64).ln()).round() as u64);
+        }
+        Some(estimate.round() as u64)
+    }
+}
+
+/// Collects a statistic of values of a column block.
+pub trait StatCollector: Send {
+    /// Adds value of a record as stored in the column.
+    fn update(&mut self, value: &[u8]);
+
+    /// Adds values a statistic of the same kind was collected from, e.g. of
+    /// another block.
+    fn merge(&mut self, stat: &ColumnStat);
+
+    /// Statistic of values added since the last call. Resets the collector.
+    fn take(&mut self) -> ColumnStat;
+}
+
+/// Collectors of column of `field`: min, max, null count and sum of integer
+/// fields, length histogram of variable sized ones and also distinct count
+/// sketch of ReadName. Index fields have none.
+pub fn collectors(field: &Fields) -> Vec<Box<dyn StatCollector>> {
+    if !is_data_field(field) {
+        return Vec::new();
+    }
+    match field_item_size(field) {
+        Some(_) => vec![Box::new(IntCollector::new(*field))],
+        None if *field == Fields::ReadName => vec![Box::new(LengthCollector::default()), Box::new(DistinctCollector::default())],
+        None => vec![Box::new(LengthCollector::default())],
+    }
+}
+
+/// Stats of `field` over all `blocks`, in order of [`collectors`]. None if
+/// some block has no column stats.
+pub fn merge_stats(field: &Fields, blocks: &[BlockMeta]) -> Option<Vec<ColumnStat>> {
+    let mut collectors = collectors(field);
+    for block in blocks {
+        if block.column_stats.len() != collectors.len() {
+            return None;
+        }
+        collectors.iter_mut().zip(&block.column_stats).for_each(|(collector, stat)| collector.merge(stat));
+    }
+    Some(collectors.iter_mut().map(|collector| collector.take()).collect())
+}
+
+/// Value of integer `field` standing for missing one, e.g. -1 of Pos.
+pub fn null_value(field: &Fields) -> Option<i64> {
+    match field {
+        Fields::RefID | Fields::Pos | Fields::NextRefID | Fields::NextPos => Some(-1),
+        Fields::Mapq => Some(255),
+        _ => None,
+    }
+}
+
+struct IntCollector {
+    field: Fields,
+    null: Option<i64>,
+    min: i64,
+    max: i64,
+    null_count: u32,
+    sum: i64,
+}
+
+impl IntCollector {
+    fn new(field: Fields) -> Self {
+        Self { field, null: null_value(&field), min: i64::MAX, max: i64::MIN, null_count: 0, sum: 0 }
+    }
+}
+
+impl StatCollector for IntCollector {
+    fn update(&mut self, value: &[u8]) {
+        let value = match value.len() {
+            1 => i64::from(value[0]),
+            2 => i64::from(u16::from_le_bytes(value.try_into().unwrap())),
+            _ => i64::from(i32::from_le_bytes(value.try_into().unwrap())),
+        };
+        if Some(value) == self.null {
+            self.null_count += 1;
+            return;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum = self.sum.wrapping_add(value);
+    }
+
+    fn merge(&mut self, stat: &ColumnStat) {
+        if let ColumnStat::Int { min, max, null_count, sum } = *stat {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+            self.null_count += null_count;
+            self.sum = self.sum.wrapping_add(sum);
+        }
+    }
+
+    fn take(&mut self) -> ColumnStat {
+        let collector = std::mem::replace(self, Self::new(self.field));
+        ColumnStat::Int { min: collector.min, max: collector.max, null_count: collector.null_count, sum: collector.sum }
+    }
+}
+
+#[derive(Default)]
+struct LengthCollector(Vec<u64>);
+
+impl LengthCollector {
+    fn add(&mut self, bucket: usize, count: u64) {
+        if self.0.len() <= bucket {
+            self.0.resize(bucket + 1, 0);
+        }
+        self.0[bucket] += count;
+    }
+}
+
+impl StatCollector for LengthCollector {
+    fn update(&mut self, value: &[u8]) {
+        self.add((usize::BITS - value.len().leading_zeros()) as usize, 1);
+    }
+
+    fn merge(&mut self, stat: &ColumnStat) {
+        if let ColumnStat::Lengths(counts) = stat {
+            counts.iter().enumerate().for_each(|(bucket, &count)| self.add(bucket, count));
+        }
+    }
+
+    fn take(&mut self) -> ColumnStat {
+        ColumnStat::Lengths(std::mem::take(&mut self.0))
+    }
+}
+
+struct DistinctCollector(Vec<u8>);
+
+impl Default for DistinctCollector {
+    fn default() -> Self {
+        Self(vec![0; SKETCH_REGISTERS])
+    }
+}
+
+impl StatCollector for DistinctCollector {
+    fn update(&mut self, value: &[u8]) {
+        let (hash, _) = name_hashes(trim_nul(value));
+        let register = hash as usize % SKETCH_REGISTERS;
+        // Registers take the low 8 bits, rank is counted in the rest.
+        let rank = ((hash >> 8).trailing_zeros() + 1).min(57) as u8;
+        self.0[register] = self.0[register].max(rank);
+    }
+
+    fn merge(&mut self, stat: &ColumnStat) {
+        if let ColumnStat::Distinct(registers) = stat {
+            self.0.iter_mut().zip(registers).for_each(|(register, &other)| *register = (*register).max(other));
+        }
+    }
+
+    fn take(&mut self) -> ColumnStat {
+        ColumnStat::Distinct(std::mem::take(self).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collectors() {
+        let mut pos = collectors(&Fields::Pos);
+        for value in [10, -1, 5, 20] {
+            pos[0].update(&i32::to_le_bytes(value));
+        }
+        let stat = pos[0].take();
+        assert_eq!(stat, ColumnStat::Int { min: 5, max: 20, null_count: 1, sum: 35 });
+        pos[0].update(&i32::to_le_bytes(3));
+        pos[0].merge(&stat);
+        assert_eq!(pos[0].take(), ColumnStat::Int { min: 3, max: 20, null_count: 1, sum: 38 });
+
+        let mut mapq = collectors(&Fields::Mapq);
+        mapq[0].update(&[255]);
+        assert!(matches!(mapq[0].take(), ColumnStat::Int { min, max, null_count: 1, .. } if min > max));
+        assert!(collectors(&Fields::LName).is_empty());
+
+        let mut names = collectors(&Fields::ReadName);
+        for i in 0..10_000 {
+            let name = format!("read{}\0", i % 5_000);
+            names.iter_mut().for_each(|collector| collector.update(name.as_bytes()));
+        }
+        assert_eq!(names[0].take(), ColumnStat::Lengths(vec![0, 0, 0, 200, 9_800]));
+        let distinct = names[1].take().distinct().unwrap();
+        assert!((4_500..5_500).contains(&distinct), "{}", distinct);
+    }
+
+    #[test]
+    fn test_merge_stats() {
+        let mut blocks = vec![BlockMeta::default(), BlockMeta::default()];
+        blocks[0].column_stats = vec![ColumnStat::Lengths(vec![1, 2])];
+        blocks[1].column_stats = vec![ColumnStat::Lengths(vec![0, 1, 4])];
+        assert_eq!(merge_stats(&Fields::RawCigar, &blocks), Some(vec![ColumnStat::Lengths(vec![1, 3, 4])]));
+        blocks[1].column_stats.clear();
+        assert_eq!(merge_stats(&Fields::RawCigar, &blocks), None);
+    }
+}
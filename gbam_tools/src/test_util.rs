@@ -0,0 +1,54 @@
+use crate::bam::sam_to_gbam::{RefSeqs, SamReader};
+use crate::meta::Codecs;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::writer::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use std::borrow::Cow;
+use std::io::{Cursor, Seek, Write};
+
+/// BAM header, reference sequences and BAM records of `sam`.
+pub(crate) fn parse_sam(sam: &str) -> (Vec<u8>, RefSeqs, Vec<Vec<u8>>) {
+    let mut sam_reader = SamReader::new(sam.as_bytes());
+    let (sam_header, ref_seqs) = sam_reader.read_header().unwrap();
+    let mut records = Vec::new();
+    let mut buf = Vec::new();
+    while sam_reader.read_record(&mut buf).unwrap() != 0 {
+        records.push(buf.clone());
+    }
+    (sam_header, ref_seqs, records)
+}
+
+/// Pushes records of `sam` to writer made by `new_writer` from its header
+/// and reference sequences, returns the writer finished.
+pub(crate) fn write_sam<W: Write + Seek>(sam: &str, new_writer: impl FnOnce(Vec<u8>, RefSeqs) -> Writer<W>) -> Writer<W> {
+    let (sam_header, ref_seqs, records) = parse_sam(sam);
+    let mut writer = new_writer(sam_header, ref_seqs);
+    for rec in records.iter() {
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+    }
+    writer.finish().unwrap();
+    writer
+}
+
+/// Writer into memory with LZ4 for all fields.
+pub(crate) fn memory_writer(sam_header: Vec<u8>, ref_seqs: RefSeqs) -> Writer<Cursor<Vec<u8>>> {
+    Writer::new_no_stats(Cursor::new(Vec::new()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false)
+}
+
+/// GBAM file written from `sam` by [`memory_writer`], which `setup` is
+/// called on before records are pushed.
+pub(crate) fn gbam_bytes_from_sam(sam: &str, setup: impl FnOnce(&mut Writer<Cursor<Vec<u8>>>)) -> Vec<u8> {
+    let writer = write_sam(sam, |sam_header, ref_seqs| {
+        let mut writer = memory_writer(sam_header, ref_seqs);
+        setup(&mut writer);
+        writer
+    });
+    writer.into_inner().into_inner()
+}
+
+/// Reader of GBAM file written from `sam` in memory, see
+/// [`gbam_bytes_from_sam`].
+pub(crate) fn gbam_from_sam(sam: &str, template: ParsingTemplate) -> Reader {
+    Reader::from_bytes(gbam_bytes_from_sam(sam, |_| ()), template).unwrap()
+}
@@ -0,0 +1,342 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use tempdir::TempDir;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const INDEX_MAGIC: u32 = 0x2468_ACE0;
+const HEADER_SIZE: u64 = 64;
+const SUMMARY_SIZE: u64 = 40;
+const INDEX_HEADER_SIZE: u64 = 48;
+// Items per data section and children per index node, as in bedGraphToBigWig.
+const ITEMS_PER_SLOT: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+const BED_GRAPH_SECTION: u8 = 1;
+
+/// Writes bedGraph intervals as BigWig (without zoom levels). Intervals have
+/// to come sorted by position, reference sequences in order given to
+/// [`BigWigWriter::new`]. Compressed data sections are kept in a temporary
+/// file until [`BigWigWriter::finish`], so output does not need to be
+/// seekable.
+pub struct BigWigWriter {
+    chroms: Vec<(String, u32)>,
+    chrom_ids: HashMap<String, u32>,
+    _tmp_dir: TempDir,
+    sections: BufWriter<File>,
+    sections_size: u64,
+    index: Vec<SectionEntry>,
+    items: Vec<(u32, u32, f32)>,
+    chrom_id: u32,
+    summary: Summary,
+    max_section_size: u32,
+    section_buf: Vec<u8>,
+}
+
+struct SectionEntry {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    // Relative to the first section.
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct Summary {
+    bases: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl BigWigWriter {
+    pub fn new(chroms: &[(String, u32)]) -> io::Result<Self> {
+        let tmp_dir = TempDir::new("gbam_bigwig")?;
+        let sections = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(tmp_dir.path().join("sections"))?;
+        let sections = BufWriter::new(sections);
+        Ok(Self {
+            chroms: chroms.to_vec(),
+            chrom_ids: chroms.iter().enumerate().map(|(id, (name, _))| (name.clone(), id as u32)).collect(),
+            _tmp_dir: tmp_dir,
+            sections,
+            sections_size: 0,
+            index: Vec::new(),
+            items: Vec::with_capacity(ITEMS_PER_SLOT),
+            chrom_id: 0,
+            summary: Summary { min: f64::MAX, max: f64::MIN, ..Summary::default() },
+            max_section_size: 0,
+            section_buf: Vec::new(),
+        })
+    }
+
+    /// Adds interval `[start, end)` of `chr` with `value`. Intervals are
+    /// clipped to reference sequence length.
+    pub fn write_region(&mut self, chr: &str, start: u32, end: u32, value: f32) -> io::Result<()> {
+        let chrom_id = *self
+            .chrom_ids
+            .get(chr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown reference sequence {}.", chr)))?;
+        let end = end.min(self.chroms[chrom_id as usize].1);
+        if start >= end {
+            return Ok(());
+        }
+        if chrom_id != self.chrom_id || self.items.len() == ITEMS_PER_SLOT {
+            self.write_section()?;
+            self.chrom_id = chrom_id;
+        }
+        self.items.push((start, end, value));
+        let (len, value) = (f64::from(end - start), f64::from(value));
+        self.summary.bases += u64::from(end - start);
+        self.summary.min = self.summary.min.min(value);
+        self.summary.max = self.summary.max.max(value);
+        self.summary.sum += value * len;
+        self.summary.sum_squares += value * value * len;
+        Ok(())
+    }
+
+    fn write_section(&mut self) -> io::Result<()> {
+        let (start, end) = match (self.items.first(), self.items.last()) {
+            (Some(first), Some(last)) => (first.0, last.1),
+            _ => return Ok(()),
+        };
+        let buf = &mut self.section_buf;
+        buf.clear();
+        buf.write_u32::<LittleEndian>(self.chrom_id)?;
+        buf.write_u32::<LittleEndian>(start)?;
+        buf.write_u32::<LittleEndian>(end)?;
+        // Item step and span, unused for bedGraph sections.
+        buf.write_u32::<LittleEndian>(0)?;
+        buf.write_u32::<LittleEndian>(0)?;
+        buf.write_u8(BED_GRAPH_SECTION)?;
+        buf.write_u8(0)?;
+        buf.write_u16::<LittleEndian>(self.items.len() as u16)?;
+        for &(start, end, value) in &self.items {
+            buf.write_u32::<LittleEndian>(start)?;
+            buf.write_u32::<LittleEndian>(end)?;
+            buf.write_f32::<LittleEndian>(value)?;
+        }
+        self.max_section_size = self.max_section_size.max(buf.len() as u32);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(buf)?;
+        let compressed = encoder.finish()?;
+        self.sections.write_all(&compressed)?;
+        self.index.push(SectionEntry {
+            chrom_id: self.chrom_id,
+            start,
+            end,
+            offset: self.sections_size,
+            size: compressed.len() as u64,
+        });
+        self.sections_size += compressed.len() as u64;
+        self.items.clear();
+        Ok(())
+    }
+
+    /// Writes the whole file to `out`.
+    pub fn finish(mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.write_section()?;
+        let mut sections = self.sections.into_inner().map_err(|e| e.into_error())?;
+
+        let chrom_tree_offset = HEADER_SIZE + SUMMARY_SIZE;
+        let key_size = self.chroms.iter().map(|(name, _)| name.len()).max().unwrap_or(1).max(1);
+        let tree_block = self.chroms.len().max(1);
+        let chrom_tree_size = 32 + 4 + (key_size + 8) * self.chroms.len();
+        let data_offset = chrom_tree_offset + chrom_tree_size as u64;
+        // Section count precedes sections.
+        let index_offset = data_offset + 8 + self.sections_size;
+        let summary_offset = HEADER_SIZE;
+
+        out.write_u32::<LittleEndian>(BIGWIG_MAGIC)?;
+        // Version, zoom levels.
+        out.write_u16::<LittleEndian>(4)?;
+        out.write_u16::<LittleEndian>(0)?;
+        out.write_u64::<LittleEndian>(chrom_tree_offset)?;
+        out.write_u64::<LittleEndian>(data_offset)?;
+        out.write_u64::<LittleEndian>(index_offset)?;
+        // Field count, defined field count, autoSql offset.
+        out.write_u16::<LittleEndian>(0)?;
+        out.write_u16::<LittleEndian>(0)?;
+        out.write_u64::<LittleEndian>(0)?;
+        out.write_u64::<LittleEndian>(summary_offset)?;
+        out.write_u32::<LittleEndian>(self.max_section_size)?;
+        out.write_u64::<LittleEndian>(0)?;
+
+        let summary = &self.summary;
+        let (min, max) = if summary.bases == 0 { (0.0, 0.0) } else { (summary.min, summary.max) };
+        out.write_u64::<LittleEndian>(summary.bases)?;
+        out.write_f64::<LittleEndian>(min)?;
+        out.write_f64::<LittleEndian>(max)?;
+        out.write_f64::<LittleEndian>(summary.sum)?;
+        out.write_f64::<LittleEndian>(summary.sum_squares)?;
+
+        // Chromosome B+ tree of one leaf, keys in sorted order.
+        out.write_u32::<LittleEndian>(CHROM_TREE_MAGIC)?;
+        out.write_u32::<LittleEndian>(tree_block as u32)?;
+        out.write_u32::<LittleEndian>(key_size as u32)?;
+        out.write_u32::<LittleEndian>(8)?;
+        out.write_u64::<LittleEndian>(self.chroms.len() as u64)?;
+        out.write_u64::<LittleEndian>(0)?;
+        out.write_u8(1)?;
+        out.write_u8(0)?;
+        out.write_u16::<LittleEndian>(self.chroms.len() as u16)?;
+        let mut sorted: Vec<_> = self.chroms.iter().enumerate().collect();
+        sorted.sort_by(|a, b| a.1 .0.as_bytes().cmp(b.1 .0.as_bytes()));
+        for (id, (name, len)) in sorted {
+            out.write_all(name.as_bytes())?;
+            out.write_all(&vec![0; key_size - name.len()])?;
+            out.write_u32::<LittleEndian>(id as u32)?;
+            out.write_u32::<LittleEndian>(*len)?;
+        }
+
+        out.write_u64::<LittleEndian>(self.index.len() as u64)?;
+        sections.seek(SeekFrom::Start(0))?;
+        io::copy(&mut BufReader::new(sections), out)?;
+
+        write_index(out, &self.index, data_offset + 8, index_offset)?;
+        out.write_u32::<LittleEndian>(BIGWIG_MAGIC)?;
+        out.flush()
+    }
+}
+
+/// Chrom and base range covered by index node: (start chrom, start, end
+/// chrom, end).
+type Bounds = (u32, u32, u32, u32);
+
+fn merge_bounds(bounds: &[Bounds]) -> Bounds {
+    let (first, last) = (bounds[0], bounds[bounds.len() - 1]);
+    (first.0, first.1, last.2, last.3)
+}
+
+/// Writes R tree index of data sections. Nodes are written level by level,
+/// root first.
+fn write_index(out: &mut dyn Write, sections: &[SectionEntry], data_start: u64, index_offset: u64) -> io::Result<()> {
+    let section_bounds: Vec<Bounds> = sections.iter().map(|s| (s.chrom_id, s.start, s.chrom_id, s.end)).collect();
+    // Bounds and number of children of nodes, leaves first.
+    let mut levels: Vec<Vec<(Bounds, usize)>> =
+        vec![section_bounds.chunks(BLOCK_SIZE).map(|chunk| (merge_bounds(chunk), chunk.len())).collect()];
+    if levels[0].is_empty() {
+        levels[0].push(((0, 0, 0, 0), 0));
+    }
+    while levels.last().unwrap().len() > 1 {
+        let bounds: Vec<Bounds> = levels.last().unwrap().iter().map(|node| node.0).collect();
+        levels.push(bounds.chunks(BLOCK_SIZE).map(|chunk| (merge_bounds(chunk), chunk.len())).collect());
+    }
+    let node_size = |level: usize, children: usize| 4 + children as u64 * if level == 0 { 32 } else { 24 };
+    // Offset of every level, root level last as in `levels`.
+    let mut level_offsets = vec![0; levels.len()];
+    let mut offset = index_offset + INDEX_HEADER_SIZE;
+    for level in (0..levels.len()).rev() {
+        level_offsets[level] = offset;
+        offset += levels[level].iter().map(|node| node_size(level, node.1)).sum::<u64>();
+    }
+
+    let root = levels.last().unwrap()[0].0;
+    out.write_u32::<LittleEndian>(INDEX_MAGIC)?;
+    out.write_u32::<LittleEndian>(BLOCK_SIZE as u32)?;
+    out.write_u64::<LittleEndian>(sections.len() as u64)?;
+    out.write_u32::<LittleEndian>(root.0)?;
+    out.write_u32::<LittleEndian>(root.1)?;
+    out.write_u32::<LittleEndian>(root.2)?;
+    out.write_u32::<LittleEndian>(root.3)?;
+    out.write_u64::<LittleEndian>(index_offset)?;
+    out.write_u32::<LittleEndian>(ITEMS_PER_SLOT as u32)?;
+    out.write_u32::<LittleEndian>(0)?;
+
+    for level in (0..levels.len()).rev() {
+        let is_leaf = level == 0;
+        // Index of the first child of the current node in the level below.
+        let mut child = 0;
+        for &(_, children) in &levels[level] {
+            out.write_u8(is_leaf as u8)?;
+            out.write_u8(0)?;
+            out.write_u16::<LittleEndian>(children as u16)?;
+            for idx in child..child + children {
+                if is_leaf {
+                    let section = &sections[idx];
+                    write_bounds(out, section_bounds[idx])?;
+                    out.write_u64::<LittleEndian>(data_start + section.offset)?;
+                    out.write_u64::<LittleEndian>(section.size)?;
+                } else {
+                    let below = &levels[level - 1];
+                    let child_offset = level_offsets[level - 1]
+                        + below[..idx].iter().map(|node| node_size(level - 1, node.1)).sum::<u64>();
+                    write_bounds(out, below[idx].0)?;
+                    out.write_u64::<LittleEndian>(child_offset)?;
+                }
+            }
+            child += children;
+        }
+    }
+    Ok(())
+}
+
+fn write_bounds(out: &mut dyn Write, bounds: Bounds) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(bounds.0)?;
+    out.write_u32::<LittleEndian>(bounds.1)?;
+    out.write_u32::<LittleEndian>(bounds.2)?;
+    out.write_u32::<LittleEndian>(bounds.3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use flate2::read::ZlibDecoder;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_bigwig() {
+        let chroms = vec![("chr2".to_owned(), 5000), ("chr1".to_owned(), 3000)];
+        let mut writer = BigWigWriter::new(&chroms).unwrap();
+        for i in 0..1500 {
+            writer.write_region("chr2", i, i + 1, i as f32).unwrap();
+        }
+        writer.write_region("chr1", 0, 3001, 2.0).unwrap();
+        assert!(writer.write_region("chrX", 0, 1, 1.0).is_err());
+        let mut data = Vec::new();
+        writer.finish(&mut data).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(cur.read_u32::<LittleEndian>().unwrap(), BIGWIG_MAGIC);
+        cur.set_position(8);
+        let chrom_tree_offset = cur.read_u64::<LittleEndian>().unwrap();
+        let data_offset = cur.read_u64::<LittleEndian>().unwrap();
+        let index_offset = cur.read_u64::<LittleEndian>().unwrap();
+        cur.set_position(HEADER_SIZE);
+        assert_eq!(cur.read_u64::<LittleEndian>().unwrap(), 4500);
+        assert_eq!(cur.read_f64::<LittleEndian>().unwrap(), 0.0);
+        assert_eq!(cur.read_f64::<LittleEndian>().unwrap(), 1499.0);
+
+        // Keys are sorted, IDs follow given order.
+        cur.set_position(chrom_tree_offset + 36);
+        let mut key = [0; 4];
+        cur.read_exact(&mut key).unwrap();
+        assert_eq!((&key, cur.read_u32::<LittleEndian>().unwrap()), (b"chr1", 1));
+
+        cur.set_position(data_offset);
+        assert_eq!(cur.read_u64::<LittleEndian>().unwrap(), 3);
+        cur.set_position(index_offset + 8);
+        assert_eq!(cur.read_u64::<LittleEndian>().unwrap(), 3);
+        // Root is a leaf, its last item is the chr1 section.
+        cur.set_position(index_offset + INDEX_HEADER_SIZE);
+        assert_eq!(cur.read_u8().unwrap(), 1);
+        cur.set_position(index_offset + INDEX_HEADER_SIZE + 4 + 2 * 32);
+        let bounds: Vec<_> = (0..4).map(|_| cur.read_u32::<LittleEndian>().unwrap()).collect();
+        assert_eq!(bounds, vec![1, 0, 1, 3000]);
+        let section_offset = cur.read_u64::<LittleEndian>().unwrap() as usize;
+        let section_size = cur.read_u64::<LittleEndian>().unwrap() as usize;
+
+        let mut section = Vec::new();
+        ZlibDecoder::new(&data[section_offset..section_offset + section_size]).read_to_end(&mut section).unwrap();
+        assert_eq!(section.len(), 24 + 12);
+        assert_eq!(&section[..4], &1u32.to_le_bytes());
+        assert_eq!(&section[32..], &2.0f32.to_le_bytes());
+        assert!(data.ends_with(&BIGWIG_MAGIC.to_le_bytes()));
+    }
+}
@@ -0,0 +1,349 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use tempdir::TempDir;
+
+const D4_MAGIC: &[u8; 4] = b"d4\xdd\xdd";
+// Root directory follows magic and version.
+const ROOT_OFFSET: u64 = 8;
+// Frame header: relative offset and size of the next frame of a stream.
+const FRAME_HEADER_SIZE: usize = 16;
+// Size of the first frame of directory streams, as readers expect it.
+const DIRECTORY_FRAME_SIZE: usize = 512;
+// Depths 0..64 go into the primary table, as with `d4tools create`.
+const BIT_WIDTH: u32 = 6;
+const DICT_HIGH: i32 = 1 << BIT_WIDTH;
+// Code of values to look up in the secondary table. 63 itself fits.
+const MASK: u8 = (1 << BIT_WIDTH) - 1;
+// Secondary table records: left + 1 (u32), length - 1 (u16), value (i32).
+const RECORD_SIZE: usize = 10;
+const MAX_RECORD_LEN: u32 = 1 << 16;
+
+const STREAM: u8 = 0;
+const SUB_DIR: u8 = 1;
+const BLOB: u8 = 2;
+
+/// Writes depth regions as D4 with the default dictionary of `d4tools
+/// create`: depths below 64 are bit packed in the primary table, higher
+/// ones are stored as ranges in an uncompressed secondary table. Regions
+/// have to come sorted by position, reference sequences in order given to
+/// [`D4Writer::new`]; bases not covered by any region have depth 0. The
+/// primary table is kept in a temporary file until [`D4Writer::finish`],
+/// so output does not need to be seekable.
+pub struct D4Writer {
+    chroms: Vec<(String, u32)>,
+    chrom_ids: HashMap<String, usize>,
+    _tmp_dir: TempDir,
+    primary: BufWriter<File>,
+    primary_size: u64,
+    chrom_id: usize,
+    // Bases of current reference sequence before it are in the primary table.
+    pos: u32,
+    bits: u32,
+    bit_count: u32,
+    // Secondary table records of every reference sequence.
+    secondary: Vec<Vec<u8>>,
+}
+
+impl D4Writer {
+    pub fn new(chroms: &[(String, u32)]) -> io::Result<Self> {
+        let tmp_dir = TempDir::new("gbam_d4")?;
+        let primary = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(tmp_dir.path().join("primary"))?;
+        Ok(Self {
+            chroms: chroms.to_vec(),
+            chrom_ids: chroms.iter().enumerate().map(|(id, (name, _))| (name.clone(), id)).collect(),
+            _tmp_dir: tmp_dir,
+            primary: BufWriter::new(primary),
+            primary_size: 0,
+            chrom_id: 0,
+            pos: 0,
+            bits: 0,
+            bit_count: 0,
+            secondary: vec![Vec::new(); chroms.len()],
+        })
+    }
+
+    /// Sets depth of interval `[start, end)` of `chr` to `value`. Intervals
+    /// are clipped to reference sequence length.
+    pub fn write_region(&mut self, chr: &str, start: u32, end: u32, value: i32) -> io::Result<()> {
+        let chrom_id = *self
+            .chrom_ids
+            .get(chr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown reference sequence {}.", chr)))?;
+        let end = end.min(self.chroms[chrom_id].1);
+        if start >= end {
+            return Ok(());
+        }
+        if chrom_id < self.chrom_id || (chrom_id == self.chrom_id && start < self.pos) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "D4 regions have to be sorted by position."));
+        }
+        while self.chrom_id < chrom_id {
+            self.finish_chrom()?;
+        }
+        self.push_codes(start - self.pos, 0)?;
+        if (0..DICT_HIGH).contains(&value) {
+            self.push_codes(end - start, value as u8)?;
+        } else {
+            self.push_codes(end - start, MASK)?;
+            let records = &mut self.secondary[chrom_id];
+            let mut left = start;
+            while left < end {
+                let len = (end - left).min(MAX_RECORD_LEN);
+                records.write_u32::<LittleEndian>(left + 1)?;
+                records.write_u16::<LittleEndian>((len - 1) as u16)?;
+                records.write_i32::<LittleEndian>(value)?;
+                left += len;
+            }
+        }
+        self.pos = end;
+        Ok(())
+    }
+
+    fn push_codes(&mut self, count: u32, code: u8) -> io::Result<()> {
+        for _ in 0..count {
+            self.bits |= u32::from(code) << self.bit_count;
+            self.bit_count += BIT_WIDTH;
+            if self.bit_count >= 8 {
+                self.primary.write_u8(self.bits as u8)?;
+                self.primary_size += 1;
+                self.bits >>= 8;
+                self.bit_count -= 8;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the rest of the current reference sequence with 0. Every
+    /// sequence starts at a byte boundary.
+    fn finish_chrom(&mut self) -> io::Result<()> {
+        let len = self.chroms[self.chrom_id].1;
+        self.push_codes(len - self.pos, 0)?;
+        if self.bit_count > 0 {
+            self.primary.write_u8(self.bits as u8)?;
+            self.primary_size += 1;
+        }
+        self.bits = 0;
+        self.bit_count = 0;
+        self.pos = 0;
+        self.chrom_id += 1;
+        Ok(())
+    }
+
+    /// Writes the whole file to `out`.
+    pub fn finish(mut self, out: &mut dyn Write) -> io::Result<()> {
+        while self.chrom_id < self.chroms.len() {
+            self.finish_chrom()?;
+        }
+        let mut primary = self.primary.into_inner().map_err(|e| e.into_error())?;
+
+        let header = json!({
+            "chrom_list": self.chroms.iter().map(|(name, size)| json!({"name": name, "size": size})).collect::<Vec<_>>(),
+            "dictionary": {"SimpleRange": {"low": 0, "high": DICT_HIGH}},
+            "denominator": "One",
+        });
+        let header = stream_frame(header.to_string().as_bytes());
+        let secondary = secondary_table(&self.chroms, &self.secondary);
+
+        // Entry offsets are relative to the directory.
+        let root_size = directory_size(&[".metadata", ".ptab", ".stab"]);
+        let header_offset = root_size as u64;
+        let primary_offset = header_offset + header.len() as u64;
+        let secondary_offset = primary_offset + self.primary_size;
+        let mut entries = Vec::new();
+        write_entry(&mut entries, STREAM, header_offset, header.len() as u64, ".metadata")?;
+        write_entry(&mut entries, BLOB, primary_offset, self.primary_size, ".ptab")?;
+        write_entry(&mut entries, SUB_DIR, secondary_offset, secondary.len() as u64, ".stab")?;
+
+        out.write_all(D4_MAGIC)?;
+        // Format version.
+        out.write_all(&[0; ROOT_OFFSET as usize - 4])?;
+        out.write_all(&directory_frames(&entries))?;
+        out.write_all(&header)?;
+        primary.seek(SeekFrom::Start(0))?;
+        io::copy(&mut BufReader::new(primary), out)?;
+        out.write_all(&secondary)?;
+        out.flush()
+    }
+}
+
+/// Secondary table directory with one stream of records per reference
+/// sequence.
+fn secondary_table(chroms: &[(String, u32)], records: &[Vec<u8>]) -> Vec<u8> {
+    let metadata = json!({
+        "format": "SimpleKV",
+        "record_format": "range",
+        "partitions": chroms.iter().map(|(name, size)| json!([name, 0, size])).collect::<Vec<_>>(),
+        "compression": "NoCompression",
+    });
+    let mut streams = vec![stream_frame(metadata.to_string().as_bytes())];
+    // Readers only take whole records, so a single one needs the zero
+    // record after it.
+    streams.extend(records.iter().map(|records| stream_frame(&[&records[..], &[0; RECORD_SIZE]].concat())));
+
+    let names: Vec<String> = std::iter::once(".metadata".to_owned()).chain((0..chroms.len()).map(|id| id.to_string())).collect();
+    let mut offset = directory_size(&names) as u64;
+    let mut entries = Vec::new();
+    for (name, stream) in names.iter().zip(&streams) {
+        write_entry(&mut entries, STREAM, offset, stream.len() as u64, name).unwrap();
+        offset += stream.len() as u64;
+    }
+    let mut table = directory_frames(&entries);
+    for stream in streams {
+        table.extend(stream);
+    }
+    table
+}
+
+/// Stream of one frame holding `payload`, followed by 0 as its end.
+fn stream_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0; FRAME_HEADER_SIZE];
+    frame.extend_from_slice(payload);
+    frame.push(0);
+    frame
+}
+
+fn write_entry(buf: &mut Vec<u8>, kind: u8, offset: u64, size: u64, name: &str) -> io::Result<()> {
+    buf.write_u8(1)?;
+    buf.write_u8(kind)?;
+    buf.write_u64::<LittleEndian>(offset)?;
+    buf.write_u64::<LittleEndian>(size)?;
+    buf.write_all(name.as_bytes())?;
+    buf.write_u8(0)
+}
+
+fn directory_size<S: AsRef<str>>(names: &[S]) -> usize {
+    let entries_size: usize = names.iter().map(|name| 19 + name.as_ref().len()).sum();
+    let payload = DIRECTORY_FRAME_SIZE - FRAME_HEADER_SIZE;
+    // Entries end with 0.
+    (entries_size + 1).div_ceil(payload) * DIRECTORY_FRAME_SIZE
+}
+
+/// Directory stream of frames of [`DIRECTORY_FRAME_SIZE`], each linked to
+/// the one right after it.
+fn directory_frames(entries: &[u8]) -> Vec<u8> {
+    let payload = DIRECTORY_FRAME_SIZE - FRAME_HEADER_SIZE;
+    let mut entries = entries.to_vec();
+    entries.push(0);
+    let chunks: Vec<&[u8]> = entries.chunks(payload).collect();
+    let mut frames = Vec::with_capacity(chunks.len() * DIRECTORY_FRAME_SIZE);
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let next = if idx + 1 < chunks.len() { DIRECTORY_FRAME_SIZE as u64 } else { 0 };
+        frames.extend_from_slice(&next.to_le_bytes());
+        frames.extend_from_slice(&next.to_le_bytes());
+        frames.extend_from_slice(chunk);
+        frames.resize((idx + 1) * DIRECTORY_FRAME_SIZE, 0);
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::io::{BufRead, Cursor, Read};
+
+    /// Entries of directory at `offset` as (kind, absolute offset, size,
+    /// name), frames followed as readers do.
+    fn read_directory(data: &[u8], offset: usize) -> Vec<(u8, usize, usize, String)> {
+        let mut content = Vec::new();
+        let mut frame = offset;
+        let mut size = DIRECTORY_FRAME_SIZE;
+        loop {
+            content.extend_from_slice(&data[frame + FRAME_HEADER_SIZE..frame + size]);
+            let mut cur = Cursor::new(&data[frame..]);
+            let next = cur.read_i64::<LittleEndian>().unwrap();
+            if next == 0 {
+                break;
+            }
+            size = cur.read_u64::<LittleEndian>().unwrap() as usize;
+            frame = (frame as i64 + next) as usize;
+        }
+        let mut cur = Cursor::new(&content[..]);
+        let mut entries = Vec::new();
+        while cur.read_u8().unwrap() == 1 {
+            let kind = cur.read_u8().unwrap();
+            let entry_offset = offset + cur.read_u64::<LittleEndian>().unwrap() as usize;
+            let size = cur.read_u64::<LittleEndian>().unwrap() as usize;
+            let mut name = Vec::new();
+            cur.read_until(0, &mut name).unwrap();
+            name.pop();
+            entries.push((kind, entry_offset, size, String::from_utf8(name).unwrap()));
+        }
+        entries
+    }
+
+    fn stream_content(data: &[u8], offset: usize, size: usize) -> String {
+        let payload = &data[offset + FRAME_HEADER_SIZE..offset + size];
+        String::from_utf8(payload.iter().copied().take_while(|&b| b != 0).collect()).unwrap()
+    }
+
+    fn code(primary: &[u8], pos: usize) -> u8 {
+        let bit = pos * BIT_WIDTH as usize;
+        let word = u16::from(primary[bit / 8]) | primary.get(bit / 8 + 1).map_or(0, |&b| u16::from(b) << 8);
+        (word >> (bit % 8)) as u8 & MASK
+    }
+
+    #[test]
+    fn test_d4() {
+        let chroms = vec![("chr2".to_owned(), 10), ("chr1".to_owned(), 7)];
+        let mut writer = D4Writer::new(&chroms).unwrap();
+        writer.write_region("chr2", 2, 4, 5).unwrap();
+        writer.write_region("chr2", 4, 5, 63).unwrap();
+        writer.write_region("chr2", 5, 6, 100).unwrap();
+        writer.write_region("chr1", 3, 100, 1).unwrap();
+        assert!(writer.write_region("chr1", 0, 1, 1).is_err());
+        assert!(writer.write_region("chrX", 0, 1, 1).is_err());
+        let mut data = Vec::new();
+        writer.finish(&mut data).unwrap();
+
+        assert_eq!(&data[..8], b"d4\xdd\xdd\0\0\0\0");
+        let root = read_directory(&data, ROOT_OFFSET as usize);
+        let names: Vec<_> = root.iter().map(|entry| (entry.0, entry.3.as_str())).collect();
+        assert_eq!(names, vec![(STREAM, ".metadata"), (BLOB, ".ptab"), (SUB_DIR, ".stab")]);
+
+        let header: serde_json::Value = serde_json::from_str(&stream_content(&data, root[0].1, root[0].2)).unwrap();
+        assert_eq!(header["chrom_list"][0], json!({"name": "chr2", "size": 10}));
+        assert_eq!(header["chrom_list"][1], json!({"name": "chr1", "size": 7}));
+        assert_eq!(header["dictionary"]["SimpleRange"], json!({"low": 0, "high": 64}));
+
+        // chr2 takes 60 bits, chr1 starts at next byte.
+        let (primary_offset, primary_size) = (root[1].1, root[1].2);
+        assert_eq!(primary_size, 8 + 6);
+        let primary = &data[primary_offset..primary_offset + primary_size];
+        let chr2: Vec<_> = (0..10).map(|pos| code(primary, pos)).collect();
+        assert_eq!(chr2, vec![0, 0, 5, 5, 63, 63, 0, 0, 0, 0]);
+        let chr1: Vec<_> = (0..7).map(|pos| code(&primary[8..], pos)).collect();
+        assert_eq!(chr1, vec![0, 0, 0, 1, 1, 1, 1]);
+
+        let secondary = read_directory(&data, root[2].1);
+        assert_eq!(secondary.iter().map(|entry| entry.3.as_str()).collect::<Vec<_>>(), vec![".metadata", "0", "1"]);
+        assert_eq!(root[2].1 + root[2].2, data.len());
+        let metadata: serde_json::Value = serde_json::from_str(&stream_content(&data, secondary[0].1, secondary[0].2)).unwrap();
+        assert_eq!(metadata["partitions"], json!([["chr2", 0, 10], ["chr1", 0, 7]]));
+        let (offset, size) = (secondary[1].1, secondary[1].2);
+        let mut cur = Cursor::new(&data[offset + FRAME_HEADER_SIZE..offset + size]);
+        assert_eq!(cur.read_u32::<LittleEndian>().unwrap(), 6);
+        assert_eq!(cur.read_u16::<LittleEndian>().unwrap(), 0);
+        assert_eq!(cur.read_i32::<LittleEndian>().unwrap(), 100);
+        let mut rest = Vec::new();
+        cur.read_to_end(&mut rest).unwrap();
+        assert!(rest.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_d4_directory_frames() {
+        let names: Vec<String> = (0..100).map(|id| format!("{:08}", id)).collect();
+        let mut entries = Vec::new();
+        for (id, name) in names.iter().enumerate() {
+            write_entry(&mut entries, STREAM, id as u64, 1, name).unwrap();
+        }
+        let frames = directory_frames(&entries);
+        assert_eq!(frames.len(), directory_size(&names));
+        assert_eq!(frames.len(), 6 * DIRECTORY_FRAME_SIZE);
+        let read = read_directory(&frames, 0);
+        assert_eq!(read.len(), 100);
+        assert_eq!(read[99], (STREAM, 99, 1, "00000099".to_owned()));
+    }
+}
@@ -0,0 +1,219 @@
+use bam_tools::BgzfWriter;
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+#[cfg(feature = "upload-commands")]
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+const SINK_BUF_SIZE: usize = 64 * 1024;
+
+/// Destination for exporter output. Call [`OutputSink::finish`] after writing
+/// all the data, otherwise compressed streams and uploads may end up truncated.
+pub trait OutputSink: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Opens output sink for `target`:
+/// - `None` or `-` - stdout,
+/// - `s3://...` - streamed multipart upload through `aws s3 cp`,
+/// - `ftp://`, `ftps://`, `sftp://...` - upload through `curl`,
+/// - anything else - local file.
+///
+/// Uploads run the external programs, which must be on PATH, and are only
+/// available with `upload-commands` feature. Otherwise remote targets are
+/// refused with [`io::ErrorKind::Unsupported`].
+///
+/// Output is BGZF compressed if `bgzip` is set or target ends with `.gz` or
+/// `.bgz`.
+pub fn open_sink(target: Option<&str>, bgzip: bool) -> io::Result<Box<dyn OutputSink>> {
+    let target = target.filter(|t| *t != "-");
+    let sink: Box<dyn OutputSink> = match target {
+        None => Box::new(StdoutSink(BufWriter::with_capacity(SINK_BUF_SIZE, io::stdout()))),
+        Some(url) if is_upload_target(url) => upload_sink(url)?,
+        Some(path) => Box::new(FileSink(BufWriter::with_capacity(SINK_BUF_SIZE, File::create(path)?))),
+    };
+
+    let compress = bgzip || matches!(target, Some(t) if t.ends_with(".gz") || t.ends_with(".bgz"));
+    if compress {
+        Ok(Box::new(BgzfSink(BgzfWriter::new(sink))))
+    } else {
+        Ok(sink)
+    }
+}
+
+fn is_upload_target(target: &str) -> bool {
+    ["s3://", "ftp://", "ftps://", "sftp://"].iter().any(|scheme| target.starts_with(scheme))
+}
+
+#[cfg(feature = "upload-commands")]
+fn upload_sink(url: &str) -> io::Result<Box<dyn OutputSink>> {
+    if url.starts_with("s3://") {
+        Ok(Box::new(CommandSink::spawn("aws", &["s3", "cp", "-", url])?))
+    } else {
+        Ok(Box::new(CommandSink::spawn("curl", &["-sS", "--ftp-create-dirs", "-T", "-", url])?))
+    }
+}
+
+#[cfg(not(feature = "upload-commands"))]
+fn upload_sink(url: &str) -> io::Result<Box<dyn OutputSink>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Output to {} needs gbam_tools built with upload-commands feature.", url),
+    ))
+}
+
+struct StdoutSink(BufWriter<Stdout>);
+
+impl Write for StdoutSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct FileSink(BufWriter<File>);
+
+impl Write for FileSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl OutputSink for FileSink {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_ref().sync_all()
+    }
+}
+
+struct BgzfSink(BgzfWriter<Box<dyn OutputSink>>);
+
+impl Write for BgzfSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl OutputSink for BgzfSink {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.0.into_inner()?.finish()
+    }
+}
+
+/// Pipes output into external uploader. Upload is complete once the process
+/// exits successfully. If the sink is dropped without
+/// [`OutputSink::finish`], stdin is still closed and the process waited for,
+/// its failure is printed to stderr.
+#[cfg(feature = "upload-commands")]
+struct CommandSink {
+    program: String,
+    child: Child,
+    // Taken when the process is waited for.
+    stdin: Option<BufWriter<ChildStdin>>,
+}
+
+#[cfg(feature = "upload-commands")]
+impl CommandSink {
+    fn spawn(program: &str, args: &[&str]) -> io::Result<Self> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to run {}: {}", program, e)))?;
+        let stdin = BufWriter::with_capacity(SINK_BUF_SIZE, child.stdin.take().unwrap());
+        Ok(Self { program: program.to_owned(), child, stdin: Some(stdin) })
+    }
+
+    fn stdin(&mut self) -> &mut BufWriter<ChildStdin> {
+        self.stdin.as_mut().expect("Upload is finished.")
+    }
+
+    /// Closes stdin, which signals end of data to the uploader, and waits
+    /// for it to exit. Does nothing if already closed.
+    fn close(&mut self) -> io::Result<()> {
+        let stdin = match self.stdin.take() {
+            Some(stdin) => stdin,
+            None => return Ok(()),
+        };
+        let flushed = stdin.into_inner().map(drop).map_err(|e| e.into_error());
+        let status = self.child.wait()?;
+        if status.success() {
+            flushed
+        } else {
+            Err(io::Error::other(format!("Upload through {} failed: {}", self.program, status)))
+        }
+    }
+}
+
+#[cfg(feature = "upload-commands")]
+impl Write for CommandSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin().flush()
+    }
+}
+
+#[cfg(feature = "upload-commands")]
+impl OutputSink for CommandSink {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.close()
+    }
+}
+
+#[cfg(feature = "upload-commands")]
+impl Drop for CommandSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_targets() {
+        assert!(is_upload_target("s3://bucket/out.bed") && is_upload_target("sftp://host/out.bed"));
+        assert!(!is_upload_target("out.bed") && !is_upload_target("https://host/out.bed"));
+        #[cfg(not(feature = "upload-commands"))]
+        assert_eq!(open_sink(Some("s3://bucket/out.bed"), false).err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(all(unix, feature = "upload-commands"))]
+    #[test]
+    fn test_command_sink() {
+        let dir = tempdir::TempDir::new("gbam_sink_test").unwrap();
+        let path = dir.path().join("uploaded");
+        let script = format!("cat > {}", path.display());
+        let mut sink = CommandSink::spawn("sh", &["-c", &script]).unwrap();
+        sink.write_all(b"data").unwrap();
+        // Dropped sink still waits for the upload.
+        drop(sink);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        let mut sink = Box::new(CommandSink::spawn("sh", &["-c", "cat > /dev/null; exit 3"]).unwrap());
+        sink.write_all(b"data").unwrap();
+        assert!(sink.finish().unwrap_err().to_string().contains("failed"));
+    }
+}
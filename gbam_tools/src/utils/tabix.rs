@@ -0,0 +1,313 @@
+use bam_tools::BgzfWriter;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::DeflateDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Same binning scheme as BAI: 16 kb linear index windows, 6 bin levels.
+const MIN_SHIFT: u32 = 14;
+// Generic format with 0-based, half-open coordinates (TBX_UCSC).
+const FORMAT_BED: i32 = 0x10000;
+// Bin holding span and line count of reference, as in htslib.
+const PSEUDO_BIN: u32 = 37450;
+// BGZF header up to and including BSIZE.
+const BLOCK_HEADER_SIZE: usize = 18;
+
+/// Columns of indexed lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TabixLayout {
+    /// `chr start end ...`, 0-based half-open, as BED and bedGraph.
+    #[default]
+    Bed,
+    /// `chr pos ...`, 0-based, as per base depth output.
+    Position,
+}
+
+/// Tabix index of a BGZF compressed BED-like file: lines of `chr start end
+/// ...` (or `chr pos ...`, see [`TabixLayout`]) sorted by chr (grouped) and
+/// start. Lines starting with `#` are skipped.
+#[derive(Debug, Default)]
+pub struct TabixIndex {
+    layout: TabixLayout,
+    names: Vec<String>,
+    refs: Vec<RefIndex>,
+}
+
+#[derive(Debug, Default)]
+struct RefIndex {
+    // Chunks of virtual positions per bin.
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    // Virtual position of the first line overlapping each window.
+    linear: Vec<u64>,
+    // Virtual positions of the first and past the last line.
+    span: Option<(u64, u64)>,
+    lines: u64,
+}
+
+impl RefIndex {
+    fn add(&mut self, start: u32, end: u32, line: (u64, u64)) {
+        let span = self.span.get_or_insert(line);
+        span.1 = line.1;
+        self.lines += 1;
+        let end = end.max(start + 1);
+        let chunks = self.bins.entry(reg2bin(start, end)).or_default();
+        match chunks.last_mut() {
+            Some(last) if last.1 == line.0 => last.1 = line.1,
+            _ => chunks.push(line),
+        }
+        let last_window = ((end - 1) >> MIN_SHIFT) as usize;
+        if self.linear.len() <= last_window {
+            self.linear.resize(last_window + 1, u64::MAX);
+        }
+        for offset in &mut self.linear[(start >> MIN_SHIFT) as usize..=last_window] {
+            *offset = (*offset).min(line.0);
+        }
+    }
+
+    /// Windows without lines point to the previous line.
+    fn fill_linear(&mut self) {
+        let mut prev = self.linear.iter().copied().find(|&offset| offset != u64::MAX).unwrap_or(0);
+        for offset in &mut self.linear {
+            if *offset == u64::MAX {
+                *offset = prev;
+            }
+            prev = *offset;
+        }
+    }
+}
+
+impl TabixIndex {
+    /// Indexes BGZF stream.
+    pub fn build<R: Read>(source: R, layout: TabixLayout) -> io::Result<Self> {
+        let mut index = Self { layout, ..Self::default() };
+        let mut blocks = Blocks::new(source);
+        let mut line = Vec::new();
+        let mut line_start = None;
+        while let Some((block_pos, data)) = blocks.next_block()? {
+            let mut pos = 0;
+            while pos < data.len() {
+                line_start.get_or_insert((block_pos << 16) | pos as u64);
+                match data[pos..].iter().position(|&c| c == b'\n') {
+                    Some(len) => {
+                        line.extend_from_slice(&data[pos..pos + len]);
+                        pos += len + 1;
+                        index.add_line(&line, (line_start.take().unwrap(), (block_pos << 16) | pos as u64))?;
+                        line.clear();
+                    }
+                    None => {
+                        line.extend_from_slice(&data[pos..]);
+                        pos = data.len();
+                    }
+                }
+            }
+        }
+        if let Some(start) = line_start {
+            index.add_line(&line, (start, blocks.end_pos << 16))?;
+        }
+        index.refs.iter_mut().for_each(RefIndex::fill_linear);
+        Ok(index)
+    }
+
+    fn add_line(&mut self, line: &[u8], offsets: (u64, u64)) -> io::Result<()> {
+        if line.is_empty() || line[0] == b'#' {
+            return Ok(());
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line: {}", String::from_utf8_lossy(line)));
+        let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+        let mut fields = line.split('\t');
+        let chr = fields.next().ok_or_else(invalid)?;
+        let start: u32 = fields.next().and_then(|f| f.trim_end().parse().ok()).ok_or_else(invalid)?;
+        let end: u32 = match self.layout {
+            TabixLayout::Bed => fields.next().and_then(|f| f.trim_end().parse().ok()).ok_or_else(invalid)?,
+            TabixLayout::Position => start + 1,
+        };
+        if self.names.last().map(String::as_str) != Some(chr) {
+            if self.names.iter().any(|name| name == chr) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Lines of {} are not grouped together.", chr)));
+            }
+            self.names.push(chr.to_owned());
+            self.refs.push(RefIndex::default());
+        }
+        self.refs.last_mut().unwrap().add(start, end, offsets);
+        Ok(())
+    }
+
+    /// Names of indexed sequences in file order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Writes the index in TBI format, BGZF compressed.
+    pub fn write<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut out = BgzfWriter::new(out);
+        out.write_all(b"TBI\x01")?;
+        out.write_i32::<LittleEndian>(self.refs.len() as i32)?;
+        out.write_i32::<LittleEndian>(FORMAT_BED)?;
+        // Sequence, start and end columns.
+        let columns = match self.layout {
+            TabixLayout::Bed => [1, 2, 3],
+            TabixLayout::Position => [1, 2, 2],
+        };
+        for col in columns {
+            out.write_i32::<LittleEndian>(col)?;
+        }
+        out.write_i32::<LittleEndian>(i32::from(b'#'))?;
+        // Lines to skip.
+        out.write_i32::<LittleEndian>(0)?;
+        let names_len: usize = self.names.iter().map(|name| name.len() + 1).sum();
+        out.write_i32::<LittleEndian>(names_len as i32)?;
+        for name in &self.names {
+            out.write_all(name.as_bytes())?;
+            out.write_u8(0)?;
+        }
+        for ref_index in &self.refs {
+            out.write_i32::<LittleEndian>(ref_index.bins.len() as i32 + 1)?;
+            for (bin, chunks) in &ref_index.bins {
+                out.write_u32::<LittleEndian>(*bin)?;
+                out.write_i32::<LittleEndian>(chunks.len() as i32)?;
+                for (start, end) in chunks {
+                    out.write_u64::<LittleEndian>(*start)?;
+                    out.write_u64::<LittleEndian>(*end)?;
+                }
+            }
+            // Span of the reference and counts of placed and unplaced lines.
+            let (span_start, span_end) = ref_index.span.unwrap_or_default();
+            out.write_u32::<LittleEndian>(PSEUDO_BIN)?;
+            out.write_i32::<LittleEndian>(2)?;
+            for value in [span_start, span_end, ref_index.lines, 0] {
+                out.write_u64::<LittleEndian>(value)?;
+            }
+            out.write_i32::<LittleEndian>(ref_index.linear.len() as i32)?;
+            for offset in &ref_index.linear {
+                out.write_u64::<LittleEndian>(*offset)?;
+            }
+        }
+        // Lines without coordinates.
+        out.write_u64::<LittleEndian>(0)?;
+        out.finish()
+    }
+}
+
+/// Writes tabix index of BGZF compressed BED-like file to `<path>.tbi` and
+/// returns its path.
+pub fn index_bed_gz(path: &Path, layout: TabixLayout) -> io::Result<PathBuf> {
+    let index = TabixIndex::build(BufReader::new(File::open(path)?), layout)?;
+    let mut index_path = path.as_os_str().to_owned();
+    index_path.push(".tbi");
+    let index_path = PathBuf::from(index_path);
+    index.write(File::create(&index_path)?)?;
+    Ok(index_path)
+}
+
+/// Bin of region `[start, end)` in BAI/tabix binning scheme.
+fn reg2bin(start: u32, end: u32) -> u32 {
+    let end = end - 1;
+    for (level_shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if start >> level_shift == end >> level_shift {
+            return offset + (start >> level_shift);
+        }
+    }
+    0
+}
+
+/// Decompressed BGZF blocks with their compressed positions.
+struct Blocks<R> {
+    source: R,
+    end_pos: u64,
+    compressed: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl<R: Read> Blocks<R> {
+    fn new(source: R) -> Self {
+        Self { source, end_pos: 0, compressed: Vec::new(), data: Vec::new() }
+    }
+
+    fn next_block(&mut self) -> io::Result<Option<(u64, &[u8])>> {
+        let mut header = [0; BLOCK_HEADER_SIZE];
+        let read = read_full(&mut self.source, &mut header)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if read < BLOCK_HEADER_SIZE || header[..2] != [0x1f, 0x8b] || &header[12..14] != b"BC" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Input is not BGZF compressed."));
+        }
+        let block_size = (&header[16..]).read_u16::<LittleEndian>()? as usize + 1;
+        // Compressed data followed by CRC32 and ISIZE.
+        self.compressed.resize(block_size.saturating_sub(BLOCK_HEADER_SIZE), 0);
+        self.source.read_exact(&mut self.compressed)?;
+        let data_end = self.compressed.len().saturating_sub(8);
+        self.data.clear();
+        DeflateDecoder::new(&self.compressed[..data_end]).read_to_end(&mut self.data)?;
+        let block_pos = self.end_pos;
+        self.end_pos += block_size as u64;
+        Ok(Some((block_pos, &self.data)))
+    }
+}
+
+fn read_full<R: Read>(source: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match source.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+
+    #[test]
+    fn test_tabix_index() {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(b"#chr\tstart\tend\tdepth\nchr1\t0\t100\t0\n").unwrap();
+        // Second line spans two blocks.
+        writer.write_all(b"chr1\t100\t200").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"00\t3\nchr2\t5\t6\t1\n").unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let index = TabixIndex::build(&data[..], TabixLayout::Bed).unwrap();
+        assert_eq!(index.names(), ["chr1", "chr2"]);
+        let second_block = u64::from(u16::from_le_bytes([data[16], data[17]])) + 1;
+        let chr1 = &index.refs[0];
+        // Header line is skipped.
+        assert_eq!(chr1.bins[&4681], vec![(21, 34)]);
+        assert_eq!(chr1.bins[&585], vec![(34, second_block << 16 | 5)]);
+        assert_eq!(chr1.linear, vec![21, 34]);
+        assert_eq!(index.refs[1].bins[&4681], vec![(second_block << 16 | 5, second_block << 16 | 16)]);
+        assert_eq!((chr1.span, chr1.lines), (Some((21, second_block << 16 | 5)), 2));
+
+        let mut tbi = Vec::new();
+        index.write(&mut tbi).unwrap();
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&tbi[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..4], b"TBI\x01");
+        assert_eq!(&decoded[36..46], b"chr1\0chr2\0");
+        // chr1 has bins 585, 4681 and the pseudo-bin.
+        assert_eq!(&decoded[46..50], &3i32.to_le_bytes());
+        let pseudo_bin = 50 + 2 * (8 + 16);
+        assert_eq!(&decoded[pseudo_bin..pseudo_bin + 8], &[&PSEUDO_BIN.to_le_bytes()[..], &2i32.to_le_bytes()[..]].concat()[..]);
+        assert!(decoded.ends_with(&0u64.to_le_bytes()));
+
+        assert!(TabixIndex::build(&b"chr1\t0\t1\n"[..], TabixLayout::Bed).is_err());
+    }
+
+    #[test]
+    fn test_position_layout() {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(b"chr1\t5\t3\nchr1\t20000\t1\n").unwrap();
+        let data = writer.into_inner().unwrap();
+        let index = TabixIndex::build(&data[..], TabixLayout::Position).unwrap();
+        let chr1 = &index.refs[0];
+        assert_eq!(chr1.bins[&4681], vec![(0, 9)]);
+        assert_eq!(chr1.bins[&4682], vec![(9, 22)]);
+        assert_eq!(chr1.linear, vec![0, 9]);
+    }
+}
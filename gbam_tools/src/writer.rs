@@ -1,5 +1,8 @@
-use super::meta::{BlockMeta, Codecs, FileInfo, FileMeta, FILE_INFO_SIZE, Stat};
-use crate::compressor::{CompressTask, Compressor, OrderingKey};
+use super::meta::{encode_meta, BlockHeader, BlockMeta, Codecs, FileFeatures, FileInfo, FileMeta, SortOrder, FILE_INFO_SIZE, GBAM_VERSION, Stat};
+use crate::compressor::{Compressor, OrderingKey};
+use crate::interval_index::IndexBuilder;
+use crate::name_index::NameIndexBuilder;
+use crate::stats::{self, ColumnStat, StatCollector};
 use crate::{SIZE_LIMIT, U32_SIZE};
 use bam_tools::record::bamrawrecord::BAMRawRecord;
 use bam_tools::record::fields::{
@@ -10,14 +13,39 @@ use crc32fast::Hasher;
 use std::borrow::Cow;
 use std::convert::TryInto;
 use std::convert::TryFrom;
-use std::io::{Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::reader::column::decompress_block;
+use crate::reader::tags::{entry_tag, RawEntries};
+use crate::reader::reader::read_footer;
+use memmap2::Mmap;
+
+/// Records pushed between calls of progress callback, see
+/// [`Writer::set_progress`].
+const PROGRESS_INTERVAL: u64 = 1 << 16;
+
+/// Progress of [`Writer`], passed to its progress callback.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteProgress {
+    /// Records pushed into the writer.
+    pub records: u64,
+    /// Position in output, which lags behind records being compressed.
+    pub bytes_written: u64,
+}
+
+/// Callback of [`Writer::set_progress`].
+pub type ProgressCallback = Box<dyn FnMut(WriteProgress) + Send>;
 
 pub(crate) struct BlockInfo {
     pub numitems: u32,
     pub uncompr_size: usize,
     pub field: Fields,
+    pub tag_col: Option<usize>,
     // Interpretation is up to the reader.
     pub stats: Option<Stat>,
+    pub column_stats: Vec<ColumnStat>,
 }
 
 impl Default for BlockInfo {
@@ -26,7 +54,9 @@ impl Default for BlockInfo {
             numitems: 0,
             uncompr_size: 0,
             field: Fields::RefID,
+            tag_col: None,
             stats: None,
+            column_stats: Vec::new(),
         }
     }
 }
@@ -49,12 +79,32 @@ where
     columns: Vec<Box<dyn Column>>,
     compressor: Compressor,
     inner: WS,
+    // Sort order claimed by the caller. Coordinate order is also checked on
+    // the fly, see `finish`.
+    sort_order: SortOrder,
+    // (RefID, Pos) of the last record. RefID is cast to u32 so unmapped (-1)
+    // go last.
+    last_coord: Option<(u32, i32)>,
+    coordinate_sorted: bool,
+    // Dropped once records come out of coordinate order.
+    interval_index: Option<IndexBuilder>,
+    name_index: Option<NameIndexBuilder>,
+    // Written before the first block, see `BlockHeader`.
+    meta_block_written: bool,
+    // Records pushed, see `set_progress`.
+    records: u64,
+    progress: Option<ProgressCallback>,
 }
 
 impl<WS> Writer<WS>
 where
     WS: Write + Seek,
 {
+    /// `codecs` holds codec for each field (indexed by `Fields as usize`). If
+    /// only one codec is passed, it is used for all fields, other lengths
+    /// panic. Block stats are
+    /// collected for `collect_stats_for` fields and always for Pos, see
+    /// [`Reader::lower_bound`](crate::reader::reader::Reader::lower_bound).
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         mut inner: WS,
@@ -70,36 +120,26 @@ where
             .seek(SeekFrom::Start((FILE_INFO_SIZE) as u64))
             .unwrap();
 
-        let mut columns = Vec::new();
-
-        let mut count = 0;
-        for field in Fields::iterator().filter(|f| is_data_field(f)) {
-            let stat_collector = collect_stats_for.iter().find(|f| *f == field).and(Some(Stat::default()));
-            let col = match field_type(field) {
-                FieldType::FixedSized => {
-                    Box::new(FixedColumn::new(*field, stat_collector)) as Box<dyn Column>
-                }
-                FieldType::VariableSized => {
-                    // Index column +1.
-                    count += 1;
-                    Box::new(VariableColumn::new(*field, stat_collector)) as Box<dyn Column>
-                }
-            };
-            columns.push(col);
-            count += 1;
-        }
-        debug_assert!(count == FIELDS_NUM);
+        let columns = create_columns(&collect_stats_for, &[]);
 
         Self {
-            // TODO: Codecs (currently only one is supported).
-            file_meta: FileMeta::new(codecs[0], ref_seqs, sam_header),
+            file_meta: FileMeta::new(&codecs, ref_seqs, sam_header),
             inner,
             compressor: Compressor::new(thread_num),
             columns,
-            file_info: FileInfo::new([1, 0], 0, 0, full_command, is_sorted),
+            file_info: FileInfo::new(0, 0, full_command, is_sorted),
+            sort_order: SortOrder::Unknown,
+            last_coord: None,
+            coordinate_sorted: true,
+            interval_index: None,
+            name_index: None,
+            meta_block_written: false,
+            records: 0,
+            progress: None,
         }
     }
 
+    /// Same as [`Writer::new`] with stats of Pos blocks only.
     pub fn new_no_stats(
         inner: WS,
         codecs: Vec<Codecs>,
@@ -121,8 +161,120 @@ where
         )
     }
 
+    /// Sets sort order of input records. Coordinate order is verified while
+    /// writing and is replaced by `Unsorted` if records come out of order. If
+    /// not set, `Coordinate` or `Unsorted` is recorded depending on the input.
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    /// Stores meta in binary encoding (see [`crate::meta::FileFeatures::BINARY_META`]),
+    /// which is smaller and opens faster than JSON for files with many blocks;
+    /// block lists are decoded only for columns which are read. Readers of
+    /// format version 1.0 can't open such files.
+    pub fn set_binary_meta(&mut self, enabled: bool) {
+        self.file_info.set_binary_meta(enabled);
+    }
+
+    /// Collects per block statistics of every column (see
+    /// [`crate::stats::collectors`]) into [`BlockMeta::column_stats`]. Has to
+    /// be called before pushing records, not for file opened for append with
+    /// records: its stats are continued if it has them.
+    pub fn set_column_stats(&mut self, enabled: bool) {
+        assert!(self.last_coord.is_none() && self.file_meta.view_blocks(&Fields::RefID).is_empty(), "Column stats have to be enabled before pushing records.");
+        set_column_stats(&mut self.columns, enabled);
+    }
+
+    /// Builds interval index (see [`crate::interval_index::IntervalIndex`])
+    /// which is stored in meta if records turn out to be coordinate sorted.
+    /// Has to be called before pushing records. Index of file opened for
+    /// append is continued if it has one.
+    pub fn set_interval_index(&mut self, enabled: bool) {
+        assert!(self.last_coord.is_none(), "Interval index has to be enabled before pushing records.");
+        self.interval_index = match enabled {
+            true => Some(IndexBuilder::new(self.file_meta.get_ref_seqs().len(), 0)),
+            false => None,
+        };
+    }
+
+    /// Builds read name index (see [`crate::name_index::NameIndex`]), which
+    /// is written after data blocks. Has to be called before pushing records,
+    /// not for file opened for append with records: its index is continued if
+    /// it has one.
+    pub fn set_name_index(&mut self, enabled: bool) {
+        assert!(self.last_coord.is_none() && self.file_meta.view_blocks(&Fields::RefID).is_empty(), "Name index has to be enabled before pushing records.");
+        self.name_index = match enabled {
+            true => Some(NameIndexBuilder::new()),
+            false => None,
+        };
+    }
+
+    /// Stores entries of `tags` (e.g. `[*b"NM", *b"AS"]`) in their own
+    /// columns compressed with `codec`, apart from RawTags column holding the
+    /// rest of tags. Readers then decompress only columns of tags they need
+    /// (see [`crate::reader::reader::Reader::select_tags`]). Shredded tags
+    /// follow the rest when records are read. Has to be called before pushing
+    /// records, tag columns of file opened for append are continued.
+    pub fn set_tag_columns(&mut self, tags: &[[u8; 2]], codec: Codecs) {
+        assert!(self.last_coord.is_none() && self.file_meta.view_blocks(&Fields::RefID).is_empty(), "Tag columns have to be set before pushing records.");
+        assert!(self.file_meta.tag_columns().is_empty(), "Tag columns are already set.");
+        let mut unique = Vec::new();
+        for tag in tags {
+            assert!(tag.iter().all(u8::is_ascii_alphanumeric), "Invalid tag name {:?}.", String::from_utf8_lossy(tag));
+            if !unique.contains(tag) {
+                unique.push(*tag);
+                self.file_meta.add_tag_column(*tag, codec);
+            }
+        }
+        if unique.is_empty() {
+            return;
+        }
+        let column_stats = self.columns.iter_mut().any(|col| !col.get_inners().0.collectors.is_empty());
+        self.columns.retain_mut(|col| col.get_inners().0.field != Fields::RawTags);
+        self.columns.extend(create_tag_columns(&unique));
+        set_column_stats(&mut self.columns, column_stats);
+    }
+
+    /// Stores user defined entry `key`, e.g. sample ID or pipeline version,
+    /// in meta. Entries of file opened for append are kept.
+    pub fn set_metadata(&mut self, key: &str, value: serde_json::Value) {
+        self.file_meta.set_metadata(key, value);
+    }
+
+    /// Calls `callback` with number of records pushed and bytes written
+    /// every 65536 records and once more when finished, e.g. to drive a
+    /// progress bar. Records of file opened for append are not counted.
+    pub fn set_progress(&mut self, callback: Option<ProgressCallback>) {
+        self.progress = callback;
+    }
+
+    /// Meta of the file being written.
+    pub fn file_meta(&self) -> &FileMeta {
+        &self.file_meta
+    }
+
     /// Push BAM record into this writer
     pub fn push_record(&mut self, record: &BAMRawRecord) {
+        if !self.meta_block_written {
+            write_meta_block(&mut self.inner, &self.file_meta);
+            self.meta_block_written = true;
+        }
+        if self.coordinate_sorted {
+            let refid = (&record.get_bytes(&Fields::RefID)[..]).read_i32::<LittleEndian>().unwrap();
+            let pos = (&record.get_bytes(&Fields::Pos)[..]).read_i32::<LittleEndian>().unwrap();
+            let coord = (refid as u32, pos);
+            self.coordinate_sorted = !matches!(self.last_coord, Some(last) if last > coord);
+            self.last_coord = Some(coord);
+        }
+        if !self.coordinate_sorted {
+            self.interval_index = None;
+        }
+        if let Some(index) = self.interval_index.as_mut() {
+            index.push_record(record);
+        }
+        if let Some(index) = self.name_index.as_mut() {
+            index.push_record(record);
+        }
         // Index fields are not written on their own. They hold index data for variable sized fields.
         for col in self.columns.iter_mut() {
             // Attempt to write data in this column. If the column is full it
@@ -139,6 +291,13 @@ where
                 );
             }
         }
+        self.records += 1;
+        if self.records.is_multiple_of(PROGRESS_INTERVAL) {
+            if let Some(progress) = self.progress.as_mut() {
+                let bytes_written = self.inner.stream_position().unwrap();
+                progress(WriteProgress { records: self.records, bytes_written });
+            }
+        }
     }
 
     /// Terminates the writer. Always call after writting all the data. Returns
@@ -159,50 +318,309 @@ where
 
         for mut task in self.compressor.finish() {
             if let OrderingKey::Key(key) = task.ordering_key {
-                write_data_and_update_meta(&mut self.inner, &mut self.file_meta, key, &mut task);
+                write_data_and_update_meta(
+                    &mut self.inner,
+                    &mut self.file_meta,
+                    key,
+                    &mut task.block_info,
+                    &task.buf,
+                );
             }
         }
 
+        let sort_order = match self.sort_order {
+            SortOrder::Unknown | SortOrder::Coordinate if self.coordinate_sorted => SortOrder::Coordinate,
+            SortOrder::Unknown | SortOrder::Coordinate => SortOrder::Unsorted,
+            other => other,
+        };
+        self.file_meta.set_sort_order(sort_order);
+        let interval_index = self.interval_index.take().filter(|_| sort_order == SortOrder::Coordinate);
+        self.file_meta.set_interval_index(interval_index.map(IndexBuilder::finish));
+        let name_index = match self.name_index.take() {
+            Some(builder) => Some(builder.write(&mut self.inner)?),
+            None => None,
+        };
+        self.file_meta.set_name_index(name_index);
+        // Temporary files, written with no command, are not recorded.
+        if !self.file_info.creation_command.is_empty() {
+            self.file_meta.add_program_record(&self.file_info.creation_command);
+        }
+
         let meta_start_pos = self.inner.stream_position()?;
         // Write meta
-        let main_meta = serde_json::to_string(&self.file_meta).unwrap();
-        let main_meta_bytes = main_meta.as_bytes();
+        let main_meta_bytes = &encode_meta(&self.file_info, &self.file_meta)[..];
         let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
         self.inner.write_all(main_meta_bytes)?;
 
         let total_bytes_written = self.inner.stream_position()?;
-        // Revert back to the beginning of the file
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        self.inner.write_all(&[0;FILE_INFO_SIZE]).unwrap();
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        let file_info = & mut self.file_info;
+        if let Some(progress) = self.progress.as_mut() {
+            progress(WriteProgress { records: self.records, bytes_written: total_bytes_written });
+        }
+        // File info at the beginning of the file is swapped last, see
+        // `rewrite_meta`.
+        let file_info = &mut self.file_info;
         file_info.seekpos = meta_start_pos;
         file_info.crc32 = crc32;
-        let file_info_bytes = serde_json::to_string(&file_info).unwrap();
-        self.inner.write_all(file_info_bytes.as_bytes())?;
+        file_info.meta_size = Some(main_meta_bytes.len() as u64);
+        // Appended files are upgraded to the version of this build.
+        file_info.gbam_version = GBAM_VERSION;
+        file_info.set_features(&self.file_meta);
+        if file_info.trailer {
+            self.inner.write_all(&file_info_bytes(file_info))?;
+            self.inner.flush()?;
+            return self.inner.stream_position();
+        }
+        write_file_info(&mut self.inner, file_info)?;
+        self.inner.flush()?;
         Ok(total_bytes_written)
     }
 }
 
+impl<W: Write> Writer<StreamOutput<W>> {
+    /// Writer into non-seekable output, e.g. stdout or multipart upload. File
+    /// info can't be updated at the beginning once meta is written, so it is
+    /// repeated after meta at the end of the file. See [`Writer::new`] for
+    /// the arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_streaming(
+        inner: W,
+        codecs: Vec<Codecs>,
+        thread_num: usize,
+        collect_stats_for: Vec<Fields>,
+        ref_seqs: Vec<(String, u32)>,
+        sam_header: Vec<u8>,
+        full_command: String,
+        is_sorted: bool,
+    ) -> Self {
+        let mut inner = StreamOutput::new(inner);
+        let mut file_info = FileInfo::new(0, 0, full_command.clone(), is_sorted);
+        file_info.trailer = true;
+        file_info.features = FileFeatures::TRAILER.bits();
+        write_file_info(&mut inner, &file_info).unwrap();
+        let mut writer = Self::new(inner, codecs, thread_num, collect_stats_for, ref_seqs, sam_header, full_command, is_sorted);
+        writer.file_info.trailer = true;
+        writer
+    }
+}
+
+impl<WS> Writer<WS>
+where
+    WS: Write + Seek,
+{
+    /// Returns underlying writer, e.g. buffer of in-memory GBAM file. Call
+    /// after [`Writer::finish`].
+    pub fn into_inner(self) -> WS {
+        self.inner
+    }
+}
+
+impl Writer<BufWriter<File>> {
+    /// Opens existing GBAM file to append records to it. The last block of
+    /// every column is loaded back into the column buffer and rewritten, so
+    /// its old compressed bytes remain in the file as unused space, as does
+    /// the old meta. The file is locked for other writers until the writer is
+    /// dropped, readers see the file as it was before append until
+    /// [`Writer::finish`] (see [`Reader::open_shared`]).
+    ///
+    /// [`Reader::open_shared`]: crate::reader::reader::Reader::open_shared
+    pub fn open_for_append<P: AsRef<Path>>(path: P, thread_num: usize) -> std::io::Result<Self> {
+        let (mut file, mut file_info, mut file_meta) = open_for_update(path)?;
+        // Appended records are not checked against existing ones.
+        file_info.is_sorted = false;
+        // Stored fingerprint does not cover appended records.
+        file_meta.set_fingerprint(None);
+        let records: u64 = file_meta.view_blocks(&Fields::RefID).iter().map(|block| u64::from(block.numitems)).sum();
+        let interval_index = file_meta.interval_index().cloned().map(|index| IndexBuilder::resume(index, records));
+        let name_index = match file_meta.name_index() {
+            Some(index) => Some(NameIndexBuilder::resume(index, &mut file)?),
+            None => None,
+        };
+
+        let collect_stats_for: Vec<Fields> = Fields::iterator()
+            .filter(|field| {
+                matches!(file_meta.view_blocks(field).first(), Some(block) if block.stats.is_some())
+            })
+            .copied()
+            .collect();
+        let tags: Vec<[u8; 2]> = file_meta.tag_columns().iter().map(|col| col.tag()).collect();
+        let mut columns = create_columns(&collect_stats_for, &tags);
+        for col in columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            load_last_block(&mut file, &mut file_meta, inner)?;
+            if let Some(idx_inner) = idx {
+                load_last_block(&mut file, &mut file_meta, idx_inner)?;
+            }
+        }
+
+        // Continue order check from the last record already in the file.
+        let coordinate_sorted = file_meta.sort_order() == SortOrder::Coordinate;
+        let last_coord = if coordinate_sorted { last_coord(&mut columns) } else { None };
+        let sort_order = match file_meta.sort_order() {
+            SortOrder::Queryname => SortOrder::Unsorted,
+            other => other,
+        };
+
+        // Bytes of the current version are not modified.
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            file_meta,
+            inner: BufWriter::new(file),
+            compressor: Compressor::new(thread_num),
+            columns,
+            file_info,
+            sort_order,
+            last_coord,
+            coordinate_sorted,
+            interval_index,
+            name_index,
+            meta_block_written: false,
+            records: 0,
+            progress: None,
+        })
+    }
+}
+
+/// (RefID, Pos) of the last record in column buffers.
+fn last_coord(columns: &mut [Box<dyn Column>]) -> Option<(u32, i32)> {
+    let mut last_value = |field: Fields| {
+        columns
+            .iter_mut()
+            .map(|col| col.get_inners().0)
+            .find(|inner| inner.field == field)
+            .filter(|inner| inner.offset >= U32_SIZE)
+            .map(|inner| (&inner.buffer[inner.offset - U32_SIZE..]).read_i32::<LittleEndian>().unwrap())
+    };
+    Some((last_value(Fields::RefID)? as u32, last_value(Fields::Pos)?))
+}
+
+/// Columns of all data fields. With `tags` RawTags is split into tag columns,
+/// see [`Writer::set_tag_columns`]. Stats of Pos are collected regardless of
+/// `collect_stats_for`.
+fn create_columns(collect_stats_for: &[Fields], tags: &[[u8; 2]]) -> Vec<Box<dyn Column>> {
+    let mut columns = Vec::new();
+
+    let mut count = 0;
+    for field in Fields::iterator().filter(|f| is_data_field(f)) {
+        if *field == Fields::RawTags && !tags.is_empty() {
+            columns.extend(create_tag_columns(tags));
+            count += 2;
+            continue;
+        }
+        let stat_collector = (*field == Fields::Pos || collect_stats_for.contains(field)).then(Stat::default);
+        let col = match field_type(field) {
+            FieldType::FixedSized => {
+                Box::new(FixedColumn::new(*field, stat_collector)) as Box<dyn Column>
+            }
+            FieldType::VariableSized => {
+                // Index column +1.
+                count += 1;
+                Box::new(VariableColumn::new(*field, stat_collector)) as Box<dyn Column>
+            }
+        };
+        columns.push(col);
+        count += 1;
+    }
+    debug_assert!(count == FIELDS_NUM);
+    columns
+}
+
+/// Sets or drops collectors of column stats of data (not index) fields.
+fn set_column_stats(columns: &mut [Box<dyn Column>], enabled: bool) {
+    for col in columns.iter_mut() {
+        let inner = col.get_inners().0;
+        inner.collectors = if enabled { stats::collectors(&inner.field) } else { Vec::new() };
+    }
+}
+
+/// Column of tags other than `tags` and a column per tag, numbered as tag
+/// columns in meta.
+fn create_tag_columns(tags: &[[u8; 2]]) -> Vec<Box<dyn Column>> {
+    let mut columns = vec![Box::new(VariableColumn::new_split(TagSplit::Rest(tags.to_vec()))) as Box<dyn Column>];
+    for (tag_col, tag) in tags.iter().enumerate() {
+        columns.push(Box::new(VariableColumn::new_split(TagSplit::Tag(*tag, tag_col))));
+    }
+    columns
+}
+
+/// Removes the last block of the field from meta and loads its data into the
+/// column buffer, so the column continues filling it.
+fn load_last_block<R: Read + Seek>(
+    source: &mut R,
+    file_meta: &mut FileMeta,
+    inner: &mut Inner,
+) -> std::io::Result<()> {
+    let codec = *file_meta.get_column_codec(&inner.field, inner.tag_col);
+    let blocks = file_meta.get_column_blocks(&inner.field, inner.tag_col);
+    if matches!(blocks.first(), Some(block) if !block.column_stats.is_empty()) {
+        inner.collectors = stats::collectors(&inner.field);
+    }
+    let block = match blocks.pop() {
+        Some(block) => block,
+        None => return Ok(()),
+    };
+
+    let mut compressed = vec![0; block.block_size as usize];
+    source.seek(SeekFrom::Start(block.seekpos))?;
+    source.read_exact(&mut compressed)?;
+
+    let mut data = vec![0; block.uncompressed_size as usize];
+    if block.uncompressed_size > 0 {
+        decompress_block(&compressed, &mut data, &codec)?;
+    }
+
+    inner.block_num = blocks.len() as u64;
+    inner.rec_count = block.numitems;
+    inner.offset = data.len();
+    if inner.stats_collector.is_some() {
+        // Files written before Pos stats were collected have no stats of it.
+        inner.stats_collector = Some(block.stats.unwrap_or_else(|| {
+            let mut stat = Stat::default();
+            data.chunks_exact(U32_SIZE).for_each(|val| stat.update(i32::from_le_bytes(val.try_into().unwrap())));
+            stat
+        }));
+    }
+    for (collector, stat) in inner.collectors.iter_mut().zip(&block.column_stats) {
+        collector.merge(stat);
+    }
+    inner.buffer = data;
+    Ok(())
+}
+
 fn flush_field_buffer<WS: Write + Seek>(
     writer: &mut WS,
     file_meta: &mut FileMeta,
     compressor: &mut Compressor,
     inner: &mut Inner,
 ) {
-    let field = &inner.field;
+    let codec = *file_meta.get_column_codec(&inner.field, inner.tag_col);
+
+    // Stored blocks skip the compressor entirely and are written out right away.
+    if codec == Codecs::NoCompression {
+        let key = inner.block_num;
+        let mut block_info = inner.generate_block_info();
+        let len = block_info.uncompr_size;
+        write_data_and_update_meta(writer, file_meta, key, &mut block_info, &inner.buffer[..len]);
+        inner.reset_for_new_block();
+        return;
+    }
+
     let mut completed_task = compressor.get_compr_block();
 
     if let OrderingKey::Key(key) = completed_task.ordering_key {
-        write_data_and_update_meta(writer, file_meta, key, &mut completed_task);
+        write_data_and_update_meta(
+            writer,
+            file_meta,
+            key,
+            &mut completed_task.block_info,
+            &completed_task.buf,
+        );
     }
 
     let old_buffer = &mut inner.buffer;
 
     let data = std::mem::replace(old_buffer, completed_task.buf);
 
-    let codec = *file_meta.get_field_codec(field);
-
     compressor.compress_block(
         OrderingKey::Key(inner.block_num),
         inner.generate_block_info(),
@@ -213,22 +631,44 @@ fn flush_field_buffer<WS: Write + Seek>(
     inner.reset_for_new_block();
 }
 
+/// Meta without block lists with its header, so blocks written after it
+/// can be recovered if the writer never finishes (see [`crate::rescue`]).
+fn write_meta_block<WS: Write>(writer: &mut WS, file_meta: &FileMeta) {
+    let data = serde_json::to_vec(&file_meta.without_blocks()).unwrap();
+    let header = BlockHeader {
+        field: None,
+        tag_col: None,
+        block_num: 0,
+        numitems: 0,
+        block_size: data.len().try_into().unwrap(),
+        uncompressed_size: data.len() as u64,
+    };
+    writer.write_all(&header.to_bytes(&data)).unwrap();
+    writer.write_all(&data).unwrap();
+}
+
 fn write_data_and_update_meta<WS: Write + Seek>(
     writer: &mut WS,
     file_meta: &mut FileMeta,
     key: u64,
-    task: &mut CompressTask,
+    block_info: &mut BlockInfo,
+    data: &[u8],
 ) {
-    let compressed_size = task.buf.len();
-    let meta = generate_meta(
-        writer,
-        &mut task.block_info,
-        compressed_size.try_into().unwrap(),
-    );
+    let header = BlockHeader {
+        field: Some(block_info.field),
+        tag_col: block_info.tag_col,
+        block_num: key,
+        numitems: block_info.numitems,
+        block_size: data.len().try_into().unwrap(),
+        uncompressed_size: block_info.uncompr_size as u64,
+    };
+    writer.write_all(&header.to_bytes(data)).unwrap();
+    let compressed_size = data.len();
+    let meta = generate_meta(writer, block_info, compressed_size.try_into().unwrap());
 
-    writer.write_all(&task.buf).unwrap();
+    writer.write_all(data).unwrap();
 
-    let field_meta = file_meta.get_blocks(&task.block_info.field);
+    let field_meta = file_meta.get_column_blocks(&block_info.field, block_info.tag_col);
     if field_meta.len() <= key as usize {
         field_meta.resize(key as usize + 1, BlockMeta::default());
     }
@@ -249,6 +689,7 @@ fn generate_meta<S: Seek>(
         block_size,
         uncompressed_size: block_info.uncompr_size as u64,
         stats: block_info.stats.take(),
+        column_stats: std::mem::take(&mut block_info.column_stats),
     }
 }
 
@@ -260,9 +701,13 @@ enum WriteStatus<'a> {
 
 struct Inner {
     stats_collector: Option<Stat>,
+    // See `Writer::set_column_stats`.
+    collectors: Vec<Box<dyn StatCollector>>,
     buffer: Vec<u8>,
     offset: usize,
     field: Fields,
+    // Tag column of RawTags or RawTagsLen field, see `TagSplit`.
+    tag_col: Option<usize>,
     rec_count: u32,
     block_num: u64,
 }
@@ -271,9 +716,11 @@ impl Inner {
     pub fn new(field: Fields, stats_collector: Option<Stat>) -> Self {
         Self {
             stats_collector,
+            collectors: Vec::new(),
             buffer: Vec::new(),
             offset: 0,
             field,
+            tag_col: None,
             rec_count: 0,
             block_num: 0,
         }
@@ -289,6 +736,9 @@ impl Inner {
 
         self.buffer[self.offset..self.offset + data.len()].clone_from_slice(data);
         self.offset += data.len();
+        for collector in self.collectors.iter_mut() {
+            collector.update(data);
+        }
 
         self.rec_count += 1;
 
@@ -317,7 +767,9 @@ impl Inner {
             numitems: self.rec_count,
             uncompr_size: self.offset,
             field: self.field,
+            tag_col: self.tag_col,
             stats: stat,
+            column_stats: self.collectors.iter_mut().map(|collector| collector.take()).collect(),
         }
     }
 }
@@ -362,9 +814,35 @@ impl Column for FixedColumn {
     }
 }
 
+/// Part of RawTags written by a column when tags are split into tag columns.
+enum TagSplit {
+    /// Entries of the tag, written into tag column of given number.
+    Tag([u8; 2], usize),
+    /// Entries of tags other than these, written into RawTags column.
+    Rest(Vec<[u8; 2]>),
+}
+
+impl TagSplit {
+    fn extract(&self, tags: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        for entry in RawEntries::new(tags) {
+            let keep = match (self, entry_tag(entry)) {
+                (TagSplit::Tag(tag, _), Some(name)) => name == *tag,
+                (TagSplit::Tag(..), None) => false,
+                (TagSplit::Rest(tags), name) => !matches!(name, Some(name) if tags.contains(&name)),
+            };
+            if keep {
+                out.extend_from_slice(entry);
+            }
+        }
+    }
+}
+
 struct VariableColumn {
     inner: Inner,
     index: FixedColumn,
+    split: Option<TagSplit>,
+    split_buf: Vec<u8>,
 }
 
 impl VariableColumn {
@@ -375,7 +853,20 @@ impl VariableColumn {
         Self {
             inner: Inner::new(field, comparator),
             index: FixedColumn::new(var_size_field_to_index(&field), None),
+            split: None,
+            split_buf: Vec::new(),
+        }
+    }
+
+    /// Column of RawTags part.
+    fn new_split(split: TagSplit) -> Self {
+        let mut col = Self::new(Fields::RawTags, None);
+        if let TagSplit::Tag(_, tag_col) = split {
+            col.inner.tag_col = Some(tag_col);
+            col.index.0.tag_col = Some(tag_col);
         }
+        col.split = Some(split);
+        col
     }
 }
 
@@ -384,7 +875,11 @@ impl Column for VariableColumn {
         let inner = &mut self.inner;
         let index_inner = &mut self.index.0;
 
-        let data = rec.get_bytes(&inner.field);
+        let mut data = rec.get_bytes(&inner.field);
+        if let Some(split) = self.split.as_ref() {
+            split.extract(data, &mut self.split_buf);
+            data = &self.split_buf;
+        }
         let mut idx_buf: [u8; U32_SIZE] = [0; U32_SIZE];
 
         if index_inner.flush_required(&idx_buf) {
@@ -440,47 +935,328 @@ where
 //     }
 // }
 
+/// Opens finished GBAM file for update of its records or meta. Waits for
+/// exclusive lock, which is held until the file is closed, so updates do not
+/// interleave.
+pub(crate) fn open_for_update<P: AsRef<Path>>(path: P) -> std::io::Result<(File, FileInfo, FileMeta)> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.lock()?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let (file_info, file_meta) = read_footer(&mmap)?;
+    Ok((file, file_info, file_meta))
+}
+
+/// Switches meta of finished GBAM file to binary or JSON encoding (see
+/// [`Writer::set_binary_meta`]), meta is rewritten in place.
+pub fn store_binary_meta<P: AsRef<Path>>(path: P, enabled: bool) -> std::io::Result<()> {
+    let (mut file, mut file_info, file_meta) = open_for_update(path)?;
+    file_info.set_binary_meta(enabled);
+    rewrite_meta(&mut file, &mut file_info, &file_meta)
+}
+
+/// Replaces meta of finished GBAM file, data blocks are kept. Footer swap:
+/// new meta is written after the end of file and only then file info is
+/// pointed to it with one write, so readers of the previous version never see
+/// a torn meta. The old meta stays in the file as unused space.
+pub(crate) fn rewrite_meta(file: &mut File, file_info: &mut FileInfo, file_meta: &FileMeta) -> std::io::Result<()> {
+    let main_meta_bytes = &encode_meta(file_info, file_meta)[..];
+    file_info.seekpos = file.seek(SeekFrom::End(0))?;
+    file.write_all(main_meta_bytes)?;
+    file.sync_data()?;
+
+    file_info.crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
+    file_info.meta_size = Some(main_meta_bytes.len() as u64);
+    // The file becomes regular, file info of streamed file at its end is
+    // left unused.
+    file_info.trailer = false;
+    file_info.gbam_version = GBAM_VERSION;
+    file_info.set_features(file_meta);
+    write_file_info(file, file_info)?;
+    file.sync_all()
+}
+
+/// Writes file info at the beginning of the file with a single write.
+pub(crate) fn write_file_info<W: Write + Seek>(out: &mut W, file_info: &FileInfo) -> std::io::Result<()> {
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&file_info_bytes(file_info))
+}
+
+/// File info JSON padded with NULs to `FILE_INFO_SIZE`.
+fn file_info_bytes(file_info: &FileInfo) -> Vec<u8> {
+    let mut file_info_bytes = serde_json::to_string(file_info).unwrap().into_bytes();
+    assert!(file_info_bytes.len() < FILE_INFO_SIZE, "File info does not fit into its space.");
+    file_info_bytes.resize(FILE_INFO_SIZE, 0);
+    file_info_bytes
+}
+
+/// Non-seekable output of [`Writer::new_streaming`]. Counts bytes written,
+/// so the writer can take stream position; seeking anywhere else fails.
+pub struct StreamOutput<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> StreamOutput<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for StreamOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for StreamOutput<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Start(pos) if pos == self.pos => Ok(pos),
+            SeekFrom::Current(0) => Ok(self.pos),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Streaming output can't seek.")),
+        }
+    }
+}
+
 pub(crate) fn calc_crc_for_meta_bytes(bytes: &[u8]) -> u32 {
     let mut hasher = Hasher::new();
     hasher.update(bytes);
     hasher.finalize()
 }
 
-// #[ignore]
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use crate::reader::parse_tmplt::*;
-//     use crate::reader::reader::*;
-//     use byteorder::ReadBytesExt;
-//     use std::io::Cursor;
-//     #[test]
-//     fn test_writer() {
-//         // let raw_records = vec![BAMRawRecord::default(); 2];
-//         // let mut buf: Vec<u8> = vec![0; SIZE_LIMIT];
-//         // let out = Cursor::new(&mut buf[..]);
-//         // let mut writer = Writer::new(out, Codecs::Gzip, 8);
-//         // for rec in raw_records.iter() {
-//         //     writer.push_record(rec);
-//         // }
-//         // let total_bytes_written = writer.finish().unwrap();
-//         // buf.resize(total_bytes_written as usize, 0);
-
-//         // let in_cursor = Box::new(Cursor::new(buf));
-//         // let mut parsing_template = ParsingTemplate::new();
-//         // parsing_template.set_all();
-//         // let mut reader = Reader::new(in_cursor, parsing_template).unwrap();
-//         // let mut records = reader.records();
-//         // let mut it = raw_records.iter();
-//         // while let Some(rec) = records.next_rec() {
-//         //     let rec_orig = it.next().unwrap();
-//         //     let orig_map_q = rec_orig.get_bytes(&Fields::Mapq)[0];
-//         //     let orig_pos = rec_orig
-//         //         .get_bytes(&Fields::Pos)
-//         //         .read_i32::<LittleEndian>()
-//         //         .unwrap();
-//         //     assert_eq!(rec.pos.unwrap(), orig_pos);
-//         //     assert_eq!(rec.mapq.unwrap(), orig_map_q);
-//         // }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::gbam_to_sam::format_sam_record;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::io_stats::IoStats;
+    use crate::reader::reader::Reader;
+    use crate::reader::record::GbamRecord;
+    use crate::test_util::{gbam_bytes_from_sam, parse_sam, write_sam};
+    use std::borrow::Cow;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+@SQ\tSN:chr1\tLN:1000\n\
+r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tNM:i:1\tRG:Z:grp\n\
+r2\t147\tchr1\t21\t60\t5M\t=\t11\t-15\tACGTA\t*\n\
+r3\t4\t*\t0\t0\t*\t*\t0\t0\tACG\tIII\n";
+
+    #[test]
+    fn test_in_memory_round_trip() {
+        let (sam_header, ref_seqs, records) = parse_sam(SAM);
+        let mut writer = Writer::new(
+            Cursor::new(Vec::new()),
+            vec![Codecs::Lz4],
+            2,
+            vec![Fields::RefID],
+            ref_seqs.clone(),
+            sam_header,
+            String::new(),
+            false,
+        );
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        writer.set_progress(Some(Box::new(move |progress| sink.lock().unwrap().push(progress))));
+        for rec in records.iter() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        let total_bytes_written = writer.finish().unwrap();
+        let bytes = writer.into_inner().into_inner();
+        assert_eq!(bytes.len() as u64, total_bytes_written);
+        assert_eq!(*reports.lock().unwrap(), vec![WriteProgress { records: 3, bytes_written: total_bytes_written }]);
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::from_bytes(bytes, template).unwrap();
+        assert_eq!(reader.amount, 3);
+        assert_eq!(reader.file_meta.sort_order(), SortOrder::Coordinate);
+        // Pos stats are collected without being asked for.
+        let stat = reader.file_meta.view_blocks(&Fields::Pos)[0].stats.clone().unwrap();
+        assert_eq!((stat.min_value, stat.max_value), (-1, 20));
+        let stat = reader.file_meta.view_blocks(&Fields::RefID)[0].stats.clone().unwrap();
+        assert_eq!((stat.min_value, stat.max_value), (-1, 0));
+        assert!(reader.file_meta.view_blocks(&Fields::Flags)[0].stats.is_none());
+
+        let mut records = reader.records();
+        let mut text = Vec::new();
+        while let Some(rec) = records.next_rec() {
+            format_sam_record(rec, &ref_seqs, &mut text);
+        }
+        let expected: String = SAM.lines().filter(|line| !line.starts_with('@')).map(|line| format!("{}\n", line)).collect();
+        assert_eq!(String::from_utf8(text).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_streaming_round_trip() {
+        let (sam_header, ref_seqs, records) = parse_sam(SAM);
+        let mut writer = Writer::new_streaming(Vec::new(), vec![Codecs::Lz4], 2, vec![Fields::RefID], ref_seqs, sam_header, String::new(), false);
+        writer.set_interval_index(true);
+        for rec in records.iter() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        let total_bytes_written = writer.finish().unwrap();
+        let bytes = writer.into_inner().into_inner();
+        assert_eq!(bytes.len() as u64, total_bytes_written);
+
+        let mut reader = Reader::from_bytes(bytes, ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.amount, 3);
+        assert!(reader.file_meta.interval_index().is_some());
+        let mut records = reader.fetch("chr1:21-21").unwrap();
+        assert_eq!(records.next_rec().unwrap().pos, Some(20));
+        assert!(records.next_rec().is_none());
+    }
+
+    #[test]
+    fn test_tag_columns() {
+        let bytes = gbam_bytes_from_sam(SAM, |writer| {
+            writer.set_tag_columns(&[*b"NM", *b"XS"], Codecs::Gzip);
+            writer.set_binary_meta(true);
+        });
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::from_bytes(bytes, template).unwrap();
+        let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+        let tag_cols: Vec<_> = reader.file_meta.tag_columns().iter().map(|col| col.tag()).collect();
+        assert_eq!(tag_cols, vec![*b"NM", *b"XS"]);
+        assert_eq!(*reader.file_meta.get_column_codec(&Fields::RawTags, Some(0)), Codecs::Gzip);
+        assert_eq!(reader.file_meta.view_column_blocks(&Fields::RawTagsLen, Some(1))[0].numitems, 3);
+
+        let file_stats = reader.stats();
+        assert_eq!(file_stats.records, 3);
+        assert_eq!(file_stats.columns.len(), FIELDS_NUM + 4);
+        let nm = &file_stats.columns[FIELDS_NUM];
+        assert_eq!((nm.field, nm.tag, nm.codec, nm.blocks), (Fields::RawTags, Some(*b"NM"), Codecs::Gzip, 1));
+        assert_eq!(nm.uncompressed_bytes, 4);
+        let pos = &file_stats.columns[Fields::Pos as usize];
+        assert_eq!((pos.codec, pos.uncompressed_bytes), (Codecs::Lz4, 12));
+        assert_eq!(pos.ratio(), Some(12.0 / pos.compressed_bytes as f64));
+
+        // Split tags follow the rest.
+        let mut text = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec() {
+            format_sam_record(rec, &ref_seqs, &mut text);
+        }
+        drop(records);
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.lines().next().unwrap(), "r1\t99\tchr1\t11\t60\t3M1I2M\t=\t21\t15\tACGTAC\tIIIIII\tRG:Z:grp\tNM:i:1");
+        assert_eq!(text.lines().nth(1).unwrap(), "r2\t147\tchr1\t21\t60\t5M\t=\t11\t-15\tACGTA\t*");
+
+        // Only the data and index block of NM column are read.
+        let stats = IoStats::shared();
+        reader.set_io_stats(Some(stats.clone()));
+        reader.select_tags(Some(&[*b"NM"]));
+        let mut rec = GbamRecord::default();
+        reader.fill_record(0, &mut rec);
+        assert_eq!(rec.tags.as_deref(), Some(&b"NMC\x01"[..]));
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.field(Fields::RawTags).blocks_read, 1);
+        assert_eq!(stats.field(Fields::RawTagsLen).blocks_read, 1);
+    }
+
+    #[test]
+    fn test_readers_during_append() {
+        let dir = TempDir::new("gbam_append_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        let (sam_header, ref_seqs, records) = parse_sam(SAM);
+        let mut writer = Writer::new_no_stats(BufWriter::new(File::create(&path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false);
+        writer.set_metadata("sample", serde_json::json!({"id": "s1", "lanes": [1, 2]}));
+        for rec in records.iter() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        let names = |reader: &mut Reader| {
+            let mut names = Vec::new();
+            let mut records = reader.records();
+            while let Some(rec) = records.next_rec() {
+                names.push(String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).trim_end_matches('\0').to_owned());
+            }
+            names
+        };
+        let template = ParsingTemplate::new_with(&[Fields::ReadName]);
+        let mut before = Reader::open_shared(&path, template.clone()).unwrap();
+        let mut appender = Writer::open_for_append(&path, 2).unwrap();
+        for rec in records.iter() {
+            appender.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        appender.finish().unwrap();
+        // File info is swapped, the lock is held until the writer is dropped.
+        assert_eq!(Reader::open_shared(&path, template.clone()).unwrap().amount, 6);
+        drop(appender);
+
+        // The version before append is still intact.
+        assert_eq!(names(&mut before), vec!["r1", "r2", "r3"]);
+        let mut after = Reader::open_shared(&path, template).unwrap();
+        assert_eq!(names(&mut after), vec!["r1", "r2", "r3", "r1", "r2", "r3"]);
+        assert_eq!(after.metadata()["sample"]["lanes"][1], 2);
+    }
+
+    #[test]
+    fn test_column_stats() {
+        let dir = TempDir::new("gbam_column_stats_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        let (sam_header, ref_seqs, records) = parse_sam(SAM);
+        let mut writer = Writer::new_no_stats(BufWriter::new(File::create(&path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false);
+        writer.set_column_stats(true);
+        writer.set_binary_meta(true);
+        writer.set_tag_columns(&[*b"NM"], Codecs::Lz4);
+        for rec in records.iter() {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        let pos = reader.file_meta.view_blocks(&Fields::Pos);
+        assert_eq!(pos[0].column_stats, vec![ColumnStat::Int { min: 10, max: 20, null_count: 1, sum: 30 }]);
+        let names = stats::merge_stats(&Fields::ReadName, reader.file_meta.view_blocks(&Fields::ReadName)).unwrap();
+        assert_eq!(names[1].distinct(), Some(3));
+        assert_eq!(reader.file_meta.view_column_blocks(&Fields::RawTags, Some(0))[0].column_stats, vec![ColumnStat::Lengths(vec![2, 0, 0, 1])]);
+        assert!(reader.file_meta.view_blocks(&Fields::LName)[0].column_stats.is_empty());
+
+        // Stats of the last block are continued on append.
+        let mut appender = Writer::open_for_append(&path, 2).unwrap();
+        for rec in records.iter() {
+            appender.push_record(&BAMRawRecord(Cow::Borrowed(rec)));
+        }
+        appender.finish().unwrap();
+        drop(appender);
+        let reader = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap();
+        let pos = stats::merge_stats(&Fields::Pos, reader.file_meta.view_blocks(&Fields::Pos)).unwrap();
+        assert_eq!(pos, vec![ColumnStat::Int { min: 10, max: 20, null_count: 2, sum: 60 }]);
+    }
+
+    #[test]
+    fn test_footer_of_update_in_progress() {
+        let dir = TempDir::new("gbam_footer_test").unwrap();
+        let path = dir.path().join("test.gbam");
+        write_sam(SAM, |sam_header, ref_seqs| {
+            Writer::new_no_stats(BufWriter::new(File::create(&path).unwrap()), vec![Codecs::Lz4], 2, ref_seqs, sam_header, String::new(), false)
+        });
+
+        // Blocks of an update are written after the old meta, which stays
+        // readable until file info is swapped.
+        let (mut file, _, _) = open_for_update(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        let reader = Reader::open_shared(&path, ParsingTemplate::new()).unwrap();
+        assert_eq!(reader.amount, 3);
+        assert_eq!(Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).unwrap().amount, 3);
+    }
+}
@@ -0,0 +1,272 @@
+//! Python bindings of GBAM reader.
+//!
+//! ```python
+//! import pygbam
+//! reader = pygbam.open("file.gbam")
+//! for rec in reader.fetch("chr1:1,000-2,000", ["qname", "pos", "cigar"]):
+//!     print(rec["qname"], rec["pos"], rec["cigar"])
+//! positions = reader.column("pos")
+//! depth = reader.depth("chr1")
+//! ```
+use gbam_tools::export::table::TableColumn;
+use gbam_tools::query::depth::DepthCalculator;
+use gbam_tools::reader::parse_tmplt::ParsingTemplate;
+use gbam_tools::reader::reader::Reader;
+use gbam_tools::reader::record::GbamRecord;
+use gbam_tools::reader::records::Records;
+use gbam_tools::reader::regions::Region;
+use gbam_tools::GbamError;
+use numpy::IntoPyArray;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Records decoded per call into the reader while iterating from Python.
+const BATCH_SIZE: usize = 4096;
+
+const ALL_COLUMNS: [TableColumn; 15] = [
+    TableColumn::ReadName,
+    TableColumn::Flag,
+    TableColumn::RefId,
+    TableColumn::RefName,
+    TableColumn::Pos,
+    TableColumn::Mapq,
+    TableColumn::Bin,
+    TableColumn::Cigar,
+    TableColumn::NextRefId,
+    TableColumn::NextRefName,
+    TableColumn::NextPos,
+    TableColumn::Tlen,
+    TableColumn::Seq,
+    TableColumn::Qual,
+    TableColumn::Tags,
+];
+
+/// OSError for failed reads and damaged files, ValueError for invalid
+/// queries.
+fn py_error(error: GbamError) -> PyErr {
+    match error {
+        GbamError::Io(e) => e.into(),
+        e => PyValueError::new_err(e.to_string()),
+    }
+}
+
+/// Opens GBAM file.
+#[pyfunction]
+fn open(path: PathBuf) -> PyResult<PyReader> {
+    let reader = Reader::new(File::open(&path)?, ParsingTemplate::new()).map_err(py_error)?;
+    Ok(PyReader { path, reader, depth: None })
+}
+
+/// GBAM file reader. Columns are named as in `gbam_binary --table`: refid,
+/// rname, pos, mapq, bin, flag, next_refid, rnext, pnext, tlen, qname,
+/// cigar, seq, qual and tags. Positions are 0-based.
+#[pyclass(name = "Reader", unsendable)]
+struct PyReader {
+    path: PathBuf,
+    reader: Reader,
+    // Created on first depth call, holds records in memory.
+    depth: Option<DepthCalculator>,
+}
+
+#[pymethods]
+impl PyReader {
+    fn __len__(&self) -> usize {
+        self.reader.amount
+    }
+
+    /// List of (name, length) of reference sequences.
+    #[getter]
+    fn ref_seqs(&self) -> Vec<(String, u32)> {
+        self.reader.file_meta.get_ref_seqs().clone()
+    }
+
+    /// SAM header text.
+    #[getter]
+    fn header(&self) -> String {
+        self.reader.header().text().to_owned()
+    }
+
+    /// Iterates over all records as dicts of `columns` (all by default).
+    #[pyo3(signature = (columns=None))]
+    fn records(slf: Bound<'_, Self>, columns: Option<Vec<String>>) -> PyResult<RecordIter> {
+        let amount = slf.borrow().reader.amount;
+        RecordIter::new(slf.unbind(), columns, vec![(0..amount, None)])
+    }
+
+    /// Iterates over records overlapping region given as in samtools view,
+    /// e.g. `chr1:1,000-2,000`, as dicts of `columns` (all by default).
+    /// Records have to be coordinate sorted.
+    #[pyo3(signature = (region, columns=None))]
+    fn fetch(slf: Bound<'_, Self>, region: &str, columns: Option<Vec<String>>) -> PyResult<RecordIter> {
+        let ranges = slf.borrow_mut().region_ranges(region)?;
+        RecordIter::new(slf.unbind(), columns, ranges)
+    }
+
+    /// Numpy array of fixed width column (refid, pos, mapq, bin, flag,
+    /// next_refid, pnext or tlen) of all records, or of records overlapping
+    /// `region`. The array takes over decoded values without copying.
+    #[pyo3(signature = (column, region=None))]
+    fn column<'py>(&mut self, py: Python<'py>, column: &str, region: Option<&str>) -> PyResult<Bound<'py, PyAny>> {
+        let column: TableColumn = column.parse()?;
+        let ranges = match region {
+            Some(region) => self.region_ranges(region)?,
+            None => vec![(0..self.reader.amount, None)],
+        };
+        let array = match column {
+            TableColumn::RefId | TableColumn::Pos | TableColumn::NextRefId | TableColumn::NextPos | TableColumn::Tlen => {
+                self.collect(&ranges, column, |rec| int_value(rec, column) as i32).into_pyarray(py).into_any()
+            }
+            TableColumn::Bin => self.collect(&ranges, column, |rec| rec.bin.unwrap()).into_pyarray(py).into_any(),
+            TableColumn::Flag => self.collect(&ranges, column, |rec| rec.flag.unwrap()).into_pyarray(py).into_any(),
+            TableColumn::Mapq => self.collect(&ranges, column, |rec| rec.mapq.unwrap()).into_pyarray(py).into_any(),
+            _ => return Err(PyValueError::new_err(format!("Column {} is not fixed width.", column.name()))),
+        };
+        Ok(array)
+    }
+
+    /// Numpy array of per base depth of reference sequence, indexed by
+    /// 0-based position. Records are loaded into memory on first call.
+    fn depth<'py>(&mut self, py: Python<'py>, ref_name: &str) -> PyResult<Bound<'py, PyAny>> {
+        if self.depth.is_none() {
+            self.depth = Some(DepthCalculator::new(File::open(&self.path)?, None)?);
+        }
+        let calculator = self.depth.as_ref().unwrap();
+        let mut depth = calculator
+            .depth(ref_name, Vec::new())
+            .ok_or_else(|| PyKeyError::new_err(format!("Unknown reference sequence {}.", ref_name)))?;
+        // Depth has one slot past the end of reference sequence.
+        depth.pop();
+        Ok(depth.into_pyarray(py).into_any())
+    }
+}
+
+impl PyReader {
+    fn region_ranges(&mut self, region: &str) -> PyResult<Vec<(Range<usize>, Option<Region>)>> {
+        let region = Region::parse(region, self.reader.file_meta.get_ref_seqs())?;
+        let ranges = self.reader.region_record_ranges(&[region]).map_err(py_error)?;
+        Ok(ranges.into_iter().map(|(range, region)| (range, Some(region))).collect())
+    }
+
+    /// Values of `column` of records in `ranges`.
+    fn collect<T>(&mut self, ranges: &[(Range<usize>, Option<Region>)], column: TableColumn, value: impl Fn(&GbamRecord) -> T) -> Vec<T> {
+        let mut values = Vec::new();
+        for (range, region) in ranges {
+            let mut records = records_in(&mut self.reader, &[column], range.clone(), *region);
+            while let Some(rec) = records.next_rec() {
+                values.push(value(rec));
+            }
+        }
+        values
+    }
+}
+
+/// Iterator over records as dicts, decoded in batches.
+#[pyclass]
+struct RecordIter {
+    reader: Py<PyReader>,
+    columns: Vec<TableColumn>,
+    // Record ranges left, with region records have to overlap.
+    ranges: VecDeque<(Range<usize>, Option<Region>)>,
+    batch: VecDeque<Py<PyDict>>,
+}
+
+impl RecordIter {
+    fn new(reader: Py<PyReader>, columns: Option<Vec<String>>, ranges: Vec<(Range<usize>, Option<Region>)>) -> PyResult<Self> {
+        let columns = match columns {
+            Some(columns) => columns.iter().map(|column| column.parse()).collect::<Result<_, _>>()?,
+            None => ALL_COLUMNS.to_vec(),
+        };
+        Ok(Self {
+            reader,
+            columns,
+            ranges: ranges.into(),
+            batch: VecDeque::new(),
+        })
+    }
+
+    fn next_batch(&mut self, py: Python<'_>) -> PyResult<()> {
+        let mut reader = self.reader.borrow_mut(py);
+        let reader = &mut *reader;
+        let ref_seqs = reader.reader.file_meta.get_ref_seqs().clone();
+        while self.batch.is_empty() {
+            let (range, region) = match self.ranges.pop_front() {
+                Some(range) => range,
+                None => break,
+            };
+            let end = range.end.min(range.start + BATCH_SIZE);
+            if end < range.end {
+                self.ranges.push_front((end..range.end, region));
+            }
+            let mut records = records_in(&mut reader.reader, &self.columns, range.start..end, region);
+            let mut text = Vec::new();
+            while let Some(rec) = records.next_rec() {
+                let dict = PyDict::new(py);
+                for &column in self.columns.iter() {
+                    let key = column.name();
+                    match column {
+                        TableColumn::RefId | TableColumn::Pos | TableColumn::Mapq | TableColumn::Bin | TableColumn::Flag | TableColumn::NextRefId | TableColumn::NextPos | TableColumn::Tlen => {
+                            dict.set_item(key, int_value(rec, column))?
+                        }
+                        _ => {
+                            text.clear();
+                            column.write_value(rec, &ref_seqs, &mut text);
+                            dict.set_item(key, String::from_utf8_lossy(&text))?
+                        }
+                    }
+                }
+                self.batch.push_back(dict.unbind());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl RecordIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<Py<PyDict>>> {
+        if self.batch.is_empty() {
+            self.next_batch(py)?;
+        }
+        Ok(self.batch.pop_front())
+    }
+}
+
+/// Records of `range` with fields of `columns`, overlapping `region` if given.
+fn records_in<'a>(reader: &'a mut Reader, columns: &[TableColumn], range: Range<usize>, region: Option<Region>) -> Records<'a> {
+    let fields = TableColumn::fields(columns);
+    match region {
+        Some(region) => reader.region_records_with(&fields, vec![(range, region)]),
+        None => reader.range_records_with(&fields, range),
+    }
+}
+
+fn int_value(rec: &GbamRecord, column: TableColumn) -> i64 {
+    match column {
+        TableColumn::RefId => rec.refid.unwrap().into(),
+        TableColumn::Pos => rec.pos.unwrap().into(),
+        TableColumn::Mapq => rec.mapq.unwrap().into(),
+        TableColumn::Bin => rec.bin.unwrap().into(),
+        TableColumn::Flag => rec.flag.unwrap().into(),
+        TableColumn::NextRefId => rec.next_ref_id.unwrap().into(),
+        TableColumn::NextPos => rec.next_pos.unwrap().into(),
+        TableColumn::Tlen => rec.tlen.unwrap().into(),
+        _ => unreachable!("Column {} is not integer.", column.name()),
+    }
+}
+
+#[pymodule]
+fn pygbam(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    m.add_class::<PyReader>()?;
+    m.add_class::<RecordIter>()?;
+    Ok(())
+}